@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS hits (
+    uid TEXT PRIMARY KEY,
+    count_of_all BIGINT NOT NULL,
+    time_of_last BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tokens (
+    uid TEXT NOT NULL,
+    date TEXT NOT NULL,
+    total BIGINT NOT NULL,
+
+    UNIQUE (uid, date)
+);
+
+CREATE INDEX IF NOT EXISTS idx_tokens_uid_date ON tokens(uid, date);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS org_members;
+DROP TABLE IF EXISTS org_usage;
+DROP TABLE IF EXISTS orgs;
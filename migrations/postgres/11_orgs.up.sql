@@ -0,0 +1,28 @@
+-- Per-org overrides of the budgets shared by their members, managed by
+-- admins. Orgs without a row get the defaults.
+CREATE TABLE IF NOT EXISTS orgs (
+    org TEXT PRIMARY KEY,
+    max_tokens_per_day BIGINT,
+    max_cost_per_day DOUBLE PRECISION,
+    time_updated BIGINT NOT NULL
+);
+
+-- What the members of an org used together in a day.
+CREATE TABLE IF NOT EXISTS org_usage (
+    org TEXT NOT NULL,
+    date TEXT NOT NULL,
+    tokens BIGINT NOT NULL,
+    cost DOUBLE PRECISION NOT NULL,
+
+    UNIQUE (org, date)
+);
+
+-- Users who made requests as members of an org, for its stats.
+CREATE TABLE IF NOT EXISTS org_members (
+    org TEXT NOT NULL,
+    uid TEXT NOT NULL,
+
+    UNIQUE (org, uid)
+);
+
+CREATE INDEX IF NOT EXISTS idx_org_members_uid ON org_members(uid);
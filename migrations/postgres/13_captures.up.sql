@@ -0,0 +1,15 @@
+-- Bodies of chat requests and their responses, deflated, for investigating
+-- abuse and debugging. Of the requests with the same req_id in request_logs.
+-- Searched by the text search vector of the bodies, since they aren't text.
+CREATE TABLE IF NOT EXISTS captures (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    request BYTEA NOT NULL,
+    response BYTEA,
+    is_truncated BIGINT NOT NULL,
+    time BIGINT NOT NULL,
+    search TSVECTOR NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_captures_time ON captures(time);
+CREATE INDEX IF NOT EXISTS idx_captures_search ON captures USING GIN (search);
@@ -0,0 +1,3 @@
+ALTER TABLE request_logs ADD COLUMN session TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_request_logs_uid_session ON request_logs(uid, session);
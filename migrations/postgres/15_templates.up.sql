@@ -0,0 +1,12 @@
+-- Named prompt templates of users and orgs. Every update is a new version,
+-- so that the prompts used before can still be told apart.
+CREATE TABLE IF NOT EXISTS templates (
+    owner_kind TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    version BIGINT NOT NULL,
+    body TEXT NOT NULL,
+    time BIGINT NOT NULL,
+
+    UNIQUE (owner_kind, owner, name, version)
+);
@@ -0,0 +1,14 @@
+-- Requests mirrored to another model or provider, to compare with their
+-- request_logs.
+CREATE TABLE IF NOT EXISTS shadow_logs (
+    req_id TEXT PRIMARY KEY,
+    model TEXT NOT NULL,
+    status BIGINT NOT NULL,
+    input_tokens BIGINT NOT NULL,
+    output_tokens BIGINT NOT NULL,
+    cost DOUBLE PRECISION NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_shadow_logs_time ON shadow_logs(time);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_request_logs_experiment;
+ALTER TABLE request_logs DROP COLUMN arm;
+ALTER TABLE request_logs DROP COLUMN experiment;
@@ -0,0 +1,4 @@
+ALTER TABLE request_logs ADD COLUMN experiment TEXT;
+ALTER TABLE request_logs ADD COLUMN arm TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_request_logs_experiment ON request_logs(experiment, time);
@@ -0,0 +1,29 @@
+-- Chat requests submitted together, to be processed in the background as
+-- the user's rate limits and budgets allow. Processed as the user who
+-- submitted them, with the budgets of their token at the time.
+CREATE TABLE IF NOT EXISTS batches (
+    id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    role TEXT NOT NULL,
+    org TEXT,
+    max_tokens_per_day BIGINT,
+    max_cost_per_day DOUBLE PRECISION,
+    endpoint TEXT NOT NULL,
+    status TEXT NOT NULL,
+    time_created BIGINT NOT NULL,
+    time_finished BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_batches_uid ON batches(uid, time_created);
+CREATE INDEX IF NOT EXISTS idx_batches_status ON batches(status, time_created);
+
+-- Code is the HTTP status of the response, null until there is one.
+CREATE TABLE IF NOT EXISTS batch_items (
+    batch_id TEXT NOT NULL,
+    idx BIGINT NOT NULL,
+    request TEXT NOT NULL,
+    code BIGINT,
+    response TEXT,
+
+    PRIMARY KEY (batch_id, idx)
+);
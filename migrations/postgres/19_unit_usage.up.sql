@@ -0,0 +1,16 @@
+-- Usage of requests measured in units other than tokens, such as the
+-- characters of speech or the images generated.
+CREATE TABLE IF NOT EXISTS unit_usage (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    quantity DOUBLE PRECISION NOT NULL,
+    detail TEXT,
+    cost DOUBLE PRECISION NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_unit_usage_time ON unit_usage(time);
+CREATE INDEX IF NOT EXISTS idx_unit_usage_uid ON unit_usage(uid, time);
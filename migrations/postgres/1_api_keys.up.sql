@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    role TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    key_prefix TEXT NOT NULL,
+    time_created BIGINT NOT NULL,
+    time_revoked BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_keys_uid ON api_keys(uid);
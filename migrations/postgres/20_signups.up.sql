@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS invites (
+    code TEXT PRIMARY KEY,
+    max_uses BIGINT NOT NULL,
+    uses BIGINT NOT NULL DEFAULT 0,
+    time_created BIGINT NOT NULL,
+    time_expires BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS signups (
+    email TEXT PRIMARY KEY,
+    invite TEXT NOT NULL,
+    time_created BIGINT NOT NULL,
+    time_upgraded BIGINT
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS stripe_reports;
+DROP TABLE IF EXISTS stripe_customers;
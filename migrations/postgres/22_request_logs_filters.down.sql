@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_request_logs_status_time;
+DROP INDEX IF EXISTS idx_request_logs_model_time;
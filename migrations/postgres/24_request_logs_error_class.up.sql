@@ -0,0 +1,2 @@
+-- Why failed requests did, as in data::ErrorClass.
+ALTER TABLE request_logs ADD COLUMN error_class TEXT;
@@ -0,0 +1,12 @@
+-- Of requests, as tagged by the client, for usage to be reported by.
+CREATE TABLE IF NOT EXISTS request_tags (
+    req_id TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    value TEXT NOT NULL,
+    time BIGINT NOT NULL,
+
+    UNIQUE (req_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS idx_request_tags_tag_time ON request_tags(tag, time);
+CREATE INDEX IF NOT EXISTS idx_request_tags_time ON request_tags(time);
@@ -0,0 +1,14 @@
+-- Of tokens minted through the admin API, for audit and revocation.
+CREATE TABLE IF NOT EXISTS jwt_mints (
+    jti TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    role TEXT NOT NULL,
+    org TEXT,
+    max_tokens_per_day BIGINT,
+    max_cost_per_day DOUBLE PRECISION,
+    minted_by TEXT NOT NULL,
+    time BIGINT NOT NULL,
+    exp BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_jwt_mints_org_time ON jwt_mints(org, time);
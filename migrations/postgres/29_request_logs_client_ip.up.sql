@@ -0,0 +1,2 @@
+-- Of the client, past trusted proxies, as in conf::IpFilter.
+ALTER TABLE request_logs ADD COLUMN client_ip TEXT;
@@ -0,0 +1,2 @@
+-- Granted by the token the batch was submitted with, as a JSON array.
+ALTER TABLE batches ADD COLUMN features TEXT;
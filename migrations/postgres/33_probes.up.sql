@@ -0,0 +1,10 @@
+-- Of upstream providers, in the background, for the status page.
+CREATE TABLE IF NOT EXISTS probes (
+    provider TEXT NOT NULL,
+    time BIGINT NOT NULL,
+    is_up BIGINT NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_probes_time ON probes(time);
@@ -0,0 +1,3 @@
+-- Of upstream's requests per minute, those reserved for the org, overriding
+-- the conf's.
+ALTER TABLE orgs ADD COLUMN reserved_rpm BIGINT;
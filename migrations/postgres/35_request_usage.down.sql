@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS unit_usage (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    quantity DOUBLE PRECISION NOT NULL,
+    detail TEXT,
+    cost DOUBLE PRECISION NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_unit_usage_time ON unit_usage(time);
+CREATE INDEX IF NOT EXISTS idx_unit_usage_uid ON unit_usage(uid, time);
+
+INSERT INTO unit_usage (
+    req_id, uid, model, endpoint, unit, quantity, detail, cost, time
+)
+SELECT
+    req_id, uid, model, endpoint, unit, input + output, detail, cost, time
+FROM request_usage
+WHERE unit IN ('characters', 'images');
+
+DROP TABLE IF EXISTS request_usage;
@@ -0,0 +1,58 @@
+-- Of requests, in the units upstream measures them in, whatever the
+-- endpoint or provider, superseding unit_usage. Those of before are carried
+-- over, but for those of audio and realtime, whose seconds weren't kept.
+CREATE TABLE IF NOT EXISTS request_usage (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    provider TEXT,
+    model TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    input DOUBLE PRECISION NOT NULL,
+    output DOUBLE PRECISION NOT NULL,
+    detail TEXT,
+    cost DOUBLE PRECISION NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_request_usage_time ON request_usage(time);
+CREATE INDEX IF NOT EXISTS idx_request_usage_uid
+    ON request_usage(uid, time);
+
+INSERT INTO request_usage (
+    req_id, uid, model, endpoint, unit, input, output, detail, cost, time
+)
+SELECT
+    req_id,
+    uid,
+    model,
+    endpoint,
+    unit,
+    CASE WHEN unit = 'images' THEN 0 ELSE quantity END,
+    CASE WHEN unit = 'images' THEN quantity ELSE 0 END,
+    detail,
+    cost,
+    time
+FROM unit_usage;
+
+INSERT INTO request_usage (
+    req_id, uid, model, endpoint, unit, input, output, detail, cost, time
+)
+SELECT
+    req_id,
+    uid,
+    model,
+    endpoint,
+    'tokens',
+    input_tokens,
+    output_tokens,
+    NULL,
+    cost,
+    time
+FROM request_logs
+WHERE (input_tokens > 0 OR output_tokens > 0)
+    AND endpoint NOT LIKE '%audio/%'
+    AND endpoint <> 'v1/realtime'
+    AND req_id NOT IN (SELECT req_id FROM unit_usage);
+
+DROP TABLE IF EXISTS unit_usage;
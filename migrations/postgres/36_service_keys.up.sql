@@ -0,0 +1,24 @@
+-- Keys shared with backend services, which sign their requests with them
+-- rather than present a JWT. Kept as is, unlike API keys, since checking a
+-- signature takes the key itself.
+CREATE TABLE IF NOT EXISTS service_keys (
+    service TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    role TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    time_created BIGINT NOT NULL,
+    time_revoked BIGINT
+);
+
+-- Of signed requests, those seen until they expire (seconds since UNIX
+-- epoch), past which their timestamps are refused anyway.
+CREATE TABLE IF NOT EXISTS signing_nonces (
+    service TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    expires BIGINT NOT NULL,
+
+    UNIQUE (service, nonce)
+);
+
+CREATE INDEX IF NOT EXISTS idx_signing_nonces_expires
+    ON signing_nonces(expires);
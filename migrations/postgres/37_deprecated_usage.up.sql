@@ -0,0 +1,12 @@
+-- Per user, of requests using models and routes deprecated in conf, for
+-- those still using them to be told before they are removed.
+CREATE TABLE IF NOT EXISTS deprecated_usage (
+    uid TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    requests BIGINT NOT NULL,
+    first_used BIGINT NOT NULL,
+    last_used BIGINT NOT NULL,
+
+    PRIMARY KEY (uid, kind, name)
+);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS costs (
+    uid TEXT NOT NULL,
+    date TEXT NOT NULL,
+    total DOUBLE PRECISION NOT NULL,
+
+    UNIQUE (uid, date)
+);
+
+CREATE TABLE IF NOT EXISTS request_logs (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    status BIGINT NOT NULL,
+    input_tokens BIGINT NOT NULL,
+    output_tokens BIGINT NOT NULL,
+    cost DOUBLE PRECISION NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    time BIGINT NOT NULL,
+    error_message TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_request_logs_uid_time ON request_logs(uid, time);
+CREATE INDEX IF NOT EXISTS idx_request_logs_time ON request_logs(time);
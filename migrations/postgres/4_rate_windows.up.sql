@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS rate_windows (
+    uid TEXT NOT NULL,
+    period BIGINT NOT NULL,
+    start BIGINT NOT NULL,
+    count BIGINT NOT NULL,
+
+    UNIQUE (uid, period, start)
+);
@@ -0,0 +1,16 @@
+-- Per-user overrides, managed by admins. Users without a row get defaults.
+CREATE TABLE IF NOT EXISTS users (
+    uid TEXT PRIMARY KEY,
+    max_tokens_per_day BIGINT,
+    is_suspended BIGINT NOT NULL DEFAULT 0,
+    time_updated BIGINT NOT NULL
+);
+
+-- Tokens granted on top of the daily budget, for that day only.
+CREATE TABLE IF NOT EXISTS bonus_tokens (
+    uid TEXT NOT NULL,
+    date TEXT NOT NULL,
+    total BIGINT NOT NULL,
+
+    UNIQUE (uid, date)
+);
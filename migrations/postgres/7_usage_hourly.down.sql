@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS job_state;
+DROP TABLE IF EXISTS usage_hourly;
@@ -0,0 +1,23 @@
+-- request_logs rolled up per user, model and hour (start, in seconds since
+-- UNIX epoch), so that stats don't have to scan the logs.
+CREATE TABLE IF NOT EXISTS usage_hourly (
+    hour BIGINT NOT NULL,
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    requests BIGINT NOT NULL,
+    errors BIGINT NOT NULL,
+    input_tokens BIGINT NOT NULL,
+    output_tokens BIGINT NOT NULL,
+    cost DOUBLE PRECISION NOT NULL,
+    duration_ms BIGINT NOT NULL,
+
+    UNIQUE (hour, uid, model)
+);
+
+CREATE INDEX IF NOT EXISTS idx_usage_hourly_uid_hour ON usage_hourly(uid, hour);
+
+-- Progress of background jobs, e.g. until when request_logs were rolled up.
+CREATE TABLE IF NOT EXISTS job_state (
+    name TEXT PRIMARY KEY,
+    value BIGINT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS token_calibration;
+DROP TABLE IF EXISTS token_estimates;
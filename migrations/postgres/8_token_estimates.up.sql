@@ -0,0 +1,18 @@
+-- Prompt tokens of successful requests as estimated before forwarding, to
+-- compare with the actual ones in request_logs.
+CREATE TABLE IF NOT EXISTS token_estimates (
+    req_id TEXT PRIMARY KEY,
+    model TEXT NOT NULL,
+    estimate BIGINT NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_token_estimates_time ON token_estimates(time);
+
+-- Per model factors, by which estimates are corrected.
+CREATE TABLE IF NOT EXISTS token_calibration (
+    model TEXT PRIMARY KEY,
+    factor DOUBLE PRECISION NOT NULL,
+    samples BIGINT NOT NULL,
+    time BIGINT NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Why and when users were last suspended, and when that was lifted (seconds
+-- since UNIX epoch). Reason is null when suspended by an admin.
+CREATE TABLE IF NOT EXISTS suspensions (
+    uid TEXT PRIMARY KEY,
+    reason TEXT,
+    time_suspended INTEGER NOT NULL,
+    time_lifted INTEGER
+);
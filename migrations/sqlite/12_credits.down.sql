@@ -0,0 +1,2 @@
+ALTER TABLE tokens DROP COLUMN cap;
+DROP TABLE IF EXISTS credits;
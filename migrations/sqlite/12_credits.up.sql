@@ -0,0 +1,19 @@
+-- Tokens users have on top of their budgets: packs granted by admins, what
+-- was left of their budgets rolled over and, taken out, what they used
+-- beyond them. The balance is the sum. Period is that of the budget which
+-- was closed, null for grants.
+CREATE TABLE IF NOT EXISTS credits (
+    uid TEXT NOT NULL,
+    tokens INTEGER NOT NULL,
+    period TEXT,
+    note TEXT,
+    time INTEGER NOT NULL,
+
+    UNIQUE (uid, period)
+);
+
+CREATE INDEX IF NOT EXISTS idx_credits_uid ON credits(uid);
+
+-- Budget the usage was last checked against, without credits, for closing
+-- the period.
+ALTER TABLE tokens ADD COLUMN cap INTEGER;
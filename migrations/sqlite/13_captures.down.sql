@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS captures_fts;
+DROP TABLE IF EXISTS captures;
@@ -0,0 +1,21 @@
+-- Bodies of chat requests and their responses, deflated, for investigating
+-- abuse and debugging. Of the requests with the same req_id in request_logs.
+CREATE TABLE IF NOT EXISTS captures (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    request BLOB NOT NULL,
+    response BLOB,
+    is_truncated INTEGER NOT NULL,
+    time INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_captures_time ON captures(time);
+
+-- Full-text index of the bodies, by the rowid of their capture, without
+-- another copy of them.
+CREATE VIRTUAL TABLE IF NOT EXISTS captures_fts USING fts5(
+    request,
+    response,
+    content = '',
+    contentless_delete = 1
+);
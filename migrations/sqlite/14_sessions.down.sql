@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_request_logs_uid_session;
+ALTER TABLE request_logs DROP COLUMN session;
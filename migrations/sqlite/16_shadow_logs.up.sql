@@ -0,0 +1,14 @@
+-- Requests mirrored to another model or provider, to compare with their
+-- request_logs.
+CREATE TABLE IF NOT EXISTS shadow_logs (
+    req_id TEXT PRIMARY KEY,
+    model TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    input_tokens INTEGER NOT NULL,
+    output_tokens INTEGER NOT NULL,
+    cost REAL NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    time INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_shadow_logs_time ON shadow_logs(time);
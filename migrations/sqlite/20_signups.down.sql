@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS signups;
+DROP TABLE IF EXISTS invites;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS invites (
+    code TEXT PRIMARY KEY,
+    max_uses INTEGER NOT NULL,
+    uses INTEGER NOT NULL DEFAULT 0,
+    time_created INTEGER NOT NULL,
+    time_expires INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS signups (
+    email TEXT PRIMARY KEY,
+    invite TEXT NOT NULL,
+    time_created INTEGER NOT NULL,
+    time_upgraded INTEGER
+);
@@ -0,0 +1,20 @@
+-- Stripe customers whom the usage of users and orgs is billed to.
+CREATE TABLE IF NOT EXISTS stripe_customers (
+    kind TEXT NOT NULL,
+    id TEXT NOT NULL,
+    customer TEXT NOT NULL,
+    time_updated INTEGER NOT NULL,
+
+    UNIQUE (kind, id)
+);
+
+-- Days of usage which Stripe accepted, so that they aren't sent again.
+CREATE TABLE IF NOT EXISTS stripe_reports (
+    kind TEXT NOT NULL,
+    id TEXT NOT NULL,
+    date TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    time_reported INTEGER NOT NULL,
+
+    UNIQUE (kind, id, date)
+);
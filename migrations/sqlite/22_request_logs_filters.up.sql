@@ -0,0 +1,3 @@
+-- For the admin's filtering of request logs by model or status.
+CREATE INDEX IF NOT EXISTS idx_request_logs_model_time ON request_logs(model, time);
+CREATE INDEX IF NOT EXISTS idx_request_logs_status_time ON request_logs(status, time);
@@ -0,0 +1,2 @@
+-- JSON of where upstream's time went, as reported by those which do.
+ALTER TABLE request_logs ADD COLUMN upstream_timing TEXT;
@@ -0,0 +1,9 @@
+-- What all users together spent at each provider a day, for spend caps.
+CREATE TABLE IF NOT EXISTS provider_spend (
+    provider TEXT NOT NULL,
+    date TEXT NOT NULL,
+    tokens INTEGER NOT NULL,
+    cost REAL NOT NULL,
+
+    UNIQUE (provider, date)
+);
@@ -0,0 +1,9 @@
+-- Of copies from other databases, by raskol db copy, for them to resume.
+CREATE TABLE IF NOT EXISTS copy_progress (
+    source TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    last_key TEXT NOT NULL,
+    copied INTEGER NOT NULL,
+
+    UNIQUE (source, table_name)
+);
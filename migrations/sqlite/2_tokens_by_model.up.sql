@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS tokens_by_model (
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    date TEXT NOT NULL,
+    total INTEGER NOT NULL,
+
+    UNIQUE (uid, model, date)
+);
@@ -0,0 +1,7 @@
+-- Defaults of users' own for chat requests, filled in where they leave
+-- them out.
+CREATE TABLE IF NOT EXISTS preferences (
+    uid TEXT PRIMARY KEY,
+    body TEXT NOT NULL,
+    time INTEGER NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Tokens left in users' buckets, as of the time, in milliseconds since the
+-- Unix epoch.
+CREATE TABLE IF NOT EXISTS rate_buckets (
+    uid TEXT PRIMARY KEY,
+    tokens REAL NOT NULL,
+    time_ms INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Of upstream providers, in the background, for the status page.
+CREATE TABLE IF NOT EXISTS probes (
+    provider TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    is_up INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_probes_time ON probes(time);
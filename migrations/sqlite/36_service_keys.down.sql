@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS signing_nonces;
+DROP TABLE IF EXISTS service_keys;
@@ -0,0 +1,12 @@
+-- Per user, of requests using models and routes deprecated in conf, for
+-- those still using them to be told before they are removed.
+CREATE TABLE IF NOT EXISTS deprecated_usage (
+    uid TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    first_used INTEGER NOT NULL,
+    last_used INTEGER NOT NULL,
+
+    PRIMARY KEY (uid, kind, name)
+);
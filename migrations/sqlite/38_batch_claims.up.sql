@@ -0,0 +1,4 @@
+-- Until when an item is being processed, by whichever instance claimed it,
+-- so that no other one sends it again meanwhile. Claims outlive instances
+-- which die holding them only until then.
+ALTER TABLE batch_items ADD COLUMN claimed_until INTEGER;
@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS rate_buckets;
+
+CREATE TABLE IF NOT EXISTS rate_buckets (
+    uid TEXT PRIMARY KEY,
+    tokens REAL NOT NULL,
+    time_ms INTEGER NOT NULL
+);
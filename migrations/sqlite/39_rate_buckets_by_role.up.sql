@@ -0,0 +1,17 @@
+-- Of users' buckets, one per role they make requests as, since roles have
+-- buckets of their own, and until when they matter, past which they would
+-- be full again, so are forgotten. Dropped, since they refill anyway.
+DROP TABLE IF EXISTS rate_buckets;
+
+CREATE TABLE IF NOT EXISTS rate_buckets (
+    uid TEXT NOT NULL,
+    role TEXT NOT NULL,
+    tokens REAL NOT NULL,
+    time_ms INTEGER NOT NULL,
+    expires_ms INTEGER NOT NULL,
+
+    PRIMARY KEY (uid, role)
+);
+
+CREATE INDEX IF NOT EXISTS idx_rate_buckets_expires
+    ON rate_buckets(expires_ms);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS costs (
+    uid TEXT NOT NULL,
+    date TEXT NOT NULL,
+    total REAL NOT NULL,
+
+    UNIQUE (uid, date)
+);
+
+CREATE TABLE IF NOT EXISTS request_logs (
+    req_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    input_tokens INTEGER NOT NULL,
+    output_tokens INTEGER NOT NULL,
+    cost REAL NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    error_message TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_request_logs_uid_time ON request_logs(uid, time);
+CREATE INDEX IF NOT EXISTS idx_request_logs_time ON request_logs(time);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS rate_windows (
+    uid TEXT NOT NULL,
+    period INTEGER NOT NULL,
+    start INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+
+    UNIQUE (uid, period, start)
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS jwt_revocations_by_uid;
+DROP TABLE IF EXISTS jwt_revocations;
@@ -0,0 +1,11 @@
+-- Individual tokens, by their "jti" claim.
+CREATE TABLE IF NOT EXISTS jwt_revocations (
+    jti TEXT PRIMARY KEY,
+    time_revoked INTEGER NOT NULL
+);
+
+-- All tokens of a user issued (by "iat" claim) before the revocation.
+CREATE TABLE IF NOT EXISTS jwt_revocations_by_uid (
+    uid TEXT PRIMARY KEY,
+    time_revoked INTEGER NOT NULL
+);
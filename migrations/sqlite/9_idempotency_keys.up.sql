@@ -0,0 +1,15 @@
+-- Responses to requests with an Idempotency-Key, replayed to repeats of
+-- them until they expire (seconds since UNIX epoch). Status and body are
+-- null while the first request is still being handled.
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    uid TEXT NOT NULL,
+    key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status INTEGER,
+    body TEXT,
+    expires INTEGER NOT NULL,
+
+    UNIQUE (uid, key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_expires ON idempotency_keys(expires);
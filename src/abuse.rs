@@ -0,0 +1,123 @@
+//! Automatic suspension of users whose recent usage looks abusive: too many
+//! requests, too many tokens or too many failures of their own making, not
+//! of upstream's nor of their budget's, within a window. Admins review and
+//! lift suspensions at `/v1/admin/suspensions`.
+
+use chrono::Utc;
+
+use crate::{
+    conf,
+    data::{Storage, Suspension, UserStats},
+};
+
+/// Why the usage is abusive. None if it is not.
+#[must_use]
+pub fn verdict(conf: &conf::Abuse, usage: &UserStats) -> Option<String> {
+    let window = conf.window;
+    if let Some(max) = conf.max_requests {
+        if usage.requests > max {
+            let requests = usage.requests;
+            return Some(format!(
+                "{requests} requests in {window}s, over {max}."
+            ));
+        }
+    }
+    if let Some(max) = conf.max_tokens {
+        let tokens = usage.input_tokens + usage.output_tokens;
+        if tokens > max {
+            return Some(format!(
+                "{tokens} tokens in {window}s, over {max}."
+            ));
+        }
+    }
+    if let Some(max) = conf.max_error_ratio {
+        if usage.requests >= conf.min_requests.max(1) {
+            #[allow(clippy::cast_precision_loss)]
+            let ratio = usage.errors as f64 / usage.requests as f64;
+            if ratio > max {
+                let (errors, requests) = (usage.errors, usage.requests);
+                return Some(format!(
+                    "{errors} of {requests} requests failed in {window}s, \
+                    over {max}."
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Suspends the users whose usage within the window is abusive. Returns
+/// the new suspensions.
+pub async fn detect(
+    storage: &dyn Storage,
+    conf: &conf::Abuse,
+) -> anyhow::Result<Vec<Suspension>> {
+    let now = Utc::now().timestamp();
+    let since = now - i64::try_from(conf.window)?;
+    let mut suspensions = Vec::new();
+    for usage in storage.suspension_candidates(since).await? {
+        if conf.exempt.iter().any(|uid| conf::matches(uid, &usage.uid)) {
+            continue;
+        }
+        let Some(reason) = verdict(conf, &usage) else {
+            continue;
+        };
+        tracing::warn!(uid = usage.uid, reason, "Suspending for abuse.");
+        storage.suspension_create(&usage.uid, &reason).await?;
+        metrics::counter!("raskol_abuse_suspensions_total").increment(1);
+        suspensions.push(Suspension {
+            uid: usage.uid,
+            reason: Some(reason),
+            time: now,
+        });
+    }
+    Ok(suspensions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verdict;
+    use crate::{conf, data::UserStats};
+
+    #[test]
+    fn thresholds() {
+        let conf = conf::Abuse {
+            window: 60,
+            max_requests: Some(100),
+            max_tokens: Some(10_000),
+            max_error_ratio: Some(0.5),
+            min_requests: 10,
+            exempt: Vec::new(),
+        };
+        let usage = |requests, errors, tokens| UserStats {
+            uid: "alice".to_string(),
+            requests,
+            errors,
+            input_tokens: tokens,
+            ..UserStats::default()
+        };
+        assert_eq!(verdict(&conf, &usage(100, 50, 10_000)), None);
+        assert_eq!(
+            verdict(&conf, &usage(101, 0, 0)).as_deref(),
+            Some("101 requests in 60s, over 100.")
+        );
+        assert_eq!(
+            verdict(&conf, &usage(1, 0, 10_001)).as_deref(),
+            Some("10001 tokens in 60s, over 10000.")
+        );
+        assert_eq!(
+            verdict(&conf, &usage(10, 6, 0)).as_deref(),
+            Some("6 of 10 requests failed in 60s, over 0.5.")
+        );
+        // Too few to tell.
+        assert_eq!(verdict(&conf, &usage(9, 9, 0)), None);
+        // Not checked.
+        let conf = conf::Abuse {
+            max_requests: None,
+            max_tokens: None,
+            max_error_ratio: None,
+            ..conf
+        };
+        assert_eq!(verdict(&conf, &usage(1000, 1000, 1_000_000)), None);
+    }
+}
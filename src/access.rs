@@ -0,0 +1,340 @@
+//! One line per request, per [`conf::AccessLog`], apart from the tracing
+//! logs, for operators to grep and tail during the event. Written once the
+//! response body is done, so with its size, and with what the handlers
+//! noted along the way, such as who the user was and the tokens used.
+
+use std::{
+    fs::OpenOptions,
+    future::Future,
+    io::{LineWriter, Write},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+
+use anyhow::Context as _;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http_body::{Body as HttpBody, Frame, SizeHint};
+
+use crate::{
+    conf, data,
+    server::{AppState, REQ_ID_HEADER},
+};
+
+tokio::task_local! {
+    static NOTED: Arc<Mutex<Noted>>;
+}
+
+/// Where the lines go.
+pub struct Log {
+    format: conf::AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Of a request, by the handlers, since the layer sees only the response.
+#[derive(Debug, Default)]
+struct Noted {
+    uid: Option<String>,
+    upstream_ms: Option<i64>,
+    input_tokens: Option<i64>,
+    output_tokens: Option<i64>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Entry {
+    #[serde(skip)]
+    pub at: DateTime<Utc>,
+
+    /// RFC 3339, of when the request came in.
+    pub time: String,
+
+    pub req_id: Option<String>,
+
+    /// Past trusted proxies.
+    pub client_ip: String,
+
+    pub method: String,
+
+    /// Without the query, which may carry secrets.
+    pub path: String,
+
+    pub protocol: String,
+    pub uid: Option<String>,
+    pub status: u16,
+
+    /// Of the response body, as sent.
+    pub bytes: u64,
+
+    /// Until the response body was done.
+    pub duration_ms: u64,
+
+    /// Of requests which went upstream.
+    pub upstream_ms: Option<i64>,
+    pub input_tokens: Option<i64>,
+    pub output_tokens: Option<i64>,
+}
+
+impl Log {
+    pub fn open(conf: &conf::AccessLog) -> anyhow::Result<Self> {
+        let out: Box<dyn Write + Send> = match &conf.file {
+            Some(file) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .context(format!(
+                        "Failed to open access log: {file:?}"
+                    ))?;
+                Box::new(LineWriter::new(file))
+            }
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(Self {
+            format: conf.format,
+            out: Mutex::new(out),
+        })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            conf::AccessLogFormat::Json => {
+                serde_json::to_string(entry).unwrap_or_default()
+            }
+            conf::AccessLogFormat::Clf => entry.clf(),
+        };
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(error) = writeln!(out, "{line}") {
+            tracing::error!(?error, "Failed to write access log.");
+        }
+    }
+}
+
+impl Entry {
+    fn clf(&self) -> String {
+        let or_dash =
+            |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        let number =
+            |value: Option<i64>| or_dash(value.map(|n| n.to_string()));
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} req_id={} duration_ms={} \
+                upstream_ms={} input_tokens={} output_tokens={}",
+            self.client_ip,
+            or_dash(self.uid.clone()),
+            self.at.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.protocol,
+            self.status,
+            self.bytes,
+            or_dash(self.req_id.clone()),
+            self.duration_ms,
+            number(self.upstream_ms),
+            number(self.input_tokens),
+            number(self.output_tokens),
+        )
+    }
+}
+
+/// Of the user, for requests which log nothing else, such as admins'.
+pub fn note_uid(uid: &str) {
+    let _ = NOTED.try_with(|noted| {
+        lock(noted).uid = Some(uid.to_string());
+    });
+}
+
+/// Of a request logged, per [`data::RequestLog`].
+pub fn note(log: &data::RequestLog) {
+    let _ = NOTED.try_with(|noted| {
+        let mut noted = lock(noted);
+        noted.uid = Some(log.uid.clone());
+        noted.upstream_ms = Some(log.duration_ms);
+        noted.input_tokens = Some(log.input_tokens);
+        noted.output_tokens = Some(log.output_tokens);
+    });
+}
+
+/// The future, noting for the request it was made in, for tasks of its own
+/// which outlive the handler, such as those of streams.
+pub fn carry<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let noted = NOTED.try_with(Arc::clone).ok();
+    async move {
+        match noted {
+            Some(noted) => NOTED.scope(noted, future).await,
+            None => future.await,
+        }
+    }
+}
+
+pub(crate) async fn layer(
+    State(AppState { access, .. }): State<AppState>,
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = access else {
+        return next.run(req).await;
+    };
+    let started = Instant::now();
+    let at = Utc::now();
+    let mut entry = Entry {
+        at,
+        time: at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        req_id: None,
+        client_ip: from.ip().to_canonical().to_string(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        protocol: format!("{:?}", req.version()),
+        uid: None,
+        status: 0,
+        bytes: 0,
+        duration_ms: 0,
+        upstream_ms: None,
+        input_tokens: None,
+        output_tokens: None,
+    };
+    let noted = Arc::new(Mutex::new(Noted::default()));
+    let resp = NOTED.scope(noted.clone(), next.run(req)).await;
+    entry.status = resp.status().as_u16();
+    entry.req_id = resp
+        .headers()
+        .get(REQ_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (parts, body) = resp.into_parts();
+    let body = Counted {
+        body,
+        pending: Some(Pending {
+            log,
+            entry,
+            noted,
+            started,
+        }),
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+fn lock(noted: &Mutex<Noted>) -> MutexGuard<'_, Noted> {
+    noted.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A response body, written to the log once done, however it ends, as when
+/// the client goes away.
+struct Counted {
+    body: Body,
+
+    /// Until written.
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    log: Arc<Log>,
+    entry: Entry,
+    noted: Arc<Mutex<Noted>>,
+    started: Instant,
+}
+
+impl Counted {
+    fn finish(&mut self) {
+        let Some(Pending {
+            log,
+            mut entry,
+            noted,
+            started,
+        }) = self.pending.take()
+        else {
+            return;
+        };
+        let noted = std::mem::take(&mut *lock(&noted));
+        entry.uid = noted.uid;
+        entry.upstream_ms = noted.upstream_ms;
+        entry.input_tokens = noted.input_tokens;
+        entry.output_tokens = noted.output_tokens;
+        entry.duration_ms =
+            u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        log.write(&entry);
+    }
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(pending)) =
+                    (frame.data_ref(), self.pending.as_mut())
+                {
+                    pending.entry.bytes += data.len() as u64;
+                }
+            }
+            Some(Err(_)) | None => self.finish(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::Entry;
+
+    #[test]
+    fn clf() {
+        let at = Utc.with_ymd_and_hms(2025, 6, 1, 12, 30, 0).unwrap();
+        let mut entry = Entry {
+            at,
+            time: at.to_rfc3339(),
+            req_id: Some("abc".to_string()),
+            client_ip: "203.0.113.7".to_string(),
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            uid: Some("alice".to_string()),
+            status: 200,
+            bytes: 512,
+            duration_ms: 840,
+            upstream_ms: Some(800),
+            input_tokens: Some(10),
+            output_tokens: Some(20),
+        };
+        assert_eq!(
+            entry.clf(),
+            "203.0.113.7 - alice [01/Jun/2025:12:30:00 +0000] \
+                \"POST /v1/chat/completions HTTP/1.1\" 200 512 req_id=abc \
+                duration_ms=840 upstream_ms=800 input_tokens=10 \
+                output_tokens=20"
+        );
+        entry.uid = None;
+        entry.upstream_ms = None;
+        assert!(entry.clf().starts_with("203.0.113.7 - - ["));
+        assert!(entry.clf().contains(" upstream_ms=- "));
+    }
+}
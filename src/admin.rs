@@ -0,0 +1,1092 @@
+//! HTTP API for admins to manage users' budgets, instead of editing the
+//! database by hand.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{self, KeepAlive, Sse},
+    routing::{get, post, put},
+    Json, Router,
+};
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    auth, billing, conf,
+    data::{self, Account, Credit, DailyUsage, Org, Suspension},
+    jobs, period, replay,
+    server::{self, AppState, REQ_ID, USER},
+    slow,
+    tracing::LogLevel,
+};
+
+/// Of [`routes`], as nested under `/v1/admin`.
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(
+        handle_user_get,
+        handle_set_max_tokens_per_day,
+        handle_grant_tokens,
+        handle_credits_get,
+        handle_credits_grant,
+        handle_suspend,
+        handle_unsuspend,
+        handle_set_org_budget,
+        handle_set_org_reserved_rpm,
+        handle_suspensions,
+        handle_events,
+        handle_errors,
+        handle_logs,
+        handle_logs_search,
+        handle_shadow,
+        handle_experiments,
+        handle_slow,
+        handle_jobs,
+        handle_deprecated,
+        handle_tags,
+        handle_units,
+        handle_billing,
+        handle_signups,
+        handle_signup_upgrade,
+        handle_log_level_get,
+        handle_log_level_set,
+        handle_replay,
+        handle_jwt_mint,
+        handle_jwt_mints,
+        handle_jwt_revoke
+    ),
+    tags((name = "admin", description = "For those of the admin role."))
+)]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    let router = Router::new()
+        .route("/users/:uid", get(handle_user_get))
+        .route(
+            "/users/:uid/max-tokens-per-day",
+            put(handle_set_max_tokens_per_day),
+        )
+        .route("/users/:uid/grant-tokens", post(handle_grant_tokens))
+        .route(
+            "/users/:uid/credits",
+            get(handle_credits_get).post(handle_credits_grant),
+        )
+        .route("/users/:uid/suspend", post(handle_suspend))
+        .route("/users/:uid/unsuspend", post(handle_unsuspend))
+        .route("/orgs/:org/budget", put(handle_set_org_budget))
+        .route("/orgs/:org/reserved-rpm", put(handle_set_org_reserved_rpm))
+        .route("/suspensions", get(handle_suspensions))
+        .route("/events", get(handle_events))
+        .route("/errors", get(handle_errors))
+        .route("/logs", get(handle_logs))
+        .route("/logs/search", get(handle_logs_search))
+        .route("/shadow", get(handle_shadow))
+        .route("/experiments", get(handle_experiments))
+        .route("/slow", get(handle_slow))
+        .route("/jobs", get(handle_jobs))
+        .route("/deprecated", get(handle_deprecated))
+        .route("/tags", get(handle_tags))
+        .route("/units", get(handle_units))
+        .route("/billing", get(handle_billing))
+        .route("/signups", get(handle_signups))
+        .route("/signups/:email/upgrade", post(handle_signup_upgrade))
+        .route(
+            "/log-level",
+            get(handle_log_level_get).put(handle_log_level_set),
+        )
+        .route("/replay/:req_id", post(handle_replay))
+        .route("/jwt", get(handle_jwt_mints).post(handle_jwt_mint))
+        .route("/jwt/:jti/revoke", post(handle_jwt_revoke));
+    #[cfg(feature = "graphql")]
+    let router = router.merge(crate::graphql::routes());
+    router
+}
+
+#[utoipa::path(
+    get,
+    path = "/log-level",
+    tag = "admin",
+    responses(
+        (status = 200, body = LogLevel),
+        (status = 404, description = "Logging is not ours, as embedded."),
+    )
+)]
+async fn handle_log_level_get() -> Result<Json<LogLevel>, StatusCode> {
+    let filter = crate::tracing::log_level().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(filter))
+}
+
+/// Until changed again or restarted, for debugging an incident without
+/// losing state.
+#[utoipa::path(
+    put,
+    path = "/log-level",
+    tag = "admin",
+    request_body = LogLevel,
+    responses(
+        (status = 200, body = LogLevel),
+        (status = 400, description = "Invalid directive."),
+        (status = 404, description = "Logging is not ours, as embedded."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_log_level_set(
+    Json(filter): Json<LogLevel>,
+) -> Result<Json<LogLevel>, StatusCode> {
+    if crate::tracing::log_level().is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    crate::tracing::log_level_set(filter.clone()).map_err(|error| {
+        tracing::warn!(?error, "Rejecting. Invalid log level.");
+        StatusCode::BAD_REQUEST
+    })?;
+    tracing::info!(?filter, "Log level set.");
+    Ok(Json(filter))
+}
+
+/// Of a captured request, through the forwarding path as configured now,
+/// as the admin replaying it.
+#[utoipa::path(
+    post,
+    path = "/replay/{req_id}",
+    tag = "admin",
+    params(("req_id" = String, Path, description = "Of the original.")),
+    responses(
+        (status = 200, body = replay::Replayed),
+        (status = 404, description = "Not captured."),
+        (status = 409, description = "Truncated when captured."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_replay(
+    State(state): State<AppState>,
+    Path(req_id): Path<String>,
+) -> Result<Json<replay::Replayed>, StatusCode> {
+    match replay::replay(&state, USER.get(), &req_id)
+        .await
+        .map_err(internal)?
+    {
+        Ok(replayed) => {
+            tracing::info!(
+                original = req_id,
+                status = replayed.status,
+                "Replayed."
+            );
+            Ok(Json(replayed))
+        }
+        Err(replay::Unreplayable::NotCaptured) => Err(StatusCode::NOT_FOUND),
+        Err(replay::Unreplayable::Truncated) => Err(StatusCode::CONFLICT),
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Minted {
+    token: String,
+    jti: String,
+
+    /// Unix time.
+    exp: u64,
+}
+
+/// For a user, recorded for audit and revocation. Org admins may only mint
+/// tokens for members of their own org, within the caps in conf.
+#[utoipa::path(
+    post,
+    path = "/jwt",
+    tag = "admin",
+    request_body = auth::Mint,
+    responses(
+        (status = 200, body = Minted),
+        (status = 400, description = "Invalid."),
+        (status = 403, description = "Beyond what the minter may mint."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_jwt_mint(
+    State(AppState { storage, .. }): State<AppState>,
+    Json(mint): Json<auth::Mint>,
+) -> Result<Json<Minted>, StatusCode> {
+    let conf = conf::global();
+    let minter = USER.get();
+    let claims = mint
+        .claims(&conf, &minter.role, minter.org.as_deref())
+        .map_err(|unmintable| {
+            tracing::warn!(?unmintable, "Rejecting. Unmintable.");
+            match unmintable {
+                auth::Unmintable::Invalid(_) => StatusCode::BAD_REQUEST,
+                auth::Unmintable::NotAllowed(_) => StatusCode::FORBIDDEN,
+            }
+        })?;
+    if minter.role != auth::ROLE_ADMIN {
+        let org = claims.org.as_deref().unwrap_or_default();
+        let is_member = storage
+            .org_has_member(org, &claims.sub)
+            .await
+            .map_err(internal)?;
+        if !is_member {
+            tracing::warn!(org, uid = claims.sub, "Rejecting. Not a member.");
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    let jti = claims.jti.clone().unwrap_or_default();
+    storage
+        .jwt_mint_record(&data::JwtMint {
+            jti: jti.clone(),
+            uid: claims.sub.clone(),
+            role: claims.role.clone(),
+            org: claims.org.clone(),
+            max_tokens_per_day: claims
+                .max_tokens_per_day
+                .map(|max| i64::try_from(max).unwrap_or(i64::MAX)),
+            max_cost_per_day: claims.max_cost_per_day,
+            minted_by: minter.uid.clone(),
+            time: unix(claims.iat.unwrap_or_default()),
+            exp: unix(claims.exp()),
+            time_revoked: None,
+        })
+        .await
+        .map_err(internal)?;
+    let token = claims.to_str(&conf.jwt).map_err(|error| {
+        tracing::error!(?error, "Failed to encode JWT.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(
+        jti,
+        uid = claims.sub,
+        role = claims.role,
+        org = ?claims.org,
+        minted_by = minter.uid,
+        "Minted JWT."
+    );
+    Ok(Json(Minted {
+        token,
+        jti,
+        exp: claims.exp(),
+    }))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MintsQuery {
+    #[serde(default = "default_mints_limit")]
+    limit: u64,
+}
+
+fn default_mints_limit() -> u64 {
+    100
+}
+
+/// Newest first. Of their own org only, for org admins.
+#[utoipa::path(
+    get,
+    path = "/jwt",
+    tag = "admin",
+    params(MintsQuery),
+    responses((status = 200, body = Vec<data::JwtMint>))
+)]
+async fn handle_jwt_mints(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(MintsQuery { limit }): Query<MintsQuery>,
+) -> Result<Json<Vec<data::JwtMint>>, StatusCode> {
+    let org = minting_org()?;
+    let mints = storage
+        .jwt_mints(org.as_deref(), limit)
+        .await
+        .map_err(internal)?;
+    Ok(Json(mints))
+}
+
+/// Of those minted through the API. Of their own org only, for org admins.
+#[utoipa::path(
+    post,
+    path = "/jwt/{jti}/revoke",
+    tag = "admin",
+    params(("jti" = String, Path)),
+    responses(
+        (status = 200, body = data::JwtMint),
+        (status = 404, description = "No such mint."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_jwt_revoke(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(jti): Path<String>,
+) -> Result<Json<data::JwtMint>, StatusCode> {
+    let org = minting_org()?;
+    let mut mint = storage
+        .jwt_mint_get(&jti)
+        .await
+        .map_err(internal)?
+        .filter(|mint| org.is_none() || mint.org == org)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!(jti, uid = mint.uid, "Revoking JWT.");
+    storage.jwt_revoke(&jti).await.map_err(internal)?;
+    if mint.time_revoked.is_none() {
+        mint = storage
+            .jwt_mint_get(&jti)
+            .await
+            .map_err(internal)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+    }
+    Ok(Json(mint))
+}
+
+/// Of what tells whose it is. Hashed as on the leaderboard, so that one
+/// user's requests can still be told apart from another's.
+fn anonymize(log: &mut data::RequestLog) {
+    let conf = conf::global();
+    log.uid = server::anonymize(&conf, &log.uid);
+    log.session = log
+        .session
+        .as_deref()
+        .map(|session| server::anonymize(&conf, session));
+    log.client_ip = None;
+}
+
+/// Whose mints the user may see: all, for admins, or their org's.
+fn minting_org() -> Result<Option<String>, StatusCode> {
+    let user = USER.get();
+    if user.role == auth::ROLE_ADMIN {
+        return Ok(None);
+    }
+    user.org.map(Some).ok_or_else(|| {
+        tracing::warn!(uid = user.uid, "Rejecting. Not of an org.");
+        StatusCode::FORBIDDEN
+    })
+}
+
+fn unix(secs: u64) -> i64 {
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+/// Self-service ones, newest first.
+#[utoipa::path(
+    get,
+    path = "/signups",
+    tag = "admin",
+    responses((status = 200, body = Vec<data::Signup>))
+)]
+async fn handle_signups(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Vec<data::Signup>>, StatusCode> {
+    let signups = storage.signup_list().await.map_err(internal)?;
+    Ok(Json(signups))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct SignupUpgrade {
+    max_tokens_per_day: u64,
+}
+
+/// From the starter budget to the given one.
+#[utoipa::path(
+    post,
+    path = "/signups/{email}/upgrade",
+    tag = "admin",
+    params(("email" = String, Path)),
+    request_body = SignupUpgrade,
+    responses(
+        (status = 200, body = data::Signup),
+        (status = 404, description = "No such signup."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_signup_upgrade(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(email): Path<String>,
+    Json(SignupUpgrade { max_tokens_per_day }): Json<SignupUpgrade>,
+) -> Result<Json<data::Signup>, StatusCode> {
+    tracing::info!(email, max_tokens_per_day, "Upgrading signup.");
+    let signup = storage
+        .signup_upgrade(&email.to_lowercase(), max_tokens_per_day)
+        .await
+        .map_err(internal)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(signup))
+}
+
+/// Of the arms of the A/B experiments, side by side.
+#[utoipa::path(
+    get,
+    path = "/experiments",
+    tag = "admin",
+    params(SinceQuery),
+    responses((status = 200, body = Vec<data::ArmStats>))
+)]
+async fn handle_experiments(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(SinceQuery { since }): Query<SinceQuery>,
+) -> Result<Json<Vec<data::ArmStats>>, StatusCode> {
+    let stats = storage.experiment_stats(since).await.map_err(internal)?;
+    Ok(Json(stats))
+}
+
+/// Of this instance, the slowest requests of each window lately, newest
+/// first. Not found when off in conf.
+#[utoipa::path(
+    get,
+    path = "/slow",
+    tag = "admin",
+    responses((status = 200, body = Vec<slow::Window>))
+)]
+async fn handle_slow() -> Result<Json<Vec<slow::Window>>, StatusCode> {
+    let sampler = slow::global().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(sampler.windows()))
+}
+
+/// Of this instance, the background jobs, with how each last went.
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    tag = "admin",
+    responses((status = 200, body = Vec<jobs::Status>))
+)]
+async fn handle_jobs() -> Json<Vec<jobs::Status>> {
+    Json(jobs::global().statuses())
+}
+
+/// Per user, requests using the models and routes deprecated in conf, of
+/// each by name, most requests first.
+#[utoipa::path(
+    get,
+    path = "/deprecated",
+    tag = "admin",
+    params(SinceQuery),
+    responses((status = 200, body = Vec<data::DeprecatedUsage>))
+)]
+async fn handle_deprecated(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(SinceQuery { since }): Query<SinceQuery>,
+) -> Result<Json<Vec<data::DeprecatedUsage>>, StatusCode> {
+    let usage = storage.deprecated_usage(since).await.map_err(internal)?;
+    Ok(Json(usage))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TagsQuery {
+    /// Per value of just this one.
+    tag: Option<String>,
+
+    /// Unix time. All time when not given.
+    #[serde(default)]
+    since: i64,
+}
+
+/// Of all users, per tag and value, costliest first.
+#[utoipa::path(
+    get,
+    path = "/tags",
+    tag = "admin",
+    params(TagsQuery),
+    responses((status = 200, body = Vec<data::TagStats>))
+)]
+async fn handle_tags(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(TagsQuery { tag, since }): Query<TagsQuery>,
+) -> Result<Json<Vec<data::TagStats>>, StatusCode> {
+    let stats = storage
+        .tag_stats(None, tag.as_deref(), since)
+        .await
+        .map_err(internal)?;
+    Ok(Json(stats))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SinceQuery {
+    /// Unix time. All time when not given.
+    #[serde(default)]
+    since: i64,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BillingQuery {
+    /// YYYY-MM, UTC.
+    month: String,
+}
+
+/// Invoices of users and orgs for the month.
+#[utoipa::path(
+    get,
+    path = "/billing",
+    tag = "admin",
+    params(BillingQuery),
+    responses(
+        (status = 200, body = Vec<billing::Invoice>),
+        (status = 400, description = "Invalid month."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_billing(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(BillingQuery { month }): Query<BillingQuery>,
+) -> Result<Json<Vec<billing::Invoice>>, StatusCode> {
+    if let Err(error) = billing::month_bounds(&month) {
+        tracing::warn!(?error, "Rejecting billing query.");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let invoices = billing::invoices(&*storage, &month)
+        .await
+        .map_err(internal)?;
+    Ok(Json(invoices))
+}
+
+/// Usage in the units upstream measures it in, such as tokens, seconds of
+/// audio or images generated, per provider, model, endpoint and unit.
+#[utoipa::path(
+    get,
+    path = "/units",
+    tag = "admin",
+    params(SinceQuery),
+    responses((status = 200, body = Vec<data::UsageTotals>))
+)]
+async fn handle_units(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(SinceQuery { since }): Query<SinceQuery>,
+) -> Result<Json<Vec<data::UsageTotals>>, StatusCode> {
+    let totals = storage.usage_totals(None, since).await.map_err(internal)?;
+    Ok(Json(totals))
+}
+
+/// How the mirrored requests did with the shadow, next to the primary.
+#[utoipa::path(
+    get,
+    path = "/shadow",
+    tag = "admin",
+    params(SinceQuery),
+    responses((status = 200, body = Vec<data::ShadowComparison>))
+)]
+async fn handle_shadow(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(SinceQuery { since }): Query<SinceQuery>,
+) -> Result<Json<Vec<data::ShadowComparison>>, StatusCode> {
+    let comparisons =
+        storage.shadow_compare(since).await.map_err(internal)?;
+    Ok(Json(comparisons))
+}
+
+const LOGS_PER_PAGE_MAX: u64 = 500;
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LogsQuery {
+    uid: Option<String>,
+    model: Option<String>,
+    status: Option<i64>,
+
+    /// Unix time. `to` is exclusive.
+    from: Option<i64>,
+    to: Option<i64>,
+
+    /// From 1.
+    #[serde(default = "default_page")]
+    page: u64,
+
+    #[serde(default = "default_errors_limit")]
+    per_page: u64,
+}
+
+fn default_page() -> u64 {
+    1
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Logs {
+    page: u64,
+    per_page: u64,
+
+    /// Of all pages.
+    summary: data::LogSummary,
+
+    logs: Vec<data::RequestLog>,
+}
+
+/// Matching the filter, newest first, for looking into reports of failing
+/// requests. Anonymized for analysts, who may not filter by user either.
+#[utoipa::path(
+    get,
+    path = "/logs",
+    tag = "admin",
+    params(LogsQuery),
+    responses(
+        (status = 200, body = Logs),
+        (status = 400, description = "Invalid page."),
+        (status = 403, description = "Filtered by user, as an analyst."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_logs(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Json<Logs>, StatusCode> {
+    if query.page == 0
+        || query.per_page == 0
+        || query.per_page > LOGS_PER_PAGE_MAX
+    {
+        tracing::warn!(
+            page = query.page,
+            per_page = query.per_page,
+            "Rejecting logs query. Invalid page."
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let is_anonymized = USER.get().role == auth::ROLE_ANALYST;
+    if is_anonymized && query.uid.is_some() {
+        tracing::warn!("Rejecting. Analysts can't filter logs by user.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let filter = data::LogFilter {
+        uid: query.uid,
+        model: query.model,
+        status: query.status,
+        since: query.from,
+        until: query.to,
+        ..data::LogFilter::default()
+    };
+    let offset = (query.page - 1).saturating_mul(query.per_page);
+    let mut logs = storage
+        .request_logs(&filter, query.per_page, offset)
+        .await
+        .map_err(internal)?;
+    if is_anonymized {
+        logs.iter_mut().for_each(anonymize);
+    }
+    let summary = storage
+        .request_logs_summary(&filter)
+        .await
+        .map_err(internal)?;
+    Ok(Json(Logs {
+        page: query.page,
+        per_page: query.per_page,
+        summary,
+        logs,
+    }))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+    q: String,
+    #[serde(default = "default_errors_limit")]
+    limit: u64,
+}
+
+/// Captured bodies containing the phrase, newest first.
+#[utoipa::path(
+    get,
+    path = "/logs/search",
+    tag = "admin",
+    params(SearchQuery),
+    responses((status = 200, body = Vec<data::Capture>))
+)]
+async fn handle_logs_search(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(SearchQuery { q, limit }): Query<SearchQuery>,
+) -> Result<Json<Vec<data::Capture>>, StatusCode> {
+    let captures =
+        storage.capture_search(&q, limit).await.map_err(internal)?;
+    Ok(Json(captures))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ErrorsQuery {
+    #[serde(default = "default_errors_limit")]
+    limit: u64,
+}
+
+fn default_errors_limit() -> u64 {
+    50
+}
+
+/// Latest failed requests.
+#[utoipa::path(
+    get,
+    path = "/errors",
+    tag = "admin",
+    params(ErrorsQuery),
+    responses((status = 200, body = Vec<data::RequestLog>))
+)]
+async fn handle_errors(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(ErrorsQuery { limit }): Query<ErrorsQuery>,
+) -> Result<Json<Vec<data::RequestLog>>, StatusCode> {
+    let errors = storage.recent_errors(limit).await.map_err(internal)?;
+    Ok(Json(errors))
+}
+
+/// Server-sent events, as they happen, for a live wall.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "admin",
+    responses((status = 200, content_type = "text/event-stream"))
+)]
+async fn handle_events(
+    State(AppState { events, .. }): State<AppState>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    tracing::info!("Watching events.");
+    let stream = futures_util::stream::unfold(
+        events.subscribe(),
+        |mut receiver| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "Events watcher fell behind.");
+                        let lagged = sse::Event::default()
+                            .event("lagged")
+                            .data(missed.to_string());
+                        return Some((Ok(lagged), receiver));
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                match sse::Event::default()
+                    .event(event.name())
+                    .json_data(&event)
+                {
+                    Ok(sse_event) => return Some((Ok(sse_event), receiver)),
+                    Err(error) => {
+                        tracing::error!(?error, ?event, "Failed to encode.");
+                    }
+                }
+            }
+        },
+    );
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UsageQuery {
+    /// Key of a budget period, e.g. "YYYY-MM-DD". The current one when
+    /// not given.
+    date: Option<String>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct UserInfo {
+    account: Account,
+    usage: DailyUsage,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct SetMaxTokensPerDay {
+    /// `null` reverts to the global limit.
+    max_tokens_per_day: Option<u64>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct SetOrgBudget {
+    /// `null` reverts to the default.
+    max_tokens_per_day: Option<u64>,
+
+    /// `null` reverts to the default.
+    max_cost_per_day: Option<f64>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct SetOrgReservedRpm {
+    /// `null` reverts to the conf's.
+    reserved_rpm: Option<u64>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct GrantTokens {
+    tokens: u64,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Granted {
+    uid: String,
+    bonus_tokens_today: u64,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct GrantCredits {
+    tokens: u64,
+
+    /// Why, for the record.
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Credits {
+    uid: String,
+    balance: i64,
+
+    /// Newest first. Not when just granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ledger: Option<Vec<Credit>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{uid}",
+    tag = "admin",
+    params(("uid" = String, Path), UsageQuery),
+    responses(
+        (status = 200, body = UserInfo),
+        (status = 400, description = "Invalid date."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_user_get(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+    Query(UsageQuery { date }): Query<UsageQuery>,
+) -> Result<Json<UserInfo>, StatusCode> {
+    let date = date.unwrap_or_else(period::current);
+    let account = storage.account_get(&uid).await.map_err(internal)?;
+    let usage = storage.usage(&uid, &date).await.map_err(|error| {
+        tracing::debug!(?error, ?date, "Failed to get usage.");
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(Json(UserInfo { account, usage }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{uid}/max-tokens-per-day",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    request_body = SetMaxTokensPerDay,
+    responses((status = 200, body = Account))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_set_max_tokens_per_day(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+    Json(SetMaxTokensPerDay { max_tokens_per_day }): Json<SetMaxTokensPerDay>,
+) -> Result<Json<Account>, StatusCode> {
+    tracing::info!(?uid, ?max_tokens_per_day, "Setting token limit.");
+    let account = storage
+        .account_set_max_tokens_per_day(&uid, max_tokens_per_day)
+        .await
+        .map_err(internal)?;
+    Ok(Json(account))
+}
+
+#[utoipa::path(
+    put,
+    path = "/orgs/{org}/budget",
+    tag = "admin",
+    params(("org" = String, Path)),
+    request_body = SetOrgBudget,
+    responses((status = 200, body = Org))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_set_org_budget(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(org): Path<String>,
+    Json(SetOrgBudget {
+        max_tokens_per_day,
+        max_cost_per_day,
+    }): Json<SetOrgBudget>,
+) -> Result<Json<Org>, StatusCode> {
+    tracing::info!(
+        ?org,
+        ?max_tokens_per_day,
+        ?max_cost_per_day,
+        "Setting org budget."
+    );
+    let org = storage
+        .org_set_budget(&org, max_tokens_per_day, max_cost_per_day)
+        .await
+        .map_err(internal)?;
+    Ok(Json(org))
+}
+
+/// Of upstream's requests per minute, per `queue.upstream_rpm` in conf.
+/// Takes effect on other instances within a minute. Not beyond what
+/// upstream allows, with those of the other orgs.
+#[utoipa::path(
+    put,
+    path = "/orgs/{org}/reserved-rpm",
+    tag = "admin",
+    params(("org" = String, Path)),
+    request_body = SetOrgReservedRpm,
+    responses(
+        (status = 200, body = Org),
+        (status = 400, description = "More than upstream allows."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_set_org_reserved_rpm(
+    State(AppState { storage, queue, .. }): State<AppState>,
+    Path(org): Path<String>,
+    Json(SetOrgReservedRpm { reserved_rpm }): Json<SetOrgReservedRpm>,
+) -> Result<Json<Org>, StatusCode> {
+    tracing::info!(?org, ?reserved_rpm, "Setting org reservation.");
+    let conf = conf::global();
+    if let Some(
+        queue_conf @ conf::Queue {
+            upstream_rpm: Some(upstream_rpm),
+            ..
+        },
+    ) = &conf.queue
+    {
+        // As the queue has them, once this one is set.
+        let mut reserved = queue_conf.reserved_rpm.clone();
+        reserved.extend(storage.orgs_reserved_rpm().await.map_err(internal)?);
+        match reserved_rpm.or(queue_conf.reserved_rpm.get(&org).copied()) {
+            Some(reserved_rpm) => reserved.insert(org.clone(), reserved_rpm),
+            None => reserved.remove(&org),
+        };
+        let total: u64 = reserved.values().sum();
+        if total > *upstream_rpm {
+            tracing::warn!(
+                total,
+                upstream_rpm,
+                "Rejecting org reservation. More than upstream allows."
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    let org = storage
+        .org_set_reserved_rpm(&org, reserved_rpm)
+        .await
+        .map_err(internal)?;
+    if let Some(queue) = queue {
+        let reserved = storage.orgs_reserved_rpm().await.map_err(internal)?;
+        queue.set_reserved(reserved);
+    }
+    Ok(Json(org))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/grant-tokens",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    request_body = GrantTokens,
+    responses((status = 200, body = Granted))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_grant_tokens(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+    Json(GrantTokens { tokens }): Json<GrantTokens>,
+) -> Result<Json<Granted>, StatusCode> {
+    tracing::info!(?uid, tokens, "Granting tokens.");
+    let bonus_tokens_today = storage
+        .account_grant_tokens(&uid, tokens)
+        .await
+        .map_err(internal)?;
+    Ok(Json(Granted {
+        uid,
+        bonus_tokens_today,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{uid}/credits",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    responses((status = 200, body = Credits))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_credits_get(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+) -> Result<Json<Credits>, StatusCode> {
+    let balance = storage.credits_balance(&uid).await.map_err(internal)?;
+    let ledger = storage.credits_list(&uid).await.map_err(internal)?;
+    Ok(Json(Credits {
+        uid,
+        balance,
+        ledger: Some(ledger),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/credits",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    request_body = GrantCredits,
+    responses((status = 200, body = Credits))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_credits_grant(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+    Json(GrantCredits { tokens, note }): Json<GrantCredits>,
+) -> Result<Json<Credits>, StatusCode> {
+    tracing::info!(?uid, tokens, ?note, "Granting credits.");
+    let balance = storage
+        .credits_grant(&uid, tokens, note.as_deref())
+        .await
+        .map_err(internal)?;
+    Ok(Json(Credits {
+        uid,
+        balance,
+        ledger: None,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/suspend",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    responses((status = 200, body = Account))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_suspend(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+) -> Result<Json<Account>, StatusCode> {
+    tracing::info!(?uid, "Suspending.");
+    let account = storage
+        .account_set_suspended(&uid, true)
+        .await
+        .map_err(internal)?;
+    Ok(Json(account))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/unsuspend",
+    tag = "admin",
+    params(("uid" = String, Path)),
+    responses((status = 200, body = Account))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_unsuspend(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(uid): Path<String>,
+) -> Result<Json<Account>, StatusCode> {
+    tracing::info!(?uid, "Unsuspending.");
+    let account = storage
+        .account_set_suspended(&uid, false)
+        .await
+        .map_err(internal)?;
+    Ok(Json(account))
+}
+
+/// Both automatic and by admins. Lifted by unsuspending.
+#[utoipa::path(
+    get,
+    path = "/suspensions",
+    tag = "admin",
+    responses((status = 200, body = Vec<Suspension>))
+)]
+async fn handle_suspensions(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Vec<Suspension>>, StatusCode> {
+    let suspensions = storage.suspension_list().await.map_err(internal)?;
+    Ok(Json(suspensions))
+}
+
+#[allow(clippy::needless_pass_by_value)] // For use in map_err.
+fn internal(error: anyhow::Error) -> StatusCode {
+    tracing::error!(?error, "Failed to hit storage.");
+    StatusCode::SERVICE_UNAVAILABLE
+}
@@ -0,0 +1,92 @@
+//! Attestations of responses, per [`crate::conf::Attestation`], so that
+//! those handed a completion, such as judges, can check that it came
+//! through us, for which user, and find it in the request logs by its
+//! request ID.
+//!
+//! An attestation is `<payload>.<signature>`, both base64url without
+//! padding: the payload is the JSON of [`Attested`], and the signature its
+//! HMAC-SHA256 with the shared secret.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+pub const HEADER: &str = "x-raskol-attestation";
+
+#[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct Attested {
+    pub uid: String,
+    pub req_id: String,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+
+    /// Of the response body, in hex.
+    pub sha256: String,
+}
+
+#[must_use]
+pub fn attest(
+    secret: &str,
+    uid: &str,
+    req_id: &str,
+    time: i64,
+    body: &[u8],
+) -> String {
+    let payload = serde_json::json!({
+        "uid": uid,
+        "req_id": req_id,
+        "time": time,
+        "sha256": digest(body),
+    });
+    let payload = URL_SAFE_NO_PAD.encode(payload.to_string());
+    let signature = hmac::sign(&key(secret), payload.as_bytes());
+    format!("{payload}.{}", URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// What it attests to, if signed with the secret, and of the body.
+#[must_use]
+pub fn verify(
+    secret: &str,
+    attestation: &str,
+    body: &[u8],
+) -> Option<Attested> {
+    let (payload, signature) = attestation.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    hmac::verify(&key(secret), payload.as_bytes(), &signature).ok()?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let attested: Attested = serde_json::from_slice(&payload).ok()?;
+    (attested.sha256 == digest(body)).then_some(attested)
+}
+
+fn key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+fn digest(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attest, verify};
+
+    #[test]
+    fn round_trip() {
+        let body = br#"{"choices":[]}"#;
+        let attestation =
+            attest("s3cret", "ada", "req-1", 1_700_000_000, body);
+        let attested = verify("s3cret", &attestation, body).unwrap();
+        assert_eq!(attested.uid, "ada");
+        assert_eq!(attested.req_id, "req-1");
+        assert_eq!(attested.time, 1_700_000_000);
+        assert!(verify("guess", &attestation, body).is_none());
+        assert!(verify("s3cret", &attestation, b"{}").is_none());
+
+        let (_, signature) = attestation.split_once('.').unwrap();
+        let forged = attest("guess", "eve", "req-1", 1_700_000_000, body);
+        let (payload, _) = forged.split_once('.').unwrap();
+        let forged = format!("{payload}.{signature}");
+        assert!(verify("s3cret", &forged, body).is_none());
+    }
+}
@@ -0,0 +1,144 @@
+//! Whisper-style audio endpoints, which take multipart/form-data uploads
+//! rather than JSON. Uploads are passed through to upstream as is; we only
+//! peek at them for the model and the size.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, Request},
+    http::{header, StatusCode},
+};
+
+use crate::{conf, server};
+
+pub struct Upload {
+    pub model: String,
+
+    /// Including the multipart boundary, so must be passed on unchanged.
+    pub content_type: String,
+
+    pub body: Bytes,
+}
+
+impl Upload {
+    pub async fn from_req(
+        req: Request,
+        max_bytes: usize,
+    ) -> Result<Self, StatusCode> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with("multipart/form-data"))
+            .ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?
+            .to_string();
+        let body = axum::body::to_bytes(req.into_body(), max_bytes)
+            .await
+            .map_err(|error| {
+                tracing::warn!(?error, "Failed to read upload.");
+                server::body_unread(&error)
+            })?;
+        let model = model(&content_type, body.clone()).await?;
+        Ok(Self {
+            model,
+            content_type,
+            body,
+        })
+    }
+
+    /// Audio duration, assuming the configured bit rate.
+    #[must_use]
+    pub fn duration_estimate(&self, conf: &conf::Audio) -> f64 {
+        #[allow(clippy::cast_precision_loss)] // Uploads are nowhere near.
+        let size = self.body.len() as f64;
+        size / conf.bytes_per_second.max(1.0)
+    }
+}
+
+#[must_use]
+pub fn is_endpoint(endpoint: &str) -> bool {
+    endpoint.ends_with("audio/transcriptions")
+        || endpoint.ends_with("audio/translations")
+}
+
+/// Audio duration in seconds, as reported in `verbose_json` responses.
+#[must_use]
+pub fn duration_from_resp_body(body: &str) -> Option<f64> {
+    #[derive(serde::Deserialize)]
+    struct Resp {
+        duration: Option<f64>,
+    }
+
+    serde_json::from_str::<Resp>(body).ok()?.duration
+}
+
+/// Audio is charged against the token budgets at a fixed rate per second.
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn tokens(conf: &conf::Audio, duration: f64) -> usize {
+    // Rounded up and clamped to be non-negative.
+    (duration * conf.tokens_per_second).ceil().max(0.0) as usize
+}
+
+async fn model(
+    content_type: &str,
+    body: Bytes,
+) -> Result<String, StatusCode> {
+    let req = Request::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to rebuild upload request.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut multipart =
+        Multipart::from_request(req, &()).await.map_err(|error| {
+            tracing::warn!(?error, "Invalid multipart upload.");
+            StatusCode::BAD_REQUEST
+        })?;
+    while let Some(field) = multipart.next_field().await.map_err(|error| {
+        tracing::warn!(?error, "Invalid multipart upload.");
+        StatusCode::BAD_REQUEST
+    })? {
+        if field.name() == Some("model") {
+            return field.text().await.map_err(|error| {
+                tracing::warn!(?error, "Invalid model field.");
+                StatusCode::BAD_REQUEST
+            });
+        }
+    }
+    tracing::warn!("Upload lacks a model.");
+    Err(StatusCode::BAD_REQUEST)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::header};
+
+    use super::Upload;
+
+    #[tokio::test]
+    async fn upload() {
+        let body = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; \
+            filename=\"a.mp3\"\r\n\
+            Content-Type: audio/mpeg\r\n\r\n\
+            0123456789\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"model\"\r\n\r\n\
+            whisper-large-v3\r\n\
+            --XYZ--\r\n";
+        let req = || {
+            Request::builder()
+                .header(
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=XYZ",
+                )
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let upload = Upload::from_req(req(), 1024).await.unwrap();
+        assert_eq!(upload.model, "whisper-large-v3");
+        assert_eq!(upload.body.len(), body.len());
+        assert!(Upload::from_req(req(), 16).await.is_err());
+    }
+}
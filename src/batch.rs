@@ -0,0 +1,446 @@
+//! Chat requests submitted together, one JSON per line, and processed in
+//! the background as the user's rate limits and budgets allow. Each goes
+//! through the same handling as if the user made it then.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+
+use crate::{
+    chat, conf,
+    data::{self, Batch, BatchStatus},
+    deprecation, jobs, ratelimit,
+    server::{self, AppState, ReqId, User, REQ_ID, USER},
+};
+
+const PROCESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Of an item, by an instance processing it. Longer than any request takes.
+const CLAIM: Duration = Duration::from_secs(10 * 60);
+
+/// Of a user's batches, once one of their items was turned away, unless
+/// told how long. Not to log a rejection of each at every tick.
+const BACKOFF: Duration = Duration::from_secs(60);
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Of an item.
+enum Handled {
+    Responded {
+        code: i64,
+        response: String,
+    },
+
+    /// As the user can't make the request for now: they're suspended,
+    /// outside their role's access windows, rate limited, out of budget or
+    /// upstream throttles. For this long, at
+    /// least.
+    Waits(Duration),
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(
+    handle_create,
+    handle_list,
+    handle_get,
+    handle_results,
+    handle_cancel
+))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/batches", get(handle_list).post(handle_create))
+        .route("/batches/:id", get(handle_get))
+        .route("/batches/:id/results", get(handle_results))
+        .route("/batches/:id/cancel", post(handle_cancel))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CreateQuery {
+    /// To send each of the requests to.
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+}
+
+fn default_endpoint() -> String {
+    "v1/chat/completions".to_string()
+}
+
+#[utoipa::path(
+    post,
+    path = "/batches",
+    tag = "batches",
+    params(CreateQuery),
+    request_body(
+        content = String,
+        description = "Chat requests, one JSON per line.",
+        content_type = "application/x-ndjson"
+    ),
+    responses(
+        (status = 201, body = Batch),
+        (status = 400, body = chat::Error),
+        (status = 403, body = chat::Error),
+    )
+)]
+async fn handle_create(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(CreateQuery { endpoint }): Query<CreateQuery>,
+    body: String,
+) -> axum::response::Result<(StatusCode, Json<Batch>)> {
+    let user = USER.get();
+    let conf = conf::global();
+    let max = conf.batches.max_requests;
+    let missing = |needed: &[conf::Feature]| {
+        server::missing_feature(&conf, &user, needed)
+            .map(server::feature_not_allowed)
+    };
+    if let Some(resp) = missing(&[conf::Feature::Batch]) {
+        return Err(resp.into());
+    }
+    let mut requests = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Parsed, to reject what would fail anyway, before queuing it.
+        let chat_req: chat::Req =
+            serde_json::from_str(line).map_err(|error| {
+                tracing::warn!(line = i + 1, ?error, "Rejecting batch.");
+                let error = chat::Error::new(
+                    "invalid_request_error",
+                    "invalid_batch",
+                    format!(
+                        "Invalid chat request on line {}: {error}",
+                        i + 1
+                    ),
+                );
+                (StatusCode::BAD_REQUEST, Json(error))
+            })?;
+        if let Some(resp) = missing(&chat_req.features()) {
+            return Err(resp.into());
+        }
+        requests.push(serde_json::to_string(&chat_req).map_err(|error| {
+            tracing::error!(?error, "Failed to encode chat request.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?);
+    }
+    if requests.is_empty() || requests.len() > max {
+        tracing::warn!(count = requests.len(), "Rejecting batch.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "invalid_batch",
+            format!("A batch must have 1 to {max} requests."),
+        );
+        return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+    }
+    let batch = Batch {
+        id: format!("batch_{}", cuid2::create_id()),
+        uid: user.uid,
+        role: user.role,
+        org: user.org,
+        budget_overrides: user.budget_overrides,
+        features: user.features,
+        endpoint,
+        status: BatchStatus::Queued,
+        total: u64::try_from(requests.len()).unwrap_or(u64::MAX),
+        completed: 0,
+        failed: 0,
+        time_created: server::unix_now_secs(),
+        time_finished: None,
+    };
+    storage
+        .batch_create(&batch, &requests)
+        .await
+        .map_err(internal)?;
+    tracing::info!(id = batch.id, total = batch.total, "Batch queued.");
+    Ok((StatusCode::CREATED, Json(batch)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/batches",
+    tag = "batches",
+    responses((status = 200, body = Vec<Batch>))
+)]
+async fn handle_list(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Vec<Batch>>, StatusCode> {
+    let batches = storage
+        .batch_list(&USER.get().uid)
+        .await
+        .map_err(internal)?;
+    Ok(Json(batches))
+}
+
+#[utoipa::path(
+    get,
+    path = "/batches/{id}",
+    tag = "batches",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = Batch),
+        (status = 404, description = "No such batch of the user."),
+    )
+)]
+async fn handle_get(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Batch>, StatusCode> {
+    Ok(Json(own(storage.as_ref(), &id).await?))
+}
+
+/// One JSON per line, of the items which have responses, in order.
+#[utoipa::path(
+    get,
+    path = "/batches/{id}/results",
+    tag = "batches",
+    params(("id" = String, Path)),
+    responses(
+        (
+            status = 200,
+            body = String,
+            content_type = "application/x-ndjson"
+        ),
+        (status = 404, description = "No such batch of the user."),
+    )
+)]
+async fn handle_results(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let batch = own(storage.as_ref(), &id).await?;
+    let items = storage.batch_items(&batch.id).await.map_err(internal)?;
+    let mut lines = String::new();
+    for item in items {
+        let (Some(code), Some(response)) = (item.code, item.response) else {
+            continue;
+        };
+        let response = serde_json::from_str(&response)
+            .unwrap_or(serde_json::Value::String(response));
+        let line = serde_json::json!({
+            "index": item.index,
+            "status": code,
+            "response": response,
+        });
+        lines.push_str(&line.to_string());
+        lines.push('\n');
+    }
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], lines))
+}
+
+#[utoipa::path(
+    post,
+    path = "/batches/{id}/cancel",
+    tag = "batches",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = Batch),
+        (status = 404, description = "No such batch of the user."),
+        (status = 409, description = "Already finished."),
+    )
+)]
+async fn handle_cancel(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Batch>, StatusCode> {
+    let batch = own(storage.as_ref(), &id).await?;
+    if !storage.batch_cancel(&batch.id).await.map_err(internal)? {
+        tracing::warn!(id, "Rejecting. Batch already finished.");
+        return Err(StatusCode::CONFLICT);
+    }
+    tracing::info!(id, "Batch cancelled.");
+    Ok(Json(own(storage.as_ref(), &id).await?))
+}
+
+/// Of the user. Others' are as good as missing.
+async fn own(
+    storage: &dyn data::Storage,
+    id: &str,
+) -> Result<Batch, StatusCode> {
+    storage
+        .batch_get(id)
+        .await
+        .map_err(internal)?
+        .filter(|batch| batch.uid == USER.get().uid)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+fn internal(error: anyhow::Error) -> StatusCode {
+    tracing::error!(?error, "Failed to hit storage.");
+    StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// As the "process_batches" job, per [`jobs`].
+pub(crate) fn spawn(state: AppState) {
+    // Of users, until when their batches wait.
+    let waiting = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+    jobs::spawn("process_batches", PROCESS_INTERVAL, move || {
+        let (state, waiting) = (state.clone(), waiting.clone());
+        async move {
+            process(&state, &mut *waiting.lock().await)
+                .await
+                .context("Failed to process batches.")
+        }
+    });
+}
+
+/// An item of each pending batch at a time, so that a big one doesn't hold
+/// up the others, until all are done or their users can make no more
+/// requests for now.
+async fn process(
+    state: &AppState,
+    waiting: &mut HashMap<String, Instant>,
+) -> anyhow::Result<()> {
+    let mut batches = state.storage.batch_pending().await?;
+    while !batches.is_empty() {
+        let mut pending = Vec::new();
+        for batch in batches {
+            let now = Instant::now();
+            waiting.retain(|_, until| *until > now);
+            if waiting.contains_key(&batch.uid) {
+                continue;
+            }
+            let now = server::unix_now_secs();
+            let until = now.saturating_add_unsigned(CLAIM.as_secs());
+            let Some(item) = state
+                .storage
+                .batch_item_claim(&batch.id, now, until)
+                .await?
+            else {
+                continue;
+            };
+            let handled = process_item(state, &batch, &item).await;
+            let (code, response) = match handled {
+                Ok(Handled::Responded { code, response }) => (code, response),
+                Ok(Handled::Waits(wait)) => {
+                    tracing::debug!(uid = batch.uid, ?wait, "Batch waits.");
+                    waiting.insert(batch.uid.clone(), Instant::now() + wait);
+                    state
+                        .storage
+                        .batch_item_release(&batch.id, item.index)
+                        .await?;
+                    continue;
+                }
+                Err(error) => {
+                    state
+                        .storage
+                        .batch_item_release(&batch.id, item.index)
+                        .await?;
+                    return Err(error);
+                }
+            };
+            if state
+                .storage
+                .batch_item_complete(&batch.id, item.index, code, &response)
+                .await?
+            {
+                pending.push(batch);
+            } else {
+                tracing::info!(id = batch.id, "Batch finished.");
+            }
+        }
+        batches = pending;
+    }
+    Ok(())
+}
+
+/// Checked beforehand where that's cheap, as rejections by the handler
+/// are logged, as any request's.
+async fn process_item(
+    state: &AppState,
+    batch: &Batch,
+    item: &data::BatchItem,
+) -> anyhow::Result<Handled> {
+    if state.storage.account_get(&batch.uid).await?.is_suspended {
+        return Ok(Handled::Waits(BACKOFF_MAX));
+    }
+    if server::access_closed(&batch.role).is_some() {
+        return Ok(Handled::Waits(BACKOFF_MAX));
+    }
+    if let Some(throttled) = state.upstream.throttled_for() {
+        return Ok(Handled::Waits(throttled));
+    }
+    let storage = state.storage.as_ref();
+    if let Err(rejection) =
+        ratelimit::check(storage, &batch.uid, &batch.role).await?
+    {
+        return Ok(Handled::Waits(rejection.retry_after()));
+    }
+    let user = User {
+        uid: batch.uid.clone(),
+        role: batch.role.clone(),
+        org: batch.org.clone(),
+        budget_overrides: batch.budget_overrides,
+        features: batch.features.clone(),
+    };
+    let req_id = ReqId {
+        req_id: format!("{}-{}", batch.id, item.index),
+        client_ip: None,
+    };
+    let req = axum::http::Request::post(format!("/{}", batch.endpoint))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(item.request.clone()))?;
+    let handling = server::handle_api(
+        State(state.clone()),
+        ConnectInfo(server::LOCALHOST),
+        Path(batch.endpoint.clone()),
+        req,
+    );
+    let handling = deprecation::count_model(state.storage.clone(), handling);
+    let resp = match USER.scope(user, REQ_ID.scope(req_id, handling)).await {
+        Ok(resp) => resp.into_response(),
+        Err(error) => error.into_response(),
+    };
+    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Ok(Handled::Waits(wait(resp.headers())));
+    }
+    let code = i64::from(resp.status().as_u16());
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+    Ok(Handled::Responded {
+        code,
+        response: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Of a 429, as it says, in seconds, of `Retry-After`, or else of the
+/// budget's reset, up to a few minutes, as credits may come sooner.
+fn wait(headers: &HeaderMap) -> Duration {
+    [header::RETRY_AFTER.as_str(), "x-ratelimit-reset"]
+        .into_iter()
+        .find_map(|name| headers.get(name)?.to_str().ok()?.parse().ok())
+        .map_or(BACKOFF, Duration::from_secs)
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    #[test]
+    fn wait() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::wait(&headers), super::BACKOFF);
+        headers.insert("x-ratelimit-reset", HeaderValue::from(40_000));
+        assert_eq!(super::wait(&headers), super::BACKOFF_MAX);
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(3));
+        assert_eq!(super::wait(&headers), Duration::from_secs(3));
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("?"));
+        assert_eq!(super::wait(&headers), super::BACKOFF_MAX);
+    }
+}
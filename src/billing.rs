@@ -0,0 +1,325 @@
+//! Monthly invoices of users and orgs, from the request logs, for charging
+//! back what was spent after the event.
+//!
+//! Usage is only logged per user, so an org's invoice is the sum of its
+//! members' whole usage, including any made as members of other orgs or of
+//! none.
+//!
+//! Usage which credits covered, per [`Storage::credits_used`], was paid for
+//! up front, so is not charged again: each user's cost is cut by the share
+//! of their budgeted tokens which credits covered.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use chrono::{Months, NaiveDate};
+
+use crate::data::{ReportRow, Storage};
+
+#[derive(
+    serde::Serialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Account {
+    User,
+    Org,
+}
+
+impl Account {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Org => "org",
+        }
+    }
+}
+
+impl FromStr for Account {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "user" => Ok(Self::User),
+            "org" => Ok(Self::Org),
+            _ => Err(anyhow!("Invalid account, expected user or org: {s:?}")),
+        }
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub account: Account,
+
+    /// The uid or org.
+    pub id: String,
+
+    /// As YYYY-MM, or, of a day's invoices, as YYYY-MM-DD.
+    pub month: String,
+
+    pub total: Line,
+
+    /// By model, most spent on first.
+    pub models: Vec<Line>,
+}
+
+#[derive(
+    serde::Serialize, utoipa::ToSchema, Debug, Clone, Default, PartialEq,
+)]
+pub struct Line {
+    /// Empty in totals.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub model: String,
+
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+
+    /// Less what credits covered.
+    pub cost: f64,
+
+    /// Of the cost, what credits covered.
+    pub credited: f64,
+
+    /// Of the input and output tokens, those credits covered.
+    pub credited_tokens: u64,
+}
+
+/// Of users, then orgs, each ordered by ID.
+pub async fn invoices(
+    storage: &dyn Storage,
+    month: &str,
+) -> anyhow::Result<Vec<Invoice>> {
+    let (from, to) = month_bounds(month)?;
+    let from = from.format("%Y-%m-%d").to_string();
+    let to = to.format("%Y-%m-%d").to_string();
+    let rows = storage.report(&from, &to, None).await?;
+    let credited = storage.credits_used(&from, &to).await?;
+    let members = storage.org_members().await?;
+    Ok(aggregate(month, &rows, &credited, &members))
+}
+
+/// As [`invoices`], for a single day, as YYYY-MM-DD.
+pub async fn daily(
+    storage: &dyn Storage,
+    date: &str,
+) -> anyhow::Result<Vec<Invoice>> {
+    let rows = storage.report(date, date, None).await?;
+    let credited = storage.credits_used(date, date).await?;
+    let members = storage.org_members().await?;
+    Ok(aggregate(date, &rows, &credited, &members))
+}
+
+/// First and last day of a YYYY-MM month.
+pub fn month_bounds(month: &str) -> anyhow::Result<(NaiveDate, NaiveDate)> {
+    let first = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+        .context(format!("Invalid month, expected YYYY-MM: {month:?}"))?;
+    let last = first
+        .checked_add_months(Months::new(1))
+        .and_then(|next| next.pred_opt())
+        .context(format!("Month out of range: {month:?}"))?;
+    Ok((first, last))
+}
+
+/// Lines of the invoices, one per account and model, with a header.
+#[must_use]
+pub fn csv(invoices: &[Invoice]) -> String {
+    let mut csv = String::from(
+        "month,account,id,model,requests,input_tokens,output_tokens,cost,\
+        credited\n",
+    );
+    for invoice in invoices {
+        for line in &invoice.models {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{}\n",
+                invoice.month,
+                invoice.account.as_str(),
+                csv_escape(&invoice.id),
+                csv_escape(&line.model),
+                line.requests,
+                line.input_tokens,
+                line.output_tokens,
+                line.cost,
+                line.credited
+            ));
+        }
+    }
+    csv
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn aggregate(
+    month: &str,
+    rows: &[ReportRow],
+    credited: &[(String, u64)],
+    members: &[(String, String)],
+) -> Vec<Invoice> {
+    let mut budgeted: BTreeMap<&str, u64> = BTreeMap::new();
+    for row in rows {
+        *budgeted.entry(row.uid.as_str()).or_default() += row.budget_tokens;
+    }
+    // Of each user's cost, the share which credits covered.
+    let covered: BTreeMap<&str, f64> = credited
+        .iter()
+        .filter_map(|(uid, tokens)| {
+            let of = *budgeted.get(uid.as_str())?;
+            #[allow(clippy::cast_precision_loss)] // Nowhere near.
+            let share = (*tokens as f64 / of.max(1) as f64).min(1.0);
+            Some((uid.as_str(), share))
+        })
+        .collect();
+    let mut users: BTreeMap<&str, BTreeMap<&str, Line>> = BTreeMap::new();
+    for row in rows {
+        let share = covered.get(row.uid.as_str()).copied().unwrap_or(0.0);
+        let tokens = row.input_tokens.saturating_add(row.output_tokens);
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let credited_tokens = (tokens as f64 * share).round() as u64;
+        let line = users
+            .entry(row.uid.as_str())
+            .or_default()
+            .entry(row.model.as_str())
+            .or_default();
+        line.requests += row.requests;
+        line.input_tokens += row.input_tokens;
+        line.output_tokens += row.output_tokens;
+        line.cost += row.cost * (1.0 - share);
+        line.credited += row.cost * share;
+        line.credited_tokens += credited_tokens;
+    }
+    let mut orgs: BTreeMap<&str, BTreeMap<&str, Line>> = BTreeMap::new();
+    for (org, uid) in members {
+        let Some(models) = users.get(uid.as_str()) else {
+            continue;
+        };
+        let org = orgs.entry(org.as_str()).or_default();
+        for (model, used) in models {
+            let line = org.entry(model).or_default();
+            line.requests += used.requests;
+            line.input_tokens += used.input_tokens;
+            line.output_tokens += used.output_tokens;
+            line.cost += used.cost;
+            line.credited += used.credited;
+            line.credited_tokens += used.credited_tokens;
+        }
+    }
+    let invoice = |account, id: &str, models: BTreeMap<&str, Line>| {
+        let mut total = Line::default();
+        let mut models: Vec<Line> = models
+            .into_iter()
+            .map(|(model, line)| {
+                total.requests += line.requests;
+                total.input_tokens += line.input_tokens;
+                total.output_tokens += line.output_tokens;
+                total.cost += line.cost;
+                total.credited += line.credited;
+                total.credited_tokens += line.credited_tokens;
+                Line {
+                    model: model.to_string(),
+                    ..line
+                }
+            })
+            .collect();
+        models.sort_by(|a, b| b.cost.total_cmp(&a.cost));
+        Invoice {
+            account,
+            id: id.to_string(),
+            month: month.to_string(),
+            total,
+            models,
+        }
+    };
+    let users = users
+        .into_iter()
+        .map(|(uid, models)| invoice(Account::User, uid, models));
+    let orgs = orgs
+        .into_iter()
+        .map(|(org, models)| invoice(Account::Org, org, models));
+    users.chain(orgs).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{aggregate, month_bounds, Account};
+    use crate::data::ReportRow;
+
+    #[test]
+    fn bounds() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        assert_eq!(
+            month_bounds("2025-06").unwrap(),
+            (date("2025-06-01"), date("2025-06-30"))
+        );
+        assert_eq!(
+            month_bounds("2024-02").unwrap(),
+            (date("2024-02-01"), date("2024-02-29"))
+        );
+        assert!(month_bounds("2025-13").is_err());
+        assert!(month_bounds("June").is_err());
+    }
+
+    #[test]
+    fn invoices() {
+        let row = |date: &str, uid: &str, model: &str, cost| ReportRow {
+            date: date.to_string(),
+            uid: uid.to_string(),
+            model: model.to_string(),
+            requests: 1,
+            input_tokens: 10,
+            output_tokens: 5,
+            budget_tokens: 15,
+            cost,
+            ..ReportRow::default()
+        };
+        let rows = [
+            row("2025-06-01", "ada", "gpt-4o", 0.5),
+            row("2025-06-02", "ada", "gpt-4o", 0.5),
+            row("2025-06-02", "ada", "gpt-4o-mini", 0.25),
+            row("2025-06-02", "bob", "gpt-4o-mini", 0.5),
+        ];
+        let members = [
+            ("team".to_string(), "ada".to_string()),
+            ("team".to_string(), "bob".to_string()),
+            ("idle".to_string(), "eve".to_string()),
+        ];
+        // Of bob's 15 tokens, 5 were covered by credits.
+        let credited = [("bob".to_string(), 5)];
+        let invoices = aggregate("2025-06", &rows, &credited, &members);
+        assert_eq!(invoices.len(), 3);
+
+        let ada = &invoices[0];
+        assert_eq!((ada.account, ada.id.as_str()), (Account::User, "ada"));
+        assert_eq!(ada.total.requests, 3);
+        assert_eq!(ada.total.input_tokens, 30);
+        assert!((ada.total.cost - 1.25).abs() < 1e-9);
+        assert!(ada.total.credited.abs() < 1e-9);
+        assert_eq!(ada.models[0].model, "gpt-4o");
+        assert_eq!(ada.models[0].requests, 2);
+
+        let bob = &invoices[1];
+        assert!((bob.total.cost - 0.5 * 2.0 / 3.0).abs() < 1e-9);
+        assert!((bob.total.credited - 0.5 / 3.0).abs() < 1e-9);
+        assert_eq!(bob.total.credited_tokens, 5);
+
+        let team = &invoices[2];
+        assert_eq!((team.account, team.id.as_str()), (Account::Org, "team"));
+        assert_eq!(team.total.requests, 4);
+        assert!((team.total.cost - (1.75 - 0.5 / 3.0)).abs() < 1e-9);
+        assert!((team.total.credited - 0.5 / 3.0).abs() < 1e-9);
+        assert_eq!(team.models[0].model, "gpt-4o");
+        assert_eq!(team.models[1].model, "gpt-4o-mini");
+        assert_eq!(team.models[1].requests, 2);
+    }
+}
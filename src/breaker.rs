@@ -0,0 +1,204 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::conf;
+
+/// Circuit breaker around an upstream provider, so that when it is down we
+/// fail fast instead of making every request wait for a connect timeout.
+///
+/// - closed: requests pass, consecutive failures are counted;
+/// - open: requests are rejected until `open_duration` passes;
+/// - half-open: a single probe request is let through, which closes the
+///   circuit if it succeeds and re-opens it if it fails.
+pub struct Breaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Closed { .. } => "closed",
+            Self::Open { .. } => "open",
+            Self::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    fn gauge(&self) -> f64 {
+        match self {
+            Self::Closed { .. } => 0.0,
+            Self::HalfOpen { .. } => 1.0,
+            Self::Open { .. } => 2.0,
+        }
+    }
+}
+
+impl Breaker {
+    #[must_use]
+    pub fn new(name: &str, conf: &conf::CircuitBreaker) -> Self {
+        let state = State::Closed {
+            consecutive_failures: 0,
+        };
+        metrics::gauge!(
+            "raskol_upstream_circuit_state",
+            "provider" => name.to_string()
+        )
+        .set(state.gauge());
+        Self {
+            name: name.to_string(),
+            failure_threshold: conf.failure_threshold.max(1),
+            open_duration: Duration::from_secs_f32(conf.open_duration),
+            state: Mutex::new(state),
+        }
+    }
+
+    /// "closed", "open" or "half_open".
+    pub fn state_name(&self) -> &'static str {
+        self.state().name()
+    }
+
+    /// Returns `Err(retry_after)` if the request should not be attempted.
+    pub fn allow(&self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if until > now => {
+                Err(until.saturating_duration_since(now))
+            }
+            State::Open { .. } => {
+                self.transition(
+                    &mut state,
+                    State::HalfOpen { probe_started: now },
+                );
+                Ok(())
+            }
+            // A probe which never reported back (e.g. the client went away)
+            // should not keep the circuit half-open forever.
+            State::HalfOpen { probe_started }
+                if now.saturating_duration_since(probe_started)
+                    > self.open_duration =>
+            {
+                *state = State::HalfOpen { probe_started: now };
+                Ok(())
+            }
+            State::HalfOpen { probe_started } => {
+                Err(self.open_duration.saturating_sub(
+                    now.saturating_duration_since(probe_started),
+                ))
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state();
+        let closed = State::Closed {
+            consecutive_failures: 0,
+        };
+        match *state {
+            State::Closed { .. } => *state = closed,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                self.transition(&mut state, closed);
+            }
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state();
+        let open = State::Open {
+            until: Instant::now() + self.open_duration,
+        };
+        match *state {
+            State::Closed {
+                consecutive_failures,
+            } => {
+                let consecutive_failures =
+                    consecutive_failures.saturating_add(1);
+                if consecutive_failures >= self.failure_threshold {
+                    self.transition(&mut state, open);
+                } else {
+                    *state = State::Closed {
+                        consecutive_failures,
+                    };
+                }
+            }
+            State::HalfOpen { .. } => self.transition(&mut state, open),
+            // Already open. Requests which started before it opened.
+            State::Open { .. } => {}
+        }
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        tracing::warn!(
+            provider = self.name,
+            from = state.name(),
+            to = next.name(),
+            "Circuit breaker state change."
+        );
+        metrics::counter!(
+            "raskol_upstream_circuit_transitions_total",
+            "provider" => self.name.clone(),
+            "to" => next.name()
+        )
+        .increment(1);
+        metrics::gauge!(
+            "raskol_upstream_circuit_state",
+            "provider" => self.name.clone()
+        )
+        .set(next.gauge());
+        *state = next;
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Breaker;
+    use crate::conf;
+
+    #[test]
+    fn opens_and_recovers() {
+        let breaker = Breaker::new(
+            "test",
+            &conf::CircuitBreaker {
+                failure_threshold: 2,
+                open_duration: 0.05,
+            },
+        );
+        breaker.record_failure();
+        assert!(breaker.allow().is_ok());
+        breaker.record_failure();
+        assert!(breaker.allow().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        // Half-open: only a single probe goes through.
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err());
+        breaker.record_failure();
+        assert!(breaker.allow().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow().is_ok());
+        breaker.record_success();
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -1,37 +1,827 @@
-#[derive(serde::Serialize, serde::Deserialize)]
+use std::collections::BTreeMap;
+
+use axum::http::StatusCode;
+
+use crate::{
+    conf::{self, RoleLimits},
+    data, tokenizer,
+};
+
+/// As OpenAI limits metadata.
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 64;
+const MAX_TAG_VALUE_LEN: usize = 512;
+
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Clone)]
 pub struct Req {
     pub model: String,
     pub messages: Vec<Msg>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+
+    /// Whatever else the client asked for (tools, response_format, seed,
+    /// etc.), passed through as is.
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Req {
-    pub fn tokens_estimate(&self) -> usize {
-        self.messages.iter().map(Msg::tokens_estimate).sum()
+    /// Brings the request within the role's limits, or explains why it
+    /// cannot be.
+    pub fn constrain(&mut self, limits: &RoleLimits) -> Result<(), String> {
+        if let Some(max) = limits.max_messages {
+            if self.messages.len() > max {
+                return Err(format!(
+                    "Too many messages: {}. Allowed: {max}.",
+                    self.messages.len()
+                ));
+            }
+        }
+        if let Some(max) = limits.max_tokens {
+            match self.max_tokens {
+                Some(requested) if requested > max && !limits.clamp => {
+                    return Err(format!(
+                        "max_tokens too large: {requested}. Allowed: {max}."
+                    ));
+                }
+                Some(requested) if requested <= max => {}
+                Some(_) | None => self.max_tokens = Some(max),
+            }
+        }
+        if let Some(requested) = self.temperature {
+            let min = limits.min_temperature.unwrap_or(f64::MIN);
+            let max = limits.max_temperature.unwrap_or(f64::MAX);
+            if !(min..=max).contains(&requested) {
+                if !limits.clamp {
+                    return Err(format!(
+                        "temperature out of range: {requested}. \
+                        Allowed: {min}..={max}."
+                    ));
+                }
+                self.temperature = Some(requested.clamp(min, max));
+            }
+        }
+        Ok(())
+    }
+
+    /// Of the request, for usage to be reported by: the text ones of
+    /// `metadata`, less the session, which is passed on as OpenAI takes
+    /// it, and those of `tags`, which is ours, so taken out.
+    pub fn take_tags(&mut self) -> Result<BTreeMap<String, String>, String> {
+        let mut tags = BTreeMap::new();
+        if let Some(metadata) =
+            self.rest.get("metadata").and_then(|m| m.as_object())
+        {
+            tags.extend(
+                metadata
+                    .iter()
+                    .filter(|(tag, _)| *tag != "session")
+                    .filter_map(|(tag, value)| {
+                        Some((tag.clone(), value.as_str()?.to_string()))
+                    }),
+            );
+        }
+        match self.rest.remove("tags") {
+            None => {}
+            Some(serde_json::Value::Object(ours)) => {
+                for (tag, value) in ours {
+                    let serde_json::Value::String(value) = value else {
+                        return Err(format!("Tag {tag:?} is not text."));
+                    };
+                    tags.insert(tag, value);
+                }
+            }
+            Some(_) => return Err("tags must be an object.".to_string()),
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(format!(
+                "Too many tags: {}. Allowed: {MAX_TAGS}.",
+                tags.len()
+            ));
+        }
+        for (tag, value) in &tags {
+            if tag.is_empty()
+                || tag.len() > MAX_TAG_LEN
+                || value.len() > MAX_TAG_VALUE_LEN
+            {
+                return Err(format!(
+                    "Tag {tag:?} is invalid. Allowed: names of 1 to \
+                    {MAX_TAG_LEN} bytes, values of at most \
+                    {MAX_TAG_VALUE_LEN}."
+                ));
+            }
+        }
+        Ok(tags)
+    }
+
+    pub fn inject_system_prompt(&mut self, prompt: &conf::SystemPrompt) {
+        let msg = Msg {
+            role: "system".to_string(),
+            content: prompt.content.clone().into(),
+            name: None,
+            rest: serde_json::Map::new(),
+        };
+        match prompt.position {
+            conf::Position::Prepend => self.messages.insert(0, msg),
+            conf::Position::Append => self.messages.push(msg),
+        }
+    }
+
+    /// Checks that the completion is JSON, and matches the schema, if the
+    /// client asked for either in response_format. Only the first choice is
+    /// checked.
+    pub fn check_json_output(&self, resp_body: &str) -> Result<(), String> {
+        let Some(format) = self.rest.get("response_format") else {
+            return Ok(());
+        };
+        let schema = match format.get("type").and_then(|t| t.as_str()) {
+            Some("json_object") => None,
+            Some("json_schema") => format.pointer("/json_schema/schema"),
+            _ => return Ok(()),
+        };
+        let resp: serde_json::Value = serde_json::from_str(resp_body)
+            .map_err(|error| format!("Response is not JSON: {error}"))?;
+        let content = resp
+            .pointer("/choices/0/message/content")
+            .and_then(|content| content.as_str())
+            .ok_or_else(|| "Response lacks content.".to_string())?;
+        let output: serde_json::Value = serde_json::from_str(content)
+            .map_err(|error| format!("Output is not JSON: {error}"))?;
+        if let Some(schema) = schema {
+            let validator = jsonschema::validator_for(schema)
+                .map_err(|error| format!("Invalid schema: {error}"))?;
+            let error = validator.iter_errors(&output).next().map(|error| {
+                format!("Output does not match schema: {error}")
+            });
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the response is to be streamed, as server-sent events.
+    #[must_use]
+    pub fn is_stream(&self) -> bool {
+        self.rest.get("stream") == Some(&true.into())
+    }
+
+    /// Whether the usage is to end the stream, per `stream_options`.
+    #[must_use]
+    pub fn is_usage_streamed(&self) -> bool {
+        self.rest
+            .get("stream_options")
+            .and_then(|options| options.get("include_usage"))
+            == Some(&true.into())
+    }
+
+    /// Of a streamed request, the same, but asking for the usage at the
+    /// end, for it to be billed by.
+    #[must_use]
+    pub fn usage_streamed(&self) -> Self {
+        let mut req = self.clone();
+        let options = req
+            .rest
+            .entry("stream_options")
+            .or_insert_with(|| serde_json::json!({}));
+        if !options.is_object() {
+            *options = serde_json::json!({});
+        }
+        options["include_usage"] = true.into();
+        req
+    }
+
+    /// Of a streamed request, the same, but to be answered whole, for the
+    /// completion to be checked before any of it is passed on.
+    #[must_use]
+    pub fn unstreamed(&self) -> Self {
+        let mut req = self.clone();
+        req.rest.remove("stream");
+        req.rest.remove("stream_options");
+        req
+    }
+
+    /// Of a whole completion, the server-sent events it would have been
+    /// streamed as, in one chunk per choice, for requests which asked for a
+    /// stream. None when the body is not of a completion.
+    #[must_use]
+    pub fn events_of(&self, resp_body: &str) -> Option<String> {
+        let resp: serde_json::Value = serde_json::from_str(resp_body).ok()?;
+        let choices = resp.get("choices")?.as_array()?;
+        let chunk = |choices: serde_json::Value| {
+            let mut chunk = serde_json::json!({
+                "id": resp["id"],
+                "object": "chat.completion.chunk",
+                "created": resp["created"],
+                "model": resp["model"],
+                "choices": choices,
+            });
+            if let Some(fingerprint) = resp.get("system_fingerprint") {
+                chunk["system_fingerprint"] = fingerprint.clone();
+            }
+            chunk
+        };
+        let mut chunks: Vec<serde_json::Value> = choices
+            .iter()
+            .map(|choice| {
+                let mut delta = choice["message"].clone();
+                if let Some(calls) = delta
+                    .get_mut("tool_calls")
+                    .and_then(serde_json::Value::as_array_mut)
+                {
+                    for (index, call) in calls.iter_mut().enumerate() {
+                        call["index"] = index.into();
+                    }
+                }
+                chunk(serde_json::json!([{
+                    "index": choice["index"],
+                    "delta": delta,
+                    "logprobs": choice["logprobs"],
+                    "finish_reason": choice["finish_reason"],
+                }]))
+            })
+            .collect();
+        if self.is_usage_streamed() {
+            let mut last = chunk(serde_json::json!([]));
+            last["usage"] = resp["usage"].clone();
+            chunks.push(last);
+        }
+        let mut events: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {chunk}\n\n"))
+            .collect();
+        events.push_str("data: [DONE]\n\n");
+        Some(events)
+    }
+
+    /// Of those granted per user, per [`conf::Role::features`], the ones
+    /// the request needs.
+    #[must_use]
+    pub fn features(&self) -> Vec<conf::Feature> {
+        let is_given = |key| {
+            self.rest.get(key).is_some_and(|value| match value {
+                serde_json::Value::Null => false,
+                serde_json::Value::Array(items) => !items.is_empty(),
+                _ => true,
+            })
+        };
+        [
+            (self.is_stream(), conf::Feature::Streaming),
+            (
+                self.messages.iter().any(Msg::has_images),
+                conf::Feature::Vision,
+            ),
+            (
+                is_given("tools") || is_given("functions"),
+                conf::Feature::Tools,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(is_needed, feature)| is_needed.then_some(feature))
+        .collect()
+    }
+
+    /// Exact with the model's tokenizer, when there is one.
+    #[must_use]
+    pub fn tokens_estimate(&self, tokenizers: &tokenizer::Registry) -> usize {
+        let count = |text: &str| {
+            tokenizers
+                .count(&self.model, text)
+                .unwrap_or_else(|| tokens_estimate(text))
+        };
+        // Tool definitions are part of the prompt too.
+        let tools = self
+            .rest
+            .get("tools")
+            .map_or(0, |tools| count(&tools.to_string()));
+        self.messages
+            .iter()
+            .map(|msg| msg.tokens_estimate(count))
+            .sum::<usize>()
+            + tools
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Clone)]
 pub struct Msg {
     pub role: String,
-    pub content: String,
+
+    /// Text, or a list of parts, or null (assistant tool calls).
+    #[serde(default)]
+    pub content: serde_json::Value,
 
     // XXX Without skipping we get JSON `"name": null`, which Groq rejects,
     //     but accepts when it is instead omitted from the structure.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+
+    /// tool_calls, tool_call_id, etc.
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Msg {
-    // The simplest estimation suggested by ChatGPT: (char count / 4).
-    fn tokens_estimate(&self) -> usize {
-        let alphanum_char_count = self
-            .content
-            .to_lowercase()
-            .chars()
-            .filter(|c| c.is_alphanumeric())
-            .count();
-        alphanum_char_count.saturating_div(4)
-        // TODO Consider using toktoken after cleaning it up:
-        //      https://github.com/xandkar/tiktoken
+    /// The text content, with text parts joined.
+    #[must_use]
+    pub fn text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part.get("text")?.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+
+    fn has_images(&self) -> bool {
+        self.content.as_array().is_some_and(|parts| {
+            parts.iter().any(|part| {
+                matches!(
+                    part.get("type").and_then(|kind| kind.as_str()),
+                    Some("image_url" | "input_image" | "image")
+                )
+            })
+        })
+    }
+
+    fn tokens_estimate(&self, count: impl Fn(&str) -> usize) -> usize {
+        let text = self.text();
+        let rest = (!self.rest.is_empty()).then(|| {
+            serde_json::Value::Object(self.rest.clone()).to_string()
+        });
+        count(&text) + rest.as_deref().map_or(0, count)
+    }
+}
+
+// The simplest estimation suggested by ChatGPT: (char count / 4).
+// The last resort, for models without a tokenizer.
+fn tokens_estimate(text: &str) -> usize {
+    let alphanum_char_count = text
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .count();
+    alphanum_char_count.saturating_div(4)
+    // TODO Consider using toktoken after cleaning it up:
+    //      https://github.com/xandkar/tiktoken
+}
+
+/// OpenAI-compatible error response body, so that OpenAI SDKs can make
+/// sense of our errors too. Codes of our own, on top of OpenAI's:
+/// - "budget_exceeded" (429): the daily token or cost budget is used up;
+/// - "overage_rate_limited" (429): past the daily token budget, where
+///   requests are served at a stricter rate;
+/// - "invalid_json" (400): the request body is not JSON;
+/// - "invalid_tags" (400): the request's tags, or metadata, are invalid;
+/// - "duplicate_prompt" (429): the same prompt was sent too often lately;
+/// - "role_limit_exceeded" (400): the request exceeds the role's limits;
+/// - "model_not_allowed" (403): the role may not use the model;
+/// - "feature_not_allowed" (403): the user was not granted a feature the
+///   request needs, such as vision;
+/// - "content_flagged" (422): moderation flagged the prompt;
+/// - "invalid_idempotency_key" (400): the Idempotency-Key header is unusable;
+/// - "idempotency_key_in_use" (409): the first request with the key is still
+///   in progress;
+/// - "idempotency_key_reused" (422): the key was used for another request;
+/// - "request_too_large" (413): the body exceeds the applicable limit;
+/// - "invalid_timeout" (400): the x-raskol-timeout header is unusable;
+/// - "invalid_dry_run" (400): the x-raskol-dry-run header is unusable, or
+///   the request is not of chat;
+/// - "dry_run_not_allowed" (403): the role may not dry run;
+/// - "spend_cap_reached" (503): the providers' daily spend cap is reached;
+/// - "outside_access_window" (403): the role may not be used at this time
+///   of day;
+/// - "response_rejected" (502): the completion violated the response filter;
+/// - "stream_broken" (in a stream, already 200): upstream broke it off
+///   midway, after what was passed on, which is charged.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct Error {
+    pub error: ErrorDetail,
+
+    /// Where the user stands, when the budget is exceeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+
+    /// Of the request body, when exceeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+
+    /// When the role may be used next, when it may not now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_window: Option<crate::schedule::Next>,
+
+    /// Of an invalid request body, each of what is wrong with it, the first
+    /// also as the error's.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<crate::lint::Problem>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct Budget {
+    pub tokens_used: u64,
+    pub tokens_limit: u64,
+    pub tokens_remaining: u64,
+
+    /// UTC, RFC 3339.
+    pub reset_at: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+impl Error {
+    /// Of an OpenAI-style error response.
+    #[must_use]
+    pub fn code_from_resp_body(body: &str) -> Option<String> {
+        #[derive(serde::Deserialize)]
+        struct Resp {
+            error: Detail,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Detail {
+            code: Option<String>,
+        }
+
+        serde_json::from_str::<Resp>(body).ok()?.error.code
+    }
+
+    #[must_use]
+    pub fn new(type_: &str, code: &str, message: String) -> Self {
+        Self {
+            error: ErrorDetail {
+                message,
+                type_: type_.to_string(),
+                param: None,
+                code: Some(code.to_string()),
+            },
+            budget: None,
+            max_bytes: None,
+            access_window: None,
+            problems: Vec::new(),
+        }
+    }
+
+    /// Generic one, for errors which were only given a status.
+    #[must_use]
+    pub fn from_status(status: StatusCode) -> Self {
+        let (type_, code) = match status {
+            StatusCode::UNAUTHORIZED => {
+                ("invalid_request_error", Some("invalid_api_key"))
+            }
+            StatusCode::FORBIDDEN => ("permission_error", None),
+            StatusCode::NOT_FOUND => ("not_found_error", None),
+            StatusCode::TOO_MANY_REQUESTS => {
+                ("requests", Some("rate_limit_exceeded"))
+            }
+            status if status.is_server_error() => ("server_error", None),
+            _ => ("invalid_request_error", None),
+        };
+        let message = status
+            .canonical_reason()
+            .unwrap_or("Unknown error")
+            .to_string();
+        Self {
+            error: ErrorDetail {
+                message,
+                type_: type_.to_string(),
+                param: None,
+                code: code.map(ToString::to_string),
+            },
+            budget: None,
+            max_bytes: None,
+            access_window: None,
+            problems: Vec::new(),
+        }
+    }
+}
+
+/// OpenAI-compatible model listing.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug)]
+pub struct Models {
+    /// Anthropic's listing lacks it.
+    #[serde(default = "list")]
+    pub object: String,
+
+    pub data: Vec<Model>,
+}
+
+fn list() -> String {
+    "list".to_string()
+}
+
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug)]
+pub struct Model {
+    pub id: String,
+
+    /// Whatever else the provider says about it, passed through as is.
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The part of an OpenAI-compatible response that reports actual usage.
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+
+    /// Seconds, as only Groq reports them.
+    #[serde(default)]
+    pub queue_time: Option<f64>,
+    #[serde(default)]
+    pub prompt_time: Option<f64>,
+    #[serde(default)]
+    pub completion_time: Option<f64>,
+}
+
+impl Usage {
+    /// Of a streamed response too, from its last event which reports
+    /// usage, in `x_groq` for Groq.
+    #[must_use]
+    pub fn from_resp_body(body: &str) -> Option<Self> {
+        if let Ok(resp) = serde_json::from_str(body) {
+            return Self::from_resp(&resp);
+        }
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .filter_map(|data| {
+                serde_json::from_str::<serde_json::Value>(data.trim()).ok()
+            })
+            .filter_map(|resp| Self::from_resp(&resp))
+            .last()
+    }
+
+    /// Of a response, or of an event of a streamed one.
+    #[must_use]
+    pub fn from_resp(resp: &serde_json::Value) -> Option<Self> {
+        resp.pointer("/usage")
+            .filter(|usage| usage.is_object())
+            .or_else(|| resp.pointer("/x_groq/usage"))
+            .and_then(|usage| serde_json::from_value(usage.clone()).ok())
+    }
+
+    /// `None` unless upstream reported where its time went.
+    #[must_use]
+    pub fn timing(&self) -> Option<data::Timing> {
+        let ms = |secs: f64| secs * 1000.0;
+        Some(data::Timing {
+            queue_ms: self.queue_time.map_or(0.0, ms),
+            prompt_ms: ms(self.prompt_time?),
+            completion_ms: ms(self.completion_time?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::{Feature, RoleLimits};
+
+    use super::{Msg, Req, Usage};
+
+    #[test]
+    fn passthrough() {
+        let body = serde_json::json!({
+            "model": "m",
+            "messages": [
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "1",
+                        "type": "function",
+                        "function": {"name": "f", "arguments": "{}"},
+                    }],
+                },
+                {"role": "tool", "content": "42", "tool_call_id": "1"},
+            ],
+            "tools": [{"type": "function", "function": {"name": "f"}}],
+            "tool_choice": "auto",
+            "seed": 7,
+        })
+        .to_string();
+        let req: Req = serde_json::from_str(&body).unwrap();
+        assert_eq!(serde_json::to_string(&req).unwrap(), body);
+    }
+
+    #[test]
+    fn json_output() {
+        let resp = |content: &str| {
+            serde_json::json!({
+                "choices": [{"message": {"content": content}}],
+            })
+            .to_string()
+        };
+        let mut req: Req =
+            serde_json::from_str(r#"{"model": "m", "messages": []}"#)
+                .unwrap();
+        assert!(req.check_json_output(&resp("not json")).is_ok());
+
+        req.rest.insert(
+            "response_format".to_string(),
+            serde_json::json!({"type": "json_object"}),
+        );
+        assert!(req.check_json_output(&resp("not json")).is_err());
+        assert!(req.check_json_output(&resp(r#"{"a": 1}"#)).is_ok());
+
+        req.rest.insert(
+            "response_format".to_string(),
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "a",
+                    "schema": {
+                        "type": "object",
+                        "properties": {"a": {"type": "string"}},
+                        "required": ["a"],
+                    },
+                },
+            }),
+        );
+        assert!(req.check_json_output(&resp(r#"{"a": 1}"#)).is_err());
+        assert!(req.check_json_output(&resp(r#"{"a": "x"}"#)).is_ok());
+    }
+
+    #[test]
+    fn constrain() {
+        let req = |max_tokens, temperature| Req {
+            model: "m".to_string(),
+            messages: vec![Msg {
+                role: "user".to_string(),
+                content: "Hi".into(),
+                name: None,
+                rest: serde_json::Map::new(),
+            }],
+            max_tokens,
+            temperature,
+            rest: serde_json::Map::new(),
+        };
+        let mut limits = RoleLimits {
+            max_tokens: Some(100),
+            max_temperature: Some(1.0),
+            ..RoleLimits::default()
+        };
+
+        let mut unset = req(None, None);
+        assert!(unset.constrain(&limits).is_ok());
+        assert_eq!(unset.max_tokens, Some(100));
+        assert!(req(Some(50), Some(0.5)).constrain(&limits).is_ok());
+        assert!(req(Some(500), None).constrain(&limits).is_err());
+        assert!(req(None, Some(1.5)).constrain(&limits).is_err());
+
+        limits.clamp = true;
+        let mut excessive = req(Some(500), Some(1.5));
+        assert!(excessive.constrain(&limits).is_ok());
+        assert_eq!(excessive.max_tokens, Some(100));
+        assert_eq!(excessive.temperature, Some(1.0));
+
+        limits.max_messages = Some(0);
+        assert!(req(None, None).constrain(&limits).is_err());
+    }
+
+    #[test]
+    fn usage() {
+        let body = r#"{"usage": {"prompt_tokens": 3, "completion_tokens": 5,
+            "total_tokens": 8}}"#;
+        let usage = Usage::from_resp_body(body).unwrap();
+        assert_eq!(usage.total_tokens, 8);
+        assert!(usage.timing().is_none());
+
+        let streamed = concat!(
+            "data: {\"choices\": [{\"delta\": {\"content\": \"Hi\"}}]}\n\n",
+            "data: {\"choices\": [], \"x_groq\": {\"usage\": {",
+            "\"prompt_tokens\": 3, \"completion_tokens\": 5, ",
+            "\"total_tokens\": 8, \"queue_time\": 0.01, ",
+            "\"prompt_time\": 0.002, \"completion_time\": 0.25}}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let usage = Usage::from_resp_body(streamed).unwrap();
+        assert_eq!(usage.completion_tokens, 5);
+        let timing = usage.timing().unwrap();
+        assert!((timing.queue_ms - 10.0).abs() < 1e-9);
+        assert!((timing.prompt_ms - 2.0).abs() < 1e-9);
+        assert!((timing.completion_ms - 250.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tags() {
+        let req = |body: serde_json::Value| -> Req {
+            serde_json::from_value(body).unwrap()
+        };
+        let mut tagged = req(serde_json::json!({
+            "model": "m",
+            "messages": [],
+            "metadata": {"session": "s", "feature": "search", "n": 1},
+            "tags": {"team": "growth"},
+        }));
+        let tags = tagged.take_tags().unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags["feature"], "search");
+        assert_eq!(tags["team"], "growth");
+        assert!(!tagged.rest.contains_key("tags"));
+        assert!(tagged.rest.contains_key("metadata"));
+
+        let mut untagged = req(serde_json::json!({
+            "model": "m",
+            "messages": [],
+        }));
+        assert!(untagged.take_tags().unwrap().is_empty());
+
+        let mut invalid = req(serde_json::json!({
+            "model": "m",
+            "messages": [],
+            "tags": {"team": 1},
+        }));
+        assert!(invalid.take_tags().is_err());
+        let mut long = req(serde_json::json!({
+            "model": "m",
+            "messages": [],
+            "tags": {"team": "x".repeat(513)},
+        }));
+        assert!(long.take_tags().is_err());
+    }
+
+    #[test]
+    fn features() {
+        let req = |body: serde_json::Value| -> Req {
+            serde_json::from_value(body).unwrap()
+        };
+        let plain = req(serde_json::json!({
+            "model": "m",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "tools": [],
+            "stream": false,
+        }));
+        assert!(plain.features().is_empty());
+        let rich = req(serde_json::json!({
+            "model": "m",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "https://x"}},
+            ]}],
+            "tools": [{"type": "function", "function": {"name": "f"}}],
+            "stream": true,
+        }));
+        assert_eq!(
+            rich.features(),
+            [Feature::Streaming, Feature::Vision, Feature::Tools]
+        );
+    }
+
+    #[test]
+    fn events_of() {
+        let req: Req = serde_json::from_value(serde_json::json!({
+            "model": "m",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }))
+        .unwrap();
+        assert!(req.is_usage_streamed());
+        let unstreamed = req.unstreamed();
+        assert!(unstreamed.usage_streamed().is_usage_streamed());
+        assert!(!unstreamed.is_stream());
+        assert!(!unstreamed.rest.contains_key("stream_options"));
+        let body = serde_json::json!({
+            "id": "c",
+            "created": 1,
+            "model": "m",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello"},
+                "finish_reason": "stop",
+            }],
+            "usage": {
+                "prompt_tokens": 1,
+                "completion_tokens": 1,
+                "total_tokens": 2,
+            },
+        })
+        .to_string();
+        let events = req.events_of(&body).unwrap();
+        let data: Vec<&str> = events
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .collect();
+        assert_eq!(data.len(), 3);
+        let first: serde_json::Value = serde_json::from_str(data[0]).unwrap();
+        assert_eq!(first["object"], "chat.completion.chunk");
+        assert_eq!(first["choices"][0]["delta"]["content"], "Hello");
+        assert_eq!(first["choices"][0]["finish_reason"], "stop");
+        assert_eq!(Usage::from_resp_body(&events).unwrap().total_tokens, 2);
+        assert_eq!(data[2], "[DONE]");
+        assert_eq!(req.events_of(r#"{"error": {}}"#), None);
     }
 }
@@ -0,0 +1,214 @@
+//! Trimming of chat prompts which would overflow the context, per
+//! [`conf::PromptCompression`], since agents looping on tool calls keep
+//! growing theirs until upstream refuses them. Of messages, unlike
+//! [`conf::Compression`], which is of bodies.
+
+use std::fmt;
+
+use crate::{chat, conf};
+
+/// Of a prompt, what was trimmed from it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Trimmed {
+    /// Messages, oldest first.
+    pub dropped: usize,
+
+    /// Messages, cut short in the middle.
+    pub truncated: usize,
+}
+
+impl fmt::Display for Trimmed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dropped={}, truncated={}", self.dropped, self.truncated)
+    }
+}
+
+/// Trims the prompt, by the strategies in order, until its tokens, as
+/// estimated, are within the threshold, or there is nothing left to trim.
+/// None when left as is.
+pub fn compress(
+    conf: &conf::PromptCompression,
+    req: &mut chat::Req,
+    window: Option<usize>,
+    estimate: impl Fn(&chat::Req) -> usize,
+) -> Option<Trimmed> {
+    let threshold = conf.max_tokens.or_else(|| {
+        window
+            .map(|window| window.saturating_sub(req.max_tokens.unwrap_or(0)))
+    })?;
+    let mut trimmed = Trimmed::default();
+    for strategy in &conf.strategies {
+        if estimate(req) <= threshold {
+            break;
+        }
+        match strategy {
+            conf::CompressionStrategy::TruncateMiddle => {
+                for msg in &mut req.messages {
+                    if msg.role != "system"
+                        && truncate_middle(msg, conf.max_message_chars)
+                    {
+                        trimmed.truncated += 1;
+                    }
+                }
+            }
+            conf::CompressionStrategy::DropOldest => {
+                let mut tokens = estimate(req);
+                while tokens > threshold {
+                    let dropped = drop_oldest(&mut req.messages);
+                    if dropped.is_empty() {
+                        break;
+                    }
+                    trimmed.dropped += dropped.len();
+                    // Of only those dropped, not all those left, again.
+                    let dropped = chat::Req {
+                        model: req.model.clone(),
+                        messages: dropped,
+                        max_tokens: None,
+                        temperature: None,
+                        rest: serde_json::Map::new(),
+                    };
+                    tokens = tokens.saturating_sub(estimate(&dropped));
+                }
+            }
+        }
+    }
+    (trimmed != Trimmed::default()).then_some(trimmed)
+}
+
+/// Of text content only, keeping its start and end, as those of pasted
+/// files and logs tend to matter most.
+fn truncate_middle(msg: &mut chat::Msg, max_chars: usize) -> bool {
+    let Some(text) = msg.content.as_str() else {
+        return false;
+    };
+    let chars = text.chars().count();
+    if chars <= max_chars {
+        return false;
+    }
+    let kept = max_chars / 2;
+    let head: String = text.chars().take(kept).collect();
+    let tail: String = text.chars().skip(chars - kept).collect();
+    let trimmed = chars - 2 * kept;
+    msg.content =
+        format!("{head}\n[... {trimmed} characters trimmed ...]\n{tail}")
+            .into();
+    true
+}
+
+/// The oldest message, apart from system ones and the last, along with the
+/// tool results which follow it, which would be left without their call.
+/// None of them when those results run up to the last message.
+fn drop_oldest(messages: &mut Vec<chat::Msg>) -> Vec<chat::Msg> {
+    let last = messages.len().saturating_sub(1);
+    let Some(first) =
+        messages[..last].iter().position(|msg| msg.role != "system")
+    else {
+        return Vec::new();
+    };
+    let mut end = first + 1;
+    while end < messages.len() && messages[end].role == "tool" {
+        end += 1;
+    }
+    if end > last {
+        return Vec::new();
+    }
+    messages.drain(first..end).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Trimmed;
+    use crate::{chat, conf};
+
+    fn req(messages: serde_json::Value) -> chat::Req {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": messages,
+            "max_tokens": 10,
+        }))
+        .unwrap()
+    }
+
+    /// A token per character, for round numbers.
+    fn chars(req: &chat::Req) -> usize {
+        req.messages.iter().map(|msg| msg.text().len()).sum()
+    }
+
+    fn conf(
+        strategies: Vec<conf::CompressionStrategy>,
+    ) -> conf::PromptCompression {
+        conf::PromptCompression {
+            max_tokens: None,
+            strategies,
+            max_message_chars: 20,
+        }
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let conf = conf(vec![conf::CompressionStrategy::DropOldest]);
+        let mut req = req(serde_json::json!([
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Look it up."},
+            {"role": "assistant", "content": null, "tool_calls": []},
+            {"role": "tool", "content": "Found it.", "tool_call_id": "a"},
+            {"role": "assistant", "content": "Done."},
+            {"role": "user", "content": "Thanks!"},
+        ]));
+        assert_eq!(super::compress(&conf, &mut req, None, chars), None);
+        assert_eq!(super::compress(&conf, &mut req, Some(60), chars), None);
+        assert_eq!(
+            super::compress(&conf, &mut req, Some(35), chars),
+            Some(Trimmed {
+                dropped: 3,
+                truncated: 0
+            })
+        );
+        let roles: Vec<&str> =
+            req.messages.iter().map(|msg| msg.role.as_str()).collect();
+        assert_eq!(roles, ["system", "assistant", "user"]);
+        // Never the system prompt, nor the last message.
+        let trimmed = super::compress(&conf, &mut req, Some(10), chars);
+        assert_eq!(trimmed.map(|trimmed| trimmed.dropped), Some(1));
+        assert_eq!(req.messages.len(), 2);
+
+        // Nor a call whose results run up to the last message.
+        let mut req = req(serde_json::json!([
+            {"role": "user", "content": "Look it up."},
+            {"role": "assistant", "content": null, "tool_calls": []},
+            {"role": "tool", "content": "Found it.", "tool_call_id": "a"},
+            {"role": "tool", "content": "And this.", "tool_call_id": "b"},
+        ]));
+        let trimmed = super::compress(&conf, &mut req, Some(20), chars);
+        assert_eq!(trimmed.map(|trimmed| trimmed.dropped), Some(1));
+        let roles: Vec<&str> =
+            req.messages.iter().map(|msg| msg.role.as_str()).collect();
+        assert_eq!(roles, ["assistant", "tool", "tool"]);
+    }
+
+    #[test]
+    fn truncate_middle() {
+        let conf = conf(vec![
+            conf::CompressionStrategy::TruncateMiddle,
+            conf::CompressionStrategy::DropOldest,
+        ]);
+        let long = "a".repeat(50) + &"b".repeat(50);
+        let mut req = req(serde_json::json!([
+            {"role": "system", "content": long},
+            {"role": "user", "content": long},
+        ]));
+        let trimmed = super::compress(&conf, &mut req, Some(200), chars);
+        assert_eq!(
+            trimmed,
+            Some(Trimmed {
+                dropped: 0,
+                truncated: 1
+            })
+        );
+        assert_eq!(req.messages[0].text(), long);
+        assert_eq!(
+            req.messages[1].text(),
+            "aaaaaaaaaa\n[... 80 characters trimmed ...]\nbbbbbbbbbb"
+        );
+    }
+}
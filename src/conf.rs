@@ -1,74 +1,2849 @@
 use std::{
+    collections::BTreeMap,
     fmt::Debug,
     fs,
-    net::IpAddr,
+    future::Future,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, LazyLock},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    time::Duration,
 };
 
 use anyhow::Context;
+use ipnet::IpNet;
 
-pub static GLOBAL: LazyLock<Arc<Conf>> = LazyLock::new(|| {
-    let conf = read_or_create_default().unwrap_or_else(|error| {
-        panic!("Failed to initialize global config: {error:?}")
-    });
-    Arc::new(conf)
-});
+static GLOBAL: OnceLock<Arc<Conf>> = OnceLock::new();
 
+tokio::task_local! {
+    /// Of the server whose request, or task, this is, per [`scope`].
+    static SCOPED: Arc<Conf>;
+}
+
+/// Of the server whose request, or task, this is, when embedded. Else the
+/// process's: the one set, or else read from the conf file, on first use.
 #[must_use]
 pub fn global() -> Arc<Conf> {
-    (*GLOBAL).clone()
+    SCOPED.try_with(Arc::clone).unwrap_or_else(|_| {
+        GLOBAL
+            .get_or_init(|| {
+                let conf = read_or_create_default().unwrap_or_else(|error| {
+                    panic!("Failed to initialize global config: {error:?}")
+                });
+                Arc::new(conf)
+            })
+            .clone()
+    })
+}
+
+/// Instead of the conf file, for what runs outside of any server, such as
+/// the CLI. Only before the first use.
+pub fn set(conf: Conf) -> anyhow::Result<()> {
+    GLOBAL
+        .set(Arc::new(conf))
+        .map_err(|_| anyhow::anyhow!("Conf already set or in use."))
+}
+
+/// Of the first server embedded, for what runs outside of it, such as the
+/// tests driving its storage, unless one is set or read already.
+pub(crate) fn set_default(conf: &Arc<Conf>) {
+    let _ = GLOBAL.get_or_init(|| conf.clone());
+}
+
+/// The future, with the conf of [`global`].
+pub async fn scope<F: Future>(conf: Arc<Conf>, future: F) -> F::Output {
+    SCOPED.scope(conf, future).await
+}
+
+/// The future, with the conf it was made with, for tasks of its own.
+pub fn carry<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let conf = SCOPED.try_with(Arc::clone).ok();
+    async move {
+        match conf {
+            Some(conf) => SCOPED.scope(conf, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Of what's derived from the conf, such as compiled patterns, kept per
+/// conf, of which there are few, so for as long as the process runs.
+pub struct Derived<T: 'static> {
+    derive: fn(&Conf) -> T,
+    of: Mutex<Vec<(Arc<Conf>, &'static T)>>,
+}
+
+impl<T: Send + Sync> Derived<T> {
+    #[must_use]
+    pub const fn new(derive: fn(&Conf) -> T) -> Self {
+        Self {
+            derive,
+            of: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Of the conf of [`global`].
+    #[must_use]
+    pub fn get(&self) -> &'static T {
+        let conf = global();
+        let mut of = self.of.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, derived)) =
+            of.iter().find(|(of, _)| Arc::ptr_eq(of, &conf))
+        {
+            return derived;
+        }
+        let derived: &'static T = Box::leak(Box::new((self.derive)(&conf)));
+        of.push((conf, derived));
+        derived
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Conf {
+    #[serde(
+        serialize_with = "serialize_log_level",
+        deserialize_with = "deserialize_log_level"
+    )]
+    pub log_level: tracing::Level,
+
+    #[serde(default)]
+    pub log: Log,
+
+    /// One or more, e.g. ["0.0.0.0", "::"] for both IPv4 and IPv6.
+    #[serde(deserialize_with = "deserialize_addrs")]
+    pub addr: Vec<IpAddr>,
+    pub port: u16,
+
+    /// Instead of addr:port.
+    #[serde(default)]
+    pub listen: Listen,
+
+    #[serde(default)]
+    pub ip_filter: IpFilter,
+
+    pub jwt: Jwt,
+
+    /// Of requests by backend services, signed with keys shared with them,
+    /// as an alternative to JWTs. Off when not set.
+    #[serde(default)]
+    pub signing: Option<Signing>,
+
+    pub target_address: String,
+
+    /// Plain HTTP is for providers on a trusted network, such as a local
+    /// Ollama or vLLM. Prompts to those are not tokenized ahead, per
+    /// [`crate::tokenizer::for_prompts`], but billed by the usage reported.
+    #[serde(default)]
+    pub target_scheme: Scheme,
+
+    /// Either a single key or a list of keys to rotate through.
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub target_auth_token: Vec<String>,
+
+    #[serde(default)]
+    pub dialect: Dialect,
+
+    /// Used with the "azure" dialect.
+    #[serde(default)]
+    pub azure: Azure,
+
+    /// Where the target lists its models.
+    #[serde(default = "default_models_endpoint")]
+    pub models_endpoint: String,
+
+    #[serde(default)]
+    pub http: Http,
+
+    #[serde(default)]
+    pub retry: Retry,
+
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreaker,
+
+    /// Holding requests while upstream throttles us, rather than failing
+    /// them. Off when not set.
+    #[serde(default)]
+    pub queue: Option<Queue>,
+
+    #[serde(default)]
+    pub health: Health,
+
+    /// Probing of the upstream providers in the background, for their uptime
+    /// and latency at `/status`. Off when not set.
+    #[serde(default)]
+    pub prober: Option<Prober>,
+
+    /// Numbers of everyone's usage together, unauthenticated, at
+    /// `/public/stats`, for a live counter on the venue's screen. Off when
+    /// not set.
+    #[serde(default)]
+    pub public_stats: Option<PublicStats>,
+
+    /// Other instances, for requests to be proxied to when upstream
+    /// can't be used from this one. Off when not set.
+    #[serde(default)]
+    pub peers: Option<Peers>,
+
+    /// Models and routes to be removed, for those using them to be told,
+    /// and counted, at `/admin/deprecated`. Off when not set.
+    #[serde(default)]
+    pub deprecations: Option<Deprecations>,
+
+    #[serde(default)]
+    pub audio: Audio,
+
+    /// Endpoints forwarded as is, other than chat and audio.
+    #[serde(default)]
+    pub passthrough: Passthrough,
+
+    /// Multipart uploads to provider file APIs, other than audio.
+    #[serde(default)]
+    pub files: Files,
+
+    /// Text-to-speech and image generation.
+    #[serde(default)]
+    pub media: Media,
+
+    /// Speech sessions relayed to the provider's realtime WebSocket API.
+    #[serde(default)]
+    pub realtime: Realtime,
+
+    /// Chat sessions over WebSocket, at /ws/chat.
+    #[serde(default)]
+    pub ws: Ws,
+
+    /// Which client headers reach upstream, and which of upstream's reach
+    /// clients.
+    #[serde(default)]
+    pub headers: Headers,
+
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// Built-in request hooks to turn on. Others are registered in code.
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    #[serde(default)]
+    pub leaderboard: Leaderboard,
+
+    /// Users signing themselves up, with invite codes. Off when not set.
+    #[serde(default)]
+    pub signup: Option<Signup>,
+
+    #[serde(default)]
+    pub redaction: Redaction,
+
+    /// Endpoint screening the prompts of roles which are moderated.
+    #[serde(default)]
+    pub moderation: Option<Moderation>,
+
+    /// Service for other backends to manage budgets and mint tokens. Needs
+    /// the `grpc` feature. Off when not set.
+    #[serde(default)]
+    pub grpc: Option<Grpc>,
+
+    /// Of chat and other API responses, attributing them to the user and
+    /// request. Off when not set.
+    #[serde(default)]
+    pub attestation: Option<Attestation>,
+
+    /// Reporting of usage to Stripe, for billing the customers mapped to
+    /// users and orgs. Off when not set.
+    #[serde(default)]
+    pub stripe: Option<Stripe>,
+
+    /// Pushing the metrics of `/metrics`, for venues which block scraping
+    /// it. Off when not set.
+    #[serde(default)]
+    pub metrics_push: Option<MetricsPush>,
+
+    /// Model -> model to retry with once when upstream says the former is
+    /// decommissioned or the request exceeds its context.
+    #[serde(default)]
+    pub fallback_models: BTreeMap<String, String>,
+
+    /// Whether to check that completions are JSON (matching the schema) when
+    /// the client asked for it in response_format, retrying once if not.
+    #[serde(default)]
+    pub validate_json_output: bool,
+
+    /// Whether chat requests with fields unknown to the API, likely typos,
+    /// as `max_token`, are rejected rather than passed on, per
+    /// [`crate::lint`].
+    #[serde(default)]
+    pub strict_requests: bool,
+
+    /// Whether to tell clients where each response came from, as a `raskol`
+    /// object in JSON responses, and as headers of streamed ones.
+    #[serde(default)]
+    pub provenance: bool,
+
+    /// Trimming of chat prompts which would overflow the context. Off when
+    /// not set.
+    #[serde(default)]
+    pub prompt_compression: Option<PromptCompression>,
+
+    /// Checking of chat completions before they are returned. Off when not
+    /// set.
+    #[serde(default)]
+    pub response_filter: Option<ResponseFilter>,
+
+    /// Exemplars of the slowest requests, at `/admin/slow`. Off when not
+    /// set.
+    #[serde(default)]
+    pub slow_requests: Option<SlowRequests>,
+
+    /// Of background jobs, by name, their schedules, if not the default.
+    #[serde(default)]
+    pub jobs: BTreeMap<String, Job>,
+
+    /// Secondary provider to use when the primary keeps failing.
+    #[serde(default)]
+    pub failover: Option<Failover>,
+
+    /// Mirroring of chat requests to another model or provider, to compare
+    /// them with the primary before switching. Off when not set.
+    #[serde(default)]
+    pub shadow: Option<Shadow>,
+
+    /// A/B experiments, by name.
+    #[serde(default)]
+    pub experiments: BTreeMap<String, Experiment>,
+
+    /// Whether our routes are also served without the version prefix, as
+    /// they were before it, e.g. `/quota` besides `/v1/quota`. Deprecated.
+    #[serde(default = "default_legacy_routes")]
+    pub legacy_routes: bool,
+
+    /// Of chat requests submitted together, at `/v1/batches`.
+    #[serde(default)]
+    pub batches: Batches,
+
+    pub min_hit_interval: f32,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+
+    /// Unlimited when not set.
+    #[serde(default)]
+    pub max_concurrent_requests_per_user: Option<usize>,
+
+    pub max_tokens_per_day: u64,
+
+    /// In the same currency as pricing. No limit when not set.
+    #[serde(default)]
+    pub max_cost_per_day: Option<f64>,
+
+    /// Of what all users together may spend a day, UTC, at the providers,
+    /// past which we stop forwarding to them. Off when not set.
+    #[serde(default)]
+    pub spend_caps: Option<SpendCaps>,
+
+    /// Degraded service, rather than refusal, for users a little past their
+    /// daily token budget. Off when not set.
+    #[serde(default)]
+    pub overage: Option<Overage>,
+
+    /// Over which the "per day" budgets are counted, despite the name.
+    /// Days starting at midnight UTC by default.
+    #[serde(default)]
+    pub budget_period: BudgetPeriod,
+
+    /// Adding what's left of users' token budgets at the end of each period
+    /// to their credits. Off when not set.
+    #[serde(default)]
+    pub rollover: Option<Rollover>,
+
+    /// Budgets shared by the members of each org, on top of their own.
+    #[serde(default)]
+    pub orgs: Orgs,
+
+    /// Automatic suspension of users whose recent usage looks abusive. Off
+    /// when not set.
+    #[serde(default)]
+    pub abuse: Option<Abuse>,
+
+    /// Rejecting chat requests which repeat the same prompt too often, as
+    /// agents stuck in a loop do. Off when not set.
+    #[serde(default)]
+    pub duplicates: Option<Duplicates>,
+
+    /// Keeping the bodies of chat requests and their responses, redacted
+    /// per `redaction`, for investigating abuse and debugging. Off when not
+    /// set.
+    #[serde(default)]
+    pub capture: Option<Capture>,
+
+    /// Request logs and daily counts older than this many days are
+    /// periodically deleted. Kept forever when not set.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+
+    /// Seconds for which responses are kept, to replay to repeats of
+    /// requests with the same Idempotency-Key.
+    #[serde(default = "default_idempotency_ttl")]
+    pub idempotency_ttl: u64,
+
+    pub sqlite_busy_timeout: f32,
+
+    /// Of the connections to the database file, when `storage` is SQLite.
+    #[serde(default)]
+    pub sqlite: Sqlite,
+
+    pub tls: Option<Tls>,
+
+    #[serde(default)]
+    pub storage: Storage,
+
+    /// Queueing usage writes and flushing them together, for throughput.
+    /// Each is written right away when not set.
+    #[serde(default)]
+    pub write_behind: Option<WriteBehind>,
+
+    /// Keeping users' usage today in memory, so that budgets are checked
+    /// without the database, which is written to in the background. Only
+    /// for a single instance, since it doesn't see the usage through others.
+    #[serde(default)]
+    pub budget_cache: bool,
+
+    /// Keeping rate limit windows and users' daily usage in Redis, so that
+    /// instances behind a load balancer share them. Request logs and the
+    /// rest stay in `storage`. Needs the `redis` feature. Off when not set.
+    #[serde(default)]
+    pub redis: Option<Redis>,
+
+    /// Of the SQLite database. Not needed for others.
+    #[serde(default)]
+    pub maintenance: Maintenance,
+
+    #[serde(default)]
+    pub limits: Limits,
+
+    /// Model -> price. Models without a price are considered free.
+    #[serde(default)]
+    pub pricing: BTreeMap<String, Price>,
+
+    /// Model -> max prompt plus completion tokens. Requests to models not
+    /// listed are not checked.
+    #[serde(default = "default_context_windows")]
+    pub context_windows: BTreeMap<String, usize>,
+
+    /// Model pattern -> Hugging Face `tokenizer.json` file, for counting the
+    /// prompt tokens of those models exactly. Others are estimated.
+    #[serde(default)]
+    pub tokenizers: BTreeMap<String, PathBuf>,
+
+    #[serde(default)]
+    pub calibration: Calibration,
+
+    /// Role name -> what it is allowed. Users with roles not listed here
+    /// are denied everything.
+    #[serde(default = "default_roles")]
+    pub roles: BTreeMap<String, Role>,
+}
+
+impl Default for Conf {
+    fn default() -> Self {
+        Self {
+            log_level: tracing::Level::INFO,
+            log: Log::default(),
+            addr: vec!["127.0.0.1".parse().unwrap_or_else(|_| {
+                unreachable!("Fat-fingered default IP address!")
+            })],
+            port: 3001,
+            listen: Listen::default(),
+            ip_filter: IpFilter::default(),
+            jwt: Jwt::default(),
+            signing: None,
+            target_address: "api.groq.com".to_string(),
+            target_scheme: Scheme::default(),
+            target_auth_token: Vec::new(),
+            dialect: Dialect::default(),
+            azure: Azure::default(),
+            models_endpoint: default_models_endpoint(),
+            http: Http::default(),
+            retry: Retry::default(),
+            circuit_breaker: CircuitBreaker::default(),
+            queue: None,
+            health: Health::default(),
+            prober: None,
+            public_stats: None,
+            peers: None,
+            deprecations: None,
+            audio: Audio::default(),
+            passthrough: Passthrough::default(),
+            files: Files::default(),
+            media: Media::default(),
+            realtime: Realtime::default(),
+            ws: Ws::default(),
+            headers: Headers::default(),
+            compression: Compression::default(),
+            hooks: Hooks::default(),
+            leaderboard: Leaderboard::default(),
+            signup: None,
+            redaction: Redaction::default(),
+            moderation: None,
+            grpc: None,
+            attestation: None,
+            stripe: None,
+            metrics_push: None,
+            fallback_models: BTreeMap::new(),
+            validate_json_output: false,
+            strict_requests: false,
+            provenance: false,
+            prompt_compression: None,
+            response_filter: None,
+            slow_requests: None,
+            jobs: BTreeMap::new(),
+            failover: None,
+            shadow: None,
+            experiments: BTreeMap::new(),
+            legacy_routes: true,
+            batches: Batches::default(),
+            min_hit_interval: 5.0,
+            rate_limit: RateLimit::default(),
+            max_concurrent_requests_per_user: None,
+            max_tokens_per_day: 1_000_000, // TODO Revise.
+            max_cost_per_day: None,
+            spend_caps: None,
+            overage: None,
+            budget_period: BudgetPeriod::default(),
+            rollover: None,
+            orgs: Orgs::default(),
+            abuse: None,
+            duplicates: None,
+            capture: None,
+            retention_days: None,
+            idempotency_ttl: default_idempotency_ttl(),
+            sqlite_busy_timeout: 60.0,
+            sqlite: Sqlite::default(),
+            tls: None,
+            storage: Storage::default(),
+            write_behind: None,
+            budget_cache: false,
+            redis: None,
+            maintenance: Maintenance::default(),
+            limits: Limits::default(),
+            pricing: BTreeMap::new(),
+            context_windows: default_context_windows(),
+            tokenizers: BTreeMap::new(),
+            calibration: Calibration::default(),
+            roles: default_roles(),
+        }
+    }
+}
+
+impl Conf {
+    /// With secrets replaced, for showing it around.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let mut conf = self.clone();
+        REDACTED.clone_into(&mut conf.jwt.secret);
+        redact_all(&mut conf.jwt.old_secrets);
+        for keyed in conf.jwt.keyed_secrets.values_mut() {
+            REDACTED.clone_into(&mut keyed.secret);
+        }
+        redact_all(&mut conf.target_auth_token);
+        if let Some(failover) = conf.failover.as_mut() {
+            redact_all(&mut failover.target_auth_token);
+        }
+        if let Some(shadow) = conf.shadow.as_mut() {
+            redact_all(&mut shadow.provider.target_auth_token);
+        }
+        if let Some(moderation) = conf.moderation.as_mut() {
+            if let Some(auth_token) = moderation.auth_token.as_mut() {
+                REDACTED.clone_into(auth_token);
+            }
+        }
+        if let Storage::Postgres { url } = &mut conf.storage {
+            REDACTED.clone_into(url);
+        }
+        if let Some(redis) = conf.redis.as_mut() {
+            REDACTED.clone_into(&mut redis.url);
+        }
+        if let Some(token) =
+            conf.grpc.as_mut().and_then(|grpc| grpc.token.as_mut())
+        {
+            REDACTED.clone_into(token);
+        }
+        if let Some(attestation) = conf.attestation.as_mut() {
+            REDACTED.clone_into(&mut attestation.secret);
+        }
+        if let Some(stripe) = conf.stripe.as_mut() {
+            REDACTED.clone_into(&mut stripe.api_key);
+        }
+        conf
+    }
+
+    /// Of what parsing alone doesn't catch, but would fail the server, or
+    /// requests, once running, so that it refuses to start instead.
+    #[must_use]
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let realtime = &self.realtime;
+        if !(realtime.reserve_secs.is_finite()
+            && realtime.reserve_secs >= 1.0)
+        {
+            errors.push(format!(
+                "realtime.reserve_secs is less than 1: {}",
+                realtime.reserve_secs
+            ));
+        }
+        if realtime.max_secs == 0 {
+            errors.push("realtime.max_secs is 0.".to_string());
+        }
+        if self.ws.idle_secs == 0 {
+            errors.push("ws.idle_secs is 0.".to_string());
+        }
+        for (name, role) in &self.roles {
+            if let Some(access) = &role.access {
+                if let Err(error) = crate::schedule::Schedule::new(access) {
+                    errors.push(format!("roles.{name}.access: {error:#}"));
+                }
+            }
+        }
+        let buckets = self
+            .limits
+            .per_role
+            .values()
+            .filter_map(|limits| limits.rate_limit.as_ref())
+            .chain([&self.rate_limit])
+            .filter_map(|rate_limit| rate_limit.bucket);
+        for bucket in buckets {
+            let rate = bucket.requests_per_minute;
+            if !rate.is_finite() || rate <= 0.0 || bucket.burst == 0 {
+                errors.push(format!(
+                    "Rate limit bucket must refill and hold at least 1 \
+                    request: {bucket:?}"
+                ));
+            }
+        }
+        if let Err(error) = crate::period::Period::new(&self.budget_period) {
+            errors.push(format!("Invalid budget_period: {error:#}"));
+        }
+        for (name, job) in &self.jobs {
+            if let Some(schedule) = &job.schedule {
+                if let Err(error) = schedule.parse::<crate::jobs::Cron>() {
+                    errors.push(format!(
+                        "jobs.{name}.schedule is invalid: {error:#}"
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+const REDACTED: &str = "<XXXXX>";
+
+fn redact_all(secrets: &mut [String]) {
+    for secret in secrets {
+        REDACTED.clone_into(secret);
+    }
+}
+
+/// Route and model patterns are matched exactly, unless they end with `*`,
+/// in which case they match any suffix. A pattern starting with `!` denies
+/// what it matches, even if other patterns allow it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Role {
+    pub routes: Vec<String>,
+
+    #[serde(default = "all")]
+    pub models: Vec<String>,
+
+    /// Scales the global budgets (tokens and cost, daily and per-model).
+    #[serde(default = "one")]
+    pub budget_multiplier: f64,
+
+    /// Operator's system message added to every chat request, such as a
+    /// usage policy.
+    #[serde(default)]
+    pub system_prompt: Option<SystemPrompt>,
+
+    /// Whether chat requests are screened by the moderation endpoint.
+    #[serde(default)]
+    pub is_moderated: bool,
+
+    /// Higher goes first out of the queue, when upstream throttles us.
+    #[serde(default)]
+    pub priority: u8,
+
+    /// Upstream endpoints which requests may be forwarded to, without the
+    /// leading slash. Globs, where `*` matches within a path segment and
+    /// `**` across them. Those starting with `!` are denied, taking
+    /// precedence.
+    #[serde(default = "all_paths")]
+    pub upstream_paths: Vec<String>,
+
+    /// Times of day at which the role may be used. Always when not set.
+    #[serde(default)]
+    pub access: Option<Access>,
+
+    /// Whether chat requests may ask, with the x-raskol-dry-run header, for
+    /// a synthetic completion instead of upstream's, for clients to be
+    /// tested without drawing on budgets.
+    #[serde(default)]
+    pub is_dry_run_allowed: bool,
+
+    /// Those its users have, besides any their JWTs' `features` claim
+    /// grants, so that some users can be given more without a role of
+    /// their own. All when not set.
+    #[serde(default)]
+    pub features: Option<Vec<Feature>>,
+}
+
+/// Capabilities which can be granted per user, per [`Role::features`].
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// Chat responses as server-sent events.
+    Streaming,
+
+    /// Images in chat messages.
+    Vision,
+
+    /// Tool, or function, definitions in chat requests.
+    Tools,
+
+    /// The /batches endpoints.
+    Batch,
+
+    /// The /ws/chat endpoint.
+    Ws,
+}
+
+impl Feature {
+    pub const ALL: [Self; 5] = [
+        Self::Streaming,
+        Self::Vision,
+        Self::Tools,
+        Self::Batch,
+        Self::Ws,
+    ];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Streaming => "streaming",
+            Self::Vision => "vision",
+            Self::Tools => "tools",
+            Self::Batch => "batch",
+            Self::Ws => "ws",
+        }
+    }
+}
+
+/// Per [`crate::schedule`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Access {
+    /// IANA name, e.g. "Europe/Berlin", of the event's local time.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+
+    /// "HH:MM-HH:MM", e.g. "08:00-24:00". Those ending before they start
+    /// span midnight.
+    pub windows: Vec<String>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SystemPrompt {
+    pub content: String,
+
+    /// Before or after the client's messages.
+    #[serde(default)]
+    pub position: Position,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    #[default]
+    Prepend,
+    Append,
+}
+
+impl Role {
+    #[must_use]
+    pub fn is_route_allowed(&self, path: &str) -> bool {
+        is_allowed(&self.routes, path)
+    }
+
+    /// Of the role, or granted, as by a JWT's claim.
+    #[must_use]
+    pub fn has_feature(&self, feature: Feature, granted: &[String]) -> bool {
+        self.features
+            .as_ref()
+            .is_none_or(|features| features.contains(&feature))
+            || granted.iter().any(|granted| granted == feature.as_str())
+    }
+
+    #[must_use]
+    pub fn is_model_allowed(&self, model: &str) -> bool {
+        is_allowed(&self.models, model)
+    }
+
+    #[must_use]
+    pub fn is_upstream_allowed(&self, endpoint: &str) -> bool {
+        let mut is_allowed = false;
+        for pattern in &self.upstream_paths {
+            match pattern.strip_prefix('!') {
+                Some(pattern) if glob_matches(pattern, endpoint) => {
+                    return false
+                }
+                Some(_) => {}
+                None => {
+                    is_allowed =
+                        is_allowed || glob_matches(pattern, endpoint);
+                }
+            }
+        }
+        is_allowed
+    }
+
+    /// The allowing model patterns, for telling users what they may use.
+    pub fn allowed_models(&self) -> impl Iterator<Item = &str> {
+        self.models
+            .iter()
+            .map(String::as_str)
+            .filter(|pattern| !pattern.starts_with('!'))
+    }
+}
+
+/// Exactly, or by prefix if the pattern ends with `*`.
+#[must_use]
+pub fn matches(pattern: &str, s: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => s.starts_with(prefix),
+        None => s == pattern,
+    }
+}
+
+/// Of paths: `*` matches within a segment, `**` across them.
+#[must_use]
+pub fn glob_matches(pattern: &str, path: &str) -> bool {
+    let Some(star) = pattern.find('*') else {
+        return pattern == path;
+    };
+    let (literal, rest) = pattern.split_at(star);
+    let Some(path) = path.strip_prefix(literal) else {
+        return false;
+    };
+    let (is_across, rest) = match rest.strip_prefix("**") {
+        Some(rest) => (true, rest),
+        None => (false, &rest[1..]),
+    };
+    for end in (0..=path.len()).filter(|&end| path.is_char_boundary(end)) {
+        if !is_across && path[..end].contains('/') {
+            break;
+        }
+        if glob_matches(rest, &path[end..]) {
+            return true;
+        }
+    }
+    false
+}
+
+fn is_allowed(patterns: &[String], s: &str) -> bool {
+    let mut is_allowed = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if matches(pattern, s) => return false,
+            Some(_) => {}
+            None => is_allowed = is_allowed || matches(pattern, s),
+        }
+    }
+    is_allowed
+}
+
+fn default_idempotency_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_models_endpoint() -> String {
+    "openai/v1/models".to_string()
+}
+
+fn all() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn all_paths() -> Vec<String> {
+    vec!["**".to_string()]
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn default_legacy_routes() -> bool {
+    true
+}
+
+fn default_context_windows() -> BTreeMap<String, usize> {
+    BTreeMap::from([
+        ("gemma2-9b-it".to_string(), 8_192),
+        ("llama-3.1-8b-instant".to_string(), 131_072),
+        ("llama-3.3-70b-versatile".to_string(), 131_072),
+        ("mixtral-8x7b-32768".to_string(), 32_768),
+    ])
+}
+
+fn default_roles() -> BTreeMap<String, Role> {
+    BTreeMap::from([
+        (
+            crate::auth::ROLE_HACKER.to_string(),
+            Role {
+                routes: vec![
+                    "/*".to_string(),
+                    "!/admin/*".to_string(),
+                    "!/total-stats".to_string(),
+                ],
+                models: all(),
+                budget_multiplier: 1.0,
+                system_prompt: None,
+                is_moderated: false,
+                priority: 0,
+                upstream_paths: all_paths(),
+                access: None,
+                is_dry_run_allowed: false,
+                features: None,
+            },
+        ),
+        (
+            crate::auth::ROLE_ORG_ADMIN.to_string(),
+            Role {
+                // All of the admin routes but minting, each, since a
+                // denying pattern can't be overridden.
+                routes: vec![
+                    "/*".to_string(),
+                    "!/admin/users/*".to_string(),
+                    "!/admin/orgs/*".to_string(),
+                    "!/admin/suspensions*".to_string(),
+                    "!/admin/events*".to_string(),
+                    "!/admin/errors*".to_string(),
+                    "!/admin/logs*".to_string(),
+                    "!/admin/shadow*".to_string(),
+                    "!/admin/experiments*".to_string(),
+                    "!/admin/slow*".to_string(),
+                    "!/admin/jobs*".to_string(),
+                    "!/admin/deprecated*".to_string(),
+                    "!/admin/tags*".to_string(),
+                    "!/admin/units*".to_string(),
+                    "!/admin/billing*".to_string(),
+                    "!/admin/signups*".to_string(),
+                    "!/admin/log-level*".to_string(),
+                    "!/admin/replay/*".to_string(),
+                    "!/admin/graphql*".to_string(),
+                    "!/total-stats".to_string(),
+                ],
+                models: all(),
+                budget_multiplier: 1.0,
+                system_prompt: None,
+                is_moderated: false,
+                priority: 0,
+                upstream_paths: all_paths(),
+                access: None,
+                is_dry_run_allowed: false,
+                features: None,
+            },
+        ),
+        (
+            crate::auth::ROLE_ANALYST.to_string(),
+            Role {
+                routes: vec![
+                    "/stats*".to_string(),
+                    "/total-stats".to_string(),
+                    "/leaderboard".to_string(),
+                    "/admin/logs".to_string(),
+                ],
+                models: Vec::new(),
+                budget_multiplier: 1.0,
+                system_prompt: None,
+                is_moderated: false,
+                priority: 0,
+                upstream_paths: Vec::new(),
+                access: None,
+                is_dry_run_allowed: false,
+                features: None,
+            },
+        ),
+        (
+            crate::auth::ROLE_ADMIN.to_string(),
+            Role {
+                routes: all(),
+                models: all(),
+                budget_multiplier: 1.0,
+                system_prompt: None,
+                is_moderated: false,
+                priority: 1,
+                upstream_paths: all_paths(),
+                access: None,
+                is_dry_run_allowed: true,
+                features: None,
+            },
+        ),
+    ])
+}
+
+/// Client used for upstream requests. Durations are in seconds.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Http {
+    pub connect_timeout: f32,
+
+    /// Whole request, including receiving the response body.
+    pub request_timeout: f32,
+
+    /// Idle connections are kept open for reuse this long.
+    pub pool_idle_timeout: f32,
+    pub pool_max_idle_per_host: usize,
+    pub tcp_keepalive: f32,
+
+    /// Use HTTP/2 when upstream supports it, otherwise only HTTP/1.1.
+    pub http2: bool,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10.0,
+            request_timeout: 300.0,
+            pool_idle_timeout: 90.0,
+            pool_max_idle_per_host: 32,
+            tcp_keepalive: 60.0,
+            http2: true,
+        }
+    }
+}
+
+impl Http {
+    pub fn client(
+        &self,
+        compression: &Compression,
+    ) -> reqwest::Result<reqwest::Client> {
+        let builder = reqwest::Client::builder()
+            .gzip(compression.upstream)
+            .brotli(compression.upstream)
+            .connect_timeout(Duration::from_secs_f32(self.connect_timeout))
+            .timeout(Duration::from_secs_f32(self.request_timeout))
+            .pool_idle_timeout(Duration::from_secs_f32(
+                self.pool_idle_timeout,
+            ))
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .tcp_keepalive(Duration::from_secs_f32(self.tcp_keepalive));
+        let builder = if self.http2 {
+            builder
+        } else {
+            builder.http1_only()
+        };
+        builder.build()
+    }
+}
+
+/// Of response bodies, gzip or brotli, as the other side accepts.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Compression {
+    /// Ours to clients. Event streams are never compressed.
+    pub responses: bool,
+
+    /// Upstream's to us, decompressed as received, since we read them for
+    /// usage.
+    pub upstream: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            responses: true,
+            upstream: true,
+        }
+    }
+}
+
+/// Each off unless set.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Hooks {
+    /// Header every API request must carry, such as one our proxy adds.
+    pub require_header: Option<String>,
+
+    /// Of all of a chat request's messages.
+    pub max_prompt_chars: Option<usize>,
+
+    /// Run in order, after the above. Needs the `wasm` feature.
+    pub wasm: Vec<WasmPlugin>,
+}
+
+/// A WebAssembly module which may rewrite or reject chat requests. See
+/// [`crate::wasm`] for what it must export.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct WasmPlugin {
+    pub path: PathBuf,
+
+    /// Roughly instructions, per request. Running out fails the request.
+    #[serde(default = "default_wasm_fuel")]
+    pub fuel: u64,
+
+    #[serde(default = "default_wasm_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+}
+
+fn default_wasm_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_wasm_max_memory_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+/// Retries of transient upstream failures: 429s, 5xxs and connection errors.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Retry {
+    /// Including the first one. 1 means no retries.
+    pub max_attempts: u32,
+
+    /// Seconds. Doubled after each attempt.
+    pub initial_backoff: f32,
+
+    /// Seconds. Upstream asking to retry after longer than this is not
+    /// retried.
+    pub max_backoff: f32,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: 0.5,
+            max_backoff: 8.0,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Queue {
+    /// Seconds a request may wait before it is failed after all.
+    pub max_wait: f32,
+
+    /// Requests beyond this many waiting are failed right away.
+    pub max_len: usize,
+
+    /// Seconds of waiting after which requests are bumped up a priority
+    /// level, so that lower priorities are not starved by higher ones.
+    pub aging: f32,
+
+    /// Fraction, from 0 to 1, of upstream's rate limits left, below which
+    /// requests of roles with a priority under `reserved_priority` are held,
+    /// so that the rest still get through before upstream throttles us.
+    /// Never when not set.
+    #[serde(default)]
+    pub min_headroom: Option<f64>,
+
+    /// Roles of at least this priority are never held for headroom.
+    #[serde(default = "default_reserved_priority")]
+    pub reserved_priority: u8,
+
+    /// Requests per minute upstream allows us, shared out between orgs per
+    /// `reserved_rpm`, per instance. Not shared out when not set.
+    #[serde(default)]
+    pub upstream_rpm: Option<u64>,
+
+    /// Of `upstream_rpm`, requests per minute reserved for each org, unless
+    /// an admin set its own, so that others' spikes can't take it all. What
+    /// is left unreserved is shared fairly between all who are after it,
+    /// users of no org included.
+    #[serde(default)]
+    pub reserved_rpm: BTreeMap<String, u64>,
+}
+
+fn default_reserved_priority() -> u8 {
+    1
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            max_wait: 30.0,
+            max_len: 100,
+            aging: 10.0,
+            min_headroom: None,
+            reserved_priority: default_reserved_priority(),
+            upstream_rpm: None,
+            reserved_rpm: BTreeMap::new(),
+        }
+    }
+}
+
+/// Defaults for orgs without their own budgets set by an admin. No limit
+/// when not set.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct Orgs {
+    #[serde(default)]
+    pub max_tokens_per_day: Option<u64>,
+
+    /// In the same currency as pricing.
+    #[serde(default)]
+    pub max_cost_per_day: Option<f64>,
+}
+
+/// Thresholds of usage within the window, beyond which users are
+/// suspended. Those not set are not checked.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Abuse {
+    /// Seconds of recent usage to judge by.
+    #[serde(default = "default_abuse_window")]
+    pub window: u64,
+
+    pub max_requests: Option<u64>,
+
+    /// Input plus output.
+    pub max_tokens: Option<u64>,
+
+    /// Of the requests, the fraction which failed by the client's fault,
+    /// per [`crate::data::ErrorClass::is_client_caused`], rather than by
+    /// upstream's, or ours, or the budget's.
+    pub max_error_ratio: Option<f64>,
+
+    /// Users with fewer requests are not judged by their error ratio.
+    #[serde(default = "default_abuse_min_requests")]
+    pub min_requests: u64,
+
+    /// Patterns of uids which are never suspended automatically.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+fn default_abuse_window() -> u64 {
+    5 * 60
+}
+
+fn default_abuse_min_requests() -> u64 {
+    50
+}
+
+impl Default for Abuse {
+    fn default() -> Self {
+        Self {
+            window: default_abuse_window(),
+            max_requests: Some(1000),
+            max_tokens: Some(1_000_000),
+            max_error_ratio: Some(0.9),
+            min_requests: default_abuse_min_requests(),
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Per [`crate::duplicates`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Duplicates {
+    /// Seconds within which requests with the same prompt count as
+    /// repeats.
+    #[serde(default = "default_duplicates_window")]
+    pub window: u64,
+
+    /// Of requests with the same prompt within the window, beyond which
+    /// they are rejected.
+    #[serde(default = "default_duplicates_max_requests")]
+    pub max_requests: usize,
+}
+
+fn default_duplicates_window() -> u64 {
+    60
+}
+
+fn default_duplicates_max_requests() -> usize {
+    5
+}
+
+/// Per upstream provider.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CircuitBreaker {
+    /// Consecutive connection errors or 5xxs after which we stop sending.
+    pub failure_threshold: u32,
+
+    /// Seconds before letting a probe request through.
+    pub open_duration: f32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: 30.0,
+        }
+    }
+}
+
+/// Readiness checks, at `/health/ready`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Health {
+    /// Whether to make a request to each provider, rather than only
+    /// report their circuit breaker states.
+    pub probe_upstream: bool,
+
+    /// Seconds.
+    pub probe_timeout: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            probe_upstream: false,
+            probe_timeout: 5.0,
+        }
+    }
+}
+
+/// Per [`crate::public`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PublicStats {
+    /// Seconds the numbers are served for before they are counted again,
+    /// however many screens ask for them.
+    pub cache_ttl: u64,
+}
+
+impl Default for PublicStats {
+    fn default() -> Self {
+        Self { cache_ttl: 10 }
+    }
+}
+
+/// Per [`crate::peers`]. Peers must take the same JWTs, so must share
+/// [`Jwt`] conf, and storage, for budgets to hold across them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Peers {
+    /// Of each, where this one reaches it, as "https://b.example.com".
+    pub urls: Vec<String>,
+
+    /// Seconds between heartbeats.
+    pub interval: u64,
+
+    /// Seconds, of each heartbeat.
+    pub timeout: f32,
+
+    /// Seconds since a peer was last healthy, after which it is no longer
+    /// proxied to.
+    pub stale_after: u64,
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            interval: 5,
+            timeout: 2.0,
+            stale_after: 15,
+        }
+    }
+}
+
+/// Per [`crate::deprecation`]. By pattern, as those of roles, e.g.
+/// "gpt-3.5*" or "/v1/completions".
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Deprecations {
+    /// As requested, before any experiment, or overage, of ours.
+    pub models: BTreeMap<String, Deprecation>,
+
+    /// As those of roles, without the version prefix.
+    pub routes: BTreeMap<String, Deprecation>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Deprecation {
+    /// As "2025-06-30", UTC, when it is to be removed.
+    pub sunset: Option<String>,
+
+    /// The model or route to use instead.
+    pub successor: Option<String>,
+}
+
+/// Per [`crate::prober`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Prober {
+    /// Seconds between probes.
+    pub interval: u64,
+
+    /// Seconds, of each probe.
+    pub timeout: f32,
+
+    /// Of probes kept, and summarized at `/status`.
+    pub history_hours: u64,
+}
+
+impl Default for Prober {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            timeout: 5.0,
+            history_hours: 24,
+        }
+    }
+}
+
+/// Today's top users, at `/v1/leaderboard`. Who may see it is up to the
+/// roles' routes.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Leaderboard {
+    /// How many users to rank.
+    pub size: u64,
+
+    /// Whether to show hashes of uids, rather than the uids.
+    pub anonymize: bool,
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Self {
+            size: 10,
+            anonymize: false,
+        }
+    }
+}
+
+/// At `/v1/signup`, which issues tokens with a starter budget, until an admin
+/// upgrades the user.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Signup {
+    /// Of the tokens issued, in seconds.
+    pub ttl: f64,
+
+    /// Of the tokens issued.
+    pub role: String,
+
+    /// Starter budget, carried by the tokens issued.
+    pub max_tokens_per_day: u64,
+}
+
+impl Default for Signup {
+    fn default() -> Self {
+        Self {
+            ttl: 7.0 * 24.0 * 3600.0,
+            role: crate::auth::ROLE_HACKER.to_string(),
+            max_tokens_per_day: 10_000,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Batches {
+    /// Of requests in a batch.
+    pub max_requests: usize,
+}
+
+impl Default for Batches {
+    fn default() -> Self {
+        Self {
+            max_requests: 10_000,
+        }
+    }
+}
+
+/// Correction of prompt token estimates per model, by how they compared
+/// with the actual counts upstream reported.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Calibration {
+    /// Over how many recent days to compare.
+    pub window_days: u32,
+
+    /// Models with fewer successful requests in the window are left as they
+    /// were.
+    pub min_samples: u64,
+
+    /// Bounds factors to [1 / max_factor, max_factor], in case of outliers.
+    pub max_factor: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            window_days: 7,
+            min_samples: 20,
+            max_factor: 4.0,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct Log {
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// Written in addition to stderr.
+    #[serde(default)]
+    pub file: Option<LogFile>,
+
+    /// Per-target filters, such as "sqlx=warn" or "raskol::upstream=debug",
+    /// on top of log_level.
+    #[serde(default)]
+    pub directives: Vec<String>,
+
+    /// One line per request, apart from the above. Off when not set.
+    #[serde(default)]
+    pub access: Option<AccessLog>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct AccessLog {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+
+    /// Appended to, instead of writing to stdout. Relative to the data dir.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// One object per line.
+    #[default]
+    Json,
+
+    /// Common Log Format, with ours after, as key=value.
+    Clf,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable, colored on stderr.
+    #[default]
+    Pretty,
+
+    /// One object per line, for shipping to log aggregators.
+    Json,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BudgetPeriod {
+    pub length: PeriodLength,
+
+    /// IANA name, e.g. "America/New_York".
+    pub timezone: String,
+
+    /// Local time of day ("HH:MM") at which periods start. Only the minutes
+    /// matter for hourly ones.
+    pub reset_at: String,
+}
+
+impl Default for BudgetPeriod {
+    fn default() -> Self {
+        Self {
+            length: PeriodLength::default(),
+            timezone: "UTC".to_string(),
+            reset_at: "00:00".to_string(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Rollover {
+    /// Credits beyond which nothing more is rolled over. Those granted by
+    /// admins count too.
+    pub max_credits: u64,
+}
+
+impl Default for Rollover {
+    fn default() -> Self {
+        Self {
+            max_credits: 1_000_000,
+        }
+    }
+}
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PeriodLength {
+    Hour,
+    #[default]
+    Day,
+
+    /// Starting on Mondays.
+    Week,
+
+    /// Starting on the 1st.
+    Month,
+}
+
+/// Rotated daily.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct LogFile {
+    /// Relative to the data dir.
+    #[serde(default = "default_log_dir")]
+    pub dir: PathBuf,
+
+    /// Of the file names, which are suffixed with the date.
+    #[serde(default = "default_log_prefix")]
+    pub prefix: String,
+
+    /// Older files are deleted. All are kept when not set.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+fn default_log_dir() -> PathBuf {
+    PathBuf::from("logs")
+}
+
+fn default_log_prefix() -> String {
+    "raskol.log".to_string()
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Https,
+    Http,
+}
+
+impl Scheme {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Https => "https",
+            Self::Http => "http",
+        }
+    }
+}
+
+/// API format spoken by a provider. Clients always speak OpenAI's.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    #[default]
+    OpenAi,
+    Anthropic,
+    Azure,
+}
+
+/// Azure OpenAI serves models by deployments, named by whoever deployed
+/// them, under a resource address such as "{resource}.openai.azure.com".
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Azure {
+    pub api_version: String,
+
+    /// Model -> deployment. Models not listed are assumed to be deployed
+    /// under their own names.
+    #[serde(default)]
+    pub deployments: BTreeMap<String, String>,
+}
+
+impl Default for Azure {
+    fn default() -> Self {
+        Self {
+            api_version: "2024-10-21".to_string(),
+            deployments: BTreeMap::new(),
+        }
+    }
+}
+
+/// What to scrub from logged request and response content.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Redaction {
+    /// Regular expressions. Matches are replaced with "[REDACTED]".
+    pub patterns: Vec<String>,
+
+    /// Also scrub error messages stored in request logs.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Self {
+            patterns: vec![
+                // Emails
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string(),
+                // Phone numbers
+                r"\+?\d[\d ().-]{7,}\d".to_string(),
+                // API keys: OpenAI, Groq, ours
+                r"\b(sk-|gsk_|rsk_)[A-Za-z0-9_-]{16,}".to_string(),
+                // JWTs
+                r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+"
+                    .to_string(),
+            ],
+            strict: false,
+        }
+    }
+}
+
+/// An OpenAI-compatible moderation endpoint, such as OpenAI's own or a
+/// local classifier.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Moderation {
+    /// Full URL, e.g. "https://api.openai.com/v1/moderations".
+    pub url: String,
+
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// Categories to reject. Any flagged one when empty.
+    #[serde(default)]
+    pub categories: Vec<String>,
+
+    /// Seconds.
+    #[serde(default = "default_moderation_timeout")]
+    pub timeout: f32,
+}
+
+/// Per [`crate::compress`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PromptCompression {
+    /// Estimated prompt tokens above which to compress. The model's context
+    /// window, less the completion's max_tokens, when not set, so off for
+    /// models without one.
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+
+    /// Applied in order, each only while the prompt is still over.
+    #[serde(default = "default_compression_strategies")]
+    pub strategies: Vec<CompressionStrategy>,
+
+    /// Of messages, past which truncate_middle cuts them.
+    #[serde(default = "default_max_message_chars")]
+    pub max_message_chars: usize,
+}
+
+fn default_compression_strategies() -> Vec<CompressionStrategy> {
+    vec![
+        CompressionStrategy::TruncateMiddle,
+        CompressionStrategy::DropOldest,
+    ]
+}
+
+fn default_max_message_chars() -> usize {
+    8_000
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionStrategy {
+    /// Messages, apart from system ones and the last, with the tool results
+    /// of their calls.
+    DropOldest,
+
+    /// Of text messages longer than max_message_chars, apart from system
+    /// ones, keeping their start and end.
+    TruncateMiddle,
+}
+
+/// Per [`crate::slow`]. Per instance, as kept in memory.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SlowRequests {
+    /// Requests kept of each window, the slowest.
+    pub per_window: usize,
+
+    /// Seconds.
+    pub window: u64,
+
+    /// Of the latest, how many are kept.
+    pub windows: usize,
+}
+
+impl Default for SlowRequests {
+    fn default() -> Self {
+        Self {
+            per_window: 10,
+            window: 3600,
+            windows: 24,
+        }
+    }
+}
+
+/// Per [`crate::jobs`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Job {
+    /// Cron-like, of 5 fields, in UTC, e.g. "0 3 * * *". The default
+    /// interval, starting right away, when not set.
+    pub schedule: Option<String>,
+    pub is_enabled: bool,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Self {
+            schedule: None,
+            is_enabled: true,
+        }
+    }
+}
+
+/// Per [`crate::filter`]. Completions which were to be streamed are asked
+/// of upstream whole instead, and streamed to the client once checked, all
+/// at once, so that nothing passes unchecked.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ResponseFilter {
+    /// Of the content of each choice.
+    #[serde(default)]
+    pub max_chars: Option<usize>,
+
+    /// Regular expressions which the content of no choice may match.
+    #[serde(default)]
+    pub banned: Vec<String>,
+
+    /// Whether completions must be JSON (matching the schema) when the
+    /// client asked for it in response_format.
+    #[serde(default)]
+    pub json: bool,
+
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+/// Of completions which violate [`ResponseFilter`], with each violation
+/// logged either way.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Banned matches replaced with "[REDACTED]", and content cut short at
+    /// max_chars. JSON which is invalid is passed on as is.
+    Redact,
+
+    /// The violations added to the response, in `raskol_violations`.
+    #[default]
+    Annotate,
+
+    /// With a "response_rejected" error instead.
+    Reject,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Grpc {
+    /// Apart from the HTTP one, so that it needn't be exposed as widely.
+    pub addr: SocketAddr,
+
+    /// Callers must send it as a bearer token. Only optional on loopback,
+    /// where callers are of this host anyway.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Grpc {
+    /// Fails without a token, unless bound to loopback, since callers can
+    /// mint any token and spend any user's budget.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.token.is_none() && !self.addr.ip().is_loopback() {
+            return Err(anyhow::anyhow!(
+                "grpc.token is required, unless grpc.addr is of loopback, \
+                not {}.",
+                self.addr
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// In the `x-raskol-attestation` header, per [`crate::attest`], or, of
+/// streams, in a comment ending them, as `: x-raskol-attestation: …`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Attestation {
+    /// Shared with those who verify, such as judges, so apart from the JWT
+    /// secret, with which they could mint tokens.
+    pub secret: String,
+}
+
+/// Per [`crate::stripe`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Stripe {
+    /// Secret or restricted key, "sk_..." or "rk_...".
+    pub api_key: String,
+
+    /// Of the meter, as set up in Stripe.
+    pub event_name: String,
+
+    #[serde(default)]
+    pub measure: StripeMeasure,
+
+    /// Seconds between checks for days to report.
+    #[serde(default = "default_stripe_interval")]
+    pub interval: u64,
+
+    /// Closed days back to report, if not yet. Stripe rejects events older
+    /// than 35 days.
+    #[serde(default = "default_stripe_lookback_days")]
+    pub lookback_days: u32,
+
+    /// Only log what would be reported.
+    #[serde(default)]
+    pub dry_run: bool,
+
+    #[serde(default = "default_stripe_api_base")]
+    pub api_base: String,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum StripeMeasure {
+    /// Input and output.
+    #[default]
+    Tokens,
+
+    /// Cost, per conf.pricing, in cents, rounded.
+    Cents,
+}
+
+/// Per [`crate::spend`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct SpendCaps {
+    /// Of all providers together.
+    #[serde(default)]
+    pub total: SpendCap,
+
+    /// Provider ("primary", "failover" or "shadow") -> its own.
+    #[serde(default)]
+    pub providers: BTreeMap<String, SpendCap>,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+pub struct SpendCap {
+    /// Unlimited when not set.
+    #[serde(default)]
+    pub max_tokens_per_day: Option<u64>,
+
+    /// In the same currency as pricing. Unlimited when not set.
+    #[serde(default)]
+    pub max_cost_per_day: Option<f64>,
+}
+
+/// Per [`crate::overage`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Overage {
+    /// Of the daily token budget, past it, up to which chat requests are
+    /// still served.
+    #[serde(default = "default_overage_max_percent")]
+    pub max_percent: f64,
+
+    /// Completion tokens, at most, of requests served past the budget.
+    #[serde(default = "default_overage_max_tokens")]
+    pub max_tokens: usize,
+
+    /// Model -> a cheaper one, which requests served past the budget are
+    /// routed to instead.
+    #[serde(default)]
+    pub models: BTreeMap<String, String>,
+
+    /// Of requests served past the budget, in addition to the usual one.
+    #[serde(default = "default_overage_rate_limit")]
+    pub rate_limit: RateLimit,
+}
+
+fn default_overage_max_percent() -> f64 {
+    20.0
+}
+
+fn default_overage_max_tokens() -> usize {
+    256
+}
+
+fn default_overage_rate_limit() -> RateLimit {
+    RateLimit {
+        burst: None,
+        requests_per_minute: Some(2),
+        requests_per_hour: None,
+        bucket: None,
+    }
+}
+
+/// Per [`crate::push`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct MetricsPush {
+    pub kind: MetricsPushKind,
+
+    /// Of the Pushgateway, e.g. "http://gateway:9091", or host:port of the
+    /// statsd server, e.g. "127.0.0.1:8125".
+    pub address: String,
+
+    /// Seconds between pushes.
+    #[serde(default = "default_metrics_push_interval")]
+    pub interval: u64,
+
+    /// Grouping the metrics in the Pushgateway. Not used by statsd.
+    #[serde(default = "default_metrics_push_job")]
+    pub job: String,
+
+    /// Of this instance, in the Pushgateway's grouping, when several push
+    /// the same job. Not used by statsd.
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsPushKind {
+    /// Prometheus Pushgateway, over HTTP.
+    Pushgateway,
+
+    /// Over UDP, with labels folded into the names.
+    Statsd,
+
+    /// Datadog's statsd, over UDP, with labels as tags.
+    Dogstatsd,
+}
+
+fn default_metrics_push_interval() -> u64 {
+    15
+}
+
+fn default_metrics_push_job() -> String {
+    "raskol".to_string()
+}
+
+fn default_stripe_interval() -> u64 {
+    3600
+}
+
+fn default_stripe_lookback_days() -> u32 {
+    7
+}
+
+fn default_stripe_api_base() -> String {
+    "https://api.stripe.com".to_string()
+}
+
+fn default_moderation_timeout() -> f32 {
+    5.0
+}
+
+/// Audio transcription and translation uploads.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Audio {
+    pub max_upload_bytes: usize,
+
+    /// Tokens charged against budgets per second of audio.
+    pub tokens_per_second: f64,
+
+    /// Assumed bit rate, for estimating the duration of an upload before
+    /// upstream reports it.
+    pub bytes_per_second: f64,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self {
+            max_upload_bytes: 25 * 1024 * 1024,
+            tokens_per_second: 10.0,
+            // 128 kbps.
+            bytes_per_second: 16_000.0,
+        }
+    }
+}
+
+/// Requests to other endpoints are rejected, since we couldn't account for
+/// them otherwise.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Passthrough {
+    /// Without the leading slash, e.g. "v1/embeddings". Patterns as in
+    /// roles' routes: exact, or by prefix if ending with `*`.
+    pub endpoints: Vec<String>,
+
+    /// For estimating the tokens of a request by its size, until upstream
+    /// reports them.
+    pub bytes_per_token: f64,
+
+    pub max_body_bytes: usize,
+}
+
+impl Default for Passthrough {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            bytes_per_token: 4.0,
+            max_body_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Any other client headers are stripped, so that nothing (such as the
+/// client's own auth) leaks upstream.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Headers {
+    /// Client headers passed on as is. Case-insensitive.
+    pub forward: Vec<String>,
+
+    /// Whether to tell upstream the client's address in X-Forwarded-For.
+    pub forwarded_for: bool,
+
+    /// Upstream headers passed back to clients, prefixed with "x-upstream-"
+    /// (instead of "x-", if they have it) so as not to be confused with
+    /// ours, e.g. "x-ratelimit-remaining-requests" as
+    /// "x-upstream-ratelimit-remaining-requests".
+    pub pass_back: Vec<String>,
+}
+
+impl Headers {
+    /// Set by us, or by reqwest, whatever the conf says.
+    pub const NEVER_FORWARDED: [&'static str; 10] = [
+        "authorization",
+        "api-key",
+        "x-api-key",
+        "cookie",
+        "host",
+        "content-length",
+        "content-type",
+        "transfer-encoding",
+        // Which reqwest negotiates, per conf.compression.upstream.
+        "accept-encoding",
+        "x-request-id",
+    ];
+}
+
+impl Default for Headers {
+    fn default() -> Self {
+        Self {
+            forward: ["accept", "user-agent", "openai-beta"]
+                .map(String::from)
+                .to_vec(),
+            forwarded_for: true,
+            pass_back: [
+                "x-ratelimit-limit-requests",
+                "x-ratelimit-limit-tokens",
+                "x-ratelimit-remaining-requests",
+                "x-ratelimit-remaining-tokens",
+                "x-ratelimit-reset-requests",
+                "x-ratelimit-reset-tokens",
+                "openai-processing-ms",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Files {
+    /// Unless the role's limits say otherwise.
+    pub max_upload_bytes: usize,
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Self {
+            max_upload_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// Text-to-speech and image generation are measured in characters and
+/// images rather than tokens, which are charged against budgets at fixed
+/// rates.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Media {
+    /// Per character of speech input.
+    pub tokens_per_character: f64,
+
+    /// Per 1024x1024 image, and in proportion to the pixels of other sizes.
+    pub tokens_per_image: f64,
+
+    /// Model -> USD per character, or per image, in proportion like tokens.
+    pub unit_prices: BTreeMap<String, f64>,
+}
+
+impl Default for Media {
+    fn default() -> Self {
+        Self {
+            tokens_per_character: 0.25,
+            tokens_per_image: 1000.0,
+            unit_prices: BTreeMap::new(),
+        }
+    }
+}
+
+/// Realtime sessions are charged by the time they are connected, since
+/// their audio is not tokenized here.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Realtime {
+    /// Tokens charged against budgets per second of a session.
+    pub tokens_per_second: f64,
+
+    /// Seconds of a session reserved from the budget when it starts.
+    pub reserve_secs: f64,
+
+    /// Seconds after which sessions are closed.
+    pub max_secs: u64,
+}
+
+impl Default for Realtime {
+    fn default() -> Self {
+        Self {
+            tokens_per_second: 10.0,
+            reserve_secs: 60.0,
+            max_secs: 30 * 60,
+        }
+    }
+}
+
+/// Sessions are closed once idle, and their credentials are checked again
+/// for each request, so that they don't outlive a revocation or expiry.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Ws {
+    /// Seconds without a message from the client after which sessions are
+    /// closed.
+    pub idle_secs: u64,
+}
+
+impl Default for Ws {
+    fn default() -> Self {
+        Self { idle_secs: 5 * 60 }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Failover {
+    pub target_address: String,
+
+    #[serde(default)]
+    pub target_scheme: Scheme,
+
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub target_auth_token: Vec<String>,
+
+    #[serde(default)]
+    pub dialect: Dialect,
+
+    #[serde(default)]
+    pub azure: Azure,
+
+    /// Model to request from the failover provider instead of the one
+    /// requested by the user, since providers name models differently.
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// Where the failover provider lists its models, if elsewhere than the
+    /// primary.
+    #[serde(default)]
+    pub models_endpoint: Option<String>,
+}
+
+/// Only the primary's responses reach clients. The mirrors' usage is ours,
+/// not counted against anyone's budget.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Shadow {
+    /// Whose requests are mirrored. Everyone's when empty.
+    #[serde(default)]
+    pub roles: Vec<String>,
+
+    /// Share of their requests which is mirrored, from 0 to 1.
+    #[serde(default = "one")]
+    pub fraction: f64,
+
+    /// Model to mirror to instead of the one requested.
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// Where to mirror to. Never the primary, whose spend it would double.
+    pub provider: Failover,
+}
+
+impl Shadow {
+    /// Whether to mirror a request of the role, at random.
+    #[must_use]
+    pub fn is_mirrored(&self, role: &str) -> bool {
+        (self.roles.is_empty() || self.roles.iter().any(|r| r == role))
+            && rand::random::<f64>() < self.fraction
+    }
+}
+
+/// Routes a share of the chat requests for a model to another, tagging
+/// their logs with the arm they were in, to compare the two.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Experiment {
+    /// As requested.
+    pub model: String,
+
+    pub alternative: String,
+
+    /// Of the requests for the model, routed to the alternative.
+    pub percent: f64,
+}
+
+impl Experiment {
+    /// At random: whether the request is routed to the alternative.
+    #[must_use]
+    pub fn is_alternative(&self) -> bool {
+        rand::random::<f64>() * 100.0 < self.percent
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Limits {
+    /// Limits for particular models, applied in addition to the global ones.
+    #[serde(default)]
+    pub per_model: BTreeMap<String, ModelLimits>,
+
+    /// Overrides of the global limits for particular roles.
+    #[serde(default)]
+    pub per_role: BTreeMap<String, RoleLimits>,
+
+    /// Of chat request bodies. Those of audio and passthrough endpoints are
+    /// `audio.max_upload_bytes` and `passthrough.max_body_bytes`.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Endpoint -> overriding limit of its request bodies. Patterns as in
+    /// roles' routes: exact, or by prefix if ending with `*`.
+    #[serde(default)]
+    pub max_body_bytes_per_endpoint: BTreeMap<String, usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            per_model: BTreeMap::new(),
+            per_role: BTreeMap::new(),
+            max_body_bytes: default_max_body_bytes(),
+            max_body_bytes_per_endpoint: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct RoleLimits {
+    pub min_hit_interval: Option<f32>,
+    pub rate_limit: Option<RateLimit>,
+
+    /// Cap on the completion length. Also applied to requests which do not
+    /// ask for a particular one.
+    pub max_tokens: Option<usize>,
+
+    pub min_temperature: Option<f64>,
+    pub max_temperature: Option<f64>,
+    pub max_messages: Option<usize>,
+
+    /// Of multipart uploads to provider file APIs. Overrides the global
+    /// `files.max_upload_bytes`.
+    pub max_upload_bytes: Option<usize>,
+
+    /// Of request bodies to any endpoint, other than file uploads.
+    /// Overrides the global and per endpoint limits.
+    pub max_body_bytes: Option<usize>,
+
+    /// Seconds to wait for upstream to respond, queueing included, when
+    /// shorter than `http.request_timeout`.
+    pub request_timeout: Option<f32>,
+
+    /// Whether to clamp max_tokens and temperature into the allowed ranges
+    /// instead of rejecting requests. Too many messages are always rejected.
+    #[serde(default)]
+    pub clamp: bool,
+}
+
+/// Which client addresses may connect at all, checked before auth.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IpFilter {
+    /// Everyone, when empty.
+    pub allow: Vec<IpNet>,
+
+    /// Takes precedence over `allow`.
+    pub deny: Vec<IpNet>,
+
+    /// Per-address limit on the endpoints which need no auth, such as
+    /// /ping and /health.
+    pub public_rate_limit: RateLimit,
+
+    /// Proxies in front, such as nginx or Cloudflare's, whose word on who
+    /// the client is is taken, per `proxy_header`, for the filter, the
+    /// limits and the logs, as is their word on the scheme and host asked
+    /// for, for `/client-config`. Only theirs, since clients can claim
+    /// anything.
+    pub trusted_proxies: Vec<IpNet>,
+
+    pub proxy_header: ProxyHeader,
+}
+
+/// Which the trusted proxies name the hops of a request in, client first.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyHeader {
+    #[default]
+    XForwardedFor,
+
+    /// Per RFC 7239, its `for=`.
+    Forwarded,
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            public_rate_limit: RateLimit {
+                burst: Some(10),
+                requests_per_minute: Some(120),
+                requests_per_hour: None,
+                bucket: None,
+            },
+            trusted_proxies: Vec::new(),
+            proxy_header: ProxyHeader::default(),
+        }
+    }
+}
+
+impl IpFilter {
+    #[must_use]
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6.
+        let ip = ip.to_canonical();
+        let is_in = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&ip));
+        !is_in(&self.deny) && (self.allow.is_empty() || is_in(&self.allow))
+    }
+
+    #[must_use]
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Per-user request counts allowed within sliding windows. Unset means
+/// unlimited.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct RateLimit {
+    /// Max requests within any second.
+    pub burst: Option<u64>,
+    pub requests_per_minute: Option<u64>,
+    pub requests_per_hour: Option<u64>,
+
+    /// Apart from the windows, in place of the minimum interval between
+    /// requests, which allows no fan-out at all. Only of the global and the
+    /// roles' limits.
+    #[serde(default)]
+    pub bucket: Option<Bucket>,
+}
+
+/// Token bucket: requests may be made as fast as they come, while there are
+/// tokens left, which are refilled at the sustained rate.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub struct Bucket {
+    /// Sustained.
+    pub requests_per_minute: f64,
+
+    /// Of requests at once, when the bucket is full.
+    pub burst: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ModelLimits {
+    pub max_tokens_per_day: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub struct Price {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl Price {
+    #[must_use]
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        // Token counts are nowhere near.
+        let (input, output) = (input_tokens as f64, output_tokens as f64);
+        (input * self.input_per_million + output * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// A Unix socket, our own or passed by systemd, for serving behind a
+/// reverse proxy on the same host. Without TLS, which the proxy terminates.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Listen {
+    /// Replacing any socket left there, such as after a crash.
+    pub unix_path: Option<PathBuf>,
+
+    /// Of the socket, e.g. 0o660 for the proxy to share our group.
+    pub unix_mode: u32,
+
+    /// Whether to use the socket passed by systemd socket activation
+    /// (LISTEN_FDS), TCP or Unix, instead of binding one.
+    pub systemd: bool,
+}
+
+impl Listen {
+    #[must_use]
+    pub fn is_set(&self) -> bool {
+        self.unix_path.is_some() || self.systemd
+    }
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            unix_path: None,
+            unix_mode: 0o660,
+            systemd: false,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Tls {
+    Files {
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+    Acme {
+        acme: Acme,
+    },
+}
+
+/// Certificates provisioned and renewed automatically, via the TLS-ALPN-01
+/// challenge, so the server must be reachable on port 443 of the domains.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Acme {
+    pub domains: Vec<String>,
+
+    /// Emails the CA may send expiry notices to.
+    #[serde(default)]
+    pub contact: Vec<String>,
+
+    /// Where the account key and certificates are kept, so that they survive
+    /// restarts. Relative to the data dir.
+    #[serde(default = "default_acme_cache_dir")]
+    pub cache_dir: PathBuf,
+
+    /// Let's Encrypt's staging environment, whose certificates aren't
+    /// trusted, but whose rate limits are generous. For trying things out.
+    #[serde(default)]
+    pub staging: bool,
+}
+
+fn default_acme_cache_dir() -> PathBuf {
+    PathBuf::from("acme")
+}
+
+/// Database backend. SQLite is enough for a single instance, while Postgres
+/// lets several instances share budget state.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Storage {
+    Sqlite { file: PathBuf },
+    Postgres { url: String },
+}
+
+/// Durations are in seconds.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Sqlite {
+    /// Of the pool reads are made from, or of the only one, when writes
+    /// aren't split off.
+    pub max_connections: u32,
+
+    /// For a connection of a pool to be free, before the query fails.
+    pub acquire_timeout: f32,
+
+    pub journal_mode: SqliteJournalMode,
+    pub synchronous: SqliteSynchronous,
+
+    /// Writes made through a connection of their own, one at a time, since
+    /// SQLite allows no more, rather than contending for the lock with
+    /// each other and holding up reads.
+    pub split_writes: bool,
+}
+
+impl Default for Sqlite {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            acquire_timeout: 30.0,
+            journal_mode: SqliteJournalMode::default(),
+            synchronous: SqliteSynchronous::default(),
+            split_writes: true,
+        }
+    }
+}
+
+/// Per SQLite's `PRAGMA journal_mode`.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteJournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+/// Per SQLite's `PRAGMA synchronous`. `normal` is safe from corruption in
+/// WAL mode, losing at most the last writes on power loss.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronous {
+    Off,
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::Sqlite {
+            file: PathBuf::from("data/data.db"),
+        }
+    }
+}
+
+impl Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sqlite { file } => f
+                .debug_struct("conf::Storage::Sqlite")
+                .field("file", file)
+                .finish(),
+            // URL may contain credentials.
+            Self::Postgres { url: _ } => f
+                .debug_struct("conf::Storage::Postgres")
+                .field("url", &REDACTED)
+                .finish(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct Redis {
+    /// E.g. "redis://127.0.0.1:6379/0".
+    pub url: String,
+
+    /// Of all keys, for sharing a Redis with others.
+    pub prefix: String,
+}
+
+impl Default for Redis {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".to_string(),
+            prefix: "raskol:".to_string(),
+        }
+    }
+}
+
+impl Debug for Redis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // URL may contain credentials.
+        f.debug_struct("conf::Redis")
+            .field("url", &REDACTED)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Capture {
+    /// Whose requests are captured. Everyone's when empty.
+    pub uids: Vec<String>,
+
+    /// Of each body, beyond which the rest is dropped.
+    pub max_bytes: usize,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            uids: Vec::new(),
+            max_bytes: 64 * 1024,
+        }
+    }
 }
 
+impl Capture {
+    #[must_use]
+    pub fn is_captured(&self, uid: &str) -> bool {
+        self.uids.is_empty() || self.uids.iter().any(|u| u == uid)
+    }
+}
+
+/// Budget settlements and request logs are queued and written together
+/// periodically, and on shutdown. A crash loses what was queued.
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-pub struct Conf {
-    #[serde(
-        serialize_with = "serialize_log_level",
-        deserialize_with = "deserialize_log_level"
-    )]
-    pub log_level: tracing::Level,
-    pub addr: IpAddr,
-    pub port: u16,
-    pub jwt: Jwt,
-    pub target_address: String,
-    pub target_auth_token: String,
-    pub min_hit_interval: f32,
-    pub max_tokens_per_day: u64,
-    pub sqlite_busy_timeout: f32,
-    pub tls: Option<Tls>,
+pub struct WriteBehind {
+    /// Milliseconds between flushes.
+    pub interval_ms: u64,
+
+    /// Of writes queued, past which the oldest are dropped, as when the
+    /// database is down and flushes keep failing.
+    #[serde(default = "default_write_behind_max_queued")]
+    pub max_queued: usize,
 }
 
-impl Default for Conf {
+impl Default for WriteBehind {
     fn default() -> Self {
         Self {
-            log_level: tracing::Level::INFO,
-            addr: "127.0.0.1".parse().unwrap_or_else(|_| {
-                unreachable!("Fat-fingered default IP address!")
-            }),
-            port: 3001,
-            jwt: Jwt::default(),
-            target_address: "api.groq.com".to_string(),
-            target_auth_token: String::new(),
-            min_hit_interval: 5.0,
-            max_tokens_per_day: 1_000_000, // TODO Revise.
-            sqlite_busy_timeout: 60.0,
-            tls: None,
+            interval_ms: 100,
+            max_queued: default_write_behind_max_queued(),
+        }
+    }
+}
+
+fn default_write_behind_max_queued() -> usize {
+    100_000
+}
+
+/// Periodic checkpointing of the WAL back into the database file, which
+/// otherwise keeps growing under load, and `PRAGMA optimize`. Done while
+/// the server is idle, if it gets to be.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Maintenance {
+    /// Seconds between runs.
+    pub interval: u64,
+
+    /// Seconds without API requests after which the server counts as idle.
+    pub idle: f32,
+
+    /// Also VACUUM, reclaiming the space of deleted rows. Blocks writes
+    /// while it rewrites the whole file, so only done when idle.
+    pub vacuum: bool,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            interval: 60 * 60,
+            idle: 10.0,
+            vacuum: false,
         }
     }
 }
 
+/// Per [`crate::signing`].
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-pub struct Tls {
-    pub cert_file: PathBuf,
-    pub key_file: PathBuf,
+#[serde(default)]
+pub struct Signing {
+    /// Seconds, which timestamps may be off by, either way, and for which
+    /// nonces are remembered.
+    pub max_skew: u64,
+
+    /// Of bodies, which are read whole to check their digest.
+    pub max_body_bytes: usize,
+}
+
+impl Default for Signing {
+    fn default() -> Self {
+        Self {
+            max_skew: 300,
+            max_body_bytes: 32 * 1024 * 1024,
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Jwt {
+    /// Ours are signed with it, and with it, audience and issuer.
     pub secret: String,
     pub audience: String,
     pub issuer: String,
+
+    /// Still accepted, but no longer signed with, so that secrets can be
+    /// rotated without invalidating the tokens out there.
+    #[serde(default)]
+    pub old_secrets: Vec<String>,
+
+    /// HS256 secrets by key ID, such as partners' own, for the tokens which
+    /// name it in their header's `kid`. Those are verified with it alone,
+    /// and it with no others, and only within what it may vouch for, so
+    /// that one leaking compromises only the users of its key.
+    #[serde(default)]
+    pub keyed_secrets: BTreeMap<String, KeyedSecret>,
+
+    /// Accepted besides ours, such as during a migration between issuers.
+    #[serde(default)]
+    pub accepted_audiences: Vec<String>,
+    #[serde(default)]
+    pub accepted_issuers: Vec<String>,
+
+    /// When set, RS256/ES256/EdDSA tokens, other than ours, are verified
+    /// against the keys published at this URL (e.g. Clerk's
+    /// `/.well-known/jwks.json`). HS256 tokens are still verified with
+    /// `secret`.
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+
+    /// When set, ours are signed with it instead of the secret, so that
+    /// others can verify them without being able to mint them. Tokens
+    /// signed with the secret are still accepted.
+    #[serde(default)]
+    pub signing_key: Option<JwtKey>,
+
+    #[serde(default)]
+    pub minting: Minting,
 }
 
 impl Default for Jwt {
@@ -77,21 +2852,143 @@ impl Default for Jwt {
             secret: "super-secret".to_string(),
             audience: "authenticated".to_string(),
             issuer: "https://bright-kitten-41.clerk.accounts.dev".to_string(),
+            old_secrets: Vec::new(),
+            keyed_secrets: BTreeMap::new(),
+            accepted_audiences: Vec::new(),
+            accepted_issuers: Vec::new(),
+            jwks_url: None,
+            signing_key: None,
+            minting: Minting::default(),
         }
     }
 }
 
+impl Jwt {
+    /// Current one first.
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.secret.as_str())
+            .chain(self.old_secrets.iter().map(String::as_str))
+    }
+
+    #[must_use]
+    pub fn audiences(&self) -> Vec<&str> {
+        std::iter::once(self.audience.as_str())
+            .chain(self.accepted_audiences.iter().map(String::as_str))
+            .collect()
+    }
+
+    #[must_use]
+    pub fn issuers(&self) -> Vec<&str> {
+        std::iter::once(self.issuer.as_str())
+            .chain(self.accepted_issuers.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// Per [`Jwt::keyed_secrets`]. Its tokens are rejected unless of one of
+/// the roles, of a user in its namespace and, if set, of its org, and
+/// without budget overrides or features, which only we may grant.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct KeyedSecret {
+    pub secret: String,
+
+    /// Never ADMIN, whatever is here.
+    pub roles: Vec<String>,
+
+    /// Of users, as "acme:", which their "sub" must start with, so that
+    /// partners can't pose as each other's users, nor as ours.
+    pub uid_prefix: String,
+
+    /// Of the tokens, which must claim it.
+    #[serde(default)]
+    pub org: Option<String>,
+}
+
+impl KeyedSecret {
+    /// Whether a token of the claims is within what it may vouch for.
+    #[must_use]
+    pub fn allows(&self, uid: &str, role: &str, org: Option<&str>) -> bool {
+        role != crate::auth::ROLE_ADMIN
+            && self.roles.iter().any(|allowed| allowed == role)
+            && !self.uid_prefix.is_empty()
+            && uid.starts_with(&self.uid_prefix)
+            && self.org.as_deref().is_none_or(|own| org == Some(own))
+    }
+}
+
 impl Debug for Jwt {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("conf::Jwt")
-            .field("secret", &"<XXXXX>")
+            .field("secret", &REDACTED)
             .field("audience", &self.audience)
             .field("issuer", &self.issuer)
+            .field("old_secrets", &[REDACTED].repeat(self.old_secrets.len()))
+            .field(
+                "keyed_secrets",
+                &self.keyed_secrets.keys().collect::<Vec<_>>(),
+            )
+            .field("accepted_audiences", &self.accepted_audiences)
+            .field("accepted_issuers", &self.accepted_issuers)
+            .field("jwks_url", &self.jwks_url)
+            .field("signing_key", &self.signing_key)
+            .field("minting", &self.minting)
             .finish()
     }
 }
 
-fn serialize_log_level<S>(
+/// Of the tokens org admins mint through the API, at `/admin/jwt`, for
+/// members of their org. Admins' are not limited.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Minting {
+    /// Seconds.
+    pub max_ttl: f64,
+
+    /// Of the tokens, of which those above the minter's own are not, per
+    /// [`crate::auth::Mint::claims`].
+    pub roles: Vec<String>,
+
+    /// Of the tokens' overrides of the global budgets, which they get when
+    /// not asked for less. Unlimited when not set.
+    pub max_tokens_per_day: Option<u64>,
+    pub max_cost_per_day: Option<f64>,
+}
+
+impl Default for Minting {
+    fn default() -> Self {
+        Self {
+            max_ttl: 30.0 * 24.0 * 60.0 * 60.0,
+            roles: vec![crate::auth::ROLE_HACKER.to_string()],
+            max_tokens_per_day: None,
+            max_cost_per_day: None,
+        }
+    }
+}
+
+/// As generated by `raskol keygen`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct JwtKey {
+    pub algorithm: JwtKeyAlgorithm,
+
+    /// PKCS#8 PEM.
+    pub private_key: PathBuf,
+
+    /// SPKI PEM.
+    pub public_key: PathBuf,
+
+    /// In our tokens' headers, by which verifiers pick the key.
+    pub kid: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub enum JwtKeyAlgorithm {
+    #[serde(rename = "EdDSA")]
+    Eddsa,
+    #[serde(rename = "ES256")]
+    Es256,
+}
+
+pub(crate) fn serialize_log_level<S>(
     level: &tracing::Level,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
@@ -102,7 +2999,7 @@ where
     serializer.serialize_str(&s)
 }
 
-fn deserialize_log_level<'de, D>(
+pub(crate) fn deserialize_log_level<'de, D>(
     deserializer: D,
 ) -> Result<tracing::Level, D::Error>
 where
@@ -114,19 +3011,65 @@ where
     tracing::Level::from_str(&s).map_err(serde::de::Error::custom)
 }
 
+fn deserialize_one_or_many<'de, D>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    let strings = match OneOrMany::deserialize(deserializer)? {
+        // Empty string used to mean no key.
+        OneOrMany::One(s) if s.is_empty() => Vec::new(),
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(strings) => strings,
+    };
+    Ok(strings)
+}
+
+fn deserialize_addrs<'de, D>(deserializer: D) -> Result<Vec<IpAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    let addrs = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    };
+    Ok(addrs)
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+pub const PATH: &str = "conf/conf.toml";
+
+/// Overlaid on the conf file in order, from its directory, if there, so that
+/// overrides of the environment, and secrets, can be deployed apart from the
+/// base. Tables are merged key by key. Other values, arrays included, are
+/// replaced.
+pub const OVERLAYS: [&str; 2] = ["local.toml", "secret.toml"];
+
 pub fn read_or_create_default() -> anyhow::Result<Conf> {
-    let path = "conf/conf.toml";
-    read_or_create_default_(path).context(path)
+    read_or_create_default_(PATH).context(PATH)
+}
+
+/// Unlike read_or_create_default, fails if the file is missing.
+pub fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Conf> {
+    Ok(read_layered(path.as_ref())?.try_into()?)
 }
 
 pub fn read_or_create_default_<P: AsRef<Path>>(
     path: P,
 ) -> anyhow::Result<Conf> {
     let path = path.as_ref();
-    let conf = if fs::exists(path)? {
-        let s = fs::read_to_string(path)?;
-        toml::from_str(&s)?
-    } else {
+    if !fs::exists(path)? {
         if let Some(parent) = path.parent() {
             let ctx = format!(
                 "Failed to create parent directory \
@@ -134,10 +3077,675 @@ pub fn read_or_create_default_<P: AsRef<Path>>(
             );
             fs::create_dir_all(parent).context(ctx)?;
         }
-        let conf = Conf::default();
-        let s = toml::to_string_pretty(&conf)?;
+        let s = toml::to_string_pretty(&Conf::default())?;
         fs::write(path, s)?;
-        conf
+    }
+    read(path)
+}
+
+/// The conf file, with the overlays found next to it merged in.
+pub fn read_layered(path: &Path) -> anyhow::Result<toml::Table> {
+    let mut table = read_table(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    for overlay in OVERLAYS.map(|name| dir.join(name)) {
+        if overlay != path && fs::exists(&overlay)? {
+            merge(&mut table, read_table(&overlay)?);
+        }
+    }
+    Ok(table)
+}
+
+fn read_table(path: &Path) -> anyhow::Result<toml::Table> {
+    let s = fs::read_to_string(path)
+        .context(format!("Failed to read conf file: {path:?}"))?;
+    toml::from_str(&s).context(format!("Invalid conf file: {path:?}"))
+}
+
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge(base, overlay);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// What the server would otherwise only find out about once running, or
+/// not at all.
+#[derive(Debug, Default)]
+pub struct Problems {
+    /// Would fail the server, or requests.
+    pub errors: Vec<String>,
+
+    /// Likely mistakes.
+    pub warnings: Vec<String>,
+}
+
+/// Reads the conf file, with its overlays, without creating a default one if
+/// it's missing, and checks what parsing alone doesn't.
+pub async fn validate<P: AsRef<Path>>(path: P) -> anyhow::Result<Problems> {
+    let raw = read_layered(path.as_ref())?;
+    let conf: Conf = raw.clone().try_into()?;
+    let mut problems = Problems {
+        errors: conf.errors(),
+        ..Problems::default()
     };
-    Ok(conf)
+
+    // Whatever we parsed, we can write back, so keys missing from the
+    // written version are those which serde ignored.
+    let known = toml::Table::try_from(&conf)?;
+    unknown_keys(&raw, &known, "", &mut problems.warnings);
+    if raw.get("target_auth_token").and_then(toml::Value::as_str) == Some("")
+    {
+        problems.warnings.push(
+            "Deprecated: target_auth_token = \"\" for no key. \
+            Use an empty list instead."
+                .to_string(),
+        );
+    }
+    if conf.jwt.secret == Jwt::default().secret {
+        problems
+            .warnings
+            .push("jwt.secret is the default one.".to_string());
+    }
+    for (kid, keyed) in &conf.jwt.keyed_secrets {
+        if keyed.secret.is_empty() {
+            problems
+                .errors
+                .push(format!("jwt.keyed_secrets.{kid}.secret is empty."));
+        } else if conf.jwt.secrets().any(|s| s == keyed.secret) {
+            problems.errors.push(format!(
+                "jwt.keyed_secrets.{kid} is also an unkeyed secret, so it \
+                would vouch for any token."
+            ));
+        }
+        if keyed.uid_prefix.is_empty() {
+            problems.errors.push(format!(
+                "jwt.keyed_secrets.{kid}.uid_prefix is empty, so none of \
+                its tokens is accepted."
+            ));
+        }
+        for role in &keyed.roles {
+            if role == crate::auth::ROLE_ADMIN {
+                problems.errors.push(format!(
+                    "jwt.keyed_secrets.{kid}.roles: ADMIN is never accepted."
+                ));
+            } else if !conf.roles.contains_key(role) {
+                problems.errors.push(format!(
+                    "jwt.keyed_secrets.{kid}.roles: unknown role: {role:?}"
+                ));
+            }
+        }
+    }
+    if let Some(key) = &conf.jwt.signing_key {
+        for file in [&key.private_key, &key.public_key] {
+            if !fs::exists(file)? {
+                problems
+                    .errors
+                    .push(format!("JWT signing key file missing: {file:?}"));
+            }
+        }
+    }
+    if !(conf.jwt.minting.max_ttl.is_finite()
+        && conf.jwt.minting.max_ttl > 0.0)
+    {
+        problems
+            .errors
+            .push("jwt.minting.max_ttl: must be positive.".to_string());
+    }
+    for role in &conf.jwt.minting.roles {
+        if !conf.roles.contains_key(role) {
+            problems
+                .errors
+                .push(format!("jwt.minting.roles: unknown role: {role:?}"));
+        }
+        if role == crate::auth::ROLE_ADMIN {
+            problems.errors.push(
+                "jwt.minting.roles: ADMIN may only be minted by admins."
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(Tls::Files {
+        cert_file,
+        key_file,
+    }) = &conf.tls
+    {
+        for file in [cert_file, key_file] {
+            if !fs::exists(file)? {
+                problems.errors.push(format!("TLS file missing: {file:?}"));
+            }
+        }
+    }
+    if conf.listen.unix_path.is_some() && conf.listen.systemd {
+        problems.errors.push(
+            "listen: unix_path and systemd are mutually exclusive."
+                .to_string(),
+        );
+    }
+    if conf.addr.is_empty() && !conf.listen.is_set() {
+        problems.errors.push("addr: none to listen on.".to_string());
+    }
+    if conf.listen.is_set() && conf.tls.is_some() {
+        problems.errors.push(
+            "listen: TLS is for serving directly, not behind a proxy."
+                .to_string(),
+        );
+    }
+    for (model, file) in &conf.tokenizers {
+        if !fs::exists(file)? {
+            problems.errors.push(format!(
+                "Tokenizer file missing for {model}: {file:?}"
+            ));
+        }
+    }
+    for pattern in &conf.redaction.patterns {
+        if let Err(error) = regex::Regex::new(pattern) {
+            problems.errors.push(format!(
+                "Invalid redaction pattern: {pattern:?}: {error}"
+            ));
+        }
+    }
+    let banned = conf
+        .response_filter
+        .iter()
+        .flat_map(|filter| filter.banned.iter());
+    for pattern in banned {
+        if let Err(error) = regex::Regex::new(pattern) {
+            problems.errors.push(format!(
+                "Invalid banned pattern: {pattern:?}: {error}"
+            ));
+        }
+    }
+    for directive in &conf.log.directives {
+        if let Err(error) =
+            directive.parse::<tracing_subscriber::filter::Directive>()
+        {
+            problems.errors.push(format!(
+                "Invalid log directive: {directive:?}: {error}"
+            ));
+        }
+    }
+
+    if conf.sqlite.max_connections == 0 {
+        problems
+            .errors
+            .push("sqlite.max_connections: must be positive.".to_string());
+    }
+    if !(conf.sqlite.acquire_timeout.is_finite()
+        && conf.sqlite.acquire_timeout > 0.0)
+    {
+        problems
+            .errors
+            .push("sqlite.acquire_timeout: must be positive.".to_string());
+    }
+    if !matches!(conf.sqlite.journal_mode, SqliteJournalMode::Wal) {
+        problems.warnings.push(
+            "sqlite.journal_mode: not wal, so reads wait for writes."
+                .to_string(),
+        );
+    }
+
+    for (name, role) in &conf.roles {
+        if let Some(access) = &role.access {
+            if access.windows.is_empty() {
+                problems.warnings.push(format!(
+                    "roles.{name}.access: no windows, so never allowed."
+                ));
+            }
+        }
+        for pattern in &role.upstream_paths {
+            if pattern.trim_start_matches('!').starts_with('/') {
+                problems.warnings.push(format!(
+                    "roles.{name}.upstream_paths: {pattern:?} starts with \
+                    a slash, which endpoints never do."
+                ));
+            }
+        }
+    }
+    for name in &conf.headers.forward {
+        if Headers::NEVER_FORWARDED.contains(&name.to_lowercase().as_str()) {
+            problems.errors.push(format!(
+                "headers.forward: {name:?} is ours to set, not the client's."
+            ));
+        }
+    }
+    if let Some(name) = &conf.hooks.require_header {
+        if axum::http::HeaderName::try_from(name.as_str()).is_err() {
+            problems.errors.push(format!(
+                "hooks.require_header: {name:?} is not a header name."
+            ));
+        }
+    }
+    for name in conf.jobs.keys() {
+        if !crate::jobs::NAMES.contains(&name.as_str()) {
+            problems
+                .warnings
+                .push(format!("jobs: unknown job: {name:?}"));
+        }
+    }
+    if let Some(deprecations) = &conf.deprecations {
+        for (kind, deprecated) in [
+            ("models", &deprecations.models),
+            ("routes", &deprecations.routes),
+        ] {
+            for (pattern, deprecation) in deprecated {
+                let Some(sunset) = &deprecation.sunset else {
+                    continue;
+                };
+                if let Err(error) = crate::deprecation::sunset(sunset) {
+                    problems.errors.push(format!(
+                        "deprecations.{kind}.{pattern:?}.sunset is invalid: \
+                        {error:#}"
+                    ));
+                }
+            }
+        }
+    }
+    let mut experimented = BTreeMap::new();
+    for (name, experiment) in &conf.experiments {
+        if !(0.0..=100.0).contains(&experiment.percent) {
+            problems.errors.push(format!(
+                "experiments.{name}.percent is not within 0..=100: {}",
+                experiment.percent
+            ));
+        }
+        if let Some(other) = experimented.insert(&experiment.model, name) {
+            problems.warnings.push(format!(
+                "experiments.{name}: model {:?} is already in {other:?}, \
+                which takes precedence.",
+                experiment.model
+            ));
+        }
+    }
+    if conf
+        .peers
+        .as_ref()
+        .is_some_and(|peers| peers.urls.is_empty())
+    {
+        problems
+            .warnings
+            .push("peers.urls is empty, so none is proxied to.".to_string());
+    }
+    if let Some(shadow) = &conf.shadow {
+        if !(0.0..=1.0).contains(&shadow.fraction) {
+            problems.errors.push(format!(
+                "shadow.fraction is not within 0..=1: {}",
+                shadow.fraction
+            ));
+        }
+        for role in &shadow.roles {
+            if !conf.roles.contains_key(role) {
+                problems
+                    .warnings
+                    .push(format!("shadow.roles: unknown role: {role:?}"));
+            }
+        }
+    }
+    if let Some(caps) = &conf.spend_caps {
+        for name in caps.providers.keys() {
+            let is_set = match name.as_str() {
+                "primary" => true,
+                "failover" => conf.failover.is_some(),
+                "shadow" => conf.shadow.is_some(),
+                _ => {
+                    problems.errors.push(format!(
+                        "spend_caps.providers: unknown provider: {name:?}"
+                    ));
+                    continue;
+                }
+            };
+            if !is_set {
+                problems.warnings.push(format!(
+                    "spend_caps.providers.{name}: {name} is not set."
+                ));
+            }
+        }
+    }
+    if let Some(overage) = &conf.overage {
+        if !(overage.max_percent.is_finite() && overage.max_percent > 0.0) {
+            problems.errors.push(format!(
+                "overage.max_percent: must be positive: {}",
+                overage.max_percent
+            ));
+        }
+        if overage.max_tokens == 0 {
+            problems
+                .errors
+                .push("overage.max_tokens: must be positive.".to_string());
+        }
+        for (from, to) in &overage.models {
+            if !conf.pricing.contains_key(to) {
+                problems.warnings.push(format!(
+                    "overage.models.{from}: {to:?} has no pricing."
+                ));
+            }
+        }
+    }
+    if let Some(duplicates) = &conf.duplicates {
+        if duplicates.window == 0 {
+            problems
+                .errors
+                .push("duplicates.window: must be positive.".to_string());
+        }
+        if duplicates.max_requests == 0 {
+            problems.errors.push(
+                "duplicates.max_requests: must be positive.".to_string(),
+            );
+        }
+    }
+    if !conf.hooks.wasm.is_empty() && !cfg!(feature = "wasm") {
+        problems.errors.push(
+            "hooks.wasm is set, but this build is without the wasm feature."
+                .to_string(),
+        );
+    }
+    for plugin in &conf.hooks.wasm {
+        if !plugin.path.is_file() {
+            problems.errors.push(format!(
+                "hooks.wasm: plugin not found: {:?}",
+                plugin.path
+            ));
+        }
+    }
+    if let Some(min_headroom) = conf
+        .queue
+        .as_ref()
+        .and_then(|queue| queue.min_headroom)
+        .filter(|min| !(0.0..=1.0).contains(min))
+    {
+        problems.errors.push(format!(
+            "queue.min_headroom is not from 0 to 1: {min_headroom}"
+        ));
+    }
+    if let Some(queue) = &conf.queue {
+        let reserved: u64 = queue.reserved_rpm.values().sum();
+        match queue.upstream_rpm {
+            None if reserved > 0 => problems.errors.push(
+                "queue.reserved_rpm without queue.upstream_rpm.".to_string(),
+            ),
+            Some(upstream_rpm) if reserved > upstream_rpm => {
+                problems.errors.push(format!(
+                    "queue.reserved_rpm: {reserved} in all, of only \
+                    {upstream_rpm} of queue.upstream_rpm."
+                ));
+            }
+            _ => {}
+        }
+    }
+    for (role, limits) in &conf.limits.per_role {
+        if let Some(secs) = limits
+            .request_timeout
+            .filter(|secs| !(secs.is_finite() && *secs > 0.0))
+        {
+            problems.errors.push(format!(
+                "limits.per_role.{role}.request_timeout is not positive: \
+                {secs}"
+            ));
+        }
+    }
+    if let Some(signup) = &conf.signup {
+        if !conf.roles.contains_key(&signup.role) {
+            problems.errors.push(format!(
+                "signup.role: unknown role: {:?}",
+                signup.role
+            ));
+        }
+        if !(signup.ttl.is_finite() && signup.ttl > 0.0) {
+            problems
+                .errors
+                .push(format!("signup.ttl is not positive: {}", signup.ttl));
+        }
+    }
+    if let Some(grpc) = &conf.grpc {
+        if !cfg!(feature = "grpc") {
+            problems.errors.push(
+                "grpc is set, but this build is without the grpc feature."
+                    .to_string(),
+            );
+        }
+        if let Err(error) = grpc.check() {
+            problems.errors.push(error.to_string());
+        }
+    }
+    if let Some(attestation) = &conf.attestation {
+        if attestation.secret.is_empty() {
+            problems
+                .errors
+                .push("attestation.secret is empty.".to_string());
+        } else if conf.jwt.secrets().any(|s| s == attestation.secret) {
+            problems.errors.push(
+                "attestation.secret is a JWT secret, so verifiers could \
+                mint tokens."
+                    .to_string(),
+            );
+        }
+    }
+    if let Some(stripe) = &conf.stripe {
+        if stripe.api_key.is_empty() {
+            problems.errors.push("stripe.api_key is empty.".to_string());
+        }
+        if stripe.event_name.is_empty() {
+            problems
+                .errors
+                .push("stripe.event_name is empty.".to_string());
+        }
+        if stripe.interval == 0 {
+            problems.errors.push("stripe.interval is 0.".to_string());
+        }
+        if !(1..=35).contains(&stripe.lookback_days) {
+            problems.errors.push(format!(
+                "stripe.lookback_days is {}, not from 1 to 35.",
+                stripe.lookback_days
+            ));
+        }
+        if matches!(stripe.measure, StripeMeasure::Cents)
+            && conf.pricing.is_empty()
+        {
+            problems.warnings.push(
+                "stripe.measure is cents, but pricing is empty, so nothing \
+                will be billed."
+                    .to_string(),
+            );
+        }
+    }
+    if conf.redis.is_some() {
+        if !cfg!(feature = "redis") {
+            problems.errors.push(
+                "redis is set, but this build is without the redis feature."
+                    .to_string(),
+            );
+        }
+        if conf.budget_cache {
+            problems.warnings.push(
+                "budget_cache is ignored when redis is set.".to_string(),
+            );
+        }
+    }
+
+    let mut urls = vec![format!(
+        "{}://{}",
+        conf.target_scheme.as_str(),
+        conf.target_address
+    )];
+    if let Some(failover) = &conf.failover {
+        urls.push(format!(
+            "{}://{}",
+            failover.target_scheme.as_str(),
+            failover.target_address
+        ));
+    }
+    if let Some(provider) = conf.shadow.as_ref().map(|s| &s.provider) {
+        urls.push(format!(
+            "{}://{}",
+            provider.target_scheme.as_str(),
+            provider.target_address
+        ));
+    }
+    urls.extend(conf.moderation.iter().map(|m| m.url.clone()));
+    urls.extend(conf.jwt.jwks_url.clone());
+    for url in urls {
+        if let Err(error) = resolve(&url).await {
+            problems
+                .errors
+                .push(format!("Unreachable URL: {url:?}: {error:#}"));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn unknown_keys(
+    raw: &toml::Table,
+    known: &toml::Table,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) {
+    for (key, value) in raw {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match (value, known.get(key)) {
+            (_, None) => unknown.push(format!("Unknown key: {path}")),
+            (toml::Value::Table(raw), Some(toml::Value::Table(known))) => {
+                unknown_keys(raw, known, &path, unknown);
+            }
+            (_, Some(_)) => {}
+        }
+    }
+}
+
+async fn resolve(url: &str) -> anyhow::Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    let host = url.host_str().context("No host.")?;
+    let port = url.port_or_known_default().context("No port.")?;
+    let mut addrs = tokio::net::lookup_host((host, port)).await?;
+    addrs.next().context("No addresses.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, merge, unknown_keys, Conf, Feature, IpFilter};
+    use crate::auth::ROLE_HACKER;
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("v1/chat/completions", "v1/chat/completions"));
+        assert!(glob_matches("v1/*/completions", "v1/chat/completions"));
+        assert!(!glob_matches("v1/*", "v1/files/abc/content"));
+        assert!(glob_matches("v1/**", "v1/files/abc/content"));
+        assert!(glob_matches("v1/files/*/content", "v1/files/abc/content"));
+        assert!(glob_matches("**", "v1/embeddings"));
+        assert!(!glob_matches("v1/files", "v1/files/abc"));
+    }
+
+    #[test]
+    fn ip_filter() {
+        let filter = |allow: &[&str], deny: &[&str]| IpFilter {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+            ..IpFilter::default()
+        };
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert!(filter(&[], &[]).is_allowed(ip("203.0.113.7")));
+        let filter = filter(&["10.0.0.0/8", "::1/128"], &["10.6.6.0/24"]);
+        assert!(filter.is_allowed(ip("10.1.2.3")));
+        assert!(filter.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(filter.is_allowed(ip("::1")));
+        assert!(!filter.is_allowed(ip("10.6.6.6")));
+        assert!(!filter.is_allowed(ip("203.0.113.7")));
+
+        // The rest as by default.
+        let filter: IpFilter =
+            toml::from_str(r#"deny = ["10.6.6.0/24"]"#).unwrap();
+        assert!(filter.allow.is_empty());
+        assert_eq!(filter.public_rate_limit.burst, Some(10));
+        assert!(!filter.is_allowed(ip("10.6.6.6")));
+    }
+
+    #[test]
+    fn features() {
+        let mut role = Conf::default().roles[ROLE_HACKER].clone();
+        assert!(role.has_feature(Feature::Vision, &[]));
+        role.features = Some(vec![Feature::Streaming]);
+        assert!(role.has_feature(Feature::Streaming, &[]));
+        assert!(!role.has_feature(Feature::Vision, &[]));
+        assert!(role.has_feature(Feature::Vision, &["vision".to_string()]));
+        assert!(!role.has_feature(Feature::Vision, &["tools".to_string()]));
+    }
+
+    #[test]
+    fn errors() {
+        let mut conf = Conf::default();
+        assert!(conf.errors().is_empty());
+        for requests_per_minute in [0.0, -1.0, f64::NAN] {
+            conf.rate_limit.bucket = Some(super::Bucket {
+                requests_per_minute,
+                burst: 1,
+            });
+            assert_eq!(conf.errors().len(), 1);
+        }
+        conf.rate_limit.bucket = None;
+        conf.realtime.reserve_secs = 0.0;
+        assert_eq!(conf.errors().len(), 1);
+        conf.realtime.reserve_secs = 1.0;
+        conf.jobs.insert(
+            "prune".to_string(),
+            super::Job {
+                schedule: Some("* * *".to_string()),
+                is_enabled: true,
+            },
+        );
+        assert_eq!(conf.errors().len(), 1);
+    }
+
+    #[test]
+    fn unknown_keys_found() {
+        let raw: toml::Table = toml::from_str(
+            "port = 1\nprot = 2\n[retry]\nmax_attempts = 1\nmax_atempts = 2",
+        )
+        .unwrap();
+        let known: toml::Table = toml::from_str(
+            "port = 1\n[retry]\nmax_attempts = 1\ninitial_backoff = 0.5",
+        )
+        .unwrap();
+        let mut unknown = Vec::new();
+        unknown_keys(&raw, &known, "", &mut unknown);
+        assert_eq!(
+            unknown,
+            ["Unknown key: prot", "Unknown key: retry.max_atempts"]
+        );
+    }
+
+    #[test]
+    fn overlays_merged() {
+        let mut base: toml::Table = toml::from_str(
+            "port = 1\ntarget_auth_token = [\"a\", \"b\"]\n\
+            [retry]\nmax_attempts = 1\ninitial_backoff = 0.5",
+        )
+        .unwrap();
+        let local: toml::Table = toml::from_str(
+            "port = 2\n[retry]\nmax_attempts = 3\n[jwt]\nsecret = \"x\"",
+        )
+        .unwrap();
+        let secret: toml::Table =
+            toml::from_str("target_auth_token = [\"c\"]").unwrap();
+        merge(&mut base, local);
+        merge(&mut base, secret);
+        let expected: toml::Table = toml::from_str(
+            "port = 2\ntarget_auth_token = [\"c\"]\n\
+            [retry]\nmax_attempts = 3\ninitial_backoff = 0.5\n\
+            [jwt]\nsecret = \"x\"",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
 }
@@ -1,207 +1,6866 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fs,
-    path::PathBuf,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Context;
-use chrono::{DateTime, Utc};
-use sqlx::Executor;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream::BoxStream, StreamExt};
 
-use crate::conf;
+#[cfg(feature = "redis")]
+use crate::shared;
+use crate::{auth, billing, conf, period, ratelimit, signing};
 
-const MIGRATIONS: [&str; 1] = [include_str!("../migrations/0_data.sql")];
+/// Versioned, and recorded as applied in the database, so that upgrades
+/// across several releases run exactly the ones which are missing.
+pub(crate) static MIGRATOR_SQLITE: sqlx::migrate::Migrator =
+    sqlx::migrate!("migrations/sqlite");
+pub(crate) static MIGRATOR_POSTGRES: sqlx::migrate::Migrator =
+    sqlx::migrate!("migrations/postgres");
 
-type Tx<'a> = sqlx::Transaction<'a, sqlx::Sqlite>;
+const HOUR: i64 = 60 * 60;
+
+/// Of the hours rolled up already, those rolled up again, for the logs
+/// written after their hour was, such as of streams, which are logged once
+/// they end, as of when they started, or of writes queued behind.
+const ROLLUP_REROLLED: i64 = 3 * HOUR;
+
+/// How much of the plain text key we keep, to help humans tell keys apart.
+const API_KEY_DISPLAY_LEN: usize = 8;
+
+type Tx<'a, DB> = sqlx::Transaction<'a, DB>;
 
 #[derive(sqlx::FromRow)]
 struct HitsRow {
     uid: String,
-    count_of_all: u64,
-    time_of_last: u64,
+    count_of_all: i64,
+    time_of_last: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct BatchRow {
+    id: String,
+    uid: String,
+    role: String,
+    org: Option<String>,
+    max_tokens_per_day: Option<i64>,
+    max_cost_per_day: Option<f64>,
+    features: Option<String>,
+    endpoint: String,
+    status: String,
+    time_created: i64,
+    time_finished: Option<i64>,
+    total: i64,
+    completed: i64,
+    failed: i64,
+}
+
+/// With the counts of its items.
+const BATCH_SELECT: &str = "SELECT
+        b.*,
+        (SELECT COUNT(*) FROM batch_items AS i
+            WHERE i.batch_id = b.id) AS total,
+        (SELECT COUNT(*) FROM batch_items AS i
+            WHERE i.batch_id = b.id AND i.code IS NOT NULL) AS completed,
+        (SELECT COUNT(*) FROM batch_items AS i
+            WHERE i.batch_id = b.id AND i.code >= 400) AS failed
+    FROM batches AS b";
+
+impl TryFrom<BatchRow> for Batch {
+    type Error = anyhow::Error;
+
+    fn try_from(row: BatchRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: row.id,
+            uid: row.uid,
+            role: row.role,
+            org: row.org,
+            budget_overrides: BudgetOverrides {
+                max_tokens_per_day: row
+                    .max_tokens_per_day
+                    .map(u64::try_from)
+                    .transpose()?,
+                max_cost_per_day: row.max_cost_per_day,
+                overage_tokens: 0,
+            },
+            features: row
+                .features
+                .map(|features| serde_json::from_str(&features))
+                .transpose()?
+                .unwrap_or_default(),
+            endpoint: row.endpoint,
+            status: row.status.parse()?,
+            total: u64::try_from(row.total)?,
+            completed: u64::try_from(row.completed)?,
+            failed: u64::try_from(row.failed)?,
+            time_created: row.time_created,
+            time_finished: row.time_finished,
+        })
+    }
+}
+
+/// A user's daily token budget, as of now.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBudget {
+    pub limit: u64,
+    pub used: u64,
+}
+
+impl TokenBudget {
+    #[must_use]
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+}
+
+/// Of a user, in the current period, what their budget is made of, as
+/// listed with their account.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BudgetBasis {
+    /// Set by an admin.
+    pub max_tokens_per_day: Option<u64>,
+    pub bonus_tokens: u64,
+    pub credits: i64,
+    pub used: u64,
+}
+
+impl BudgetBasis {
+    /// As [`Storage::token_budget`] has it, without a token's overrides.
+    #[must_use]
+    pub fn token_budget(&self, role: &str) -> TokenBudget {
+        let limit = token_limit(
+            self.max_tokens_per_day,
+            self.bonus_tokens,
+            self.credits,
+            role,
+            BudgetOverrides::default(),
+        );
+        TokenBudget {
+            limit: limit.total(),
+            used: self.used,
+        }
+    }
+}
+
+/// Budgets carried by a user's token. They override the global ones, but
+/// not an admin's for the user, and are not scaled by role.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BudgetOverrides {
+    pub max_tokens_per_day: Option<u64>,
+    pub max_cost_per_day: Option<f64>,
+
+    /// Past the daily token budget, the tokens a request served in overage
+    /// may take, per [`crate::overage`]. Not of the token, but of the one
+    /// request.
+    pub overage_tokens: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Amount {
+    pub tokens: usize,
+    pub cost: f64,
+}
+
+/// Amount taken from a user's budget before forwarding a request, to be
+/// settled once the actual usage is known.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub uid: String,
+    pub org: Option<String>,
+    pub model: String,
+    pub date: String,
+    pub amount: Amount,
+}
+
+/// Which of the daily budgets a reservation didn't fit in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    /// Of the model, per `limits.per_model`.
+    Model,
+
+    /// The user's tokens.
+    Tokens,
+
+    /// The user's cost.
+    Cost,
+
+    /// The org's shared tokens or cost.
+    Org,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct RequestLog {
+    pub req_id: String,
+    pub uid: String,
+    pub model: String,
+    pub endpoint: String,
+    pub status: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub cost: f64,
+    pub duration_ms: i64,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+
+    pub error_message: Option<String>,
+
+    /// Conversation it's part of, as tagged by the client.
+    pub session: Option<String>,
+
+    /// A/B experiment it was part of, and its arm in it.
+    pub experiment: Option<String>,
+    pub arm: Option<String>,
+
+    /// As reported by upstream, if it does.
+    pub timing: Option<Timing>,
+
+    /// Of failed requests, when known.
+    pub error_class: Option<ErrorClass>,
+
+    /// Of the client, past any trusted proxies, per
+    /// [`crate::conf::IpFilter::trusted_proxies`]. None for requests of our
+    /// own, as of batches.
+    pub client_ip: Option<String>,
+
+    /// As by the client, per [`crate::chat::Req::take_tags`]. Kept apart, for
+    /// usage to be reported by, so not read back with the log.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+
+    /// In the units upstream measures it in, rather than the tokens charged
+    /// for it. Kept apart too, per [`Storage::usage_totals`].
+    #[serde(skip)]
+    #[cfg_attr(feature = "graphql", graphql(skip))]
+    pub usage: Option<Usage>,
+}
+
+/// What a request is measured in, whatever the endpoint or provider.
+#[derive(
+    serde::Serialize,
+    utoipa::ToSchema,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Tokens,
+
+    /// Of audio, transcribed or spoken in realtime.
+    Seconds,
+
+    /// Of text, spoken.
+    Characters,
+    Images,
+}
+
+impl Unit {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tokens => "tokens",
+            Self::Seconds => "seconds",
+            Self::Characters => "characters",
+            Self::Images => "images",
+        }
+    }
+}
+
+impl std::str::FromStr for Unit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "tokens" => Ok(Self::Tokens),
+            "seconds" => Ok(Self::Seconds),
+            "characters" => Ok(Self::Characters),
+            "images" => Ok(Self::Images),
+            _ => Err(anyhow!("Invalid unit: {s:?}")),
+        }
+    }
+}
+
+/// Of a request, as upstream measures it. Its cost is that of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// "primary" or "failover", unless none responded.
+    pub provider: Option<String>,
+    pub unit: Unit,
+
+    /// Of the request, e.g. prompt tokens or seconds of audio uploaded.
+    pub input: f64,
+
+    /// Of the response, e.g. completion tokens or images generated.
+    pub output: f64,
+
+    /// Qualifies the unit, e.g. the size of the images.
+    pub detail: Option<String>,
+}
+
+impl Usage {
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // Nowhere near.
+    pub fn tokens(
+        provider: Option<&str>,
+        input: usize,
+        output: usize,
+    ) -> Self {
+        Self {
+            provider: provider.map(str::to_string),
+            unit: Unit::Tokens,
+            input: input as f64,
+            output: output as f64,
+            detail: None,
+        }
+    }
+}
+
+/// Why a request failed, telling the client's fault from upstream's.
+#[derive(
+    serde::Serialize,
+    utoipa::ToSchema,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Upstream refused our key.
+    Auth,
+
+    /// Refused, or cut off, for the user's budget running out. Not counted
+    /// as an error in stats, nor towards suspensions, since it is no fault.
+    Budget,
+
+    /// Refused as is, by us or upstream.
+    Validation,
+    #[serde(rename = "upstream_429")]
+    Upstream429,
+    #[serde(rename = "upstream_5xx")]
+    Upstream5xx,
+
+    /// Upstream could not be reached.
+    Network,
+
+    /// Upstream took longer than we wait.
+    Timeout,
+
+    /// Cut off, as storage failed and we couldn't draw on the budget.
+    Unaccounted,
+}
+
+impl ErrorClass {
+    /// Whether the client is to blame, as opposed to upstream, or us, or
+    /// its budget running out. As of `suspension_candidates`' SQL.
+    #[must_use]
+    pub fn is_client_caused(self) -> bool {
+        self == Self::Validation
+    }
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::Budget => "budget",
+            Self::Validation => "validation",
+            Self::Upstream429 => "upstream_429",
+            Self::Upstream5xx => "upstream_5xx",
+            Self::Network => "network",
+            Self::Timeout => "timeout",
+            Self::Unaccounted => "unaccounted",
+        }
+    }
+}
+
+impl std::str::FromStr for ErrorClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auth" => Ok(Self::Auth),
+            "budget" => Ok(Self::Budget),
+            "validation" => Ok(Self::Validation),
+            "upstream_429" => Ok(Self::Upstream429),
+            "upstream_5xx" => Ok(Self::Upstream5xx),
+            "network" => Ok(Self::Network),
+            "timeout" => Ok(Self::Timeout),
+            "unaccounted" => Ok(Self::Unaccounted),
+            _ => Err(anyhow!("Invalid error class: {s:?}")),
+        }
+    }
+}
+
+/// Of requests since a time, of how many failed, by why.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct ErrorRates {
+    pub requests: u64,
+
+    /// Most frequent first.
+    pub classes: Vec<ErrorRate>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct ErrorRate {
+    pub class: ErrorClass,
+    pub errors: u64,
+
+    /// Of all requests, from 0 to 1.
+    pub rate: f64,
+}
+
+/// Where upstream's time went on a request, in milliseconds, as reported by
+/// those which do, such as Groq.
+#[derive(
+    serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy,
+)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct Timing {
+    /// Waiting for upstream to get to it.
+    pub queue_ms: f64,
+    pub prompt_ms: f64,
+    pub completion_ms: f64,
+}
+
+/// Of a model's successful requests.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct LatencyStats {
+    pub model: String,
+    pub requests: u64,
+
+    /// Of the whole request, as we saw it.
+    pub duration_ms: Percentiles,
+
+    /// Of the requests which upstream reported them for, if any.
+    pub queue_ms: Option<Percentiles>,
+    pub prompt_ms: Option<Percentiles>,
+    pub completion_ms: Option<Percentiles>,
+}
+
+#[derive(
+    serde::Serialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq,
+)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+}
+
+/// Of request logs. Each only when set.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub uid: Option<String>,
+    pub model: Option<String>,
+    pub session: Option<String>,
+
+    /// Only failed requests.
+    pub is_error: bool,
+
+    /// Only those with this HTTP status.
+    pub status: Option<i64>,
+
+    /// Seconds since UNIX epoch. `until` is exclusive.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+/// Of everyone's requests, with nothing of any one user's.
+#[derive(
+    serde::Serialize, utoipa::ToSchema, Debug, Clone, Default, PartialEq,
+)]
+pub struct PublicStats {
+    pub requests: u64,
+
+    /// Input and output.
+    pub tokens: u64,
+
+    /// Users who made requests, each a team at events.
+    pub active_teams: u64,
+
+    /// Of the requests which succeeded. None when none did.
+    pub duration_ms_p95: Option<i64>,
+}
+
+/// Of the request logs matching a filter.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct LogSummary {
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+}
+
+/// Of [`LogFilter`], binding its fields in order as $1 to $7.
+const LOG_FILTER_WHERE: &str = "WHERE (CAST($1 AS TEXT) IS NULL OR uid = $1)
+    AND (CAST($2 AS TEXT) IS NULL OR model = $2)
+    AND (CAST($3 AS TEXT) IS NULL OR session = $3)
+    AND status >= $4
+    AND (CAST($5 AS BIGINT) IS NULL OR status = $5)
+    AND (CAST($6 AS BIGINT) IS NULL OR time >= $6)
+    AND (CAST($7 AS BIGINT) IS NULL OR time < $7)";
+
+/// Of [`JwtMint`], with whether it was revoked since.
+const JWT_MINT_SELECT: &str = "SELECT
+        jwt_mints.jti,
+        jwt_mints.uid,
+        jwt_mints.role,
+        jwt_mints.org,
+        jwt_mints.max_tokens_per_day,
+        jwt_mints.max_cost_per_day,
+        jwt_mints.minted_by,
+        jwt_mints.time,
+        jwt_mints.exp,
+        jwt_revocations.time_revoked
+    FROM jwt_mints
+    LEFT JOIN jwt_revocations ON jwt_revocations.jti = jwt_mints.jti";
+
+/// Tuples being limited to 16 columns.
+#[derive(sqlx::FromRow)]
+struct RequestLogRow {
+    req_id: String,
+    uid: String,
+    model: String,
+    endpoint: String,
+    status: i64,
+    input_tokens: i64,
+    output_tokens: i64,
+    cost: f64,
+    duration_ms: i64,
+    time: i64,
+    error_message: Option<String>,
+    session: Option<String>,
+    experiment: Option<String>,
+    arm: Option<String>,
+    upstream_timing: Option<String>,
+    error_class: Option<String>,
+    client_ip: Option<String>,
+}
+
+fn request_log_from_row(row: RequestLogRow) -> RequestLog {
+    RequestLog {
+        req_id: row.req_id,
+        uid: row.uid,
+        model: row.model,
+        endpoint: row.endpoint,
+        status: row.status,
+        input_tokens: row.input_tokens,
+        output_tokens: row.output_tokens,
+        cost: row.cost,
+        duration_ms: row.duration_ms,
+        time: row.time,
+        error_message: row.error_message,
+        session: row.session,
+        experiment: row.experiment,
+        arm: row.arm,
+        timing: row
+            .upstream_timing
+            .and_then(|timing| serde_json::from_str(&timing).ok()),
+        error_class: row.error_class.and_then(|class| class.parse().ok()),
+        client_ip: row.client_ip,
+        tags: BTreeMap::new(),
+        usage: None,
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub uid: String,
+    pub role: String,
+    pub key_prefix: String,
+    pub time_created: i64,
+    pub time_revoked: Option<i64>,
+}
+
+/// Of a user, the requests using a model or route deprecated in conf, per
+/// [`crate::deprecation`].
+#[derive(sqlx::FromRow, serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct DeprecatedUsage {
+    pub uid: String,
+
+    /// "model" or "route".
+    pub kind: String,
+
+    /// As requested, e.g. "gpt-3.5-turbo".
+    pub name: String,
+    pub requests: i64,
+
+    /// Seconds since UNIX epoch.
+    pub first_used: i64,
+    pub last_used: i64,
 }
 
-#[derive(sqlx::FromRow)]
-struct TokensRow {
-    // Used in SQL, but not in Rust. Here for documentation.
-    #[allow(dead_code)]
-    uid: String,
+/// Shared with a backend service, which signs its requests with it, per
+/// [`crate::signing`].
+#[derive(sqlx::FromRow)]
+pub struct ServiceKey {
+    pub service: String,
+
+    /// Who its requests are made as.
+    pub uid: String,
+    pub role: String,
+
+    /// Hex. Kept as is, not hashed as API keys are, since checking an
+    /// HMAC takes the key itself, so storage must be kept as private as
+    /// the keys.
+    pub secret: String,
+    pub time_created: i64,
+    pub time_revoked: Option<i64>,
+}
+
+/// Of a token minted through the admin API.
+#[derive(sqlx::FromRow, serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct JwtMint {
+    pub jti: String,
+    pub uid: String,
+    pub role: String,
+    pub org: Option<String>,
+    pub max_tokens_per_day: Option<i64>,
+    pub max_cost_per_day: Option<f64>,
+
+    /// Uid of the admin.
+    pub minted_by: String,
+
+    /// Unix times.
+    pub time: i64,
+    pub exp: i64,
+    pub time_revoked: Option<i64>,
+}
+
+/// Admin-managed settings of a user.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Account {
+    pub uid: String,
+
+    /// Overrides the global limit when set.
+    pub max_tokens_per_day: Option<u64>,
+
+    pub is_suspended: bool,
+}
+
+impl Account {
+    fn new(uid: &str) -> Self {
+        Self {
+            uid: uid.to_string(),
+            max_tokens_per_day: None,
+            is_suspended: false,
+        }
+    }
+}
+
+/// Admin-managed settings of an org, and what its members used today.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Org {
+    pub org: String,
+
+    /// Override the defaults when set.
+    pub max_tokens_per_day: Option<u64>,
+    pub max_cost_per_day: Option<f64>,
+
+    /// Of upstream's requests per minute. Overrides the conf's when set.
+    pub reserved_rpm: Option<u64>,
+
+    pub tokens_today: u64,
+    pub cost_today: f64,
+}
+
+/// What a user consumed in a day.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct DailyUsage {
+    pub uid: String,
+    pub date: String,
+    pub tokens: u64,
+    pub tokens_by_model: BTreeMap<String, u64>,
+    pub bonus_tokens: u64,
+    pub cost: f64,
+    pub requests: u64,
+}
+
+/// Usage of a model by a user in a day.
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct ReportRow {
+    pub date: String,
+    pub uid: String,
+    pub model: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+
+    /// As counted against the budget, which includes estimates for requests
+    /// whose actual usage is unknown.
+    pub budget_tokens: u64,
+
+    pub cost: f64,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct UserStats {
+    pub uid: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+
+    /// Of the `errors`, those with a 4xx status. The rest are 5xx.
+    /// This and the below are from the request logs still retained.
+    pub client_errors: u64,
+    pub server_errors: u64,
+
+    pub avg_duration_ms: f64,
+    pub p95_duration_ms: u64,
+}
+
+#[derive(
+    serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    #[default]
+    Tokens,
+    Requests,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct LeaderboardEntry {
+    pub uid: String,
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+/// What to sort stats by. Numbers are sorted from the largest.
+#[derive(
+    serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsSort {
+    #[default]
+    Uid,
+    Requests,
+    Errors,
+    Tokens,
+    Cost,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatsPage {
+    pub sort: StatsSort,
+
+    /// All when not set.
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+/// How prompt token estimates of a model compared with the actual counts
+/// reported by upstream.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Calibration {
+    pub model: String,
+
+    /// Requests compared.
+    pub samples: u64,
+
+    pub estimated: u64,
+    pub actual: u64,
+
+    /// What to multiply estimates by.
+    pub factor: f64,
+}
+
+/// Where a request with an idempotency key stands.
+#[derive(Debug, Clone)]
+pub enum Idempotency {
+    /// The first with the key, which is now to be completed or released.
+    Claimed,
+
+    /// The first with the key is still being handled.
+    InProgress,
+
+    /// The key was used for a different request.
+    Mismatch,
+
+    /// The response to the first with the key, to replay.
+    Done { status: u16, body: String },
+}
+
+/// Bodies of a chat request and its response, kept for investigations.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Capture {
+    /// Of its request log.
+    pub req_id: String,
+    pub uid: String,
+    pub request: String,
+
+    /// None when upstream failed.
+    pub response: Option<String>,
+
+    /// Whether either was cut short.
+    pub is_truncated: bool,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+}
+
+/// Deflated bodies.
+type CaptureRow = (String, String, Vec<u8>, Option<Vec<u8>>, i64, i64);
+
+fn capture_from_row(
+    (req_id, uid, request, response, is_truncated, time): CaptureRow,
+) -> anyhow::Result<Capture> {
+    let inflate = |body: &[u8]| -> anyhow::Result<String> {
+        let body = miniz_oxide::inflate::decompress_to_vec(body)
+            .map_err(|error| anyhow!("Corrupt capture: {error}"))?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    };
+    Ok(Capture {
+        req_id,
+        uid,
+        request: inflate(&request)?,
+        response: response.as_deref().map(inflate).transpose()?,
+        is_truncated: is_truncated != 0,
+        time,
+    })
+}
+
+/// Whose a prompt template is. Users and orgs may have templates of the
+/// same name.
+#[derive(Debug, Clone, Copy)]
+pub enum Owner<'a> {
+    User(&'a str),
+    Org(&'a str),
+}
+
+impl Owner<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::User(_) => "user",
+            Self::Org(_) => "org",
+        }
+    }
+
+    fn id(&self) -> &str {
+        match self {
+            Self::User(id) | Self::Org(id) => id,
+        }
+    }
+}
+
+/// Code with which users sign themselves up.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Invite {
+    pub code: String,
+    pub max_uses: u64,
+    pub uses: u64,
+
+    /// Seconds since UNIX epoch.
+    pub time_created: i64,
+    pub time_expires: Option<i64>,
+}
+
+/// Who the usage of a user or org is billed to.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct StripeCustomer {
+    pub kind: billing::Account,
+
+    /// The uid or org.
+    pub id: String,
+
+    /// Stripe's ID, "cus_...".
+    pub customer: String,
+}
+
+/// User who signed themselves up.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Signup {
+    pub email: String,
+
+    /// Code they signed up with.
+    pub invite: String,
+
+    /// Seconds since UNIX epoch.
+    pub time_created: i64,
+
+    /// When an admin approved more than the starter budget.
+    pub time_upgraded: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignupOutcome {
+    Created,
+
+    /// Unknown, expired or used up.
+    InvalidInvite,
+
+    /// Already signed up.
+    Exists,
+}
+
+/// Of an upstream provider, by [`crate::prober`].
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub provider: String,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+
+    pub is_up: bool,
+
+    /// Until it responded, or failed to.
+    pub latency_ms: i64,
+
+    pub error: Option<String>,
+}
+
+/// Version of a named prompt template.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub version: i64,
+
+    /// JSON, as in [`crate::template::Body`].
+    pub body: String,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+}
+
+/// Entry of a user's credits ledger.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Credit {
+    /// Taken out when negative.
+    pub tokens: i64,
+
+    /// Of the budget which was closed. None for grants.
+    pub period: Option<String>,
+
+    pub note: Option<String>,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+}
+
+/// A suspended user.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Suspension {
+    pub uid: String,
+
+    /// Why it was suspended automatically. None when by an admin.
+    pub reason: Option<String>,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+}
+
+/// Usage within a time bucket.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct UsageBucket {
+    /// Start, in seconds since UNIX epoch.
+    pub time: i64,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+}
+
+/// Usage of a conversation, made of the requests tagged with it.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct SessionStats {
+    pub session: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+
+    /// Of the first and the latest requests, in seconds since UNIX epoch.
+    pub first: i64,
+    pub last: i64,
+}
+
+/// Chat requests submitted together, processed in the background as the
+/// user who submitted them.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Batch {
+    pub id: String,
+    pub uid: String,
+    pub role: String,
+    pub org: Option<String>,
+
+    /// Of the token it was submitted with.
+    #[serde(skip)]
+    pub budget_overrides: BudgetOverrides,
+    #[serde(skip)]
+    pub features: Vec<String>,
+
+    pub endpoint: String,
+    pub status: BatchStatus,
+    pub total: u64,
+
+    /// With a response, successful or not.
+    pub completed: u64,
+
+    /// Of the completed.
+    pub failed: u64,
+
+    /// Seconds since UNIX epoch.
+    pub time_created: i64,
+    pub time_finished: Option<i64>,
+}
+
+#[derive(
+    serde::Serialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Queued,
+    InProgress,
+    Completed,
+    Cancelled,
+}
+
+impl BatchStatus {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::InProgress => "in_progress",
+            Self::Completed => "completed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::str::FromStr for BatchStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "queued" => Ok(Self::Queued),
+            "in_progress" => Ok(Self::InProgress),
+            "completed" => Ok(Self::Completed),
+            "cancelled" => Ok(Self::Cancelled),
+            _ => Err(anyhow!("Invalid batch status: {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    /// Position in the batch, from 0.
+    pub index: i64,
+
+    /// JSON.
+    pub request: String,
+
+    /// HTTP status of the response, `None` until there is one.
+    pub code: Option<i64>,
+
+    pub response: Option<String>,
+}
+
+/// Usage of an arm of an A/B experiment.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct ArmStats {
+    pub experiment: String,
+    pub arm: String,
+
+    /// Routed to.
+    pub model: String,
+
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+}
+
+/// Usage of the requests tagged with a value of a tag.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct TagStats {
+    pub tag: String,
+    pub value: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+}
+
+/// Of a request mirrored to another model or provider.
+#[derive(Debug, Clone)]
+pub struct ShadowLog {
+    /// Of the mirrored request.
+    pub req_id: String,
+    pub model: String,
+    pub status: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub cost: f64,
+    pub duration_ms: i64,
+
+    /// Seconds since UNIX epoch.
+    pub time: i64,
+}
+
+/// Of the mirrored requests, from a model to another.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct ShadowComparison {
+    pub model: String,
+    pub shadow_model: String,
+    pub requests: u64,
+    pub primary: ShadowSide,
+    pub shadow: ShadowSide,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Default)]
+pub struct ShadowSide {
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub avg_duration_ms: f64,
+}
+
+/// Of the usage since some time, per provider, model, endpoint, unit and
+/// detail.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct UsageTotals {
+    pub provider: Option<String>,
+    pub model: String,
+    pub endpoint: String,
+    pub unit: Unit,
+    pub detail: Option<String>,
+    pub requests: u64,
+    pub input: f64,
+    pub output: f64,
+    pub cost: f64,
+}
+
+/// Everything the server needs to persist, independent of the database
+/// backend. See [`connect`] for how the backend is selected.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Deletes request logs and token estimates from before `time` and
+    /// daily counts from before `date`. Returns the number of rows deleted
+    /// per table.
+    async fn prune(
+        &self,
+        time: i64,
+        date: &str,
+    ) -> anyhow::Result<BTreeMap<String, u64>>;
+
+    /// Checkpoints the WAL, truncating it, and lets SQLite optimize its
+    /// query plans, after VACUUM if asked to. Nothing for other backends,
+    /// which look after themselves.
+    async fn maintain(&self, vacuum: bool) -> anyhow::Result<()>;
+
+    /// Rolls request logs up into hourly usage, for the hours since the
+    /// previous rollup, and again the few before, until the given time
+    /// (exclusive, rounded down to the hour). Returns until when usage is
+    /// rolled up.
+    async fn rollup(&self, until: i64) -> anyhow::Result<i64>;
+
+    /// Usage since the given time (rounded down to the hour) per user, or
+    /// just the given user, or just the members of the given org. From
+    /// hourly rollups, plus the request logs not yet rolled up.
+    async fn stats(
+        &self,
+        uid: Option<&str>,
+        org: Option<&str>,
+        since: i64,
+        page: &StatsPage,
+    ) -> anyhow::Result<Vec<UserStats>>;
+
+    /// Usage from `from` (rounded down to the bucket) until `to`, per bucket
+    /// of the given seconds, which must be a multiple of an hour. Of all
+    /// users, or just the given user. Empty buckets are left out.
+    async fn timeseries(
+        &self,
+        uid: Option<&str>,
+        bucket: i64,
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<UsageBucket>>;
+
+    /// Latest failed requests, newest first.
+    async fn recent_errors(
+        &self,
+        limit: u64,
+    ) -> anyhow::Result<Vec<RequestLog>>;
+
+    /// Those matching the filter, newest first.
+    async fn request_logs(
+        &self,
+        filter: &LogFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<RequestLog>>;
+
+    /// Those of the times, oldest first, as read, rather than all at once,
+    /// for exports of any size.
+    fn request_logs_stream(
+        &self,
+        time_from: i64,
+        time_to: i64,
+    ) -> BoxStream<'_, anyhow::Result<RequestLog>>;
+
+    async fn request_log(
+        &self,
+        req_id: &str,
+    ) -> anyhow::Result<Option<RequestLog>>;
+
+    /// Totals of all those matching the filter.
+    async fn request_logs_summary(
+        &self,
+        filter: &LogFilter,
+    ) -> anyhow::Result<LogSummary>;
+
+    /// Per model, of requests since the given time, of all users unless
+    /// one is given. From request logs, so only as far back as those are
+    /// kept.
+    async fn latency_stats(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<LatencyStats>>;
+
+    /// Of requests since the given time, of all users unless one is given.
+    /// From request logs, so only as far back as those are kept.
+    async fn error_rates(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<ErrorRates>;
+
+    /// Of requests since the given time, of all users together. From
+    /// request logs too.
+    async fn public_stats(&self, since: i64) -> anyhow::Result<PublicStats>;
+
+    /// The user's sessions active since the given time, latest first.
+    /// From request logs, so only as far back as those are kept.
+    async fn sessions(
+        &self,
+        uid: &str,
+        since: i64,
+        limit: u64,
+    ) -> anyhow::Result<Vec<SessionStats>>;
+
+    /// `None` if the user has no requests in it.
+    async fn session(
+        &self,
+        uid: &str,
+        session: &str,
+    ) -> anyhow::Result<Option<SessionStats>>;
+
+    async fn capture(&self, capture: &Capture) -> anyhow::Result<()>;
+
+    /// Those whose bodies contain the text, newest first.
+    async fn capture_search(
+        &self,
+        text: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<Capture>>;
+
+    async fn capture_get(
+        &self,
+        req_id: &str,
+    ) -> anyhow::Result<Option<Capture>>;
+
+    /// Of the requests since the given time, per experiment, arm and model.
+    /// From request logs, so only as far back as those are kept.
+    async fn experiment_stats(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<ArmStats>>;
+
+    /// Of the requests since the given time, of the user or of all, per
+    /// value of the tag, or of every tag when not given, costliest first.
+    /// From request logs, so only as far back as those are kept.
+    async fn tag_stats(
+        &self,
+        uid: Option<&str>,
+        tag: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<TagStats>>;
+
+    async fn shadow_log(&self, log: &ShadowLog) -> anyhow::Result<()>;
+
+    /// Queues the batch, with the requests as its items, in order.
+    async fn batch_create(
+        &self,
+        batch: &Batch,
+        requests: &[String],
+    ) -> anyhow::Result<()>;
+
+    async fn batch_get(&self, id: &str) -> anyhow::Result<Option<Batch>>;
+
+    /// The user's, newest first.
+    async fn batch_list(&self, uid: &str) -> anyhow::Result<Vec<Batch>>;
+
+    /// In order.
+    async fn batch_items(&self, id: &str) -> anyhow::Result<Vec<BatchItem>>;
+
+    /// Unless already finished. Returns whether it was cancelled.
+    async fn batch_cancel(&self, id: &str) -> anyhow::Result<bool>;
+
+    /// Those which aren't finished yet, oldest first.
+    async fn batch_pending(&self) -> anyhow::Result<Vec<Batch>>;
+
+    /// Claims, until the time given, the first item of the batch without a
+    /// response yet, nor a claim as of `now`, so that no two instances
+    /// process it at once.
+    async fn batch_item_claim(
+        &self,
+        id: &str,
+        now: i64,
+        until: i64,
+    ) -> anyhow::Result<Option<BatchItem>>;
+
+    /// Of a claimed item, for it to be claimed again, as when its user can't
+    /// make requests for now.
+    async fn batch_item_release(
+        &self,
+        id: &str,
+        index: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Keeps the response to the item, finishing the batch after its last
+    /// one. Returns whether the batch is still pending, which it isn't after
+    /// the last item, or if it was cancelled meanwhile.
+    async fn batch_item_complete(
+        &self,
+        id: &str,
+        index: i64,
+        code: i64,
+        response: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Of the requests logged with their [`RequestLog::usage`], since the
+    /// given time, of all users, or just the given user.
+    async fn usage_totals(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<UsageTotals>>;
+
+    /// Of the requests mirrored since the given time, per pair of models.
+    async fn shadow_compare(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<ShadowComparison>>;
+
+    /// Top users on the given date ("YYYY-MM-DD", UTC), best first.
+    async fn leaderboard(
+        &self,
+        date: &str,
+        rank: Rank,
+        limit: u64,
+    ) -> anyhow::Result<Vec<LeaderboardEntry>>;
+
+    /// Keeps the prompt token estimate of a request, before calibration, to
+    /// compare it later with the actual count in its log.
+    async fn token_estimate_record(
+        &self,
+        req_id: &str,
+        model: &str,
+        estimate: u64,
+        time: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Estimated vs actual prompt tokens per model, of the successful
+    /// requests since `time`. The factor is their plain ratio.
+    async fn token_estimate_totals(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<Calibration>>;
+
+    async fn calibration_set(
+        &self,
+        calibration: &Calibration,
+        time: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Model -> factor.
+    async fn calibration_get(&self) -> anyhow::Result<BTreeMap<String, f64>>;
+
+    /// Claims the user's idempotency key for the request with the given
+    /// fingerprint, unless it is already claimed and not yet expired.
+    async fn idempotency_claim(
+        &self,
+        uid: &str,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        expires: i64,
+    ) -> anyhow::Result<Idempotency>;
+
+    /// Keeps the response of the claimed key, for replaying.
+    async fn idempotency_complete(
+        &self,
+        uid: &str,
+        key: &str,
+        status: u16,
+        body: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Gives up the claim, so that the request may be retried.
+    async fn idempotency_release(
+        &self,
+        uid: &str,
+        key: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Checks that the database is reachable.
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    /// Returns hit count and duration since previous hit.
+    async fn hit(&self, uid: &str) -> anyhow::Result<(u64, Duration)>;
+
+    /// Atomically adds the requested amount to today's usage, but only if
+    /// it fits in all of the daily budgets: global tokens, model tokens (if
+    /// the model has a limit) and cost (if there is a limit).
+    /// Global budgets are scaled by the role's multiplier, unless
+    /// overridden. With an org, its shared budgets must fit too, and the
+    /// user is counted as its member. Returns the budget it exceeds if it
+    /// doesn't fit.
+    async fn budget_reserve(
+        &self,
+        uid: &str,
+        org: Option<&str>,
+        role: &str,
+        overrides: BudgetOverrides,
+        model: &str,
+        requested: Amount,
+    ) -> anyhow::Result<Result<Reservation, Exceeded>>;
+
+    /// Today's global token budget (not the per-model ones), the way
+    /// budget_reserve sees it.
+    async fn token_budget(
+        &self,
+        uid: &str,
+        role: &str,
+        overrides: BudgetOverrides,
+    ) -> anyhow::Result<TokenBudget>;
+
+    /// Replaces the reserved amount with the actually used amount, which is
+    /// 0 when the request failed and the reservation should be refunded.
+    async fn budget_settle(
+        &self,
+        reservation: &Reservation,
+        used: Amount,
+    ) -> anyhow::Result<()>;
+
+    /// Request IDs may come from clients, so a reused one is suffixed to
+    /// keep the logs apart.
+    async fn log_request(&self, log: &RequestLog) -> anyhow::Result<()>;
+
+    /// Writes the settlements and request logs queued when write-behind is
+    /// on in conf. Nothing otherwise.
+    async fn flush(&self) -> anyhow::Result<()>;
+
+    /// Counts a request in each of the windows, but only if it fits in all
+    /// of them. Returns the first window it does not fit in.
+    async fn rate_acquire(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Option<ratelimit::Window>>;
+
+    /// Per window, counts of its current and previous fixed windows,
+    /// without counting a request.
+    async fn rate_counts(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Vec<(u64, u64)>>;
+
+    /// Takes a token from the user's bucket of the role, as of now, since
+    /// the Unix epoch. Returns how long until there is one, if there is none
+    /// now.
+    async fn rate_take(
+        &self,
+        uid: &str,
+        role: &str,
+        bucket: ratelimit::Bucket,
+        now: Duration,
+    ) -> anyhow::Result<Option<Duration>>;
+
+    /// Forgets buckets unused for long enough to be full again, as of now,
+    /// since the Unix epoch. Returns how many.
+    async fn rate_buckets_expire(&self, now: Duration)
+        -> anyhow::Result<u64>;
+
+    /// Creates a new key and returns it in plain text. This is the only time
+    /// the plain text key is available, since we only store its hash.
+    async fn api_key_create(
+        &self,
+        uid: &str,
+        role: &str,
+    ) -> anyhow::Result<(ApiKey, String)>;
+
+    /// Revokes the given key of the user, or all of their keys if no key ID
+    /// is given. Returns the number of keys revoked.
+    async fn api_key_revoke(
+        &self,
+        uid: &str,
+        id: Option<&str>,
+    ) -> anyhow::Result<u64>;
+
+    async fn api_key_list(&self, uid: &str) -> anyhow::Result<Vec<ApiKey>>;
+
+    /// Finds the active (non-revoked) key matching the given plain text key.
+    async fn api_key_lookup(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<ApiKey>>;
+
+    /// Creates a key for the service, replacing any it had, revoked or not.
+    async fn service_key_create(
+        &self,
+        service: &str,
+        uid: &str,
+        role: &str,
+    ) -> anyhow::Result<ServiceKey>;
+
+    /// Returns whether the service had an active key.
+    async fn service_key_revoke(&self, service: &str)
+        -> anyhow::Result<bool>;
+
+    async fn service_key_list(&self) -> anyhow::Result<Vec<ServiceKey>>;
+
+    /// Unless revoked.
+    async fn service_key_get(
+        &self,
+        service: &str,
+    ) -> anyhow::Result<Option<ServiceKey>>;
+
+    /// Claims the service's nonce until it expires, unless it is claimed
+    /// already. Returns whether claimed.
+    async fn signing_nonce_claim(
+        &self,
+        service: &str,
+        nonce: &str,
+        now: i64,
+        expires: i64,
+    ) -> anyhow::Result<bool>;
+
+    /// Forgets those expired as of now, which could no longer be replayed.
+    /// Returns how many.
+    async fn signing_nonces_expire(&self, now: i64) -> anyhow::Result<u64>;
+
+    /// Counts a request of the user using the deprecated model or route.
+    async fn deprecated_usage_add(
+        &self,
+        uid: &str,
+        kind: &str,
+        name: &str,
+        time: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Of those used since the given time, by name, most requests first.
+    async fn deprecated_usage(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<DeprecatedUsage>>;
+
+    async fn jwt_revoke(&self, jti: &str) -> anyhow::Result<()>;
+
+    /// Ignores whether it is revoked, since it is just being minted.
+    async fn jwt_mint_record(&self, mint: &JwtMint) -> anyhow::Result<()>;
+
+    /// Of the org, or of all, newest first.
+    async fn jwt_mints(
+        &self,
+        org: Option<&str>,
+        limit: u64,
+    ) -> anyhow::Result<Vec<JwtMint>>;
+
+    async fn jwt_mint_get(
+        &self,
+        jti: &str,
+    ) -> anyhow::Result<Option<JwtMint>>;
+
+    /// Revokes all of the user's tokens issued until now.
+    async fn jwt_revoke_all(&self, uid: &str) -> anyhow::Result<()>;
+
+    /// Tokens without "iat" can't be told apart from the ones issued before
+    /// the revocation of all of the user's tokens, so they are considered
+    /// revoked too.
+    async fn jwt_is_revoked(
+        &self,
+        jti: Option<&str>,
+        uid: &str,
+        iat: Option<u64>,
+    ) -> anyhow::Result<bool>;
+
+    /// All users who ever made a request or were managed by an admin.
+    async fn account_list(&self) -> anyhow::Result<Vec<Account>>;
+
+    /// A page of [`Storage::account_list`], with each one's budget, in a
+    /// single query. Pending write-behind usage is left out.
+    async fn account_page(
+        &self,
+        is_suspended: Option<bool>,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<(Account, BudgetBasis)>>;
+
+    /// Returns defaults for users who were never managed.
+    async fn account_get(&self, uid: &str) -> anyhow::Result<Account>;
+
+    /// `None` reverts to the global limit.
+    async fn account_set_max_tokens_per_day(
+        &self,
+        uid: &str,
+        max_tokens_per_day: Option<u64>,
+    ) -> anyhow::Result<Account>;
+
+    /// By an admin. Lifting a suspension also lifts automatic ones.
+    async fn account_set_suspended(
+        &self,
+        uid: &str,
+        is_suspended: bool,
+    ) -> anyhow::Result<Account>;
+
+    /// Automatically, for the given reason.
+    async fn suspension_create(
+        &self,
+        uid: &str,
+        reason: &str,
+    ) -> anyhow::Result<Account>;
+
+    /// Currently suspended users, most recently suspended first.
+    async fn suspension_list(&self) -> anyhow::Result<Vec<Suspension>>;
+
+    /// Per user not currently suspended, their requests, errors of their
+    /// own making, per [`ErrorClass::is_client_caused`], and tokens from the
+    /// request logs since `time`, except those from before their last
+    /// suspension was lifted, which were already dealt with.
+    async fn suspension_candidates(
+        &self,
+        time: i64,
+    ) -> anyhow::Result<Vec<UserStats>>;
+
+    /// Adds tokens on top of today's budget. Returns today's bonus total.
+    async fn account_grant_tokens(
+        &self,
+        uid: &str,
+        tokens: u64,
+    ) -> anyhow::Result<u64>;
+
+    /// Adds to the user's credits, which are used once their budget for
+    /// a period is. Returns the balance.
+    async fn credits_grant(
+        &self,
+        uid: &str,
+        tokens: u64,
+        note: Option<&str>,
+    ) -> anyhow::Result<i64>;
+
+    async fn credits_balance(&self, uid: &str) -> anyhow::Result<i64>;
+
+    /// Newest first.
+    async fn credits_list(&self, uid: &str) -> anyhow::Result<Vec<Credit>>;
+
+    /// Closes the budget periods before `period` in which users made
+    /// requests: what they used beyond the budget is taken out of their
+    /// credits, as much as there is, and, with `max_rollover`, what was
+    /// left of it is added, up to that many credits. Returns how many were
+    /// closed.
+    async fn credits_close(
+        &self,
+        period: &str,
+        max_rollover: Option<u64>,
+    ) -> anyhow::Result<u64>;
+
+    /// Of the budget periods starting within the dates, as YYYY-MM-DD,
+    /// the tokens taken out of each user's credits, by uid.
+    async fn credits_used(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> anyhow::Result<Vec<(String, u64)>>;
+
+    async fn org_get(&self, org: &str) -> anyhow::Result<Org>;
+
+    /// `None` reverts to the defaults.
+    async fn org_set_budget(
+        &self,
+        org: &str,
+        max_tokens_per_day: Option<u64>,
+        max_cost_per_day: Option<f64>,
+    ) -> anyhow::Result<Org>;
+
+    /// `None` reverts to the conf's.
+    async fn org_set_reserved_rpm(
+        &self,
+        org: &str,
+        reserved_rpm: Option<u64>,
+    ) -> anyhow::Result<Org>;
+
+    /// Of the orgs which have their own, ordered.
+    async fn orgs_reserved_rpm(&self) -> anyhow::Result<Vec<(String, u64)>>;
+
+    /// As (org, uid), of all who ever made requests as members, ordered.
+    async fn org_members(&self) -> anyhow::Result<Vec<(String, String)>>;
+
+    /// Whether the user ever made requests as a member of the org.
+    async fn org_has_member(
+        &self,
+        org: &str,
+        uid: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Per user, model and day, for dates ("YYYY-MM-DD", UTC) from `from`
+    /// to `to`, inclusive. Ordered by date, user and model.
+    async fn report(
+        &self,
+        from: &str,
+        to: &str,
+        uid: Option<&str>,
+    ) -> anyhow::Result<Vec<ReportRow>>;
+
+    /// Usage on the given date ("YYYY-MM-DD", UTC).
+    async fn usage(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<DailyUsage>;
+
+    /// Adds the next version of the template. Returns its number.
+    async fn template_put(
+        &self,
+        owner: Owner<'_>,
+        name: &str,
+        body: &str,
+    ) -> anyhow::Result<i64>;
+
+    /// The latest version, unless another is asked for.
+    async fn template_get(
+        &self,
+        owner: Owner<'_>,
+        name: &str,
+        version: Option<i64>,
+    ) -> anyhow::Result<Option<Template>>;
+
+    /// Latest versions, by name.
+    async fn template_list(
+        &self,
+        owner: Owner<'_>,
+    ) -> anyhow::Result<Vec<Template>>;
+
+    /// All versions. Returns whether there were any.
+    async fn template_delete(
+        &self,
+        owner: Owner<'_>,
+        name: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// JSON, as in [`crate::preferences::Preferences`].
+    async fn preferences_get(
+        &self,
+        uid: &str,
+    ) -> anyhow::Result<Option<String>>;
+
+    /// Replacing any before.
+    async fn preferences_put(
+        &self,
+        uid: &str,
+        body: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Returns whether there were any.
+    async fn preferences_delete(&self, uid: &str) -> anyhow::Result<bool>;
+
+    async fn invite_create(
+        &self,
+        code: &str,
+        max_uses: u64,
+        time_expires: Option<i64>,
+    ) -> anyhow::Result<Invite>;
+
+    /// Newest first.
+    async fn invite_list(&self) -> anyhow::Result<Vec<Invite>>;
+
+    /// Uses up one of the invite's uses, unless the email already signed
+    /// up.
+    async fn signup_create(
+        &self,
+        email: &str,
+        invite: &str,
+    ) -> anyhow::Result<SignupOutcome>;
+
+    /// Newest first.
+    async fn signup_list(&self) -> anyhow::Result<Vec<Signup>>;
+
+    /// Sets the user's own daily budget, which overrides the starter one
+    /// in their token. `None` if they never signed up.
+    async fn signup_upgrade(
+        &self,
+        email: &str,
+        max_tokens_per_day: u64,
+    ) -> anyhow::Result<Option<Signup>>;
+
+    /// Maps the user or org to the Stripe customer billed for its usage.
+    /// `None` unmaps it.
+    async fn stripe_customer_set(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        customer: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    async fn stripe_customers(&self) -> anyhow::Result<Vec<StripeCustomer>>;
+
+    async fn stripe_is_reported(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        date: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Records that Stripe accepted the usage of the day.
+    async fn stripe_reported(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        date: &str,
+        value: u64,
+    ) -> anyhow::Result<()>;
+
+    /// Adds to what all users spent at the provider on the date, returning
+    /// the sum.
+    async fn provider_spend_add(
+        &self,
+        provider: &str,
+        date: &str,
+        amount: Amount,
+    ) -> anyhow::Result<Amount>;
+
+    /// Of each provider, on the date.
+    async fn provider_spend(
+        &self,
+        date: &str,
+    ) -> anyhow::Result<Vec<(String, Amount)>>;
+
+    async fn probe_record(&self, probe: &Probe) -> anyhow::Result<()>;
+
+    /// Of all providers, since the time, oldest first.
+    async fn probes(&self, since: i64) -> anyhow::Result<Vec<Probe>>;
+
+    /// Of before the time. Returns how many.
+    async fn probes_prune(&self, before: i64) -> anyhow::Result<u64>;
+}
+
+/// Connects to the backend selected in conf and brings its schema up to date.
+pub async fn connect() -> anyhow::Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = match pool().await? {
+        Pool::Sqlite { reader, writer } => {
+            MIGRATOR_SQLITE.run(&writer).await?;
+            Arc::new(Sql::new(reader, writer).await?)
+        }
+        Pool::Postgres(pool) => {
+            MIGRATOR_POSTGRES.run(&pool).await?;
+            Arc::new(Sql::new(pool.clone(), pool).await?)
+        }
+    };
+    Ok(storage)
+}
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    pub is_applied: bool,
+}
+
+/// Applies those which are missing.
+pub async fn migrate() -> anyhow::Result<()> {
+    match pool().await? {
+        Pool::Sqlite { writer, .. } => MIGRATOR_SQLITE.run(&writer).await?,
+        Pool::Postgres(pool) => MIGRATOR_POSTGRES.run(&pool).await?,
+    }
+    Ok(())
+}
+
+/// All known to this release, in order.
+pub async fn migrations() -> anyhow::Result<Vec<Migration>> {
+    match pool().await? {
+        Pool::Sqlite { reader, .. } => {
+            migrations_(&MIGRATOR_SQLITE, &reader).await
+        }
+        Pool::Postgres(pool) => migrations_(&MIGRATOR_POSTGRES, &pool).await,
+    }
+}
+
+/// Reverts the applied migrations newer than `target`, or just the latest
+/// one when not given. Returns the versions reverted.
+pub async fn migrate_revert(target: Option<i64>) -> anyhow::Result<Vec<i64>> {
+    let applied: Vec<i64> = migrations()
+        .await?
+        .into_iter()
+        .filter(|migration| migration.is_applied)
+        .map(|migration| migration.version)
+        .collect();
+    let target = match target {
+        Some(target) => target,
+        None => match applied.as_slice() {
+            [] => return Ok(Vec::new()),
+            [_] => -1,
+            [.., previous, _] => *previous,
+        },
+    };
+    match pool().await? {
+        Pool::Sqlite { writer, .. } => {
+            MIGRATOR_SQLITE.undo(&writer, target).await?;
+        }
+        Pool::Postgres(pool) => MIGRATOR_POSTGRES.undo(&pool, target).await?,
+    }
+    Ok(applied.into_iter().filter(|v| *v > target).rev().collect())
+}
+
+async fn migrations_<DB>(
+    migrator: &sqlx::migrate::Migrator,
+    pool: &sqlx::Pool<DB>,
+) -> anyhow::Result<Vec<Migration>>
+where
+    DB: sqlx::Database,
+    DB::Connection: sqlx::migrate::Migrate,
+{
+    use sqlx::migrate::Migrate;
+
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    let migrations = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| Migration {
+            version: migration.version,
+            description: migration.description.to_string(),
+            is_applied: applied.contains(&migration.version),
+        })
+        .collect();
+    Ok(migrations)
+}
+
+enum Pool {
+    /// The same one, unless writes are split off, per [`conf::Sqlite`].
+    Sqlite {
+        reader: sqlx::SqlitePool,
+        writer: sqlx::SqlitePool,
+    },
+    Postgres(sqlx::PgPool),
+}
+
+/// Connects to the backend selected in conf, leaving its schema as it is.
+async fn pool() -> anyhow::Result<Pool> {
+    let conf = conf::global();
+    let pool = match &conf.storage {
+        conf::Storage::Sqlite { file } => {
+            if let Some(parent) = file.parent() {
+                let ctx = format!(
+                    "Failed to create parent directory \
+                    for database file: {file:?}"
+                );
+                fs::create_dir_all(parent).context(ctx)?;
+            }
+            let sqlite = &conf.sqlite;
+            let busy_timeout =
+                Duration::from_secs_f32(conf.sqlite_busy_timeout);
+            let options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(file)
+                .create_if_missing(true)
+                .journal_mode(journal_mode(sqlite.journal_mode))
+                .synchronous(synchronous(sqlite.synchronous))
+                .busy_timeout(busy_timeout);
+            let pool_options = |max_connections| {
+                sqlx::sqlite::SqlitePoolOptions::new()
+                    .max_connections(max_connections)
+                    .acquire_timeout(Duration::from_secs_f32(
+                        sqlite.acquire_timeout,
+                    ))
+            };
+            // The writer first, for it to set the journal mode, which
+            // takes the lock, before readers connect.
+            let writer = if sqlite.split_writes {
+                pool_options(1).connect_with(options.clone()).await?
+            } else {
+                pool_options(sqlite.max_connections)
+                    .connect_with(options.clone())
+                    .await?
+            };
+            let reader = if sqlite.split_writes {
+                pool_options(sqlite.max_connections)
+                    .connect_with(options)
+                    .await?
+            } else {
+                writer.clone()
+            };
+            Pool::Sqlite { reader, writer }
+        }
+        conf::Storage::Postgres { url } => Pool::Postgres(
+            sqlx::PgPool::connect(url)
+                .await
+                .context("Failed to connect to Postgres")?,
+        ),
+    };
+    Ok(pool)
+}
+
+fn journal_mode(
+    mode: conf::SqliteJournalMode,
+) -> sqlx::sqlite::SqliteJournalMode {
+    use sqlx::sqlite::SqliteJournalMode as Mode;
+    match mode {
+        conf::SqliteJournalMode::Delete => Mode::Delete,
+        conf::SqliteJournalMode::Truncate => Mode::Truncate,
+        conf::SqliteJournalMode::Persist => Mode::Persist,
+        conf::SqliteJournalMode::Memory => Mode::Memory,
+        conf::SqliteJournalMode::Wal => Mode::Wal,
+        conf::SqliteJournalMode::Off => Mode::Off,
+    }
+}
+
+fn synchronous(
+    synchronous: conf::SqliteSynchronous,
+) -> sqlx::sqlite::SqliteSynchronous {
+    use sqlx::sqlite::SqliteSynchronous as Synchronous;
+    match synchronous {
+        conf::SqliteSynchronous::Off => Synchronous::Off,
+        conf::SqliteSynchronous::Normal => Synchronous::Normal,
+        conf::SqliteSynchronous::Full => Synchronous::Full,
+        conf::SqliteSynchronous::Extra => Synchronous::Extra,
+    }
+}
+
+/// SQL implementation of [`Storage`], shared by all sqlx backends.
+///
+/// Queries must stick to the common subset of SQL and use `$N` placeholders,
+/// which both SQLite and Postgres understand.
+pub struct Sql<DB: sqlx::Database> {
+    /// Of reads.
+    pool: sqlx::Pool<DB>,
+
+    /// Of writes, and reads which must see them within the same
+    /// transaction. May be the same pool.
+    writer: sqlx::Pool<DB>,
+
+    /// Not when writes are immediate.
+    write_behind: Option<WriteBehind>,
+
+    /// Not when budgets are checked in the database.
+    budget_cache: Option<BudgetCache>,
+
+    /// Not when rate limits and budgets are counted in the database.
+    #[cfg(feature = "redis")]
+    redis: Option<shared::Redis>,
+}
+
+/// Settlements and request logs queued to be written together, in one
+/// transaction, rather than each in its own, which serializes requests on
+/// SQLite's single writer. Budget checks still see the queued settlements.
+struct WriteBehind {
+    pending: std::sync::Mutex<Pending>,
+
+    /// One flush at a time.
+    flush: tokio::sync::Mutex<()>,
+
+    /// Per [`conf::WriteBehind::max_queued`].
+    max_queued: usize,
+}
+
+#[derive(Default)]
+struct Pending {
+    queued: Vec<Write>,
+
+    /// Taken from the queue, but not yet committed.
+    flushing: Vec<Write>,
+}
+
+#[derive(Clone)]
+enum Write {
+    Settle(Reservation, Amount),
+    Log(RequestLog),
+}
+
+/// How long budget limits set by admins are cached, since they may be set
+/// with the CLI, from another process.
+const BUDGET_LIMITS_TTL: Duration = Duration::from_secs(60);
+
+/// Today's usage of users, and what their limits depend on, so that budget
+/// checks don't hit the database. Only correct for a single instance, since
+/// the usage through others isn't seen.
+#[derive(Default)]
+struct BudgetCache {
+    state: std::sync::Mutex<BudgetCacheState>,
+}
+
+#[derive(Default)]
+struct BudgetCacheState {
+    /// Of all the cached budgets. They are dropped once it's another day.
+    date: String,
+    budgets: HashMap<String, Arc<tokio::sync::Mutex<CachedBudget>>>,
+}
+
+/// Each part loaded lazily, while holding the lock, so that concurrent
+/// requests of the user don't each load it.
+#[derive(Default)]
+struct CachedBudget {
+    usage: Option<CachedUsage>,
+    limits: Option<CachedLimits>,
+}
+
+#[derive(Debug, Default)]
+struct CachedUsage {
+    tokens: i64,
+    cost: f64,
+    tokens_by_model: HashMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedLimits {
+    /// Set by an admin.
+    max_tokens_per_day: Option<u64>,
+    bonus_tokens: u64,
+    credits: i64,
+    loaded: Instant,
+}
+
+/// Of a user's tokens in a period.
+#[derive(Debug, Clone, Copy)]
+struct TokenLimit {
+    /// The budget itself.
+    cap: u64,
+    credits: i64,
+}
+
+impl TokenLimit {
+    fn total(self) -> u64 {
+        self.cap.saturating_add_signed(self.credits)
+    }
+}
+
+/// Of a user's budgets, for a request.
+#[derive(Debug, Clone, Copy)]
+struct Maxes {
+    /// Including credits.
+    tokens: i64,
+
+    /// Without them, to be recorded for closing the period.
+    cap: i64,
+
+    tokens_for_model: i64,
+    cost: f64,
+}
+
+impl BudgetCache {
+    fn entry(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> Arc<tokio::sync::Mutex<CachedBudget>> {
+        let mut state = self.state();
+        if state.date != date {
+            date.clone_into(&mut state.date);
+            state.budgets.clear();
+        }
+        state.budgets.entry(uid.to_string()).or_default().clone()
+    }
+
+    fn get(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> Option<Arc<tokio::sync::Mutex<CachedBudget>>> {
+        let state = self.state();
+        (state.date == date)
+            .then(|| state.budgets.get(uid).cloned())
+            .flatten()
+    }
+
+    /// Clamped at 0, the way the database does.
+    async fn settle(
+        &self,
+        reservation: &Reservation,
+        used: Amount,
+    ) -> anyhow::Result<()> {
+        let Some(entry) = self.get(&reservation.uid, &reservation.date)
+        else {
+            return Ok(());
+        };
+        let tokens_delta = i64::try_from(used.tokens)?
+            - i64::try_from(reservation.amount.tokens)?;
+        let cost_delta = used.cost - reservation.amount.cost;
+        let mut budget = entry.lock().await;
+        if let Some(usage) = budget.usage.as_mut() {
+            usage.tokens = (usage.tokens + tokens_delta).max(0);
+            usage.cost = (usage.cost + cost_delta).max(0.0);
+            if let Some(tokens) =
+                usage.tokens_by_model.get_mut(&reservation.model)
+            {
+                *tokens = (*tokens + tokens_delta).max(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// For the changes by an admin to take effect right away.
+    async fn forget_limits(&self, uid: &str) {
+        let Some(entry) = self.get(uid, &period::current()) else {
+            return;
+        };
+        entry.lock().await.limits = None;
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BudgetCacheState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// What the queued settlements will change today's totals by, for a user,
+/// one of their models and their org.
+#[derive(Debug, Default, Clone, Copy)]
+struct Deltas {
+    tokens: i64,
+    tokens_for_model: i64,
+    cost: f64,
+    org_tokens: i64,
+    org_cost: f64,
+}
+
+impl WriteBehind {
+    fn new(max_queued: usize) -> Self {
+        Self {
+            pending: std::sync::Mutex::default(),
+            flush: tokio::sync::Mutex::default(),
+            max_queued,
+        }
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn push(&self, write: Write) {
+        let mut pending = self.pending();
+        pending.queued.push(write);
+        self.cap(&mut pending.queued);
+    }
+
+    /// Of a failed flush, back in front of those queued since, since
+    /// nothing of it was committed.
+    fn requeue(&self, pending: &mut Pending, flushing: Vec<Write>) {
+        let queued = std::mem::replace(&mut pending.queued, flushing);
+        pending.queued.extend(queued);
+        self.cap(&mut pending.queued);
+    }
+
+    /// Dropping the oldest past the max, rather than growing until out of
+    /// memory.
+    fn cap(&self, queued: &mut Vec<Write>) {
+        let excess = queued.len().saturating_sub(self.max_queued);
+        if excess == 0 {
+            return;
+        }
+        queued.drain(..excess);
+        tracing::error!(excess, "Dropped queued writes. Too many.");
+        metrics::counter!("raskol_write_behind_dropped_total")
+            .increment(u64::try_from(excess).unwrap_or(u64::MAX));
+    }
+
+    fn deltas(
+        &self,
+        uid: &str,
+        model: &str,
+        org: Option<&str>,
+        date: &str,
+    ) -> anyhow::Result<Deltas> {
+        let pending = self.pending();
+        let mut deltas = Deltas::default();
+        for write in pending.flushing.iter().chain(&pending.queued) {
+            let Write::Settle(reservation, used) = write else {
+                continue;
+            };
+            if reservation.date != date {
+                continue;
+            }
+            let tokens = i64::try_from(used.tokens)?
+                - i64::try_from(reservation.amount.tokens)?;
+            let cost = used.cost - reservation.amount.cost;
+            if reservation.uid == uid {
+                deltas.tokens += tokens;
+                deltas.cost += cost;
+                if reservation.model == model {
+                    deltas.tokens_for_model += tokens;
+                }
+            }
+            if org.is_some() && reservation.org.as_deref() == org {
+                deltas.org_tokens += tokens;
+                deltas.org_cost += cost;
+            }
+        }
+        Ok(deltas)
+    }
+}
+
+#[async_trait::async_trait]
+impl<DB> Storage for Sql<DB>
+where
+    DB: sqlx::Database,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'c> &'c sqlx::Pool<DB>: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    for<'q> i64: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> f64: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> String:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<&'q str>: sqlx::Encode<'q, DB> + sqlx::Type<DB>,
+    for<'q> &'q str: sqlx::Encode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<i64>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<f64>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Vec<u8>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<Vec<u8>>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'s> &'s str: sqlx::ColumnIndex<DB::Row>,
+    usize: sqlx::ColumnIndex<DB::Row>,
+{
+    async fn prune(
+        &self,
+        time: i64,
+        date: &str,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        let mut deleted = BTreeMap::new();
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        // Counting separately, since rows_affected isn't backend-agnostic
+        // and RETURNING every deleted row could be a lot.
+        if DB::NAME == "SQLite" {
+            // Not deleted along, since the index doesn't know their times.
+            sqlx::query(
+                "DELETE FROM captures_fts WHERE rowid IN (
+                    SELECT rowid FROM captures WHERE time < $1
+                )",
+            )
+            .bind(time)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for table in [
+            "request_logs",
+            "request_tags",
+            "token_estimates",
+            "captures",
+            "shadow_logs",
+            "request_usage",
+        ] {
+            let (count,): (i64,) = sqlx::query_as(&format!(
+                "SELECT COUNT(*) FROM {table} WHERE time < $1"
+            ))
+            .bind(time)
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query(&format!("DELETE FROM {table} WHERE time < $1"))
+                .bind(time)
+                .execute(&mut *tx)
+                .await?;
+            deleted.insert(table.to_string(), u64::try_from(count)?);
+        }
+        for table in [
+            "tokens",
+            "tokens_by_model",
+            "costs",
+            "bonus_tokens",
+            "org_usage",
+            "provider_spend",
+        ] {
+            let (count,): (i64,) = sqlx::query_as(&format!(
+                "SELECT COUNT(*) FROM {table} WHERE date < $1"
+            ))
+            .bind(date)
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query(&format!("DELETE FROM {table} WHERE date < $1"))
+                .bind(date)
+                .execute(&mut *tx)
+                .await?;
+            deleted.insert(table.to_string(), u64::try_from(count)?);
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn maintain(&self, vacuum: bool) -> anyhow::Result<()> {
+        if DB::NAME != "SQLite" {
+            return Ok(());
+        }
+        if vacuum {
+            sqlx::query("VACUUM").execute(&self.writer).await?;
+        }
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.writer)
+            .await?;
+        sqlx::query("PRAGMA optimize").execute(&self.writer).await?;
+        Ok(())
+    }
+
+    async fn rollup(&self, until: i64) -> anyhow::Result<i64> {
+        let until = until - until.rem_euclid(HOUR);
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let state: Option<(i64,)> = sqlx::query_as(
+            "SELECT value FROM job_state WHERE name = 'usage_hourly'",
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let from = match state {
+            Some((rolled,)) => rolled - ROLLUP_REROLLED,
+            None => {
+                let (min,): (Option<i64>,) =
+                    sqlx::query_as("SELECT MIN(time) FROM request_logs")
+                        .fetch_one(&mut *tx)
+                        .await?;
+                min.map_or(until, |min| min - min.rem_euclid(HOUR))
+            }
+        };
+        if from >= until {
+            tx.rollback().await?;
+            return Ok(from);
+        }
+        // Replacing on conflict, so re-rolling an hour is harmless.
+        sqlx::query(
+            "INSERT INTO usage_hourly (
+                    hour,
+                    uid,
+                    model,
+                    requests,
+                    errors,
+                    input_tokens,
+                    output_tokens,
+                    cost,
+                    duration_ms
+                )
+                SELECT
+                    (time / 3600) * 3600 AS hour,
+                    uid,
+                    model,
+                    COUNT(*),
+                    CAST(SUM(
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost),
+                    CAST(SUM(duration_ms) AS BIGINT)
+                FROM request_logs
+                WHERE time >= $1 AND time < $2
+                GROUP BY hour, uid, model
+                ON CONFLICT(hour, uid, model) DO UPDATE SET
+                    requests = excluded.requests,
+                    errors = excluded.errors,
+                    input_tokens = excluded.input_tokens,
+                    output_tokens = excluded.output_tokens,
+                    cost = excluded.cost,
+                    duration_ms = excluded.duration_ms",
+        )
+        .bind(from)
+        .bind(until)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO job_state (name, value)
+                VALUES ('usage_hourly', $1)
+                ON CONFLICT(name) DO UPDATE SET value = $1",
+        )
+        .bind(until)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(until)
+    }
+
+    async fn stats(
+        &self,
+        uid: Option<&str>,
+        org: Option<&str>,
+        since: i64,
+        page: &StatsPage,
+    ) -> anyhow::Result<Vec<UserStats>> {
+        let since_hour = since - since.rem_euclid(HOUR);
+        // Not bind parameters, since these are expressions.
+        let order = match page.sort {
+            StatsSort::Uid => "uid",
+            StatsSort::Requests => "SUM(requests) DESC, uid",
+            StatsSort::Errors => "SUM(errors) DESC, uid",
+            StatsSort::Tokens => {
+                "SUM(input_tokens) + SUM(output_tokens) DESC, uid"
+            }
+            StatsSort::Cost => "SUM(cost) DESC, uid",
+        };
+        let limit = page.limit.map_or(Ok(i64::MAX), i64::try_from)?;
+        let offset = i64::try_from(page.offset)?;
+        let rows: Vec<(String, i64, i64, i64, i64, f64)> =
+            sqlx::query_as(&format!(
+                "WITH rolled AS (
+                    SELECT COALESCE(MAX(value), 0) AS until FROM job_state
+                    WHERE name = 'usage_hourly'
+                )
+                SELECT
+                    uid,
+                    CAST(SUM(requests) AS BIGINT),
+                    CAST(SUM(errors) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost)
+                FROM (
+                    SELECT
+                        uid,
+                        requests,
+                        errors,
+                        input_tokens,
+                        output_tokens,
+                        cost
+                    FROM usage_hourly
+                    WHERE hour >= $1 AND hour < (SELECT until FROM rolled)
+                    UNION ALL
+                    SELECT
+                        uid,
+                        1,
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END,
+                        input_tokens,
+                        output_tokens,
+                        cost
+                    FROM request_logs
+                    WHERE time >= $1 AND time >= (SELECT until FROM rolled)
+                ) usage
+                WHERE (CAST($2 AS TEXT) IS NULL OR uid = $2)
+                AND (
+                    CAST($5 AS TEXT) IS NULL
+                    OR uid IN (SELECT uid FROM org_members WHERE org = $5)
+                )
+                GROUP BY uid
+                ORDER BY {order}
+                LIMIT $3 OFFSET $4"
+            ))
+            .bind(since_hour)
+            .bind(uid)
+            .bind(limit)
+            .bind(offset)
+            .bind(org)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut stats = rows
+            .into_iter()
+            .map(|(uid, requests, errors, input, output, cost)| {
+                Ok(UserStats {
+                    uid,
+                    requests: u64::try_from(requests)?,
+                    errors: u64::try_from(errors)?,
+                    input_tokens: u64::try_from(input)?,
+                    output_tokens: u64::try_from(output)?,
+                    cost,
+                    ..UserStats::default()
+                })
+            })
+            .collect::<anyhow::Result<Vec<UserStats>>>()?;
+        let index: HashMap<String, usize> = stats
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| (stats.uid.clone(), i))
+            .collect();
+        // In one scan, with the nearest-rank percentile by window
+        // functions, since SQLite has no percentile aggregate.
+        let rows: Vec<(String, i64, i64, f64, i64)> = sqlx::query_as(
+            "SELECT
+                    uid,
+                    CAST(SUM(
+                        CASE WHEN status BETWEEN 400 AND 499
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(
+                        CASE WHEN status >= 500 THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(AVG(duration_ms) AS DOUBLE PRECISION),
+                    CAST(COALESCE(MAX(
+                        CASE WHEN rank = (95 * count + 99) / 100
+                        THEN duration_ms END
+                    ), 0) AS BIGINT)
+                FROM (
+                    SELECT
+                        uid,
+                        status,
+                        error_class,
+                        duration_ms,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY uid ORDER BY duration_ms
+                        ) AS rank,
+                        COUNT(*) OVER (PARTITION BY uid) AS count
+                    FROM request_logs
+                    WHERE time >= $1
+                    AND (CAST($2 AS TEXT) IS NULL OR uid = $2)
+                ) ranked
+                GROUP BY uid",
+        )
+        .bind(since)
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        for (uid, client_errors, server_errors, avg_duration_ms, p95) in rows
+        {
+            if let Some(stats) = index.get(&uid).map(|&i| &mut stats[i]) {
+                stats.client_errors = u64::try_from(client_errors)?;
+                stats.server_errors = u64::try_from(server_errors)?;
+                stats.avg_duration_ms = avg_duration_ms;
+                stats.p95_duration_ms = u64::try_from(p95)?;
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn timeseries(
+        &self,
+        uid: Option<&str>,
+        bucket: i64,
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<UsageBucket>> {
+        let bucket = bucket.max(HOUR);
+        let from = from - from.rem_euclid(bucket);
+        let rows: Vec<(i64, i64, i64, i64, i64, f64, i64)> = sqlx::query_as(
+            "WITH rolled AS (
+                    SELECT COALESCE(MAX(value), 0) AS until FROM job_state
+                    WHERE name = 'usage_hourly'
+                )
+                SELECT
+                    bucket,
+                    CAST(SUM(requests) AS BIGINT),
+                    CAST(SUM(errors) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost),
+                    CAST(SUM(duration_ms) AS BIGINT)
+                FROM (
+                    SELECT
+                        (hour / $3) * $3 AS bucket,
+                        uid,
+                        requests,
+                        errors,
+                        input_tokens,
+                        output_tokens,
+                        cost,
+                        duration_ms
+                    FROM usage_hourly
+                    WHERE hour >= $1 AND hour < $2
+                    AND hour < (SELECT until FROM rolled)
+                    UNION ALL
+                    SELECT
+                        (time / $3) * $3 AS bucket,
+                        uid,
+                        1,
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END,
+                        input_tokens,
+                        output_tokens,
+                        cost,
+                        duration_ms
+                    FROM request_logs
+                    WHERE time >= $1 AND time < $2
+                    AND time >= (SELECT until FROM rolled)
+                ) usage
+                WHERE CAST($4 AS TEXT) IS NULL OR uid = $4
+                GROUP BY bucket
+                ORDER BY bucket",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(bucket)
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(time, requests, errors, input, output, cost, duration)| {
+                #[allow(clippy::cast_precision_loss)]
+                let avg_duration_ms =
+                    duration as f64 / requests.max(1) as f64;
+                Ok(UsageBucket {
+                    time,
+                    requests: u64::try_from(requests)?,
+                    errors: u64::try_from(errors)?,
+                    input_tokens: u64::try_from(input)?,
+                    output_tokens: u64::try_from(output)?,
+                    cost,
+                    avg_duration_ms,
+                })
+            })
+            .collect()
+    }
+
+    async fn recent_errors(
+        &self,
+        limit: u64,
+    ) -> anyhow::Result<Vec<RequestLog>> {
+        let filter = LogFilter {
+            is_error: true,
+            ..LogFilter::default()
+        };
+        self.request_logs(&filter, limit, 0).await
+    }
+
+    async fn request_logs(
+        &self,
+        filter: &LogFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<RequestLog>> {
+        let rows: Vec<RequestLogRow> = sqlx::query_as(&format!(
+            "SELECT
+                    req_id,
+                    uid,
+                    model,
+                    endpoint,
+                    status,
+                    input_tokens,
+                    output_tokens,
+                    cost,
+                    duration_ms,
+                    time,
+                    error_message,
+                    session,
+                    experiment,
+                    arm,
+                    upstream_timing,
+                    error_class,
+                    client_ip
+                FROM request_logs
+                {LOG_FILTER_WHERE}
+                ORDER BY time DESC
+                LIMIT $8
+                OFFSET $9"
+        ))
+        .bind(filter.uid.as_deref())
+        .bind(filter.model.as_deref())
+        .bind(filter.session.as_deref())
+        .bind(if filter.is_error { 400_i64 } else { 0 })
+        .bind(filter.status)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(i64::try_from(limit)?)
+        .bind(i64::try_from(offset)?)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(request_log_from_row).collect())
+    }
+
+    fn request_logs_stream(
+        &self,
+        time_from: i64,
+        time_to: i64,
+    ) -> BoxStream<'_, anyhow::Result<RequestLog>> {
+        sqlx::query_as::<_, RequestLogRow>(
+            "SELECT
+                    req_id,
+                    uid,
+                    model,
+                    endpoint,
+                    status,
+                    input_tokens,
+                    output_tokens,
+                    cost,
+                    duration_ms,
+                    time,
+                    error_message,
+                    session,
+                    experiment,
+                    arm,
+                    upstream_timing,
+                    error_class,
+                    client_ip
+                FROM request_logs
+                WHERE time >= $1 AND time < $2
+                ORDER BY time, req_id",
+        )
+        .bind(time_from)
+        .bind(time_to)
+        .fetch(&self.pool)
+        .map(|row| Ok(request_log_from_row(row?)))
+        .boxed()
+    }
+
+    async fn request_log(
+        &self,
+        req_id: &str,
+    ) -> anyhow::Result<Option<RequestLog>> {
+        let row: Option<RequestLogRow> = sqlx::query_as(
+            "SELECT
+                    req_id,
+                    uid,
+                    model,
+                    endpoint,
+                    status,
+                    input_tokens,
+                    output_tokens,
+                    cost,
+                    duration_ms,
+                    time,
+                    error_message,
+                    session,
+                    experiment,
+                    arm,
+                    upstream_timing,
+                    error_class,
+                    client_ip
+                FROM request_logs
+                WHERE req_id = $1",
+        )
+        .bind(req_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(request_log_from_row))
+    }
+
+    async fn request_logs_summary(
+        &self,
+        filter: &LogFilter,
+    ) -> anyhow::Result<LogSummary> {
+        // Casting sums, since Postgres widens them to NUMERIC.
+        let (requests, errors, input, output, cost, duration): (
+            i64,
+            Option<i64>,
+            Option<i64>,
+            Option<i64>,
+            Option<f64>,
+            Option<i64>,
+        ) = sqlx::query_as(&format!(
+            "SELECT
+                    COUNT(*),
+                    CAST(SUM(
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost),
+                    CAST(SUM(duration_ms) AS BIGINT)
+                FROM request_logs
+                {LOG_FILTER_WHERE}"
+        ))
+        .bind(filter.uid.as_deref())
+        .bind(filter.model.as_deref())
+        .bind(filter.session.as_deref())
+        .bind(if filter.is_error { 400_i64 } else { 0 })
+        .bind(filter.status)
+        .bind(filter.since)
+        .bind(filter.until)
+        .fetch_one(&self.pool)
+        .await?;
+        let requests = u64::try_from(requests)?;
+        #[allow(clippy::cast_precision_loss)]
+        let avg_duration_ms = if requests == 0 {
+            0.0
+        } else {
+            duration.unwrap_or(0) as f64 / requests as f64
+        };
+        Ok(LogSummary {
+            requests,
+            errors: u64::try_from(errors.unwrap_or(0))?,
+            input_tokens: u64::try_from(input.unwrap_or(0))?,
+            output_tokens: u64::try_from(output.unwrap_or(0))?,
+            cost: cost.unwrap_or(0.0),
+            avg_duration_ms,
+        })
+    }
+
+    async fn latency_stats(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<LatencyStats>> {
+        // Of upstream's timing, stored as JSON, which backends read
+        // differently.
+        let timing = |field: &str| {
+            if DB::NAME == "SQLite" {
+                format!("json_extract(upstream_timing, '$.{field}')")
+            } else {
+                format!(
+                    "CAST(
+                        CAST(upstream_timing AS JSON) ->> '{field}'
+                        AS DOUBLE PRECISION
+                    )"
+                )
+            }
+        };
+        // Nearest-rank, with window functions, since SQLite has no
+        // percentile aggregate. Of each model and measure, of those set.
+        let rows: Vec<(String, String, i64, f64, f64)> =
+            sqlx::query_as(&format!(
+                "WITH logs AS (
+                    SELECT model, duration_ms, upstream_timing
+                    FROM request_logs
+                    WHERE time >= $1
+                    AND status < 400
+                    AND (CAST($2 AS TEXT) IS NULL OR uid = $2)
+                ),
+                samples AS (
+                    SELECT
+                        model,
+                        'duration' AS measure,
+                        CAST(duration_ms AS DOUBLE PRECISION) AS value
+                    FROM logs
+                    UNION ALL
+                    SELECT model, 'queue', {} FROM logs
+                    UNION ALL
+                    SELECT model, 'prompt', {} FROM logs
+                    UNION ALL
+                    SELECT model, 'completion', {} FROM logs
+                )
+                SELECT
+                    model,
+                    measure,
+                    CAST(MAX(count) AS BIGINT),
+                    MAX(CASE WHEN rank = (50 * count + 99) / 100
+                        THEN value END),
+                    MAX(CASE WHEN rank = (95 * count + 99) / 100
+                        THEN value END)
+                FROM (
+                    SELECT
+                        model,
+                        measure,
+                        value,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY model, measure ORDER BY value
+                        ) AS rank,
+                        COUNT(*) OVER (PARTITION BY model, measure) AS count
+                    FROM samples
+                    WHERE value IS NOT NULL
+                ) ranked
+                GROUP BY model, measure
+                ORDER BY model",
+                timing("queue_ms"),
+                timing("prompt_ms"),
+                timing("completion_ms"),
+            ))
+            .bind(since)
+            .bind(uid)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut models: BTreeMap<String, LatencyStats> = BTreeMap::new();
+        for (model, measure, count, p50, p95) in rows {
+            let stats =
+                models.entry(model.clone()).or_insert_with(|| LatencyStats {
+                    model,
+                    requests: 0,
+                    duration_ms: Percentiles { p50: 0.0, p95: 0.0 },
+                    queue_ms: None,
+                    prompt_ms: None,
+                    completion_ms: None,
+                });
+            let percentiles = Percentiles { p50, p95 };
+            match measure.as_str() {
+                "duration" => {
+                    stats.requests = u64::try_from(count)?;
+                    stats.duration_ms = percentiles;
+                }
+                "queue" => stats.queue_ms = Some(percentiles),
+                "prompt" => stats.prompt_ms = Some(percentiles),
+                "completion" => stats.completion_ms = Some(percentiles),
+                _ => {}
+            }
+        }
+        Ok(models.into_values().collect())
+    }
+
+    async fn public_stats(&self, since: i64) -> anyhow::Result<PublicStats> {
+        // Casting sums, since Postgres widens them to NUMERIC.
+        let (requests, tokens, active_teams, succeeded): (
+            i64,
+            Option<i64>,
+            i64,
+            Option<i64>,
+        ) = sqlx::query_as(
+            "SELECT
+                    COUNT(*),
+                    CAST(SUM(input_tokens + output_tokens) AS BIGINT),
+                    COUNT(DISTINCT uid),
+                    CAST(SUM(
+                        CASE WHEN status < 400 THEN 1 ELSE 0 END
+                    ) AS BIGINT)
+                FROM request_logs
+                WHERE time >= $1",
+        )
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
+        // Nearest-rank, as of latency_stats, without fetching them all.
+        let succeeded = u64::try_from(succeeded.unwrap_or(0))?;
+        let duration_ms_p95 = if succeeded == 0 {
+            None
+        } else {
+            let offset = i64::try_from((succeeded * 95).div_ceil(100) - 1)?;
+            sqlx::query_scalar(
+                "SELECT duration_ms FROM request_logs
+                    WHERE time >= $1 AND status < 400
+                    ORDER BY duration_ms
+                    LIMIT 1 OFFSET $2",
+            )
+            .bind(since)
+            .bind(offset)
+            .fetch_optional(&self.pool)
+            .await?
+        };
+        Ok(PublicStats {
+            requests: u64::try_from(requests)?,
+            tokens: u64::try_from(tokens.unwrap_or(0))?,
+            active_teams: u64::try_from(active_teams)?,
+            duration_ms_p95,
+        })
+    }
+
+    async fn error_rates(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<ErrorRates> {
+        let rows: Vec<(Option<String>, i64)> = sqlx::query_as(
+            "SELECT error_class, COUNT(*) FROM request_logs
+                WHERE time >= $1
+                AND (CAST($2 AS TEXT) IS NULL OR uid = $2)
+                GROUP BY error_class",
+        )
+        .bind(since)
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut requests = 0;
+        let mut classes = Vec::new();
+        for (class, count) in rows {
+            let count = u64::try_from(count)?;
+            requests += count;
+            // Those of classes since retired are still requests.
+            if let Some(class) = class.and_then(|class| class.parse().ok()) {
+                classes.push((class, count));
+            }
+        }
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let classes = classes
+            .into_iter()
+            .map(|(class, errors)| {
+                #[allow(clippy::cast_precision_loss)]
+                let rate = errors as f64 / requests as f64;
+                ErrorRate {
+                    class,
+                    errors,
+                    rate,
+                }
+            })
+            .collect();
+        Ok(ErrorRates { requests, classes })
+    }
+
+    async fn sessions(
+        &self,
+        uid: &str,
+        since: i64,
+        limit: u64,
+    ) -> anyhow::Result<Vec<SessionStats>> {
+        self.sessions_of(uid, None, since, limit).await
+    }
+
+    async fn session(
+        &self,
+        uid: &str,
+        session: &str,
+    ) -> anyhow::Result<Option<SessionStats>> {
+        let mut sessions = self.sessions_of(uid, Some(session), 0, 1).await?;
+        Ok(sessions.pop())
+    }
+
+    async fn capture(&self, capture: &Capture) -> anyhow::Result<()> {
+        let deflate = |body: &str| {
+            miniz_oxide::deflate::compress_to_vec(body.as_bytes(), 6)
+        };
+        let response = capture.response.as_deref().unwrap_or("");
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        // Backends search text differently.
+        if DB::NAME == "SQLite" {
+            let (rowid,): (i64,) = sqlx::query_as(
+                "INSERT INTO captures
+                    (req_id, uid, request, response, is_truncated, time)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    RETURNING rowid",
+            )
+            .bind(&capture.req_id)
+            .bind(&capture.uid)
+            .bind(deflate(&capture.request))
+            .bind(capture.response.as_deref().map(deflate))
+            .bind(i64::from(capture.is_truncated))
+            .bind(capture.time)
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query(
+                "INSERT INTO captures_fts (rowid, request, response)
+                    VALUES ($1, $2, $3)",
+            )
+            .bind(rowid)
+            .bind(capture.request.as_str())
+            .bind(response)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO captures
+                    (req_id, uid, request, response, is_truncated, time,
+                    search)
+                    VALUES ($1, $2, $3, $4, $5, $6,
+                    to_tsvector('simple', $7) || to_tsvector('simple', $8))",
+            )
+            .bind(&capture.req_id)
+            .bind(&capture.uid)
+            .bind(deflate(&capture.request))
+            .bind(capture.response.as_deref().map(deflate))
+            .bind(i64::from(capture.is_truncated))
+            .bind(capture.time)
+            .bind(capture.request.as_str())
+            .bind(response)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn capture_search(
+        &self,
+        text: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<Capture>> {
+        let query = if DB::NAME == "SQLite" {
+            "SELECT req_id, uid, request, response, is_truncated, time
+                FROM captures
+                WHERE rowid IN (
+                    SELECT rowid FROM captures_fts WHERE captures_fts MATCH $1
+                )
+                ORDER BY time DESC
+                LIMIT $2"
+        } else {
+            "SELECT req_id, uid, request, response, is_truncated, time
+                FROM captures
+                WHERE search @@ phraseto_tsquery('simple', $1)
+                ORDER BY time DESC
+                LIMIT $2"
+        };
+        // A phrase, rather than FTS5's query syntax, which a stray quote
+        // or operator in the text would break.
+        let text = if DB::NAME == "SQLite" {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text.to_string()
+        };
+        let rows: Vec<CaptureRow> = sqlx::query_as(query)
+            .bind(text)
+            .bind(i64::try_from(limit)?)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(capture_from_row).collect()
+    }
+
+    async fn capture_get(
+        &self,
+        req_id: &str,
+    ) -> anyhow::Result<Option<Capture>> {
+        let row: Option<CaptureRow> = sqlx::query_as(
+            "SELECT req_id, uid, request, response, is_truncated, time
+                FROM captures
+                WHERE req_id = $1",
+        )
+        .bind(req_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(capture_from_row).transpose()
+    }
+
+    async fn experiment_stats(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<ArmStats>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            String,
+            String,
+            String,
+            i64,
+            i64,
+            i64,
+            i64,
+            f64,
+            i64,
+        )> = sqlx::query_as(
+            "SELECT
+                    experiment,
+                    arm,
+                    model,
+                    COUNT(*),
+                    CAST(SUM(
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost),
+                    CAST(SUM(duration_ms) AS BIGINT)
+                FROM request_logs
+                WHERE experiment IS NOT NULL AND arm IS NOT NULL
+                AND time >= $1
+                GROUP BY experiment, arm, model
+                ORDER BY experiment, arm, model",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    experiment,
+                    arm,
+                    model,
+                    requests,
+                    errors,
+                    input,
+                    output,
+                    cost,
+                    duration,
+                )| {
+                    #[allow(clippy::cast_precision_loss)]
+                    let avg_duration_ms =
+                        duration as f64 / requests.max(1) as f64;
+                    Ok(ArmStats {
+                        experiment,
+                        arm,
+                        model,
+                        requests: u64::try_from(requests)?,
+                        errors: u64::try_from(errors)?,
+                        input_tokens: u64::try_from(input)?,
+                        output_tokens: u64::try_from(output)?,
+                        cost,
+                        avg_duration_ms,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn batch_create(
+        &self,
+        batch: &Batch,
+        requests: &[String],
+    ) -> anyhow::Result<()> {
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        sqlx::query(
+            "INSERT INTO batches (
+                id,
+                uid,
+                role,
+                org,
+                max_tokens_per_day,
+                max_cost_per_day,
+                features,
+                endpoint,
+                status,
+                time_created,
+                time_finished
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        )
+        .bind(&batch.id)
+        .bind(&batch.uid)
+        .bind(&batch.role)
+        .bind(batch.org.as_deref())
+        .bind(
+            batch
+                .budget_overrides
+                .max_tokens_per_day
+                .map(i64::try_from)
+                .transpose()?,
+        )
+        .bind(batch.budget_overrides.max_cost_per_day)
+        .bind(serde_json::to_string(&batch.features)?)
+        .bind(&batch.endpoint)
+        .bind(batch.status.as_str())
+        .bind(batch.time_created)
+        .bind(batch.time_finished)
+        .execute(&mut *tx)
+        .await?;
+        for (index, request) in requests.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO batch_items (batch_id, idx, request)
+                    VALUES ($1, $2, $3)",
+            )
+            .bind(&batch.id)
+            .bind(i64::try_from(index)?)
+            .bind(request)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn batch_get(&self, id: &str) -> anyhow::Result<Option<Batch>> {
+        let row: Option<BatchRow> =
+            sqlx::query_as(&format!("{BATCH_SELECT} WHERE b.id = $1"))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        row.map(Batch::try_from).transpose()
+    }
+
+    async fn batch_list(&self, uid: &str) -> anyhow::Result<Vec<Batch>> {
+        let rows: Vec<BatchRow> = sqlx::query_as(&format!(
+            "{BATCH_SELECT} WHERE b.uid = $1 ORDER BY b.time_created DESC"
+        ))
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(Batch::try_from).collect()
+    }
+
+    async fn batch_items(&self, id: &str) -> anyhow::Result<Vec<BatchItem>> {
+        let rows: Vec<(i64, String, Option<i64>, Option<String>)> =
+            sqlx::query_as(
+                "SELECT idx, request, code, response FROM batch_items
+                    WHERE batch_id = $1
+                    ORDER BY idx",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+        let items = rows
+            .into_iter()
+            .map(|(index, request, code, response)| BatchItem {
+                index,
+                request,
+                code,
+                response,
+            })
+            .collect();
+        Ok(items)
+    }
+
+    async fn batch_cancel(&self, id: &str) -> anyhow::Result<bool> {
+        let cancelled: Option<(String,)> = sqlx::query_as(
+            "UPDATE batches SET status = $1, time_finished = $2
+                WHERE id = $3 AND status IN ($4, $5)
+                RETURNING id",
+        )
+        .bind(BatchStatus::Cancelled.as_str())
+        .bind(unix_now()?)
+        .bind(id)
+        .bind(BatchStatus::Queued.as_str())
+        .bind(BatchStatus::InProgress.as_str())
+        .fetch_optional(&self.writer)
+        .await?;
+        Ok(cancelled.is_some())
+    }
+
+    async fn batch_pending(&self) -> anyhow::Result<Vec<Batch>> {
+        let rows: Vec<BatchRow> = sqlx::query_as(&format!(
+            "{BATCH_SELECT} WHERE b.status IN ($1, $2)
+                ORDER BY b.time_created"
+        ))
+        .bind(BatchStatus::Queued.as_str())
+        .bind(BatchStatus::InProgress.as_str())
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(Batch::try_from).collect()
+    }
+
+    async fn batch_item_claim(
+        &self,
+        id: &str,
+        now: i64,
+        until: i64,
+    ) -> anyhow::Result<Option<BatchItem>> {
+        // Claimed in the one statement, with the claim checked again in the
+        // outer one, for Postgres to check it of the row as another
+        // instance may have just claimed it.
+        let row: Option<(i64, String)> = sqlx::query_as(
+            "UPDATE batch_items SET claimed_until = $3
+                WHERE batch_id = $1
+                    AND code IS NULL
+                    AND (claimed_until IS NULL OR claimed_until <= $2)
+                    AND idx = (
+                        SELECT idx FROM batch_items
+                            WHERE batch_id = $1
+                                AND code IS NULL
+                                AND (
+                                    claimed_until IS NULL
+                                    OR claimed_until <= $2
+                                )
+                            ORDER BY idx
+                            LIMIT 1
+                    )
+                RETURNING idx, request",
+        )
+        .bind(id)
+        .bind(now)
+        .bind(until)
+        .fetch_optional(&self.writer)
+        .await?;
+        Ok(row.map(|(index, request)| BatchItem {
+            index,
+            request,
+            code: None,
+            response: None,
+        }))
+    }
+
+    async fn batch_item_release(
+        &self,
+        id: &str,
+        index: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE batch_items SET claimed_until = NULL
+                WHERE batch_id = $1 AND idx = $2",
+        )
+        .bind(id)
+        .bind(index)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn batch_item_complete(
+        &self,
+        id: &str,
+        index: i64,
+        code: i64,
+        response: &str,
+    ) -> anyhow::Result<bool> {
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let pending: Option<(String,)> = sqlx::query_as(
+            "SELECT id FROM batches WHERE id = $1 AND status IN ($2, $3)",
+        )
+        .bind(id)
+        .bind(BatchStatus::Queued.as_str())
+        .bind(BatchStatus::InProgress.as_str())
+        .fetch_optional(&mut *tx)
+        .await?;
+        if pending.is_none() {
+            return Ok(false);
+        }
+        sqlx::query(
+            "UPDATE batch_items SET code = $1, response = $2
+                WHERE batch_id = $3 AND idx = $4",
+        )
+        .bind(code)
+        .bind(response)
+        .bind(id)
+        .bind(index)
+        .execute(&mut *tx)
+        .await?;
+        let (left,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM batch_items
+                WHERE batch_id = $1 AND code IS NULL",
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        let (status, time_finished) = if left == 0 {
+            (BatchStatus::Completed, Some(unix_now()?))
+        } else {
+            (BatchStatus::InProgress, None)
+        };
+        sqlx::query(
+            "UPDATE batches SET status = $1, time_finished = $2
+                WHERE id = $3",
+        )
+        .bind(status.as_str())
+        .bind(time_finished)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(left > 0)
+    }
+
+    async fn tag_stats(
+        &self,
+        uid: Option<&str>,
+        tag: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<TagStats>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            String,
+            String,
+            i64,
+            i64,
+            i64,
+            i64,
+            f64,
+            i64,
+        )> = sqlx::query_as(
+            "SELECT
+                        request_tags.tag,
+                        request_tags.value,
+                        COUNT(*),
+                        CAST(SUM(
+                            CASE WHEN request_logs.status >= 400
+                                AND COALESCE(request_logs.error_class, '')
+                                    <> 'budget'
+                            THEN 1 ELSE 0 END
+                        ) AS BIGINT),
+                        CAST(SUM(request_logs.input_tokens) AS BIGINT),
+                        CAST(SUM(request_logs.output_tokens) AS BIGINT),
+                        SUM(request_logs.cost),
+                        CAST(SUM(request_logs.duration_ms) AS BIGINT)
+                    FROM request_tags
+                    JOIN request_logs
+                    ON request_logs.req_id = request_tags.req_id
+                    WHERE request_tags.time >= $1
+                    AND (CAST($2 AS TEXT) IS NULL OR request_logs.uid = $2)
+                    AND (CAST($3 AS TEXT) IS NULL OR request_tags.tag = $3)
+                    GROUP BY request_tags.tag, request_tags.value
+                    ORDER BY SUM(request_logs.cost) DESC, COUNT(*) DESC",
+        )
+        .bind(since)
+        .bind(uid)
+        .bind(tag)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    tag,
+                    value,
+                    requests,
+                    errors,
+                    input,
+                    output,
+                    cost,
+                    duration,
+                )| {
+                    #[allow(clippy::cast_precision_loss)]
+                    let avg_duration_ms =
+                        duration as f64 / requests.max(1) as f64;
+                    Ok(TagStats {
+                        tag,
+                        value,
+                        requests: u64::try_from(requests)?,
+                        errors: u64::try_from(errors)?,
+                        input_tokens: u64::try_from(input)?,
+                        output_tokens: u64::try_from(output)?,
+                        cost,
+                        avg_duration_ms,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn shadow_log(&self, log: &ShadowLog) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO shadow_logs (
+                req_id,
+                model,
+                status,
+                input_tokens,
+                output_tokens,
+                cost,
+                duration_ms,
+                time
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT(req_id) DO NOTHING",
+        )
+        .bind(&log.req_id)
+        .bind(&log.model)
+        .bind(log.status)
+        .bind(log.input_tokens)
+        .bind(log.output_tokens)
+        .bind(log.cost)
+        .bind(log.duration_ms)
+        .bind(log.time)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn usage_totals(
+        &self,
+        uid: Option<&str>,
+        since: i64,
+    ) -> anyhow::Result<Vec<UsageTotals>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            Option<String>,
+            String,
+            String,
+            String,
+            Option<String>,
+            i64,
+            f64,
+            f64,
+            f64,
+        )> = sqlx::query_as(
+            "SELECT provider, model, endpoint, unit, detail, COUNT(*),
+                        SUM(input), SUM(output), SUM(cost)
+                    FROM request_usage
+                    WHERE time >= $1
+                        AND (CAST($2 AS TEXT) IS NULL OR uid = $2)
+                    GROUP BY provider, model, endpoint, unit, detail
+                    ORDER BY provider, model, endpoint, unit, detail",
+        )
+        .bind(since)
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    provider,
+                    model,
+                    endpoint,
+                    unit,
+                    detail,
+                    requests,
+                    input,
+                    output,
+                    cost,
+                )| {
+                    Ok(UsageTotals {
+                        provider,
+                        model,
+                        endpoint,
+                        unit: unit.parse()?,
+                        detail,
+                        requests: u64::try_from(requests)?,
+                        input,
+                        output,
+                        cost,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn shadow_compare(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<ShadowComparison>> {
+        // Errors, input and output tokens, cost and duration of a side.
+        let side = |t: &str| {
+            format!(
+                "CAST(SUM(CASE WHEN {t}.status >= 400 THEN 1 ELSE 0 END)
+                    AS BIGINT),
+                CAST(SUM({t}.input_tokens) AS BIGINT),
+                CAST(SUM({t}.output_tokens) AS BIGINT),
+                SUM({t}.cost),
+                CAST(SUM({t}.duration_ms) AS BIGINT)"
+            )
+        };
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            String,
+            String,
+            i64,
+            i64,
+            i64,
+            i64,
+            f64,
+            i64,
+            i64,
+            i64,
+            i64,
+            f64,
+            i64,
+        )> = sqlx::query_as(&format!(
+            // Of the request logs, the one nearest in time, since they are
+            // suffixed when a client reuses a request ID, while the shadow
+            // is logged under it as is.
+            "SELECT l.model, s.model, COUNT(*), {}, {}
+                FROM shadow_logs AS s
+                JOIN request_logs AS l ON l.req_id = (
+                    SELECT r.req_id FROM request_logs AS r
+                    WHERE r.time BETWEEN s.time - 3600 AND s.time + 3600
+                    AND (
+                        r.req_id = s.req_id
+                        OR r.req_id LIKE s.req_id || '-%'
+                    )
+                    ORDER BY ABS(r.time - s.time)
+                    LIMIT 1
+                )
+                WHERE s.time >= $1
+                GROUP BY l.model, s.model
+                ORDER BY l.model, s.model",
+            side("l"),
+            side("s"),
+        ))
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        let side = |requests: i64,
+                    errors: i64,
+                    input: i64,
+                    output: i64,
+                    cost: f64,
+                    duration: i64| {
+            #[allow(clippy::cast_precision_loss)]
+            let avg_duration_ms = duration as f64 / requests.max(1) as f64;
+            anyhow::Ok(ShadowSide {
+                errors: u64::try_from(errors)?,
+                input_tokens: u64::try_from(input)?,
+                output_tokens: u64::try_from(output)?,
+                cost,
+                avg_duration_ms,
+            })
+        };
+        rows.into_iter()
+            .map(
+                |(
+                    model,
+                    shadow_model,
+                    requests,
+                    p_errors,
+                    p_input,
+                    p_output,
+                    p_cost,
+                    p_duration,
+                    s_errors,
+                    s_input,
+                    s_output,
+                    s_cost,
+                    s_duration,
+                )| {
+                    Ok(ShadowComparison {
+                        model,
+                        shadow_model,
+                        requests: u64::try_from(requests)?,
+                        primary: side(
+                            requests, p_errors, p_input, p_output, p_cost,
+                            p_duration,
+                        )?,
+                        shadow: side(
+                            requests, s_errors, s_input, s_output, s_cost,
+                            s_duration,
+                        )?,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn leaderboard(
+        &self,
+        date: &str,
+        rank: Rank,
+        limit: u64,
+    ) -> anyhow::Result<Vec<LeaderboardEntry>> {
+        let (from, to) = period::bounds(date)?;
+        // Not a bind parameter, since it is a column.
+        let order = match rank {
+            Rank::Tokens => "tokens DESC, requests DESC",
+            Rank::Requests => "requests DESC, tokens DESC",
+        };
+        let rows: Vec<(String, i64, i64)> = sqlx::query_as(&format!(
+            "SELECT
+                    uid,
+                    COUNT(*) AS requests,
+                    CAST(SUM(input_tokens + output_tokens) AS BIGINT)
+                        AS tokens
+                FROM request_logs
+                WHERE time >= $1 AND time < $2
+                GROUP BY uid
+                ORDER BY {order}, uid
+                LIMIT $3"
+        ))
+        .bind(from)
+        .bind(to)
+        .bind(i64::try_from(limit)?)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(uid, requests, tokens)| {
+                Ok(LeaderboardEntry {
+                    uid,
+                    requests: u64::try_from(requests)?,
+                    tokens: u64::try_from(tokens)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn token_estimate_record(
+        &self,
+        req_id: &str,
+        model: &str,
+        estimate: u64,
+        time: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO token_estimates (req_id, model, estimate, time)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(req_id) DO NOTHING",
+        )
+        .bind(req_id)
+        .bind(model)
+        .bind(i64::try_from(estimate)?)
+        .bind(time)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn token_estimate_totals(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<Calibration>> {
+        // Requests which fell back to another model are left out, since
+        // the estimate was for the original one.
+        let rows: Vec<(String, i64, i64, i64)> = sqlx::query_as(
+            "SELECT
+                    e.model,
+                    COUNT(*),
+                    CAST(SUM(e.estimate) AS BIGINT),
+                    CAST(SUM(l.input_tokens) AS BIGINT)
+                FROM token_estimates AS e
+                JOIN request_logs AS l ON l.req_id = e.req_id
+                WHERE e.time >= $1
+                    AND l.status = 200
+                    AND l.model = e.model
+                GROUP BY e.model
+                ORDER BY e.model",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(model, samples, estimated, actual)| {
+                #[allow(clippy::cast_precision_loss)]
+                let factor = actual as f64 / (estimated.max(1) as f64);
+                Ok(Calibration {
+                    model,
+                    samples: u64::try_from(samples)?,
+                    estimated: u64::try_from(estimated)?,
+                    actual: u64::try_from(actual)?,
+                    factor,
+                })
+            })
+            .collect()
+    }
+
+    async fn calibration_set(
+        &self,
+        calibration: &Calibration,
+        time: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO token_calibration (model, factor, samples, time)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(model) DO UPDATE SET
+                factor = excluded.factor,
+                samples = excluded.samples,
+                time = excluded.time",
+        )
+        .bind(&calibration.model)
+        .bind(calibration.factor)
+        .bind(i64::try_from(calibration.samples)?)
+        .bind(time)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn calibration_get(&self) -> anyhow::Result<BTreeMap<String, f64>> {
+        let rows: Vec<(String, f64)> =
+            sqlx::query_as("SELECT model, factor FROM token_calibration")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn idempotency_claim(
+        &self,
+        uid: &str,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        expires: i64,
+    ) -> anyhow::Result<Idempotency> {
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        sqlx::query("DELETE FROM idempotency_keys WHERE expires <= $1")
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        let inserted: Option<(String,)> = sqlx::query_as(
+            "INSERT INTO idempotency_keys (uid, key, fingerprint, expires)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(uid, key) DO NOTHING
+            RETURNING key",
+        )
+        .bind(uid)
+        .bind(key)
+        .bind(fingerprint)
+        .bind(expires)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if inserted.is_some() {
+            tx.commit().await?;
+            return Ok(Idempotency::Claimed);
+        }
+        let (claimed_fingerprint, status, body): (
+            String,
+            Option<i64>,
+            Option<String>,
+        ) = sqlx::query_as(
+            "SELECT fingerprint, status, body
+            FROM idempotency_keys
+            WHERE uid = $1 AND key = $2",
+        )
+        .bind(uid)
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        let idempotency = match (status, body) {
+            _ if claimed_fingerprint != fingerprint => Idempotency::Mismatch,
+            (Some(status), Some(body)) => Idempotency::Done {
+                status: u16::try_from(status)?,
+                body,
+            },
+            _ => Idempotency::InProgress,
+        };
+        Ok(idempotency)
+    }
+
+    async fn idempotency_complete(
+        &self,
+        uid: &str,
+        key: &str,
+        status: u16,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE idempotency_keys
+            SET status = $3, body = $4
+            WHERE uid = $1 AND key = $2",
+        )
+        .bind(uid)
+        .bind(key)
+        .bind(i64::from(status))
+        .bind(body)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn idempotency_release(
+        &self,
+        uid: &str,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys WHERE uid = $1 AND key = $2",
+        )
+        .bind(uid)
+        .bind(key)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn hit(&self, uid: &str) -> anyhow::Result<(u64, Duration)> {
+        let now = SystemTime::now();
+        let curr = i64::try_from(now.duration_since(UNIX_EPOCH)?.as_secs())?;
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let prev_opt: Option<HitsRow> =
+            sqlx::query_as("SELECT * FROM hits WHERE uid = $1")
+                .bind(uid)
+                .fetch_optional(&mut *tx)
+                .await?;
+        let (prev_count, prev_time) = match prev_opt {
+            None => {
+                sqlx::query(
+                    "INSERT INTO hits (uid, count_of_all, time_of_last)
+                        VALUES ($1, 1, $2)",
+                )
+                .bind(uid)
+                .bind(curr)
+                .execute(&mut *tx)
+                .await?;
+                (0, UNIX_EPOCH)
+            }
+            Some(HitsRow {
+                uid,
+                count_of_all,
+                time_of_last: prev,
+            }) => {
+                sqlx::query(
+                    "UPDATE hits SET
+                        count_of_all = count_of_all + 1,
+                        time_of_last = $1
+                        WHERE uid = $2",
+                )
+                .bind(curr)
+                .bind(uid)
+                .execute(&mut *tx)
+                .await?;
+                let prev = u64::try_from(prev)?;
+                (
+                    u64::try_from(count_of_all)?,
+                    UNIX_EPOCH + Duration::from_secs(prev),
+                )
+            }
+        };
+        tx.commit().await?;
+        let elapsed_since_prev_hit = now.duration_since(prev_time)?;
+        Ok((prev_count.saturating_add(1), elapsed_since_prev_hit))
+    }
+
+    async fn budget_reserve(
+        &self,
+        uid: &str,
+        org: Option<&str>,
+        role: &str,
+        overrides: BudgetOverrides,
+        model: &str,
+        requested: Amount,
+    ) -> anyhow::Result<Result<Reservation, Exceeded>> {
+        let conf = conf::global();
+        let multiplier = auth::budget_multiplier(&conf, role);
+        let scale = |max: u64| scale(max, multiplier);
+        let date = period::current();
+        let limit = self.max_tokens(uid, role, overrides, &date).await?;
+        let cap = i64::try_from(limit.cap).unwrap_or(i64::MAX);
+        let max_tokens = cap
+            .saturating_add(limit.credits)
+            .saturating_add(i64::try_from(overrides.overage_tokens)?);
+        let max_tokens_for_model = match conf.limits.per_model.get(model) {
+            None => i64::MAX,
+            Some(limits) => i64::try_from(scale(limits.max_tokens_per_day))?,
+        };
+        let max_cost = overrides
+            .max_cost_per_day
+            .or_else(|| conf.max_cost_per_day.map(|max| max * multiplier))
+            .unwrap_or(f64::MAX);
+        let deltas = match &self.write_behind {
+            None => Deltas::default(),
+            Some(write_behind) => {
+                write_behind.deltas(uid, model, org, &date)?
+            }
+        };
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            let reservation = Reservation {
+                uid: uid.to_string(),
+                org: org.map(str::to_string),
+                model: model.to_string(),
+                date,
+                amount: requested,
+            };
+            let max = shared::Totals {
+                tokens: max_tokens,
+                tokens_for_model: max_tokens_for_model,
+                cost: max_cost,
+            };
+            return self
+                .budget_reserve_shared(redis, reservation, max, cap, deltas)
+                .await;
+        }
+        if let Some(cache) = &self.budget_cache {
+            let reservation = Reservation {
+                uid: uid.to_string(),
+                org: org.map(str::to_string),
+                model: model.to_string(),
+                date,
+                amount: requested,
+            };
+            let max = Maxes {
+                tokens: max_tokens,
+                cap,
+                tokens_for_model: max_tokens_for_model,
+                cost: max_cost,
+            };
+            // Cached usage already includes them, only org usage doesn't.
+            let deltas = Deltas {
+                org_tokens: deltas.org_tokens,
+                org_cost: deltas.org_cost,
+                ..Deltas::default()
+            };
+            return self
+                .budget_reserve_cached(cache, reservation, max, deltas)
+                .await;
+        }
+        // Totals in the database are off by the queued settlements.
+        let max_tokens = max_tokens.saturating_sub(deltas.tokens);
+        let max_tokens_for_model =
+            max_tokens_for_model.saturating_sub(deltas.tokens_for_model);
+        let max_cost = max_cost - deltas.cost;
+        let tokens = i64::try_from(requested.tokens)?;
+        if tokens > max_tokens_for_model {
+            return Ok(Err(Exceeded::Model));
+        }
+        if tokens > max_tokens {
+            return Ok(Err(Exceeded::Tokens));
+        }
+        if requested.cost > max_cost {
+            return Ok(Err(Exceeded::Cost));
+        }
+        // Conditional upserts, so concurrent requests can't all pass a check
+        // made before any of them was counted. All in one transaction, so
+        // we either count against all budgets or none.
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let total_tokens_for_model_opt: Option<(i64,)> = sqlx::query_as(
+            "INSERT INTO tokens_by_model (uid, model, date, total)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(uid, model, date) DO UPDATE SET
+                total = tokens_by_model.total + $4
+                WHERE tokens_by_model.total + $4 <= $5
+                RETURNING total",
+        )
+        .bind(uid)
+        .bind(model)
+        .bind(&date)
+        .bind(tokens)
+        .bind(max_tokens_for_model)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if total_tokens_for_model_opt.is_none() {
+            tx.rollback().await?;
+            return Ok(Err(Exceeded::Model));
+        }
+        let total_tokens_opt: Option<(i64,)> = sqlx::query_as(
+            "INSERT INTO tokens (uid, date, total, cap)
+                VALUES ($1, $2, $3, $5)
+                ON CONFLICT(uid, date) DO UPDATE SET
+                total = tokens.total + $3,
+                cap = $5
+                WHERE tokens.total + $3 <= $4
+                RETURNING total",
+        )
+        .bind(uid)
+        .bind(&date)
+        .bind(tokens)
+        .bind(max_tokens)
+        .bind(cap)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if total_tokens_opt.is_none() {
+            tx.rollback().await?;
+            return Ok(Err(Exceeded::Tokens));
+        }
+        let total_cost_opt: Option<(f64,)> = sqlx::query_as(
+            "INSERT INTO costs (uid, date, total)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(uid, date) DO UPDATE SET
+                total = costs.total + $3
+                WHERE costs.total + $3 <= $4
+                RETURNING total",
+        )
+        .bind(uid)
+        .bind(&date)
+        .bind(requested.cost)
+        .bind(max_cost)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if total_cost_opt.is_none() {
+            tx.rollback().await?;
+            return Ok(Err(Exceeded::Cost));
+        }
+        if let Some(org) = org {
+            if !self
+                .org_reserve(&mut tx, org, uid, &date, requested, deltas)
+                .await?
+            {
+                tx.rollback().await?;
+                return Ok(Err(Exceeded::Org));
+            }
+        }
+        tx.commit().await?;
+        Ok(Ok(Reservation {
+            uid: uid.to_string(),
+            org: org.map(str::to_string),
+            model: model.to_string(),
+            date,
+            amount: requested,
+        }))
+    }
+
+    async fn token_budget(
+        &self,
+        uid: &str,
+        role: &str,
+        overrides: BudgetOverrides,
+    ) -> anyhow::Result<TokenBudget> {
+        let date = period::current();
+        let limit =
+            self.max_tokens(uid, role, overrides, &date).await?.total();
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            if let Some(used) = redis.tokens(uid, &date).await? {
+                let used = u64::try_from(used.max(0))?;
+                return Ok(TokenBudget { limit, used });
+            }
+        }
+        if let Some(cache) = &self.budget_cache {
+            let entry = cache.entry(uid, &date);
+            let mut budget = entry.lock().await;
+            let usage = self.cached_usage(&mut budget, uid, &date).await?;
+            let used = u64::try_from(usage.tokens.max(0))?;
+            return Ok(TokenBudget { limit, used });
+        }
+        let used: Option<(i64,)> = sqlx::query_as(
+            "SELECT total FROM tokens WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(&date)
+        .fetch_optional(&self.pool)
+        .await?;
+        let mut used = used.map_or(0, |(used,)| used);
+        if let Some(write_behind) = &self.write_behind {
+            used += write_behind.deltas(uid, "", None, &date)?.tokens;
+        }
+        let used = u64::try_from(used.max(0))?;
+        Ok(TokenBudget { limit, used })
+    }
+
+    async fn budget_settle(
+        &self,
+        reservation: &Reservation,
+        used: Amount,
+    ) -> anyhow::Result<()> {
+        if let Some(cache) = &self.budget_cache {
+            cache.settle(reservation, used).await?;
+        }
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            redis.settle(reservation, used).await?;
+        }
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.push(Write::Settle(reservation.clone(), used));
+            return Ok(());
+        }
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        Self::settle(&mut tx, reservation, used).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn log_request(&self, log: &RequestLog) -> anyhow::Result<()> {
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.push(Write::Log(log.clone()));
+            return Ok(());
+        }
+        // With its tags and usage, or none of them.
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        Self::log_request_insert(&mut tx, log).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let Some(write_behind) = &self.write_behind else {
+            return Ok(());
+        };
+        let _flush = write_behind.flush.lock().await;
+        let writes = {
+            let mut pending = write_behind.pending();
+            pending.flushing = std::mem::take(&mut pending.queued);
+            pending.flushing.clone()
+        };
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let result = self.write_all(&writes).await;
+        let mut pending = write_behind.pending();
+        let flushing = std::mem::take(&mut pending.flushing);
+        match &result {
+            Ok(()) => {
+                metrics::counter!("raskol_write_behind_flushed_total")
+                    .increment(u64::try_from(writes.len())?);
+            }
+            Err(_) => write_behind.requeue(&mut pending, flushing),
+        }
+        result
+    }
+
+    async fn rate_acquire(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Option<ratelimit::Window>> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.rate_acquire(uid, windows, now).await;
+        }
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        for window in windows {
+            let period = window.period.as_secs().max(1);
+            let into_window = now.as_secs() % period;
+            let start = now.as_secs() - into_window;
+            let prev_start = start.saturating_sub(period);
+            let (period, start, prev_start) = (
+                i64::try_from(period)?,
+                i64::try_from(start)?,
+                i64::try_from(prev_start)?,
+            );
+            sqlx::query(
+                "DELETE FROM rate_windows
+                    WHERE uid = $1 AND period = $2 AND start < $3",
+            )
+            .bind(uid)
+            .bind(period)
+            .bind(prev_start)
+            .execute(&mut *tx)
+            .await?;
+            let prev_count: Option<(i64,)> = sqlx::query_as(
+                "SELECT count FROM rate_windows
+                    WHERE uid = $1 AND period = $2 AND start = $3",
+            )
+            .bind(uid)
+            .bind(period)
+            .bind(prev_start)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let prev_count =
+                u64::try_from(prev_count.map_or(0, |(count,)| count))?;
+            let capacity = window.capacity(
+                prev_count,
+                Duration::from_secs(into_window)
+                    + Duration::from_nanos(u64::from(now.subsec_nanos())),
+            );
+            let capacity = i64::try_from(capacity)?;
+            let count_opt: Option<(i64,)> = if capacity < 1 {
+                None
+            } else {
+                sqlx::query_as(
+                    "INSERT INTO rate_windows (uid, period, start, count)
+                        VALUES ($1, $2, $3, 1)
+                        ON CONFLICT(uid, period, start) DO UPDATE SET
+                        count = rate_windows.count + 1
+                        WHERE rate_windows.count + 1 <= $4
+                        RETURNING count",
+                )
+                .bind(uid)
+                .bind(period)
+                .bind(start)
+                .bind(capacity)
+                .fetch_optional(&mut *tx)
+                .await?
+            };
+            if count_opt.is_none() {
+                tx.rollback().await?;
+                return Ok(Some(*window));
+            }
+        }
+        tx.commit().await?;
+        Ok(None)
+    }
+
+    async fn rate_counts(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Vec<(u64, u64)>> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.rate_counts(uid, windows, now).await;
+        }
+        let mut counts = Vec::with_capacity(windows.len());
+        for window in windows {
+            let period = window.period.as_secs().max(1);
+            let start = now.as_secs() - now.as_secs() % period;
+            let prev_start = start.saturating_sub(period);
+            let rows: Vec<(i64, i64)> = sqlx::query_as(
+                "SELECT start, count FROM rate_windows
+                    WHERE uid = $1 AND period = $2 AND start >= $3",
+            )
+            .bind(uid)
+            .bind(i64::try_from(period)?)
+            .bind(i64::try_from(prev_start)?)
+            .fetch_all(&self.pool)
+            .await?;
+            let (mut curr, mut prev) = (0, 0);
+            for (row_start, count) in rows {
+                let count = u64::try_from(count.max(0))?;
+                match u64::try_from(row_start)? {
+                    row_start if row_start == start => curr = count,
+                    row_start if row_start == prev_start => prev = count,
+                    _ => {}
+                }
+            }
+            counts.push((curr, prev));
+        }
+        Ok(counts)
+    }
+
+    async fn rate_take(
+        &self,
+        uid: &str,
+        role: &str,
+        bucket: ratelimit::Bucket,
+        now: Duration,
+    ) -> anyhow::Result<Option<Duration>> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.rate_take(uid, role, bucket, now).await;
+        }
+        let now_ms = i64::try_from(now.as_millis())?;
+        // XXX On Postgres, concurrent takes may read the same tokens, letting
+        //     one more request through now and then.
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let row: Option<(f64, i64)> = sqlx::query_as(
+            "SELECT tokens, time_ms FROM rate_buckets
+                WHERE uid = $1 AND role = $2",
+        )
+        .bind(uid)
+        .bind(role)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let (tokens, elapsed) = match row {
+            None => (None, Duration::ZERO),
+            Some((tokens, time_ms)) => {
+                let elapsed = u64::try_from(now_ms - time_ms).unwrap_or(0);
+                (Some(tokens), Duration::from_millis(elapsed))
+            }
+        };
+        match bucket.take(tokens, elapsed) {
+            Ok(tokens) => {
+                let ttl_ms =
+                    i64::try_from(bucket.ttl())?.saturating_mul(1000);
+                sqlx::query(
+                    "INSERT INTO rate_buckets
+                        (uid, role, tokens, time_ms, expires_ms)
+                        VALUES ($1, $2, $3, $4, $5)
+                        ON CONFLICT(uid, role) DO UPDATE SET
+                        tokens = $3, time_ms = $4, expires_ms = $5",
+                )
+                .bind(uid)
+                .bind(role)
+                .bind(tokens)
+                .bind(now_ms)
+                .bind(now_ms.saturating_add(ttl_ms))
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(None)
+            }
+            Err(retry_after) => {
+                tx.rollback().await?;
+                Ok(Some(retry_after))
+            }
+        }
+    }
+
+    async fn api_key_create(
+        &self,
+        uid: &str,
+        role: &str,
+    ) -> anyhow::Result<(ApiKey, String)> {
+        let key = auth::api_key_generate();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let api_key = ApiKey {
+            id: cuid2::create_id(),
+            uid: uid.to_string(),
+            role: role.to_string(),
+            key_prefix: key.chars().take(API_KEY_DISPLAY_LEN).collect(),
+            time_created: i64::try_from(now)?,
+            time_revoked: None,
+        };
+        sqlx::query(
+            "INSERT INTO api_keys
+                (id, uid, role, key_hash, key_prefix, time_created)
+                VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&api_key.id)
+        .bind(&api_key.uid)
+        .bind(&api_key.role)
+        .bind(auth::api_key_hash(&key))
+        .bind(&api_key.key_prefix)
+        .bind(api_key.time_created)
+        .execute(&self.writer)
+        .await?;
+        Ok((api_key, key))
+    }
+
+    async fn api_key_revoke(
+        &self,
+        uid: &str,
+        id: Option<&str>,
+    ) -> anyhow::Result<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let now = i64::try_from(now)?;
+        // Counting RETURNING rows, since rows_affected isn't backend-agnostic.
+        let revoked: Vec<(String,)> = sqlx::query_as(
+            "UPDATE api_keys SET time_revoked = $1
+                WHERE uid = $2
+                AND (CAST($3 AS TEXT) IS NULL OR id = $3)
+                AND time_revoked IS NULL
+                RETURNING id",
+        )
+        .bind(now)
+        .bind(uid)
+        .bind(id)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(u64::try_from(revoked.len())?)
+    }
+
+    async fn api_key_list(&self, uid: &str) -> anyhow::Result<Vec<ApiKey>> {
+        let keys: Vec<ApiKey> = sqlx::query_as(
+            "SELECT id, uid, role, key_prefix, time_created, time_revoked
+                FROM api_keys
+                WHERE uid = $1
+                ORDER BY time_created",
+        )
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    async fn api_key_lookup(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<ApiKey>> {
+        let key_opt: Option<ApiKey> = sqlx::query_as(
+            "SELECT id, uid, role, key_prefix, time_created, time_revoked
+                FROM api_keys
+                WHERE key_hash = $1 AND time_revoked IS NULL",
+        )
+        .bind(auth::api_key_hash(key))
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(key_opt)
+    }
+
+    async fn service_key_create(
+        &self,
+        service: &str,
+        uid: &str,
+        role: &str,
+    ) -> anyhow::Result<ServiceKey> {
+        let key = ServiceKey {
+            service: service.to_string(),
+            uid: uid.to_string(),
+            role: role.to_string(),
+            secret: signing::secret_generate(),
+            time_created: unix_now()?,
+            time_revoked: None,
+        };
+        sqlx::query(
+            "INSERT INTO service_keys
+                (service, uid, role, secret, time_created)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT(service) DO UPDATE SET
+                    uid = excluded.uid,
+                    role = excluded.role,
+                    secret = excluded.secret,
+                    time_created = excluded.time_created,
+                    time_revoked = NULL",
+        )
+        .bind(&key.service)
+        .bind(&key.uid)
+        .bind(&key.role)
+        .bind(&key.secret)
+        .bind(key.time_created)
+        .execute(&self.writer)
+        .await?;
+        Ok(key)
+    }
+
+    async fn service_key_revoke(
+        &self,
+        service: &str,
+    ) -> anyhow::Result<bool> {
+        let revoked: Option<(String,)> = sqlx::query_as(
+            "UPDATE service_keys SET time_revoked = $1
+                WHERE service = $2 AND time_revoked IS NULL
+                RETURNING service",
+        )
+        .bind(unix_now()?)
+        .bind(service)
+        .fetch_optional(&self.writer)
+        .await?;
+        Ok(revoked.is_some())
+    }
+
+    async fn service_key_list(&self) -> anyhow::Result<Vec<ServiceKey>> {
+        let keys: Vec<ServiceKey> = sqlx::query_as(
+            "SELECT service, uid, role, secret, time_created, time_revoked
+                FROM service_keys
+                ORDER BY service",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    async fn service_key_get(
+        &self,
+        service: &str,
+    ) -> anyhow::Result<Option<ServiceKey>> {
+        let key: Option<ServiceKey> = sqlx::query_as(
+            "SELECT service, uid, role, secret, time_created, time_revoked
+                FROM service_keys
+                WHERE service = $1 AND time_revoked IS NULL",
+        )
+        .bind(service)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    async fn signing_nonce_claim(
+        &self,
+        service: &str,
+        nonce: &str,
+        now: i64,
+        expires: i64,
+    ) -> anyhow::Result<bool> {
+        // Expired ones, not yet forgotten, are as good as unseen.
+        let inserted: Option<(String,)> = sqlx::query_as(
+            "INSERT INTO signing_nonces (service, nonce, expires)
+            VALUES ($1, $2, $3)
+            ON CONFLICT(service, nonce) DO UPDATE SET expires = $3
+                WHERE signing_nonces.expires <= $4
+            RETURNING nonce",
+        )
+        .bind(service)
+        .bind(nonce)
+        .bind(expires)
+        .bind(now)
+        .fetch_optional(&self.writer)
+        .await?;
+        Ok(inserted.is_some())
+    }
+
+    async fn rate_buckets_expire(
+        &self,
+        now: Duration,
+    ) -> anyhow::Result<u64> {
+        let expired: Vec<(i64,)> = sqlx::query_as(
+            "DELETE FROM rate_buckets WHERE expires_ms <= $1
+                RETURNING expires_ms",
+        )
+        .bind(i64::try_from(now.as_millis())?)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(u64::try_from(expired.len())?)
+    }
+
+    async fn signing_nonces_expire(&self, now: i64) -> anyhow::Result<u64> {
+        let expired: Vec<(i64,)> = sqlx::query_as(
+            "DELETE FROM signing_nonces WHERE expires <= $1
+                RETURNING expires",
+        )
+        .bind(now)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(u64::try_from(expired.len())?)
+    }
+
+    async fn deprecated_usage_add(
+        &self,
+        uid: &str,
+        kind: &str,
+        name: &str,
+        time: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO deprecated_usage
+                (uid, kind, name, requests, first_used, last_used)
+            VALUES ($1, $2, $3, 1, $4, $4)
+            ON CONFLICT(uid, kind, name) DO UPDATE SET
+                requests = deprecated_usage.requests + 1,
+                last_used = excluded.last_used",
+        )
+        .bind(uid)
+        .bind(kind)
+        .bind(name)
+        .bind(time)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn deprecated_usage(
+        &self,
+        since: i64,
+    ) -> anyhow::Result<Vec<DeprecatedUsage>> {
+        let usage: Vec<DeprecatedUsage> = sqlx::query_as(
+            "SELECT uid, kind, name, requests, first_used, last_used
+                FROM deprecated_usage
+                WHERE last_used >= $1
+                ORDER BY kind, name, requests DESC, uid",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn jwt_revoke(&self, jti: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO jwt_revocations (jti, time_revoked)
+                VALUES ($1, $2)
+                ON CONFLICT(jti) DO NOTHING",
+        )
+        .bind(jti)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn jwt_mint_record(&self, mint: &JwtMint) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO jwt_mints (
+                    jti,
+                    uid,
+                    role,
+                    org,
+                    max_tokens_per_day,
+                    max_cost_per_day,
+                    minted_by,
+                    time,
+                    exp
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(&mint.jti)
+        .bind(&mint.uid)
+        .bind(&mint.role)
+        .bind(mint.org.as_deref())
+        .bind(mint.max_tokens_per_day)
+        .bind(mint.max_cost_per_day)
+        .bind(&mint.minted_by)
+        .bind(mint.time)
+        .bind(mint.exp)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn jwt_mints(
+        &self,
+        org: Option<&str>,
+        limit: u64,
+    ) -> anyhow::Result<Vec<JwtMint>> {
+        let mints: Vec<JwtMint> = sqlx::query_as(&format!(
+            "{JWT_MINT_SELECT}
+                WHERE CAST($1 AS TEXT) IS NULL OR jwt_mints.org = $1
+                ORDER BY jwt_mints.time DESC
+                LIMIT $2"
+        ))
+        .bind(org)
+        .bind(i64::try_from(limit)?)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(mints)
+    }
+
+    async fn jwt_mint_get(
+        &self,
+        jti: &str,
+    ) -> anyhow::Result<Option<JwtMint>> {
+        let mint: Option<JwtMint> = sqlx::query_as(&format!(
+            "{JWT_MINT_SELECT}
+                WHERE jwt_mints.jti = $1"
+        ))
+        .bind(jti)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(mint)
+    }
+
+    async fn jwt_revoke_all(&self, uid: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO jwt_revocations_by_uid (uid, time_revoked)
+                VALUES ($1, $2)
+                ON CONFLICT(uid) DO UPDATE SET time_revoked = $2",
+        )
+        .bind(uid)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn jwt_is_revoked(
+        &self,
+        jti: Option<&str>,
+        uid: &str,
+        iat: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        if let Some(jti) = jti {
+            let revoked: Option<(i64,)> = sqlx::query_as(
+                "SELECT time_revoked FROM jwt_revocations WHERE jti = $1",
+            )
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+            if revoked.is_some() {
+                return Ok(true);
+            }
+        }
+        let revoked_all: Option<(i64,)> = sqlx::query_as(
+            "SELECT time_revoked FROM jwt_revocations_by_uid WHERE uid = $1",
+        )
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await?;
+        let is_revoked = match (revoked_all, iat) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some((time_revoked,)), Some(iat)) => {
+                i64::try_from(iat)? <= time_revoked
+            }
+        };
+        Ok(is_revoked)
+    }
+
+    async fn account_list(&self) -> anyhow::Result<Vec<Account>> {
+        let rows: Vec<(String, Option<i64>, i64)> = sqlx::query_as(
+            "SELECT
+                    known.uid,
+                    users.max_tokens_per_day,
+                    COALESCE(users.is_suspended, 0)
+                FROM (SELECT uid FROM hits UNION SELECT uid FROM users) known
+                LEFT JOIN users ON users.uid = known.uid
+                ORDER BY known.uid",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(uid, max_tokens_per_day, is_suspended)| {
+                Ok(Account {
+                    uid,
+                    max_tokens_per_day: max_tokens_per_day
+                        .map(u64::try_from)
+                        .transpose()?,
+                    is_suspended: is_suspended != 0,
+                })
+            })
+            .collect()
+    }
+
+    async fn account_page(
+        &self,
+        is_suspended: Option<bool>,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<(Account, BudgetBasis)>> {
+        let rows: Vec<(String, Option<i64>, i64, i64, i64, i64)> =
+            sqlx::query_as(
+                "SELECT
+                        known.uid,
+                        users.max_tokens_per_day,
+                        COALESCE(users.is_suspended, 0),
+                        COALESCE(bonus_tokens.total, 0),
+                        COALESCE((
+                            SELECT CAST(SUM(credits.tokens) AS BIGINT)
+                            FROM credits
+                            WHERE credits.uid = known.uid
+                        ), 0),
+                        COALESCE(tokens.total, 0)
+                    FROM (SELECT uid FROM hits UNION SELECT uid FROM users)
+                        known
+                    LEFT JOIN users ON users.uid = known.uid
+                    LEFT JOIN bonus_tokens
+                        ON bonus_tokens.uid = known.uid
+                        AND bonus_tokens.date = $1
+                    LEFT JOIN tokens
+                        ON tokens.uid = known.uid AND tokens.date = $1
+                    WHERE CAST($2 AS BIGINT) IS NULL
+                        OR COALESCE(users.is_suspended, 0) = $2
+                    ORDER BY known.uid
+                    LIMIT $3 OFFSET $4",
+            )
+            .bind(period::current())
+            .bind(is_suspended.map(i64::from))
+            .bind(i64::try_from(limit)?)
+            .bind(i64::try_from(offset)?)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    uid,
+                    max_tokens_per_day,
+                    is_suspended,
+                    bonus,
+                    credits,
+                    used,
+                )| {
+                    let max_tokens_per_day =
+                        max_tokens_per_day.map(u64::try_from).transpose()?;
+                    let account = Account {
+                        uid,
+                        max_tokens_per_day,
+                        is_suspended: is_suspended != 0,
+                    };
+                    let basis = BudgetBasis {
+                        max_tokens_per_day,
+                        bonus_tokens: u64::try_from(bonus)?,
+                        credits,
+                        used: u64::try_from(used.max(0))?,
+                    };
+                    Ok((account, basis))
+                },
+            )
+            .collect()
+    }
+
+    async fn account_get(&self, uid: &str) -> anyhow::Result<Account> {
+        let row_opt: Option<(Option<i64>, i64)> = sqlx::query_as(
+            "SELECT max_tokens_per_day, is_suspended FROM users WHERE uid = $1",
+        )
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await?;
+        let account = match row_opt {
+            None => Account::new(uid),
+            Some((max_tokens_per_day, is_suspended)) => Account {
+                uid: uid.to_string(),
+                max_tokens_per_day: max_tokens_per_day
+                    .map(u64::try_from)
+                    .transpose()?,
+                is_suspended: is_suspended != 0,
+            },
+        };
+        Ok(account)
+    }
+
+    async fn account_set_max_tokens_per_day(
+        &self,
+        uid: &str,
+        max_tokens_per_day: Option<u64>,
+    ) -> anyhow::Result<Account> {
+        let max_tokens_per_day =
+            max_tokens_per_day.map(i64::try_from).transpose()?;
+        sqlx::query(
+            "INSERT INTO users (uid, max_tokens_per_day, time_updated)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(uid) DO UPDATE SET
+                max_tokens_per_day = $2,
+                time_updated = $3",
+        )
+        .bind(uid)
+        .bind(max_tokens_per_day)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        if let Some(cache) = &self.budget_cache {
+            cache.forget_limits(uid).await;
+        }
+        self.account_get(uid).await
+    }
+
+    async fn account_set_suspended(
+        &self,
+        uid: &str,
+        is_suspended: bool,
+    ) -> anyhow::Result<Account> {
+        if is_suspended {
+            self.suspend(uid, None).await?;
+        } else {
+            let now = unix_now()?;
+            let mut tx = self.writer.begin().await?;
+            sqlx::query(
+                "INSERT INTO users (uid, is_suspended, time_updated)
+                    VALUES ($1, 0, $2)
+                    ON CONFLICT(uid) DO UPDATE SET
+                    is_suspended = 0,
+                    time_updated = $2",
+            )
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "UPDATE suspensions SET time_lifted = $2
+                    WHERE uid = $1 AND time_lifted IS NULL",
+            )
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        self.account_get(uid).await
+    }
+
+    async fn suspension_create(
+        &self,
+        uid: &str,
+        reason: &str,
+    ) -> anyhow::Result<Account> {
+        self.suspend(uid, Some(reason)).await?;
+        self.account_get(uid).await
+    }
+
+    async fn suspension_list(&self) -> anyhow::Result<Vec<Suspension>> {
+        // Those suspended before suspensions were recorded have no row.
+        let rows: Vec<(String, Option<String>, i64)> = sqlx::query_as(
+            "SELECT
+                    users.uid,
+                    suspensions.reason,
+                    COALESCE(suspensions.time_suspended, users.time_updated)
+                FROM users
+                LEFT JOIN suspensions ON suspensions.uid = users.uid
+                WHERE users.is_suspended != 0
+                ORDER BY 3 DESC, users.uid",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let suspensions = rows
+            .into_iter()
+            .map(|(uid, reason, time)| Suspension { uid, reason, time })
+            .collect();
+        Ok(suspensions)
+    }
+
+    async fn suspension_candidates(
+        &self,
+        time: i64,
+    ) -> anyhow::Result<Vec<UserStats>> {
+        let rows: Vec<(String, i64, i64, i64, i64)> = sqlx::query_as(
+            "SELECT
+                    logs.uid,
+                    CAST(COUNT(*) AS BIGINT),
+                    CAST(SUM(
+                        CASE WHEN logs.error_class = 'validation'
+                            THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(logs.input_tokens) AS BIGINT),
+                    CAST(SUM(logs.output_tokens) AS BIGINT)
+                FROM request_logs logs
+                LEFT JOIN users ON users.uid = logs.uid
+                LEFT JOIN suspensions ON suspensions.uid = logs.uid
+                WHERE logs.time >= $1
+                AND COALESCE(logs.error_class, '') <> 'budget'
+                AND COALESCE(users.is_suspended, 0) = 0
+                AND (
+                    suspensions.time_lifted IS NULL
+                    OR logs.time > suspensions.time_lifted
+                )
+                GROUP BY logs.uid
+                ORDER BY logs.uid",
+        )
+        .bind(time)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(uid, requests, errors, input, output)| {
+                Ok(UserStats {
+                    uid,
+                    requests: u64::try_from(requests)?,
+                    errors: u64::try_from(errors)?,
+                    input_tokens: u64::try_from(input)?,
+                    output_tokens: u64::try_from(output)?,
+                    ..UserStats::default()
+                })
+            })
+            .collect()
+    }
+
+    async fn account_grant_tokens(
+        &self,
+        uid: &str,
+        tokens: u64,
+    ) -> anyhow::Result<u64> {
+        let (total,): (i64,) = sqlx::query_as(
+            "INSERT INTO bonus_tokens (uid, date, total)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(uid, date) DO UPDATE SET
+                total = bonus_tokens.total + $3
+                RETURNING total",
+        )
+        .bind(uid)
+        .bind(period::current())
+        .bind(i64::try_from(tokens)?)
+        .fetch_one(&self.writer)
+        .await?;
+        if let Some(cache) = &self.budget_cache {
+            cache.forget_limits(uid).await;
+        }
+        Ok(u64::try_from(total)?)
+    }
+
+    async fn credits_grant(
+        &self,
+        uid: &str,
+        tokens: u64,
+        note: Option<&str>,
+    ) -> anyhow::Result<i64> {
+        sqlx::query(
+            "INSERT INTO credits (uid, tokens, period, note, time)
+                VALUES ($1, $2, NULL, $3, $4)",
+        )
+        .bind(uid)
+        .bind(i64::try_from(tokens)?)
+        .bind(note)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        if let Some(cache) = &self.budget_cache {
+            cache.forget_limits(uid).await;
+        }
+        self.credits_balance(uid).await
+    }
+
+    async fn credits_balance(&self, uid: &str) -> anyhow::Result<i64> {
+        let mut conn = self.pool.acquire().await?;
+        Self::credits_balance_of(&mut conn, uid).await
+    }
 
-    // Used in SQL, but not in Rust. Here for documentation.
-    #[allow(dead_code)]
-    date: String,
+    async fn credits_list(&self, uid: &str) -> anyhow::Result<Vec<Credit>> {
+        let rows: Vec<(i64, Option<String>, Option<String>, i64)> =
+            sqlx::query_as(
+                "SELECT tokens, period, note, time FROM credits
+                    WHERE uid = $1
+                    ORDER BY time DESC",
+            )
+            .bind(uid)
+            .fetch_all(&self.pool)
+            .await?;
+        let credits = rows
+            .into_iter()
+            .map(|(tokens, period, note, time)| Credit {
+                tokens,
+                period,
+                note,
+                time,
+            })
+            .collect();
+        Ok(credits)
+    }
 
-    total: u64,
-}
+    async fn credits_close(
+        &self,
+        period: &str,
+        max_rollover: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let max_rollover =
+            max_rollover.map(i64::try_from).transpose()?.unwrap_or(0);
+        let now = unix_now()?;
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        // In order, since each depends on the balance left by the previous.
+        let open: Vec<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT uid, date, total, cap FROM tokens
+                WHERE date < $1 AND cap IS NOT NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM credits
+                    WHERE credits.uid = tokens.uid
+                    AND credits.period = tokens.date
+                )
+                ORDER BY date, uid",
+        )
+        .bind(period)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (uid, date, total, cap) in &open {
+            let balance = Self::credits_balance_of(&mut tx, uid).await?;
+            let tokens = if total > cap {
+                -(total - cap).min(balance.max(0))
+            } else {
+                (cap - total).min((max_rollover - balance).max(0))
+            };
+            sqlx::query(
+                "INSERT INTO credits (uid, tokens, period, note, time)
+                    VALUES ($1, $2, $3, NULL, $4)
+                    ON CONFLICT(uid, period) DO NOTHING",
+            )
+            .bind(uid)
+            .bind(tokens)
+            .bind(date)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(u64::try_from(open.len())?)
+    }
 
-#[derive(Clone)]
-pub struct Storage {
-    pool: sqlx::Pool<sqlx::Sqlite>,
-}
+    async fn credits_used(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> anyhow::Result<Vec<(String, u64)>> {
+        // Period keys start with their date, so compare as strings.
+        let until = NaiveDate::parse_from_str(to, "%Y-%m-%d")
+            .ok()
+            .and_then(|to| to.succ_opt())
+            .context(format!("Invalid date: {to:?}"))?
+            .format("%Y-%m-%d")
+            .to_string();
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT uid, CAST(SUM(-tokens) AS BIGINT) FROM credits
+                WHERE tokens < 0 AND period >= $1 AND period < $2
+                GROUP BY uid",
+        )
+        .bind(from)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(uid, tokens)| Ok((uid, u64::try_from(tokens)?)))
+            .collect()
+    }
 
-impl Storage {
-    pub async fn connect() -> anyhow::Result<Self> {
-        let file_path = PathBuf::from("data/data.db");
-        if let Some(parent) = file_path.parent() {
-            let ctx = format!(
-                "Failed to create parent directory \
-                for database file: {file_path:?}"
-            );
-            fs::create_dir_all(parent).context(ctx)?;
+    async fn org_get(&self, org: &str) -> anyhow::Result<Org> {
+        let settings: Option<(Option<i64>, Option<f64>, Option<i64>)> =
+            sqlx::query_as(
+                "SELECT max_tokens_per_day, max_cost_per_day, reserved_rpm
+                    FROM orgs
+                    WHERE org = $1",
+            )
+            .bind(org)
+            .fetch_optional(&self.pool)
+            .await?;
+        let (max_tokens_per_day, max_cost_per_day, reserved_rpm) =
+            settings.unwrap_or_default();
+        let usage: Option<(i64, f64)> = sqlx::query_as(
+            "SELECT tokens, cost FROM org_usage WHERE org = $1 AND date = $2",
+        )
+        .bind(org)
+        .bind(period::current())
+        .fetch_optional(&self.pool)
+        .await?;
+        let (tokens_today, cost_today) = usage.unwrap_or_default();
+        Ok(Org {
+            org: org.to_string(),
+            max_tokens_per_day: max_tokens_per_day
+                .map(u64::try_from)
+                .transpose()?,
+            max_cost_per_day,
+            reserved_rpm: reserved_rpm.map(u64::try_from).transpose()?,
+            tokens_today: u64::try_from(tokens_today)?,
+            cost_today,
+        })
+    }
+
+    async fn org_set_budget(
+        &self,
+        org: &str,
+        max_tokens_per_day: Option<u64>,
+        max_cost_per_day: Option<f64>,
+    ) -> anyhow::Result<Org> {
+        let max_tokens_per_day =
+            max_tokens_per_day.map(i64::try_from).transpose()?;
+        sqlx::query(
+            "INSERT INTO orgs (
+                    org,
+                    max_tokens_per_day,
+                    max_cost_per_day,
+                    time_updated
+                )
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(org) DO UPDATE SET
+                max_tokens_per_day = $2,
+                max_cost_per_day = $3,
+                time_updated = $4",
+        )
+        .bind(org)
+        .bind(max_tokens_per_day)
+        .bind(max_cost_per_day)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        self.org_get(org).await
+    }
+
+    async fn org_set_reserved_rpm(
+        &self,
+        org: &str,
+        reserved_rpm: Option<u64>,
+    ) -> anyhow::Result<Org> {
+        let reserved_rpm = reserved_rpm.map(i64::try_from).transpose()?;
+        sqlx::query(
+            "INSERT INTO orgs (org, reserved_rpm, time_updated)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(org) DO UPDATE SET
+                reserved_rpm = $2,
+                time_updated = $3",
+        )
+        .bind(org)
+        .bind(reserved_rpm)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        self.org_get(org).await
+    }
+
+    async fn orgs_reserved_rpm(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT org, reserved_rpm FROM orgs
+                WHERE reserved_rpm IS NOT NULL
+                ORDER BY org",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(org, reserved_rpm)| {
+                Ok((org, u64::try_from(reserved_rpm)?))
+            })
+            .collect()
+    }
+
+    async fn org_members(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let members = sqlx::query_as(
+            "SELECT org, uid FROM org_members ORDER BY org, uid",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(members)
+    }
+
+    async fn org_has_member(
+        &self,
+        org: &str,
+        uid: &str,
+    ) -> anyhow::Result<bool> {
+        let member: Option<(String,)> = sqlx::query_as(
+            "SELECT uid FROM org_members WHERE org = $1 AND uid = $2",
+        )
+        .bind(org)
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(member.is_some())
+    }
+
+    async fn report(
+        &self,
+        from: &str,
+        to: &str,
+        uid: Option<&str>,
+    ) -> anyhow::Result<Vec<ReportRow>> {
+        let (time_from, _) = date_bounds(from)?;
+        let (_, time_to) = date_bounds(to)?;
+        // Days since epoch, rather than dates, since date functions differ
+        // between backends. Casting sums, since Postgres widens them to
+        // NUMERIC.
+        let logged: Vec<(i64, String, String, i64, i64, i64, i64, f64)> =
+            sqlx::query_as(
+                "SELECT
+                        time / 86400 AS day,
+                        uid,
+                        model,
+                        COUNT(*),
+                        CAST(SUM(
+                            CASE WHEN status >= 400
+                                AND COALESCE(error_class, '') <> 'budget'
+                            THEN 1 ELSE 0 END
+                        ) AS BIGINT),
+                        CAST(SUM(input_tokens) AS BIGINT),
+                        CAST(SUM(output_tokens) AS BIGINT),
+                        SUM(cost)
+                    FROM request_logs
+                    WHERE time >= $1 AND time < $2
+                    AND (CAST($3 AS TEXT) IS NULL OR uid = $3)
+                    GROUP BY day, uid, model",
+            )
+            .bind(time_from)
+            .bind(time_to)
+            .bind(uid)
+            .fetch_all(&self.pool)
+            .await?;
+        let budgeted: Vec<(String, String, String, i64)> = sqlx::query_as(
+            "SELECT date, uid, model, total FROM tokens_by_model
+                WHERE date >= $1 AND date <= $2
+                AND (CAST($3 AS TEXT) IS NULL OR uid = $3)",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(uid)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut rows: BTreeMap<(String, String, String), ReportRow> =
+            BTreeMap::new();
+        for (day, uid, model, requests, errors, input, output, cost) in logged
+        {
+            let date = DateTime::from_timestamp(day * 86400, 0)
+                .context(format!("Invalid day: {day}"))?
+                .format("%Y-%m-%d")
+                .to_string();
+            let row = rows
+                .entry((date.clone(), uid.clone(), model.clone()))
+                .or_insert_with(|| ReportRow {
+                    date,
+                    uid,
+                    model,
+                    ..ReportRow::default()
+                });
+            row.requests = u64::try_from(requests)?;
+            row.errors = u64::try_from(errors)?;
+            row.input_tokens = u64::try_from(input)?;
+            row.output_tokens = u64::try_from(output)?;
+            row.cost = cost;
         }
-        let busy_timeout =
-            Duration::from_secs_f32(conf::global().sqlite_busy_timeout);
-        let options = sqlx::sqlite::SqliteConnectOptions::new()
-            .filename(file_path)
-            .create_if_missing(true)
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .busy_timeout(busy_timeout);
-        let pool = sqlx::SqlitePool::connect_with(options).await?;
-        let selph = Self { pool };
-        for migration in MIGRATIONS {
-            selph.pool.execute(migration).await?;
+        for (date, uid, model, total) in budgeted {
+            let row = rows
+                .entry((date.clone(), uid.clone(), model.clone()))
+                .or_insert_with(|| ReportRow {
+                    date,
+                    uid,
+                    model,
+                    ..ReportRow::default()
+                });
+            row.budget_tokens = u64::try_from(total)?;
         }
-        Ok(selph)
+        Ok(rows.into_values().collect())
     }
 
-    /// Returns hit count and duration since previous hit.
-    pub async fn hit(&self, uid: &str) -> anyhow::Result<(u64, Duration)> {
-        let now = SystemTime::now();
-        let tx = self.pool.begin().await?;
-        let (tx, x) = hit(tx, uid, now).await?;
-        tx.commit().await?;
-        Ok(x)
+    async fn usage(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<DailyUsage> {
+        let tokens: Option<(i64,)> = sqlx::query_as(
+            "SELECT total FROM tokens WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        let cost: Option<(f64,)> = sqlx::query_as(
+            "SELECT total FROM costs WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        let by_model: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT model, total FROM tokens_by_model
+                WHERE uid = $1 AND date = $2
+                ORDER BY model",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_all(&self.pool)
+        .await?;
+        let (from, to) = period::bounds(date)?;
+        let (requests,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM request_logs
+                WHERE uid = $1 AND time >= $2 AND time < $3",
+        )
+        .bind(uid)
+        .bind(from)
+        .bind(to)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(DailyUsage {
+            uid: uid.to_string(),
+            date: date.to_string(),
+            tokens: u64::try_from(tokens.map_or(0, |(total,)| total))?,
+            tokens_by_model: by_model
+                .into_iter()
+                .map(|(model, total)| Ok((model, u64::try_from(total)?)))
+                .collect::<anyhow::Result<_>>()?,
+            bonus_tokens: self.bonus_tokens(uid, date).await?,
+            cost: cost.map_or(0.0, |(total,)| total),
+            requests: u64::try_from(requests)?,
+        })
     }
 
-    pub async fn tokens_check(
+    async fn template_put(
         &self,
-        uid: &str,
-        requested_amount: usize,
+        owner: Owner<'_>,
+        name: &str,
+        body: &str,
+    ) -> anyhow::Result<i64> {
+        // XXX Concurrent puts of the same template may race for the next
+        //     version, in which case the loser fails on the unique
+        //     constraint instead of overwriting.
+        let (version,): (i64,) = sqlx::query_as(
+            "INSERT INTO templates
+                (owner_kind, owner, name, version, body, time)
+                SELECT $1, $2, $3, COALESCE(MAX(version), 0) + 1, $4, $5
+                FROM templates
+                WHERE owner_kind = $1 AND owner = $2 AND name = $3
+                RETURNING version",
+        )
+        .bind(owner.kind())
+        .bind(owner.id())
+        .bind(name)
+        .bind(body)
+        .bind(unix_now()?)
+        .fetch_one(&self.writer)
+        .await?;
+        Ok(version)
+    }
+
+    async fn template_get(
+        &self,
+        owner: Owner<'_>,
+        name: &str,
+        version: Option<i64>,
+    ) -> anyhow::Result<Option<Template>> {
+        let row: Option<(i64, String, i64)> = sqlx::query_as(
+            "SELECT version, body, time FROM templates
+                WHERE owner_kind = $1 AND owner = $2 AND name = $3
+                AND (CAST($4 AS BIGINT) IS NULL OR version = $4)
+                ORDER BY version DESC
+                LIMIT 1",
+        )
+        .bind(owner.kind())
+        .bind(owner.id())
+        .bind(name)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(version, body, time)| Template {
+            name: name.to_string(),
+            version,
+            body,
+            time,
+        }))
+    }
+
+    async fn template_list(
+        &self,
+        owner: Owner<'_>,
+    ) -> anyhow::Result<Vec<Template>> {
+        let rows: Vec<(String, i64, String, i64)> = sqlx::query_as(
+            "SELECT name, version, body, time FROM templates AS t
+                WHERE owner_kind = $1 AND owner = $2
+                AND version = (
+                    SELECT MAX(version) FROM templates
+                    WHERE owner_kind = t.owner_kind
+                    AND owner = t.owner
+                    AND name = t.name
+                )
+                ORDER BY name",
+        )
+        .bind(owner.kind())
+        .bind(owner.id())
+        .fetch_all(&self.pool)
+        .await?;
+        let templates = rows
+            .into_iter()
+            .map(|(name, version, body, time)| Template {
+                name,
+                version,
+                body,
+                time,
+            })
+            .collect();
+        Ok(templates)
+    }
+
+    async fn template_delete(
+        &self,
+        owner: Owner<'_>,
+        name: &str,
     ) -> anyhow::Result<bool> {
-        let requested_amount = u64::try_from(requested_amount)?;
-        let now = SystemTime::now();
-        let tx = self.pool.begin().await?;
-        let (tx, is_enough) =
-            tokens_check(tx, uid, now, requested_amount).await?;
-        tx.commit().await?;
-        Ok(is_enough)
+        let deleted: Vec<(i64,)> = sqlx::query_as(
+            "DELETE FROM templates
+                WHERE owner_kind = $1 AND owner = $2 AND name = $3
+                RETURNING version",
+        )
+        .bind(owner.kind())
+        .bind(owner.id())
+        .bind(name)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(!deleted.is_empty())
+    }
+
+    async fn preferences_get(
+        &self,
+        uid: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let row: Option<(String,)> =
+            sqlx::query_as("SELECT body FROM preferences WHERE uid = $1")
+                .bind(uid)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(body,)| body))
     }
 
-    pub async fn tokens_consume(
+    async fn preferences_put(
         &self,
         uid: &str,
-        requested_amount: usize,
+        body: &str,
     ) -> anyhow::Result<()> {
-        let requested_amount = u64::try_from(requested_amount)?;
-        let now = SystemTime::now();
-        let tx = self.pool.begin().await?;
-        let tx = tokens_consume(tx, uid, now, requested_amount).await?;
-        tx.commit().await?;
+        sqlx::query(
+            "INSERT INTO preferences (uid, body, time) VALUES ($1, $2, $3)
+                ON CONFLICT(uid) DO UPDATE SET body = $2, time = $3",
+        )
+        .bind(uid)
+        .bind(body)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
         Ok(())
     }
-}
 
-pub async fn hit<'a>(
-    mut tx: Tx<'a>,
-    uid: &str,
-    now: SystemTime,
-) -> anyhow::Result<(Tx<'a>, (u64, Duration))> {
-    let curr = i64::try_from(now.duration_since(UNIX_EPOCH)?.as_secs())?;
-    let prev_opt: Option<HitsRow> =
-        sqlx::query_as("SELECT * FROM hits WHERE uid = ?")
-            .bind(uid)
-            .fetch_optional(&mut *tx)
+    async fn preferences_delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let deleted: Vec<(String,)> = sqlx::query_as(
+            "DELETE FROM preferences WHERE uid = $1 RETURNING uid",
+        )
+        .bind(uid)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(!deleted.is_empty())
+    }
+
+    async fn invite_create(
+        &self,
+        code: &str,
+        max_uses: u64,
+        time_expires: Option<i64>,
+    ) -> anyhow::Result<Invite> {
+        let time_created = unix_now()?;
+        sqlx::query(
+            "INSERT INTO invites (code, max_uses, time_created, time_expires)
+                VALUES ($1, $2, $3, $4)",
+        )
+        .bind(code)
+        .bind(i64::try_from(max_uses)?)
+        .bind(time_created)
+        .bind(time_expires)
+        .execute(&self.writer)
+        .await?;
+        Ok(Invite {
+            code: code.to_string(),
+            max_uses,
+            uses: 0,
+            time_created,
+            time_expires,
+        })
+    }
+
+    async fn invite_list(&self) -> anyhow::Result<Vec<Invite>> {
+        let rows: Vec<(String, i64, i64, i64, Option<i64>)> = sqlx::query_as(
+            "SELECT code, max_uses, uses, time_created, time_expires
+                FROM invites
+                ORDER BY time_created DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(code, max_uses, uses, time_created, time_expires)| {
+                Ok(Invite {
+                    code,
+                    max_uses: u64::try_from(max_uses)?,
+                    uses: u64::try_from(uses)?,
+                    time_created,
+                    time_expires,
+                })
+            })
+            .collect()
+    }
+
+    async fn signup_create(
+        &self,
+        email: &str,
+        invite: &str,
+    ) -> anyhow::Result<SignupOutcome> {
+        let now = unix_now()?;
+        // Rolled back when dropped, so that nothing is used up by failures.
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        let created: Option<(String,)> = sqlx::query_as(
+            "INSERT INTO signups (email, invite, time_created)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(email) DO NOTHING
+                RETURNING email",
+        )
+        .bind(email)
+        .bind(invite)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if created.is_none() {
+            return Ok(SignupOutcome::Exists);
+        }
+        let used: Option<(String,)> = sqlx::query_as(
+            "UPDATE invites SET uses = uses + 1
+                WHERE code = $1
+                AND uses < max_uses
+                AND (time_expires IS NULL OR time_expires > $2)
+                RETURNING code",
+        )
+        .bind(invite)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if used.is_none() {
+            return Ok(SignupOutcome::InvalidInvite);
+        }
+        tx.commit().await?;
+        Ok(SignupOutcome::Created)
+    }
+
+    async fn signup_list(&self) -> anyhow::Result<Vec<Signup>> {
+        let rows: Vec<(String, String, i64, Option<i64>)> = sqlx::query_as(
+            "SELECT email, invite, time_created, time_upgraded
+                FROM signups
+                ORDER BY time_created DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(email, invite, time_created, time_upgraded)| Signup {
+                email,
+                invite,
+                time_created,
+                time_upgraded,
+            })
+            .collect())
+    }
+
+    async fn signup_upgrade(
+        &self,
+        email: &str,
+        max_tokens_per_day: u64,
+    ) -> anyhow::Result<Option<Signup>> {
+        let row: Option<(String, String, i64, Option<i64>)> = sqlx::query_as(
+            "UPDATE signups SET time_upgraded = $2
+                WHERE email = $1
+                RETURNING email, invite, time_created, time_upgraded",
+        )
+        .bind(email)
+        .bind(unix_now()?)
+        .fetch_optional(&self.writer)
+        .await?;
+        let Some((email, invite, time_created, time_upgraded)) = row else {
+            return Ok(None);
+        };
+        self.account_set_max_tokens_per_day(&email, Some(max_tokens_per_day))
             .await?;
-    let (prev_count, prev_time) = match prev_opt {
-        None => {
+        Ok(Some(Signup {
+            email,
+            invite,
+            time_created,
+            time_upgraded,
+        }))
+    }
+
+    async fn stripe_customer_set(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        customer: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let Some(customer) = customer else {
             sqlx::query(
-                "INSERT INTO hits (uid, count_of_all, time_of_last)
-                    VALUES (?, 1, ?)",
+                "DELETE FROM stripe_customers WHERE kind = $1 AND id = $2",
             )
-            .bind(uid)
-            .bind(curr)
-            .execute(&mut *tx)
+            .bind(kind.as_str())
+            .bind(id)
+            .execute(&self.writer)
+            .await?;
+            return Ok(());
+        };
+        sqlx::query(
+            "INSERT INTO stripe_customers (kind, id, customer, time_updated)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(kind, id) DO UPDATE SET
+                customer = $3,
+                time_updated = $4",
+        )
+        .bind(kind.as_str())
+        .bind(id)
+        .bind(customer)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn stripe_customers(&self) -> anyhow::Result<Vec<StripeCustomer>> {
+        let rows: Vec<(String, String, String)> = sqlx::query_as(
+            "SELECT kind, id, customer FROM stripe_customers
+                ORDER BY kind, id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(kind, id, customer)| {
+                Ok(StripeCustomer {
+                    kind: kind.parse()?,
+                    id,
+                    customer,
+                })
+            })
+            .collect()
+    }
+
+    async fn stripe_is_reported(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        date: &str,
+    ) -> anyhow::Result<bool> {
+        let row: Option<(i64,)> = sqlx::query_as(
+            "SELECT value FROM stripe_reports
+                WHERE kind = $1 AND id = $2 AND date = $3",
+        )
+        .bind(kind.as_str())
+        .bind(id)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    async fn stripe_reported(
+        &self,
+        kind: billing::Account,
+        id: &str,
+        date: &str,
+        value: u64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO stripe_reports
+                (kind, id, date, value, time_reported)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT(kind, id, date) DO NOTHING",
+        )
+        .bind(kind.as_str())
+        .bind(id)
+        .bind(date)
+        .bind(i64::try_from(value)?)
+        .bind(unix_now()?)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn provider_spend_add(
+        &self,
+        provider: &str,
+        date: &str,
+        amount: Amount,
+    ) -> anyhow::Result<Amount> {
+        let (tokens, cost): (i64, f64) = sqlx::query_as(
+            "INSERT INTO provider_spend (provider, date, tokens, cost)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(provider, date) DO UPDATE SET
+                tokens = provider_spend.tokens + $3,
+                cost = provider_spend.cost + $4
+                RETURNING tokens, cost",
+        )
+        .bind(provider)
+        .bind(date)
+        .bind(i64::try_from(amount.tokens)?)
+        .bind(amount.cost)
+        .fetch_one(&self.writer)
+        .await?;
+        Ok(Amount {
+            tokens: usize::try_from(tokens)?,
+            cost,
+        })
+    }
+
+    async fn provider_spend(
+        &self,
+        date: &str,
+    ) -> anyhow::Result<Vec<(String, Amount)>> {
+        let rows: Vec<(String, i64, f64)> = sqlx::query_as(
+            "SELECT provider, tokens, cost FROM provider_spend
+                WHERE date = $1
+                ORDER BY provider",
+        )
+        .bind(date)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(provider, tokens, cost)| {
+                let tokens = usize::try_from(tokens)?;
+                Ok((provider, Amount { tokens, cost }))
+            })
+            .collect()
+    }
+
+    async fn probe_record(&self, probe: &Probe) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO probes (provider, time, is_up, latency_ms, error)
+                VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&probe.provider)
+        .bind(probe.time)
+        .bind(i64::from(probe.is_up))
+        .bind(probe.latency_ms)
+        .bind(&probe.error)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn probes(&self, since: i64) -> anyhow::Result<Vec<Probe>> {
+        let rows: Vec<(String, i64, i64, i64, Option<String>)> =
+            sqlx::query_as(
+                "SELECT provider, time, is_up, latency_ms, error FROM probes
+                    WHERE time >= $1
+                    ORDER BY time",
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
             .await?;
-            (0, UNIX_EPOCH)
+        let probes = rows
+            .into_iter()
+            .map(|(provider, time, is_up, latency_ms, error)| Probe {
+                provider,
+                time,
+                is_up: is_up != 0,
+                latency_ms,
+                error,
+            })
+            .collect();
+        Ok(probes)
+    }
+
+    async fn probes_prune(&self, before: i64) -> anyhow::Result<u64> {
+        // Counting RETURNING rows, since rows_affected isn't backend-agnostic.
+        let pruned: Vec<(i64,)> = sqlx::query_as(
+            "DELETE FROM probes WHERE time < $1 RETURNING time",
+        )
+        .bind(before)
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(u64::try_from(pruned.len())?)
+    }
+}
+
+impl<DB> Sql<DB>
+where
+    DB: sqlx::Database,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'c> &'c sqlx::Pool<DB>: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    for<'q> i64: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> f64: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> String:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<&'q str>: sqlx::Encode<'q, DB> + sqlx::Type<DB>,
+    for<'q> &'q str: sqlx::Encode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<i64>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<f64>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Vec<u8>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> Option<Vec<u8>>:
+        sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'s> &'s str: sqlx::ColumnIndex<DB::Row>,
+    usize: sqlx::ColumnIndex<DB::Row>,
+{
+    /// Reason is None when by an admin.
+    async fn suspend(
+        &self,
+        uid: &str,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let now = unix_now()?;
+        let mut tx = self.writer.begin().await?;
+        sqlx::query(
+            "INSERT INTO users (uid, is_suspended, time_updated)
+                VALUES ($1, 1, $2)
+                ON CONFLICT(uid) DO UPDATE SET
+                is_suspended = 1,
+                time_updated = $2",
+        )
+        .bind(uid)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO suspensions (uid, reason, time_suspended)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(uid) DO UPDATE SET
+                reason = $2,
+                time_suspended = $3,
+                time_lifted = NULL",
+        )
+        .bind(uid)
+        .bind(reason)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub(crate) async fn new(
+        pool: sqlx::Pool<DB>,
+        writer: sqlx::Pool<DB>,
+    ) -> anyhow::Result<Self> {
+        let conf = conf::global();
+        let write_behind = conf
+            .write_behind
+            .as_ref()
+            .map(|write_behind| WriteBehind::new(write_behind.max_queued));
+        // Redis already keeps usage out of the database.
+        let budget_cache = (conf.budget_cache && conf.redis.is_none())
+            .then(BudgetCache::default);
+        #[cfg(feature = "redis")]
+        let redis = match &conf.redis {
+            None => None,
+            Some(redis) => Some(shared::Redis::connect(redis).await?),
+        };
+        #[cfg(not(feature = "redis"))]
+        if conf.redis.is_some() {
+            anyhow::bail!(
+                "redis is set in conf, but this build is without the redis \
+                feature."
+            );
         }
-        Some(HitsRow {
-            uid,
-            count_of_all,
-            time_of_last: prev,
-        }) => {
+        Ok(Self {
+            pool,
+            writer,
+            write_behind,
+            budget_cache,
+            #[cfg(feature = "redis")]
+            redis,
+        })
+    }
+
+    /// All in one transaction.
+    async fn write_all(&self, writes: &[Write]) -> anyhow::Result<()> {
+        let mut tx: Tx<DB> = self.writer.begin().await?;
+        for write in writes {
+            match write {
+                Write::Settle(reservation, used) => {
+                    Self::settle(&mut tx, reservation, *used).await?;
+                }
+                Write::Log(log) => {
+                    Self::log_request_insert(&mut tx, log).await?;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn settle(
+        conn: &mut DB::Connection,
+        reservation: &Reservation,
+        used: Amount,
+    ) -> anyhow::Result<()> {
+        let tokens_delta = i64::try_from(used.tokens)?
+            - i64::try_from(reservation.amount.tokens)?;
+        let cost_delta = used.cost - reservation.amount.cost;
+        sqlx::query(
+            "UPDATE tokens SET
+                total = CASE
+                    WHEN total + $1 < 0 THEN 0
+                    ELSE total + $1
+                END
+                WHERE uid = $2 AND date = $3",
+        )
+        .bind(tokens_delta)
+        .bind(&reservation.uid)
+        .bind(&reservation.date)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(
+            "UPDATE tokens_by_model SET
+                total = CASE
+                    WHEN total + $1 < 0 THEN 0
+                    ELSE total + $1
+                END
+                WHERE uid = $2 AND model = $3 AND date = $4",
+        )
+        .bind(tokens_delta)
+        .bind(&reservation.uid)
+        .bind(&reservation.model)
+        .bind(&reservation.date)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(
+            "UPDATE costs SET
+                total = CASE
+                    WHEN total + $1 < 0 THEN 0
+                    ELSE total + $1
+                END
+                WHERE uid = $2 AND date = $3",
+        )
+        .bind(cost_delta)
+        .bind(&reservation.uid)
+        .bind(&reservation.date)
+        .execute(&mut *conn)
+        .await?;
+        if let Some(org) = &reservation.org {
             sqlx::query(
-                "UPDATE hits SET
-                    count_of_all = count_of_all + 1,
-                    time_of_last = ?
-                    WHERE uid = ?",
+                "UPDATE org_usage SET
+                    tokens = CASE
+                        WHEN tokens + $1 < 0 THEN 0
+                        ELSE tokens + $1
+                    END,
+                    cost = CASE
+                        WHEN cost + $2 < 0 THEN 0
+                        ELSE cost + $2
+                    END
+                    WHERE org = $3 AND date = $4",
             )
-            .bind(curr)
-            .bind(uid)
-            .execute(&mut *tx)
+            .bind(tokens_delta)
+            .bind(cost_delta)
+            .bind(org)
+            .bind(&reservation.date)
+            .execute(&mut *conn)
             .await?;
-            (count_of_all, UNIX_EPOCH + Duration::from_secs(prev))
         }
-    };
-    let elapsed_since_prev_hit = now.duration_since(prev_time)?;
-    Ok((tx, (prev_count.saturating_add(1), elapsed_since_prev_hit)))
-}
-
-async fn tokens_check<'a>(
-    mut tx: Tx<'a>,
-    uid: &str,
-    now: SystemTime,
-    requested_amount: u64,
-) -> anyhow::Result<(Tx<'a>, bool)> {
-    let date = DateTime::<Utc>::from(now).format("%Y-%m-%d").to_string();
-    let prev_opt: Option<TokensRow> =
-        sqlx::query_as("SELECT * FROM tokens WHERE uid = ? AND date = ?")
-            .bind(uid)
-            .bind(&date)
-            .fetch_optional(&mut *tx)
+        Ok(())
+    }
+
+    /// Of just the given session, or any.
+    async fn sessions_of(
+        &self,
+        uid: &str,
+        session: Option<&str>,
+        since: i64,
+        limit: u64,
+    ) -> anyhow::Result<Vec<SessionStats>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            String,
+            i64,
+            i64,
+            i64,
+            i64,
+            f64,
+            i64,
+            i64,
+            i64,
+        )> = sqlx::query_as(
+            "SELECT
+                    session,
+                    COUNT(*),
+                    CAST(SUM(
+                        CASE WHEN status >= 400
+                            AND COALESCE(error_class, '') <> 'budget'
+                        THEN 1 ELSE 0 END
+                    ) AS BIGINT),
+                    CAST(SUM(input_tokens) AS BIGINT),
+                    CAST(SUM(output_tokens) AS BIGINT),
+                    SUM(cost),
+                    CAST(SUM(duration_ms) AS BIGINT),
+                    MIN(time),
+                    MAX(time)
+                FROM request_logs
+                WHERE uid = $1
+                AND session IS NOT NULL
+                AND (CAST($2 AS TEXT) IS NULL OR session = $2)
+                GROUP BY session
+                HAVING MAX(time) >= $3
+                ORDER BY MAX(time) DESC
+                LIMIT $4",
+        )
+        .bind(uid)
+        .bind(session)
+        .bind(since)
+        .bind(i64::try_from(limit)?)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    session,
+                    requests,
+                    errors,
+                    input,
+                    output,
+                    cost,
+                    duration,
+                    first,
+                    last,
+                )| {
+                    #[allow(clippy::cast_precision_loss)]
+                    let avg_duration_ms =
+                        duration as f64 / requests.max(1) as f64;
+                    Ok(SessionStats {
+                        session,
+                        requests: u64::try_from(requests)?,
+                        errors: u64::try_from(errors)?,
+                        input_tokens: u64::try_from(input)?,
+                        output_tokens: u64::try_from(output)?,
+                        cost,
+                        avg_duration_ms,
+                        first,
+                        last,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    /// Request IDs may come from clients, so a reused one is suffixed to
+    /// keep the logs apart. Of several statements, so to be run in a
+    /// transaction.
+    async fn log_request_insert(
+        conn: &mut DB::Connection,
+        log: &RequestLog,
+    ) -> anyhow::Result<()> {
+        if !Self::log_request_insert_as(conn, log, &log.req_id).await? {
+            let req_id = format!("{}-{}", log.req_id, cuid2::create_id());
+            tracing::warn!(req_id, "Request ID reused. Suffixed.");
+            Self::log_request_insert_as(conn, log, &req_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether inserted, which it is not if the ID is taken.
+    async fn log_request_insert_as(
+        conn: &mut DB::Connection,
+        log: &RequestLog,
+        req_id: &str,
+    ) -> anyhow::Result<bool> {
+        let timing =
+            log.timing.map(|t| serde_json::to_string(&t)).transpose()?;
+        let inserted: Option<(String,)> = sqlx::query_as(
+            "INSERT INTO request_logs (
+                req_id,
+                uid,
+                model,
+                endpoint,
+                status,
+                input_tokens,
+                output_tokens,
+                cost,
+                duration_ms,
+                time,
+                error_message,
+                session,
+                experiment,
+                arm,
+                upstream_timing,
+                error_class,
+                client_ip
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+                $15, $16, $17
+            )
+            ON CONFLICT(req_id) DO NOTHING
+            RETURNING req_id",
+        )
+        .bind(req_id)
+        .bind(&log.uid)
+        .bind(&log.model)
+        .bind(&log.endpoint)
+        .bind(log.status)
+        .bind(log.input_tokens)
+        .bind(log.output_tokens)
+        .bind(log.cost)
+        .bind(log.duration_ms)
+        .bind(log.time)
+        .bind(log.error_message.as_deref())
+        .bind(log.session.as_deref())
+        .bind(log.experiment.as_deref())
+        .bind(log.arm.as_deref())
+        .bind(timing.as_deref())
+        .bind(log.error_class.map(ErrorClass::as_str))
+        .bind(log.client_ip.as_deref())
+        .fetch_optional(&mut *conn)
+        .await?;
+        if inserted.is_none() {
+            return Ok(false);
+        }
+        for (tag, value) in &log.tags {
+            sqlx::query(
+                "INSERT INTO request_tags (req_id, tag, value, time)
+                    VALUES ($1, $2, $3, $4)",
+            )
+            .bind(req_id)
+            .bind(tag)
+            .bind(value)
+            .bind(log.time)
+            .execute(&mut *conn)
             .await?;
-    let used = match prev_opt {
-        None => {
+        }
+        if let Some(usage) = &log.usage {
             sqlx::query(
-                "INSERT INTO tokens (uid, date, total)
-                    VALUES (?, ?, 0)",
+                "INSERT INTO request_usage (
+                    req_id,
+                    uid,
+                    provider,
+                    model,
+                    endpoint,
+                    unit,
+                    input,
+                    output,
+                    detail,
+                    cost,
+                    time
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
             )
-            .bind(uid)
-            .bind(&date)
-            .execute(&mut *tx)
+            .bind(req_id)
+            .bind(&log.uid)
+            .bind(usage.provider.as_deref())
+            .bind(&log.model)
+            .bind(&log.endpoint)
+            .bind(usage.unit.as_str())
+            .bind(usage.input)
+            .bind(usage.output)
+            .bind(usage.detail.as_deref())
+            .bind(log.cost)
+            .bind(log.time)
+            .execute(&mut *conn)
             .await?;
-            0
         }
-        Some(TokensRow {
-            uid: _,
-            date: _,
-            total,
-        }) => total,
-    };
-    let max = conf::global().max_tokens_per_day;
-    let remaining = max.saturating_sub(used);
-    Ok((tx, remaining >= requested_amount))
-}
-
-async fn tokens_consume<'a>(
-    mut tx: Tx<'a>,
-    uid: &str,
-    now: SystemTime,
-    requested_amount: u64,
-) -> anyhow::Result<Tx<'a>> {
-    let date = DateTime::<Utc>::from(now).format("%Y-%m-%d").to_string();
-    let requested_amount = i64::try_from(requested_amount)?;
-    sqlx::query(
-        "INSERT INTO tokens (uid, date, total)
-                    VALUES (?, ?, ?)
-                    ON CONFLICT(uid, date) DO UPDATE SET
-                    total = total + ?
-                    ",
+        Ok(true)
+    }
+
+    /// Token limit of the user in the period, including its bonus, and
+    /// their credits.
+    async fn max_tokens(
+        &self,
+        uid: &str,
+        role: &str,
+        overrides: BudgetOverrides,
+        date: &str,
+    ) -> anyhow::Result<TokenLimit> {
+        let limits = match &self.budget_cache {
+            None => self.limits_load(uid, date).await?,
+            Some(cache) => {
+                let entry = cache.entry(uid, date);
+                let mut budget = entry.lock().await;
+                match budget.limits {
+                    Some(limits)
+                        if limits.loaded.elapsed() < BUDGET_LIMITS_TTL =>
+                    {
+                        limits
+                    }
+                    _ => *budget
+                        .limits
+                        .insert(self.limits_load(uid, date).await?),
+                }
+            }
+        };
+        Ok(token_limit(
+            limits.max_tokens_per_day,
+            limits.bonus_tokens,
+            limits.credits,
+            role,
+            overrides,
+        ))
+    }
+
+    async fn limits_load(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<CachedLimits> {
+        Ok(CachedLimits {
+            max_tokens_per_day: self
+                .account_get(uid)
+                .await?
+                .max_tokens_per_day,
+            bonus_tokens: self.bonus_tokens(uid, date).await?,
+            credits: self.credits_balance(uid).await?,
+            loaded: Instant::now(),
+        })
+    }
+
+    /// Loaded from the database the first time on the date.
+    async fn cached_usage<'b>(
+        &self,
+        budget: &'b mut CachedBudget,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<&'b mut CachedUsage> {
+        if let Some(usage) = budget.usage.take() {
+            return Ok(budget.usage.insert(usage));
+        }
+        let tokens: Option<(i64,)> = sqlx::query_as(
+            "SELECT total FROM tokens WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        let cost: Option<(f64,)> = sqlx::query_as(
+            "SELECT total FROM costs WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        let tokens_by_model: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT model, total FROM tokens_by_model
+                WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(budget.usage.insert(CachedUsage {
+            tokens: tokens.map_or(0, |(tokens,)| tokens),
+            cost: cost.map_or(0.0, |(cost,)| cost),
+            tokens_by_model: tokens_by_model.into_iter().collect(),
+        }))
+    }
+
+    /// Checks the user's budgets against their cached usage, going to the
+    /// database only for the shared budgets of their org.
+    async fn budget_reserve_cached(
+        &self,
+        cache: &BudgetCache,
+        reservation: Reservation,
+        max: Maxes,
+        deltas: Deltas,
+    ) -> anyhow::Result<Result<Reservation, Exceeded>> {
+        let Reservation {
+            uid,
+            org,
+            model,
+            date,
+            amount,
+        } = &reservation;
+        let entry = cache.entry(uid, date);
+        let mut budget = entry.lock().await;
+        let usage = self.cached_usage(&mut budget, uid, date).await?;
+        let tokens = i64::try_from(amount.tokens)?;
+        let tokens_for_model =
+            usage.tokens_by_model.get(model).copied().unwrap_or(0);
+        if tokens_for_model.saturating_add(tokens) > max.tokens_for_model {
+            return Ok(Err(Exceeded::Model));
+        }
+        if usage.tokens.saturating_add(tokens) > max.tokens {
+            return Ok(Err(Exceeded::Tokens));
+        }
+        if usage.cost + amount.cost > max.cost {
+            return Ok(Err(Exceeded::Cost));
+        }
+        if let Some(org) = org {
+            let mut tx: Tx<DB> = self.writer.begin().await?;
+            if !self
+                .org_reserve(&mut tx, org, uid, date, *amount, deltas)
+                .await?
+            {
+                tx.rollback().await?;
+                return Ok(Err(Exceeded::Org));
+            }
+            tx.commit().await?;
+        }
+        usage.tokens += tokens;
+        usage.cost += amount.cost;
+        *usage.tokens_by_model.entry(model.clone()).or_default() += tokens;
+        drop(budget);
+        self.usage_write_through(&reservation, tokens, max.cap)
+            .await;
+        Ok(Ok(reservation))
+    }
+
+    /// Checks the user's budgets against their usage in Redis, loading it
+    /// from the database first if it isn't there. Org budgets stay in the
+    /// database.
+    #[cfg(feature = "redis")]
+    async fn budget_reserve_shared(
+        &self,
+        redis: &shared::Redis,
+        reservation: Reservation,
+        max: shared::Totals,
+        cap: i64,
+        deltas: Deltas,
+    ) -> anyhow::Result<Result<Reservation, Exceeded>> {
+        let Reservation {
+            uid,
+            org,
+            model,
+            date,
+            amount,
+        } = &reservation;
+        if redis.usage(uid, model, date).await?.is_none() {
+            let usage = self.usage(uid, date).await?;
+            let tokens_for_model =
+                usage.tokens_by_model.get(model).copied().unwrap_or(0);
+            // Those queued aren't in the database yet.
+            let usage = shared::Totals {
+                tokens: i64::try_from(usage.tokens)? + deltas.tokens,
+                tokens_for_model: i64::try_from(tokens_for_model)?
+                    + deltas.tokens_for_model,
+                cost: usage.cost + deltas.cost,
+            };
+            redis.seed(uid, model, date, usage).await?;
+        }
+        if let Some(exceeded) = redis.reserve(&reservation, max).await? {
+            return Ok(Err(exceeded));
+        }
+        if let Some(org) = org {
+            let deltas = Deltas {
+                org_tokens: deltas.org_tokens,
+                org_cost: deltas.org_cost,
+                ..Deltas::default()
+            };
+            let mut tx: Tx<DB> = self.writer.begin().await?;
+            if !self
+                .org_reserve(&mut tx, org, uid, date, *amount, deltas)
+                .await?
+            {
+                tx.rollback().await?;
+                redis.settle(&reservation, Amount::default()).await?;
+                return Ok(Err(Exceeded::Org));
+            }
+            tx.commit().await?;
+        }
+        let tokens = i64::try_from(amount.tokens)?;
+        self.usage_write_through(&reservation, tokens, cap).await;
+        Ok(Ok(reservation))
+    }
+
+    /// Before the reservation is returned, so that its settlement, which
+    /// is clamped at 0, can't land first. Unconditional, since the check is
+    /// done against the cache, or Redis.
+    async fn usage_write_through(
+        &self,
+        reservation: &Reservation,
+        tokens: i64,
+        cap: i64,
+    ) {
+        if let Err(error) =
+            Self::usage_add(&self.writer, reservation, tokens, cap).await
+        {
+            tracing::error!(
+                ?error,
+                ?reservation,
+                "Failed to write through usage."
+            );
+        }
+    }
+
+    async fn usage_add(
+        pool: &sqlx::Pool<DB>,
+        reservation: &Reservation,
+        tokens: i64,
+        cap: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx: Tx<DB> = pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO tokens_by_model (uid, model, date, total)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(uid, model, date) DO UPDATE SET
+                total = tokens_by_model.total + $4",
+        )
+        .bind(&reservation.uid)
+        .bind(&reservation.model)
+        .bind(&reservation.date)
+        .bind(tokens)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO tokens (uid, date, total, cap)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(uid, date) DO UPDATE SET
+                total = tokens.total + $3,
+                cap = $4",
+        )
+        .bind(&reservation.uid)
+        .bind(&reservation.date)
+        .bind(tokens)
+        .bind(cap)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO costs (uid, date, total)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(uid, date) DO UPDATE SET
+                total = costs.total + $3",
+        )
+        .bind(&reservation.uid)
+        .bind(&reservation.date)
+        .bind(reservation.amount.cost)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Counts the amount against the org's shared budgets, unless it doesn't
+    /// fit, and the user as its member. Returns whether it fit.
+    async fn org_reserve(
+        &self,
+        conn: &mut DB::Connection,
+        org: &str,
+        uid: &str,
+        date: &str,
+        requested: Amount,
+        deltas: Deltas,
+    ) -> anyhow::Result<bool> {
+        let tokens = i64::try_from(requested.tokens)?;
+        let (max_tokens, max_cost) = self.org_max(org).await?;
+        let max_tokens = max_tokens
+            .map_or(Ok(i64::MAX), i64::try_from)?
+            .saturating_sub(deltas.org_tokens);
+        let max_cost = max_cost.unwrap_or(f64::MAX) - deltas.org_cost;
+        if tokens > max_tokens || requested.cost > max_cost {
+            return Ok(false);
+        }
+        let org_usage_opt: Option<(i64,)> = sqlx::query_as(
+            "INSERT INTO org_usage (org, date, tokens, cost)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT(org, date) DO UPDATE SET
+                tokens = org_usage.tokens + $3,
+                cost = org_usage.cost + $4
+                WHERE org_usage.tokens + $3 <= $5
+                AND org_usage.cost + $4 <= $6
+                RETURNING tokens",
+        )
+        .bind(org)
+        .bind(date)
+        .bind(tokens)
+        .bind(requested.cost)
+        .bind(max_tokens)
+        .bind(max_cost)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if org_usage_opt.is_none() {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT INTO org_members (org, uid) VALUES ($1, $2)
+                ON CONFLICT(org, uid) DO NOTHING",
+        )
+        .bind(org)
+        .bind(uid)
+        .execute(&mut *conn)
+        .await?;
+        Ok(true)
+    }
+
+    /// Daily tokens and cost shared by the org's members. No limit when
+    /// `None`.
+    async fn org_max(
+        &self,
+        org: &str,
+    ) -> anyhow::Result<(Option<u64>, Option<f64>)> {
+        let conf = conf::global();
+        let org = self.org_get(org).await?;
+        Ok((
+            org.max_tokens_per_day.or(conf.orgs.max_tokens_per_day),
+            org.max_cost_per_day.or(conf.orgs.max_cost_per_day),
+        ))
+    }
+
+    async fn credits_balance_of(
+        conn: &mut DB::Connection,
+        uid: &str,
+    ) -> anyhow::Result<i64> {
+        // Casting, since Postgres widens the sum to NUMERIC.
+        let (balance,): (Option<i64>,) = sqlx::query_as(
+            "SELECT CAST(SUM(tokens) AS BIGINT) FROM credits WHERE uid = $1",
+        )
+        .bind(uid)
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(balance.unwrap_or(0))
+    }
+
+    async fn bonus_tokens(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<u64> {
+        let total: Option<(i64,)> = sqlx::query_as(
+            "SELECT total FROM bonus_tokens WHERE uid = $1 AND date = $2",
+        )
+        .bind(uid)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(u64::try_from(total.map_or(0, |(total,)| total))?)
+    }
+}
+
+/// Of a user's own limits, in the role, with the token's overrides.
+fn token_limit(
+    max_tokens_per_day: Option<u64>,
+    bonus_tokens: u64,
+    credits: i64,
+    role: &str,
+    overrides: BudgetOverrides,
+) -> TokenLimit {
+    let conf = conf::global();
+    let multiplier = auth::budget_multiplier(&conf, role);
+    // Explicit limits for the user are not scaled.
+    let cap = max_tokens_per_day
+        .or(overrides.max_tokens_per_day)
+        .unwrap_or_else(|| scale(conf.max_tokens_per_day, multiplier))
+        .saturating_add(bonus_tokens);
+    TokenLimit { cap, credits }
+}
+
+fn scale(max: u64, multiplier: f64) -> u64 {
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    let scaled = (max as f64 * multiplier) as u64;
+    scaled
+}
+
+fn unix_now() -> anyhow::Result<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(i64::try_from(now)?)
+}
+
+/// Seconds since UNIX epoch at the start of the given UTC date and the next.
+pub(crate) fn date_bounds(date: &str) -> anyhow::Result<(i64, i64)> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .context(format!("Invalid date: {date:?}"))?;
+    let start = date
+        .and_hms_opt(0, 0, 0)
+        .context("Invalid start of day")?
+        .and_utc()
+        .timestamp();
+    Ok((start, start + 24 * 60 * 60))
+}
+
+/// Deletes data older than the given number of days.
+pub async fn prune(
+    storage: &dyn Storage,
+    retention_days: u32,
+) -> anyhow::Result<BTreeMap<String, u64>> {
+    let retention = chrono::Duration::days(i64::from(retention_days));
+    let cutoff = Utc::now() - retention;
+    let date = cutoff.format("%Y-%m-%d").to_string();
+    let deleted = storage.prune(cutoff.timestamp(), &date).await?;
+    for (table, count) in &deleted {
+        metrics::counter!("raskol_pruned_rows_total", "table" => table.clone())
+            .increment(*count);
+    }
+    tracing::info!(retention_days, ?date, ?deleted, "Pruned old data.");
+    Ok(deleted)
+}
+
+pub async fn maintain(
+    storage: &dyn Storage,
+    vacuum: bool,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    storage.maintain(vacuum).await?;
+    let duration = started.elapsed();
+    metrics::histogram!(
+        "raskol_db_maintenance_duration_seconds",
+        "vacuum" => vacuum.to_string()
     )
-    .bind(uid)
-    .bind(&date)
-    .bind(requested_amount)
-    .bind(requested_amount)
-    .execute(&mut *tx)
-    .await?;
-    Ok(tx)
+    .record(duration.as_secs_f64());
+    tracing::info!(?duration, vacuum, "Maintained database.");
+    Ok(())
+}
+
+/// Recomputes the per model factors from the recent requests and stores
+/// those of models with enough of them. Returns the stored ones.
+pub async fn calibrate(
+    storage: &dyn Storage,
+    conf: &conf::Calibration,
+) -> anyhow::Result<Vec<Calibration>> {
+    let now = Utc::now();
+    let since = now - chrono::Duration::days(i64::from(conf.window_days));
+    let mut calibrations =
+        storage.token_estimate_totals(since.timestamp()).await?;
+    calibrations
+        .retain(|calibration| calibration.samples >= conf.min_samples);
+    for calibration in &mut calibrations {
+        calibration.factor = calibration
+            .factor
+            .clamp(1.0 / conf.max_factor, conf.max_factor);
+        storage
+            .calibration_set(calibration, now.timestamp())
+            .await?;
+    }
+    tracing::info!(?calibrations, "Calibrated token estimates.");
+    Ok(calibrations)
+}
+
+/// UTC. For reports. Budgets are by [`period::current`].
+#[must_use]
+pub fn today() -> String {
+    DateTime::<Utc>::from(SystemTime::now())
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Amount, Reservation, Write, WriteBehind};
+
+    fn settle(tokens: usize) -> Write {
+        let reservation = Reservation {
+            uid: "alice".to_string(),
+            org: None,
+            model: "mock".to_string(),
+            date: "2026-10-16".to_string(),
+            amount: Amount::default(),
+        };
+        Write::Settle(reservation, Amount { tokens, cost: 0.0 })
+    }
+
+    fn queued(write_behind: &WriteBehind) -> Vec<usize> {
+        write_behind
+            .pending()
+            .queued
+            .iter()
+            .map(|write| match write {
+                Write::Settle(_, used) => used.tokens,
+                Write::Log(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write_behind_capped() {
+        let write_behind = WriteBehind::new(3);
+        for tokens in 1..=4 {
+            write_behind.push(settle(tokens));
+        }
+        assert_eq!(queued(&write_behind), [2, 3, 4]);
+
+        // Failed to flush, while more were queued.
+        let flushing = std::mem::take(&mut write_behind.pending().queued);
+        write_behind.push(settle(5));
+        write_behind.requeue(&mut write_behind.pending(), flushing);
+        assert_eq!(queued(&write_behind), [3, 4, 5]);
+    }
 }
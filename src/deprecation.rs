@@ -0,0 +1,313 @@
+//! Of models and routes which operators mark as deprecated, per
+//! [`conf::Deprecations`], what clients using them are told, and who they
+//! are. Responses get `Deprecation` and `Sunset` headers, per RFC 9745 and
+//! RFC 8594, and a `Warning` header field saying what to use instead. Each
+//! use is counted per user, at `/admin/deprecated`, so that the teams still
+//! on an old model alias can be chased down before it is removed. So are
+//! the models of batch items and WebSocket messages, though their clients
+//! get no headers.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+use crate::{
+    conf,
+    data::Storage,
+    server::{unix_now_secs, AppState, USER},
+    version,
+};
+
+/// Of the `Sunset` header, per RFC 9110.
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+tokio::task_local! {
+    /// Of the request, the model, as requested, once it is allowed.
+    static MODEL: Arc<Mutex<Option<String>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Model,
+    Route,
+}
+
+impl Kind {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Model => "model",
+            Self::Route => "route",
+        }
+    }
+}
+
+/// Of a request, what it used which is deprecated.
+#[derive(Debug)]
+struct Used<'a> {
+    kind: Kind,
+
+    /// As requested.
+    name: String,
+
+    /// Of conf, as matched.
+    pattern: &'a str,
+    deprecation: &'a conf::Deprecation,
+}
+
+/// Of a date in conf, as "2025-06-30", its start, in UTC.
+pub fn sunset(date: &str) -> anyhow::Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+    Ok(date.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// Of the request the handler serves, the model, as requested.
+pub fn note_model(model: &str) {
+    let _ = MODEL.try_with(|noted| {
+        *lock(noted) = Some(model.to_string());
+    });
+}
+
+/// Tells clients of what their requests used which is deprecated, and
+/// counts it for the user. Must run after auth.
+pub(crate) async fn layer(
+    State(AppState { storage, .. }): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Response {
+    let conf = conf::global();
+    let Some(deprecations) = &conf.deprecations else {
+        return next.run(req).await;
+    };
+    let route = version::route(req.extensions(), uri.path()).to_string();
+    let (mut resp, model) = noting_model(next.run(req)).await;
+    let used = find(deprecations, Some(route), model);
+    if used.is_empty() {
+        return resp;
+    }
+    tell(resp.headers_mut(), &used);
+    count(storage, used);
+    resp
+}
+
+/// Counts the deprecated model the handling uses, if any, for the user, of
+/// requests handled other than through [`layer`], such as batch items and
+/// WebSocket messages. Must run within [`USER`].
+pub(crate) async fn count_model<F: Future>(
+    storage: Arc<dyn Storage>,
+    handling: F,
+) -> F::Output {
+    let conf = conf::global();
+    let Some(deprecations) = &conf.deprecations else {
+        return handling.await;
+    };
+    let (output, model) = noting_model(handling).await;
+    let used = find(deprecations, None, model);
+    if !used.is_empty() {
+        count(storage, used);
+    }
+    output
+}
+
+async fn noting_model<F: Future>(handling: F) -> (F::Output, Option<String>) {
+    let noted = Arc::new(Mutex::new(None));
+    let output = MODEL.scope(noted.clone(), handling).await;
+    let model = lock(&noted).take();
+    (output, model)
+}
+
+/// Of each used, for the user, and in metrics.
+fn count(storage: Arc<dyn Storage>, used: Vec<Used>) {
+    let uid = USER.get().uid;
+    let now = unix_now_secs();
+    let names: Vec<(Kind, String)> = used
+        .into_iter()
+        .map(|used| {
+            metrics::counter!(
+                "raskol_deprecated_requests_total",
+                "kind" => used.kind.as_str(),
+                "pattern" => used.pattern.to_string()
+            )
+            .increment(1);
+            (used.kind, used.name)
+        })
+        .collect();
+    // Not to hold up the response.
+    tokio::spawn(async move {
+        for (kind, name) in names {
+            if let Err(error) = storage
+                .deprecated_usage_add(&uid, kind.as_str(), &name, now)
+                .await
+            {
+                tracing::error!(?error, "Failed to count deprecated usage.");
+            }
+        }
+    });
+}
+
+/// The most specific pattern matching each: exact ones over prefixes, and
+/// longer prefixes over shorter ones.
+fn find(
+    deprecations: &conf::Deprecations,
+    route: Option<String>,
+    model: Option<String>,
+) -> Vec<Used<'_>> {
+    [
+        (Kind::Route, &deprecations.routes, route),
+        (Kind::Model, &deprecations.models, model),
+    ]
+    .into_iter()
+    .filter_map(|(kind, deprecated, name)| {
+        let name = name?;
+        let (pattern, deprecation) = deprecated
+            .iter()
+            .filter(|(pattern, _)| conf::matches(pattern, &name))
+            .max_by_key(|(pattern, _)| {
+                (!pattern.ends_with('*'), pattern.len())
+            })?;
+        Some(Used {
+            kind,
+            name,
+            pattern,
+            deprecation,
+        })
+    })
+    .collect()
+}
+
+/// With the soonest sunset of those used, and a warning of each.
+fn tell(headers: &mut HeaderMap, used: &[Used]) {
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    let soonest = used
+        .iter()
+        .filter_map(|used| used.deprecation.sunset.as_deref())
+        .filter_map(|date| sunset(date).ok())
+        .min();
+    if let Some(soonest) = soonest {
+        let date = soonest.format(HTTP_DATE).to_string();
+        if let Ok(date) = HeaderValue::from_str(&date) {
+            headers.insert("sunset", date);
+        }
+    }
+    for used in used {
+        if let (Kind::Route, Some(successor)) =
+            (used.kind, &used.deprecation.successor)
+        {
+            let link = format!("<{successor}>; rel=\"successor-version\"");
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                headers.append(header::LINK, link);
+            }
+        }
+        // Of code 299, "miscellaneous persistent warning", per RFC 7234.
+        let warning = format!("299 raskol {:?}", warning(used));
+        if let Ok(warning) = HeaderValue::from_str(&warning) {
+            headers.append(header::WARNING, warning);
+        }
+    }
+}
+
+fn warning(used: &Used) -> String {
+    let kind = match used.kind {
+        Kind::Model => "Model",
+        Kind::Route => "Route",
+    };
+    let sunset = used
+        .deprecation
+        .sunset
+        .as_ref()
+        .map_or_else(String::new, |date| {
+            format!(", to be removed on {date}")
+        });
+    let successor = used
+        .deprecation
+        .successor
+        .as_ref()
+        .map_or_else(String::new, |successor| {
+            format!(" Use {successor:?} instead.")
+        });
+    format!("{kind} {:?} is deprecated{sunset}.{successor}", used.name)
+}
+
+fn lock(noted: &Mutex<Option<String>>) -> MutexGuard<'_, Option<String>> {
+    noted.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+
+    use crate::conf;
+
+    #[test]
+    fn tell() {
+        let mut deprecations = conf::Deprecations::default();
+        deprecations.models.insert(
+            "gpt-3.5*".to_string(),
+            conf::Deprecation {
+                sunset: Some("2025-06-30".to_string()),
+                successor: Some("gpt-4o-mini".to_string()),
+            },
+        );
+        deprecations.routes.insert(
+            "/v1/completions".to_string(),
+            conf::Deprecation {
+                sunset: Some("2025-03-01".to_string()),
+                successor: Some("/v1/chat/completions".to_string()),
+            },
+        );
+        let route = Some("/v1/chat/completions".to_string());
+        assert!(super::find(&deprecations, route.clone(), None).is_empty());
+        let model = Some("gpt-4o".to_string());
+        assert!(super::find(&deprecations, route.clone(), model).is_empty());
+
+        let model = Some("gpt-3.5-turbo".to_string());
+        let used = super::find(&deprecations, route, model);
+        assert_eq!(used.len(), 1);
+        assert_eq!(used[0].pattern, "gpt-3.5*");
+        let mut headers = HeaderMap::new();
+        super::tell(&mut headers, &used);
+        assert_eq!(headers["deprecation"], "true");
+        assert_eq!(headers["sunset"], "Mon, 30 Jun 2025 00:00:00 GMT");
+        assert_eq!(
+            headers["warning"],
+            "299 raskol \"Model \\\"gpt-3.5-turbo\\\" is deprecated, to be \
+            removed on 2025-06-30. Use \\\"gpt-4o-mini\\\" instead.\""
+        );
+        assert!(!headers.contains_key("link"));
+
+        // The soonest sunset, of both.
+        let route = Some("/v1/completions".to_string());
+        let model = Some("gpt-3.5-turbo".to_string());
+        let used = super::find(&deprecations, route, model);
+        let mut headers = HeaderMap::new();
+        super::tell(&mut headers, &used);
+        assert_eq!(headers["sunset"], "Sat, 01 Mar 2025 00:00:00 GMT");
+        assert_eq!(headers.get_all("warning").iter().count(), 2);
+        assert_eq!(
+            headers["link"],
+            "</v1/chat/completions>; rel=\"successor-version\""
+        );
+
+        assert!(super::sunset("2025-02-30").is_err());
+
+        // The most specific, though not the first in order.
+        for pattern in ["gpt-3.5-turbo*", "gpt-3.5-turbo"] {
+            deprecations
+                .models
+                .insert(pattern.to_string(), conf::Deprecation::default());
+            let model = Some("gpt-3.5-turbo".to_string());
+            let used = super::find(&deprecations, None, model);
+            assert_eq!(used[0].pattern, pattern);
+        }
+    }
+}
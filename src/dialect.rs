@@ -0,0 +1,415 @@
+//! Translation between the OpenAI chat format, which our clients speak, and
+//! Anthropic's Messages API, for providers configured with
+//! `dialect = "anthropic"`.
+//!
+//! Streamed responses are translated event by event, as they arrive, into
+//! the chunks OpenAI streams, ending with one of the usage, and "[DONE]".
+
+use axum::body::Bytes;
+
+use crate::chat;
+
+pub const ANTHROPIC_ENDPOINT: &str = "v1/messages";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Anthropic requires max_tokens, while OpenAI clients usually leave it out.
+const ANTHROPIC_MAX_TOKENS: usize = 4096;
+
+#[derive(serde::Serialize, Debug)]
+pub struct AnthropicReq {
+    model: String,
+    max_tokens: usize,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+
+    messages: Vec<AnthropicMsg>,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct AnthropicMsg {
+    role: String,
+
+    /// Text or a list of parts, both of which Anthropic accepts.
+    content: serde_json::Value,
+}
+
+impl From<&chat::Req> for AnthropicReq {
+    fn from(req: &chat::Req) -> Self {
+        // System prompts are not messages there, but a separate field.
+        let (system, messages): (Vec<_>, Vec<_>) =
+            req.messages.iter().partition(|msg| msg.role == "system");
+        let system: Vec<String> =
+            system.iter().map(|msg| msg.text()).collect();
+        Self {
+            model: req.model.clone(),
+            max_tokens: req.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages: messages
+                .into_iter()
+                .map(|msg| AnthropicMsg {
+                    role: msg.role.clone(),
+                    content: msg.content.clone(),
+                })
+                .collect(),
+            stream: false,
+        }
+    }
+}
+
+impl AnthropicReq {
+    /// To have the response sent as server-sent events.
+    #[must_use]
+    pub fn streamed(self) -> Self {
+        Self {
+            stream: true,
+            ..self
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct AnthropicResp {
+    id: String,
+    model: String,
+    content: Vec<AnthropicContent>,
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    type_: String,
+
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct AnthropicUsage {
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+/// Translates a Messages API response body to a chat completion one.
+pub fn from_anthropic_resp_body(body: &str) -> serde_json::Result<String> {
+    let resp: AnthropicResp = serde_json::from_str(body)?;
+    let content: String = resp
+        .content
+        .iter()
+        .filter(|content| content.type_ == "text")
+        .map(|content| content.text.as_str())
+        .collect();
+    let finish_reason = resp.stop_reason.as_deref().map(finish_reason);
+    let usage = &resp.usage;
+    serde_json::to_string(&serde_json::json!({
+        "id": resp.id,
+        "object": "chat.completion",
+        "created": created(),
+        "model": resp.model,
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": content,
+            },
+            "finish_reason": finish_reason,
+        }],
+        "usage": {
+            "prompt_tokens": usage.input_tokens,
+            "completion_tokens": usage.output_tokens,
+            "total_tokens": usage.input_tokens + usage.output_tokens,
+        },
+    }))
+}
+
+/// Of a Messages API stream, its events as chat completion chunks.
+#[derive(Debug, Default)]
+pub struct AnthropicEvents {
+    /// Of a line not yet complete, since chunks may end anywhere.
+    pending: Vec<u8>,
+
+    /// Of the message, as started.
+    id: String,
+    model: String,
+
+    /// As of the latest event reporting them.
+    input_tokens: usize,
+    output_tokens: usize,
+
+    /// Of the message, as reported before it stops.
+    finish_reason: Option<&'static str>,
+}
+
+impl AnthropicEvents {
+    /// Of what was received, the complete events, translated. Those with
+    /// nothing for clients, such as pings, are dropped.
+    pub fn add(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Bytes::new();
+        };
+        let complete: Vec<u8> = self.pending.drain(..=end).collect();
+        let mut translated = String::new();
+        // Of each event, its type is in the data too, so the "event:" line
+        // can be skipped.
+        for line in String::from_utf8_lossy(&complete).lines() {
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let Ok(event) =
+                serde_json::from_str::<serde_json::Value>(data.trim())
+            else {
+                continue;
+            };
+            for data in self.translate(&event) {
+                translated.push_str("data: ");
+                translated.push_str(&data);
+                translated.push_str("\n\n");
+            }
+        }
+        Bytes::from(translated)
+    }
+
+    fn translate(&mut self, event: &serde_json::Value) -> Vec<String> {
+        let tokens = |value: &serde_json::Value| {
+            value
+                .as_u64()
+                .and_then(|tokens| usize::try_from(tokens).ok())
+        };
+        match event["type"].as_str() {
+            Some("message_start") => {
+                let message = &event["message"];
+                let text = |value: &serde_json::Value| {
+                    value.as_str().unwrap_or_default().to_string()
+                };
+                self.id = text(&message["id"]);
+                self.model = text(&message["model"]);
+                let usage = &message["usage"];
+                self.input_tokens =
+                    tokens(&usage["input_tokens"]).unwrap_or_default();
+                self.output_tokens =
+                    tokens(&usage["output_tokens"]).unwrap_or_default();
+                let delta = serde_json::json!({
+                    "role": "assistant",
+                    "content": "",
+                });
+                vec![self.chunk(&delta, None)]
+            }
+            Some("content_block_delta") => {
+                let delta = &event["delta"];
+                match delta["text"].as_str() {
+                    Some(text) if delta["type"] == "text_delta" => {
+                        let delta = serde_json::json!({"content": text});
+                        vec![self.chunk(&delta, None)]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            Some("message_delta") => {
+                if let Some(output_tokens) =
+                    tokens(&event["usage"]["output_tokens"])
+                {
+                    self.output_tokens = output_tokens;
+                }
+                if let Some(reason) = event["delta"]["stop_reason"].as_str() {
+                    self.finish_reason = Some(finish_reason(reason));
+                }
+                Vec::new()
+            }
+            Some("message_stop") => {
+                let finish_reason = self.finish_reason.unwrap_or("stop");
+                let usage = serde_json::json!({
+                    "id": self.id,
+                    "object": "chat.completion.chunk",
+                    "created": created(),
+                    "model": self.model,
+                    "choices": [],
+                    "usage": {
+                        "prompt_tokens": self.input_tokens,
+                        "completion_tokens": self.output_tokens,
+                        "total_tokens":
+                            self.input_tokens + self.output_tokens,
+                    },
+                });
+                vec![
+                    self.chunk(&serde_json::json!({}), Some(finish_reason)),
+                    usage.to_string(),
+                    "[DONE]".to_string(),
+                ]
+            }
+            Some("error") => {
+                let error = &event["error"];
+                let error = serde_json::json!({
+                    "error": {
+                        "message": error["message"],
+                        "type": error["type"],
+                        "code": null,
+                    },
+                });
+                vec![error.to_string()]
+            }
+            // Pings, and the start and stop of content blocks.
+            _ => Vec::new(),
+        }
+    }
+
+    fn chunk(
+        &self,
+        delta: &serde_json::Value,
+        finish_reason: Option<&str>,
+    ) -> String {
+        serde_json::json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": created(),
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }],
+        })
+        .to_string()
+    }
+}
+
+/// Of Anthropic's stop reason, OpenAI's.
+fn finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        // end_turn, stop_sequence
+        _ => "stop",
+    }
+}
+
+fn created() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{chat, streaming};
+
+    use super::{from_anthropic_resp_body, AnthropicEvents, AnthropicReq};
+
+    #[test]
+    fn req() {
+        let msg = |role: &str, content: &str| chat::Msg {
+            role: role.to_string(),
+            content: content.into(),
+            name: None,
+            rest: serde_json::Map::new(),
+        };
+        let req = chat::Req {
+            model: "claude".to_string(),
+            messages: vec![msg("system", "Be brief."), msg("user", "Hi")],
+            max_tokens: None,
+            temperature: None,
+            rest: serde_json::Map::new(),
+        };
+        let req = serde_json::to_value(AnthropicReq::from(&req)).unwrap();
+        assert_eq!(
+            req,
+            serde_json::json!({
+                "model": "claude",
+                "max_tokens": 4096,
+                "system": "Be brief.",
+                "messages": [{"role": "user", "content": "Hi"}],
+            })
+        );
+    }
+
+    #[test]
+    fn resp() {
+        let body = r#"{
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude",
+            "content": [{"type": "text", "text": "Hello"}],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 3, "output_tokens": 5}
+        }"#;
+        let body = from_anthropic_resp_body(body).unwrap();
+        let usage = chat::Usage::from_resp_body(&body).unwrap();
+        assert_eq!(usage.total_tokens, 8);
+        let resp: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(resp["choices"][0]["message"]["content"], "Hello");
+        assert_eq!(resp["choices"][0]["finish_reason"], "length");
+    }
+
+    #[test]
+    fn events() {
+        let stream = concat!(
+            "event: message_start\n",
+            r#"data: {"type":"message_start","message":{"id":"msg_1","#,
+            r#""model":"claude","usage":{"input_tokens":3,"#,
+            r#""output_tokens":1}}}"#,
+            "\n\n",
+            "event: ping\n",
+            r#"data: {"type":"ping"}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_delta","index":0,"#,
+            r#""delta":{"type":"text_delta","text":"Hel"}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_delta","index":0,"#,
+            r#""delta":{"type":"text_delta","text":"lo"}}"#,
+            "\n\n",
+            r#"data: {"type":"message_delta","#,
+            r#""delta":{"stop_reason":"max_tokens"},"#,
+            r#""usage":{"output_tokens":5}}"#,
+            "\n\n",
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        );
+        let mut events = AnthropicEvents::default();
+        let mut tally = streaming::Tally::default();
+        let mut translated = String::new();
+        // However the chunks happen to be cut.
+        for chunk in stream.as_bytes().chunks(7) {
+            let chunk = events.add(chunk);
+            tally.add(&chunk);
+            translated.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert!(tally.is_done);
+        assert!(tally.error.is_none());
+        assert_eq!(tally.completion_tokens, 2);
+        let usage = tally.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (3, 5));
+        let chunks: Vec<serde_json::Value> = translated
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str(data).ok())
+            .collect();
+        let content: String = chunks
+            .iter()
+            .filter_map(|chunk| {
+                chunk["choices"][0]["delta"]["content"].as_str()
+            })
+            .collect();
+        assert_eq!(content, "Hello");
+        assert_eq!(chunks[0]["object"], "chat.completion.chunk");
+        assert_eq!(chunks[0]["id"], "msg_1");
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "length");
+
+        let mut events = AnthropicEvents::default();
+        let error = concat!(
+            "event: error\n",
+            r#"data: {"type":"error","error":{"type":"overloaded_error","#,
+            r#""message":"Overloaded"}}"#,
+            "\n\n",
+        );
+        let mut tally = streaming::Tally::default();
+        tally.add(&events.add(error.as_bytes()));
+        assert_eq!(tally.error.as_deref(), Some("Overloaded"));
+    }
+}
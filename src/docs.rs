@@ -0,0 +1,154 @@
+//! OpenAPI description of the routes we serve, at `/openapi.json` and
+//! `/openapi.yaml`. Each router module describes its own routes as `Docs`,
+//! merged here for those mounted. Legacy routes, without the version
+//! prefix, are left out.
+
+use std::future;
+
+use anyhow::Context;
+use axum::{http::header, routing::get, Router};
+use utoipa::{
+    openapi::{
+        self,
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+    Modify, OpenApi,
+};
+
+use crate::{
+    admin, batch,
+    conf::Conf,
+    preferences, prober, public,
+    server::{self, AppState, Routes},
+    signup, template, version, ws,
+};
+
+/// Name of the security scheme of the routes which need a token.
+pub const BEARER: &str = "bearer";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "raskol",
+        description = "Share 1 API key with N group members without \
+            revealing it."
+    ),
+    modifiers(&Bearer),
+    security(("bearer" = []))
+)]
+struct Root;
+
+/// Our JWTs, or API keys, as `Authorization: Bearer <token>`.
+struct Bearer;
+
+impl Modify for Bearer {
+    fn modify(&self, doc: &mut openapi::OpenApi) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .bearer_format("JWT")
+            .build();
+        doc.components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(BEARER, SecurityScheme::Http(scheme));
+    }
+}
+
+/// Of the given routes, as mounted with the conf.
+#[must_use]
+pub fn openapi(conf: &Conf, routes: &[Routes]) -> openapi::OpenApi {
+    let mut doc = Root::openapi();
+    let mut versioned = openapi::OpenApi::default();
+    for routes in routes {
+        match routes {
+            Routes::Public => {
+                doc.merge(server::PublicDocs::openapi());
+                if conf.signup.is_some() {
+                    versioned.merge(signup::Docs::openapi());
+                }
+                if conf.prober.is_some() {
+                    doc.merge(prober::Docs::openapi());
+                }
+                if conf.public_stats.is_some() {
+                    doc.merge(public::Docs::openapi());
+                }
+            }
+            Routes::Admin => {
+                versioned = versioned.nest("/admin", admin::Docs::openapi());
+            }
+            Routes::Stats => {
+                doc.merge(server::ModelsDocs::openapi());
+                versioned.merge(server::StatsDocs::openapi());
+                versioned.merge(template::Docs::openapi());
+                versioned.merge(preferences::Docs::openapi());
+                versioned.merge(batch::Docs::openapi());
+            }
+            Routes::Api => {
+                doc.merge(server::ApiDocs::openapi());
+                versioned.merge(server::TemplateDocs::openapi());
+                versioned.merge(ws::Docs::openapi());
+            }
+        }
+    }
+    doc.nest(version::PREFIX, versioned)
+}
+
+/// Rendered once, since the routes don't change while we serve them.
+pub(crate) fn routes(
+    conf: &Conf,
+    routes: &[Routes],
+) -> anyhow::Result<Router<AppState>> {
+    let doc = openapi(conf, routes);
+    let json = doc
+        .to_pretty_json()
+        .context("Failed to render OpenAPI as JSON.")?;
+    let yaml = doc.to_yaml().context("Failed to render OpenAPI as YAML.")?;
+    Ok(Router::new()
+        .route(
+            "/openapi.json",
+            get(move || {
+                let content_type =
+                    [(header::CONTENT_TYPE, "application/json")];
+                future::ready((content_type, json.clone()))
+            }),
+        )
+        .route(
+            "/openapi.yaml",
+            get(move || {
+                let content_type =
+                    [(header::CONTENT_TYPE, "application/yaml")];
+                future::ready((content_type, yaml.clone()))
+            }),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::openapi;
+    use crate::{conf::Conf, server::Routes};
+
+    #[test]
+    fn paths() {
+        let doc = openapi(&Conf::default(), &Routes::ALL);
+        for path in [
+            "/ping",
+            "/v1/quota",
+            "/v1/stats/latency",
+            "/v1/sessions/{session}",
+            "/v1/chat/completions",
+            "/openai/v1/chat/completions",
+            "/v1/models",
+            "/v1/admin/logs",
+            "/v1/admin/users/{uid}",
+            "/v1/batches",
+            "/v1/preferences",
+            "/v1/templates/{name}/complete",
+        ] {
+            assert!(doc.paths.paths.contains_key(path), "{path}");
+        }
+        assert!(!doc.paths.paths.contains_key("/v1/signup"));
+        assert!(!doc.paths.paths.contains_key("/quota"));
+        assert!(!doc.paths.paths.contains_key("/status"));
+        let doc = openapi(&Conf::default(), &[Routes::Public]);
+        assert!(!doc.paths.paths.contains_key("/v1/quota"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Rejecting chat requests which repeat the same prompt too often, per
+//! [`conf::Duplicates`], since agents stuck in a loop retry identical calls
+//! until the budget is gone. Prompts are the same when their model and
+//! messages are, regardless of case and of runs of whitespace in the text.
+//!
+//! Retries of a request with the same Idempotency-Key are not repeats, but
+//! left to be replayed.
+//!
+//! In-memory, as the per-IP rate limits, so counted per instance.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{chat, conf};
+
+/// Above this many tracked users, those with nothing recent are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// Recent prompts, per user.
+#[derive(Default)]
+pub struct Duplicates {
+    seen: Mutex<HashMap<String, VecDeque<Seen>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Seen {
+    fingerprint: [u8; 32],
+
+    /// Idempotency-Key, if any.
+    key: Option<String>,
+
+    /// Since the Unix epoch.
+    at: Duration,
+}
+
+/// Of the requests with the same prompt, within the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejection {
+    pub requests: usize,
+    pub retry_after: Duration,
+}
+
+impl Duplicates {
+    /// Counts the request, unless there were already as many with the same
+    /// prompt as allowed, as of now, since the Unix epoch, or it is a retry
+    /// of one with the same idempotency key.
+    pub fn check(
+        &self,
+        conf: &conf::Duplicates,
+        uid: &str,
+        req: &chat::Req,
+        key: Option<&str>,
+        now: Duration,
+    ) -> Result<(), Rejection> {
+        let window = Duration::from_secs(conf.window);
+        let is_recent = |seen: &Seen| now.saturating_sub(seen.at) < window;
+        let fingerprint = fingerprint(req);
+        let mut seen =
+            self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.len() >= MAX_TRACKED {
+            seen.retain(|_, recent| recent.back().is_some_and(is_recent));
+        }
+        let recent = seen.entry(uid.to_string()).or_default();
+        while recent.front().is_some_and(|seen| !is_recent(seen)) {
+            recent.pop_front();
+        }
+        let is_retry = key.is_some()
+            && recent.iter().any(|seen| {
+                seen.fingerprint == fingerprint && seen.key.as_deref() == key
+            });
+        if is_retry {
+            return Ok(());
+        }
+        let mut same = recent
+            .iter()
+            .filter(|seen| seen.fingerprint == fingerprint)
+            .peekable();
+        let first = same.peek().map(|seen| seen.at);
+        let requests = same.count();
+        if let Some(first) = first.filter(|_| requests >= conf.max_requests) {
+            return Err(Rejection {
+                requests,
+                retry_after: (first + window).saturating_sub(now),
+            });
+        }
+        recent.push_back(Seen {
+            fingerprint,
+            key: key.map(str::to_string),
+            at: now,
+        });
+        Ok(())
+    }
+}
+
+fn fingerprint(req: &chat::Req) -> [u8; 32] {
+    let mut hash = Sha256::new().chain_update(req.model.as_bytes());
+    for msg in &req.messages {
+        hash.update(b"\0");
+        hash.update(msg.role.as_bytes());
+        hash.update(b"\0");
+        hash.update(normalize(&msg.text()).as_bytes());
+        hash.update(b"\0");
+        // Tool calls and their results, whose repeats are loops too.
+        hash.update(serde_json::to_vec(&msg.rest).unwrap_or_default());
+    }
+    hash.finalize().into()
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Duplicates, Rejection};
+    use crate::{chat, conf};
+
+    fn req(content: &str) -> chat::Req {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": content}],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn duplicates() {
+        let conf = conf::Duplicates {
+            window: 60,
+            max_requests: 2,
+        };
+        let duplicates = Duplicates::default();
+        let at = Duration::from_secs;
+        let check = |content, uid, now| {
+            duplicates.check(&conf, uid, &req(content), None, now)
+        };
+        assert_eq!(check("Hello there", "a", at(1000)), Ok(()));
+        assert_eq!(check("hello   THERE ", "a", at(1010)), Ok(()));
+        assert_eq!(
+            check("Hello there", "a", at(1020)),
+            Err(Rejection {
+                requests: 2,
+                retry_after: at(40),
+            })
+        );
+        assert_eq!(check("Hello there", "b", at(1020)), Ok(()));
+        assert_eq!(check("Something else", "a", at(1020)), Ok(()));
+        assert_eq!(check("Hello there", "a", at(1060)), Ok(()));
+        assert!(check("Hello there", "a", at(1065)).is_err());
+
+        // Retries of one with a key, but not others with keys.
+        let check = |key, now| {
+            duplicates.check(&conf, "c", &req("Hi"), Some(key), now)
+        };
+        assert_eq!(check("x", at(1000)), Ok(()));
+        assert_eq!(check("x", at(1001)), Ok(()));
+        assert_eq!(check("x", at(1002)), Ok(()));
+        assert_eq!(check("y", at(1003)), Ok(()));
+        assert!(check("z", at(1004)).is_err());
+        assert_eq!(check("y", at(1005)), Ok(()));
+    }
+}
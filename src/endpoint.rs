@@ -0,0 +1,216 @@
+//! Which kind of endpoint a request under `/` is for, which decides how it
+//! is parsed and accounted for.
+
+use axum::{
+    body::Bytes,
+    extract::Request,
+    http::{header, StatusCode},
+};
+
+use crate::{audio, conf, server};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Parsed, so that the messages can be tokenized and rewritten.
+    Chat,
+
+    /// Multipart uploads, charged by their duration.
+    Audio,
+
+    /// Any other, if configured to be passed through. Forwarded as is and
+    /// charged by the usage upstream reports, or else by size.
+    Raw,
+}
+
+impl Kind {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Chat => "chat",
+            Self::Audio => "audio",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+/// `None` if the endpoint is not one we know, nor one to pass through.
+#[must_use]
+pub fn classify(conf: &conf::Conf, endpoint: &str) -> Option<Kind> {
+    if endpoint.ends_with("chat/completions") {
+        Some(Kind::Chat)
+    } else if audio::is_endpoint(endpoint) {
+        Some(Kind::Audio)
+    } else if conf
+        .passthrough
+        .endpoints
+        .iter()
+        .any(|pattern| conf::matches(pattern, endpoint))
+    {
+        Some(Kind::Raw)
+    } else {
+        None
+    }
+}
+
+/// Of the request body: the role's, else the endpoint's, else the kind's.
+#[must_use]
+pub fn max_body_bytes(
+    conf: &conf::Conf,
+    role: &str,
+    endpoint: &str,
+    kind: Kind,
+) -> usize {
+    if let Some(max_bytes) = conf
+        .limits
+        .per_role
+        .get(role)
+        .and_then(|limits| limits.max_body_bytes)
+    {
+        return max_bytes;
+    }
+    if let Some(max_bytes) = conf
+        .limits
+        .max_body_bytes_per_endpoint
+        .iter()
+        .find(|(pattern, _)| conf::matches(pattern, endpoint))
+        .map(|(_, max_bytes)| *max_bytes)
+    {
+        return max_bytes;
+    }
+    match kind {
+        Kind::Chat => conf.limits.max_body_bytes,
+        Kind::Audio => conf.audio.max_upload_bytes,
+        Kind::Raw => conf.passthrough.max_body_bytes,
+    }
+}
+
+/// Of a request to pass through.
+pub struct Raw {
+    /// Empty if the body doesn't name one.
+    pub model: String,
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+impl Raw {
+    pub async fn from_req(
+        req: Request,
+        max_bytes: usize,
+    ) -> Result<Self, StatusCode> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/json")
+            .to_string();
+        let body = axum::body::to_bytes(req.into_body(), max_bytes)
+            .await
+            .map_err(|error| {
+                tracing::warn!(?error, "Failed to read request body.");
+                server::body_unread(&error)
+            })?;
+        Ok(Self {
+            model: model(&body).unwrap_or_default(),
+            content_type,
+            body,
+        })
+    }
+
+    /// From the size, until upstream reports the usage.
+    #[must_use]
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    pub fn tokens_estimate(&self, conf: &conf::Passthrough) -> usize {
+        let bytes = self.body.len() as f64;
+        (bytes / conf.bytes_per_token.max(f64::MIN_POSITIVE))
+            .ceil()
+            .max(0.0) as usize
+    }
+}
+
+/// Of a JSON body.
+fn model(body: &[u8]) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Req {
+        model: String,
+    }
+
+    serde_json::from_slice::<Req>(body)
+        .ok()
+        .map(|req| req.model)
+}
+
+/// Input and output tokens, as reported in OpenAI-style responses, of which
+/// some (such as embeddings) have no output.
+#[must_use]
+pub fn usage_from_resp_body(body: &str) -> Option<(usize, usize)> {
+    #[derive(serde::Deserialize)]
+    struct Resp {
+        usage: Usage,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Usage {
+        prompt_tokens: usize,
+        #[serde(default)]
+        completion_tokens: usize,
+    }
+
+    let usage = serde_json::from_str::<Resp>(body).ok()?.usage;
+    Some((usage.prompt_tokens, usage.completion_tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, max_body_bytes, usage_from_resp_body, Kind};
+    use crate::conf::{Conf, RoleLimits};
+
+    #[test]
+    fn kinds() {
+        let mut conf = Conf::default();
+        conf.passthrough.endpoints = vec!["v1/embeddings*".to_string()];
+        let kind = |endpoint| classify(&conf, endpoint);
+        assert_eq!(kind("v1/chat/completions"), Some(Kind::Chat));
+        assert_eq!(kind("v1/audio/transcriptions"), Some(Kind::Audio));
+        assert_eq!(kind("v1/embeddings"), Some(Kind::Raw));
+        assert_eq!(kind("v1/fine_tuning/jobs"), None);
+    }
+
+    #[test]
+    fn body_limits() {
+        let mut conf = Conf::default();
+        conf.limits.max_body_bytes = 100;
+        conf.audio.max_upload_bytes = 1000;
+        conf.limits
+            .max_body_bytes_per_endpoint
+            .insert("v1/chat/*".to_string(), 200);
+        conf.limits.per_role.insert(
+            "judge".to_string(),
+            RoleLimits {
+                max_body_bytes: Some(50),
+                ..RoleLimits::default()
+            },
+        );
+        let max = |role, endpoint, kind| {
+            max_body_bytes(&conf, role, endpoint, kind)
+        };
+        assert_eq!(max("hacker", "chat/completions", Kind::Chat), 100);
+        assert_eq!(max("hacker", "v1/chat/completions", Kind::Chat), 200);
+        assert_eq!(
+            max("hacker", "v1/audio/transcriptions", Kind::Audio),
+            1000
+        );
+        assert_eq!(max("judge", "v1/audio/transcriptions", Kind::Audio), 50);
+    }
+
+    #[test]
+    fn usage() {
+        let embeddings =
+            r#"{"usage": {"prompt_tokens": 8, "total_tokens": 8}}"#;
+        assert_eq!(usage_from_resp_body(embeddings), Some((8, 0)));
+        assert_eq!(usage_from_resp_body(r#"{"data": []}"#), None);
+    }
+}
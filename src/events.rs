@@ -0,0 +1,100 @@
+//! Live feed of what the server is doing, for admins to watch at
+//! `/v1/admin/events`.
+
+use tokio::sync::broadcast;
+
+use crate::data::{RequestLog, Suspension};
+
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    RequestStarted {
+        req_id: String,
+        uid: String,
+        model: String,
+        endpoint: String,
+    },
+    RequestFinished(RequestLog),
+    BudgetRejected(RequestLog),
+    UpstreamError {
+        req_id: String,
+        uid: String,
+        model: String,
+        status: u16,
+    },
+    Suspended(Suspension),
+
+    /// Of a provider, or all of them ("total"), per [`crate::spend`].
+    SpendCapped {
+        cap: String,
+        date: String,
+        tokens: usize,
+        cost: f64,
+    },
+}
+
+impl Event {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RequestStarted { .. } => "request_started",
+            Self::RequestFinished(_) => "request_finished",
+            Self::BudgetRejected(_) => "budget_rejected",
+            Self::UpstreamError { .. } => "upstream_error",
+            Self::Suspended(_) => "suspended",
+            Self::SpendCapped { .. } => "spend_capped",
+        }
+    }
+}
+
+/// Fans events out to however many are watching. Nothing is kept when
+/// nobody is, and those who fall behind miss the oldest events.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        // Fails only when nobody is watching, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Events};
+
+    #[tokio::test]
+    async fn fan_out() {
+        let started = |req_id: &str| Event::RequestStarted {
+            req_id: req_id.to_string(),
+            uid: "alice".to_string(),
+            model: "llama".to_string(),
+            endpoint: "v1/chat/completions".to_string(),
+        };
+        let events = Events::new(2);
+        events.publish(started("unwatched"));
+        let mut a = events.subscribe();
+        let mut b = events.subscribe();
+        events.publish(started("1"));
+        for receiver in [&mut a, &mut b] {
+            let event = receiver.recv().await.unwrap();
+            let event = serde_json::to_value(event).unwrap();
+            assert_eq!(event["type"], "request_started");
+            assert_eq!(event["req_id"], "1");
+        }
+        assert!(a.try_recv().is_err());
+    }
+}
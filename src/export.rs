@@ -0,0 +1,210 @@
+//! Request logs streamed out of storage into files, for analysis elsewhere,
+//! such as in DuckDB or pandas, after the event. Rows are written as read,
+//! in batches for Parquet, so that exports of any size fit in memory.
+
+use std::io::Write;
+
+use futures_util::{stream::BoxStream, StreamExt};
+
+use crate::data::{self, RequestLog, Storage};
+
+/// Of the days, YYYY-MM-DD, UTC, inclusive, oldest first.
+pub fn request_logs<'a>(
+    storage: &'a dyn Storage,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<BoxStream<'a, anyhow::Result<RequestLog>>> {
+    let (time_from, _) = data::date_bounds(from)?;
+    let (_, time_to) = data::date_bounds(to)?;
+    Ok(storage.request_logs_stream(time_from, time_to))
+}
+
+/// One JSON object per line. Returns the number of rows.
+pub async fn jsonl(
+    mut logs: BoxStream<'_, anyhow::Result<RequestLog>>,
+    mut out: impl Write,
+) -> anyhow::Result<u64> {
+    let mut rows = 0;
+    while let Some(log) = logs.next().await {
+        serde_json::to_writer(&mut out, &log?)?;
+        writeln!(out)?;
+        rows += 1;
+    }
+    out.flush()?;
+    Ok(rows)
+}
+
+#[cfg(feature = "parquet")]
+pub use columnar::parquet;
+
+#[cfg(feature = "parquet")]
+mod columnar {
+    use std::{io::Write, sync::Arc};
+
+    use ::parquet::{
+        arrow::ArrowWriter, basic::Compression,
+        file::properties::WriterProperties,
+    };
+    use arrow_array::{
+        ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray,
+    };
+    use arrow_schema::{DataType, Field, Schema};
+    use futures_util::{stream::BoxStream, StreamExt};
+
+    use crate::data::{ErrorClass, RequestLog};
+
+    /// Of rows, converted to columns at a time.
+    const BATCH: usize = 8192;
+
+    /// Of rows, buffered before being flushed to the file.
+    const ROW_GROUP: usize = 8 * BATCH;
+
+    /// With the timing flattened into columns. Returns the number of rows.
+    pub async fn parquet(
+        mut logs: BoxStream<'_, anyhow::Result<RequestLog>>,
+        out: impl Write + Send,
+    ) -> anyhow::Result<u64> {
+        let schema = Arc::new(schema());
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(out, schema.clone(), Some(props))?;
+        let mut rows = 0;
+        let mut batch = Vec::with_capacity(BATCH);
+        while let Some(log) = logs.next().await {
+            batch.push(log?);
+            if batch.len() == BATCH {
+                writer.write(&record_batch(&schema, &batch)?)?;
+                rows += batch.len() as u64;
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            writer.write(&record_batch(&schema, &batch)?)?;
+            rows += batch.len() as u64;
+        }
+        writer.close()?;
+        Ok(rows)
+    }
+
+    fn schema() -> Schema {
+        let string =
+            |name, nullable| Field::new(name, DataType::Utf8, nullable);
+        let int = |name| Field::new(name, DataType::Int64, false);
+        let float =
+            |name, nullable| Field::new(name, DataType::Float64, nullable);
+        Schema::new(vec![
+            string("req_id", false),
+            string("uid", false),
+            string("model", false),
+            string("endpoint", false),
+            int("status"),
+            int("input_tokens"),
+            int("output_tokens"),
+            float("cost", false),
+            int("duration_ms"),
+            int("time"),
+            string("error_message", true),
+            string("session", true),
+            string("experiment", true),
+            string("arm", true),
+            string("error_class", true),
+            string("client_ip", true),
+            float("queue_ms", true),
+            float("prompt_ms", true),
+            float("completion_ms", true),
+        ])
+    }
+
+    /// In the order of [`schema`].
+    fn record_batch(
+        schema: &Arc<Schema>,
+        logs: &[RequestLog],
+    ) -> anyhow::Result<RecordBatch> {
+        let strings = |f: fn(&RequestLog) -> &str| -> ArrayRef {
+            Arc::new(StringArray::from_iter_values(logs.iter().map(f)))
+        };
+        let optional_strings =
+            |f: fn(&RequestLog) -> Option<&str>| -> ArrayRef {
+                Arc::new(logs.iter().map(f).collect::<StringArray>())
+            };
+        let ints = |f: fn(&RequestLog) -> i64| -> ArrayRef {
+            Arc::new(Int64Array::from_iter_values(logs.iter().map(f)))
+        };
+        let floats = |f: fn(&RequestLog) -> Option<f64>| -> ArrayRef {
+            Arc::new(logs.iter().map(f).collect::<Float64Array>())
+        };
+        let columns = vec![
+            strings(|log| log.req_id.as_str()),
+            strings(|log| log.uid.as_str()),
+            strings(|log| log.model.as_str()),
+            strings(|log| log.endpoint.as_str()),
+            ints(|log| log.status),
+            ints(|log| log.input_tokens),
+            ints(|log| log.output_tokens),
+            floats(|log| Some(log.cost)),
+            ints(|log| log.duration_ms),
+            ints(|log| log.time),
+            optional_strings(|log| log.error_message.as_deref()),
+            optional_strings(|log| log.session.as_deref()),
+            optional_strings(|log| log.experiment.as_deref()),
+            optional_strings(|log| log.arm.as_deref()),
+            optional_strings(|log| log.error_class.map(ErrorClass::as_str)),
+            optional_strings(|log| log.client_ip.as_deref()),
+            floats(|log| log.timing.map(|timing| timing.queue_ms)),
+            floats(|log| log.timing.map(|timing| timing.prompt_ms)),
+            floats(|log| log.timing.map(|timing| timing.completion_ms)),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use futures_util::{stream, StreamExt};
+
+    use crate::data::RequestLog;
+
+    fn log(req_id: &str) -> RequestLog {
+        RequestLog {
+            req_id: req_id.to_string(),
+            uid: "alice".to_string(),
+            model: "gpt-4o".to_string(),
+            endpoint: "chat".to_string(),
+            status: 200,
+            input_tokens: 10,
+            output_tokens: 20,
+            cost: 0.01,
+            duration_ms: 300,
+            time: 1_750_000_000,
+            error_message: None,
+            session: None,
+            experiment: None,
+            arm: None,
+            timing: None,
+            error_class: None,
+            client_ip: None,
+            tags: BTreeMap::new(),
+            usage: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn jsonl() {
+        let logs = stream::iter([Ok(log("a")), Ok(log("b"))]).boxed();
+        let mut out = Vec::new();
+        assert_eq!(super::jsonl(logs, &mut out).await.unwrap(), 2);
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["req_id"], "b");
+        assert_eq!(lines[1]["output_tokens"], 20);
+    }
+}
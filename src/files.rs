@@ -0,0 +1,53 @@
+//! Multipart uploads to provider file APIs, such as OpenAI's `/v1/files`.
+//! Unlike audio uploads, these are streamed upstream as they arrive, since
+//! files can be far bigger than we'd want to hold in memory.
+
+use axum::{body::Body, http::HeaderMap};
+use futures_util::StreamExt;
+
+use crate::conf::Conf;
+
+/// With the boundary, if the request is multipart at all.
+#[must_use]
+pub fn content_type(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("multipart/"))
+}
+
+/// Of the role, or the global default.
+#[must_use]
+pub fn max_upload_bytes(conf: &Conf, role: &str) -> usize {
+    conf.limits
+        .per_role
+        .get(role)
+        .and_then(|limits| limits.max_upload_bytes)
+        .unwrap_or(conf.files.max_upload_bytes)
+}
+
+/// Declared, which need not be true, so the body is limited regardless.
+#[must_use]
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// The body, failing once more than `max_bytes` of it passed through, which
+/// aborts the upstream request mid-way.
+#[must_use]
+pub fn limited(body: Body, max_bytes: usize) -> reqwest::Body {
+    let mut seen: usize = 0;
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        seen = seen.saturating_add(chunk.len());
+        if seen > max_bytes {
+            tracing::warn!(seen, max_bytes, "Upload too large. Aborting.");
+            return Err(std::io::Error::other("upload too large"));
+        }
+        Ok(chunk)
+    });
+    reqwest::Body::wrap_stream(stream)
+}
@@ -0,0 +1,285 @@
+//! Checking of chat completions against [`conf::ResponseFilter`] before they
+//! are returned, for venues which must answer for what the models say, such
+//! as those with minors. Of whole completions only, so streamed ones are
+//! asked of upstream whole while the filter is on, and streamed to the
+//! client once checked.
+
+use regex::Regex;
+
+use crate::{chat, conf};
+
+const REPLACEMENT: &str = "[REDACTED]";
+
+static GLOBAL: conf::Derived<Option<Filter>> =
+    conf::Derived::new(|conf| conf.response_filter.as_ref().map(Filter::new));
+
+/// Of the global conf, unless off.
+#[must_use]
+pub fn global() -> Option<&'static Filter> {
+    GLOBAL.get().as_ref()
+}
+
+pub struct Filter {
+    max_chars: Option<usize>,
+    banned: Vec<Regex>,
+    is_json_checked: bool,
+    pub action: conf::FilterAction,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Of the choice.
+    pub index: usize,
+    pub rule: Rule,
+    pub detail: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    MaxChars,
+    Banned,
+    Json,
+}
+
+/// Of a response which violated the rules.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// To be returned instead.
+    Altered {
+        body: String,
+        violations: Vec<Violation>,
+    },
+    Rejected {
+        violations: Vec<Violation>,
+    },
+
+    /// As is, since the action can't mend them, as invalid JSON when
+    /// redacting.
+    Unaltered {
+        violations: Vec<Violation>,
+    },
+}
+
+impl Rule {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MaxChars => "max_chars",
+            Self::Banned => "banned",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl Filter {
+    /// Invalid patterns are skipped, with an error logged, as those of
+    /// [`crate::redact`].
+    #[must_use]
+    pub fn new(conf: &conf::ResponseFilter) -> Self {
+        let banned = conf
+            .banned
+            .iter()
+            .filter_map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|error| {
+                        tracing::error!(
+                            ?error,
+                            pattern,
+                            "Invalid banned pattern. Skipping."
+                        );
+                    })
+                    .ok()
+            })
+            .collect();
+        Self {
+            max_chars: conf.max_chars,
+            banned,
+            is_json_checked: conf.json,
+            action: conf.action,
+        }
+    }
+
+    /// None when the response passes, or is not a chat completion.
+    #[must_use]
+    pub fn check(
+        &self,
+        chat_req: &chat::Req,
+        resp_body: &str,
+    ) -> Option<Outcome> {
+        let mut resp: serde_json::Value =
+            serde_json::from_str(resp_body).ok()?;
+        let mut violations = Vec::new();
+        for (index, content) in contents(&resp) {
+            let chars = content.chars().count();
+            if let Some(max) = self.max_chars.filter(|max| chars > *max) {
+                violations.push(Violation {
+                    index,
+                    rule: Rule::MaxChars,
+                    detail: format!("{chars} characters, of at most {max}."),
+                });
+            }
+            for pattern in &self.banned {
+                if pattern.is_match(content) {
+                    violations.push(Violation {
+                        index,
+                        rule: Rule::Banned,
+                        detail: format!("Matches {:?}.", pattern.as_str()),
+                    });
+                }
+            }
+        }
+        if self.is_json_checked {
+            if let Err(detail) = chat_req.check_json_output(resp_body) {
+                violations.push(Violation {
+                    index: 0,
+                    rule: Rule::Json,
+                    detail,
+                });
+            }
+        }
+        if violations.is_empty() {
+            return None;
+        }
+        match self.action {
+            conf::FilterAction::Reject => {
+                return Some(Outcome::Rejected { violations });
+            }
+            conf::FilterAction::Redact => {
+                if !self.redact(&mut resp) {
+                    return Some(Outcome::Unaltered { violations });
+                }
+            }
+            conf::FilterAction::Annotate => {
+                resp["raskol_violations"] =
+                    serde_json::to_value(&violations).unwrap_or_default();
+            }
+        }
+        Some(Outcome::Altered {
+            body: resp.to_string(),
+            violations,
+        })
+    }
+
+    /// Returns whether anything was.
+    fn redact(&self, resp: &mut serde_json::Value) -> bool {
+        let Some(choices) = resp["choices"].as_array_mut() else {
+            return false;
+        };
+        let mut is_redacted = false;
+        for choice in choices {
+            let Some(content) = choice["message"]["content"].as_str() else {
+                continue;
+            };
+            let mut content = content.to_string();
+            for pattern in &self.banned {
+                content =
+                    pattern.replace_all(&content, REPLACEMENT).into_owned();
+            }
+            if let Some(max) = self.max_chars {
+                content = content.chars().take(max).collect();
+            }
+            if choice["message"]["content"] != content.as_str() {
+                choice["message"]["content"] = content.into();
+                is_redacted = true;
+            }
+        }
+        is_redacted
+    }
+}
+
+/// Of the choices, those with text.
+fn contents(resp: &serde_json::Value) -> Vec<(usize, &str)> {
+    let Some(choices) = resp["choices"].as_array() else {
+        return Vec::new();
+    };
+    choices
+        .iter()
+        .enumerate()
+        .filter_map(|(index, choice)| {
+            let content = choice["message"]["content"].as_str()?;
+            Some((index, content))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Outcome, Rule};
+    use crate::{chat, conf};
+
+    fn req(response_format: serde_json::Value) -> chat::Req {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "response_format": response_format,
+        }))
+        .unwrap()
+    }
+
+    fn resp(content: &str) -> String {
+        serde_json::json!({
+            "choices": [{"message": {"content": content}}],
+        })
+        .to_string()
+    }
+
+    fn filter(action: conf::FilterAction) -> Filter {
+        Filter::new(&conf::ResponseFilter {
+            max_chars: Some(12),
+            banned: vec![r"(?i)secret\w*".to_string()],
+            json: true,
+            action,
+        })
+    }
+
+    fn content(body: &str) -> String {
+        let resp: serde_json::Value = serde_json::from_str(body).unwrap();
+        resp["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn check() {
+        let text = req(serde_json::json!({"type": "text"}));
+        let json = req(serde_json::json!({"type": "json_object"}));
+        let filter = filter(conf::FilterAction::Annotate);
+        assert_eq!(filter.check(&text, &resp("All fine.")), None);
+        assert_eq!(filter.check(&text, "data: [DONE]"), None);
+        assert_eq!(filter.check(&json, &resp(r#"{"a": 1}"#)), None);
+        let Some(Outcome::Altered { body, violations }) =
+            filter.check(&json, &resp("The SECRETS are out"))
+        else {
+            panic!("Not annotated.");
+        };
+        let rules: Vec<Rule> = violations.iter().map(|v| v.rule).collect();
+        assert_eq!(rules, [Rule::MaxChars, Rule::Banned, Rule::Json]);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["raskol_violations"][1]["rule"], "banned");
+    }
+
+    #[test]
+    fn redact() {
+        let text = req(serde_json::json!({"type": "text"}));
+        let filter = filter(conf::FilterAction::Redact);
+        let Some(Outcome::Altered { body, .. }) =
+            filter.check(&text, &resp("A secret."))
+        else {
+            panic!("Not redacted.");
+        };
+        assert_eq!(content(&body), "A [REDACTED]");
+        let rejecting = super::filter(conf::FilterAction::Reject);
+        assert!(matches!(
+            rejecting.check(&text, &resp("A secret.")),
+            Some(Outcome::Rejected { .. })
+        ));
+        // Invalid JSON is left as is, and said to be.
+        let json = req(serde_json::json!({"type": "json_object"}));
+        assert!(matches!(
+            filter.check(&json, &resp("Not JSON.")),
+            Some(Outcome::Unaltered { .. })
+        ));
+    }
+}
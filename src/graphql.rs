@@ -0,0 +1,282 @@
+//! GraphQL API for admins, over the same data as the rest of the admin API,
+//! for dashboards which want to pick what they query. Read-only.
+
+use std::sync::{Arc, OnceLock};
+
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, EmptySubscription,
+    InputObject, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{extract::State, response::Html, routing::get, Router};
+
+use crate::{
+    auth,
+    data::{self, Storage},
+    server::AppState,
+};
+
+/// Of any list, however many asked for.
+const MAX_PAGE: u64 = 1000;
+
+/// Nesting is shallow, so deeper queries are likely abuse.
+const MAX_DEPTH: usize = 5;
+
+/// Of a query, with each field counting once per item of its list, so
+/// that listing pages of users' budgets is bounded too.
+const MAX_COMPLEXITY: usize = 5000;
+
+type AdminSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/graphql", get(handle_graphiql).post(handle_graphql))
+}
+
+async fn handle_graphql(
+    State(AppState { storage, .. }): State<AppState>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    schema()
+        .execute(req.into_inner().data(storage))
+        .await
+        .into()
+}
+
+/// In-browser IDE, for exploring the schema.
+async fn handle_graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/v1/admin/graphql")
+            .finish(),
+    )
+}
+
+fn schema() -> &'static AdminSchema {
+    static SCHEMA: OnceLock<AdminSchema> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        Schema::build(Query, EmptyMutation, EmptySubscription)
+            .limit_depth(MAX_DEPTH)
+            .limit_complexity(MAX_COMPLEXITY)
+            .finish()
+    })
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// All who ever made a request or were managed by an admin, by uid.
+    #[graphql(complexity = "page(limit, child_complexity)")]
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        is_suspended: Option<bool>,
+        #[graphql(default)] offset: u64,
+        #[graphql(default = 100)] limit: u64,
+    ) -> async_graphql::Result<Vec<User>> {
+        let accounts = storage(ctx)?
+            .account_page(is_suspended, limit.min(MAX_PAGE), offset)
+            .await
+            .map_err(internal)?;
+        Ok(accounts
+            .into_iter()
+            .map(|(account, basis)| User {
+                account,
+                basis: Some(basis),
+            })
+            .collect())
+    }
+
+    async fn user(
+        &self,
+        ctx: &Context<'_>,
+        uid: String,
+    ) -> async_graphql::Result<User> {
+        let account =
+            storage(ctx)?.account_get(&uid).await.map_err(internal)?;
+        Ok(User {
+            account,
+            basis: None,
+        })
+    }
+
+    /// Newest first.
+    #[graphql(complexity = "page(limit, child_complexity)")]
+    async fn request_logs(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: LogFilter,
+        #[graphql(default)] offset: u64,
+        #[graphql(default = 100)] limit: u64,
+    ) -> async_graphql::Result<Vec<data::RequestLog>> {
+        storage(ctx)?
+            .request_logs(&filter.into(), limit.min(MAX_PAGE), offset)
+            .await
+            .map_err(internal)
+    }
+
+    /// Per user, or just the given user, or the given org's members, since
+    /// the given time (rounded down to the hour), by uid.
+    #[graphql(complexity = "page(limit, child_complexity)")]
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        uid: Option<String>,
+        org: Option<String>,
+        #[graphql(default)] since: i64,
+        #[graphql(default)] offset: u64,
+        #[graphql(default = 100)] limit: u64,
+    ) -> async_graphql::Result<Vec<data::UserStats>> {
+        let page = data::StatsPage {
+            limit: Some(limit.min(MAX_PAGE)),
+            offset,
+            ..data::StatsPage::default()
+        };
+        storage(ctx)?
+            .stats(uid.as_deref(), org.as_deref(), since, &page)
+            .await
+            .map_err(internal)
+    }
+
+    /// Of all users, or just the given one, per bucket of the given
+    /// seconds, a multiple of an hour, from `from` until `to`, or now.
+    async fn timeseries(
+        &self,
+        ctx: &Context<'_>,
+        uid: Option<String>,
+        #[graphql(default = 3600)] bucket: i64,
+        from: i64,
+        to: Option<i64>,
+    ) -> async_graphql::Result<Vec<data::UsageBucket>> {
+        let to = to.unwrap_or_else(|| chrono::Utc::now().timestamp());
+        storage(ctx)?
+            .timeseries(uid.as_deref(), bucket, from, to)
+            .await
+            .map_err(internal)
+    }
+}
+
+pub struct User {
+    account: data::Account,
+
+    /// When listed, so as not to query per user.
+    basis: Option<data::BudgetBasis>,
+}
+
+#[Object]
+impl User {
+    async fn uid(&self) -> &str {
+        &self.account.uid
+    }
+
+    /// Set by an admin, overriding the global limit.
+    async fn max_tokens_per_day(&self) -> Option<u64> {
+        self.account.max_tokens_per_day
+    }
+
+    async fn is_suspended(&self) -> bool {
+        self.account.is_suspended
+    }
+
+    /// Today's, as it would be for the user in the given role.
+    async fn budget(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "auth::ROLE_HACKER.to_string()")]
+        role: String,
+    ) -> async_graphql::Result<Budget> {
+        let budget = match &self.basis {
+            Some(basis) => basis.token_budget(&role),
+            None => storage(ctx)?
+                .token_budget(
+                    &self.account.uid,
+                    &role,
+                    data::BudgetOverrides::default(),
+                )
+                .await
+                .map_err(internal)?,
+        };
+        Ok(Budget {
+            limit: budget.limit,
+            used: budget.used,
+            remaining: budget.remaining(),
+        })
+    }
+
+    async fn credits(&self, ctx: &Context<'_>) -> async_graphql::Result<i64> {
+        if let Some(basis) = &self.basis {
+            return Ok(basis.credits);
+        }
+        storage(ctx)?
+            .credits_balance(&self.account.uid)
+            .await
+            .map_err(internal)
+    }
+}
+
+/// In tokens.
+#[derive(SimpleObject)]
+pub struct Budget {
+    limit: u64,
+    used: u64,
+    remaining: u64,
+}
+
+/// Each only when set. Times are seconds since UNIX epoch, `until`
+/// exclusive.
+#[derive(InputObject, Default)]
+pub struct LogFilter {
+    uid: Option<String>,
+    model: Option<String>,
+    session: Option<String>,
+    #[graphql(default)]
+    is_error: bool,
+    status: Option<i64>,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl From<LogFilter> for data::LogFilter {
+    fn from(filter: LogFilter) -> Self {
+        Self {
+            uid: filter.uid,
+            model: filter.model,
+            session: filter.session,
+            is_error: filter.is_error,
+            status: filter.status,
+            since: filter.since,
+            until: filter.until,
+        }
+    }
+}
+
+fn storage<'a>(
+    ctx: &Context<'a>,
+) -> async_graphql::Result<&'a Arc<dyn Storage>> {
+    ctx.data::<Arc<dyn Storage>>()
+}
+
+/// Of a list field, its items' complexity, times as many as there may be.
+fn page(limit: u64, child_complexity: usize) -> usize {
+    usize::try_from(limit.min(MAX_PAGE))
+        .unwrap_or(usize::MAX)
+        .saturating_mul(child_complexity)
+}
+
+fn internal(error: anyhow::Error) -> async_graphql::Error {
+    tracing::error!(?error, "Failed to hit storage.");
+    async_graphql::Error::new("Storage unavailable.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::schema;
+
+    #[test]
+    fn sdl() {
+        let sdl = schema().sdl();
+        for query in ["users(", "requestLogs(", "stats(", "timeseries("] {
+            assert!(sdl.contains(query), "{query} missing from: {sdl}");
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! gRPC service for other backends, per proto/raskol.proto, on its own
+//! port, per [`conf::Grpc`]. Callers are trusted with any user's budget, so
+//! they authenticate with the shared token, rather than as users, which
+//! only callers on loopback may go without.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tonic::{Request, Response, Status};
+
+use crate::{
+    auth, conf,
+    data::{self, Storage},
+};
+
+#[allow(clippy::all, clippy::pedantic)]
+pub mod proto {
+    tonic::include_proto!("raskol.v1");
+}
+
+use proto::raskol_server::{Raskol, RaskolServer};
+
+/// Until the shutdown signal.
+pub async fn serve<F>(
+    conf: &conf::Grpc,
+    storage: Arc<dyn Storage>,
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send,
+{
+    conf.check()?;
+    let token = conf.token.as_ref().map(|token| format!("Bearer {token}"));
+    let service = RaskolServer::with_interceptor(
+        Service { storage },
+        move |req: Request<()>| check_token(token.as_deref(), req),
+    );
+    tracing::info!(addr = ?conf.addr, "gRPC listening.");
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(conf.addr, shutdown)
+        .await?;
+    Ok(())
+}
+
+/// In constant time, so that it can't be guessed byte by byte.
+fn check_token(
+    token: Option<&str>,
+    req: Request<()>,
+) -> Result<Request<()>, Status> {
+    let Some(token) = token else {
+        return Ok(req);
+    };
+    let given = req.metadata().get("authorization");
+    match given.map(|given| {
+        ring::constant_time::verify_slices_are_equal(
+            given.as_bytes(),
+            token.as_bytes(),
+        )
+    }) {
+        Some(Ok(())) => Ok(req),
+        _ => {
+            tracing::warn!("Rejecting gRPC call. Invalid token.");
+            Err(Status::unauthenticated("Invalid token."))
+        }
+    }
+}
+
+struct Service {
+    storage: Arc<dyn Storage>,
+}
+
+#[tonic::async_trait]
+impl Raskol for Service {
+    async fn check_budget(
+        &self,
+        req: Request<proto::CheckBudgetRequest>,
+    ) -> Result<Response<proto::Budget>, Status> {
+        let proto::CheckBudgetRequest { uid, role } = req.into_inner();
+        let budget = self.budget(&uid, &role_or_default(role)).await?;
+        Ok(Response::new(budget))
+    }
+
+    async fn consume_budget(
+        &self,
+        req: Request<proto::ConsumeBudgetRequest>,
+    ) -> Result<Response<proto::ConsumeBudgetResponse>, Status> {
+        let proto::ConsumeBudgetRequest {
+            uid,
+            role,
+            org,
+            model,
+            input_tokens,
+            output_tokens,
+        } = req.into_inner();
+        let conf = conf::global();
+        let role = role_or_default(role);
+        let (input_tokens, output_tokens) = (
+            usize::try_from(input_tokens).map_err(invalid)?,
+            usize::try_from(output_tokens).map_err(invalid)?,
+        );
+        let amount = data::Amount {
+            tokens: input_tokens.saturating_add(output_tokens),
+            cost: conf
+                .pricing
+                .get(&model)
+                .map_or(0.0, |price| price.cost(input_tokens, output_tokens)),
+        };
+        let reservation = self
+            .storage
+            .budget_reserve(
+                &uid,
+                org.as_deref(),
+                &role,
+                data::BudgetOverrides::default(),
+                &model,
+                amount,
+            )
+            .await
+            .map_err(internal)?;
+        let allowed = reservation.is_ok();
+        if let Ok(reservation) = reservation {
+            self.storage
+                .budget_settle(&reservation, amount)
+                .await
+                .map_err(internal)?;
+        }
+        tracing::info!(uid, model, ?amount, allowed, "Consumed via gRPC.");
+        let budget = self.budget(&uid, &role).await?;
+        Ok(Response::new(proto::ConsumeBudgetResponse {
+            allowed,
+            budget: Some(budget),
+        }))
+    }
+
+    async fn get_stats(
+        &self,
+        req: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::GetStatsResponse>, Status> {
+        let proto::GetStatsRequest { uid, since } = req.into_inner();
+        let stats = self
+            .storage
+            .stats(uid.as_deref(), None, since, &data::StatsPage::default())
+            .await
+            .map_err(internal)?;
+        let users = stats
+            .into_iter()
+            .map(|stats| proto::UserStats {
+                uid: stats.uid,
+                requests: stats.requests,
+                errors: stats.errors,
+                input_tokens: stats.input_tokens,
+                output_tokens: stats.output_tokens,
+                cost: stats.cost,
+            })
+            .collect();
+        Ok(Response::new(proto::GetStatsResponse { users }))
+    }
+
+    async fn mint_jwt(
+        &self,
+        req: Request<proto::MintJwtRequest>,
+    ) -> Result<Response<proto::MintJwtResponse>, Status> {
+        let proto::MintJwtRequest {
+            uid,
+            role,
+            org,
+            ttl_secs,
+            max_tokens_per_day,
+            features,
+        } = req.into_inner();
+        let conf = conf::global();
+        if ttl_secs == 0 {
+            return Err(Status::invalid_argument("ttl_secs must be > 0."));
+        }
+        let mut claims =
+            auth::Claims::new(&uid, Duration::from_secs(ttl_secs), &conf.jwt)
+                .map_err(|error| failed_to_mint(&error))?;
+        claims.role = role_or_default(role);
+        claims.org = org;
+        claims.max_tokens_per_day = max_tokens_per_day;
+        claims.features = features;
+        let token = claims
+            .to_str(&conf.jwt)
+            .map_err(|error| failed_to_mint(&error))?;
+        let jti = claims.jti.unwrap_or_default();
+        tracing::info!(jti, uid, role = claims.role, "Minted JWT via gRPC.");
+        Ok(Response::new(proto::MintJwtResponse { token, jti }))
+    }
+}
+
+impl Service {
+    async fn budget(
+        &self,
+        uid: &str,
+        role: &str,
+    ) -> Result<proto::Budget, Status> {
+        let budget = self
+            .storage
+            .token_budget(uid, role, data::BudgetOverrides::default())
+            .await
+            .map_err(internal)?;
+        Ok(proto::Budget {
+            limit: budget.limit,
+            used: budget.used,
+            remaining: budget.remaining(),
+        })
+    }
+}
+
+/// Proto3 strings can't be absent, so empty stands for the default.
+fn role_or_default(role: String) -> String {
+    if role.is_empty() {
+        auth::ROLE_HACKER.to_string()
+    } else {
+        role
+    }
+}
+
+fn invalid(error: impl std::fmt::Display) -> Status {
+    Status::invalid_argument(error.to_string())
+}
+
+fn failed_to_mint(error: &dyn std::error::Error) -> Status {
+    tracing::error!(?error, "Failed to mint JWT.");
+    Status::internal("Failed to mint JWT.")
+}
+
+fn internal(error: anyhow::Error) -> Status {
+    tracing::error!(?error, "Failed to handle gRPC call.");
+    Status::unavailable("Storage unavailable.")
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{metadata::MetadataValue, Request};
+
+    use super::check_token;
+
+    #[test]
+    fn token() {
+        let token: MetadataValue<_> = "Bearer s3cret".parse().unwrap();
+        assert!(check_token(None, Request::new(())).is_ok());
+        assert!(check_token(Some(&token), Request::new(())).is_err());
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer s3cret".parse().unwrap());
+        assert!(check_token(Some(&token), req).is_ok());
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer guess".parse().unwrap());
+        assert!(check_token(Some(&token), req).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Which client headers reach upstream, and which of upstream's reach
+//! clients, per [`conf::Headers`]. And who the client is, when behind
+//! proxies, per [`conf::IpFilter::trusted_proxies`].
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::conf;
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED: &str = "forwarded";
+
+/// Of a request from the peer, the client: going back along the hops the
+/// proxies named, the first not a trusted proxy. A hop which can't be
+/// parsed, such as "unknown", ends the way back, since whoever is before
+/// it is unknown, so the client is the proxy which named it.
+#[must_use]
+pub fn client_ip(
+    conf: &conf::IpFilter,
+    incoming: &HeaderMap,
+    peer: IpAddr,
+) -> IpAddr {
+    let mut client = peer.to_canonical();
+    if !conf.is_trusted_proxy(client) {
+        return client;
+    }
+    let name = match conf.proxy_header {
+        conf::ProxyHeader::XForwardedFor => FORWARDED_FOR,
+        conf::ProxyHeader::Forwarded => FORWARDED,
+    };
+    // Of however many lines, which are as one, comma-separated.
+    let hops: Vec<Option<IpAddr>> = incoming
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| match conf.proxy_header {
+            conf::ProxyHeader::XForwardedFor => address(hop),
+            conf::ProxyHeader::Forwarded => forwarded_for(hop),
+        })
+        .collect();
+    for hop in hops.into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop.to_canonical();
+        if !conf.is_trusted_proxy(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Of an element of a Forwarded header, such as
+/// `for="[2001:db8::1]:4711";proto=https`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("for") {
+            address(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// With or without a port, and quotes.
+fn address(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Some(bracketed) = hop.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    hop.parse().ok().or_else(|| {
+        hop.parse::<SocketAddr>().ok().map(|address| address.ip())
+    })
+}
+
+/// Of the client's request, those to pass on upstream.
+#[must_use]
+pub fn outgoing(
+    conf: &conf::Headers,
+    incoming: &HeaderMap,
+    from: IpAddr,
+) -> HeaderMap {
+    let mut outgoing = HeaderMap::new();
+    for name in &conf.forward {
+        // Lowercased, as header names are compared.
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        if conf::Headers::NEVER_FORWARDED.contains(&name.as_str()) {
+            continue;
+        }
+        for value in incoming.get_all(&name) {
+            outgoing.append(name.clone(), value.clone());
+        }
+    }
+    if conf.forwarded_for {
+        // Appended to, as proxies do, in case the client is behind one.
+        let from = from.to_canonical().to_string();
+        let chain = match incoming
+            .get(FORWARDED_FOR)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(chain) => format!("{chain}, {from}"),
+            None => from,
+        };
+        if let Ok(value) = HeaderValue::from_str(&chain) {
+            outgoing.insert(FORWARDED_FOR, value);
+        }
+    }
+    outgoing
+}
+
+/// Of upstream's response, those to pass back to the client, renamed so as
+/// not to be confused with ours.
+#[must_use]
+pub fn pass_back(conf: &conf::Headers, upstream: &HeaderMap) -> HeaderMap {
+    let mut passed = HeaderMap::new();
+    for name in &conf.pass_back {
+        let name = name.to_lowercase();
+        let Some(value) = upstream.get(name.as_str()) else {
+            continue;
+        };
+        let renamed = format!(
+            "x-upstream-{}",
+            name.strip_prefix("x-").unwrap_or(&name)
+        );
+        if let Ok(renamed) = HeaderName::try_from(renamed) {
+            passed.insert(renamed, value.clone());
+        }
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use axum::http::HeaderMap;
+
+    use super::{client_ip, outgoing, pass_back};
+    use crate::conf;
+
+    #[test]
+    fn policy() {
+        let mut conf = conf::Headers::default();
+        conf.forward.push("Authorization".to_string());
+        let incoming: HeaderMap = [
+            ("accept", "application/json"),
+            ("authorization", "Bearer ours"),
+            ("cookie", "session=1"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+        .collect();
+        let from = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let outgoing = outgoing(&conf, &incoming, from);
+        assert_eq!(outgoing.len(), 2);
+        assert_eq!(outgoing["accept"], "application/json");
+        assert_eq!(outgoing["x-forwarded-for"], "10.0.0.1, 192.0.2.1");
+
+        let upstream: HeaderMap = [
+            ("x-ratelimit-remaining-requests", "99"),
+            ("openai-processing-ms", "120"),
+            ("set-cookie", "x=1"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+        .collect();
+        let passed = pass_back(&conf, &upstream);
+        assert_eq!(passed.len(), 2);
+        assert_eq!(passed["x-upstream-ratelimit-remaining-requests"], "99");
+        assert_eq!(passed["x-upstream-openai-processing-ms"], "120");
+    }
+
+    #[test]
+    fn client() {
+        let conf = conf::IpFilter {
+            trusted_proxies: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            ..conf::IpFilter::default()
+        };
+        let headers = |pairs: &[(&str, &str)]| -> HeaderMap {
+            pairs
+                .iter()
+                .map(|(name, value)| {
+                    (name.parse().unwrap(), value.parse().unwrap())
+                })
+                .collect()
+        };
+        let ip = |ip: &str| -> IpAddr { ip.parse().unwrap() };
+        let proxied = headers(&[
+            ("x-forwarded-for", "198.51.100.9, 203.0.113.7"),
+            ("x-forwarded-for", "10.1.1.1"),
+        ]);
+        // Not from a proxy, so the header is the client's own.
+        assert_eq!(
+            client_ip(&conf, &proxied, ip("192.0.2.1")),
+            ip("192.0.2.1")
+        );
+        // The nearest not trusted, rather than the first, which is the
+        // client's to make up.
+        assert_eq!(
+            client_ip(&conf, &proxied, ip("::ffff:10.0.0.1")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(&conf, &HeaderMap::new(), ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+        let unknown = headers(&[("x-forwarded-for", "203.0.113.7, unknown")]);
+        assert_eq!(
+            client_ip(&conf, &unknown, ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+
+        let conf = conf::IpFilter {
+            proxy_header: conf::ProxyHeader::Forwarded,
+            ..conf
+        };
+        let forwarded = headers(&[
+            ("x-forwarded-for", "192.0.2.66"),
+            (
+                "forwarded",
+                r#"for=203.0.113.7:1234;proto=https, for="[2001:db8::5]""#,
+            ),
+        ]);
+        assert_eq!(
+            client_ip(&conf, &forwarded, ip("10.0.0.1")),
+            ip("203.0.113.7")
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Custom logic run at fixed points of handling API requests, such as
+//! prompt rewriting, extra validation or accounting, registered on the
+//! [`crate::Server`] builder. Some are built in, and turned on in conf.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{chat, conf, upstream};
+
+/// Who and what a request is for.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub uid: String,
+    pub role: String,
+    pub req_id: String,
+    pub endpoint: String,
+}
+
+/// Each point does nothing unless implemented. Hooks run in the order they
+/// were registered, and the first to reject a request stops it.
+#[async_trait::async_trait]
+pub trait Hook: Send + Sync {
+    /// In logs.
+    fn name(&self) -> &str;
+
+    /// Before auth, so of any API request, as the client sent it. Rejects
+    /// it with the response, if any.
+    async fn pre_auth(&self, _req: &mut Request) -> Result<(), Response> {
+        Ok(())
+    }
+
+    /// Of chat requests, once our own limits and rewrites are applied, and
+    /// before they are counted and forwarded.
+    async fn pre_forward(
+        &self,
+        _ctx: &Context,
+        _chat_req: &mut chat::Req,
+    ) -> Result<(), Response> {
+        Ok(())
+    }
+
+    /// Of successful responses, once accounted for, before the client gets
+    /// them, but for streamed ones, which are only seen once passed on,
+    /// whole, too late to be altered.
+    async fn post_response(
+        &self,
+        _ctx: &Context,
+        _forwarded: &mut upstream::Forwarded,
+    ) {
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Hooks(Vec<Arc<dyn Hook>>);
+
+impl Hooks {
+    /// The built-in ones turned on in conf.
+    pub fn from_conf(conf: &conf::Hooks) -> anyhow::Result<Self> {
+        let mut hooks = Self::default();
+        if let Some(name) = &conf.require_header {
+            hooks.push(Arc::new(RequireHeader {
+                name: name.parse()?,
+            }));
+        }
+        if let Some(max) = conf.max_prompt_chars {
+            hooks.push(Arc::new(MaxPromptChars { max }));
+        }
+        #[cfg(feature = "wasm")]
+        for plugin in &conf.wasm {
+            hooks.push(Arc::new(crate::wasm::Plugin::load(plugin)?));
+        }
+        Ok(hooks)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn push(&mut self, hook: Arc<dyn Hook>) {
+        self.0.push(hook);
+    }
+
+    pub async fn pre_auth(&self, req: &mut Request) -> Result<(), Response> {
+        for hook in &self.0 {
+            if let Err(resp) = hook.pre_auth(req).await {
+                tracing::warn!(hook = hook.name(), "Rejecting. By hook.");
+                return Err(resp);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn pre_forward(
+        &self,
+        ctx: &Context,
+        chat_req: &mut chat::Req,
+    ) -> Result<(), Response> {
+        for hook in &self.0 {
+            if let Err(resp) = hook.pre_forward(ctx, chat_req).await {
+                tracing::warn!(hook = hook.name(), "Rejecting. By hook.");
+                return Err(resp);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn post_response(
+        &self,
+        ctx: &Context,
+        forwarded: &mut upstream::Forwarded,
+    ) {
+        for hook in &self.0 {
+            hook.post_response(ctx, forwarded).await;
+        }
+    }
+}
+
+/// Rejects requests without the header, such as an event's access code
+/// added by the proxy in front of us.
+pub struct RequireHeader {
+    pub name: HeaderName,
+}
+
+#[async_trait::async_trait]
+impl Hook for RequireHeader {
+    fn name(&self) -> &str {
+        "require_header"
+    }
+
+    async fn pre_auth(&self, req: &mut Request) -> Result<(), Response> {
+        if req.headers().contains_key(&self.name) {
+            return Ok(());
+        }
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "missing_header",
+            format!("Header {} is required.", self.name),
+        );
+        Err((StatusCode::BAD_REQUEST, Json(error)).into_response())
+    }
+}
+
+/// Rejects chat requests whose messages are longer, in characters, which
+/// is cheaper to check than tokens.
+pub struct MaxPromptChars {
+    pub max: usize,
+}
+
+#[async_trait::async_trait]
+impl Hook for MaxPromptChars {
+    fn name(&self) -> &str {
+        "max_prompt_chars"
+    }
+
+    async fn pre_forward(
+        &self,
+        _ctx: &Context,
+        chat_req: &mut chat::Req,
+    ) -> Result<(), Response> {
+        let chars: usize = chat_req
+            .messages
+            .iter()
+            .map(|msg| msg.text().chars().count())
+            .sum();
+        if chars <= self.max {
+            return Ok(());
+        }
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "prompt_too_long",
+            format!("Prompt is {chars} characters, over {}.", self.max),
+        );
+        Err((StatusCode::BAD_REQUEST, Json(error)).into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::StatusCode};
+
+    use super::{Context, Hooks};
+    use crate::{chat, conf};
+
+    #[tokio::test]
+    async fn built_in() {
+        let hooks = Hooks::from_conf(&conf::Hooks {
+            require_header: Some("x-event-code".to_string()),
+            max_prompt_chars: Some(5),
+            ..conf::Hooks::default()
+        })
+        .unwrap();
+
+        let mut req = Request::new(Body::empty());
+        let resp = hooks.pre_auth(&mut req).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        req.headers_mut()
+            .insert("x-event-code", "hack".parse().unwrap());
+        assert!(hooks.pre_auth(&mut req).await.is_ok());
+
+        let ctx = Context {
+            uid: "u".to_string(),
+            role: "user".to_string(),
+            req_id: "r".to_string(),
+            endpoint: "v1/chat/completions".to_string(),
+        };
+        let mut chat_req: chat::Req =
+            serde_json::from_value(serde_json::json!({
+                "model": "m",
+                "messages": [{"role": "user", "content": "Hello!"}],
+            }))
+            .unwrap();
+        assert!(hooks.pre_forward(&ctx, &mut chat_req).await.is_err());
+        chat_req.messages[0].content = "Hi.".into();
+        assert!(hooks.pre_forward(&ctx, &mut chat_req).await.is_ok());
+    }
+}
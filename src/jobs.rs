@@ -0,0 +1,463 @@
+//! Background jobs, such as pruning and calibration, run on schedules,
+//! which conf may change, per [`conf::Job`], or turn off. How each went last
+//! is kept, per instance, at `/admin/jobs`, since their failures are
+//! otherwise only in the logs.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    str::FromStr,
+    sync::{LazyLock, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Datelike, TimeDelta, Timelike, Utc};
+
+use crate::conf;
+
+/// Of those spawned by the server, in conf.
+pub const NAMES: [&str; 14] = [
+    "prune",
+    "rollup",
+    "expire",
+    "calibrate",
+    "close_credits",
+    "detect_abuse",
+    "heartbeat",
+    "reserve",
+    "flush",
+    "process_batches",
+    "maintain",
+    "report_stripe",
+    "push_metrics",
+    "probe",
+];
+
+/// Of minutes, how far ahead the next run of a cron schedule is looked for.
+const CRON_HORIZON: i64 = 366 * 24 * 60;
+
+static GLOBAL: LazyLock<Jobs> = LazyLock::new(Jobs::default);
+
+#[must_use]
+pub fn global() -> &'static Jobs {
+    &GLOBAL
+}
+
+/// Of those spawned, by name.
+#[derive(Default)]
+pub struct Jobs {
+    statuses: Mutex<BTreeMap<&'static str, Status>>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub name: String,
+
+    /// As in conf, or the default interval, e.g. "every 3600s".
+    pub schedule: String,
+    pub is_enabled: bool,
+    pub runs: u64,
+    pub failures: u64,
+
+    /// Seconds since UNIX epoch.
+    pub last_started: Option<i64>,
+    pub last_duration_ms: Option<i64>,
+
+    /// Of the last run, if it failed.
+    pub last_error: Option<String>,
+    pub next: Option<i64>,
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Starting right away.
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// Of 5 fields, minute, hour, day of month, month and day of week, in UTC.
+/// Each is `*`, or a comma separated list of values and ranges, as in
+/// `1-5`, any of them with a step, as in `*/15`. Sunday is 0, or 7. When
+/// both days are restricted, either matching will do, as in cron.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    is_any_day: bool,
+    is_any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(anyhow!("Expected 5 fields, got {}.", fields.len()));
+        };
+        let weekday_set = field(weekdays, 0, 7).context("Day of week")?;
+        Ok(Self {
+            expression: s.trim().to_string(),
+            minutes: field(minutes, 0, 59).context("Minute")?,
+            hours: field(hours, 0, 23).context("Hour")?,
+            days: field(days, 1, 31).context("Day of month")?,
+            months: field(months, 1, 12).context("Month")?,
+            // Sunday either way.
+            weekdays: (weekday_set | (weekday_set >> 7)) & 0x7f,
+            is_any_day: days == "*",
+            is_any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Cron {
+    #[must_use]
+    pub fn matches(&self, time: DateTime<Utc>) -> bool {
+        let is = |set: u64, value: u32| set & (1 << value) != 0;
+        let is_day = is(self.days, time.day());
+        let is_weekday =
+            is(self.weekdays, time.weekday().num_days_from_sunday());
+        let is_day = match (self.is_any_day, self.is_any_weekday) {
+            (false, false) => is_day || is_weekday,
+            _ => is_day && is_weekday,
+        };
+        is(self.minutes, time.minute())
+            && is(self.hours, time.hour())
+            && is(self.months, time.month())
+            && is_day
+    }
+
+    /// The first minute after the time which matches, if any within a year.
+    #[must_use]
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)?;
+        (1..=CRON_HORIZON)
+            .map(|minutes| start + TimeDelta::minutes(minutes))
+            .find(|time| self.matches(*time))
+    }
+}
+
+/// Of values from min to max, as a bit set.
+fn field(s: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut set = 0;
+    for item in s.split(',') {
+        let (range, step): (&str, u32) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse()?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            return Err(anyhow!("Zero step: {item:?}"));
+        }
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None if step > 1 => (range.parse()?, max),
+            None => {
+                let value = range.parse()?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err(anyhow!("Out of {min}-{max}: {item:?}"));
+        }
+        for value in (start..=end).step_by(usize::try_from(step)?) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl Schedule {
+    /// Per conf, else every `default`.
+    pub fn of(name: &str, default: Duration) -> anyhow::Result<Self> {
+        match conf::global()
+            .jobs
+            .get(name)
+            .and_then(|job| job.schedule.as_ref())
+        {
+            Some(cron) => Ok(Self::Cron(cron.parse()?)),
+            None => Ok(Self::Every(default)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Every(period) => format!("every {}s", period.as_secs()),
+            Self::Cron(cron) => cron.expression.clone(),
+        }
+    }
+}
+
+impl Jobs {
+    /// By name.
+    #[must_use]
+    pub fn statuses(&self) -> Vec<Status> {
+        self.lock().values().cloned().collect()
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut Status)) {
+        if let Some(status) = self.lock().get_mut(name) {
+            f(status);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<&'static str, Status>> {
+        self.statuses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Runs the job on its schedule, per conf, else every `default`, unless
+/// turned off there. Not again until it is done, however long it takes.
+pub fn spawn<F, Fut>(name: &'static str, default: Duration, run: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let schedule = Schedule::of(name, default);
+    start(global(), name, schedule, is_enabled(name), Start::Now, run);
+}
+
+/// As [`spawn`], but on the default interval, first run once it is up,
+/// rather than right away.
+pub fn spawn_later<F, Fut>(name: &'static str, default: Duration, run: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let schedule = Schedule::of(name, default);
+    start(
+        global(),
+        name,
+        schedule,
+        is_enabled(name),
+        Start::Later,
+        run,
+    );
+}
+
+fn is_enabled(name: &str) -> bool {
+    conf::global()
+        .jobs
+        .get(name)
+        .is_none_or(|job| job.is_enabled)
+}
+
+/// Of an interval, when it is first run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Start {
+    Now,
+    Later,
+}
+
+/// An invalid schedule, which conf validation refuses anyway, is kept as
+/// the status's error, rather than run.
+fn start<F, Fut>(
+    jobs: &'static Jobs,
+    name: &'static str,
+    schedule: anyhow::Result<Schedule>,
+    is_enabled: bool,
+    first: Start,
+    run: F,
+) where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut status = Status {
+        name: name.to_string(),
+        schedule: String::new(),
+        is_enabled,
+        runs: 0,
+        failures: 0,
+        last_started: None,
+        last_duration_ms: None,
+        last_error: None,
+        next: None,
+    };
+    let schedule = match schedule {
+        Ok(schedule) => schedule,
+        Err(error) => {
+            tracing::error!(?error, name, "Invalid job schedule. Not run.");
+            status.is_enabled = false;
+            status.last_error = Some(format!("{error:#}"));
+            jobs.lock().insert(name, status);
+            return;
+        }
+    };
+    status.schedule = schedule.describe();
+    jobs.lock().insert(name, status);
+    if !is_enabled {
+        tracing::info!(name, "Job turned off.");
+        return;
+    }
+    let (mut interval, cron) = match schedule {
+        Schedule::Every(period) => {
+            let at = match first {
+                Start::Now => tokio::time::Instant::now(),
+                Start::Later => tokio::time::Instant::now() + period,
+            };
+            let mut interval = tokio::time::interval_at(at, period);
+            // Rather than in a burst, making up for a long run.
+            interval.set_missed_tick_behavior(
+                tokio::time::MissedTickBehavior::Delay,
+            );
+            (Some(interval), None)
+        }
+        Schedule::Cron(cron) => (None, Some(cron)),
+    };
+    // Of the server spawning it, if embedded.
+    tokio::spawn(conf::carry(async move {
+        loop {
+            if let Some(cron) = &cron {
+                let Some(next) = cron.next(Utc::now()) else {
+                    tracing::warn!(name, "Job never due again.");
+                    return;
+                };
+                jobs.update(name, |status| {
+                    status.next = Some(next.timestamp());
+                });
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+            } else if let Some(interval) = &mut interval {
+                interval.tick().await;
+            }
+            let started = Instant::now();
+            jobs.update(name, |status| {
+                status.last_started = Some(Utc::now().timestamp());
+                status.next = None;
+            });
+            let result = run().await;
+            let duration_ms = i64::try_from(started.elapsed().as_millis())
+                .unwrap_or(i64::MAX);
+            if let Err(error) = &result {
+                tracing::error!(?error, name, "Job failed.");
+                metrics::counter!("raskol_job_failures_total", "job" => name)
+                    .increment(1);
+            }
+            jobs.update(name, |status| {
+                status.runs += 1;
+                status.last_duration_ms = Some(duration_ms);
+                status.last_error =
+                    result.as_ref().err().map(|error| format!("{error:#}"));
+                if result.is_err() {
+                    status.failures += 1;
+                }
+            });
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::anyhow;
+    use chrono::{DateTime, Utc};
+
+    use super::{Cron, Jobs, Schedule, Start};
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn cron() {
+        let cron: Cron = "*/15 3 * * *".parse().unwrap();
+        assert!(cron.matches(at("2025-01-01T03:30:00Z")));
+        assert!(!cron.matches(at("2025-01-01T03:31:00Z")));
+        assert!(!cron.matches(at("2025-01-01T04:30:00Z")));
+        assert_eq!(
+            cron.next(at("2025-01-01T03:50:10Z")),
+            Some(at("2025-01-02T03:00:00Z"))
+        );
+
+        // Weekdays, Sunday as 7 too.
+        let cron: Cron = "0 9 * * 1-5,7".parse().unwrap();
+        // A Saturday.
+        assert!(!cron.matches(at("2025-01-04T09:00:00Z")));
+        assert!(cron.matches(at("2025-01-05T09:00:00Z")));
+        assert!(cron.matches(at("2025-01-06T09:00:00Z")));
+
+        // Either day, when both are restricted.
+        let cron: Cron = "0 0 1 * 1".parse().unwrap();
+        assert!(cron.matches(at("2025-01-01T00:00:00Z")));
+        assert!(cron.matches(at("2025-01-06T00:00:00Z")));
+        assert!(!cron.matches(at("2025-01-07T00:00:00Z")));
+
+        // Never.
+        let cron: Cron = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(cron.next(at("2025-01-01T00:00:00Z")), None);
+
+        for invalid in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *"]
+        {
+            assert!(invalid.parse::<Cron>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spawn() {
+        let jobs: &'static Jobs = Box::leak(Box::default());
+        let every = || Ok(Schedule::Every(Duration::from_secs(10)));
+        let runs = Arc::new(AtomicU64::new(0));
+        let counted = runs.clone();
+        super::start(jobs, "counted", every(), true, Start::Now, move || {
+            let runs = counted.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    1 => Err(anyhow!("Second run.")),
+                    _ => Ok(()),
+                }
+            }
+        });
+        let later = runs.clone();
+        super::start(jobs, "later", every(), true, Start::Later, move || {
+            let runs = later.clone();
+            async move {
+                runs.fetch_add(100, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+        super::start(jobs, "off", every(), false, Start::Now, || async {
+            Err(anyhow!("Run while off."))
+        });
+        let invalid = "* * *".parse::<Cron>().map(Schedule::Cron);
+        super::start(jobs, "invalid", invalid, true, Start::Now, || async {
+            Err(anyhow!("Run while invalid."))
+        });
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 102);
+
+        let statuses = jobs.statuses();
+        let names: Vec<&str> =
+            statuses.iter().map(|status| status.name.as_str()).collect();
+        assert_eq!(names, ["counted", "invalid", "later", "off"]);
+        let counted = &statuses[0];
+        assert_eq!(counted.schedule, "every 10s");
+        assert!(counted.is_enabled);
+        assert_eq!((counted.runs, counted.failures), (2, 1));
+        assert_eq!(counted.last_error.as_deref(), Some("Second run."));
+        assert!(counted.last_started.is_some());
+        let invalid = &statuses[1];
+        assert!(!invalid.is_enabled);
+        assert_eq!(invalid.runs, 0);
+        assert!(invalid.last_error.is_some());
+        assert_eq!((statuses[2].runs, statuses[2].failures), (1, 0));
+        let off = &statuses[3];
+        assert!(!off.is_enabled);
+        assert_eq!(off.runs, 0);
+        assert_eq!(off.last_error, None);
+    }
+}
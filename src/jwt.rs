@@ -1,31 +1,446 @@
-use jsonwebtoken::Algorithm;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use jsonwebtoken::{
+    errors::ErrorKind, jwk::JwkSet, Algorithm, DecodingKey, EncodingKey,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair as _,
+        ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use serde::de::IgnoredAny;
+use tokio::sync::RwLock;
 
 use crate::conf;
 
-pub type Result<T> = jsonwebtoken::errors::Result<T>;
+/// Algorithms we are willing to accept in a token header. Anything else is
+/// rejected before we even look for a key.
+const ALGORITHMS: [Algorithm; 4] = [
+    Algorithm::HS256,
+    Algorithm::RS256,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+/// Don't re-fetch the JWKS more often than this, even when we keep seeing
+/// unknown kids, since those may just be garbage tokens.
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+static JWKS: LazyLock<Jwks> = LazyLock::new(Jwks::default);
+
+/// Of our signing keys, by file, read once, since every request needs one.
+static PUBLIC_KEYS: LazyLock<Mutex<HashMap<PathBuf, DecodingKey>>> =
+    LazyLock::new(Mutex::default);
+
+/// Likewise, since every token minted needs one.
+static PRIVATE_KEYS: LazyLock<Mutex<HashMap<PathBuf, EncodingKey>>> =
+    LazyLock::new(Mutex::default);
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Of a token's claims, those which keyed secrets are bound by, as of
+/// [`crate::auth::Claims`].
+#[derive(serde::Deserialize)]
+struct Scope {
+    sub: String,
+
+    #[serde(default = "default_role")]
+    role: String,
+
+    #[serde(default)]
+    org: Option<String>,
+
+    /// Which keyed secrets may not vouch for at all, whatever their value,
+    /// as they would lift the user's budgets and limits.
+    #[serde(default)]
+    max_tokens_per_day: Option<IgnoredAny>,
+    #[serde(default)]
+    max_cost_per_day: Option<IgnoredAny>,
+    #[serde(default)]
+    features: Vec<IgnoredAny>,
+}
+
+impl Scope {
+    fn is_overriding(&self) -> bool {
+        self.max_tokens_per_day.is_some()
+            || self.max_cost_per_day.is_some()
+            || !self.features.is_empty()
+    }
+}
+
+fn default_role() -> String {
+    crate::auth::ROLE_HACKER.to_string()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Token(jsonwebtoken::errors::Error),
+    Jwks(anyhow::Error),
+    Key(anyhow::Error),
+
+    /// Claims beyond what the key of the kid may vouch for.
+    Scope(String),
+}
+
+impl Error {
+    #[must_use]
+    pub fn kind(&self) -> Option<&ErrorKind> {
+        match self {
+            Self::Token(e) => Some(e.kind()),
+            Self::Jwks(_) | Self::Key(_) | Self::Scope(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Token(e) => write!(f, "Invalid token: {e}"),
+            Self::Jwks(e) => write!(f, "Failed to get JWKS key: {e:?}"),
+            Self::Key(e) => write!(f, "Failed to load signing key: {e:?}"),
+            Self::Scope(kid) => {
+                write!(f, "Claims beyond what key {kid:?} may vouch for.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        Self::Token(e)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::Token(kind.into())
+    }
+}
 
 pub fn encode<T>(claims: &T, conf: &conf::Jwt) -> Result<String>
 where
     T: serde::Serialize,
 {
-    let str = jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        claims,
-        &jsonwebtoken::EncodingKey::from_secret(conf.secret.as_bytes()),
-    )?;
+    let Some(key) = &conf.signing_key else {
+        let str = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            claims,
+            &EncodingKey::from_secret(conf.secret.as_bytes()),
+        )?;
+        return Ok(str);
+    };
+    let mut header = jsonwebtoken::Header::new(key.algorithm.into());
+    header.kid = Some(key.kid.clone());
+    let str = jsonwebtoken::encode(&header, claims, &private_key(key)?)?;
     Ok(str)
 }
 
-pub fn decode<T>(str: &str, conf: &conf::Jwt) -> Result<T>
+/// Of a decoded token, which key vouched for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verifier {
+    /// Ours: our secret, an old one, or our signing key.
+    Ours,
+
+    /// Of a third-party issuer, from [`conf::Jwt::jwks_url`].
+    Jwks,
+
+    /// Of a partner, per [`conf::Jwt::keyed_secrets`].
+    Keyed,
+}
+
+pub async fn decode<T>(str: &str, conf: &conf::Jwt) -> Result<(T, Verifier)>
 where
     T: serde::de::DeserializeOwned,
 {
-    let mut validation_opts = jsonwebtoken::Validation::new(Algorithm::HS256);
+    let header = jsonwebtoken::decode_header(str)?;
+    if !ALGORITHMS.contains(&header.alg) {
+        return Err(ErrorKind::InvalidAlgorithm.into());
+    }
+    let mut validation_opts = jsonwebtoken::Validation::new(header.alg);
     validation_opts.leeway = 0; // "exp" should mean what it says.
-    validation_opts.set_audience(&[&conf.audience]);
-    validation_opts.set_issuer(&[&conf.issuer]);
-    let key = jsonwebtoken::DecodingKey::from_secret(conf.secret.as_bytes());
-    let jsonwebtoken::TokenData { claims, .. } =
-        jsonwebtoken::decode::<T>(str, &key, &validation_opts)?;
-    Ok(claims)
+    validation_opts.set_audience(&conf.audiences());
+    validation_opts.set_issuer(&conf.issuers());
+    if let Some(key) = conf.signing_key.as_ref().filter(|key| {
+        header.alg == Algorithm::from(key.algorithm)
+            && header.kid.as_deref() == Some(key.kid.as_str())
+    }) {
+        let key = public_key(key)?;
+        let jsonwebtoken::TokenData { claims, .. } =
+            jsonwebtoken::decode::<T>(str, &key, &validation_opts)?;
+        return Ok((claims, Verifier::Ours));
+    }
+    if header.alg != Algorithm::HS256 {
+        let Some(url) = conf.jwks_url.as_deref() else {
+            return Err(ErrorKind::InvalidAlgorithm.into());
+        };
+        let kid = header.kid.ok_or(ErrorKind::InvalidToken)?;
+        let key = JWKS.key(url, &kid).await?;
+        let jsonwebtoken::TokenData { claims, .. } =
+            jsonwebtoken::decode::<T>(str, &key, &validation_opts)?;
+        return Ok((claims, Verifier::Jwks));
+    }
+    if let Some((kid, keyed)) = header
+        .kid
+        .as_deref()
+        .and_then(|kid| conf.keyed_secrets.get(kid).map(|keyed| (kid, keyed)))
+    {
+        let key = DecodingKey::from_secret(keyed.secret.as_bytes());
+        let jsonwebtoken::TokenData { claims: scope, .. } =
+            jsonwebtoken::decode::<Scope>(str, &key, &validation_opts)?;
+        if !keyed.allows(&scope.sub, &scope.role, scope.org.as_deref())
+            || scope.is_overriding()
+        {
+            return Err(Error::Scope(kid.to_string()));
+        }
+        let jsonwebtoken::TokenData { claims, .. } =
+            jsonwebtoken::decode::<T>(str, &key, &validation_opts)?;
+        return Ok((claims, Verifier::Keyed));
+    }
+    // Of the current secret, unless signed with an old one.
+    let mut first_error = None;
+    for secret in conf.secrets() {
+        let key = DecodingKey::from_secret(secret.as_bytes());
+        match jsonwebtoken::decode::<T>(str, &key, &validation_opts) {
+            Ok(jsonwebtoken::TokenData { claims, .. }) => {
+                return Ok((claims, Verifier::Ours));
+            }
+            Err(error) if *error.kind() == ErrorKind::InvalidSignature => {
+                first_error.get_or_insert(error);
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Err(first_error
+        .unwrap_or_else(|| ErrorKind::InvalidSignature.into())
+        .into())
+}
+
+/// Without verifying anything, for debugging tokens which fail to decode.
+pub fn decode_unverified(
+    str: &str,
+) -> Result<(jsonwebtoken::Header, serde_json::Value)> {
+    let header = jsonwebtoken::decode_header(str)?;
+    let mut validation_opts = jsonwebtoken::Validation::new(header.alg);
+    validation_opts.insecure_disable_signature_validation();
+    validation_opts.validate_exp = false;
+    validation_opts.validate_aud = false;
+    validation_opts.required_spec_claims.clear();
+    let jsonwebtoken::TokenData { claims, .. } = jsonwebtoken::decode(
+        str,
+        &DecodingKey::from_secret(&[]),
+        &validation_opts,
+    )?;
+    Ok((header, claims))
+}
+
+impl From<conf::JwtKeyAlgorithm> for Algorithm {
+    fn from(algorithm: conf::JwtKeyAlgorithm) -> Self {
+        match algorithm {
+            conf::JwtKeyAlgorithm::Eddsa => Self::EdDSA,
+            conf::JwtKeyAlgorithm::Es256 => Self::ES256,
+        }
+    }
+}
+
+/// Until restart, even if the file changes.
+fn private_key(key: &conf::JwtKey) -> Result<EncodingKey> {
+    let mut keys = PRIVATE_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(encoding_key) = keys.get(&key.private_key) {
+        return Ok(encoding_key.clone());
+    }
+    let pem = std::fs::read(&key.private_key)
+        .context(format!("Failed to read: {:?}", key.private_key))
+        .map_err(Error::Key)?;
+    let encoding_key = match key.algorithm {
+        conf::JwtKeyAlgorithm::Eddsa => EncodingKey::from_ed_pem(&pem),
+        conf::JwtKeyAlgorithm::Es256 => EncodingKey::from_ec_pem(&pem),
+    }
+    .map_err(|e| Error::Key(e.into()))?;
+    keys.insert(key.private_key.clone(), encoding_key.clone());
+    Ok(encoding_key)
+}
+
+/// Until restart, even if the file changes.
+fn public_key(key: &conf::JwtKey) -> Result<DecodingKey> {
+    let mut keys = PUBLIC_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(decoding_key) = keys.get(&key.public_key) {
+        return Ok(decoding_key.clone());
+    }
+    let pem = std::fs::read(&key.public_key)
+        .context(format!("Failed to read: {:?}", key.public_key))
+        .map_err(Error::Key)?;
+    let decoding_key = match key.algorithm {
+        conf::JwtKeyAlgorithm::Eddsa => DecodingKey::from_ed_pem(&pem),
+        conf::JwtKeyAlgorithm::Es256 => DecodingKey::from_ec_pem(&pem),
+    }
+    .map_err(|e| Error::Key(e.into()))?;
+    keys.insert(key.public_key.clone(), decoding_key.clone());
+    Ok(decoding_key)
+}
+
+pub struct Keypair {
+    /// PKCS#8 PEM.
+    pub private_pem: String,
+    /// SPKI PEM.
+    pub public_pem: String,
+    /// Of the public key, for others to verify ours with.
+    pub jwk: serde_json::Value,
+}
+
+/// For [`conf::JwtKey`].
+pub fn keygen(
+    algorithm: conf::JwtKeyAlgorithm,
+    kid: &str,
+) -> anyhow::Result<Keypair> {
+    let rng = SystemRandom::new();
+    let (pkcs8, spki, mut jwk) = match algorithm {
+        conf::JwtKeyAlgorithm::Eddsa => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                .map_err(|_| anyhow!("Failed to generate key."))?;
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .map_err(|e| anyhow!("Generated invalid key: {e}"))?;
+            let public = pair.public_key().as_ref();
+            let jwk = serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(public),
+            });
+            (pkcs8.as_ref().to_vec(), spki(SPKI_ED25519, public), jwk)
+        }
+        conf::JwtKeyAlgorithm::Es256 => {
+            let alg = &ECDSA_P256_SHA256_FIXED_SIGNING;
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng)
+                .map_err(|_| anyhow!("Failed to generate key."))?;
+            let pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng)
+                .map_err(|e| anyhow!("Generated invalid key: {e}"))?;
+            // Uncompressed: 0x04, then x and y, 32 bytes each.
+            let public = pair.public_key().as_ref();
+            let jwk = serde_json::json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&public[33..]),
+            });
+            (pkcs8.as_ref().to_vec(), spki(SPKI_P256, public), jwk)
+        }
+    };
+    jwk["use"] = "sig".into();
+    jwk["alg"] = serde_json::to_value(Algorithm::from(algorithm))?;
+    jwk["kid"] = kid.into();
+    Ok(Keypair {
+        private_pem: pem("PRIVATE KEY", &pkcs8),
+        public_pem: pem("PUBLIC KEY", &spki),
+        jwk,
+    })
+}
+
+/// DER of SubjectPublicKeyInfo up to the key itself, which follows.
+const SPKI_ED25519: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const SPKI_P256: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+    0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03,
+    0x42, 0x00,
+];
+
+fn spki(prefix: &[u8], public: &[u8]) -> Vec<u8> {
+    [prefix, public].concat()
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// Keys fetched from a JWKS endpoint, cached by kid.
+#[derive(Default)]
+struct Jwks {
+    state: RwLock<JwksState>,
+}
+
+#[derive(Default)]
+struct JwksState {
+    url: String,
+    keys: HashMap<String, DecodingKey>,
+    fetched_at: Option<Instant>,
+}
+
+impl Jwks {
+    async fn key(&self, url: &str, kid: &str) -> Result<DecodingKey> {
+        {
+            let state = self.state.read().await;
+            if state.url == url {
+                if let Some(key) = state.keys.get(kid) {
+                    return Ok(key.clone());
+                }
+            }
+        }
+        let mut state = self.state.write().await;
+        // Another task may have refreshed while we waited for the lock.
+        if state.url == url {
+            if let Some(key) = state.keys.get(kid) {
+                return Ok(key.clone());
+            }
+        }
+        let is_refresh_allowed = state.url != url
+            || state.fetched_at.is_none_or(|fetched_at| {
+                fetched_at.elapsed() >= JWKS_MIN_REFRESH_INTERVAL
+            });
+        if is_refresh_allowed {
+            tracing::info!(?url, ?kid, "Fetching JWKS.");
+            let keys = fetch(url).await.map_err(Error::Jwks)?;
+            *state = JwksState {
+                url: url.to_string(),
+                keys,
+                fetched_at: Some(Instant::now()),
+            };
+        }
+        state.keys.get(kid).cloned().ok_or_else(|| {
+            tracing::debug!(?kid, "Key ID not found in JWKS.");
+            ErrorKind::InvalidToken.into()
+        })
+    }
+}
+
+async fn fetch(url: &str) -> anyhow::Result<HashMap<String, DecodingKey>> {
+    let set: JwkSet =
+        reqwest::get(url).await?.error_for_status()?.json().await?;
+    let mut keys = HashMap::new();
+    for jwk in &set.keys {
+        let Some(kid) = jwk.common.key_id.as_ref() else {
+            tracing::warn!(?jwk, "Skipping JWK without a key ID.");
+            continue;
+        };
+        match DecodingKey::from_jwk(jwk) {
+            Ok(key) => {
+                keys.insert(kid.clone(), key);
+            }
+            Err(error) => {
+                tracing::warn!(?kid, ?error, "Skipping unusable JWK.");
+            }
+        }
+    }
+    Ok(keys)
 }
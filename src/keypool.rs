@@ -0,0 +1,417 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::HeaderMap, StatusCode};
+
+/// How long to sideline a key which got throttled without upstream telling
+/// us for how long.
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(60);
+
+/// Upstream API keys, used round-robin, skipping the ones which upstream
+/// currently throttles.
+pub struct KeyPool {
+    /// Of the provider, for metrics.
+    name: String,
+    keys: Vec<Key>,
+    next: AtomicUsize,
+}
+
+struct Key {
+    token: String,
+    state: Mutex<KeyState>,
+}
+
+#[derive(Debug, Default)]
+struct KeyState {
+    requests: Limit,
+    tokens: Limit,
+    throttled_until: Option<Instant>,
+}
+
+/// As last reported by upstream.
+#[derive(Debug, Default)]
+struct Limit {
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    reset_at: Option<Instant>,
+}
+
+impl Limit {
+    fn from_headers(headers: &HeaderMap, of: &str, now: Instant) -> Self {
+        Self {
+            limit: header_u64(headers, &format!("x-ratelimit-limit-{of}")),
+            remaining: header_u64(
+                headers,
+                &format!("x-ratelimit-remaining-{of}"),
+            ),
+            reset_at: header_duration(
+                headers,
+                &format!("x-ratelimit-reset-{of}"),
+            )
+            .map(|reset| now + reset),
+        }
+    }
+
+    /// Back to the full limit once reset.
+    fn remaining(&self, now: Instant) -> Option<u64> {
+        match self.reset_at {
+            Some(reset_at) if reset_at <= now => self.limit,
+            _ => self.remaining,
+        }
+    }
+}
+
+/// Of the keys' rate limits together, as last reported by upstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Headroom {
+    /// Of requests or tokens, whichever is lower, from 0 to 1.
+    pub fraction: f64,
+
+    /// Until the soonest limit resets, if known.
+    pub resets_in: Option<Duration>,
+}
+
+/// A key picked for a single upstream request.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    index: usize,
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub enum Pick {
+    Key(Lease),
+
+    /// No keys configured, so requests go out without auth.
+    Anonymous,
+
+    /// All keys are throttled. Retry after the earliest becomes available.
+    Throttled {
+        retry_after: Duration,
+    },
+}
+
+impl Lease {
+    /// Of the key, in conf, to tell which it was without the key itself.
+    #[must_use]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl KeyPool {
+    #[must_use]
+    pub fn new(name: &str, tokens: &[String]) -> Self {
+        let keys = tokens
+            .iter()
+            .map(|token| Key {
+                token: token.clone(),
+                state: Mutex::new(KeyState::default()),
+            })
+            .collect();
+        Self {
+            name: name.to_string(),
+            keys,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// `None` until upstream reported both the limits and what remains of
+    /// them.
+    #[must_use]
+    pub fn headroom(&self) -> Option<Headroom> {
+        let now = Instant::now();
+        // Of requests and of tokens: remaining and limit, summed over keys.
+        let mut sums = [(0_u64, 0_u64); 2];
+        let mut resets_in: Option<Duration> = None;
+        for key in &self.keys {
+            let state = key.state();
+            for (sum, limit) in
+                sums.iter_mut().zip([&state.requests, &state.tokens])
+            {
+                if let (Some(remaining), Some(max)) =
+                    (limit.remaining(now), limit.limit)
+                {
+                    sum.0 = sum.0.saturating_add(remaining.min(max));
+                    sum.1 = sum.1.saturating_add(max);
+                }
+                if let Some(left) = limit
+                    .reset_at
+                    .and_then(|reset_at| reset_at.checked_duration_since(now))
+                    .filter(|left| !left.is_zero())
+                {
+                    resets_in = Some(resets_in.map_or(left, |r| r.min(left)));
+                }
+            }
+        }
+        let fraction = sums
+            .iter()
+            .filter(|(_, max)| *max > 0)
+            .map(|(remaining, max)| u64_f64(*remaining) / u64_f64(*max))
+            .reduce(f64::min)?;
+        Some(Headroom {
+            fraction,
+            resets_in,
+        })
+    }
+
+    pub fn pick(&self) -> Pick {
+        if self.keys.is_empty() {
+            return Pick::Anonymous;
+        }
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut earliest: Option<Instant> = None;
+        for offset in 0..self.keys.len() {
+            let index = (start + offset) % self.keys.len();
+            let key = &self.keys[index];
+            let mut state = key.state();
+            match state.throttled_until {
+                Some(until) if until > now => {
+                    earliest = Some(earliest.map_or(until, |e| e.min(until)));
+                }
+                Some(_) => {
+                    // Expired. Whatever we knew about the remaining counts
+                    // was reset by now.
+                    *state = KeyState::default();
+                    return Pick::Key(key.lease(index));
+                }
+                None => return Pick::Key(key.lease(index)),
+            }
+        }
+        let retry_after = earliest.map_or(DEFAULT_THROTTLE, |until| {
+            until.saturating_duration_since(now)
+        });
+        Pick::Throttled { retry_after }
+    }
+
+    /// Records what upstream told us about the key's rate limits.
+    pub fn update(
+        &self,
+        lease: &Lease,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) {
+        let Some(key) = self.keys.get(lease.index) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut state = key.state();
+        state.requests = Limit::from_headers(headers, "requests", now);
+        state.tokens = Limit::from_headers(headers, "tokens", now);
+        for (of, remaining) in [
+            ("requests", state.requests.remaining),
+            ("tokens", state.tokens.remaining),
+        ] {
+            if let Some(remaining) = remaining {
+                metrics::gauge!(
+                    "raskol_upstream_remaining",
+                    "provider" => self.name.clone(),
+                    "key" => lease.index.to_string(),
+                    "of" => of,
+                )
+                .set(u64_f64(remaining));
+            }
+        }
+        let throttle = if status == StatusCode::TOO_MANY_REQUESTS {
+            Some(
+                header_duration(headers, "retry-after")
+                    .or_else(|| {
+                        header_duration(headers, "x-ratelimit-reset-requests")
+                    })
+                    .unwrap_or(DEFAULT_THROTTLE),
+            )
+        } else {
+            let requests_reset = (state.requests.remaining == Some(0))
+                .then(|| {
+                    header_duration(headers, "x-ratelimit-reset-requests")
+                })
+                .map(|reset| reset.unwrap_or(DEFAULT_THROTTLE));
+            let tokens_reset = (state.tokens.remaining == Some(0))
+                .then(|| header_duration(headers, "x-ratelimit-reset-tokens"))
+                .map(|reset| reset.unwrap_or(DEFAULT_THROTTLE));
+            requests_reset.max(tokens_reset)
+        };
+        state.throttled_until = throttle.map(|duration| {
+            tracing::warn!(
+                key = lease.index,
+                ?duration,
+                ?state,
+                "Upstream key throttled."
+            );
+            now + duration
+        });
+        drop(state);
+        if let Some(headroom) = self.headroom() {
+            metrics::gauge!(
+                "raskol_upstream_headroom",
+                "provider" => self.name.clone(),
+            )
+            .set(headroom.fraction);
+        }
+    }
+}
+
+impl Key {
+    fn state(&self) -> std::sync::MutexGuard<'_, KeyState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn lease(&self, index: usize) -> Lease {
+        Lease {
+            index,
+            token: self.token.clone(),
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)] // Limits are nowhere near.
+fn u64_f64(n: u64) -> f64 {
+    n as f64
+}
+
+/// The standard `Retry-After` header, in seconds.
+#[must_use]
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    header_duration(headers, "retry-after")
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn header_duration(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    parse_duration(headers.get(name)?.to_str().ok()?)
+}
+
+/// Parses durations as given in rate-limit headers: either plain seconds
+/// (`"7"`, `"7.66"`) or Go-style (`"2m59.56s"`, `"1h2m"`, `"250ms"`).
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let mut total = 0.0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let unit_start = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .filter(|i| *i > 0)?;
+        let (num, tail) = rest.split_at(unit_start);
+        let num: f64 = num.parse().ok()?;
+        let unit_end = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        let multiplier = match unit {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        total += num * multiplier;
+        rest = tail;
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use super::{parse_duration, KeyPool, Pick};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("7"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_duration("7.5s"), Some(Duration::from_millis(7500)));
+        assert_eq!(parse_duration("2m30s"), Some(Duration::from_secs(150)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("5x"), None);
+    }
+
+    #[test]
+    fn rotation_skips_throttled() {
+        let pool = KeyPool::new("test", &["a".to_string(), "b".to_string()]);
+        let Pick::Key(a) = pool.pick() else { panic!() };
+        let Pick::Key(b) = pool.pick() else { panic!() };
+        assert_ne!(a.token, b.token);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "30".parse().unwrap());
+        pool.update(&a, StatusCode::TOO_MANY_REQUESTS, &headers);
+        for _ in 0..3 {
+            let Pick::Key(key) = pool.pick() else {
+                panic!()
+            };
+            assert_eq!(key.token, b.token);
+        }
+
+        let mut headers = HeaderMap::new();
+        headers
+            .insert("x-ratelimit-remaining-requests", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset-requests", "10s".parse().unwrap());
+        pool.update(&b, StatusCode::OK, &headers);
+        let Pick::Throttled { retry_after } = pool.pick() else {
+            panic!()
+        };
+        assert!(retry_after <= Duration::from_secs(10));
+        assert!(retry_after > Duration::from_secs(9));
+    }
+
+    #[test]
+    fn headroom() {
+        let pool = KeyPool::new("test", &["a".to_string(), "b".to_string()]);
+        assert_eq!(pool.headroom(), None);
+        let Pick::Key(a) = pool.pick() else { panic!() };
+        let Pick::Key(b) = pool.pick() else { panic!() };
+        pool.update(
+            &a,
+            StatusCode::OK,
+            &headers(&[
+                ("x-ratelimit-limit-requests", "100"),
+                ("x-ratelimit-remaining-requests", "10"),
+                ("x-ratelimit-reset-requests", "30s"),
+                ("x-ratelimit-limit-tokens", "1000"),
+                ("x-ratelimit-remaining-tokens", "500"),
+            ]),
+        );
+        let headroom = pool.headroom().unwrap();
+        assert!((headroom.fraction - 0.1).abs() < 1e-9);
+        assert!(headroom.resets_in.unwrap() <= Duration::from_secs(30));
+
+        // Tokens now lower, over both keys.
+        pool.update(
+            &b,
+            StatusCode::OK,
+            &headers(&[
+                ("x-ratelimit-limit-requests", "100"),
+                ("x-ratelimit-remaining-requests", "90"),
+                ("x-ratelimit-limit-tokens", "1000"),
+                ("x-ratelimit-remaining-tokens", "100"),
+            ]),
+        );
+        let headroom = pool.headroom().unwrap();
+        assert!((headroom.fraction - 0.3).abs() < 1e-9);
+    }
+}
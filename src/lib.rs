@@ -1,7 +1,73 @@
+pub mod abuse;
+pub mod access;
+pub mod admin;
+pub mod attest;
+pub mod audio;
 pub mod auth;
+pub mod batch;
+pub mod billing;
+pub mod breaker;
 pub mod chat;
+pub mod compress;
 pub mod conf;
 pub mod data;
+pub mod dbcopy;
+pub mod deprecation;
+pub mod dialect;
+pub mod docs;
+pub mod duplicates;
+pub mod endpoint;
+pub mod events;
+pub mod export;
+pub mod files;
+pub mod filter;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod headers;
+pub mod hook;
+pub mod jobs;
 pub mod jwt;
+pub mod keypool;
+pub mod lint;
+pub mod listener;
+pub mod loadtest;
+pub mod media;
+pub mod mock;
+pub mod moderation;
+pub mod overage;
+pub mod peers;
+pub mod period;
+pub mod preferences;
+pub mod prober;
+pub mod provenance;
+pub mod public;
+pub mod push;
+pub mod queue;
+pub mod ratelimit;
+pub mod realtime;
+pub mod redact;
+pub mod replay;
+pub mod schedule;
 pub mod server;
+#[cfg(feature = "redis")]
+pub mod shared;
+pub mod signing;
+pub mod signup;
+pub mod slow;
+pub mod spend;
+pub mod streaming;
+pub mod stripe;
+pub mod template;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tokenizer;
 pub mod tracing;
+pub mod upstream;
+pub mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod ws;
+
+pub use server::{Routes, Server};
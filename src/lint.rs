@@ -0,0 +1,303 @@
+//! Of chat request bodies, what is wrong with them, each by where it is and
+//! how to fix it, rather than the first error of parsing, which tells
+//! little. In strict mode, per [`crate::conf::Conf::strict_requests`],
+//! fields unknown to the API are too, since they are likely typos, as
+//! `max_token`, which upstream would otherwise ignore.
+
+use serde_json::Value;
+
+/// Of the request body, as OpenAI's API takes it, and ours, `tags`.
+const FIELDS: [&str; 33] = [
+    "audio",
+    "frequency_penalty",
+    "function_call",
+    "functions",
+    "logit_bias",
+    "logprobs",
+    "max_completion_tokens",
+    "max_tokens",
+    "messages",
+    "metadata",
+    "modalities",
+    "model",
+    "n",
+    "parallel_tool_calls",
+    "prediction",
+    "presence_penalty",
+    "prompt_cache_key",
+    "reasoning_effort",
+    "response_format",
+    "seed",
+    "service_tier",
+    "stop",
+    "store",
+    "stream",
+    "stream_options",
+    "tags",
+    "temperature",
+    "tool_choice",
+    "tools",
+    "top_logprobs",
+    "top_p",
+    "user",
+    "web_search_options",
+];
+
+const ROLES: [&str; 6] = [
+    "assistant",
+    "developer",
+    "function",
+    "system",
+    "tool",
+    "user",
+];
+
+/// How far off a field may be from a known one to be suggested instead.
+const MAX_TYPO_DISTANCE: usize = 2;
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Of the body, as in `messages[0].content`. Empty for the whole.
+    pub param: String,
+    pub message: String,
+}
+
+/// Of a chat request body, none when it would do.
+#[must_use]
+pub fn chat(req: &Value, is_strict: bool) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    let Some(req) = req.as_object() else {
+        problems.expected("", "an object", req);
+        return problems.0;
+    };
+    match req.get("model") {
+        None => problems.add(
+            "model",
+            "Missing. The model to use, as \"gpt-4o-mini\".".to_string(),
+        ),
+        Some(Value::String(_)) => {}
+        Some(model) => problems.expected("model", "a string", model),
+    }
+    match req.get("messages") {
+        None => problems.add(
+            "messages",
+            "Missing. The conversation so far, as \
+            [{\"role\": \"user\", \"content\": \"Hi\"}]."
+                .to_string(),
+        ),
+        Some(Value::Array(messages)) => {
+            for (i, msg) in messages.iter().enumerate() {
+                problems.msg(&format!("messages[{i}]"), msg, is_strict);
+            }
+        }
+        Some(messages) => problems.expected(
+            "messages",
+            "an array of messages, as \
+            [{\"role\": \"user\", \"content\": \"Hi\"}]",
+            messages,
+        ),
+    }
+    for field in ["max_tokens", "max_completion_tokens"] {
+        match req.get(field) {
+            None | Some(Value::Null) => {}
+            Some(Value::Number(n)) if n.is_u64() => {}
+            Some(value) => {
+                problems.expected(field, "a whole number, 0 or more", value);
+            }
+        }
+    }
+    match req.get("temperature") {
+        None | Some(Value::Null | Value::Number(_)) => {}
+        Some(value) => problems.expected("temperature", "a number", value),
+    }
+    match req.get("stream") {
+        None | Some(Value::Null | Value::Bool(_)) => {}
+        Some(value) => problems.expected("stream", "true or false", value),
+    }
+    if is_strict {
+        for field in
+            req.keys().filter(|field| !FIELDS.contains(&field.as_str()))
+        {
+            let message = match suggest(field) {
+                Some(known) => {
+                    format!("Unknown field. Did you mean {known:?}?")
+                }
+                None => "Unknown field.".to_string(),
+            };
+            problems.add(field, message);
+        }
+    }
+    problems.0
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, param: &str, message: String) {
+        self.0.push(Problem {
+            param: param.to_string(),
+            message,
+        });
+    }
+
+    fn expected(&mut self, param: &str, expected: &str, got: &Value) {
+        self.add(param, format!("Expected {expected}, got {}.", kind(got)));
+    }
+
+    fn msg(&mut self, param: &str, msg: &Value, is_strict: bool) {
+        let Some(msg) = msg.as_object() else {
+            self.expected(
+                param,
+                "a message, as {\"role\": \"user\", \"content\": \"Hi\"}",
+                msg,
+            );
+            return;
+        };
+        let role = format!("{param}.role");
+        match msg.get("role") {
+            None => self.add(
+                &role,
+                format!("Missing. One of: {}.", ROLES.join(", ")),
+            ),
+            Some(Value::String(name)) => {
+                if is_strict && !ROLES.contains(&name.as_str()) {
+                    self.add(
+                        &role,
+                        format!(
+                            "Unknown role {name:?}. One of: {}.",
+                            ROLES.join(", ")
+                        ),
+                    );
+                }
+            }
+            Some(value) => self.expected(&role, "a string", value),
+        }
+        let content = format!("{param}.content");
+        match msg.get("content") {
+            None | Some(Value::Null | Value::String(_)) => {}
+            Some(Value::Array(parts)) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if part.get("type").and_then(Value::as_str).is_none() {
+                        self.expected(
+                            &format!("{content}[{i}]"),
+                            "a content item, with its type, as \
+                            {\"type\": \"text\", \"text\": \"Hi\"}",
+                            part,
+                        );
+                    }
+                }
+            }
+            Some(value) => self.expected(
+                &content,
+                "a string or an array of content items, as \
+                [{\"type\": \"text\", \"text\": \"Hi\"}]",
+                value,
+            ),
+        }
+        match msg.get("name") {
+            None | Some(Value::Null | Value::String(_)) => {}
+            Some(value) => {
+                self.expected(&format!("{param}.name"), "a string", value);
+            }
+        }
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// The known field closest to the unknown one, if close enough. Not of
+/// those so short that anything would be close.
+fn suggest(field: &str) -> Option<&'static str> {
+    FIELDS
+        .into_iter()
+        .filter(|known| known.len() > 2 * MAX_TYPO_DISTANCE)
+        .map(|known| (distance(field, known), known))
+        .filter(|(distance, _)| *distance <= MAX_TYPO_DISTANCE)
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein's, of characters.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Problem;
+
+    fn params(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|p| p.param.as_str()).collect()
+    }
+
+    #[test]
+    fn chat() {
+        let req = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "max_token": 100,
+            "tags": {"team": "a"},
+        });
+        assert_eq!(super::chat(&req, false), []);
+        let problems = super::chat(&req, true);
+        assert_eq!(params(&problems), ["max_token"]);
+        assert_eq!(
+            problems[0].message,
+            "Unknown field. Did you mean \"max_tokens\"?"
+        );
+
+        let req = json!({
+            "messages": [
+                {"role": "user", "content": {"text": "Hi"}},
+                {"content": [{"text": "Hi"}]},
+                "Hi",
+            ],
+            "max_tokens": -1,
+            "temperature": "0.5",
+        });
+        let problems = super::chat(&req, false);
+        assert_eq!(
+            params(&problems),
+            [
+                "model",
+                "messages[0].content",
+                "messages[1].role",
+                "messages[1].content[0]",
+                "messages[2]",
+                "max_tokens",
+                "temperature",
+            ]
+        );
+        assert!(problems[1]
+            .message
+            .starts_with("Expected a string or an array of content items"));
+        assert!(problems[1].message.ends_with("got an object."));
+
+        assert_eq!(super::distance("max_token", "max_tokens"), 1);
+        assert_eq!(super::distance("temprature", "temperature"), 1);
+        assert_eq!(super::suggest("banana"), None);
+    }
+}
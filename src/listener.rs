@@ -0,0 +1,282 @@
+//! Where the server listens: TCP on each addr:port, by default, or a Unix
+//! socket, its own or passed by systemd socket activation, per
+//! [`conf::Listen`], unless it serves TLS, which is only on TCP.
+
+use std::{future::Future, io, net::SocketAddr};
+#[cfg(unix)]
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use axum::Router;
+
+use crate::conf;
+
+/// Peers on Unix sockets have no address, so they are given this one,
+/// since they are local, as is the proxy in front of us.
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// First of the file descriptors passed by systemd.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+
+        /// Of the socket, to remove once done, unless systemd made it.
+        path: Option<PathBuf>,
+    },
+}
+
+impl Listener {
+    pub fn bind_all(conf: &conf::Conf) -> anyhow::Result<Vec<Self>> {
+        let listen = &conf.listen;
+        #[cfg(unix)]
+        if listen.systemd {
+            return Ok(vec![Self::inherited()?]);
+        }
+        #[cfg(unix)]
+        if let Some(path) = &listen.unix_path {
+            return Ok(vec![Self::bind_unix(
+                path.clone(),
+                listen.unix_mode,
+            )?]);
+        }
+        if listen.is_set() {
+            bail!("Unix sockets are not supported on this platform.");
+        }
+        bind_tcp(conf)?
+            .into_iter()
+            .map(|listener| {
+                let addr = listener.local_addr()?;
+                tracing::warn!(?addr, "Listening unencrypted.");
+                Ok(Self::Tcp(tokio::net::TcpListener::from_std(listener)?))
+            })
+            .collect()
+    }
+
+    /// Until the shutdown signal, after which connections in flight are
+    /// waited for.
+    pub async fn serve<F>(
+        self,
+        app: Router,
+        shutdown: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self {
+            Self::Tcp(listener) => {
+                let routes =
+                    app.into_make_service_with_connect_info::<SocketAddr>();
+                axum::serve(listener, routes)
+                    .with_graceful_shutdown(shutdown)
+                    .await?;
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, path } => {
+                serve_unix(listener, app, shutdown).await;
+                if let Some(path) = path {
+                    if let Err(error) = std::fs::remove_file(&path) {
+                        tracing::warn!(?error, ?path, "Failed to remove.");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: PathBuf, mode: u32) -> anyhow::Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let is_stale = std::fs::symlink_metadata(&path)
+            .is_ok_and(|meta| meta.file_type().is_socket());
+        if is_stale {
+            std::fs::remove_file(&path)
+                .context(format!("Failed to remove old socket: {path:?}"))?;
+        }
+        let listener = tokio::net::UnixListener::bind(&path)
+            .context(format!("Failed to bind Unix socket: {path:?}"))?;
+        std::fs::set_permissions(
+            &path,
+            std::fs::Permissions::from_mode(mode),
+        )
+        .context(format!("Failed to set permissions of: {path:?}"))?;
+        tracing::info!(?path, mode = format!("{mode:o}"), "Listening.");
+        Ok(Self::Unix {
+            listener,
+            path: Some(path),
+        })
+    }
+
+    /// The first socket systemd passed us, whichever kind it is.
+    #[cfg(unix)]
+    fn inherited() -> anyhow::Result<Self> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        let fds = listen_fds(
+            std::process::id(),
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+        )?;
+        if fds > 1 {
+            tracing::warn!(fds, "Passed more sockets than 1. Using the 1st.");
+        }
+        // SAFETY: systemd passed it to us, so nothing else owns it, and we
+        // take it only once.
+        let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        // Fails for sockets of other families.
+        if let Ok(addr) = unix.local_addr() {
+            unix.set_nonblocking(true)?;
+            tracing::info!(?addr, "Listening on socket from systemd.");
+            return Ok(Self::Unix {
+                listener: tokio::net::UnixListener::from_std(unix)?,
+                path: None,
+            });
+        }
+        let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+        let addr = tcp
+            .local_addr()
+            .context("Socket from systemd is neither Unix nor TCP.")?;
+        tcp.set_nonblocking(true)?;
+        tracing::warn!(
+            ?addr,
+            "Listening unencrypted on socket from systemd."
+        );
+        Ok(Self::Tcp(tokio::net::TcpListener::from_std(tcp)?))
+    }
+}
+
+/// To each of the addresses. IPv6 ones take only IPv6 if there are others,
+/// since on Linux they'd take IPv4 too, and clash with IPv4 ones.
+pub fn bind_tcp(
+    conf: &conf::Conf,
+) -> anyhow::Result<Vec<std::net::TcpListener>> {
+    if conf.addr.is_empty() {
+        bail!("No addresses to listen on.");
+    }
+    let is_only_v6 = conf.addr.len() > 1;
+    conf.addr
+        .iter()
+        .map(|ip| {
+            let addr = SocketAddr::new(*ip, conf.port);
+            bind_tcp_one(addr, is_only_v6)
+                .context(format!("Failed to bind: {addr}"))
+        })
+        .collect()
+}
+
+fn bind_tcp_one(
+    addr: SocketAddr,
+    is_only_v6: bool,
+) -> io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(is_only_v6)?;
+    }
+    // As tokio does, so that restarts needn't wait out TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// axum only serves TCP, so we drive hyper ourselves.
+#[cfg(unix)]
+async fn serve_unix<F>(
+    listener: tokio::net::UnixListener,
+    app: Router,
+    shutdown: F,
+) where
+    F: Future<Output = ()> + Send + 'static,
+{
+    use axum::{extract::ConnectInfo, Extension};
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+
+    let app = app.layer(Extension(ConnectInfo(UNIX_PEER)));
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    // Such as running out of file descriptors, which may
+                    // pass, so not worth stopping for.
+                    tracing::error!(?error, "Failed to accept connection.");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let conn = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let conn = graceful.watch(conn);
+        tokio::spawn(async move {
+            if let Err(error) = conn.await {
+                tracing::debug!(?error, "Connection failed.");
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+/// How many sockets systemd passed, if to us, since the variables are
+/// inherited by children too.
+#[cfg(unix)]
+fn listen_fds(
+    pid: u32,
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+) -> anyhow::Result<usize> {
+    let listen_pid: u32 = listen_pid
+        .context("LISTEN_PID not set. Not socket-activated?")?
+        .parse()
+        .context("Invalid LISTEN_PID.")?;
+    if listen_pid != pid {
+        bail!("Sockets are for PID {listen_pid}, not ours: {pid}.");
+    }
+    let fds: usize = listen_fds
+        .context("LISTEN_FDS not set.")?
+        .parse()
+        .context("Invalid LISTEN_FDS.")?;
+    if fds == 0 {
+        bail!("No sockets passed by systemd.");
+    }
+    Ok(fds)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::listen_fds;
+
+    #[test]
+    fn systemd_env() {
+        assert_eq!(listen_fds(42, Some("42"), Some("1")).unwrap(), 1);
+        assert!(listen_fds(42, Some("7"), Some("1")).is_err());
+        assert!(listen_fds(42, Some("42"), Some("0")).is_err());
+        assert!(listen_fds(42, None, None).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Synthetic chat traffic against a running instance, for sizing hardware
+//! before an event. Requests are made at a fixed rate, from users of
+//! ephemeral JWTs in turn, and timed from sending to the end of the
+//! response, so that the latencies are as clients see them.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use tokio::{task::JoinSet, time::Instant};
+
+use crate::{auth, chat, conf, jwt};
+
+/// Of the JWTs' subjects, so that their usage is easy to tell apart.
+pub const UID_PREFIX: &str = "loadtest-";
+
+/// Of requests, beyond which they count as failed.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    /// Of the instance, e.g. "http://127.0.0.1:3001".
+    pub url: String,
+
+    pub users: u32,
+
+    /// Requests per second, of all users together.
+    pub rps: f64,
+
+    pub duration: Duration,
+    pub model: String,
+    pub max_tokens: u32,
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct Report {
+    pub requests: u64,
+    pub ok: u64,
+
+    /// Of users whose budget was used up.
+    pub budget_rejected: u64,
+
+    /// Of other 429s, such as of rate limits.
+    pub rate_limited: u64,
+
+    /// Of other statuses, by status.
+    pub failed: BTreeMap<u16, u64>,
+
+    /// Of requests which got no response.
+    pub unanswered: u64,
+
+    /// Of those made, over the duration.
+    pub rps: f64,
+
+    /// Of all answered, in milliseconds.
+    pub latency_p50: u64,
+    pub latency_p90: u64,
+    pub latency_p99: u64,
+    pub latency_max: u64,
+}
+
+/// One per user, valid for the duration of the test and a minute after,
+/// for requests still in flight.
+pub fn mint(
+    users: u32,
+    duration: Duration,
+    jwt_conf: &conf::Jwt,
+) -> anyhow::Result<Vec<String>> {
+    let ttl = duration.saturating_add(Duration::from_secs(60));
+    (0..users.max(1))
+        .map(|i| {
+            let claims = auth::Claims::new(
+                &format!("{UID_PREFIX}{i}"),
+                ttl,
+                jwt_conf,
+            )?;
+            jwt::encode(&claims, jwt_conf)
+        })
+        .collect()
+}
+
+/// Waits for the requests still in flight when the duration is up.
+pub async fn run(
+    http: &reqwest::Client,
+    test: &LoadTest,
+    tokens: &[String],
+) -> anyhow::Result<Report> {
+    if tokens.is_empty() {
+        anyhow::bail!("No users to make requests as.");
+    }
+    let Some(period) = period(test.rps) else {
+        anyhow::bail!("Invalid requests per second: {}.", test.rps);
+    };
+    let url =
+        format!("{}/v1/chat/completions", test.url.trim_end_matches('/'));
+    let body = serde_json::json!({
+        "model": test.model,
+        "max_tokens": test.max_tokens,
+        "messages": [{
+            "role": "user",
+            "content": "Say something about load testing.",
+        }],
+    });
+    let mut interval = tokio::time::interval(period);
+    let mut in_flight = JoinSet::new();
+    let start = Instant::now();
+    let mut made: usize = 0;
+    while start.elapsed() < test.duration {
+        interval.tick().await;
+        let req = http
+            .post(&url)
+            .timeout(TIMEOUT)
+            .bearer_auth(&tokens[made % tokens.len()])
+            .json(&body);
+        in_flight.spawn(async move {
+            let sent = Instant::now();
+            let outcome = send(req).await;
+            (outcome, sent.elapsed())
+        });
+        made += 1;
+    }
+    let elapsed = start.elapsed();
+    let mut report = Report::default();
+    let mut latencies = Vec::with_capacity(made);
+    while let Some(joined) = in_flight.join_next().await {
+        let (outcome, latency) = joined.context("Request task failed.")?;
+        report.requests += 1;
+        match outcome {
+            Outcome::Ok => report.ok += 1,
+            Outcome::BudgetRejected => report.budget_rejected += 1,
+            Outcome::RateLimited => report.rate_limited += 1,
+            Outcome::Failed(status) => {
+                *report.failed.entry(status).or_default() += 1;
+            }
+            Outcome::Unanswered => {
+                report.unanswered += 1;
+                continue;
+            }
+        }
+        latencies.push(latency);
+    }
+    #[allow(clippy::cast_precision_loss)]
+    {
+        report.rps = report.requests as f64 / elapsed.as_secs_f64();
+    }
+    latencies.sort_unstable();
+    report.latency_p50 = millis(percentile(&latencies, 50));
+    report.latency_p90 = millis(percentile(&latencies, 90));
+    report.latency_p99 = millis(percentile(&latencies, 99));
+    report.latency_max = millis(latencies.last().copied());
+    Ok(report)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    BudgetRejected,
+    RateLimited,
+    Failed(u16),
+    Unanswered,
+}
+
+async fn send(req: reqwest::RequestBuilder) -> Outcome {
+    let resp = match req.send().await {
+        Ok(resp) => resp,
+        Err(error) => {
+            tracing::debug!(?error, "Request failed.");
+            return Outcome::Unanswered;
+        }
+    };
+    let status = resp.status().as_u16();
+    // Read through, so that streaming and slow bodies are timed in full.
+    let Ok(body) = resp.text().await else {
+        return Outcome::Unanswered;
+    };
+    outcome(status, &body)
+}
+
+fn outcome(status: u16, body: &str) -> Outcome {
+    match status {
+        200..=299 => Outcome::Ok,
+        429 => match chat::Error::code_from_resp_body(body).as_deref() {
+            Some("budget_exceeded") => Outcome::BudgetRejected,
+            _ => Outcome::RateLimited,
+        },
+        status => Outcome::Failed(status),
+    }
+}
+
+/// Of sorted latencies, by the nearest rank.
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+fn millis(latency: Option<Duration>) -> u64 {
+    latency.map_or(0, |latency| {
+        u64::try_from(latency.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// Between requests, at the rate. None unless it is positive, and the
+/// period is of a nanosecond at least, as an interval must be.
+fn period(rps: f64) -> Option<Duration> {
+    Some(rps)
+        .filter(|rps| rps.is_finite() && *rps > 0.0)
+        .and_then(|rps| Duration::try_from_secs_f64(1.0 / rps).ok())
+        .filter(|period| !period.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{outcome, percentile, period, Outcome};
+
+    #[test]
+    fn periods() {
+        assert_eq!(period(4.0), Some(Duration::from_millis(250)));
+        assert_eq!(period(0.0), None);
+        assert_eq!(period(-1.0), None);
+        assert_eq!(period(f64::NAN), None);
+        assert_eq!(period(1e10), None);
+        assert_eq!(period(1e-300), None);
+    }
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<Duration> =
+            (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50), Some(Duration::from_millis(50)));
+        assert_eq!(percentile(&sorted, 99), Some(Duration::from_millis(99)));
+        assert_eq!(
+            percentile(&sorted[..1], 90),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn outcomes() {
+        let budget = r#"{"error":{"message":"","type":"insufficient_quota",
+            "param":null,"code":"budget_exceeded"}}"#;
+        assert_eq!(outcome(200, ""), Outcome::Ok);
+        assert_eq!(outcome(429, budget), Outcome::BudgetRejected);
+        assert_eq!(outcome(429, "{}"), Outcome::RateLimited);
+        assert_eq!(outcome(502, ""), Outcome::Failed(502));
+    }
+}
@@ -0,0 +1,170 @@
+//! Text-to-speech and image generation, which are measured in characters
+//! and images rather than tokens. Requests are passed through as is; we
+//! only read enough of them to count their units.
+
+use crate::{conf, data};
+
+pub const SPEECH_ENDPOINT: &str = "v1/audio/speech";
+pub const IMAGES_ENDPOINT: &str = "v1/images/generations";
+
+/// Upstream's, for image requests which don't name one.
+pub const IMAGES_MODEL_DEFAULT: &str = "dall-e-2";
+
+/// Side of the image size which [`conf::Media::tokens_per_image`] is of.
+const IMAGE_SIDE: f64 = 1024.0;
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct SpeechReq {
+    pub model: String,
+    pub input: String,
+    pub voice: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct ImagesReq {
+    /// [`IMAGES_MODEL_DEFAULT`] when not given.
+    pub model: Option<String>,
+    pub prompt: String,
+    pub n: Option<usize>,
+    pub size: Option<String>,
+}
+
+/// What a request is measured in.
+#[derive(Debug, Clone)]
+pub struct Units {
+    /// [`data::Unit::Characters`] or [`data::Unit::Images`].
+    pub unit: data::Unit,
+    pub quantity: f64,
+
+    /// Qualifies the unit, e.g. the size of the images.
+    pub detail: Option<String>,
+
+    /// Of each unit, relative to the rates in conf.
+    weight: f64,
+}
+
+impl Units {
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // Inputs are nowhere near.
+    pub fn of_speech(req: &SpeechReq) -> Self {
+        Self {
+            unit: data::Unit::Characters,
+            quantity: req.input.chars().count() as f64,
+            detail: req.voice.clone(),
+            weight: 1.0,
+        }
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn of_images(req: &ImagesReq) -> Self {
+        let size = req.size.clone().unwrap_or_else(|| "1024x1024".into());
+        let weight = pixels(&size)
+            .map_or(1.0, |pixels| pixels / (IMAGE_SIDE * IMAGE_SIDE));
+        Self {
+            unit: data::Unit::Images,
+            quantity: req.n.unwrap_or(1) as f64,
+            detail: Some(size),
+            weight,
+        }
+    }
+
+    /// The same, but as many as upstream says it generated, if it says.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn generated(&self, resp_body: &[u8]) -> Self {
+        #[derive(serde::Deserialize)]
+        struct Resp {
+            data: Vec<serde_json::Value>,
+        }
+
+        if self.unit != data::Unit::Images {
+            return self.clone();
+        }
+        match serde_json::from_slice::<Resp>(resp_body) {
+            Ok(resp) => Self {
+                quantity: resp.data.len() as f64,
+                ..self.clone()
+            },
+            Err(_) => self.clone(),
+        }
+    }
+
+    /// Charged against the token budgets.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn tokens(&self, conf: &conf::Media) -> usize {
+        let rate = match self.unit {
+            data::Unit::Characters => conf.tokens_per_character,
+            _ => conf.tokens_per_image,
+        };
+        // Rounded up and clamped to be non-negative.
+        (self.quantity * self.weight * rate).ceil().max(0.0) as usize
+    }
+
+    #[must_use]
+    pub fn cost(&self, conf: &conf::Media, model: &str) -> f64 {
+        conf.unit_prices
+            .get(model)
+            .map_or(0.0, |price| self.quantity * self.weight * price)
+    }
+
+    /// As logged, the characters spoken being of the request and the images
+    /// of the response.
+    #[must_use]
+    pub fn usage(self, provider: &str) -> data::Usage {
+        let (input, output) = match self.unit {
+            data::Unit::Images => (0.0, self.quantity),
+            _ => (self.quantity, 0.0),
+        };
+        data::Usage {
+            provider: Some(provider.to_string()),
+            unit: self.unit,
+            input,
+            output,
+            detail: self.detail,
+        }
+    }
+}
+
+/// Of a "WIDTHxHEIGHT" size.
+fn pixels(size: &str) -> Option<f64> {
+    let (width, height) = size.split_once('x')?;
+    let width: u32 = width.trim().parse().ok()?;
+    let height: u32 = height.trim().parse().ok()?;
+    Some(f64::from(width) * f64::from(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImagesReq, SpeechReq, Units};
+    use crate::conf;
+
+    #[test]
+    fn units() {
+        let conf = conf::Media {
+            tokens_per_character: 0.5,
+            tokens_per_image: 1000.0,
+            unit_prices: [("dall-e".to_string(), 0.04)].into(),
+        };
+        let speech = Units::of_speech(&SpeechReq {
+            model: "tts".to_string(),
+            input: "Héllo".to_string(),
+            voice: None,
+        });
+        assert_eq!(speech.tokens(&conf), 3);
+
+        let images = Units::of_images(&ImagesReq {
+            model: Some("dall-e".to_string()),
+            prompt: "A cat.".to_string(),
+            n: Some(2),
+            size: Some("512x512".to_string()),
+        });
+        assert_eq!(images.tokens(&conf), 500);
+        assert!((images.cost(&conf, "dall-e") - 0.02).abs() < 1e-9);
+
+        // Fewer than asked for.
+        let generated = images.generated(br#"{"data": [{"url": "x"}]}"#);
+        assert_eq!(generated.tokens(&conf), 250);
+    }
+}
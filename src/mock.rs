@@ -0,0 +1,280 @@
+//! An OpenAI-compatible provider of canned completions, for tests and local
+//! development without a real provider's key. Replies echo the last message,
+//! a word per token, streamed if asked.
+//!
+//! Errors are injected at random, at the configured rate, or always for
+//! models named "mock-error-{status}", e.g. "mock-error-429".
+
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::Path,
+    http::{header, Method, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    Json, Router,
+};
+
+use crate::chat;
+
+const ERROR_MODEL_PREFIX: &str = "mock-error-";
+
+#[derive(Debug, Clone)]
+pub struct Mock {
+    /// Before each response.
+    pub latency: Duration,
+
+    /// Of requests failed with `error_status`, from 0.0 to 1.0.
+    pub error_rate: f64,
+    pub error_status: StatusCode,
+
+    /// Listed at any path ending with "models".
+    pub models: Vec<String>,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            error_status: StatusCode::INTERNAL_SERVER_ERROR,
+            models: vec!["mock".to_string()],
+        }
+    }
+}
+
+/// At any prefix, such as "/v1" or "/openai/v1".
+pub fn routes(mock: Mock) -> Router {
+    Router::new().route(
+        "/*endpoint",
+        axum::routing::any(
+            move |method: Method,
+                  Path(endpoint): Path<String>,
+                  body: String| {
+                let mock = mock.clone();
+                async move { handle(&mock, &method, &endpoint, &body).await }
+            },
+        ),
+    )
+}
+
+pub async fn serve(addr: SocketAddr, mock: Mock) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(?addr, ?mock, "Mock upstream listening.");
+    axum::serve(listener, routes(mock)).await?;
+    Ok(())
+}
+
+async fn handle(
+    mock: &Mock,
+    method: &Method,
+    endpoint: &str,
+    body: &str,
+) -> Response {
+    tokio::time::sleep(mock.latency).await;
+    match *method {
+        Method::GET if endpoint.ends_with("models") => {
+            let data: Vec<serde_json::Value> = mock
+                .models
+                .iter()
+                .map(|id| {
+                    serde_json::json!({
+                        "id": id,
+                        "object": "model",
+                        "owned_by": "mock",
+                    })
+                })
+                .collect();
+            Json(serde_json::json!({"object": "list", "data": data}))
+                .into_response()
+        }
+        Method::POST if endpoint.ends_with("chat/completions") => {
+            complete(mock, body)
+        }
+        _ => rejection(StatusCode::NOT_FOUND),
+    }
+}
+
+fn complete(mock: &Mock, body: &str) -> Response {
+    let chat_req: chat::Req = match serde_json::from_str(body) {
+        Ok(chat_req) => chat_req,
+        Err(error) => {
+            tracing::warn!(?error, "Mock rejecting. Invalid chat request.");
+            return rejection(StatusCode::BAD_REQUEST);
+        }
+    };
+    if let Some(status) =
+        injected_status(mock, &chat_req.model, rand::random())
+    {
+        return rejection(status);
+    }
+    reply(&chat_req, prompt_tokens(&chat_req))
+}
+
+/// Echoing the last message, as OpenAI would reply, streamed if asked, with
+/// the prompt tokens as given.
+pub(crate) fn reply(chat_req: &chat::Req, prompt_tokens: usize) -> Response {
+    let words = reply_words(chat_req);
+    let usage = serde_json::json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": words.len(),
+        "total_tokens": prompt_tokens + words.len(),
+    });
+    if chat_req.is_stream() {
+        let usage = chat_req.is_usage_streamed().then_some(usage);
+        stream(&chat_req.model, &words, usage).into_response()
+    } else {
+        Json(serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "created": 0,
+            "model": chat_req.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": words.join(" ")},
+                "finish_reason": "stop",
+            }],
+            "usage": usage,
+        }))
+        .into_response()
+    }
+}
+
+/// A chunk per word, then one with the finish reason, and then one with the
+/// usage, if asked for.
+fn stream(
+    model: &str,
+    words: &[String],
+    usage: Option<serde_json::Value>,
+) -> Sse<impl futures_util::Stream<Item = Result<Event, Infallible>>> {
+    let chunk = |delta: serde_json::Value, finish_reason, usage| {
+        let choices = if delta.is_null() {
+            serde_json::json!([])
+        } else {
+            serde_json::json!([{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }])
+        };
+        serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": model,
+            "choices": choices,
+            "usage": usage,
+        })
+        .to_string()
+    };
+    let null = serde_json::Value::Null;
+    let mut data: Vec<String> = words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let content = if i == 0 {
+                word.clone()
+            } else {
+                format!(" {word}")
+            };
+            chunk(
+                serde_json::json!({"content": content}),
+                null.clone(),
+                null.clone(),
+            )
+        })
+        .collect();
+    data.push(chunk(serde_json::json!({}), "stop".into(), null.clone()));
+    if let Some(usage) = usage {
+        data.push(chunk(null.clone(), null.clone(), usage));
+    }
+    data.push("[DONE]".to_string());
+    let events = data.into_iter().map(|data| Ok(Event::default().data(data)));
+    Sse::new(futures_util::stream::iter(events))
+}
+
+/// Status to fail with, if any, given a roll of the dice in 0.0..1.0.
+fn injected_status(
+    mock: &Mock,
+    model: &str,
+    roll: f64,
+) -> Option<StatusCode> {
+    if let Some(status) = model.strip_prefix(ERROR_MODEL_PREFIX) {
+        return status
+            .parse()
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok());
+    }
+    (roll < mock.error_rate).then_some(mock.error_status)
+}
+
+/// Of the last message, capped by max_tokens.
+fn reply_words(chat_req: &chat::Req) -> Vec<String> {
+    let text = chat_req
+        .messages
+        .last()
+        .map(chat::Msg::text)
+        .unwrap_or_default();
+    text.split_whitespace()
+        .take(chat_req.max_tokens.unwrap_or(usize::MAX))
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn prompt_tokens(chat_req: &chat::Req) -> usize {
+    chat_req
+        .messages
+        .iter()
+        .map(|msg| msg.text().split_whitespace().count())
+        .sum()
+}
+
+fn rejection(status: StatusCode) -> Response {
+    let mut resp =
+        (status, Json(chat::Error::from_status(status))).into_response();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, header::HeaderValue::from(1));
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::{injected_status, reply_words, Mock};
+    use crate::chat;
+
+    #[test]
+    fn injection() {
+        let mock = Mock {
+            error_rate: 0.5,
+            error_status: StatusCode::BAD_GATEWAY,
+            ..Mock::default()
+        };
+        assert_eq!(
+            injected_status(&mock, "mock-error-429", 0.9),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(injected_status(&mock, "mock-error-x", 0.0), None);
+        assert_eq!(
+            injected_status(&mock, "mock", 0.1),
+            Some(StatusCode::BAD_GATEWAY)
+        );
+        assert_eq!(injected_status(&mock, "mock", 0.9), None);
+    }
+
+    #[test]
+    fn echo() {
+        let chat_req: chat::Req = serde_json::from_value(serde_json::json!({
+            "model": "mock",
+            "max_tokens": 2,
+            "messages": [{"role": "user", "content": "Say  it back."}],
+        }))
+        .unwrap();
+        assert_eq!(reply_words(&chat_req), ["Say", "it"]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Screening of prompts by an OpenAI-compatible moderation endpoint before
+//! forwarding them.
+
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::{chat, conf};
+
+#[derive(serde::Serialize)]
+struct Req<'a> {
+    input: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct Resp {
+    results: Vec<Verdict>,
+}
+
+#[derive(serde::Deserialize)]
+struct Verdict {
+    categories: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The categories the prompt is flagged for, of those we care about.
+/// Empty if it is fine.
+pub async fn check(
+    http: &reqwest::Client,
+    conf: &conf::Moderation,
+    chat_req: &chat::Req,
+) -> anyhow::Result<Vec<String>> {
+    // Only what the user wrote, not our own system prompts.
+    let input = chat_req
+        .messages
+        .iter()
+        .filter(|msg| msg.role != "system")
+        .map(chat::Msg::text)
+        .collect::<Vec<String>>()
+        .join("\n");
+    let mut builder = http
+        .post(&conf.url)
+        .timeout(Duration::from_secs_f32(conf.timeout))
+        .json(&Req {
+            input,
+            model: conf.model.as_deref(),
+        });
+    if let Some(token) = &conf.auth_token {
+        builder = builder.bearer_auth(token);
+    }
+    let resp: Resp = builder
+        .send()
+        .await
+        .context("Failed to reach moderation endpoint.")?
+        .error_for_status()
+        .context("Moderation endpoint failed.")?
+        .json()
+        .await
+        .context("Invalid moderation response.")?;
+    Ok(flagged(&conf.categories, &resp))
+}
+
+fn flagged(categories: &[String], resp: &Resp) -> Vec<String> {
+    let mut flagged: Vec<String> = resp
+        .results
+        .iter()
+        .flat_map(|verdict| &verdict.categories)
+        .filter(|(_, is_flagged)| is_flagged.as_bool() == Some(true))
+        .map(|(category, _)| category.clone())
+        .filter(|category| {
+            categories.is_empty() || categories.contains(category)
+        })
+        .collect();
+    flagged.sort();
+    flagged.dedup();
+    flagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flagged, Resp};
+
+    #[test]
+    fn categories() {
+        let resp: Resp = serde_json::from_value(serde_json::json!({
+            "results": [{
+                "flagged": true,
+                "categories": {
+                    "hate": true,
+                    "violence": true,
+                    "sexual": false,
+                },
+            }],
+        }))
+        .unwrap();
+        assert_eq!(flagged(&[], &resp), ["hate", "violence"]);
+        assert_eq!(flagged(&["violence".to_string()], &resp), ["violence"]);
+        assert!(flagged(&["sexual".to_string()], &resp).is_empty());
+    }
+}
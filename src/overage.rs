@@ -0,0 +1,171 @@
+//! Degraded service, rather than refusal, for users a little past their
+//! daily token budget, per [`conf::Overage`], since running out mid-demo
+//! shouldn't be a wall. Up to a margin past the budget, chat requests are
+//! still served, but with fewer completion tokens, by cheaper models and at
+//! a stricter rate. Past the margin, and for other endpoints, the budget
+//! holds as usual.
+//!
+//! Usage in overage is reserved as any other, within the margin rather
+//! than the budget, and within the org's and the cost budgets as usual, so
+//! that the margin runs out too.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    chat, conf,
+    data::{Storage, TokenBudget},
+    ratelimit,
+};
+
+/// Of the keys requests in overage are counted under, apart from the usual
+/// rate limits.
+const KEY_PREFIX: &str = "overage:";
+
+#[derive(Debug)]
+pub enum Overage {
+    /// Within the budget, or too far past it to be served.
+    No,
+
+    Degraded(Degraded),
+
+    /// By the stricter rate limit.
+    Throttled(ratelimit::Rejection),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Degraded {
+    /// Model, as requested, before any cheaper one.
+    pub requested: String,
+
+    /// Tokens past the budget, which it is reserved within.
+    pub margin: u64,
+}
+
+/// Of a chat request needing about `tokens`, which is degraded if it is to
+/// be served in overage.
+pub async fn check(
+    storage: &dyn Storage,
+    conf: &conf::Overage,
+    uid: &str,
+    budget: TokenBudget,
+    tokens: usize,
+    req: &mut chat::Req,
+) -> anyhow::Result<Overage> {
+    let needed = u64::try_from(tokens)?;
+    if !is_within_margin(conf, budget, needed) {
+        return Ok(Overage::No);
+    }
+    let windows = ratelimit::windows(&conf.rate_limit);
+    if !windows.is_empty() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let key = format!("{KEY_PREFIX}{uid}");
+        if let Some(window) =
+            storage.rate_acquire(&key, &windows, now).await?
+        {
+            let rejection = ratelimit::Rejection::exceeded(window, now);
+            return Ok(Overage::Throttled(rejection));
+        }
+    }
+    Ok(Overage::Degraded(degrade(conf, budget, req)))
+}
+
+/// Past the budget, but not the margin.
+fn is_within_margin(
+    conf: &conf::Overage,
+    budget: TokenBudget,
+    needed: u64,
+) -> bool {
+    if budget.remaining() >= needed {
+        return false;
+    }
+    let margin = margin(conf, budget);
+    budget.used.saturating_add(needed) <= budget.limit.saturating_add(margin)
+}
+
+fn margin(conf: &conf::Overage, budget: TokenBudget) -> u64 {
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    let margin = (budget.limit as f64 * conf.max_percent / 100.0) as u64;
+    margin
+}
+
+fn degrade(
+    conf: &conf::Overage,
+    budget: TokenBudget,
+    req: &mut chat::Req,
+) -> Degraded {
+    let requested = req.model.clone();
+    if let Some(cheaper) = conf.models.get(&req.model) {
+        req.model.clone_from(cheaper);
+    }
+    req.max_tokens = Some(
+        req.max_tokens
+            .map_or(conf.max_tokens, |max| max.min(conf.max_tokens)),
+    );
+    Degraded {
+        requested,
+        margin: margin(conf, budget),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{degrade, is_within_margin, Degraded};
+    use crate::{chat, conf, data::TokenBudget};
+
+    fn overage() -> conf::Overage {
+        conf::Overage {
+            max_percent: 20.0,
+            max_tokens: 256,
+            models: BTreeMap::from([(
+                "gpt-4o".to_string(),
+                "gpt-4o-mini".to_string(),
+            )]),
+            rate_limit: conf::RateLimit::default(),
+        }
+    }
+
+    #[test]
+    fn margin() {
+        let conf = overage();
+        let budget = |used| TokenBudget { limit: 1000, used };
+        assert!(!is_within_margin(&conf, budget(500), 100));
+        assert!(is_within_margin(&conf, budget(950), 100));
+        assert!(is_within_margin(&conf, budget(1100), 100));
+        assert!(!is_within_margin(&conf, budget(1150), 100));
+    }
+
+    #[test]
+    fn degraded() {
+        let conf = overage();
+        let mut req: chat::Req = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "max_tokens": 1000,
+        }))
+        .unwrap();
+        let budget = TokenBudget {
+            limit: 1000,
+            used: 1000,
+        };
+        assert_eq!(
+            degrade(&conf, budget, &mut req),
+            Degraded {
+                requested: "gpt-4o".to_string(),
+                margin: 200,
+            }
+        );
+        assert_eq!(req.model, "gpt-4o-mini");
+        assert_eq!(req.max_tokens, Some(256));
+        req.model = "o1".to_string();
+        req.max_tokens = Some(100);
+        degrade(&conf, budget, &mut req);
+        assert_eq!(req.model, "o1");
+        assert_eq!(req.max_tokens, Some(100));
+    }
+}
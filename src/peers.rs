@@ -0,0 +1,291 @@
+//! Other instances of raskol, per [`conf::Peers`], for requests to be
+//! proxied to when this one can't serve them for now, as when upstream
+//! throttles it, or its spend caps are reached, or its circuits are open.
+//! For deployments of two boxes or so, at events, without a load balancer
+//! which knows as much. Each is checked by a heartbeat to its readiness,
+//! which tells whether it can serve requests itself, so that two instances
+//! which both can't don't pass requests to each other. Proxied rather than
+//! redirected, since clients drop `Authorization` on redirects to other
+//! origins, as peers are.
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::TryStreamExt;
+
+use crate::{
+    chat, conf,
+    server::{unix_now_secs, AppState},
+};
+
+/// Of requests proxied from a peer, so that they aren't proxied on again.
+const PROXIED: &str = "x-raskol-proxied";
+
+/// Of a connection, not to be passed on, per RFC 9110.
+const HOP_BY_HOP: [HeaderName; 6] = [
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+];
+
+static GLOBAL: conf::Derived<Option<Peers>> =
+    conf::Derived::new(|conf| conf.peers.clone().map(Peers::new));
+
+/// Of the global conf, unless off.
+#[must_use]
+pub fn global() -> Option<&'static Peers> {
+    GLOBAL.get().as_ref()
+}
+
+pub struct Peers {
+    conf: conf::Peers,
+
+    /// In the order of conf.
+    statuses: Mutex<Vec<Status>>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub url: String,
+
+    /// As of the last heartbeat.
+    pub is_healthy: bool,
+
+    /// Seconds since UNIX epoch, of the last heartbeat it was healthy at.
+    pub last_healthy: Option<i64>,
+
+    /// Of the last heartbeat, if it was not healthy.
+    pub error: Option<String>,
+}
+
+/// Of a peer's readiness, what tells whether it is.
+#[derive(serde::Deserialize)]
+struct Readiness {
+    is_ready: bool,
+
+    #[serde(default)]
+    is_exhausted: bool,
+}
+
+impl Peers {
+    #[must_use]
+    pub fn new(conf: conf::Peers) -> Self {
+        let statuses = conf
+            .urls
+            .iter()
+            .map(|url| Status {
+                url: url.trim_end_matches('/').to_string(),
+                is_healthy: false,
+                last_healthy: None,
+                error: None,
+            })
+            .collect();
+        Self {
+            conf,
+            statuses: Mutex::new(statuses),
+        }
+    }
+
+    #[must_use]
+    pub fn statuses(&self) -> Vec<Status> {
+        self.lock().clone()
+    }
+
+    /// The first in conf which was healthy at its last heartbeat, unless
+    /// that was too long ago.
+    #[must_use]
+    pub fn healthy(&self, now: i64) -> Option<String> {
+        let stale_after = i64::try_from(self.conf.stale_after).ok()?;
+        self.lock()
+            .iter()
+            .find(|status| {
+                status.is_healthy
+                    && status.last_healthy.is_some_and(|last| {
+                        now.saturating_sub(last) <= stale_after
+                    })
+            })
+            .map(|status| status.url.clone())
+    }
+
+    /// Of each peer, at once. Only fails if none is healthy, for the job's
+    /// status to tell.
+    pub async fn heartbeat(
+        &self,
+        http: &reqwest::Client,
+    ) -> anyhow::Result<()> {
+        let urls: Vec<String> = self
+            .statuses()
+            .into_iter()
+            .map(|status| status.url)
+            .collect();
+        let timeout = Duration::from_secs_f32(self.conf.timeout);
+        let checks = urls.iter().map(|url| check(http, url, timeout));
+        let results = futures_util::future::join_all(checks).await;
+        let now = unix_now_secs();
+        let mut statuses = self.lock();
+        for (status, result) in statuses.iter_mut().zip(results) {
+            status.is_healthy = result.is_ok();
+            if result.is_ok() {
+                status.last_healthy = Some(now);
+            }
+            status.error = result.err();
+        }
+        if statuses.iter().any(|status| status.is_healthy) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("No peer is healthy."))
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Status>> {
+        self.statuses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Healthy if ready and able to serve requests itself.
+async fn check(
+    http: &reqwest::Client,
+    url: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let resp = http
+        .get(format!("{url}/health/ready"))
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|error| format!("Unreachable: {error}"))?;
+    let status = resp.status();
+    let readiness: Readiness = resp
+        .json()
+        .await
+        .map_err(|error| format!("Invalid readiness ({status}): {error}"))?;
+    match readiness {
+        Readiness {
+            is_ready: false, ..
+        } => Err(format!("Not ready ({status}).")),
+        Readiness {
+            is_exhausted: true, ..
+        } => Err("Can't serve requests itself.".to_string()),
+        Readiness { .. } => Ok(()),
+    }
+}
+
+/// Proxies requests to a healthy peer while upstream can't be used from
+/// here. Otherwise, or if none is, they are served here as ever.
+pub(crate) async fn layer(
+    State(AppState { upstream, http, .. }): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(peers) = global() else {
+        return next.run(req).await;
+    };
+    if !upstream.is_exhausted() || req.headers().contains_key(PROXIED) {
+        return next.run(req).await;
+    }
+    let Some(peer) = peers.healthy(unix_now_secs()) else {
+        tracing::warn!("Upstream exhausted, and no peer healthy.");
+        return next.run(req).await;
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let url = format!("{peer}{path}");
+    tracing::info!(%url, "Proxying to peer. Upstream exhausted.");
+    metrics::counter!("raskol_peer_proxied_total").increment(1);
+    match proxy(&http, &url, req).await {
+        Ok(resp) => resp,
+        Err(error) => {
+            tracing::error!(?error, %url, "Failed to proxy to peer.");
+            let error = chat::Error::new(
+                "server_error",
+                "peer_unavailable",
+                "Upstream is unavailable, and so is the peer tried instead."
+                    .to_string(),
+            );
+            (StatusCode::BAD_GATEWAY, Json(error)).into_response()
+        }
+    }
+}
+
+/// The request as is, body and all, but for its hop-by-hop headers, and
+/// the peer's response likewise.
+async fn proxy(
+    http: &reqwest::Client,
+    url: &str,
+    req: Request,
+) -> reqwest::Result<Response> {
+    let (parts, body) = req.into_parts();
+    let mut headers = parts.headers;
+    strip(&mut headers);
+    headers.remove(header::HOST);
+    headers.insert(PROXIED, header::HeaderValue::from_static("1"));
+    let body = reqwest::Body::wrap_stream(body.into_data_stream());
+    let received = http
+        .request(parts.method, url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await?;
+    let code = received.status();
+    let mut headers = received.headers().clone();
+    strip(&mut headers);
+    let body = Body::from_stream(received.bytes_stream().map_err(|error| {
+        tracing::warn!(?error, "Peer's response broke off.");
+        error
+    }));
+    let mut resp = Response::new(body);
+    *resp.status_mut() = code;
+    *resp.headers_mut() = headers;
+    Ok(resp)
+}
+
+fn strip(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Peers;
+    use crate::conf;
+
+    #[test]
+    fn healthy() {
+        let peers = Peers::new(conf::Peers {
+            urls: vec![
+                "http://a:8000/".to_string(),
+                "http://b:8000".to_string(),
+            ],
+            ..conf::Peers::default()
+        });
+        assert_eq!(peers.healthy(100), None);
+        {
+            let mut statuses = peers.lock();
+            statuses[1].is_healthy = true;
+            statuses[1].last_healthy = Some(100);
+        }
+        assert_eq!(peers.healthy(110).as_deref(), Some("http://b:8000"));
+        assert_eq!(peers.healthy(200), None);
+        {
+            let mut statuses = peers.lock();
+            statuses[0].is_healthy = true;
+            statuses[0].last_healthy = Some(105);
+        }
+        assert_eq!(peers.healthy(110).as_deref(), Some("http://a:8000"));
+    }
+}
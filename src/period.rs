@@ -0,0 +1,258 @@
+//! Budget periods, keyed by the local date (or time, if hourly) at which
+//! they start.
+
+use anyhow::{anyhow, Context};
+use chrono::{
+    DateTime, Datelike, Days, Months, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeDelta, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::conf::{self, PeriodLength};
+
+/// Of conf, which is refused at load when invalid, per
+/// [`conf::Conf::errors`].
+static GLOBAL: conf::Derived<Period> = conf::Derived::new(|conf| {
+    Period::new(&conf.budget_period).unwrap_or_else(|error| {
+        panic!("Failed to initialize budget period: {error:?}")
+    })
+});
+
+/// Key of the current period.
+#[must_use]
+pub fn current() -> String {
+    GLOBAL.get().key(Utc::now())
+}
+
+/// Seconds since UNIX epoch at the start of the keyed period and the next.
+pub fn bounds(key: &str) -> anyhow::Result<(i64, i64)> {
+    GLOBAL.get().bounds(key)
+}
+
+/// Seconds since UNIX epoch at the start of the next period.
+pub fn next() -> anyhow::Result<i64> {
+    let (_, next) = bounds(&current())?;
+    Ok(next)
+}
+
+/// Key of the current day, in the timezone of periods, starting at their
+/// reset time, whatever their length. For what is capped daily, as of
+/// [`conf::SpendCaps`].
+#[must_use]
+pub fn today() -> String {
+    GLOBAL.get().daily().key(Utc::now())
+}
+
+/// Seconds since UNIX epoch at the start of the keyed day, as of [`today`],
+/// and the next.
+pub fn day_bounds(key: &str) -> anyhow::Result<(i64, i64)> {
+    GLOBAL.get().daily().bounds(key)
+}
+
+/// Seconds since UNIX epoch at the start of the next day, as of [`today`].
+pub fn tomorrow() -> anyhow::Result<i64> {
+    let (_, next) = day_bounds(&today())?;
+    Ok(next)
+}
+
+/// Of the length of periods, as in "Daily budget".
+#[must_use]
+pub fn adjective() -> &'static str {
+    match GLOBAL.get().length {
+        PeriodLength::Hour => "Hourly",
+        PeriodLength::Day => "Daily",
+        PeriodLength::Week => "Weekly",
+        PeriodLength::Month => "Monthly",
+    }
+}
+
+#[derive(Debug)]
+pub struct Period {
+    length: PeriodLength,
+    timezone: Tz,
+
+    /// Of the start from the local midnight, or hour if hourly.
+    offset: TimeDelta,
+}
+
+impl Period {
+    pub fn new(conf: &conf::BudgetPeriod) -> anyhow::Result<Self> {
+        let timezone: Tz = conf.timezone.parse().map_err(|error| {
+            anyhow!("Invalid timezone: {:?}: {error}", conf.timezone)
+        })?;
+        let reset_at = NaiveTime::parse_from_str(&conf.reset_at, "%H:%M")
+            .context(format!("Invalid reset_at: {:?}", conf.reset_at))?;
+        let offset = match conf.length {
+            PeriodLength::Hour => {
+                TimeDelta::minutes(i64::from(reset_at.minute()))
+            }
+            _ => reset_at - NaiveTime::MIN,
+        };
+        Ok(Self {
+            length: conf.length,
+            timezone,
+            offset,
+        })
+    }
+
+    /// Of days, in the same timezone, starting at the same time, or at the
+    /// same minute past midnight if hourly.
+    #[must_use]
+    pub fn daily(&self) -> Self {
+        Self {
+            length: PeriodLength::Day,
+            timezone: self.timezone,
+            offset: self.offset,
+        }
+    }
+
+    /// Of the period containing the given time.
+    #[must_use]
+    pub fn key(&self, time: DateTime<Utc>) -> String {
+        // As if periods started at midnight, or on the hour.
+        let shifted =
+            time.with_timezone(&self.timezone).naive_local() - self.offset;
+        let date = shifted.date();
+        match self.length {
+            PeriodLength::Hour => {
+                let hour = NaiveTime::from_hms_opt(shifted.hour(), 0, 0)
+                    .unwrap_or_default();
+                (date.and_time(hour) + self.offset)
+                    .format("%Y-%m-%dT%H:%M")
+                    .to_string()
+            }
+            PeriodLength::Day => date.format("%Y-%m-%d").to_string(),
+            PeriodLength::Week => {
+                let since_monday = date.weekday().num_days_from_monday();
+                (date - Days::new(u64::from(since_monday)))
+                    .format("%Y-%m-%d")
+                    .to_string()
+            }
+            PeriodLength::Month => date.format("%Y-%m-01").to_string(),
+        }
+    }
+
+    pub fn bounds(&self, key: &str) -> anyhow::Result<(i64, i64)> {
+        let invalid = || format!("Invalid period: {key:?}");
+        let start = match self.length {
+            PeriodLength::Hour => {
+                NaiveDateTime::parse_from_str(key, "%Y-%m-%dT%H:%M")
+                    .context(invalid())?
+            }
+            _ => {
+                NaiveDate::parse_from_str(key, "%Y-%m-%d")
+                    .context(invalid())?
+                    .and_time(NaiveTime::MIN)
+                    + self.offset
+            }
+        };
+        let end = match self.length {
+            PeriodLength::Hour => Some(start + TimeDelta::hours(1)),
+            PeriodLength::Day => start.checked_add_days(Days::new(1)),
+            PeriodLength::Week => start.checked_add_days(Days::new(7)),
+            PeriodLength::Month => start.checked_add_months(Months::new(1)),
+        }
+        .context(invalid())?;
+        Ok((self.timestamp(start)?, self.timestamp(end)?))
+    }
+
+    /// Of a local time. The earlier one when the clocks go back, and the
+    /// hour after when they skip it.
+    fn timestamp(&self, local: NaiveDateTime) -> anyhow::Result<i64> {
+        let time = self
+            .timezone
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                self.timezone
+                    .from_local_datetime(&(local + TimeDelta::hours(1)))
+                    .earliest()
+            })
+            .context(format!("Nonexistent local time: {local}"))?;
+        Ok(time.timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::Period;
+    use crate::conf::{BudgetPeriod, PeriodLength};
+
+    fn period(
+        length: PeriodLength,
+        timezone: &str,
+        reset_at: &str,
+    ) -> Period {
+        Period::new(&BudgetPeriod {
+            length,
+            timezone: timezone.to_string(),
+            reset_at: reset_at.to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn keys() {
+        // A Friday.
+        let t =
+            |hour| Utc.with_ymd_and_hms(2026, 10, 16, hour, 10, 0).unwrap();
+        let day = period(PeriodLength::Day, "UTC", "00:00");
+        assert_eq!(day.key(t(0)), "2026-10-16");
+        assert_eq!(day.key(t(23)), "2026-10-16");
+
+        // 09:00 in New York is 13:00 UTC in October.
+        let day = period(PeriodLength::Day, "America/New_York", "09:00");
+        assert_eq!(day.key(t(12)), "2026-10-15");
+        assert_eq!(day.key(t(13)), "2026-10-16");
+
+        let week = period(PeriodLength::Week, "UTC", "00:00");
+        assert_eq!(week.key(t(12)), "2026-10-12");
+
+        let month = period(PeriodLength::Month, "UTC", "00:00");
+        assert_eq!(month.key(t(12)), "2026-10-01");
+
+        let hour = period(PeriodLength::Hour, "UTC", "09:30");
+        assert_eq!(hour.key(t(12)), "2026-10-16T11:30");
+
+        // Days, of the same timezone and time, whatever the length.
+        let week = period(PeriodLength::Week, "America/New_York", "09:00");
+        assert_eq!(week.daily().key(t(12)), "2026-10-15");
+        assert_eq!(week.daily().key(t(13)), "2026-10-16");
+        let hour = period(PeriodLength::Hour, "America/New_York", "09:30");
+        assert_eq!(hour.daily().key(t(4)), "2026-10-15");
+        assert_eq!(hour.daily().key(t(5)), "2026-10-16");
+    }
+
+    #[test]
+    fn bounds() {
+        let ts = |day, hour| {
+            Utc.with_ymd_and_hms(2026, 10, day, hour, 0, 0)
+                .unwrap()
+                .timestamp()
+        };
+        let day = period(PeriodLength::Day, "America/New_York", "09:00");
+        assert_eq!(
+            day.bounds("2026-10-16").unwrap(),
+            (ts(16, 13), ts(17, 13))
+        );
+        let week = period(PeriodLength::Week, "UTC", "00:00");
+        assert_eq!(
+            week.bounds("2026-10-12").unwrap(),
+            (ts(12, 0), ts(19, 0))
+        );
+        assert!(day.bounds("yesterday").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let conf = |timezone: &str, reset_at: &str| BudgetPeriod {
+            length: PeriodLength::Day,
+            timezone: timezone.to_string(),
+            reset_at: reset_at.to_string(),
+        };
+        assert!(Period::new(&conf("Mars/Olympus_Mons", "00:00")).is_err());
+        assert!(Period::new(&conf("UTC", "25:00")).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Defaults of the user's own for chat requests, kept server-side, so that
+//! thin clients can send just the messages. Filled in only where requests
+//! leave them out, before anything else of ours is applied to them, so that
+//! role limits apply to them as to those sent.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+
+use crate::{
+    data::Storage,
+    server::{AppState, USER},
+};
+
+/// How long until preferences saved on another instance take effect. On
+/// this one, they do at once.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Bound on memory use. Exceeding it just clears the cache.
+const MAX_CACHED: usize = 10_000;
+
+/// uid -> the user's preferences, if any, and when they were looked up, so
+/// that we don't hit storage on every chat request.
+type Cache = HashMap<String, (Option<Preferences>, Instant)>;
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Mutex::default);
+
+/// As stored and as given to the API.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+    Debug,
+    Clone,
+    Default,
+)]
+pub struct Preferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+
+    /// Unless the request has max_completion_tokens instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+
+    /// Of requests without a system message of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+}
+
+impl Preferences {
+    /// Into the request, as JSON, since that is where fields left out can be
+    /// told from those sent.
+    pub fn fill(&self, req: &mut serde_json::Value) {
+        let Some(req) = req.as_object_mut() else {
+            return;
+        };
+        if let Some(model) =
+            self.model.as_ref().filter(|_| lacks(req, "model"))
+        {
+            req.insert("model".to_string(), model.clone().into());
+        }
+        if let Some(temperature) =
+            self.temperature.filter(|_| lacks(req, "temperature"))
+        {
+            req.insert("temperature".to_string(), temperature.into());
+        }
+        if let Some(max_tokens) = self.max_tokens.filter(|_| {
+            lacks(req, "max_tokens") && lacks(req, "max_completion_tokens")
+        }) {
+            req.insert("max_tokens".to_string(), max_tokens.into());
+        }
+        if let (Some(prompt), Some(serde_json::Value::Array(messages))) =
+            (&self.system_prompt, req.get_mut("messages"))
+        {
+            if !messages.iter().any(is_system) {
+                messages.insert(
+                    0,
+                    serde_json::json!({"role": "system", "content": prompt}),
+                );
+            }
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.model.as_ref().is_none_or(|model| !model.is_empty())
+            && self
+                .temperature
+                .is_none_or(|temperature| (0.0..=2.0).contains(&temperature))
+            && self.max_tokens.is_none_or(|max_tokens| max_tokens > 0)
+    }
+}
+
+/// Whether the request leaves out any of what preferences may fill in, so
+/// that those which do not needn't be looked up.
+#[must_use]
+pub fn is_lacking(req: &serde_json::Value) -> bool {
+    let Some(req) = req.as_object() else {
+        return false;
+    };
+    ["model", "temperature", "max_tokens"]
+        .into_iter()
+        .any(|field| lacks(req, field))
+        || req
+            .get("messages")
+            .and_then(serde_json::Value::as_array)
+            .is_some_and(|messages| !messages.iter().any(is_system))
+}
+
+/// The user's, if any. None when they cannot be looked up, since they are
+/// only a convenience.
+pub async fn get(storage: &dyn Storage, uid: &str) -> Option<Preferences> {
+    if let Some((preferences, looked_up)) = cache().get(uid) {
+        if looked_up.elapsed() < CACHE_TTL {
+            return preferences.clone();
+        }
+    }
+    let body = storage
+        .preferences_get(uid)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get preferences.");
+        })
+        .ok()?;
+    let preferences = match body {
+        Some(body) => Some(
+            serde_json::from_str(&body)
+                .map_err(|error| {
+                    tracing::error!(?error, "Invalid preferences.");
+                })
+                .ok()?,
+        ),
+        None => None,
+    };
+    let mut cache = cache();
+    if cache.len() >= MAX_CACHED {
+        cache.clear();
+    }
+    cache.insert(uid.to_string(), (preferences.clone(), Instant::now()));
+    preferences
+}
+
+fn cache() -> MutexGuard<'static, Cache> {
+    CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lacks(
+    req: &serde_json::Map<String, serde_json::Value>,
+    field: &str,
+) -> bool {
+    req.get(field).is_none_or(serde_json::Value::is_null)
+}
+
+fn is_system(msg: &serde_json::Value) -> bool {
+    matches!(msg["role"].as_str(), Some("system" | "developer"))
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_get, handle_put, handle_delete))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route(
+        "/preferences",
+        get(handle_get).put(handle_put).delete(handle_delete),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/preferences",
+    tag = "preferences",
+    responses(
+        (status = 200, description = "Empty when none.", body = Preferences),
+    )
+)]
+async fn handle_get(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Preferences>, StatusCode> {
+    let uid = USER.get().uid;
+    let body = storage.preferences_get(&uid).await.map_err(internal)?;
+    let preferences = match body {
+        Some(body) => serde_json::from_str(&body)
+            .map_err(|error| internal(error.into()))?,
+        None => Preferences::default(),
+    };
+    Ok(Json(preferences))
+}
+
+#[utoipa::path(
+    put,
+    path = "/preferences",
+    tag = "preferences",
+    request_body = Preferences,
+    responses(
+        (status = 200, description = "Replaced.", body = Preferences),
+        (status = 400, description = "Invalid preferences."),
+    )
+)]
+async fn handle_put(
+    State(AppState { storage, .. }): State<AppState>,
+    Json(preferences): Json<Preferences>,
+) -> Result<Json<Preferences>, StatusCode> {
+    let uid = USER.get().uid;
+    if !preferences.is_valid() {
+        tracing::warn!(?preferences, "Rejecting. Invalid preferences.");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let body = serde_json::to_string(&preferences)
+        .map_err(|error| internal(error.into()))?;
+    storage
+        .preferences_put(&uid, &body)
+        .await
+        .map_err(internal)?;
+    cache().remove(&uid);
+    tracing::info!(?preferences, "Preferences saved.");
+    Ok(Json(preferences))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/preferences",
+    tag = "preferences",
+    responses(
+        (status = 204, description = "Deleted."),
+        (status = 404, description = "None to delete."),
+    )
+)]
+async fn handle_delete(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<StatusCode, StatusCode> {
+    let uid = USER.get().uid;
+    let is_deleted =
+        storage.preferences_delete(&uid).await.map_err(internal)?;
+    cache().remove(&uid);
+    if is_deleted {
+        tracing::info!("Preferences deleted.");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+fn internal(error: anyhow::Error) -> StatusCode {
+    tracing::error!(?error, "Failed to hit storage.");
+    StatusCode::SERVICE_UNAVAILABLE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Preferences;
+
+    #[test]
+    fn fill() {
+        let preferences = Preferences {
+            model: Some("gpt-4o".to_string()),
+            temperature: Some(0.2),
+            max_tokens: Some(100),
+            system_prompt: Some("Be brief.".to_string()),
+        };
+        let mut req = serde_json::json!({
+            "messages": [{"role": "user", "content": "Hi"}],
+            "temperature": 1.0,
+            "max_completion_tokens": 50,
+        });
+        assert!(super::is_lacking(&req));
+        preferences.fill(&mut req);
+        assert_eq!(
+            req,
+            serde_json::json!({
+                "model": "gpt-4o",
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": "Hi"},
+                ],
+                "temperature": 1.0,
+                "max_completion_tokens": 50,
+            })
+        );
+        let mut req = serde_json::json!({
+            "model": "llama",
+            "messages": [{"role": "developer", "content": "Be verbose."}],
+            "temperature": null,
+            "max_tokens": 10,
+        });
+        preferences.fill(&mut req);
+        assert_eq!(req["model"], "llama");
+        assert_eq!(req["temperature"], 0.2);
+        assert_eq!(req["max_tokens"], 10);
+        assert_eq!(req["messages"].as_array().unwrap().len(), 1);
+        assert!(!super::is_lacking(&req));
+    }
+}
@@ -0,0 +1,216 @@
+//! Probing of the upstream providers in the background, per
+//! [`conf::Prober`], for their uptime and latency at `/status`, to be shown
+//! on the venue's screen, so that participants can tell an outage from a
+//! bug of their own. Probes are kept in storage, so that the history
+//! survives restarts and is of all instances.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+
+use crate::{
+    conf,
+    data::{self, Storage},
+    jobs,
+    server::{unix_now_secs, AppState},
+    upstream::Upstream,
+};
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct Status {
+    /// Of the history summarized.
+    pub hours: u64,
+
+    pub providers: Vec<ProviderStatus>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct ProviderStatus {
+    pub name: String,
+
+    /// As of the latest probe.
+    pub is_up: bool,
+
+    /// Seconds since UNIX epoch.
+    pub last_probed: i64,
+
+    pub probes: u64,
+
+    /// Fraction of the probes which succeeded.
+    pub uptime: f64,
+
+    /// Of the probes which succeeded. None when none did.
+    pub latency_ms_avg: Option<i64>,
+    pub latency_ms_p95: Option<i64>,
+
+    /// Of the latest probe, when it failed.
+    pub error: Option<String>,
+}
+
+/// As the "probe" job, per [`jobs`].
+pub fn spawn(
+    storage: Arc<dyn Storage>,
+    http: reqwest::Client,
+    upstream: Arc<Upstream>,
+    conf: conf::Prober,
+) {
+    let interval = Duration::from_secs(conf.interval.max(1));
+    jobs::spawn("probe", interval, move || {
+        probe(
+            storage.clone(),
+            http.clone(),
+            upstream.clone(),
+            conf.clone(),
+        )
+    });
+}
+
+async fn probe(
+    storage: Arc<dyn Storage>,
+    http: reqwest::Client,
+    upstream: Arc<Upstream>,
+    conf: conf::Prober,
+) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs_f32(conf.timeout);
+    for probe in upstream.probe(&http, timeout).await {
+        if !probe.is_up {
+            tracing::warn!(?probe, "Upstream probe failed.");
+        }
+        if let Err(error) = storage.probe_record(&probe).await {
+            tracing::error!(?error, "Failed to record probe.");
+        }
+    }
+    let before = unix_now_secs() - history_secs(&conf);
+    storage
+        .probes_prune(before)
+        .await
+        .context("Failed to prune probes.")?;
+    Ok(())
+}
+
+/// Per provider, in the order they were first probed in.
+#[must_use]
+pub fn summarize(probes: &[data::Probe]) -> Vec<ProviderStatus> {
+    let mut order = Vec::new();
+    let mut by_provider: BTreeMap<&str, Vec<&data::Probe>> = BTreeMap::new();
+    for probe in probes {
+        let probes = by_provider.entry(&probe.provider).or_default();
+        if probes.is_empty() {
+            order.push(probe.provider.as_str());
+        }
+        probes.push(probe);
+    }
+    order
+        .into_iter()
+        .filter_map(|name| {
+            let probes = by_provider.remove(name)?;
+            let last = probes.last()?;
+            let mut latencies: Vec<i64> = probes
+                .iter()
+                .filter(|probe| probe.is_up)
+                .map(|probe| probe.latency_ms)
+                .collect();
+            latencies.sort_unstable();
+            #[allow(clippy::cast_precision_loss)] // Nowhere near.
+            let uptime = latencies.len() as f64 / probes.len() as f64;
+            let count = i64::try_from(latencies.len()).ok()?;
+            let latency_ms_avg =
+                (count > 0).then(|| latencies.iter().sum::<i64>() / count);
+            // Nearest rank.
+            let latency_ms_p95 = latencies
+                .get((latencies.len() * 95).div_ceil(100).saturating_sub(1))
+                .copied();
+            Some(ProviderStatus {
+                name: name.to_string(),
+                is_up: last.is_up,
+                last_probed: last.time,
+                probes: u64::try_from(probes.len()).ok()?,
+                uptime,
+                latency_ms_avg,
+                latency_ms_p95,
+                error: last.error.clone(),
+            })
+        })
+        .collect()
+}
+
+fn history_secs(conf: &conf::Prober) -> i64 {
+    i64::try_from(conf.history_hours.saturating_mul(3600)).unwrap_or(i64::MAX)
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_status))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/status", get(handle_status))
+}
+
+/// Uptime and latency of the upstream providers, as probed lately.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "public",
+    security(()),
+    responses((status = 200, body = Status))
+)]
+async fn handle_status(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Status>, StatusCode> {
+    let conf = conf::global();
+    let Some(prober) = &conf.prober else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let since = unix_now_secs() - history_secs(prober);
+    let probes = storage.probes(since).await.map_err(|error| {
+        tracing::error!(?error, "Failed to get probes.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    Ok(Json(Status {
+        hours: prober.history_hours,
+        providers: summarize(&probes),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data;
+
+    fn probe(provider: &str, time: i64, latency_ms: i64) -> data::Probe {
+        data::Probe {
+            provider: provider.to_string(),
+            time,
+            is_up: latency_ms < 5000,
+            latency_ms,
+            error: (latency_ms >= 5000).then(|| "Timed out.".to_string()),
+        }
+    }
+
+    #[test]
+    fn summarize() {
+        let probes = [
+            probe("primary", 0, 100),
+            probe("failover", 0, 5000),
+            probe("primary", 60, 300),
+            probe("primary", 120, 5000),
+            probe("primary", 180, 200),
+        ];
+        let providers = super::summarize(&probes);
+        assert_eq!(providers.len(), 2);
+        let primary = &providers[0];
+        assert_eq!(primary.name, "primary");
+        assert!(primary.is_up);
+        assert_eq!(primary.last_probed, 180);
+        assert_eq!(primary.probes, 4);
+        assert!((primary.uptime - 0.75).abs() < f64::EPSILON);
+        assert_eq!(primary.latency_ms_avg, Some(200));
+        assert_eq!(primary.latency_ms_p95, Some(300));
+        let failover = &providers[1];
+        assert!(!failover.is_up);
+        assert!(failover.uptime.abs() < f64::EPSILON);
+        assert_eq!(failover.latency_ms_avg, None);
+        assert_eq!(failover.latency_ms_p95, None);
+        assert_eq!(failover.error.as_deref(), Some("Timed out."));
+    }
+}
@@ -0,0 +1,95 @@
+//! Where each response came from, per [`crate::conf::Conf::provenance`], so
+//! that clients can log exactly what served each completion: which model,
+//! after experiments, overage and fallback, from which provider, for how
+//! many tokens. As a `raskol` object in whole JSON responses, and as headers
+//! of streamed ones, whose usage is known only once they end.
+
+/// Of streamed responses, the model used.
+pub const MODEL_HEADER: &str = "x-raskol-model";
+
+/// Of streamed responses, the provider which responded.
+pub const PROVIDER_HEADER: &str = "x-raskol-provider";
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub req_id: String,
+
+    /// Actually used.
+    pub model: String,
+
+    /// By the client.
+    pub requested: String,
+
+    /// "primary" or "failover".
+    pub provider: &'static str,
+
+    /// Charged, including those of responses discarded along the way.
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+
+    /// In dollars, when the model is priced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+
+    /// Whether upstream refused the model and the fallback was used.
+    pub is_fallback: bool,
+
+    /// Whether the primary failed and the failover responded.
+    pub is_failover: bool,
+
+    /// Whether served past the budget, with the overage model.
+    pub is_degraded: bool,
+}
+
+/// Into the body, as `raskol`. None when it is not a JSON object, as those
+/// of some passed through endpoints are not.
+#[must_use]
+pub fn inject(body: &str, provenance: &Provenance) -> Option<String> {
+    let mut body: serde_json::Value = serde_json::from_str(body).ok()?;
+    body.as_object_mut()?
+        .insert("raskol".to_string(), serde_json::to_value(provenance).ok()?);
+    Some(body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Provenance;
+
+    #[test]
+    fn inject() {
+        let provenance = Provenance {
+            req_id: "abc".to_string(),
+            model: "gpt-4o-mini".to_string(),
+            requested: "gpt-4o".to_string(),
+            provider: "failover",
+            input_tokens: 10,
+            output_tokens: 20,
+            cost: None,
+            is_fallback: true,
+            is_failover: true,
+            is_degraded: false,
+        };
+        let body =
+            super::inject(r#"{"id": "chatcmpl-1"}"#, &provenance).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "id": "chatcmpl-1",
+                "raskol": {
+                    "req_id": "abc",
+                    "model": "gpt-4o-mini",
+                    "requested": "gpt-4o",
+                    "provider": "failover",
+                    "input_tokens": 10,
+                    "output_tokens": 20,
+                    "is_fallback": true,
+                    "is_failover": true,
+                    "is_degraded": false,
+                },
+            })
+        );
+        assert_eq!(super::inject("[1, 2]", &provenance), None);
+        assert_eq!(super::inject("audio", &provenance), None);
+    }
+}
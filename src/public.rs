@@ -0,0 +1,87 @@
+//! Numbers of everyone's usage together, per [`conf::PublicStats`], at
+//! `/public/stats`, for a live counter on the venue's screen. Without
+//! authentication, so with nothing of any one user's, and counted at most
+//! once per the cache's TTL, however many screens ask, and rate limited per
+//! IP, as the other public routes are.
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use tokio::sync::Mutex;
+
+use crate::{
+    conf, data, period,
+    server::{unix_now_secs, AppState},
+};
+
+/// Of when they were counted. Held while counting, so that those asking
+/// meanwhile wait for the count rather than make their own.
+static CACHE: LazyLock<Mutex<Option<(Instant, Stats)>>> =
+    LazyLock::new(Mutex::default);
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Stats {
+    /// Of the day so far, in the timezone of budget periods, as
+    /// "2025-01-01".
+    pub day: String,
+
+    #[serde(flatten)]
+    pub totals: data::PublicStats,
+
+    /// Seconds since UNIX epoch, of when they were counted.
+    pub time: i64,
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_stats))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/public/stats", get(handle_stats))
+}
+
+/// Of everyone's requests today so far, as of up to the cache's TTL ago.
+/// Days are as of [`period::today`].
+#[utoipa::path(
+    get,
+    path = "/public/stats",
+    tag = "public",
+    security(()),
+    responses((status = 200, body = Stats))
+)]
+async fn handle_stats(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Stats>, StatusCode> {
+    let conf = conf::global();
+    let Some(public) = &conf.public_stats else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let ttl = Duration::from_secs(public.cache_ttl);
+    let mut cache = CACHE.lock().await;
+    if let Some((counted, stats)) = cache.as_ref() {
+        if counted.elapsed() < ttl {
+            return Ok(Json(stats.clone()));
+        }
+    }
+    let day = period::today();
+    let since = period::day_bounds(&day)
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get day bounds.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .0;
+    let totals = storage.public_stats(since).await.map_err(|error| {
+        tracing::error!(?error, "Failed to count public stats.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    let stats = Stats {
+        day,
+        totals,
+        time: unix_now_secs(),
+    };
+    *cache = Some((Instant::now(), stats.clone()));
+    Ok(Json(stats))
+}
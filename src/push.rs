@@ -0,0 +1,446 @@
+//! Pushing the metrics served at `/metrics`, per [`conf::MetricsPush`],
+//! for venues which block scraping them. The Pushgateway is given them as
+//! rendered. Statsd is given gauges, and the quantiles of summaries, as
+//! gauges, while counters, and the sums and counts of summaries and
+//! histograms, are given as how much they grew since the last push, since
+//! statsd counts increments.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::http::header;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::net::UdpSocket;
+
+use crate::{
+    conf::{self, MetricsPushKind},
+    jobs,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Of a UDP datagram, so that it isn't fragmented on common links.
+const MAX_DATAGRAM: usize = 1432;
+
+/// As the "push_metrics" job, per [`jobs`].
+pub fn spawn(
+    metrics: PrometheusHandle,
+    http: reqwest::Client,
+    conf: conf::MetricsPush,
+) {
+    let interval = Duration::from_secs(conf.interval.max(1));
+    // Of counters, as of the last push, by series.
+    let last = Arc::new(Mutex::new(HashMap::new()));
+    jobs::spawn("push_metrics", interval, move || {
+        let (metrics, http, conf, last) =
+            (metrics.clone(), http.clone(), conf.clone(), last.clone());
+        async move {
+            let rendered = metrics.render();
+            match conf.kind {
+                MetricsPushKind::Pushgateway => {
+                    push_gateway(&http, &conf, rendered).await
+                }
+                kind => {
+                    let lines = statsd(
+                        &rendered,
+                        kind,
+                        &mut last
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner),
+                    );
+                    send(&conf.address, &lines).await
+                }
+            }
+            .context("Failed to push metrics.")
+        }
+    });
+}
+
+/// Replacing what we pushed before, under the same grouping.
+async fn push_gateway(
+    http: &reqwest::Client,
+    conf: &conf::MetricsPush,
+    rendered: String,
+) -> anyhow::Result<()> {
+    http.put(gateway_url(conf))
+        .timeout(TIMEOUT)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(rendered)
+        .send()
+        .await
+        .context("Failed to reach Pushgateway.")?
+        .error_for_status()
+        .context("Pushgateway rejected metrics.")?;
+    Ok(())
+}
+
+/// Of the job's group, and the instance's, with the names encoded as path
+/// segments, since they may hold slashes, or spaces.
+fn gateway_url(conf: &conf::MetricsPush) -> String {
+    let encoded = |value: &str| {
+        percent_encoding::utf8_percent_encode(
+            value,
+            percent_encoding::NON_ALPHANUMERIC,
+        )
+        .to_string()
+    };
+    let mut url = format!(
+        "{}/metrics/job/{}",
+        conf.address.trim_end_matches('/'),
+        encoded(&conf.job)
+    );
+    if let Some(instance) = &conf.instance {
+        url.push_str("/instance/");
+        url.push_str(&encoded(instance));
+    }
+    url
+}
+
+async fn send(address: &str, lines: &[String]) -> anyhow::Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let to = tokio::net::lookup_host(address)
+        .await
+        .context("Failed to resolve statsd address.")?
+        .next()
+        .context("Statsd address resolved to nothing.")?;
+    let from: SocketAddr = if to.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(from).await?;
+    for datagram in datagrams(lines) {
+        socket
+            .send_to(datagram.as_bytes(), to)
+            .await
+            .context("Failed to send to statsd.")?;
+    }
+    Ok(())
+}
+
+/// Lines joined, as many as fit in each.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram)
+                if datagram.len() + 1 + line.len() <= MAX_DATAGRAM =>
+            {
+                datagram.push('\n');
+                datagram.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Counter,
+    Gauge,
+}
+
+/// Of the rendered metrics, in the kind of statsd's format. Counters which
+/// didn't grow since `last` are left out.
+fn statsd(
+    rendered: &str,
+    kind: MetricsPushKind,
+    last: &mut HashMap<String, f64>,
+) -> Vec<String> {
+    let mut types = HashMap::new();
+    let mut lines = Vec::new();
+    for line in rendered.lines() {
+        if let Some(declared) = line.strip_prefix("# TYPE ") {
+            if let Some((name, type_)) = declared.split_once(' ') {
+                types.insert(name, type_.trim());
+            }
+            continue;
+        }
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let Some(sample) = Sample::parse(line) else {
+            continue;
+        };
+        let Some(type_) = type_of(&types, sample.name) else {
+            continue;
+        };
+        if !sample.value.is_finite() {
+            continue;
+        }
+        let value = match type_ {
+            Type::Gauge => sample.value,
+            Type::Counter => {
+                let previous = last
+                    .insert(sample.series.to_string(), sample.value)
+                    .unwrap_or(0.0);
+                let delta = sample.value - previous;
+                if delta.abs() < f64::EPSILON {
+                    continue;
+                }
+                // Reset, such as by a restart.
+                if delta < 0.0 {
+                    sample.value
+                } else {
+                    delta
+                }
+            }
+        };
+        lines.extend(sample.statsd(value, type_, kind));
+    }
+    lines
+}
+
+/// None for those not pushed: histograms' buckets, which are many.
+fn type_of(types: &HashMap<&str, &str>, name: &str) -> Option<Type> {
+    match types.get(name) {
+        Some(&"counter") => return Some(Type::Counter),
+        Some(_) => return Some(Type::Gauge),
+        None => {}
+    }
+    for suffix in ["_sum", "_count", "_bucket"] {
+        let Some(base) = name.strip_suffix(suffix) else {
+            continue;
+        };
+        if let Some(&"summary" | &"histogram") = types.get(base) {
+            return (suffix != "_bucket").then_some(Type::Counter);
+        }
+    }
+    Some(Type::Gauge)
+}
+
+/// A line of the Prometheus text format.
+#[derive(Debug, PartialEq)]
+struct Sample<'a> {
+    /// The name and labels, as rendered.
+    series: &'a str,
+
+    name: &'a str,
+    labels: Vec<(&'a str, String)>,
+    value: f64,
+}
+
+impl<'a> Sample<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let (series, value) = line.trim().rsplit_once(' ')?;
+        let value = value.parse().ok()?;
+        let (name, labels) = match series.split_once('{') {
+            Some((name, labels)) => {
+                (name, parse_labels(labels.strip_suffix('}')?)?)
+            }
+            None => (series, Vec::new()),
+        };
+        Some(Self {
+            series,
+            name,
+            labels,
+            value,
+        })
+    }
+
+    fn statsd(
+        &self,
+        value: f64,
+        type_: Type,
+        kind: MetricsPushKind,
+    ) -> Vec<String> {
+        let type_ = match type_ {
+            Type::Counter => "c",
+            Type::Gauge => "g",
+        };
+        if let MetricsPushKind::Dogstatsd = kind {
+            let tags: Vec<String> = self
+                .labels
+                .iter()
+                .map(|(key, value)| {
+                    format!("{key}:{}", sanitized(value, is_tag_safe))
+                })
+                .collect();
+            let line = format!("{}:{value}|{type_}", self.name);
+            return vec![if tags.is_empty() {
+                line
+            } else {
+                format!("{line}|#{}", tags.join(","))
+            }];
+        }
+        let mut name = self.name.to_string();
+        for (key, value) in &self.labels {
+            name.push('.');
+            name.push_str(key);
+            name.push('.');
+            name.push_str(&sanitized(value, is_name_safe));
+        }
+        // Signed gauges are changes to plain statsd, not values.
+        if type_ == "g" && value < 0.0 {
+            return vec![format!("{name}:0|g"), format!("{name}:{value}|g")];
+        }
+        vec![format!("{name}:{value}|{type_}")]
+    }
+}
+
+/// Of `key="value",...`, unescaped.
+fn parse_labels(mut labels: &str) -> Option<Vec<(&str, String)>> {
+    let mut parsed = Vec::new();
+    loop {
+        labels = labels.trim_start_matches(',').trim_start();
+        if labels.is_empty() {
+            return Some(parsed);
+        }
+        let (key, rest) = labels.split_once("=\"")?;
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (i, '"') => break i,
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                (_, c) => value.push(c),
+            }
+        };
+        parsed.push((key.trim(), value));
+        labels = &rest[end + 1..];
+    }
+}
+
+fn sanitized(value: &str, is_safe: fn(char) -> bool) -> String {
+    value
+        .chars()
+        .map(|c| if is_safe(c) { c } else { '_' })
+        .collect()
+}
+
+fn is_name_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_tag_safe(c: char) -> bool {
+    !(c.is_whitespace() || matches!(c, ',' | '|' | '#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{datagrams, gateway_url, statsd, Sample, MAX_DATAGRAM};
+    use crate::conf::{MetricsPush, MetricsPushKind};
+
+    const RENDERED: &str = r#"# TYPE raskol_request_errors_total counter
+raskol_request_errors_total{class="auth"} 3
+raskol_request_errors_total{class="upstream_5xx"} 1
+
+# TYPE raskol_queue_depth gauge
+raskol_queue_depth 2
+
+# TYPE raskol_latency summary
+raskol_latency{endpoint="v1/chat",quantile="0.5"} 0.25
+raskol_latency_sum{endpoint="v1/chat"} 10
+raskol_latency_count{endpoint="v1/chat"} 40
+
+# TYPE raskol_tokens histogram
+raskol_tokens_bucket{le="+Inf"} 5
+raskol_tokens_sum 100
+raskol_tokens_count 5
+"#;
+
+    #[test]
+    fn samples() {
+        let sample =
+            Sample::parse(r#"a_total{x="say \"hi\"",y="1,2"} 4"#).unwrap();
+        assert_eq!(sample.name, "a_total");
+        assert_eq!(sample.series, r#"a_total{x="say \"hi\"",y="1,2"}"#);
+        assert_eq!(
+            sample.labels,
+            [("x", r#"say "hi""#.to_string()), ("y", "1,2".to_string())]
+        );
+        assert!((sample.value - 4.0).abs() < f64::EPSILON);
+        assert!(Sample::parse("a_total").is_none());
+    }
+
+    #[test]
+    fn dogstatsd() {
+        let mut last = HashMap::new();
+        let lines = statsd(RENDERED, MetricsPushKind::Dogstatsd, &mut last);
+        assert_eq!(
+            lines,
+            [
+                "raskol_request_errors_total:3|c|#class:auth",
+                "raskol_request_errors_total:1|c|#class:upstream_5xx",
+                "raskol_queue_depth:2|g",
+                "raskol_latency:0.25|g|#endpoint:v1/chat,quantile:0.5",
+                "raskol_latency_sum:10|c|#endpoint:v1/chat",
+                "raskol_latency_count:40|c|#endpoint:v1/chat",
+                "raskol_tokens_sum:100|c",
+                "raskol_tokens_count:5|c",
+            ]
+        );
+        let rendered = RENDERED.replace(r#""auth"} 3"#, r#""auth"} 5"#);
+        let lines = statsd(&rendered, MetricsPushKind::Dogstatsd, &mut last);
+        assert_eq!(
+            lines,
+            [
+                "raskol_request_errors_total:2|c|#class:auth",
+                "raskol_queue_depth:2|g",
+                "raskol_latency:0.25|g|#endpoint:v1/chat,quantile:0.5",
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_statsd() {
+        let lines =
+            statsd(RENDERED, MetricsPushKind::Statsd, &mut HashMap::new());
+        assert_eq!(lines[0], "raskol_request_errors_total.class.auth:3|c");
+        assert_eq!(
+            lines[3],
+            "raskol_latency.endpoint.v1_chat.quantile.0_5:0.25|g"
+        );
+        let lines = statsd(
+            "# TYPE a gauge\na -1\n",
+            MetricsPushKind::Statsd,
+            &mut HashMap::new(),
+        );
+        assert_eq!(lines, ["a:0|g", "a:-1|g"]);
+    }
+
+    #[test]
+    fn gateway_urls() {
+        let mut conf = MetricsPush {
+            kind: MetricsPushKind::Pushgateway,
+            address: "http://gateway:9091/".to_string(),
+            interval: 15,
+            job: "raskol".to_string(),
+            instance: None,
+        };
+        assert_eq!(
+            gateway_url(&conf),
+            "http://gateway:9091/metrics/job/raskol"
+        );
+        conf.job = "raskol/eu west".to_string();
+        conf.instance = Some("10.0.0.1:8080".to_string());
+        assert_eq!(
+            gateway_url(&conf),
+            "http://gateway:9091/metrics/job/raskol%2Feu%20west\
+            /instance/10%2E0%2E0%2E1%3A8080"
+        );
+    }
+
+    #[test]
+    fn split() {
+        let line = "x".repeat(MAX_DATAGRAM / 2);
+        let lines = vec![line.clone(), line.clone(), "y:1|c".to_string()];
+        assert_eq!(
+            datagrams(&lines),
+            [line.clone(), format!("{line}\ny:1|c")]
+        );
+    }
+}
@@ -0,0 +1,581 @@
+//! Holding requests while upstream throttles us, rather than failing them
+//! right away. Waiting requests are released by the priority of their
+//! users' roles and, within a priority, in order, taking turns between
+//! users, so that one user's burst doesn't hold up everyone else. Where
+//! upstream's requests per minute are known, they are shared out between
+//! orgs, so that one team's spike doesn't take them all.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{conf, keypool::Headroom};
+
+/// How often held requests check whether upstream's headroom recovered,
+/// since other responses update it, besides the limits resetting.
+const HOLD_POLL: Duration = Duration::from_secs(1);
+
+/// Of upstream's requests per minute.
+const RPM_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too many are waiting already.
+    Full,
+
+    /// Upstream kept throttling for longer than we may wait.
+    TimedOut,
+}
+
+pub struct Queue {
+    conf: conf::Queue,
+    state: Mutex<State>,
+    turn: Notify,
+
+    /// Not when upstream's requests per minute are not known.
+    slices: Option<Mutex<Slices>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_ticket: u64,
+
+    /// Priority -> its waiting requests.
+    classes: BTreeMap<u8, Class>,
+
+    len: usize,
+}
+
+#[derive(Default)]
+struct Class {
+    /// uid -> tickets, in arrival order.
+    waiting: HashMap<String, VecDeque<Ticket>>,
+
+    /// Users with waiting tickets, whose turn is next first.
+    turns: VecDeque<String>,
+}
+
+struct Ticket {
+    id: u64,
+
+    /// When the request first joined the queue.
+    since: Instant,
+}
+
+/// Of upstream's requests per minute, those sent within the last one, by
+/// org, so that each gets its reservation first, and a fair share of what
+/// is left.
+struct Slices {
+    capacity: u64,
+
+    /// org -> its reservation, per conf or admin.
+    reserved: BTreeMap<String, u64>,
+
+    /// org ("" for users of none) -> when its requests were sent, and
+    /// whether from its reservation.
+    sent: HashMap<String, VecDeque<(Instant, bool)>>,
+}
+
+impl Slices {
+    /// Takes a slot for the org or, if there is none left for it, tells
+    /// when to try again.
+    fn take(&mut self, org: &str, now: Instant) -> Result<(), Duration> {
+        for sent in self.sent.values_mut() {
+            while sent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= RPM_WINDOW)
+            {
+                sent.pop_front();
+            }
+        }
+        self.sent.retain(|_, sent| !sent.is_empty());
+        let reserved = self.reserved.get(org).copied().unwrap_or(0);
+        if self.count(org, true) < reserved {
+            self.push(org, now, true);
+            return Ok(());
+        }
+        let unreserved = self
+            .capacity
+            .saturating_sub(self.reserved.values().sum::<u64>());
+        let mut used = 0;
+        // Those after the unreserved, the org included.
+        let mut contenders = u64::from(!self.sent.contains_key(org));
+        for other in self.sent.keys() {
+            let shared = self.count(other, false);
+            used += shared;
+            if shared > 0 || other == org {
+                contenders += 1;
+            }
+        }
+        let share = unreserved.div_ceil(contenders);
+        if used < unreserved && self.count(org, false) < share {
+            self.push(org, now, false);
+            return Ok(());
+        }
+        // Once the oldest of those it is after frees up.
+        let oldest = self
+            .sent
+            .iter()
+            .flat_map(|(other, sent)| {
+                sent.iter().filter(move |(_, is_reserved)| {
+                    !is_reserved || other == org
+                })
+            })
+            .map(|(at, _)| *at)
+            .min()
+            .unwrap_or(now);
+        Err((oldest + RPM_WINDOW).saturating_duration_since(now))
+    }
+
+    fn count(&self, org: &str, is_reserved: bool) -> u64 {
+        let count = self.sent.get(org).map_or(0, |sent| {
+            sent.iter().filter(|(_, r)| *r == is_reserved).count()
+        });
+        u64::try_from(count).unwrap_or(u64::MAX)
+    }
+
+    fn push(&mut self, org: &str, now: Instant, is_reserved: bool) {
+        self.sent
+            .entry(org.to_string())
+            .or_default()
+            .push_back((now, is_reserved));
+    }
+}
+
+impl State {
+    fn enqueue(&mut self, priority: u8, uid: &str, since: Instant) -> u64 {
+        let id = self.next_ticket;
+        self.next_ticket += 1;
+        let class = self.classes.entry(priority).or_default();
+        let tickets = class.waiting.entry(uid.to_string()).or_default();
+        if tickets.is_empty() {
+            class.turns.push_back(uid.to_string());
+        }
+        tickets.push_back(Ticket { id, since });
+        self.len += 1;
+        id
+    }
+
+    /// The class with the highest priority goes first, but lower ones are
+    /// bumped up a level for each `aging` their oldest has waited, so that
+    /// they are not starved.
+    fn is_next(
+        &self,
+        priority: u8,
+        uid: &str,
+        id: u64,
+        now: Instant,
+        aging: Duration,
+    ) -> bool {
+        let next = self.classes.iter().max_by_key(|(priority, class)| {
+            let oldest = class
+                .waiting
+                .values()
+                .filter_map(VecDeque::front)
+                .map(|ticket| ticket.since)
+                .min()
+                .unwrap_or(now);
+            let levels = now.duration_since(oldest).as_secs_f64()
+                / aging.as_secs_f64().max(f64::EPSILON);
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let levels = levels.min(f64::from(u8::MAX)) as u32;
+            (u32::from(**priority) + levels, **priority)
+        });
+        let Some((next_priority, class)) = next else {
+            return false;
+        };
+        *next_priority == priority
+            && class.turns.front().is_some_and(|next| next == uid)
+            && class
+                .waiting
+                .get(uid)
+                .and_then(VecDeque::front)
+                .is_some_and(|next| next.id == id)
+    }
+
+    fn dequeue(&mut self, priority: u8, uid: &str, id: u64) {
+        let Some(class) = self.classes.get_mut(&priority) else {
+            return;
+        };
+        let Some(tickets) = class.waiting.get_mut(uid) else {
+            return;
+        };
+        let Some(position) = tickets.iter().position(|t| t.id == id) else {
+            return;
+        };
+        tickets.remove(position);
+        self.len -= 1;
+        let is_user_done = tickets.is_empty();
+        if is_user_done {
+            class.waiting.remove(uid);
+        }
+        match (
+            class.turns.iter().position(|next| next == uid),
+            is_user_done,
+        ) {
+            (Some(turn), true) => {
+                class.turns.remove(turn);
+            }
+            // Having had a turn, the user goes to the back.
+            (Some(0), false) => class.turns.rotate_left(1),
+            _ => {}
+        }
+        if class.waiting.is_empty() {
+            self.classes.remove(&priority);
+        }
+    }
+}
+
+impl Queue {
+    #[must_use]
+    pub fn new(conf: &conf::Queue) -> Self {
+        Self {
+            conf: conf.clone(),
+            state: Mutex::default(),
+            turn: Notify::new(),
+            slices: conf.upstream_rpm.map(|capacity| {
+                Mutex::new(Slices {
+                    capacity,
+                    reserved: conf.reserved_rpm.clone(),
+                    sent: HashMap::new(),
+                })
+            }),
+        }
+    }
+
+    /// Of orgs, reservations of their own, set by admins, over those in
+    /// conf. Replaces those set before.
+    pub fn set_reserved(&self, own: impl IntoIterator<Item = (String, u64)>) {
+        let Some(slices) = &self.slices else {
+            return;
+        };
+        let mut reserved = self.conf.reserved_rpm.clone();
+        reserved.extend(own);
+        slices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reserved = reserved;
+    }
+
+    /// Waits, if need be, for a slot of upstream's requests per minute, out
+    /// of the org's reservation first, then out of its share of the rest.
+    /// Not when upstream's requests per minute are not known. The wait is
+    /// limited since `started`, as the others are.
+    pub async fn pace(
+        &self,
+        org: Option<&str>,
+        started: Instant,
+    ) -> Result<(), Rejection> {
+        let Some(slices) = &self.slices else {
+            return Ok(());
+        };
+        let org = org.unwrap_or_default();
+        let deadline = started + Duration::from_secs_f32(self.conf.max_wait);
+        let mut is_paced = false;
+        loop {
+            let now = Instant::now();
+            let taken = slices
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take(org, now);
+            let Err(retry_after) = taken else {
+                return Ok(());
+            };
+            if now >= deadline {
+                return Err(Rejection::TimedOut);
+            }
+            if !is_paced {
+                is_paced = true;
+                tracing::info!(org, ?retry_after, "Pacing. Out of slots.");
+                metrics::counter!("raskol_queue_paced_total").increment(1);
+            }
+            let retry =
+                retry_after.clamp(Duration::from_millis(10), HOLD_POLL);
+            tokio::time::sleep_until((now + retry).min(deadline)).await;
+        }
+    }
+
+    /// Waits, if need be, until upstream stops throttling and it is the
+    /// turn of the user, of the given priority. `throttled_for` tells how
+    /// much longer upstream asked us to back off. The wait is limited since
+    /// `started`, which is earlier than now when the request is back for
+    /// another go. Returns how long it has waited since `started`.
+    pub async fn wait(
+        &self,
+        uid: &str,
+        priority: u8,
+        started: Instant,
+        throttled_for: impl Fn() -> Option<Duration>,
+    ) -> Result<Duration, Rejection> {
+        let ticket = {
+            let mut state = self.lock();
+            if state.len == 0 && throttled_for().is_none() {
+                return Ok(started.elapsed());
+            }
+            if state.len >= self.conf.max_len {
+                return Err(Rejection::Full);
+            }
+            let ticket = state.enqueue(priority, uid, started);
+            metrics::gauge!("raskol_queue_depth").set(len_f64(state.len));
+            ticket
+        };
+        // Leaves the queue even if the client gives up waiting.
+        let _waiting = Waiting {
+            queue: self,
+            priority,
+            uid,
+            ticket,
+        };
+        let deadline = started + Duration::from_secs_f32(self.conf.max_wait);
+        let aging = Duration::from_secs_f32(self.conf.aging);
+        loop {
+            let turn = self.turn.notified();
+            tokio::pin!(turn);
+            // Registered before checking, so that no turn is missed.
+            turn.as_mut().enable();
+            let throttled = throttled_for();
+            if throttled.is_none()
+                && self.lock().is_next(
+                    priority,
+                    uid,
+                    ticket,
+                    Instant::now(),
+                    aging,
+                )
+            {
+                break Ok(started.elapsed());
+            }
+            let until = throttled
+                .map_or(deadline, |throttled| Instant::now() + throttled)
+                .min(deadline);
+            tokio::select! {
+                () = turn => {}
+                () = tokio::time::sleep_until(until) => {}
+            }
+            if Instant::now() >= deadline {
+                break Err(Rejection::TimedOut);
+            }
+        }
+    }
+
+    /// Holds requests of a priority under the reserved one while upstream's
+    /// headroom is low, leaving what is left of it to the others. Apart
+    /// from the queue's order, so as not to hold up the others behind
+    /// them. The hold is limited since `started`, as the wait is.
+    pub async fn hold(
+        &self,
+        priority: u8,
+        started: Instant,
+        headroom: impl Fn() -> Option<Headroom>,
+    ) -> Result<(), Rejection> {
+        let Some(min_headroom) = self.conf.min_headroom else {
+            return Ok(());
+        };
+        if priority >= self.conf.reserved_priority {
+            return Ok(());
+        }
+        let deadline = started + Duration::from_secs_f32(self.conf.max_wait);
+        let mut is_held = false;
+        loop {
+            let Some(low) = headroom()
+                .filter(|headroom| headroom.fraction < min_headroom)
+            else {
+                return Ok(());
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Rejection::TimedOut);
+            }
+            if !is_held {
+                is_held = true;
+                tracing::info!(?low, priority, "Holding. Low headroom.");
+                metrics::counter!("raskol_queue_held_total").increment(1);
+            }
+            let poll = low.resets_in.map_or(HOLD_POLL, |r| r.min(HOLD_POLL));
+            tokio::time::sleep_until((now + poll).min(deadline)).await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct Waiting<'a> {
+    queue: &'a Queue,
+    priority: u8,
+    uid: &'a str,
+    ticket: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.queue.lock();
+            state.dequeue(self.priority, self.uid, self.ticket);
+            metrics::gauge!("raskol_queue_depth").set(len_f64(state.len));
+        }
+        self.queue.turn.notify_waiters();
+    }
+}
+
+#[allow(clippy::cast_precision_loss)] // Queues are nowhere near.
+fn len_f64(len: usize) -> f64 {
+    len as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::{Queue, Rejection, Slices, State};
+    use crate::{conf, keypool::Headroom};
+
+    #[test]
+    fn turns() {
+        let now = Instant::now();
+        let aging = Duration::from_secs(10);
+        let mut state = State::default();
+        let a1 = state.enqueue(0, "a", now);
+        let a2 = state.enqueue(0, "a", now);
+        let b1 = state.enqueue(0, "b", now);
+        assert!(state.is_next(0, "a", a1, now, aging));
+        state.dequeue(0, "a", a1);
+        // b's turn, despite a being first with its second.
+        assert!(!state.is_next(0, "a", a2, now, aging));
+        assert!(state.is_next(0, "b", b1, now, aging));
+        state.dequeue(0, "b", b1);
+        assert!(state.is_next(0, "a", a2, now, aging));
+        state.dequeue(0, "a", a2);
+        assert_eq!(state.len, 0);
+        assert!(state.classes.is_empty());
+    }
+
+    #[test]
+    fn priorities() {
+        let now = Instant::now();
+        let aging = Duration::from_secs(10);
+        let mut state = State::default();
+        let low = state.enqueue(0, "a", now);
+        let high = state.enqueue(1, "b", now);
+        assert!(state.is_next(1, "b", high, now, aging));
+        assert!(!state.is_next(0, "a", low, now, aging));
+        state.dequeue(1, "b", high);
+        let later = now + Duration::from_secs(15);
+        let high = state.enqueue(1, "b", later);
+        // Bumped up to par, but ties go to the higher.
+        assert!(state.is_next(1, "b", high, later, aging));
+        // Bumped up above.
+        let later = now + Duration::from_secs(20);
+        assert!(state.is_next(0, "a", low, later, aging));
+    }
+
+    #[test]
+    fn slices() {
+        let now = Instant::now();
+        let mut slices = Slices {
+            capacity: 10,
+            reserved: [("a".to_string(), 4)].into(),
+            sent: HashMap::new(),
+        };
+        for _ in 0..3 {
+            assert_eq!(slices.take("b", now), Ok(()));
+        }
+        assert_eq!(slices.take("", now), Ok(()));
+        // Past its share of the unreserved 6, with another after them too.
+        let later = now + Duration::from_secs(15);
+        assert_eq!(slices.take("b", later), Err(Duration::from_secs(45)));
+        // Its reservation, untouched by the others.
+        for _ in 0..4 {
+            assert_eq!(slices.take("a", later), Ok(()));
+        }
+        // Then its share of the rest, along with the others.
+        assert_eq!(slices.take("a", later), Ok(()));
+        assert_eq!(slices.take("", later), Ok(()));
+        assert!(slices.take("", later).is_err());
+        assert!(slices.take("a", later).is_err());
+        // A minute on, the first ones free up.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(slices.take("b", later), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait() {
+        let queue = Arc::new(Queue::new(&conf::Queue {
+            max_wait: 5.0,
+            max_len: 1,
+            aging: 10.0,
+            ..conf::Queue::default()
+        }));
+        let now = Instant::now;
+        let result = queue.wait("a", 0, now(), || None).await;
+        assert_eq!(result, Ok(Duration::ZERO));
+
+        // Throttled for 2s, then free.
+        let throttled_until = now() + Duration::from_secs(2);
+        let throttled_for = move || {
+            throttled_until
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+        };
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait("a", 0, now(), throttled_for).await }
+        });
+        tokio::task::yield_now().await;
+        let result = queue.wait("b", 0, now(), || None).await;
+        assert_eq!(result, Err(Rejection::Full));
+        assert_eq!(waiting.await.unwrap(), Ok(Duration::from_secs(2)));
+
+        // Throttled for longer than we may wait, in total.
+        let started = now() - Duration::from_secs(4);
+        let throttled_for = || Some(Duration::from_secs(60));
+        let result = queue.wait("a", 0, started, throttled_for).await;
+        assert_eq!(result, Err(Rejection::TimedOut));
+        assert_eq!(now() - started, Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hold() {
+        let queue = Queue::new(&conf::Queue {
+            max_wait: 5.0,
+            min_headroom: Some(0.2),
+            reserved_priority: 1,
+            ..conf::Queue::default()
+        });
+        let now = Instant::now;
+        let low = || {
+            Some(Headroom {
+                fraction: 0.1,
+                resets_in: None,
+            })
+        };
+        assert_eq!(queue.hold(1, now(), low).await, Ok(()));
+        assert_eq!(queue.hold(0, now(), || None).await, Ok(()));
+
+        // Recovers after 2s.
+        let recovered_at = now() + Duration::from_secs(2);
+        let recovering = move || {
+            (Instant::now() < recovered_at).then_some(Headroom {
+                fraction: 0.1,
+                resets_in: None,
+            })
+        };
+        let started = now();
+        assert_eq!(queue.hold(0, started, recovering).await, Ok(()));
+        assert_eq!(now() - started, Duration::from_secs(2));
+
+        let started = now();
+        assert_eq!(
+            queue.hold(0, started, low).await,
+            Err(Rejection::TimedOut)
+        );
+        assert_eq!(now() - started, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{conf, data::Storage};
+
+/// Limit on the number of requests within a sliding window.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub period: Duration,
+    pub max_requests: u64,
+}
+
+impl Window {
+    /// Approximates a sliding window with the counts of the current and
+    /// previous fixed windows, assuming requests in the previous window were
+    /// evenly spread. Returns how many requests the current fixed window can
+    /// hold in total, given the count of the previous one and how far we
+    /// are into the current one.
+    #[must_use]
+    pub fn capacity(&self, prev_count: u64, elapsed: Duration) -> u64 {
+        let period = self.period.as_secs_f64();
+        let remaining =
+            (1.0 - elapsed.as_secs_f64() / period).clamp(0.0, 1.0);
+        #[allow(
+            clippy::cast_precision_loss,
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss
+        )]
+        let prev_weighted = (prev_count as f64 * remaining).floor() as u64;
+        self.max_requests.saturating_sub(prev_weighted)
+    }
+}
+
+/// Per [`conf::Bucket`], in tokens of a request each.
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    /// Per second.
+    pub rate: f64,
+    pub capacity: f64,
+}
+
+impl Bucket {
+    #[must_use]
+    pub fn new(conf: &conf::Bucket) -> Self {
+        #[allow(clippy::cast_precision_loss)] // Bursts are nowhere near.
+        let capacity = conf.burst as f64;
+        Self {
+            rate: conf.requests_per_minute / 60.0,
+            capacity,
+        }
+    }
+
+    /// Of the tokens left as of the elapsed time ago, those left after a
+    /// request, or else how long until there is one for it. Full when
+    /// there were none before.
+    pub fn take(
+        &self,
+        tokens: Option<f64>,
+        elapsed: Duration,
+    ) -> Result<f64, Duration> {
+        let tokens = tokens.map_or(self.capacity, |tokens| {
+            (tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity)
+        });
+        if tokens >= 1.0 {
+            Ok(tokens - 1.0)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+        }
+    }
+
+    /// Seconds, after which an unused bucket is full again, so forgotten.
+    #[must_use]
+    pub fn ttl(&self) -> u64 {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let ttl = (self.capacity / self.rate).ceil() as u64;
+        ttl + 1
+    }
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    /// Too close to the previous request.
+    Interval { retry_after: Duration },
+
+    /// Out of tokens in the bucket.
+    Bucket { retry_after: Duration },
+
+    /// Too many requests within a window.
+    Window {
+        window: Window,
+        retry_after: Duration,
+    },
+}
+
+impl Rejection {
+    /// Of the window, as of now, since the Unix epoch.
+    #[must_use]
+    pub fn exceeded(window: Window, now: Duration) -> Self {
+        // Conservative: by the end of the current fixed window the count
+        // restarts and the previous count is only going to be discounted.
+        let period = window.period.as_secs().max(1);
+        let into_window = now.as_secs() % period;
+        let retry_after = Duration::from_secs(period - into_window);
+        Self::Window {
+            window,
+            retry_after,
+        }
+    }
+
+    #[must_use]
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Self::Interval { retry_after }
+            | Self::Bucket { retry_after }
+            | Self::Window { retry_after, .. } => *retry_after,
+        }
+    }
+
+    /// What the client is told of the limit, naming its setting.
+    #[must_use]
+    pub fn message(&self) -> String {
+        match self {
+            Self::Interval { .. } => "Too soon after the previous request, \
+                per min_hit_interval."
+                .to_string(),
+            Self::Bucket { .. } => {
+                "Rate limit exceeded, per rate_limit.bucket.".to_string()
+            }
+            Self::Window { window, .. } => {
+                let setting = match window.period.as_secs() {
+                    1 => "burst",
+                    60 => "requests_per_minute",
+                    _ => "requests_per_hour",
+                };
+                format!(
+                    "Rate limit of {} requests per {}s exceeded, per \
+                    rate_limit.{setting}.",
+                    window.max_requests,
+                    window.period.as_secs(),
+                )
+            }
+        }
+    }
+}
+
+#[must_use]
+pub fn windows(rate_limit: &conf::RateLimit) -> Vec<Window> {
+    [
+        (Duration::from_secs(1), rate_limit.burst),
+        (Duration::from_secs(60), rate_limit.requests_per_minute),
+        (Duration::from_secs(3600), rate_limit.requests_per_hour),
+    ]
+    .into_iter()
+    .filter_map(|(period, max_opt)| {
+        max_opt.map(|max_requests| Window {
+            period,
+            max_requests,
+        })
+    })
+    .collect()
+}
+
+/// Counts the request against the user's limits, failing if any of them is
+/// exceeded.
+pub async fn check(
+    storage: &dyn Storage,
+    uid: &str,
+    role: &str,
+) -> anyhow::Result<Result<(), Rejection>> {
+    let conf = conf::global();
+    let (min_hit_interval, rate_limit) = limits(&conf, role);
+    let (hit_count, elapsed_since_prev) = storage.hit(uid).await?;
+    tracing::debug!(
+        hit_count,
+        ?elapsed_since_prev,
+        ?min_hit_interval,
+        "Checking interval."
+    );
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    if let Some(bucket) = &rate_limit.bucket {
+        let bucket = Bucket::new(bucket);
+        if let Some(retry_after) =
+            storage.rate_take(uid, role, bucket, now).await?
+        {
+            return Ok(Err(Rejection::Bucket { retry_after }));
+        }
+    } else if elapsed_since_prev < min_hit_interval {
+        let retry_after = min_hit_interval.saturating_sub(elapsed_since_prev);
+        return Ok(Err(Rejection::Interval { retry_after }));
+    }
+
+    let windows = windows(rate_limit);
+    if windows.is_empty() {
+        return Ok(Ok(()));
+    }
+    if let Some(window) = storage.rate_acquire(uid, &windows, now).await? {
+        return Ok(Err(Rejection::exceeded(window, now)));
+    }
+    Ok(Ok(()))
+}
+
+/// Where a user stands with their limits, for clients to pace themselves.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct State {
+    /// Seconds between requests.
+    pub min_interval: f32,
+
+    pub windows: Vec<WindowState>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct WindowState {
+    /// Seconds.
+    pub period: u64,
+
+    pub limit: u64,
+
+    /// Requests which would be let through now.
+    pub remaining: u64,
+
+    /// Seconds until the current fixed window ends, when the count
+    /// restarts.
+    pub reset: u64,
+}
+
+/// Without counting a request.
+pub async fn state(
+    storage: &dyn Storage,
+    uid: &str,
+    role: &str,
+) -> anyhow::Result<State> {
+    let conf = conf::global();
+    let (min_hit_interval, rate_limit) = limits(&conf, role);
+    let windows = windows(rate_limit);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let counts = storage.rate_counts(uid, &windows, now).await?;
+    let windows = windows
+        .iter()
+        .zip(counts)
+        .map(|(window, (curr, prev))| {
+            let period = window.period.as_secs().max(1);
+            let into_window = now.as_secs() % period;
+            let elapsed = Duration::from_secs(into_window)
+                + Duration::from_nanos(u64::from(now.subsec_nanos()));
+            WindowState {
+                period,
+                limit: window.max_requests,
+                remaining: window
+                    .capacity(prev, elapsed)
+                    .saturating_sub(curr),
+                reset: period - into_window,
+            }
+        })
+        .collect();
+    Ok(State {
+        // Of none, with the bucket in its place.
+        min_interval: if rate_limit.bucket.is_some() {
+            0.0
+        } else {
+            min_hit_interval.as_secs_f32()
+        },
+        windows,
+    })
+}
+
+/// Minimum interval between requests and the windows, of the role's if it
+/// has its own.
+fn limits<'a>(
+    conf: &'a conf::Conf,
+    role: &str,
+) -> (Duration, &'a conf::RateLimit) {
+    let role_limits = conf.limits.per_role.get(role);
+    let min_hit_interval = role_limits
+        .and_then(|limits| limits.min_hit_interval)
+        .unwrap_or(conf.min_hit_interval);
+    let rate_limit = role_limits
+        .and_then(|limits| limits.rate_limit.as_ref())
+        .unwrap_or(&conf.rate_limit);
+    (Duration::from_secs_f32(min_hit_interval), rate_limit)
+}
+
+/// In-flight requests per user. In-memory, since in-flight requests don't
+/// survive a restart anyway.
+#[derive(Default)]
+pub struct Concurrency {
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Concurrency {
+    /// Returns `None` if the user already has `max` requests in flight,
+    /// otherwise a permit to be held for the duration of the request.
+    pub fn try_acquire(
+        &self,
+        uid: &str,
+        max: usize,
+    ) -> Option<OwnedSemaphorePermit> {
+        let semaphore = {
+            let mut semaphores = self
+                .semaphores
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            semaphores
+                .entry(uid.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        };
+        semaphore.try_acquire_owned().ok()
+    }
+}
+
+/// Above this many tracked addresses, the ones not seen lately are
+/// forgotten.
+const PER_IP_MAX_TRACKED: usize = 10_000;
+
+/// Request counts per client address, for the endpoints which need no auth.
+/// In-memory, so that a scanner hammering them doesn't hammer the database.
+#[derive(Default)]
+pub struct PerIp {
+    counts: Mutex<HashMap<IpAddr, Vec<Count>>>,
+}
+
+/// Of one window.
+#[derive(Clone, Copy, Default)]
+struct Count {
+    start: u64,
+    prev: u64,
+    curr: u64,
+}
+
+impl PerIp {
+    /// Counts the request, unless it exceeds any of the windows, in which
+    /// case the exceeded window is returned. Same approximation as the
+    /// per-user limits.
+    pub fn acquire(
+        &self,
+        ip: IpAddr,
+        windows: &[Window],
+        now: Duration,
+    ) -> Option<Window> {
+        let mut counts = self
+            .counts
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if counts.len() >= PER_IP_MAX_TRACKED {
+            let longest = windows
+                .iter()
+                .map(|window| window.period.as_secs())
+                .max()
+                .unwrap_or(0);
+            let stale = now.as_secs().saturating_sub(2 * longest);
+            counts.retain(|_, counts| {
+                counts.iter().any(|count| count.start >= stale)
+            });
+        }
+        let counts = counts
+            .entry(ip)
+            .or_insert_with(|| vec![Count::default(); windows.len()]);
+        counts.resize(windows.len(), Count::default());
+        for (window, count) in windows.iter().zip(counts.iter_mut()) {
+            let period = window.period.as_secs().max(1);
+            let into_window = now.as_secs() % period;
+            let start = now.as_secs() - into_window;
+            if count.start != start {
+                count.prev = if count.start + period == start {
+                    count.curr
+                } else {
+                    0
+                };
+                count.curr = 0;
+                count.start = start;
+            }
+            let capacity = window.capacity(
+                count.prev,
+                Duration::from_secs(into_window)
+                    + Duration::from_nanos(u64::from(now.subsec_nanos())),
+            );
+            if count.curr >= capacity {
+                return Some(*window);
+            }
+        }
+        for count in counts.iter_mut() {
+            count.curr += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::{Bucket, Concurrency, PerIp, Rejection, Window};
+    use crate::conf;
+
+    #[test]
+    fn capacity() {
+        let window = Window {
+            period: Duration::from_secs(60),
+            max_requests: 10,
+        };
+        assert_eq!(window.capacity(0, Duration::ZERO), 10);
+        assert_eq!(window.capacity(10, Duration::ZERO), 0);
+        assert_eq!(window.capacity(10, Duration::from_secs(30)), 5);
+        assert_eq!(window.capacity(10, Duration::from_secs(60)), 10);
+        assert_eq!(window.capacity(100, Duration::from_secs(30)), 0);
+    }
+
+    #[test]
+    fn bucket() {
+        let bucket = Bucket::new(&conf::Bucket {
+            requests_per_minute: 60.0,
+            burst: 3,
+        });
+        let secs = Duration::from_secs_f64;
+        // A burst at once, then one a second.
+        let mut tokens = None;
+        for _ in 0..3 {
+            tokens = Some(bucket.take(tokens, Duration::ZERO).unwrap());
+        }
+        assert_eq!(tokens, Some(0.0));
+        assert_eq!(bucket.take(tokens, secs(0.25)), Err(secs(0.75)));
+        assert_eq!(bucket.take(tokens, secs(1.0)), Ok(0.0));
+        // Never more than the burst.
+        assert_eq!(bucket.take(tokens, secs(3600.0)), Ok(2.0));
+        assert_eq!(bucket.ttl(), 4);
+    }
+
+    #[test]
+    fn message() {
+        let window = Window {
+            period: Duration::from_secs(60),
+            max_requests: 10,
+        };
+        let rejection = Rejection::exceeded(window, Duration::ZERO);
+        assert_eq!(
+            rejection.message(),
+            "Rate limit of 10 requests per 60s exceeded, per \
+            rate_limit.requests_per_minute."
+        );
+    }
+
+    #[test]
+    fn concurrency() {
+        let concurrency = Concurrency::default();
+        let a1 = concurrency.try_acquire("a", 2).unwrap();
+        let _a2 = concurrency.try_acquire("a", 2).unwrap();
+        assert!(concurrency.try_acquire("a", 2).is_none());
+        let _b1 = concurrency.try_acquire("b", 2).unwrap();
+        drop(a1);
+        assert!(concurrency.try_acquire("a", 2).is_some());
+    }
+
+    #[test]
+    fn per_ip() {
+        let windows = [Window {
+            period: Duration::from_secs(60),
+            max_requests: 2,
+        }];
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let per_ip = PerIp::default();
+        let t = |secs| Duration::from_secs(secs);
+        assert!(per_ip.acquire(a, &windows, t(600)).is_none());
+        assert!(per_ip.acquire(a, &windows, t(610)).is_none());
+        assert!(per_ip.acquire(a, &windows, t(620)).is_some());
+        assert!(per_ip.acquire(b, &windows, t(620)).is_none());
+        // Half of the previous window still counts.
+        assert!(per_ip.acquire(a, &windows, t(690)).is_none());
+        assert!(per_ip.acquire(a, &windows, t(690)).is_some());
+        assert!(per_ip.acquire(a, &windows, t(900)).is_none());
+    }
+}
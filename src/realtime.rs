@@ -0,0 +1,138 @@
+//! Realtime (speech) sessions, relayed between the client's WebSocket and
+//! upstream's as is. We can't see what they use, since the audio isn't
+//! tokenized here, so they are charged by how long they last, drawing on the
+//! budget as they go on, and closed once it runs out.
+
+use std::{future::Future, time::Duration};
+
+use axum::extract::ws::{self, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite;
+
+use crate::{conf, upstream::RealtimeSocket};
+
+/// Relays messages both ways until either side closes, the session reaches
+/// its max duration, or the budget runs out. Every period, `draw` is to
+/// reserve the next one, up to the max, and tell whether it could. Returns
+/// how long it lasted.
+pub async fn relay<F, Fut>(
+    mut client: WebSocket,
+    mut upstream: RealtimeSocket,
+    max: Duration,
+    period: Duration,
+    mut draw: F,
+) -> Duration
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let started = Instant::now();
+    let deadline = tokio::time::sleep(max);
+    tokio::pin!(deadline);
+    let mut draws = tokio::time::interval_at(started + period, period);
+    loop {
+        tokio::select! {
+            msg = client.recv() => {
+                let Some(Ok(msg)) = msg else { break };
+                let is_close = matches!(msg, ws::Message::Close(_));
+                let is_sent = upstream.send(to_upstream(msg)).await.is_ok();
+                if !is_sent || is_close {
+                    break;
+                }
+            }
+            msg = upstream.next() => {
+                let Some(Ok(msg)) = msg else { break };
+                let Some(msg) = to_client(msg) else { continue };
+                let is_close = matches!(msg, ws::Message::Close(_));
+                let is_sent = client.send(msg).await.is_ok();
+                if !is_sent || is_close {
+                    break;
+                }
+            }
+            () = &mut deadline => {
+                tracing::warn!(?max, "Realtime session too long. Closing.");
+                break;
+            }
+            _ = draws.tick() => {
+                let left = max.saturating_sub(started.elapsed());
+                if left.is_zero() || draw(left.min(period)).await {
+                    continue;
+                }
+                tracing::warn!("Budget exceeded. Closing realtime session.");
+                let frame = ws::CloseFrame {
+                    code: ws::close_code::POLICY,
+                    reason: "Budget exceeded.".into(),
+                };
+                let _ = client.send(ws::Message::Close(Some(frame))).await;
+                break;
+            }
+        }
+    }
+    // Either may be closed already.
+    let _ = upstream.close(None).await;
+    let _ = client.close().await;
+    started.elapsed()
+}
+
+/// Charged against the token budgets for the session.
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn tokens(conf: &conf::Realtime, duration: Duration) -> usize {
+    // Rounded up and clamped to be non-negative.
+    (duration.as_secs_f64() * conf.tokens_per_second)
+        .ceil()
+        .max(0.0) as usize
+}
+
+fn to_upstream(msg: ws::Message) -> tungstenite::Message {
+    match msg {
+        ws::Message::Text(text) => tungstenite::Message::Text(text),
+        ws::Message::Binary(data) => tungstenite::Message::Binary(data),
+        ws::Message::Ping(data) => tungstenite::Message::Ping(data),
+        ws::Message::Pong(data) => tungstenite::Message::Pong(data),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|frame| {
+                tungstenite::protocol::CloseFrame {
+                    code: frame.code.into(),
+                    reason: frame.reason,
+                }
+            }))
+        }
+    }
+}
+
+/// Raw frames are not received, only sent, so there's nothing to relay.
+fn to_client(msg: tungstenite::Message) -> Option<ws::Message> {
+    let msg = match msg {
+        tungstenite::Message::Text(text) => ws::Message::Text(text),
+        tungstenite::Message::Binary(data) => ws::Message::Binary(data),
+        tungstenite::Message::Ping(data) => ws::Message::Ping(data),
+        tungstenite::Message::Pong(data) => ws::Message::Pong(data),
+        tungstenite::Message::Close(frame) => {
+            ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason,
+            }))
+        }
+        tungstenite::Message::Frame(_) => return None,
+    };
+    Some(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::conf;
+
+    #[test]
+    fn tokens() {
+        let conf = conf::Realtime {
+            tokens_per_second: 10.0,
+            ..conf::Realtime::default()
+        };
+        assert_eq!(super::tokens(&conf, Duration::from_millis(1_050)), 11);
+        assert_eq!(super::tokens(&conf, Duration::ZERO), 0);
+    }
+}
@@ -0,0 +1,95 @@
+//! Scrubbing of personal data and secrets from what we log.
+
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::conf;
+
+const REPLACEMENT: &str = "[REDACTED]";
+
+static GLOBAL: conf::Derived<Redactor> =
+    conf::Derived::new(|conf| Redactor::new(&conf.redaction));
+
+/// Redacts with the global conf's patterns.
+#[must_use]
+pub fn redact(s: &str) -> Cow<'_, str> {
+    GLOBAL.get().redact(s)
+}
+
+/// Whether error messages are redacted too, not only logged content.
+#[must_use]
+pub fn is_strict() -> bool {
+    GLOBAL.get().is_strict
+}
+
+/// Of an error message, as logged: redacted too when strict.
+#[must_use]
+pub fn error_message(message: &str) -> String {
+    if is_strict() {
+        redact(message).into_owned()
+    } else {
+        message.to_string()
+    }
+}
+
+pub struct Redactor {
+    patterns: Vec<Regex>,
+    is_strict: bool,
+}
+
+impl Redactor {
+    /// Invalid patterns are skipped, with an error logged, rather than
+    /// preventing startup.
+    #[must_use]
+    pub fn new(conf: &conf::Redaction) -> Self {
+        let patterns = conf
+            .patterns
+            .iter()
+            .filter_map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|error| {
+                        tracing::error!(
+                            ?error,
+                            pattern,
+                            "Invalid redaction pattern. Skipping."
+                        );
+                    })
+                    .ok()
+            })
+            .collect();
+        Self {
+            patterns,
+            is_strict: conf.strict,
+        }
+    }
+
+    #[must_use]
+    pub fn redact<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        let mut s = Cow::Borrowed(s);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&s, REPLACEMENT)
+            {
+                s = Cow::Owned(redacted);
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redactor;
+    use crate::conf;
+
+    #[test]
+    fn defaults() {
+        let redactor = Redactor::new(&conf::Redaction::default());
+        let s = "Mail bob@example.com or call +1 (555) 123-4567, \
+            key gsk_abcdefghijklmnopqrstuvwxyz, 42 apples.";
+        assert_eq!(
+            redactor.redact(s),
+            "Mail [REDACTED] or call [REDACTED], key [REDACTED], 42 apples."
+        );
+    }
+}
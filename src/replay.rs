@@ -0,0 +1,108 @@
+//! Replaying captured chat requests, per [`crate::conf::Capture`], through
+//! the forwarding path as configured now, for debugging regressions of
+//! providers. Replays are made as whoever replays them, so as not to count
+//! against the original user, and logged, and captured, under request IDs
+//! starting with [`REQ_ID_PREFIX`]. They are limited as the requests of
+//! whoever replays them are, by rate and concurrency.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+use crate::server::{self, AppState, ReqId, User, REQ_ID, USER};
+
+pub const REQ_ID_PREFIX: &str = "replay-";
+
+/// Of captures whose request logs were pruned.
+const DEFAULT_ENDPOINT: &str = "v1/chat/completions";
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Replayed {
+    /// Of the replay, which it is logged under.
+    pub req_id: String,
+    pub endpoint: String,
+    pub status: u16,
+    pub response: String,
+
+    /// Of the original, when still logged.
+    pub original_status: Option<i64>,
+
+    /// Of the original, unless upstream failed.
+    pub original_response: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unreplayable {
+    NotCaptured,
+
+    /// Cut short when captured, so not the request that was made.
+    Truncated,
+}
+
+/// As the user, of the request with the given ID.
+pub(crate) async fn replay(
+    state: &AppState,
+    user: User,
+    req_id: &str,
+) -> anyhow::Result<Result<Replayed, Unreplayable>> {
+    let Some(capture) = state.storage.capture_get(req_id).await? else {
+        return Ok(Err(Unreplayable::NotCaptured));
+    };
+    // Which may be only the response's doing.
+    if capture.is_truncated
+        && serde_json::from_str::<serde_json::Value>(&capture.request)
+            .is_err()
+    {
+        return Ok(Err(Unreplayable::Truncated));
+    }
+    let original = state.storage.request_log(req_id).await?;
+    let endpoint = original
+        .as_ref()
+        .map_or(DEFAULT_ENDPOINT, |log| log.endpoint.as_str())
+        .to_string();
+    let replay_id = ReqId {
+        req_id: format!("{REQ_ID_PREFIX}{req_id}-{}", cuid2::create_id()),
+        client_ip: None,
+    };
+    let replay_req_id = replay_id.req_id.clone();
+    tracing::info!(
+        req_id,
+        replay_req_id,
+        endpoint,
+        uid = user.uid,
+        "Replaying request."
+    );
+    let req = axum::http::Request::post(format!("/{endpoint}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(capture.request))?;
+    let handling = async {
+        server::rate_limit_check(state.storage.as_ref(), &user).await?;
+        // Held until the response is read, as by the concurrency layer.
+        let permit = server::concurrency_acquire(&state.concurrency, &user)?;
+        let handled = server::handle_api(
+            State(state.clone()),
+            ConnectInfo(server::LOCALHOST),
+            Path(endpoint.clone()),
+            req,
+        );
+        let resp = USER.scope(user.clone(), handled).await;
+        Ok::<_, Response>((resp.into_response(), permit))
+    };
+    let (resp, _permit) = match REQ_ID.scope(replay_id, handling).await {
+        Ok(handled) => handled,
+        Err(resp) => (resp, None),
+    };
+    let status = resp.status().as_u16();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+    Ok(Ok(Replayed {
+        req_id: replay_req_id,
+        endpoint,
+        status,
+        response: String::from_utf8_lossy(&body).into_owned(),
+        original_status: original.map(|log| log.status),
+        original_response: capture.response,
+    }))
+}
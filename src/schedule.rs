@@ -0,0 +1,204 @@
+//! Times of day at which roles may be used, per [`conf::Access`], so that
+//! the shared key isn't burned overnight by scripts left running.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context};
+use chrono::{
+    DateTime, NaiveDate, NaiveTime, TimeDelta, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::conf;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Of the roles with access windows in the global conf, parsed once. Those
+/// invalid are refused at start, per [`conf::Conf::errors`].
+static GLOBAL: conf::Derived<BTreeMap<String, anyhow::Result<Schedule>>> =
+    conf::Derived::new(|conf| {
+        conf.roles
+            .iter()
+            .filter_map(|(name, role)| {
+                let access = role.access.as_ref()?;
+                Some((name.clone(), Schedule::new(access)))
+            })
+            .collect()
+    });
+
+/// Of the role, per the global conf, unless it may be used at any time.
+pub fn of(role: &str) -> Option<&'static anyhow::Result<Schedule>> {
+    GLOBAL.get().get(role)
+}
+
+#[derive(Debug)]
+pub struct Schedule {
+    timezone: Tz,
+    windows: Vec<Window>,
+}
+
+/// In minutes since local midnight. Spans midnight when it ends before it
+/// starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    start: u32,
+    end: u32,
+}
+
+/// When the role may be used next.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Next {
+    /// RFC 3339, in the schedule's timezone.
+    pub from: String,
+    pub until: String,
+}
+
+impl Schedule {
+    pub fn new(conf: &conf::Access) -> anyhow::Result<Self> {
+        let timezone: Tz = conf.timezone.parse().map_err(|error| {
+            anyhow!("Invalid timezone: {:?}: {error}", conf.timezone)
+        })?;
+        let windows = conf
+            .windows
+            .iter()
+            .map(|window| {
+                Window::parse(window)
+                    .context(format!("Invalid window: {window:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { timezone, windows })
+    }
+
+    #[must_use]
+    pub fn is_open(&self, time: DateTime<Utc>) -> bool {
+        let local = time.with_timezone(&self.timezone);
+        let minute = local.hour() * 60 + local.minute();
+        self.windows.iter().any(|window| window.contains(minute))
+    }
+
+    /// Of the windows starting after the time, the first. None if there are
+    /// none.
+    #[must_use]
+    pub fn next(&self, time: DateTime<Utc>) -> Option<Next> {
+        let today = time.with_timezone(&self.timezone).date_naive();
+        let mut starts: Vec<(DateTime<Tz>, &Window)> = Vec::new();
+        for days in 0..=1 {
+            let date = today + TimeDelta::days(days);
+            for window in &self.windows {
+                let Some(start) = self.local(date, window.start) else {
+                    continue;
+                };
+                if start > time {
+                    starts.push((start, window));
+                }
+            }
+        }
+        let (start, window) = starts.into_iter().min_by_key(|(at, _)| *at)?;
+        let until = start + TimeDelta::minutes(i64::from(window.len()));
+        Some(Next {
+            from: start.to_rfc3339(),
+            until: until.to_rfc3339(),
+        })
+    }
+
+    /// Skipping ahead past clocks set forward.
+    fn local(&self, date: NaiveDate, minute: u32) -> Option<DateTime<Tz>> {
+        let midnight = date.and_time(NaiveTime::MIN);
+        (0..=2).find_map(|hours| {
+            let at = midnight
+                + TimeDelta::minutes(i64::from(minute))
+                + TimeDelta::hours(hours);
+            self.timezone.from_local_datetime(&at).earliest()
+        })
+    }
+}
+
+impl Window {
+    /// "HH:MM-HH:MM", where the end may be "24:00".
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let (start, end) =
+            s.split_once('-').context("Expected HH:MM-HH:MM.")?;
+        let start = minute_of_day(start.trim())?;
+        let end = match end.trim() {
+            "24:00" => MINUTES_PER_DAY,
+            end => minute_of_day(end)?,
+        };
+        if start == end {
+            return Err(anyhow!("Empty. Use 00:00-24:00 for all day."));
+        }
+        Ok(Self { start, end })
+    }
+
+    fn contains(self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    fn len(self) -> u32 {
+        if self.start < self.end {
+            self.end - self.start
+        } else {
+            self.end + MINUTES_PER_DAY - self.start
+        }
+    }
+}
+
+fn minute_of_day(s: &str) -> anyhow::Result<u32> {
+    let time = NaiveTime::parse_from_str(s, "%H:%M")?;
+    Ok(time.hour() * 60 + time.minute())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{Next, Schedule, Window};
+    use crate::conf;
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            Window::parse("08:00-24:00").unwrap(),
+            Window {
+                start: 480,
+                end: 1440
+            }
+        );
+        assert!(Window::parse("09:00-09:00").is_err());
+        assert!(Window::parse("9am-5pm").is_err());
+        let overnight = Window::parse("22:00-06:00").unwrap();
+        assert!(overnight.contains(23 * 60));
+        assert!(overnight.contains(0));
+        assert!(!overnight.contains(6 * 60));
+        assert_eq!(overnight.len(), 8 * 60);
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = Schedule::new(&conf::Access {
+            timezone: "Europe/Berlin".to_string(),
+            windows: vec!["08:00-24:00".to_string()],
+        })
+        .unwrap();
+        assert!(schedule.is_open(at("2025-06-01T12:00:00+02:00")));
+        assert!(!schedule.is_open(at("2025-06-01T03:00:00+02:00")));
+        assert_eq!(
+            schedule.next(at("2025-06-01T03:00:00+02:00")),
+            Some(Next {
+                from: "2025-06-01T08:00:00+02:00".to_string(),
+                until: "2025-06-02T00:00:00+02:00".to_string(),
+            })
+        );
+        assert_eq!(
+            schedule.next(at("2025-06-01T12:00:00+02:00")).unwrap().from,
+            "2025-06-02T08:00:00+02:00"
+        );
+    }
+}
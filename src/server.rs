@@ -1,66 +1,758 @@
-use std::{env, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Context};
 use axum::{
-    extract::{ConnectInfo, Path, Request},
+    body::Body,
+    extract::{
+        ws::WebSocketUpgrade, ConnectInfo, FromRequest, OriginalUri, Path,
+        Query, Request, State,
+    },
     http::{header, StatusCode},
     middleware::{self, Next},
-    response::{Response, Result},
+    response::{IntoResponse, Response, Result},
     routing::get,
-    Json,
+    Extension, Json,
 };
 
+use futures_util::{future, FutureExt, StreamExt};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tower_http::compression::CompressionLayer;
+
 use crate::{
-    auth, chat,
+    abuse, access, admin, attest, audio, auth, batch, chat, compress,
     conf::{self, Conf},
-    data::Storage,
+    data::{self, Storage},
+    deprecation, docs, duplicates, endpoint,
+    events::{self, Event},
+    files, filter, headers, hook, jobs, jwt, lint, listener, media, mock,
+    moderation, overage, peers, period, preferences, prober, provenance,
+    public, push, queue, ratelimit, realtime, redact, replay, schedule,
+    signing, signup, slow, streaming, stripe, template, tokenizer,
+    upstream::{self, Upstream},
+    version, ws,
 };
 
+/// How long until a JWT revocation takes effect.
+const REVOCATION_CACHE_TTL: Duration = Duration::from_secs(10);
+
+pub(crate) const REQ_ID_HEADER: &str = "x-request-id";
+
+/// Of requests made here rather than by a client, such as of batches.
+pub(crate) const LOCALHOST: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+/// Tags the request with the conversation it's part of, for analytics.
+/// Otherwise taken from `metadata.session` of a chat request.
+const SESSION_HEADER: &str = "x-raskol-session";
+
+const SESSION_MAX_LEN: usize = 255;
+
+/// Seconds the client is willing to wait for upstream. Only shortens the
+/// configured timeouts.
+const TIMEOUT_HEADER: &str = "x-raskol-timeout";
+
+/// "true" for a synthetic completion, echoing the last message, instead of
+/// upstream's, for roles which allow it. Of chat only.
+const DRY_RUN_HEADER: &str = "x-raskol-dry-run";
+
+/// Logged for requests the client gave up on before we responded, as nginx
+/// does.
+const CLIENT_CLOSED_REQUEST: i64 = 499;
+
+/// Of a day, in seconds.
+const DAY: i64 = 24 * 60 * 60;
+
+/// How far behind a watcher of the live events may fall before missing some.
+const EVENTS_CAPACITY: usize = 1024;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often request logs are rolled up into hourly usage. Stats stay exact
+/// in between, since they read the not yet rolled up logs directly.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const CALIBRATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const ABUSE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How soon after a budget period ends the credits of its users are
+/// settled. Until then, they still have the credits they used in it.
+const CREDITS_CLOSE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How soon reservations set by admins take effect on other instances than
+/// the one they were set on.
+const RESERVED_RPM_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Of the events of a stream, held for a client slower than upstream.
+const STREAM_BUFFER: usize = 32;
+
+/// How long requests in flight, such as streams, are waited for on shutdown.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// How often database maintenance checks whether the server went idle.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Groups of routes, to mount only some of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routes {
+    /// Ping, metrics, health, the dashboard and the OpenAPI docs, which
+    /// need no auth, and signups.
+    Public,
+
+    /// Under `/v1/admin`.
+    Admin,
+
+    /// Usage stats, model listings, templates, preferences and batches.
+    Stats,
+
+    /// Forwarded upstream: chat and other endpoints, WebSockets and media.
+    Api,
+}
+
+impl Routes {
+    pub const ALL: [Self; 4] =
+        [Self::Public, Self::Admin, Self::Stats, Self::Api];
+}
+
+/// Of [`Routes::Public`], less signups.
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(
+    handle_ping,
+    handle_metrics,
+    handle_health,
+    handle_health_ready,
+    handle_dashboard
+))]
+pub(crate) struct PublicDocs;
+
+/// Of [`Routes::Stats`], less templates, preferences, batches and model
+/// listings, as under the version prefix.
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(
+    handle_stats,
+    handle_stats_timeseries,
+    handle_stats_cost,
+    handle_stats_latency,
+    handle_stats_errors,
+    handle_stats_tags,
+    handle_quota,
+    handle_client_config,
+    handle_validate,
+    handle_sessions,
+    handle_session,
+    handle_total_stats,
+    handle_org,
+    handle_org_stats,
+    handle_leaderboard
+))]
+pub(crate) struct StatsDocs;
+
+/// Of [`Routes::Stats`], at upstream's paths rather than ours.
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_models, handle_provider_models))]
+pub(crate) struct ModelsDocs;
+
+/// Of [`Routes::Api`], at upstream's paths.
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(handle_api, handle_realtime, handle_speech, handle_images),
+    modifiers(&ProviderPrefixed)
+)]
+pub(crate) struct ApiDocs;
+
+/// Of [`Routes::Api`], less WebSockets, as under the version prefix.
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_template_complete))]
+pub(crate) struct TemplateDocs;
+
+/// Chat at upstream's own path too, such as Groq's, which clients pointed
+/// at us rather than at it keep using.
+struct ProviderPrefixed;
+
+impl utoipa::Modify for ProviderPrefixed {
+    fn modify(&self, doc: &mut utoipa::openapi::OpenApi) {
+        let Some(mut item) =
+            doc.paths.paths.get("/v1/chat/completions").cloned()
+        else {
+            return;
+        };
+        if let Some(operation) = &mut item.post {
+            operation.operation_id =
+                Some("chat_completions_openai".to_string());
+        }
+        doc.paths
+            .paths
+            .insert("/openai/v1/chat/completions".to_string(), item);
+    }
+}
+
+/// Builds the router, for mounting in another axum app, or for driving in
+/// tests, without the binary. Which is this, with the conf file and the
+/// database it names, listening as it says.
+///
+/// Each has its own conf, which its requests and tasks see, per
+/// [`conf::scope`]. What runs outside of any, such as the CLI, sees the
+/// first one's, unless one is set, per [`conf::set`]. The router needs
+/// [`ConnectInfo`], as from
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+pub struct Server {
+    conf: Option<Conf>,
+    storage: Option<Arc<dyn Storage>>,
+    metrics: Option<PrometheusHandle>,
+    routes: Vec<Routes>,
+    is_background: bool,
+    hooks: Vec<Arc<dyn hook::Hook>>,
+}
+
+impl Default for Server {
+    /// With the conf file.
+    fn default() -> Self {
+        Self {
+            conf: None,
+            storage: None,
+            metrics: None,
+            routes: Routes::ALL.to_vec(),
+            is_background: true,
+            hooks: Vec::new(),
+        }
+    }
+}
+
+impl Server {
+    #[must_use]
+    pub fn new(conf: Conf) -> Self {
+        Self {
+            conf: Some(conf),
+            ..Self::default()
+        }
+    }
+
+    /// Instead of connecting to the one in the conf.
+    #[must_use]
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// For when the app has its own recorder installed. Otherwise ours is,
+    /// if none is yet.
+    #[must_use]
+    pub fn metrics(mut self, metrics: PrometheusHandle) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// All by default.
+    #[must_use]
+    pub fn routes(mut self, routes: &[Routes]) -> Self {
+        self.routes = routes.to_vec();
+        self
+    }
+
+    /// Whether to spawn the periodic tasks, such as pruning and rollups.
+    /// On by default. Tests may not want them.
+    #[must_use]
+    pub fn background(mut self, is_background: bool) -> Self {
+        self.is_background = is_background;
+        self
+    }
+
+    /// Run after the built-in ones turned on in conf, in the order added.
+    #[must_use]
+    pub fn hook(mut self, hook: Arc<dyn hook::Hook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub async fn router(self) -> anyhow::Result<axum::Router> {
+        let (router, _) = self.build().await?;
+        Ok(router)
+    }
+
+    /// Of a captured request, per [`replay`], as the given user, against
+    /// the current conf.
+    pub async fn replay(
+        self,
+        req_id: &str,
+        uid: &str,
+        role: &str,
+    ) -> anyhow::Result<replay::Replayed> {
+        let (_, state) = self.background(false).build().await?;
+        let conf = state.conf.clone();
+        let user = User {
+            uid: uid.to_string(),
+            role: role.to_string(),
+            org: None,
+            budget_overrides: data::BudgetOverrides::default(),
+            features: Vec::new(),
+        };
+        match conf::scope(conf, replay::replay(&state, user, req_id)).await? {
+            Ok(replayed) => Ok(replayed),
+            Err(replay::Unreplayable::NotCaptured) => {
+                Err(anyhow!("Request {req_id:?} was not captured."))
+            }
+            Err(replay::Unreplayable::Truncated) => Err(anyhow!(
+                "Request {req_id:?} was truncated when captured."
+            )),
+        }
+    }
+
+    async fn build(mut self) -> anyhow::Result<(axum::Router, AppState)> {
+        let conf = self.conf.take().map_or_else(conf::global, Arc::new);
+        conf::set_default(&conf);
+        conf::scope(conf, self.build_scoped()).await
+    }
+
+    /// Of the conf of [`Self::build`], in scope.
+    async fn build_scoped(self) -> anyhow::Result<(axum::Router, AppState)> {
+        let Self {
+            conf: _,
+            storage,
+            metrics,
+            routes,
+            is_background,
+            hooks: custom,
+        } = self;
+        if routes.is_empty() {
+            return Err(anyhow!("No routes to mount."));
+        }
+        let conf = conf::global();
+        let errors = conf.errors();
+        if !errors.is_empty() {
+            return Err(anyhow!("Invalid conf: {}", errors.join(" ")));
+        }
+        // Loading them can take a while, so not on the first request.
+        let _ = tokenizer::global();
+        let metrics = match metrics {
+            Some(metrics) => metrics,
+            None => PrometheusBuilder::new()
+                .install_recorder()
+                .unwrap_or_else(|error| {
+                    tracing::warn!(?error, "Metrics recorder not ours.");
+                    PrometheusBuilder::new().build_recorder().handle()
+                }),
+        };
+        let storage = match storage {
+            Some(storage) => storage,
+            None => data::connect().await?,
+        };
+        let mut hooks = hook::Hooks::from_conf(&conf.hooks)
+            .context("Failed to set up hooks.")?;
+        for hook in custom {
+            hooks.push(hook);
+        }
+        let upstream = Upstream::new(&conf);
+        if let Some(spend) = upstream.spend() {
+            spend
+                .load(storage.as_ref())
+                .await
+                .context("Failed to load spend.")?;
+        }
+        let state = AppState {
+            conf: conf.clone(),
+            metrics,
+            storage,
+            concurrency: Arc::new(ratelimit::Concurrency::default()),
+            per_ip: Arc::new(ratelimit::PerIp::default()),
+            duplicates: Arc::new(duplicates::Duplicates::default()),
+            events: events::Events::new(EVENTS_CAPACITY),
+            revocations: Arc::new(auth::Revocations::new(
+                REVOCATION_CACHE_TTL,
+            )),
+            http: conf
+                .http
+                .client(&conf.compression)
+                .context("Failed to build HTTP client.")?,
+            upstream: Arc::new(upstream),
+            queue: conf
+                .queue
+                .as_ref()
+                .map(|q| Arc::new(queue::Queue::new(q))),
+            activity: Arc::new(Activity::default()),
+            hooks: Arc::new(hooks),
+            access: conf
+                .log
+                .access
+                .as_ref()
+                .map(access::Log::open)
+                .transpose()?
+                .map(Arc::new),
+        };
+        if is_background {
+            spawn_background(&conf, &state);
+        }
+        spawn_per_instance(&conf, &state);
+        let mut router = axum::Router::new();
+        // Ours, mounted under the version prefix, and as legacy aliases.
+        let mut versioned = axum::Router::new();
+        if routes.contains(&Routes::Public) {
+            router = router.merge(
+                axum::Router::new()
+                    .route("/ping", get(handle_ping))
+                    .route("/metrics", get(handle_metrics))
+                    .route("/health", get(handle_health))
+                    .route("/health/ready", get(handle_health_ready))
+                    .route("/dashboard", get(handle_dashboard))
+                    .merge(docs::routes(&conf, &routes)?)
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        per_ip_rate_limit_layer,
+                    )),
+            );
+            if conf.signup.is_some() {
+                versioned = versioned.merge(signup::routes().route_layer(
+                    middleware::from_fn_with_state(
+                        state.clone(),
+                        per_ip_rate_limit_layer,
+                    ),
+                ));
+            }
+            if conf.prober.is_some() {
+                router = router.merge(prober::routes().route_layer(
+                    middleware::from_fn_with_state(
+                        state.clone(),
+                        per_ip_rate_limit_layer,
+                    ),
+                ));
+            }
+            if conf.public_stats.is_some() {
+                router = router.merge(public::routes().route_layer(
+                    middleware::from_fn_with_state(
+                        state.clone(),
+                        per_ip_rate_limit_layer,
+                    ),
+                ));
+            }
+        }
+        if routes.contains(&Routes::Admin) {
+            versioned = versioned.nest(
+                "/admin",
+                admin::routes()
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        deprecation::layer,
+                    ))
+                    .route_layer(middleware::from_fn(role_layer))
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        auth_layer,
+                    )),
+            );
+        }
+        if routes.contains(&Routes::Stats) {
+            versioned = versioned.merge(
+                axum::Router::new()
+                    .route("/stats", get(handle_stats))
+                    .route("/stats/timeseries", get(handle_stats_timeseries))
+                    .route("/stats/cost", get(handle_stats_cost))
+                    .route("/stats/latency", get(handle_stats_latency))
+                    .route("/stats/errors", get(handle_stats_errors))
+                    .route("/stats/tags", get(handle_stats_tags))
+                    .route("/quota", get(handle_quota))
+                    .route("/client-config", get(handle_client_config))
+                    .route("/validate", axum::routing::post(handle_validate))
+                    .route("/sessions", get(handle_sessions))
+                    .route("/sessions/:session", get(handle_session))
+                    .route("/total-stats", get(handle_total_stats))
+                    .route("/orgs/:org", get(handle_org))
+                    .route("/orgs/:org/stats", get(handle_org_stats))
+                    .route("/leaderboard", get(handle_leaderboard))
+                    .merge(template::routes())
+                    .merge(preferences::routes())
+                    .merge(batch::routes())
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        deprecation::layer,
+                    ))
+                    .route_layer(middleware::from_fn(role_layer))
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        auth_layer,
+                    )),
+            );
+            router = router.merge(
+                axum::Router::new()
+                    .route("/v1/models", get(handle_models))
+                    .route(
+                        "/api/:provider/models",
+                        get(handle_provider_models),
+                    )
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        deprecation::layer,
+                    ))
+                    .route_layer(middleware::from_fn(role_layer))
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        auth_layer,
+                    )),
+            );
+        }
+        if routes.contains(&Routes::Api) {
+            versioned = versioned.merge(api_layered(
+                axum::Router::new()
+                    .route(
+                        "/templates/:name/complete",
+                        axum::routing::post(handle_template_complete),
+                    )
+                    .merge(ws::routes()),
+                &state,
+            ));
+            router = router.nest(
+                "/",
+                api_layered(
+                    axum::Router::new()
+                        .route("/*endpoint", axum::routing::post(handle_api))
+                        .route("/v1/realtime", get(handle_realtime))
+                        .route(
+                            &format!("/{}", media::SPEECH_ENDPOINT),
+                            axum::routing::post(handle_speech),
+                        )
+                        .route(
+                            &format!("/{}", media::IMAGES_ENDPOINT),
+                            axum::routing::post(handle_images),
+                        ),
+                    &state,
+                ),
+            );
+        }
+        router = router.nest(
+            version::PREFIX,
+            versioned.clone().layer(Extension(version::Versioned)),
+        );
+        // A route layer needs routes.
+        if conf.legacy_routes && versioned.has_routes() {
+            router = router.merge(
+                versioned
+                    .route_layer(middleware::from_fn(version::legacy_layer)),
+            );
+        }
+        let router = router
+            .route_layer(middleware::from_fn(req_id_layer))
+            // Not a route layer, so that unknown routes are filtered too.
+            .layer(middleware::from_fn(ip_filter_layer))
+            .layer(middleware::from_fn(version::negotiate_layer))
+            .layer(middleware::from_fn(error_body_layer));
+        let router = if conf.compression.responses {
+            router.layer(CompressionLayer::new())
+        } else {
+            router
+        };
+        // Outermost, for all to see the client, and for the access log to
+        // see responses as sent. All of the server's conf.
+        let router = router
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                access::layer,
+            ))
+            .layer(middleware::from_fn(client_ip_layer))
+            .layer(middleware::from_fn_with_state(state.clone(), conf_layer));
+        Ok((router.with_state(state.clone()), state))
+    }
+}
+
+/// Those of [`Routes::Api`], behind the hooks, auth and the user's limits.
+fn api_layered(
+    router: axum::Router<AppState>,
+    state: &AppState,
+) -> axum::Router<AppState> {
+    router
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            deprecation::layer,
+        ))
+        .route_layer(middleware::from_fn(slow::layer))
+        .route_layer(middleware::from_fn(forwarded_headers_layer))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            activity_layer,
+        ))
+        // XXX Layers run bottom-up, so auth runs before the limits.
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            concurrency_layer,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit_layer,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            peers::layer,
+        ))
+        .route_layer(middleware::from_fn(role_layer))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_layer,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            pre_auth_layer,
+        ))
+}
+
+/// Of every instance, since each paces its own requests, and queues its
+/// own writes.
+fn spawn_per_instance(conf: &Conf, state: &AppState) {
+    if let (
+        Some(queue),
+        Some(conf::Queue {
+            upstream_rpm: Some(_),
+            ..
+        }),
+    ) = (&state.queue, &conf.queue)
+    {
+        let (storage, queue) = (state.storage.clone(), queue.clone());
+        jobs::spawn("reserve", RESERVED_RPM_INTERVAL, move || {
+            reserve(storage.clone(), queue.clone())
+        });
+    }
+    if let Some(write_behind) = &conf.write_behind {
+        let storage = state.storage.clone();
+        let interval = Duration::from_millis(write_behind.interval_ms);
+        jobs::spawn("flush", interval, move || {
+            let storage = storage.clone();
+            async move {
+                storage
+                    .flush()
+                    .await
+                    .context("Failed to flush queued writes.")
+            }
+        });
+    }
+}
+
+fn spawn_background(conf: &Conf, state: &AppState) {
+    if let Some(retention_days) = conf.retention_days {
+        let storage = state.storage.clone();
+        jobs::spawn("prune", PRUNE_INTERVAL, move || {
+            prune(storage.clone(), retention_days)
+        });
+    }
+    let storage = state.storage.clone();
+    jobs::spawn("rollup", ROLLUP_INTERVAL, move || rollup(storage.clone()));
+    let storage = state.storage.clone();
+    jobs::spawn("expire", EXPIRY_INTERVAL, move || expire(storage.clone()));
+    let storage = state.storage.clone();
+    jobs::spawn("calibrate", CALIBRATION_INTERVAL, move || {
+        calibrate(storage.clone())
+    });
+    let storage = state.storage.clone();
+    jobs::spawn("close_credits", CREDITS_CLOSE_INTERVAL, move || {
+        close_credits(storage.clone())
+    });
+    batch::spawn(state.clone());
+    if let conf::Storage::Sqlite { .. } = conf.storage {
+        let (storage, activity) =
+            (state.storage.clone(), state.activity.clone());
+        let maintenance = conf.maintenance.clone();
+        let interval = Duration::from_secs(maintenance.interval);
+        // Not right at startup.
+        jobs::spawn_later("maintain", interval, move || {
+            maintain(storage.clone(), activity.clone(), maintenance.clone())
+        });
+    }
+    if let (Some(peers), Some(peers_conf)) = (peers::global(), &conf.peers) {
+        let http = state.http.clone();
+        let interval = Duration::from_secs(peers_conf.interval);
+        jobs::spawn("heartbeat", interval, move || {
+            let http = http.clone();
+            async move { peers.heartbeat(&http).await }
+        });
+    }
+    if let Some(abuse) = &conf.abuse {
+        let (storage, events) = (state.storage.clone(), state.events.clone());
+        let abuse = abuse.clone();
+        jobs::spawn("detect_abuse", ABUSE_INTERVAL, move || {
+            detect_abuse(storage.clone(), events.clone(), abuse.clone())
+        });
+    }
+    if let Some(stripe) = &conf.stripe {
+        stripe::spawn(
+            state.storage.clone(),
+            state.http.clone(),
+            stripe.clone(),
+        );
+    }
+    if let Some(push) = &conf.metrics_push {
+        push::spawn(state.metrics.clone(), state.http.clone(), push.clone());
+    }
+    if let Some(prober) = &conf.prober {
+        prober::spawn(
+            state.storage.clone(),
+            state.http.clone(),
+            state.upstream.clone(),
+            prober.clone(),
+        );
+    }
+}
+
 #[tracing::instrument(name = "server", skip_all)]
 pub async fn run() -> anyhow::Result<()> {
     let conf = conf::global();
     let dir = env::current_dir()?;
     tracing::info!(?dir, ?conf, "Starting.");
-    let addr = SocketAddr::from((conf.addr, conf.port));
-    let storage = Storage::connect().await?;
-    let routes = axum::Router::new()
-        .route("/ping", get(handle_ping))
-        .nest(
-            "/",
-            axum::Router::new()
-                .route(
-                    "/*endpoint",
-                    axum::routing::post({
-                        let storage = storage.clone();
-                        move |conn_info, endpoint, payload| {
-                            handle_api(storage, conn_info, endpoint, payload)
-                        }
-                    }),
-                )
-                .route_layer(middleware::from_fn({
-                    |req, next: Next| auth_layer(req, next)
-                })),
-        )
-        .route_layer(middleware::from_fn({
-            |req, next: Next| REQ_ID.scope(ReqId::new(), next.run(req))
-        }))
-        .into_make_service_with_connect_info::<SocketAddr>();
+    let (app, state) = Server::default().build().await?;
 
+    let shutdown = shutdown_signal().boxed().shared();
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = conf.grpc.clone() {
+        grpc.check()?;
+        let storage = state.storage.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(error) =
+                crate::grpc::serve(&grpc, storage, shutdown).await
+            {
+                tracing::error!(?error, "gRPC server failed.");
+            }
+        });
+    }
     match &conf.tls {
         None => {
-            let listener = tokio::net::TcpListener::bind(addr).await?;
-            tracing::warn!(?addr, "Listening unencrypted.");
-            axum::serve(listener, routes).await?;
+            let serving =
+                listener::Listener::bind_all(&conf)?.into_iter().map(
+                    |listener| listener.serve(app.clone(), shutdown.clone()),
+                );
+            tokio::select! {
+                result = future::try_join_all(serving) => {
+                    result?;
+                }
+                () = async {
+                    shutdown.await;
+                    tokio::time::sleep(SHUTDOWN_GRACE).await;
+                } => {
+                    tracing::warn!("Gave up waiting for requests in flight.");
+                }
+            }
         }
-        Some(conf::Tls {
-            cert_file,
-            key_file,
-        }) => {
+        Some(_) if conf.listen.is_set() => {
+            return Err(anyhow!(
+                "TLS is for serving directly, not on the sockets in listen."
+            ));
+        }
+        Some(tls) => {
             // XXX One MUST do this manual init of rustls provider when using
             //     more than a single dep which itself depends on rustls.
-            //     Here we using 2:
+            //     Here we using 3:
             //     - axum_server
             //     - reqwest
+            //     - rustls_acme
             rustls::crypto::aws_lc_rs::default_provider()
                 .install_default()
                 .map_err(|crypto_provider| {
@@ -69,7 +761,66 @@ pub async fn run() -> anyhow::Result<()> {
                         {crypto_provider:?}"
                     )
                 })?;
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+                }
+            });
+            let listeners = listener::bind_tcp(&conf)?;
+            let routes =
+                app.into_make_service_with_connect_info::<SocketAddr>();
+            serve_tls(listeners, tls, routes, handle).await?;
+        }
+    }
+
+    // What's queued is otherwise lost.
+    state.storage.flush().await?;
+    tracing::info!("Stopped.");
+    Ok(())
+}
 
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(
+            tokio::signal::unix::SignalKind::terminate(),
+        ) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(error) => {
+                tracing::error!(?error, "Failed to listen for SIGTERM.");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        () = terminate => {}
+    }
+    tracing::info!("Shutting down.");
+}
+
+async fn serve_tls(
+    listeners: Vec<std::net::TcpListener>,
+    tls: &conf::Tls,
+    routes: axum::extract::connect_info::IntoMakeServiceWithConnectInfo<
+        axum::Router,
+        SocketAddr,
+    >,
+    handle: axum_server::Handle,
+) -> anyhow::Result<()> {
+    match tls {
+        conf::Tls::Files {
+            cert_file,
+            key_file,
+        } => {
             let config =
                 axum_server::tls_rustls::RustlsConfig::from_pem_file(
                     cert_file, key_file,
@@ -80,200 +831,5342 @@ pub async fn run() -> anyhow::Result<()> {
                     cert_file={cert_file:?}, key_file={key_file:?}"
                 ))?;
 
-            tracing::info!(
-                ?addr,
-                ?cert_file,
-                ?key_file,
-                "Listening with TLS."
-            );
-            axum_server::bind_rustls(addr, config).serve(routes).await?;
+            let serving = listeners.into_iter().map(|listener| {
+                let addr = listener.local_addr().ok();
+                tracing::info!(
+                    ?addr,
+                    ?cert_file,
+                    ?key_file,
+                    "Listening with TLS."
+                );
+                axum_server::from_tcp_rustls(listener, config.clone())
+                    .handle(handle.clone())
+                    .serve(routes.clone())
+            });
+            future::try_join_all(serving).await?;
+        }
+        conf::Tls::Acme { acme } => {
+            let mut state = rustls_acme::AcmeConfig::new(&acme.domains)
+                .contact(
+                    acme.contact
+                        .iter()
+                        .map(|email| format!("mailto:{email}")),
+                )
+                .cache(rustls_acme::caches::DirCache::new(
+                    acme.cache_dir.clone(),
+                ))
+                .directory_lets_encrypt(!acme.staging)
+                .state();
+            let acceptor = state.axum_acceptor(state.default_rustls_config());
+            tokio::spawn(async move {
+                // Drives provisioning and renewals.
+                while let Some(event) = state.next().await {
+                    match event {
+                        Ok(event) => tracing::info!(?event, "ACME event."),
+                        Err(error) => {
+                            tracing::error!(?error, "ACME failure.");
+                        }
+                    }
+                }
+            });
+            let serving = listeners.into_iter().map(|listener| {
+                let addr = listener.local_addr().ok();
+                tracing::info!(?addr, ?acme, "Listening with TLS via ACME.");
+                axum_server::from_tcp(listener)
+                    .acceptor(acceptor.clone())
+                    .handle(handle.clone())
+                    .serve(routes.clone())
+            });
+            future::try_join_all(serving).await?;
         }
     }
-
     Ok(())
 }
 
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PingQuery {
+    /// Whether to measure round trips to the storage and the providers.
+    #[serde(default)]
+    latency: bool,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Latency {
+    storage: StorageHealth,
+    upstream: Vec<upstream::ProviderHealth>,
+}
+
+/// Empty, unless asked for latency, for clients to pick between instances
+/// and providers, and for us to spot providers degrading.
+#[utoipa::path(
+    get,
+    path = "/ping",
+    tag = "public",
+    security(()),
+    params(PingQuery),
+    responses((status = 200, body = Latency,
+        description = "Only when asked for latency, otherwise empty."))
+)]
 #[tracing::instrument(
     skip_all,
     fields(req_id = REQ_ID.get().req_id)
 )]
 async fn handle_ping(
+    State(AppState {
+        storage,
+        http,
+        upstream,
+        ..
+    }): State<AppState>,
     ConnectInfo(from): ConnectInfo<SocketAddr>,
-) -> StatusCode {
-    tracing::info!(?from, "Handling ping request.");
-    StatusCode::OK
+    Query(PingQuery { latency }): Query<PingQuery>,
+) -> Response {
+    tracing::info!(?from, latency, "Handling ping request.");
+    if !latency {
+        return StatusCode::OK.into_response();
+    }
+    let probe_timeout =
+        Duration::from_secs_f32(conf::global().health.probe_timeout);
+    let latency = Latency {
+        storage: storage_health(storage.as_ref()).await,
+        upstream: upstream.health(&http, Some(probe_timeout)).await,
+    };
+    Json(latency).into_response()
 }
 
-#[tracing::instrument(
-    skip_all,
-    fields(
-        req_id = REQ_ID.get().req_id,
-        uid = USER.get().uid
-    )
+/// Static. Data is fetched by the page with the user's own credentials.
+#[utoipa::path(
+    get,
+    path = "/dashboard",
+    tag = "public",
+    security(()),
+    responses((status = 200, content_type = "text/html", body = String))
 )]
-async fn handle_api(
-    storage: Storage,
-    ConnectInfo(from): ConnectInfo<SocketAddr>,
-    Path(endpoint): Path<String>,
-    Json(chat_req): Json<chat::Req>,
-) -> Result<Response<String>, StatusCode> {
-    tracing::info!(?from, "Handling API request.");
-    let conf = conf::global();
-    let user: User = USER.get();
+async fn handle_dashboard() -> axum::response::Html<&'static str> {
+    axum::response::Html(include_str!("dashboard.html"))
+}
 
-    //
-    // Rate Limit
-    //
-    let _token_count = chat_req.tokens_estimate();
-    let (hit_count, elapsed_since_prev) =
-        storage.hit(&user.uid).await.map_err(|error| {
-            tracing::error!(?error, "Failed to hit storage.");
-            StatusCode::SERVICE_UNAVAILABLE
-        })?;
-    let min_hit_interval = Duration::from_secs_f32(conf.min_hit_interval);
-    tracing::debug!(
-        hit_count,
-        ?elapsed_since_prev,
-        ?min_hit_interval,
-        "Checking interval."
-    );
-    if elapsed_since_prev < min_hit_interval {
-        tracing::warn!("Rejecting. Too close to previous request.");
-        // TODO Explain reason in response body.
-        return Err(StatusCode::TOO_MANY_REQUESTS);
-    };
+async fn prune(
+    storage: Arc<dyn Storage>,
+    retention_days: u32,
+) -> anyhow::Result<()> {
+    let pruned = data::prune(storage.as_ref(), retention_days)
+        .await
+        .context("Failed to prune old data.")?;
+    tracing::debug!(?pruned, "Pruned old data.");
+    Ok(())
+}
 
-    //
-    // Token Budget:
-    // 1. check if enough in budget
-    // 2. make request
-    // 3. consume from budget
-    //
-    let token_count = chat_req.tokens_estimate();
-    let is_enough_tokens_in_budget = storage
-        .tokens_check(&user.uid, token_count)
+/// Of what is kept only until it no longer matters.
+async fn expire(storage: Arc<dyn Storage>) -> anyhow::Result<()> {
+    let nonces = storage
+        .signing_nonces_expire(unix_now_secs())
         .await
-        .map_err(|error| {
-            tracing::error!(?error, "Failed to hit storage.");
-            StatusCode::SERVICE_UNAVAILABLE
-        })?;
-    if !is_enough_tokens_in_budget {
-        tracing::warn!("Rejecting. Token budget exceeded.");
-        // TODO Explain reason in response body.
-        return Err(StatusCode::TOO_MANY_REQUESTS);
-    }
-
-    let address = &conf.target_address;
-    let url = format!("https://{address}/{endpoint}");
-    let (client, out_req) = reqwest::Client::new()
-        .post(url)
-        .bearer_auth(&conf.target_auth_token)
-        .json(&chat_req)
-        .build_split();
-    let out_req = out_req.map_err(|error| {
-        tracing::error!(?error, "Failed to build outgoing request.");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    tracing::debug!(
-        out_headers = ?out_req.headers(),
-        out_body = ?out_req
-            .body()
-            .map(|b| b.as_bytes().map(|b| String::from_utf8_lossy(b))),
-        "Outgoing reqwest."
-    );
-    let resp = client.execute(out_req).await.map_err(|error| {
-        tracing::error!(?error, "Failed to make the external request.");
-        StatusCode::SERVICE_UNAVAILABLE
-    })?;
+        .context("Failed to expire signing nonces.")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let buckets = storage
+        .rate_buckets_expire(now)
+        .await
+        .context("Failed to expire rate buckets.")?;
+    tracing::debug!(nonces, buckets, "Expired.");
+    Ok(())
+}
 
-    let status = resp.status();
-    let headers = resp.headers().to_owned();
-    let code = status.as_u16();
-    let code = StatusCode::from_u16(code).map_err(|error| {
-        tracing::error!(?error, ?code, "Failed to convert status code.");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    let body = resp.text().await.map_err(|error| {
-        tracing::error!(
-            ?error,
-            ?code,
-            "Failed to receive body from target host."
-        );
-        StatusCode::SERVICE_UNAVAILABLE
-    })?;
-    if !status.is_success() {
-        tracing::error!(
-            ?status,
-            ?headers,
-            ?body,
-            "External request rejected."
-        );
-        return Err(StatusCode::SERVICE_UNAVAILABLE);
+async fn rollup(storage: Arc<dyn Storage>) -> anyhow::Result<()> {
+    let until = storage
+        .rollup(unix_now_secs())
+        .await
+        .context("Failed to roll up usage.")?;
+    tracing::debug!(until, "Rolled up usage.");
+    Ok(())
+}
+
+async fn close_credits(storage: Arc<dyn Storage>) -> anyhow::Result<()> {
+    let max_rollover = conf::global()
+        .rollover
+        .as_ref()
+        .map(|rollover| rollover.max_credits);
+    let closed = storage
+        .credits_close(&period::current(), max_rollover)
+        .await
+        .context("Failed to close budget periods.")?;
+    if closed > 0 {
+        tracing::info!(closed, "Closed budget periods.");
+    }
+    Ok(())
+}
+
+async fn reserve(
+    storage: Arc<dyn Storage>,
+    queue: Arc<queue::Queue>,
+) -> anyhow::Result<()> {
+    let reserved = storage
+        .orgs_reserved_rpm()
+        .await
+        .context("Failed to get org reservations.")?;
+    queue.set_reserved(reserved);
+    Ok(())
+}
+
+async fn calibrate(storage: Arc<dyn Storage>) -> anyhow::Result<()> {
+    let calibrated =
+        data::calibrate(storage.as_ref(), &conf::global().calibration)
+            .await
+            .context("Failed to calibrate token estimates.");
+    // Stored ones, even if not recomputed for lack of recent requests.
+    let factors = storage
+        .calibration_get()
+        .await
+        .context("Failed to get calibration.")?;
+    tokenizer::global().set_factors(factors);
+    calibrated.map(|_| ())
+}
+
+async fn detect_abuse(
+    storage: Arc<dyn Storage>,
+    events: events::Events,
+    conf: conf::Abuse,
+) -> anyhow::Result<()> {
+    let suspensions = abuse::detect(storage.as_ref(), &conf)
+        .await
+        .context("Failed to detect abuse.")?;
+    for suspension in suspensions {
+        events.publish(Event::Suspended(suspension));
     }
-    // XXX If tokens_consume fails - we don't want to fail the request, so
-    //     we might end-up not consuming. May need to yell louder here. Alert?
-    if let Err(error) = storage.tokens_consume(&user.uid, token_count).await {
-        tracing::error!(?error, ?token_count, "Failed to consume tokens!");
+    Ok(())
+}
+
+/// Waits for the server to be idle, up to the next scheduled run, so as not
+/// to hold up requests. Still checkpoints the WAL if it never is, since that
+/// is what keeps it from growing, but doesn't VACUUM.
+async fn maintain(
+    storage: Arc<dyn Storage>,
+    activity: Arc<Activity>,
+    conf: conf::Maintenance,
+) -> anyhow::Result<()> {
+    let period = Duration::from_secs(conf.interval);
+    let idle = Duration::from_secs_f32(conf.idle);
+    let deadline = Instant::now() + period;
+    let mut is_idle = activity.is_idle_for(idle);
+    while !is_idle && Instant::now() + IDLE_POLL_INTERVAL < deadline {
+        tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        is_idle = activity.is_idle_for(idle);
     }
-    if is_json(&body) {
-        Response::builder()
-            .status(code)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body)
-    } else {
-        Response::builder().status(code).body(body)
+    let vacuum = conf.vacuum && is_idle;
+    data::maintain(storage.as_ref(), vacuum)
+        .await
+        .context("Failed to maintain database.")
+}
+
+/// Sends the request to the shadow as well, logging how it did there, for
+/// comparison with how it did with the primary.
+async fn mirror(
+    storage: Arc<dyn Storage>,
+    http: reqwest::Client,
+    upstream: Arc<Upstream>,
+    events: events::Events,
+    req_id: String,
+    endpoint: String,
+    chat_req: chat::Req,
+) {
+    let Some(model) = upstream.shadow_model(&chat_req.model) else {
+        return;
+    };
+    let started = Instant::now();
+    let result = upstream.forward_shadow(&http, &endpoint, &chat_req).await;
+    let duration_ms =
+        i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX);
+    let (code, usage) = match &result {
+        Ok(upstream::Forwarded { code, body, .. }) => {
+            (*code, chat::Usage::from_resp_body(body))
+        }
+        Err(code) => (*code, None),
+    };
+    let (input_tokens, output_tokens) = usage.map_or((0, 0), |usage| {
+        (usage.prompt_tokens, usage.completion_tokens)
+    });
+    let cost = conf::global()
+        .pricing
+        .get(model)
+        .map_or(0.0, |price| price.cost(input_tokens, output_tokens));
+    if let (Some(spend), Ok(forwarded)) = (upstream.spend(), &result) {
+        let used = data::Amount {
+            tokens: input_tokens.saturating_add(output_tokens),
+            cost,
+        };
+        spend
+            .record(storage.as_ref(), &events, forwarded.provider, used)
+            .await;
+    }
+    metrics::counter!(
+        "raskol_shadow_requests_total",
+        "status" => code.as_u16().to_string()
+    )
+    .increment(1);
+    let log = data::ShadowLog {
+        req_id,
+        model: model.to_string(),
+        status: i64::from(code.as_u16()),
+        input_tokens: i64::try_from(input_tokens).unwrap_or(i64::MAX),
+        output_tokens: i64::try_from(output_tokens).unwrap_or(i64::MAX),
+        cost,
+        duration_ms,
+        time: unix_now_secs(),
+    };
+    if let Err(error) = storage.shadow_log(&log).await {
+        tracing::error!(?error, ?log, "Failed to log shadow request.");
     }
-    .map_err(|error| {
-        tracing::error!(?error, ?code, "Failed to build response.");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })
 }
 
-#[derive(Debug, Clone)]
-struct User {
-    pub uid: String,
+/// For calibration. Failing to is not worth failing the request.
+async fn record_tokens_estimate(
+    storage: &dyn Storage,
+    model: &str,
+    estimate: usize,
+) {
+    let req_id = REQ_ID.get().req_id;
+    let estimate = u64::try_from(estimate).unwrap_or(u64::MAX);
+    if let Err(error) = storage
+        .token_estimate_record(&req_id, model, estimate, unix_now_secs())
+        .await
+    {
+        tracing::error!(?error, "Failed to record tokens estimate.");
+    }
 }
 
-#[derive(Debug, Clone)]
-struct ReqId {
-    pub req_id: String,
+/// Of what is forwarded, to tell whether a repeat is the same request.
+fn fingerprint(endpoint: &str, payload: &upstream::Payload) -> String {
+    use sha2::{Digest, Sha256};
+
+    let hash = Sha256::new().chain_update(endpoint.as_bytes());
+    let hash = match payload {
+        upstream::Payload::Chat(chat_req) => hash
+            .chain_update(serde_json::to_vec(chat_req).unwrap_or_default()),
+        upstream::Payload::Raw { body, .. } => hash.chain_update(body),
+    };
+    hex::encode(hash.finalize())
 }
 
-impl ReqId {
-    fn new() -> Self {
-        let req_id = cuid2::create_id();
-        Self { req_id }
+/// Claims the key for the request, unless its first request is still in
+/// progress, or was another. The first's response, if done, to replay.
+async fn idempotency_claim(
+    storage: &dyn Storage,
+    user: &User,
+    key: &str,
+    fingerprint: &str,
+) -> Result<Option<Response>> {
+    let now = unix_now_secs();
+    let expires = now.saturating_add(
+        i64::try_from(conf::global().idempotency_ttl).unwrap_or(i64::MAX),
+    );
+    let idempotency = storage
+        .idempotency_claim(&user.uid, key, fingerprint, now, expires)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to claim idempotency key.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    match idempotency {
+        data::Idempotency::Claimed => Ok(None),
+        data::Idempotency::InProgress => {
+            tracing::warn!(key, "Rejecting. Repeat still in progress.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "idempotency_key_in_use",
+                "A request with this Idempotency-Key is still in progress."
+                    .to_string(),
+            );
+            Err((StatusCode::CONFLICT, Json(error)).into())
+        }
+        data::Idempotency::Mismatch => {
+            tracing::warn!(key, "Rejecting. Key used for another.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "idempotency_key_reused",
+                "This Idempotency-Key was used for a different request."
+                    .to_string(),
+            );
+            Err((StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into())
+        }
+        data::Idempotency::Done { status, body } => {
+            tracing::info!(key, status, "Replaying response.");
+            Ok(Some(replay(status, body)?.into_response()))
+        }
     }
 }
 
-tokio::task_local! {
-    pub static USER: User;
-    pub static REQ_ID: ReqId;
+/// Keeps successful responses for replaying, and releases the key
+/// otherwise, so that the client may retry. Failing to is logged, but not
+/// worth failing the request.
+async fn idempotency_settle(
+    storage: &Arc<dyn Storage>,
+    user: &User,
+    key: Option<String>,
+    forwarded: Option<&upstream::Forwarded>,
+) {
+    let Some(key) = key else {
+        return;
+    };
+    let result = match forwarded {
+        Some(forwarded) if forwarded.code.is_success() => {
+            storage
+                .idempotency_complete(
+                    &user.uid,
+                    &key,
+                    forwarded.code.as_u16(),
+                    &forwarded.body,
+                )
+                .await
+        }
+        _ => storage.idempotency_release(&user.uid, &key).await,
+    };
+    if let Err(error) = result {
+        tracing::error!(?error, key, "Failed to settle idempotency key.");
+    }
 }
 
-async fn auth_layer(
-    req: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    let conf: Arc<Conf> = conf::global();
-    let auth_token = req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|header| header.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    if let Some(user) = authorize(auth_token, &conf.jwt) {
+/// The idempotency key, if it's 1 to [`IDEMPOTENCY_KEY_MAX_LEN`] visible
+/// ASCII characters.
+fn idempotency_key_valid(value: &header::HeaderValue) -> Result<String> {
+    let key = value.to_str().ok().filter(|key| {
+        !key.is_empty() && key.len() <= IDEMPOTENCY_KEY_MAX_LEN
+    });
+    let Some(key) = key else {
+        tracing::warn!(?value, "Rejecting. Invalid idempotency key.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "invalid_idempotency_key",
+            format!(
+                "Idempotency-Key must be 1 to {IDEMPOTENCY_KEY_MAX_LEN} \
+                visible ASCII characters."
+            ),
+        );
+        return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+    };
+    Ok(key.to_string())
+}
+
+/// The session tag, if it's 1 to [`SESSION_MAX_LEN`] visible ASCII
+/// characters.
+fn session_valid(tag: Option<&str>) -> Result<String> {
+    let valid = tag.filter(|tag| {
+        !tag.is_empty()
+            && tag.len() <= SESSION_MAX_LEN
+            && tag.bytes().all(|byte| byte.is_ascii_graphic())
+    });
+    let Some(valid) = valid else {
+        tracing::warn!(?tag, "Rejecting. Invalid session.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "invalid_session",
+            format!(
+                "Session must be 1 to {SESSION_MAX_LEN} visible ASCII \
+                characters."
+            ),
+        );
+        return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+    };
+    Ok(valid.to_string())
+}
+
+/// Of the whole upstream exchange, including any wait in the queue: the
+/// role's or the client's, whichever is shorter, if any. Either way, no
+/// longer than `http.request_timeout`, which the client enforces per try.
+fn upstream_timeout(
+    conf: &Conf,
+    role: &str,
+    requested: Option<&header::HeaderValue>,
+) -> Result<Option<Duration>> {
+    let requested = match requested {
+        None => None,
+        Some(value) => {
+            let secs = value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|secs| secs.is_finite() && *secs > 0.0);
+            let Some(secs) = secs else {
+                tracing::warn!(?value, "Rejecting. Invalid timeout.");
+                let error = chat::Error::new(
+                    "invalid_request_error",
+                    "invalid_timeout",
+                    format!("{TIMEOUT_HEADER} must be positive seconds."),
+                );
+                return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+            };
+            Duration::try_from_secs_f64(secs).ok()
+        }
+    };
+    let role = conf
+        .limits
+        .per_role
+        .get(role)
+        .and_then(|limits| limits.request_timeout)
+        .and_then(|secs| Duration::try_from_secs_f32(secs).ok());
+    Ok(match (role, requested) {
+        (Some(role), Some(requested)) => Some(role.min(requested)),
+        (role, requested) => role.or(requested),
+    })
+}
+
+/// Of the model, naming those the role may use.
+fn model_not_allowed(conf: &Conf, role: &str, model: &str) -> Response {
+    tracing::warn!(model, "Rejecting. Model not allowed.");
+    let allowed: Vec<&str> = conf
+        .roles
+        .get(role)
+        .map(|role| role.allowed_models().collect())
+        .unwrap_or_default();
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "model_not_allowed",
+        format!(
+            "Model {:?} is not allowed for role {:?}. Allowed models: {}.",
+            model,
+            role,
+            if allowed.is_empty() {
+                "none".to_string()
+            } else {
+                allowed.join(", ")
+            }
+        ),
+    );
+    (StatusCode::FORBIDDEN, Json(error)).into_response()
+}
+
+/// Whether the prompt, of `token_count` tokens, and the completion fit in
+/// the model's context window, if it's known.
+fn context_fits(
+    conf: &Conf,
+    chat_req: &chat::Req,
+    token_count: usize,
+) -> Result<()> {
+    let model = &chat_req.model;
+    let window = conf.context_windows.get(model).copied();
+    let needed = token_count.saturating_add(chat_req.max_tokens.unwrap_or(0));
+    if let Some(window) = window.filter(|window| needed > *window) {
+        tracing::warn!(needed, window, "Rejecting. Context exceeded.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "context_length_exceeded",
+            format!(
+                "Model {model:?} has a context window of {window} tokens, \
+                but the request needs about {needed}: {token_count} in the \
+                messages and {} for the completion (max_tokens).",
+                chat_req.max_tokens.unwrap_or(0)
+            ),
+        );
+        return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+    }
+    Ok(())
+}
+
+/// Of uploads, and other than chat, which is all a dry run can reply to.
+fn dry_run_of_other(endpoint: &str) -> Response {
+    tracing::warn!(endpoint, "Rejecting. Dry run of other than chat.");
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "invalid_dry_run",
+        "Only chat completions can be dry run.".to_string(),
+    );
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+}
+
+/// Whether the request asks for a dry run, which the role must allow.
+fn dry_run_asked(
+    conf: &Conf,
+    role: &str,
+    asked: Option<&header::HeaderValue>,
+) -> Result<bool> {
+    let Some(value) = asked else {
+        return Ok(false);
+    };
+    let is_asked = match value.to_str().map(str::trim) {
+        Ok("true") => true,
+        Ok("false") => false,
+        _ => {
+            tracing::warn!(?value, "Rejecting. Invalid dry run header.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "invalid_dry_run",
+                format!("{DRY_RUN_HEADER} must be true or false."),
+            );
+            return Err((StatusCode::BAD_REQUEST, Json(error)).into());
+        }
+    };
+    let is_allowed = conf
+        .roles
+        .get(role)
+        .is_some_and(|role| role.is_dry_run_allowed);
+    if is_asked && !is_allowed {
+        tracing::warn!(role, "Rejecting. Dry run not allowed.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "dry_run_not_allowed",
+            format!("Role {role:?} may not dry run."),
+        );
+        return Err((StatusCode::FORBIDDEN, Json(error)).into());
+    }
+    Ok(is_asked)
+}
+
+/// Redacted, as logs are, per [`redact`]. Failures are logged, but not
+/// worth failing the request over.
+async fn capture_bodies(
+    storage: &dyn Storage,
+    conf: &conf::Capture,
+    log: &data::RequestLog,
+    chat_req: &chat::Req,
+    response: Option<&str>,
+) {
+    let request = match serde_json::to_string(chat_req) {
+        Ok(request) => request,
+        Err(error) => {
+            tracing::error!(?error, "Failed to encode request to capture.");
+            return;
+        }
+    };
+    let request = redact::redact(&request);
+    let (request, is_request_truncated) = truncate(&request, conf.max_bytes);
+    let response = response.map(redact::redact);
+    let response = response
+        .as_deref()
+        .map(|body| truncate(body, conf.max_bytes));
+    let capture = data::Capture {
+        req_id: log.req_id.clone(),
+        uid: log.uid.clone(),
+        request: request.to_string(),
+        response: response.map(|(body, _)| body.to_string()),
+        is_truncated: is_request_truncated
+            || response.is_some_and(|(_, is_truncated)| is_truncated),
+        time: log.time,
+    };
+    if let Err(error) = storage.capture(&capture).await {
+        tracing::error!(?error, req_id = log.req_id, "Failed to capture.");
+    }
+}
+
+/// To at most `max` bytes, on a character boundary. Whether it was cut.
+fn truncate(s: &str, max: usize) -> (&str, bool) {
+    if s.len() <= max {
+        return (s, false);
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&s[..end], true)
+}
+
+fn replay(status: u16, body: String) -> Result<Response<String>, StatusCode> {
+    let mut resp = Response::builder()
+        .status(status)
+        .header("x-raskol-idempotent-replay", "true");
+    if is_json(&body) {
+        resp = resp.header(header::CONTENT_TYPE, "application/json");
+    }
+    let resp = resp.body(body).map_err(|error| {
+        tracing::error!(?error, status, "Failed to build response.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(resp)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatsQuery {
+    /// Unix time. Rounded down to the hour. All time when not given.
+    #[serde(default)]
+    since: i64,
+}
+
+/// The user's own.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "stats",
+    params(StatsQuery),
+    responses((status = 200, body = data::UserStats))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(StatsQuery { since }): Query<StatsQuery>,
+) -> Result<Json<data::UserStats>, StatusCode> {
+    let uid = USER.get().uid;
+    let stats = storage
+        .stats(Some(&uid), None, since, &data::StatsPage::default())
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?
+        .pop()
+        .unwrap_or_else(|| data::UserStats {
+            uid,
+            ..data::UserStats::default()
+        });
+    Ok(Json(stats))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Quota {
+    /// Key of the current budget period.
+    period: String,
+
+    /// When the budgets reset, as RFC 3339.
+    reset_at: String,
+
+    tokens: QuotaTokens,
+    cost: QuotaCost,
+    rate_limit: ratelimit::State,
+
+    /// In flight at once. No limit when not set.
+    max_concurrent_requests: Option<usize>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct QuotaTokens {
+    limit: u64,
+    used: u64,
+    remaining: u64,
+}
+
+/// In dollars, per conf.pricing. No limit when not set.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct QuotaCost {
+    limit: Option<f64>,
+    used: f64,
+    remaining: Option<f64>,
+}
+
+/// Where the caller stands with their budgets and rate limits, so that
+/// clients can show a usage meter rather than wait for a 429.
+#[utoipa::path(
+    get,
+    path = "/quota",
+    tag = "stats",
+    responses((status = 200, body = Quota))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_quota(
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<Json<Quota>, StatusCode> {
+    let conf = conf::global();
+    let user = USER.get();
+    let internal = |error: anyhow::Error| {
+        tracing::error!(?error, "Failed to get quota.");
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let period = period::current();
+    let (_, reset) = period::bounds(&period).map_err(internal)?;
+    let tokens = storage
+        .token_budget(&user.uid, &user.role, user.budget_overrides)
+        .await
+        .map_err(internal)?;
+    let usage = storage.usage(&user.uid, &period).await.map_err(internal)?;
+    let cost_limit = user.budget_overrides.max_cost_per_day.or_else(|| {
+        let multiplier = auth::budget_multiplier(&conf, &user.role);
+        conf.max_cost_per_day.map(|max| max * multiplier)
+    });
+    let rate_limit =
+        ratelimit::state(storage.as_ref(), &user.uid, &user.role)
+            .await
+            .map_err(internal)?;
+    Ok(Json(Quota {
+        period,
+        reset_at: chrono::DateTime::from_timestamp(reset, 0)
+            .unwrap_or_default()
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        tokens: QuotaTokens {
+            limit: tokens.limit,
+            used: tokens.used,
+            remaining: tokens.remaining(),
+        },
+        cost: QuotaCost {
+            limit: cost_limit,
+            used: usage.cost,
+            remaining: cost_limit.map(|limit| (limit - usage.cost).max(0.0)),
+        },
+        rate_limit,
+        max_concurrent_requests: conf.max_concurrent_requests_per_user,
+    }))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ClientConfig {
+    /// Of our routes, and of upstream's under ours, as the caller reached
+    /// us, e.g. "https://example.com/v1", for OpenAI's SDKs.
+    base_url: String,
+    api_version: u32,
+    role: String,
+
+    /// Of the models named in conf, those the role may use. Upstream's
+    /// are at `/v1/models`.
+    models: Vec<ClientModel>,
+    limits: ClientLimits,
+
+    /// Whether chat completions may be streamed, as server-sent events.
+    streaming: bool,
+
+    /// Those the caller has, per [`conf::Feature`], streaming among them.
+    features: Vec<&'static str>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ClientModel {
+    id: String,
+    context_window: Option<usize>,
+
+    /// Used instead, if upstream refuses the model.
+    fallback: Option<String>,
+}
+
+/// Of the caller's role. None where there is none.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ClientLimits {
+    /// Of completions.
+    max_tokens: Option<usize>,
+    max_messages: Option<usize>,
+    min_temperature: Option<f64>,
+    max_temperature: Option<f64>,
+
+    /// Of chat request bodies.
+    max_body_bytes: usize,
+    max_concurrent_requests: Option<usize>,
+    rate_limit: ratelimit::State,
+}
+
+/// What starter kits need to configure themselves at runtime: where to send
+/// requests, which models, within which limits, and with which features.
+/// Budgets are at `/quota`.
+#[utoipa::path(
+    get,
+    path = "/client-config",
+    tag = "stats",
+    responses((status = 200, body = ClientConfig))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_client_config(
+    State(AppState { storage, .. }): State<AppState>,
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    proxy: Option<Extension<Proxy>>,
+    headers: header::HeaderMap,
+) -> Result<Json<ClientConfig>, StatusCode> {
+    let conf = conf::global();
+    let user = USER.get();
+    // As set by a reverse proxy, if any, and only if trusted, as anyone else
+    // could point clients elsewhere.
+    let peer = proxy.map_or(from, |Extension(Proxy(peer))| peer);
+    let is_proxied =
+        conf.ip_filter.is_trusted_proxy(peer.ip().to_canonical());
+    let get = |name: &'static str| {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    };
+    let get_forwarded = |name| if is_proxied { get(name) } else { None };
+    let scheme = get_forwarded("x-forwarded-proto")
+        .unwrap_or(if conf.tls.is_some() { "https" } else { "http" });
+    let host = get_forwarded("x-forwarded-host")
+        .or_else(|| get("host"))
+        .unwrap_or("localhost");
+    let role = conf.roles.get(&user.role);
+    let mut ids: BTreeSet<&String> = conf
+        .pricing
+        .keys()
+        .chain(conf.context_windows.keys())
+        .chain(conf.fallback_models.keys())
+        .chain(conf.limits.per_model.keys())
+        .chain(conf.experiments.values().map(|e| &e.model))
+        .collect();
+    ids.retain(|id| role.is_some_and(|role| role.is_model_allowed(id)));
+    let models = ids
+        .into_iter()
+        .map(|id| ClientModel {
+            id: id.clone(),
+            context_window: conf.context_windows.get(id).copied(),
+            fallback: conf.fallback_models.get(id).cloned(),
+        })
+        .collect();
+    let role_limits = conf.limits.per_role.get(&user.role);
+    let rate_limit =
+        ratelimit::state(storage.as_ref(), &user.uid, &user.role)
+            .await
+            .map_err(|error| {
+                tracing::error!(?error, "Failed to get rate limit state.");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+    let features: Vec<&'static str> = conf::Feature::ALL
+        .into_iter()
+        .filter(|feature| {
+            missing_feature(&conf, &user, &[*feature]).is_none()
+        })
+        .map(conf::Feature::as_str)
+        .collect();
+    Ok(Json(ClientConfig {
+        base_url: format!("{scheme}://{host}{}", version::PREFIX),
+        api_version: version::CURRENT,
+        streaming: features.contains(&conf::Feature::Streaming.as_str()),
+        features,
+        role: user.role,
+        models,
+        limits: ClientLimits {
+            max_tokens: role_limits.and_then(|limits| limits.max_tokens),
+            max_messages: role_limits.and_then(|limits| limits.max_messages),
+            min_temperature: role_limits
+                .and_then(|limits| limits.min_temperature),
+            max_temperature: role_limits
+                .and_then(|limits| limits.max_temperature),
+            max_body_bytes: endpoint::max_body_bytes(
+                &conf,
+                &user.role,
+                "v1/chat/completions",
+                endpoint::Kind::Chat,
+            ),
+            max_concurrent_requests: conf.max_concurrent_requests_per_user,
+            rate_limit,
+        },
+    }))
+}
+
+/// Of a chat request, as far as can be told without forwarding it.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Validation {
+    /// Whether it passed all the checks.
+    is_valid: bool,
+
+    model: String,
+
+    /// Of the messages, with the role's system prompt, as counted against
+    /// the budget.
+    prompt_tokens: usize,
+
+    /// In dollars, per conf.pricing, of the prompt, and of the completion
+    /// too, at max_tokens. Not when the model is not priced.
+    prompt_cost: Option<f64>,
+    max_cost: Option<f64>,
+
+    /// Of the model, per conf.context_windows.
+    context_window: Option<usize>,
+
+    budget: Option<chat::Budget>,
+
+    /// Of the failed checks, as the chat endpoint would reject it with.
+    errors: Vec<chat::ErrorDetail>,
+}
+
+/// Runs a chat request through the checks the chat endpoint would, of role
+/// limits, tags, the model, the context window and the budget, without
+/// forwarding it or drawing on the budget, for integrations to be linted
+/// cheaply.
+#[utoipa::path(
+    post,
+    path = "/validate",
+    tag = "stats",
+    request_body = chat::Req,
+    responses(
+        (status = 200, body = Validation),
+        (status = 400, description = "Unparsable."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_validate(
+    State(AppState { storage, .. }): State<AppState>,
+    Json(mut req): Json<chat::Req>,
+) -> Json<Validation> {
+    let conf = conf::global();
+    let user = USER.get();
+    let mut errors = Vec::new();
+    let mut reject = |code: &str, message: String| {
+        errors.push(
+            chat::Error::new("invalid_request_error", code, message).error,
+        );
+    };
+    if let Some(limits) = conf.limits.per_role.get(&user.role) {
+        if let Err(message) = req.constrain(limits) {
+            reject("role_limit_exceeded", message);
+        }
+    }
+    if let Err(message) = req.take_tags() {
+        reject("invalid_tags", message);
+    }
+    if let Some(prompt) = conf
+        .roles
+        .get(&user.role)
+        .and_then(|role| role.system_prompt.as_ref())
+    {
+        req.inject_system_prompt(prompt);
+    }
+    let model = req.model.clone();
+    let estimate = req.tokens_estimate(tokenizer::for_prompts(&conf));
+    let prompt_tokens = tokenizer::global().calibrate(&model, estimate);
+    let max_tokens = req.max_tokens.unwrap_or(0);
+    let is_model_allowed = conf
+        .roles
+        .get(&user.role)
+        .is_some_and(|role| role.is_model_allowed(&model));
+    if !is_model_allowed {
+        reject(
+            "model_not_allowed",
+            format!(
+                "Model {model:?} is not allowed for role {:?}.",
+                user.role
+            ),
+        );
+    }
+    if let Some(feature) = missing_feature(&conf, &user, &req.features()) {
+        reject("feature_not_allowed", feature_not_allowed_message(feature));
+    }
+    let context_window = conf.context_windows.get(&model).copied();
+    let needed = prompt_tokens.saturating_add(max_tokens);
+    if let Some(window) = context_window.filter(|window| needed > *window) {
+        reject(
+            "context_length_exceeded",
+            format!(
+                "Model {model:?} has a context window of {window} tokens, \
+                but the request needs about {needed}."
+            ),
+        );
+    }
+    let budget = token_budget(storage.as_ref(), &user).await;
+    let needed = u64::try_from(prompt_tokens).unwrap_or(u64::MAX);
+    if let Some(budget) = budget.as_ref().filter(|b| b.remaining() < needed) {
+        reject(
+            "budget_exceeded",
+            format!(
+                "{} budget exceeded. {} tokens remaining, but the request \
+                needs about {prompt_tokens}.",
+                period::adjective(),
+                budget.remaining()
+            ),
+        );
+    }
+    let price = conf.pricing.get(&model).copied();
+    tracing::info!(model, prompt_tokens, errors = errors.len(), "Validated.");
+    Json(Validation {
+        is_valid: errors.is_empty(),
+        prompt_tokens,
+        prompt_cost: price.map(|price| price.cost(prompt_tokens, 0)),
+        max_cost: price.map(|price| price.cost(prompt_tokens, max_tokens)),
+        context_window,
+        budget: budget.map(|budget| chat::Budget {
+            tokens_used: budget.used,
+            tokens_limit: budget.limit,
+            tokens_remaining: budget.remaining(),
+            reset_at: budget_reset_at(),
+        }),
+        errors,
+        model,
+    })
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Bucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TimeseriesQuery {
+    #[serde(default)]
+    bucket: Bucket,
+
+    /// Unix time. A day ago when not given.
+    from: Option<i64>,
+
+    /// Unix time, exclusive. Until now when not given.
+    to: Option<i64>,
+
+    /// Someone else's, or everyone's with "*", for those who may see
+    /// /total-stats.
+    uid: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/timeseries",
+    tag = "stats",
+    params(TimeseriesQuery),
+    responses(
+        (status = 200, body = Vec<data::UsageBucket>),
+        (status = 403, description = "Others' stats not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats_timeseries(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<TimeseriesQuery>,
+) -> Result<Json<Vec<data::UsageBucket>>, StatusCode> {
+    let user = USER.get();
+    let uid = query.uid.unwrap_or_else(|| user.uid.clone());
+    if uid != user.uid
+        && !auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+    {
+        tracing::warn!(uid, "Rejecting. Others' stats not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let bucket = match query.bucket {
+        Bucket::Hour => 60 * 60,
+        Bucket::Day => DAY,
+    };
+    let from = query.from.unwrap_or_else(|| unix_now_secs() - DAY);
+    let until = query.to.unwrap_or(i64::MAX);
+    let uid = (uid != "*").then_some(uid);
+    let series = storage
+        .timeseries(uid.as_deref(), bucket, from, until)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(series))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum CostGroupBy {
+    Model,
+    Day,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CostQuery {
+    group_by: CostGroupBy,
+
+    /// YYYY-MM-DD, UTC. 30 days before `to` when not given.
+    from: Option<String>,
+
+    /// YYYY-MM-DD, UTC, inclusive. Today when not given.
+    to: Option<String>,
+
+    /// Someone else's, or everyone's with "*", for those who may see
+    /// /total-stats.
+    uid: Option<String>,
+}
+
+/// Of a model or a day.
+#[derive(serde::Serialize, utoipa::ToSchema, Default)]
+struct CostGroup {
+    key: String,
+    requests: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost: f64,
+}
+
+/// By model, most spent on first, or by day, in order.
+#[utoipa::path(
+    get,
+    path = "/stats/cost",
+    tag = "stats",
+    params(CostQuery),
+    responses(
+        (status = 200, body = Vec<CostGroup>),
+        (status = 400, description = "Invalid date, or `from` after `to`."),
+        (status = 403, description = "Others' stats not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats_cost(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<CostQuery>,
+) -> Result<Json<Vec<CostGroup>>, StatusCode> {
+    let user = USER.get();
+    let uid = query.uid.unwrap_or_else(|| user.uid.clone());
+    if uid != user.uid
+        && !auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+    {
+        tracing::warn!(uid, "Rejecting. Others' stats not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let date = |date: &str| {
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+            tracing::warn!(date, "Rejecting. Invalid date.");
+            StatusCode::BAD_REQUEST
+        })
+    };
+    let to = date(&query.to.unwrap_or_else(data::today))?;
+    let from = match &query.from {
+        Some(from) => date(from)?,
+        None => to - chrono::Days::new(30),
+    };
+    if from > to {
+        tracing::warn!(%from, %to, "Rejecting. Dates out of order.");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let uid = (uid != "*").then_some(uid);
+    let rows = storage
+        .report(
+            &from.format("%Y-%m-%d").to_string(),
+            &to.format("%Y-%m-%d").to_string(),
+            uid.as_deref(),
+        )
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get report.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    let mut groups: BTreeMap<String, CostGroup> = BTreeMap::new();
+    for row in rows {
+        let key = match query.group_by {
+            CostGroupBy::Model => row.model,
+            CostGroupBy::Day => row.date,
+        };
+        let group = groups.entry(key.clone()).or_insert_with(|| CostGroup {
+            key,
+            ..CostGroup::default()
+        });
+        group.requests += row.requests;
+        group.input_tokens += row.input_tokens;
+        group.output_tokens += row.output_tokens;
+        group.cost += row.cost;
+    }
+    let mut groups: Vec<CostGroup> = groups.into_values().collect();
+    if let CostGroupBy::Model = query.group_by {
+        groups.sort_by(|a, b| b.cost.total_cmp(&a.cost));
+    }
+    Ok(Json(groups))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SinceQuery {
+    /// Unix time. A day ago when not given.
+    since: Option<i64>,
+
+    /// Someone else's, or everyone's with "*", for those who may see
+    /// /total-stats.
+    uid: Option<String>,
+}
+
+/// Percentiles per model, of the whole requests and of where upstream's
+/// time went, if it says.
+#[utoipa::path(
+    get,
+    path = "/stats/latency",
+    tag = "stats",
+    params(SinceQuery),
+    responses(
+        (status = 200, body = Vec<data::LatencyStats>),
+        (status = 403, description = "Others' stats not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats_latency(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<SinceQuery>,
+) -> Result<Json<Vec<data::LatencyStats>>, StatusCode> {
+    let user = USER.get();
+    let uid = query.uid.unwrap_or_else(|| user.uid.clone());
+    if uid != user.uid
+        && !auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+    {
+        tracing::warn!(uid, "Rejecting. Others' stats not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let since = query.since.unwrap_or_else(|| unix_now_secs() - DAY);
+    let uid = (uid != "*").then_some(uid);
+    let stats = storage.latency_stats(uid.as_deref(), since).await.map_err(
+        |error| {
+            tracing::error!(?error, "Failed to get latency stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )?;
+    Ok(Json(stats))
+}
+
+/// Error rates by class, telling upstream failing from clients' requests
+/// being refused.
+#[utoipa::path(
+    get,
+    path = "/stats/errors",
+    tag = "stats",
+    params(SinceQuery),
+    responses(
+        (status = 200, body = data::ErrorRates),
+        (status = 403, description = "Others' stats not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats_errors(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<SinceQuery>,
+) -> Result<Json<data::ErrorRates>, StatusCode> {
+    let user = USER.get();
+    let uid = query.uid.unwrap_or_else(|| user.uid.clone());
+    if uid != user.uid
+        && !auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+    {
+        tracing::warn!(uid, "Rejecting. Others' stats not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let since = query.since.unwrap_or_else(|| unix_now_secs() - DAY);
+    let uid = (uid != "*").then_some(uid);
+    let rates = storage.error_rates(uid.as_deref(), since).await.map_err(
+        |error| {
+            tracing::error!(?error, "Failed to get error rates.");
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )?;
+    Ok(Json(rates))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TagsQuery {
+    /// Per value of just this one.
+    tag: Option<String>,
+
+    /// Unix time. A day ago when not given.
+    since: Option<i64>,
+
+    /// Someone else's, for those who may see /total-stats.
+    uid: Option<String>,
+}
+
+/// Per tag and value, of the requests tagged, in `metadata` or `tags`,
+/// costliest first.
+#[utoipa::path(
+    get,
+    path = "/stats/tags",
+    tag = "stats",
+    params(TagsQuery),
+    responses(
+        (status = 200, body = Vec<data::TagStats>),
+        (status = 403, description = "Others' usage not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_stats_tags(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<TagsQuery>,
+) -> Result<Json<Vec<data::TagStats>>, StatusCode> {
+    let uid = others_uid(query.uid)?;
+    let since = query.since.unwrap_or_else(|| unix_now_secs() - DAY);
+    let stats = storage
+        .tag_stats(Some(&uid), query.tag.as_deref(), since)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get tag stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(stats))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SessionsQuery {
+    /// Unix time. A day ago when not given.
+    since: Option<i64>,
+
+    #[serde(default = "default_sessions_limit")]
+    limit: u64,
+
+    /// Someone else's, for those who may see /total-stats.
+    uid: Option<String>,
+}
+
+fn default_sessions_limit() -> u64 {
+    50
+}
+
+/// Latest first.
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "stats",
+    params(SessionsQuery),
+    responses(
+        (status = 200, body = Vec<data::SessionStats>),
+        (status = 403, description = "Others' sessions not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_sessions(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<SessionsQuery>,
+) -> Result<Json<Vec<data::SessionStats>>, StatusCode> {
+    let uid = others_uid(query.uid)?;
+    let since = query.since.unwrap_or_else(|| unix_now_secs() - DAY);
+    let sessions = storage.sessions(&uid, since, query.limit).await.map_err(
+        |error| {
+            tracing::error!(?error, "Failed to get sessions.");
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )?;
+    Ok(Json(sessions))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SessionQuery {
+    /// Someone else's, for those who may see /total-stats.
+    uid: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{session}",
+    tag = "stats",
+    params(("session" = String, Path), SessionQuery),
+    responses(
+        (status = 200, body = data::SessionStats),
+        (status = 403, description = "Others' sessions not allowed."),
+        (status = 404),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_session(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(session): Path<String>,
+    Query(query): Query<SessionQuery>,
+) -> Result<Json<data::SessionStats>, StatusCode> {
+    let uid = others_uid(query.uid)?;
+    let stats = storage
+        .session(&uid, &session)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get session.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(stats))
+}
+
+/// Whose usage to look at: the user's own, unless they may see others'.
+fn others_uid(uid: Option<String>) -> Result<String, StatusCode> {
+    let user = USER.get();
+    let uid = uid.unwrap_or_else(|| user.uid.clone());
+    if uid != user.uid
+        && !auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+    {
+        tracing::warn!(uid, "Rejecting. Others' usage not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(uid)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LeaderboardQuery {
+    #[serde(default)]
+    by: data::Rank,
+}
+
+/// Of the current budget period.
+#[utoipa::path(
+    get,
+    path = "/leaderboard",
+    tag = "stats",
+    params(LeaderboardQuery),
+    responses((status = 200, body = Vec<data::LeaderboardEntry>))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_leaderboard(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(LeaderboardQuery { by }): Query<LeaderboardQuery>,
+) -> Result<Json<Vec<data::LeaderboardEntry>>, StatusCode> {
+    let conf = conf::global();
+    let mut entries = storage
+        .leaderboard(&period::current(), by, conf.leaderboard.size)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get leaderboard.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    if conf.leaderboard.anonymize {
+        for entry in &mut entries {
+            entry.uid = anonymize(&conf, &entry.uid);
+        }
+    }
+    Ok(Json(entries))
+}
+
+/// Stable, so that teams can still follow their position. Salted with our
+/// secret, so that known uids can't be matched with their hashes.
+pub(crate) fn anonymize(conf: &Conf, uid: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let hash = Sha256::new()
+        .chain_update(conf.jwt.secret.as_bytes())
+        .chain_update(uid.as_bytes())
+        .finalize();
+    hex::encode(&hash[..6])
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TotalStatsQuery {
+    /// Unix time. Rounded down to the hour. All time when not given.
+    #[serde(default)]
+    since: i64,
+
+    #[serde(default)]
+    sort: data::StatsSort,
+
+    /// All when not given.
+    limit: Option<u64>,
+
+    #[serde(default)]
+    offset: u64,
+}
+
+/// Of all users.
+#[utoipa::path(
+    get,
+    path = "/total-stats",
+    tag = "stats",
+    params(TotalStatsQuery),
+    responses((status = 200, body = Vec<data::UserStats>))
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_total_stats(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(query): Query<TotalStatsQuery>,
+) -> Result<Json<Vec<data::UserStats>>, StatusCode> {
+    let page = data::StatsPage {
+        sort: query.sort,
+        limit: query.limit,
+        offset: query.offset,
+    };
+    let stats = storage
+        .stats(None, None, query.since, &page)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(stats))
+}
+
+/// Members may see their own org, and those who may see /total-stats any.
+pub(crate) fn is_org_allowed(user: &User, org: &str) -> bool {
+    user.org.as_deref() == Some(org)
+        || auth::authorize(&conf::global(), &user.role, "/total-stats", None)
+}
+
+#[utoipa::path(
+    get,
+    path = "/orgs/{org}",
+    tag = "stats",
+    params(("org" = String, Path)),
+    responses(
+        (status = 200, body = data::Org),
+        (status = 403, description = "Other orgs not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_org(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(org): Path<String>,
+) -> Result<Json<data::Org>, StatusCode> {
+    if !is_org_allowed(&USER.get(), &org) {
+        tracing::warn!(org, "Rejecting. Other orgs not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let org = storage.org_get(&org).await.map_err(|error| {
+        tracing::error!(?error, "Failed to get org.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    Ok(Json(org))
+}
+
+/// Of the users who made requests as members of the org.
+#[utoipa::path(
+    get,
+    path = "/orgs/{org}/stats",
+    tag = "stats",
+    params(("org" = String, Path), TotalStatsQuery),
+    responses(
+        (status = 200, body = Vec<data::UserStats>),
+        (status = 403, description = "Other orgs not allowed."),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_org_stats(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(org): Path<String>,
+    Query(query): Query<TotalStatsQuery>,
+) -> Result<Json<Vec<data::UserStats>>, StatusCode> {
+    if !is_org_allowed(&USER.get(), &org) {
+        tracing::warn!(org, "Rejecting. Other orgs' stats not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let page = data::StatsPage {
+        sort: query.sort,
+        limit: query.limit,
+        offset: query.offset,
+    };
+    let stats = storage
+        .stats(None, Some(&org), query.since, &page)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get stats.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(stats))
+}
+
+/// Upstream's, less those the role may not use.
+#[utoipa::path(
+    get,
+    path = "/v1/models",
+    tag = "stats",
+    responses((status = 200, body = chat::Models))
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn handle_models(
+    State(AppState { http, upstream, .. }): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Json<chat::Models>, StatusCode> {
+    models(&http, &upstream, None, uri.path()).await
+}
+
+/// Of the "primary" or the "failover" provider.
+#[utoipa::path(
+    get,
+    path = "/api/{provider}/models",
+    tag = "stats",
+    params(("provider" = String, Path)),
+    responses(
+        (status = 200, body = chat::Models),
+        (status = 404, description = "No such provider."),
+    )
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn handle_provider_models(
+    State(AppState { http, upstream, .. }): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path(provider): Path<String>,
+) -> Result<Json<chat::Models>, StatusCode> {
+    models(&http, &upstream, Some(&provider), uri.path()).await
+}
+
+/// Upstream's models, less those the user's role may not use.
+async fn models(
+    http: &reqwest::Client,
+    upstream: &Upstream,
+    provider: Option<&str>,
+    path: &str,
+) -> Result<Json<chat::Models>, StatusCode> {
+    let conf = conf::global();
+    let role = USER.get().role;
+    let mut models = upstream.models(http, provider).await?;
+    models
+        .data
+        .retain(|model| auth::authorize(&conf, &role, path, Some(&model.id)));
+    Ok(Json(models))
+}
+
+/// Liveness: the process is up and serving.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "public",
+    security(()),
+    responses((status = 200, body = String))
+)]
+async fn handle_health() -> &'static str {
+    "OK"
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Readiness {
+    is_ready: bool,
+
+    /// Whether upstream can't be used from here for now, so that requests
+    /// are proxied to peers, per [`conf::Peers`].
+    is_exhausted: bool,
+    storage: StorageHealth,
+    upstream: Vec<upstream::ProviderHealth>,
+
+    /// As of their last heartbeats, when on in conf.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers: Option<Vec<peers::Status>>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct StorageHealth {
+    is_reachable: bool,
+    latency_ms: u128,
+    error: Option<String>,
+}
+
+/// Readiness: dependencies are usable. Ready as long as the storage is and
+/// at least one of the providers is not known to be down.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "public",
+    security(()),
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, body = Readiness),
+    )
+)]
+#[tracing::instrument(skip_all, fields(req_id = REQ_ID.get().req_id))]
+async fn handle_health_ready(
+    State(AppState {
+        storage,
+        http,
+        upstream: upstream_state,
+        ..
+    }): State<AppState>,
+) -> (StatusCode, Json<Readiness>) {
+    let conf = conf::global();
+    let storage = storage_health(storage.as_ref()).await;
+    let probe_timeout = conf
+        .health
+        .probe_upstream
+        .then(|| Duration::from_secs_f32(conf.health.probe_timeout));
+    let upstream = upstream_state.health(&http, probe_timeout).await;
+    let is_upstream_ready = upstream.iter().any(|provider| {
+        provider.circuit != "open" && provider.is_reachable != Some(false)
+    });
+    let is_ready = storage.is_reachable && is_upstream_ready;
+    let is_exhausted = upstream_state.is_exhausted();
+    let code = if is_ready {
+        StatusCode::OK
+    } else {
+        tracing::warn!(?storage.error, ?upstream, "Not ready.");
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        is_ready,
+        is_exhausted,
+        storage,
+        upstream,
+        peers: peers::global().map(peers::Peers::statuses),
+    };
+    (code, Json(readiness))
+}
+
+async fn storage_health(storage: &dyn Storage) -> StorageHealth {
+    let started = Instant::now();
+    let result = storage.ping().await;
+    StorageHealth {
+        is_reachable: result.is_ok(),
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(|error| format!("{error:#}")),
+    }
+}
+
+/// In the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "public",
+    security(()),
+    responses((status = 200, content_type = "text/plain", body = String))
+)]
+async fn handle_metrics(
+    State(AppState { metrics, .. }): State<AppState>,
+) -> String {
+    metrics.render()
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct Completion {
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+
+    /// The latest when not given.
+    version: Option<i64>,
+
+    /// To forward the rendered chat request to.
+    #[serde(default = "default_completion_endpoint")]
+    endpoint: String,
+
+    /// Max tokens, temperature, model, etc., as in a chat request.
+    #[serde(flatten)]
+    rest: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_completion_endpoint() -> String {
+    "v1/chat/completions".to_string()
+}
+
+/// Renders the user's template, or else their org's, and handles it as a
+/// chat request, with the same headers.
+#[utoipa::path(
+    post,
+    path = "/templates/{name}/complete",
+    tag = "api",
+    params(("name" = String, Path)),
+    request_body = Completion,
+    responses(
+        (status = 200, description = "As of chat completions."),
+        (status = 404, description = "No such template."),
+    )
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn handle_template_complete(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    req: Request,
+) -> Result<Response> {
+    let user = USER.get();
+    let (mut parts, body) = req.into_parts();
+    let Json(completion) = Json::<Completion>::from_request(
+        Request::from_parts(parts.clone(), body),
+        &(),
+    )
+    .await?;
+    let owners = [
+        Some(data::Owner::User(&user.uid)),
+        user.org.as_deref().map(data::Owner::Org),
+    ];
+    let mut template = None;
+    for owner in owners.into_iter().flatten() {
+        template = state
+            .storage
+            .template_get(owner, &name, completion.version)
+            .await
+            .map_err(|error| {
+                tracing::error!(?error, "Failed to get template.");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+        if template.is_some() {
+            break;
+        }
+    }
+    let Some(template) = template else {
+        tracing::warn!(name, "Rejecting. No such template.");
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let body: template::Body =
+        serde_json::from_str(&template.body).map_err(|error| {
+            tracing::error!(?error, name, "Invalid template.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let messages = body.render(&completion.variables).map_err(|missing| {
+        tracing::warn!(?missing, "Rejecting. Template variables missing.");
+        let missing: Vec<String> = missing.into_iter().collect();
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "template_variables_missing",
+            format!("Missing template variables: {}.", missing.join(", ")),
+        );
+        (StatusCode::BAD_REQUEST, Json(error))
+    })?;
+    let mut chat_req = completion.rest;
+    if let (false, Some(model)) = (chat_req.contains_key("model"), body.model)
+    {
+        chat_req.insert("model".to_string(), model.into());
+    }
+    chat_req.insert(
+        "messages".to_string(),
+        serde_json::to_value(messages).map_err(|error| {
+            tracing::error!(?error, "Failed to encode messages.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    );
+    let chat_req = serde_json::to_vec(&chat_req).map_err(|error| {
+        tracing::error!(?error, "Failed to encode chat request.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(name, version = template.version, "Template rendered.");
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let req = Request::from_parts(parts, Body::from(chat_req));
+    handle_api(State(state), connect_info, Path(completion.endpoint), req)
+        .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+/// Chat completions, parsed, checked against the role's limits and charged
+/// by their usage. Others of upstream's endpoints, such as audio ones or
+/// those passed through per conf, are forwarded likewise at their own
+/// paths.
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions",
+    tag = "api",
+    operation_id = "chat_completions",
+    params(
+        ("idempotency-key" = Option<String>, Header,
+            description = "To replay the response of a repeated request."),
+        ("x-raskol-session" = Option<String>, Header,
+            description = "Conversation the request is part of."),
+        ("x-raskol-timeout" = Option<f64>, Header,
+            description = "Seconds to wait on upstream, within the role's."),
+        ("x-raskol-dry-run" = Option<bool>, Header,
+            description = "For a synthetic completion, if the role may."),
+    ),
+    request_body = chat::Req,
+    responses(
+        (status = 200, description = "Upstream's, as is.",
+            body = serde_json::Value,
+            headers(
+                ("x-raskol-cost" = String,
+                    description = "In dollars, when the model is priced."),
+                ("x-raskol-fallback" = String,
+                    description = "Model used instead of the requested."),
+                ("x-raskol-queued-ms" = u64,
+                    description = "Held in the queue for, if at all."),
+                ("x-raskol-overage" = String,
+                    description = "\"degraded\", when past the budget."),
+                ("x-raskol-model" = String,
+                    description = "Used, of streams, with provenance on."),
+                ("x-raskol-provider" = String,
+                    description = "Which, of streams, with provenance on."),
+                ("x-ratelimit-remaining-tokens" = u64,
+                    description = "Of the daily budget."),
+            )),
+        (status = 400, body = chat::Error),
+        (status = 403, body = chat::Error),
+        (status = 422, body = chat::Error),
+        (status = 429, body = chat::Error),
+        (status = 503, body = chat::Error),
+        (status = 504, body = chat::Error),
+    )
+)]
+pub(crate) async fn handle_api(
+    State(AppState {
+        storage,
+        http,
+        upstream,
+        events,
+        queue,
+        hooks,
+        duplicates,
+        ..
+    }): State<AppState>,
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    Path(endpoint): Path<String>,
+    req: Request,
+) -> Result<Response> {
+    tracing::info!(?from, "Handling API request.");
+    let conf = conf::global();
+    let user: User = USER.get();
+    let idempotency_key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => None,
+        Some(value) => Some(idempotency_key_valid(value)?),
+    };
+    let mut session = match req.headers().get(SESSION_HEADER) {
+        None => None,
+        Some(value) => Some(session_valid(value.to_str().ok())?),
+    };
+    let timeout = upstream_timeout(
+        &conf,
+        &user.role,
+        req.headers().get(TIMEOUT_HEADER),
+    )?;
+    upstream_allowed(&user.role, &endpoint)?;
+    let is_dry_run =
+        dry_run_asked(&conf, &user.role, req.headers().get(DRY_RUN_HEADER))?;
+    let hook_ctx = hook::Context {
+        uid: user.uid.clone(),
+        role: user.role.clone(),
+        req_id: REQ_ID.get().req_id,
+        endpoint: endpoint.clone(),
+    };
+
+    if let (false, Some(content_type)) = (
+        audio::is_endpoint(&endpoint),
+        files::content_type(req.headers()),
+    ) {
+        if is_dry_run {
+            return Err(dry_run_of_other(&endpoint).into());
+        }
+        spend_capped(&upstream, false)?;
+        let content_type = content_type.to_string();
+        return handle_upload(
+            storage.as_ref(),
+            &http,
+            &upstream,
+            &events,
+            endpoint,
+            &content_type,
+            session,
+            req,
+        )
+        .await
+        .map(IntoResponse::into_response);
+    }
+
+    // Dry runs draw on no limits, but those of the user's requests.
+    if !is_dry_run {
+        spend_capped(&upstream, true)?;
+    }
+    // Audio uploads and other endpoints are passed through as is. Chat is
+    // parsed.
+    let upload;
+    let raw;
+    let chat_req;
+    let mut is_system_prompt_injected = false;
+    // Before calibration. Only of chat, since audio is not tokenized.
+    let mut tokens_estimate = None;
+    // Name, as configured, and arm.
+    let mut experiment: Option<(&String, &conf::Experiment, &str)> = None;
+    // Of chat served past the budget.
+    let mut degraded: Option<overage::Degraded> = None;
+    // Of chat, by the client.
+    let mut tags = BTreeMap::new();
+    // Of chat which would have overflowed the context.
+    let mut trimmed: Option<compress::Trimmed> = None;
+    let Some(kind) = endpoint::classify(&conf, &endpoint) else {
+        tracing::warn!(endpoint, "Rejecting. Unknown endpoint.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "unknown_endpoint",
+            format!("Endpoint {endpoint:?} is not supported."),
+        );
+        return Err((StatusCode::NOT_FOUND, Json(error)).into());
+    };
+    let max_body_bytes =
+        endpoint::max_body_bytes(&conf, &user.role, &endpoint, kind);
+    let too_large = |code: StatusCode| {
+        if code == StatusCode::PAYLOAD_TOO_LARGE {
+            body_too_large(&endpoint, max_body_bytes)
+        } else {
+            code.into_response()
+        }
+    };
+    let (model, payload, token_count) = if kind == endpoint::Kind::Audio {
+        upload = audio::Upload::from_req(req, max_body_bytes)
+            .await
+            .map_err(too_large)?;
+        let duration = upload.duration_estimate(&conf.audio);
+        let payload = upstream::Payload::Raw {
+            model: &upload.model,
+            content_type: &upload.content_type,
+            body: upload.body.clone(),
+        };
+        (&upload.model, payload, audio::tokens(&conf.audio, duration))
+    } else if kind == endpoint::Kind::Raw {
+        raw = endpoint::Raw::from_req(req, max_body_bytes)
+            .await
+            .map_err(too_large)?;
+        let payload = upstream::Payload::Raw {
+            model: &raw.model,
+            content_type: &raw.content_type,
+            body: raw.body.clone(),
+        };
+        let tokens = raw.tokens_estimate(&conf.passthrough);
+        (&raw.model, payload, tokens)
+    } else {
+        if !is_json_typed(req.headers()) {
+            return Err(not_json().into());
+        }
+        let body = axum::body::to_bytes(req.into_body(), max_body_bytes)
+            .await
+            .map_err(|error| {
+                tracing::warn!(?error, "Failed to read request body.");
+                too_large(body_unread(&error))
+            })?;
+        let prepared = chat_prepare(
+            &conf,
+            storage.as_ref(),
+            &hooks,
+            &hook_ctx,
+            &duplicates,
+            is_dry_run,
+            idempotency_key.as_deref(),
+            &mut session,
+            &body,
+        )
+        .await?;
+        tags = prepared.tags;
+        is_system_prompt_injected = prepared.is_system_prompt_injected;
+        experiment = prepared.experiment;
+        trimmed = prepared.trimmed;
+        tokens_estimate = Some(prepared.tokens_estimate);
+        degraded = prepared.degraded;
+        chat_req = prepared.req;
+        (
+            &chat_req.model,
+            upstream::Payload::Chat(&chat_req),
+            prepared.token_count,
+        )
+    };
+
+    // As requested, since the experiment, and the overage, are ours.
+    let requested = experiment.map_or_else(
+        || {
+            degraded
+                .as_ref()
+                .map_or(model, |degraded| &degraded.requested)
+        },
+        |(_, experiment, _)| &experiment.model,
+    );
+    let (experiment, arm) = match experiment {
+        Some((name, _, arm)) => (Some(name.clone()), Some(arm.to_string())),
+        None => (None, None),
+    };
+    let path = format!("/{endpoint}");
+    // Passed through requests need not be of any.
+    let checked = Some(requested.as_str())
+        .filter(|model| kind != endpoint::Kind::Raw || !model.is_empty());
+    if !auth::authorize(&conf, &user.role, &path, checked) {
+        return Err(model_not_allowed(&conf, &user.role, requested).into());
+    }
+    deprecation::note_model(requested);
+    if let upstream::Payload::Chat(chat_req) = &payload {
+        let needed = chat_req.features();
+        if let Some(feature) = missing_feature(&conf, &user, &needed) {
+            return Err(feature_not_allowed(feature).into());
+        }
+    }
+
+    let is_moderated = conf
+        .roles
+        .get(&user.role)
+        .is_some_and(|role| role.is_moderated);
+    if let (true, Some(moderation), upstream::Payload::Chat(chat_req)) =
+        (is_moderated, &conf.moderation, &payload)
+    {
+        let flagged = moderation::check(&http, moderation, chat_req)
+            .await
+            .map_err(|error| {
+                // Fail closed, since moderation was asked for.
+                tracing::error!(?error, "Failed to moderate.");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+        if !flagged.is_empty() {
+            let flagged = flagged.join(", ");
+            tracing::warn!(flagged, "Rejecting. Flagged by moderation.");
+            let log = data::RequestLog {
+                session: session.clone(),
+                experiment: experiment.clone(),
+                arm: arm.clone(),
+                tags: tags.clone(),
+                ..rejected_log(
+                    &user,
+                    model,
+                    &endpoint,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &format!("Flagged: {flagged}"),
+                    data::ErrorClass::Validation,
+                )
+            };
+            log_request(storage.as_ref(), &log).await;
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "content_flagged",
+                format!("Flagged by moderation for: {flagged}."),
+            );
+            return Err(
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into()
+            );
+        }
+    }
+
+    if let upstream::Payload::Chat(chat_req) = &payload {
+        context_fits(&conf, chat_req, token_count)?;
+    }
+
+    if is_dry_run {
+        let upstream::Payload::Chat(chat_req) = &payload else {
+            return Err(dry_run_of_other(&endpoint).into());
+        };
+        tracing::info!(model, token_count, "Dry run. Not forwarding.");
+        metrics::counter!("raskol_dry_runs_total").increment(1);
+        let mut resp = mock::reply(chat_req, token_count);
+        resp.headers_mut()
+            .insert(DRY_RUN_HEADER, header::HeaderValue::from_static("true"));
+        return Ok(resp);
+    }
+
+    if let Some(key) = &idempotency_key {
+        let fingerprint = fingerprint(&endpoint, &payload);
+        let replayed =
+            idempotency_claim(storage.as_ref(), &user, key, &fingerprint)
+                .await?;
+        if let Some(replayed) = replayed {
+            return Ok(replayed);
+        }
+    }
+
+    //
+    // Budget:
+    // 1. reserve the estimate from the budget
+    // 2. make request
+    // 3. settle the reservation with the actual usage (or refund on failure
+    //    or disconnect)
+    //
+    let started = Instant::now();
+    events.publish(Event::RequestStarted {
+        req_id: REQ_ID.get().req_id,
+        uid: user.uid.clone(),
+        model: model.clone(),
+        endpoint: endpoint.clone(),
+    });
+    let price = conf.pricing.get(model).copied();
+    let estimate = data::Amount {
+        tokens: token_count,
+        // Output is unknown until we get the response.
+        cost: price.map_or(0.0, |price| price.cost(token_count, 0)),
+    };
+    // Past the budget, but within the margin, when degraded.
+    let overrides = data::BudgetOverrides {
+        overage_tokens: degraded
+            .as_ref()
+            .map_or(0, |degraded| degraded.margin),
+        ..user.budget_overrides
+    };
+    let reservation = storage
+        .budget_reserve(
+            &user.uid,
+            user.org.as_deref(),
+            &user.role,
+            overrides,
+            model,
+            estimate,
+        )
+        .await;
+    let reservation = match reservation {
+        Ok(reservation) => reservation,
+        Err(error) => {
+            tracing::error!(?error, "Failed to hit storage.");
+            idempotency_settle(&storage, &user, idempotency_key, None).await;
+            return Err(StatusCode::SERVICE_UNAVAILABLE.into());
+        }
+    };
+    let reservation = match reservation {
+        Ok(reservation) => reservation,
+        Err(exceeded) => {
+            tracing::warn!(
+                token_count,
+                ?exceeded,
+                "Rejecting. Budget exceeded."
+            );
+            idempotency_settle(&storage, &user, idempotency_key, None).await;
+            let (error, headers) = budget_rejected(
+                storage.as_ref(),
+                &user,
+                exceeded,
+                model,
+                estimate,
+            )
+            .await;
+            let log = data::RequestLog {
+                session: session.clone(),
+                experiment: experiment.clone(),
+                arm: arm.clone(),
+                tags: tags.clone(),
+                ..rejected_log(
+                    &user,
+                    model,
+                    &endpoint,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &error.error.message,
+                    data::ErrorClass::Budget,
+                )
+            };
+            log_request(storage.as_ref(), &log).await;
+            events.publish(Event::BudgetRejected(log));
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                Json(error),
+            )
+                .into());
+        }
+    };
+    let unsettled = Unsettled {
+        storage: storage.clone(),
+        events: events.clone(),
+        reservation,
+        idempotency_key: idempotency_key.clone(),
+        req_id: REQ_ID.get().req_id,
+        client_ip: REQ_ID.get().client_ip,
+        endpoint: endpoint.clone(),
+        session: session.clone(),
+        experiment: experiment.clone(),
+        arm: arm.clone(),
+        tags: tags.clone(),
+        started,
+        is_forwarded: AtomicBool::new(false),
+        is_settled: false,
+    };
+    if let (Some(shadow), upstream::Payload::Chat(chat_req)) =
+        (&conf.shadow, &payload)
+    {
+        if shadow.is_mirrored(&user.role) {
+            tokio::spawn(conf::carry(mirror(
+                storage.clone(),
+                http.clone(),
+                upstream.clone(),
+                events.clone(),
+                REQ_ID.get().req_id,
+                endpoint.clone(),
+                (*chat_req).clone(),
+            )));
+        }
+    }
+    // Passed on as it arrives, unless it is to be validated, or filtered,
+    // whole.
+    let streamed = match &payload {
+        upstream::Payload::Chat(chat_req)
+            if chat_req.is_stream()
+                && !conf.validate_json_output
+                && filter::global().is_none() =>
+        {
+            Some(*chat_req)
+        }
+        _ => None,
+    };
+    // Otherwise asked of upstream whole, and streamed to the client after,
+    // all at once.
+    let unstreamed;
+    let mut as_events = None;
+    let payload = match payload {
+        upstream::Payload::Chat(chat_req)
+            if chat_req.is_stream() && streamed.is_none() =>
+        {
+            as_events = Some(chat_req);
+            unstreamed = chat_req.unstreamed();
+            upstream::Payload::Chat(&unstreamed)
+        }
+        payload => payload,
+    };
+    let forward = {
+        let (http, endpoint, payload) = (&http, &endpoint, &payload);
+        let upstream = &upstream;
+        move || async move {
+            match streamed {
+                Some(chat_req) => upstream
+                    .forward_streaming(
+                        http,
+                        endpoint,
+                        &chat_req.usage_streamed(),
+                    )
+                    .await
+                    .map(Forwarding::Streaming),
+                None => upstream
+                    .forward(http, endpoint, payload)
+                    .await
+                    .map(Forwarding::Whole),
+            }
+        }
+    };
+    // Of all attempts, retries included.
+    let deadline =
+        timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let (result, mut queued) = forward_queued(
+        &upstream,
+        queue.as_deref(),
+        &user,
+        deadline,
+        &unsettled.is_forwarded,
+        forward,
+    )
+    .await;
+    if !queued.is_zero() {
+        slow::note_queued_ms(
+            i64::try_from(queued.as_millis()).unwrap_or(i64::MAX),
+        );
+    }
+    let result = match result {
+        Ok(Forwarding::Whole(forwarded)) => Ok(forwarded),
+        Ok(Forwarding::Streaming(received)) => {
+            let capture = conf
+                .capture
+                .clone()
+                .filter(|capture| capture.is_captured(&user.uid))
+                .zip(streamed.cloned());
+            let streaming = Streaming {
+                storage: storage.clone(),
+                events: events.clone(),
+                upstream: upstream.clone(),
+                user: user.clone(),
+                model: model.clone(),
+                price,
+                token_count,
+                tokens_estimate,
+                overrides,
+                is_usage_passed: streamed
+                    .is_some_and(chat::Req::is_usage_streamed),
+                hooks: hooks.clone(),
+                hook_ctx: hook_ctx.clone(),
+                attestation: conf.attestation.clone(),
+                capture,
+                unsettled,
+            };
+            let provider = received.provider;
+            let mut resp = streaming.respond(received);
+            let headers = resp.headers_mut();
+            if conf.provenance {
+                if let Ok(value) = header::HeaderValue::from_str(model) {
+                    headers.insert(provenance::MODEL_HEADER, value);
+                }
+                headers.insert(
+                    provenance::PROVIDER_HEADER,
+                    header::HeaderValue::from_static(provider),
+                );
+            }
+            headers.extend(served_headers(
+                is_system_prompt_injected,
+                trimmed,
+                degraded.is_some(),
+                queued,
+            ));
+            let budget = token_budget(storage.as_ref(), &user).await;
+            resp.headers_mut()
+                .extend(rate_limit_headers(budget.as_ref()));
+            return Ok(resp);
+        }
+        Err(failed) => Err(failed),
+    };
+    let (mut result, mut error_class) = classified(result);
+    // Usage of responses not passed on to the client, but still paid for.
+    let mut discarded = (0, 0);
+    let chat_req = match &payload {
+        upstream::Payload::Chat(chat_req) if conf.validate_json_output => {
+            Some(chat_req)
+        }
+        _ => None,
+    };
+    if let Some(chat_req) = chat_req {
+        let mut attempt = 1;
+        while let Ok(forwarded) = &result {
+            let Err(reason) = chat_req.check_json_output(&forwarded.body)
+            else {
+                break;
+            };
+            if let Some(usage) = chat::Usage::from_resp_body(&forwarded.body)
+            {
+                discarded.0 += usage.prompt_tokens;
+                discarded.1 += usage.completion_tokens;
+            }
+            if attempt >= 2 {
+                tracing::error!(reason, "Invalid JSON output. Giving up.");
+                result = Err(StatusCode::BAD_GATEWAY);
+                // Not the client's fault, as far as it's concerned.
+                error_class = Some(data::ErrorClass::Upstream5xx);
+                break;
+            }
+            tracing::warn!(reason, "Invalid JSON output. Retrying.");
+            // As the first attempt was: queued, and by the same deadline.
+            let forward = {
+                let (http, endpoint, payload) = (&http, &endpoint, &payload);
+                let upstream = &upstream;
+                move || upstream.forward(http, endpoint, payload)
+            };
+            let (retried, waited) = forward_queued(
+                &upstream,
+                queue.as_deref(),
+                &user,
+                deadline,
+                &unsettled.is_forwarded,
+                forward,
+            )
+            .await;
+            queued += waited;
+            (result, error_class) = classified(retried);
+            attempt += 1;
+        }
+    }
+    // Of whole completions, those streamed having been passed on already.
+    let mut filtered = None;
+    let mut rejected = None;
+    if let (Some(filter), upstream::Payload::Chat(chat_req), Ok(forwarded)) =
+        (filter::global(), &payload, &mut result)
+    {
+        match filter.check(chat_req, &forwarded.body) {
+            None => {}
+            Some(filter::Outcome::Altered { body, violations }) => {
+                log_violations(&user.uid, &violations);
+                forwarded.body = body;
+                filtered = Some(filter.action);
+            }
+            Some(filter::Outcome::Unaltered { violations }) => {
+                log_violations(&user.uid, &violations);
+            }
+            Some(filter::Outcome::Rejected { violations }) => {
+                log_violations(&user.uid, &violations);
+                if let Some(usage) =
+                    chat::Usage::from_resp_body(&forwarded.body)
+                {
+                    discarded.0 += usage.prompt_tokens;
+                    discarded.1 += usage.completion_tokens;
+                }
+                result = Err(StatusCode::BAD_GATEWAY);
+                // Not the client's fault, as far as it's concerned.
+                error_class = Some(data::ErrorClass::Upstream5xx);
+                let rules: Vec<&str> =
+                    violations.iter().map(|v| v.rule.as_str()).collect();
+                rejected = Some(chat::Error::new(
+                    "server_error",
+                    "response_rejected",
+                    format!(
+                        "The completion was rejected for: {}.",
+                        rules.join(", ")
+                    ),
+                ));
+            }
+        }
+    }
+    let ((input_tokens, output_tokens), timing, seconds) = usage_of(
+        storage.as_ref(),
+        &result,
+        &payload,
+        kind,
+        model,
+        token_count,
+        tokens_estimate,
+    )
+    .await;
+    let input_tokens = input_tokens.saturating_add(discarded.0);
+    let output_tokens = output_tokens.saturating_add(discarded.1);
+    // Charged for what was actually used.
+    let model = result
+        .as_ref()
+        .ok()
+        .and_then(|forwarded| forwarded.fallback_model.as_ref())
+        .unwrap_or(model);
+    let price = conf.pricing.get(model).copied();
+    let used = data::Amount {
+        tokens: input_tokens.saturating_add(output_tokens),
+        cost: price
+            .map_or(0.0, |price| price.cost(input_tokens, output_tokens)),
+    };
+    let reservation = unsettled.settle();
+    // XXX If settling fails - we don't want to fail the request, so we might
+    //     end-up charging the estimate instead of the actual usage.
+    if let Err(error) = storage.budget_settle(&reservation, used).await {
+        tracing::error!(
+            ?error,
+            ?reservation,
+            ?used,
+            "Failed to settle budget!"
+        );
+    }
+    if let (Some(spend), Ok(forwarded)) = (upstream.spend(), &result) {
+        spend
+            .record(storage.as_ref(), &events, forwarded.provider, used)
+            .await;
+    }
+    let log = data::RequestLog {
+        req_id: REQ_ID.get().req_id,
+        uid: user.uid.clone(),
+        model: model.clone(),
+        endpoint: endpoint.clone(),
+        status: match &result {
+            Ok(upstream::Forwarded { code, .. }) | Err(code) => {
+                i64::from(code.as_u16())
+            }
+        },
+        input_tokens: i64::try_from(input_tokens).unwrap_or(i64::MAX),
+        output_tokens: i64::try_from(output_tokens).unwrap_or(i64::MAX),
+        cost: used.cost,
+        duration_ms: i64::try_from(started.elapsed().as_millis())
+            .unwrap_or(i64::MAX),
+        time: unix_now_secs(),
+        error_message: result
+            .as_ref()
+            .err()
+            .and_then(|code| code.canonical_reason())
+            .map(redact::error_message),
+        session,
+        experiment,
+        arm,
+        timing,
+        error_class,
+        client_ip: REQ_ID.get().client_ip,
+        tags,
+        usage: match (seconds, &result) {
+            (Some(seconds), Ok(forwarded)) => Some(data::Usage {
+                provider: Some(forwarded.provider.to_string()),
+                unit: data::Unit::Seconds,
+                input: seconds,
+                output: 0.0,
+                detail: None,
+            }),
+            // Of discarded responses too, though it then failed.
+            _ if used.tokens > 0 => Some(data::Usage::tokens(
+                result.as_ref().ok().map(|forwarded| forwarded.provider),
+                input_tokens,
+                output_tokens,
+            )),
+            _ => None,
+        },
+    };
+    if let Ok(forwarded) = &result {
+        slow::note_response_bytes(forwarded.body.len());
+    }
+    log_request(storage.as_ref(), &log).await;
+    if let (Some(capture), upstream::Payload::Chat(chat_req)) =
+        (&conf.capture, &payload)
+    {
+        if capture.is_captured(&user.uid) {
+            let response = result.as_ref().ok().map(|f| f.body.as_str());
+            capture_bodies(
+                storage.as_ref(),
+                capture,
+                &log,
+                chat_req,
+                response,
+            )
+            .await;
+        }
+    }
+    if let Err(code) = &result {
+        events.publish(Event::UpstreamError {
+            req_id: log.req_id.clone(),
+            uid: log.uid.clone(),
+            model: log.model.clone(),
+            status: code.as_u16(),
+        });
+    }
+    events.publish(Event::RequestFinished(log));
+    // Before the response is stored, so that replays match.
+    if let Ok(forwarded) = &mut result {
+        hooks.post_response(&hook_ctx, forwarded).await;
+    }
+    if let (true, Ok(forwarded)) = (conf.provenance, &mut result) {
+        let provenance = provenance::Provenance {
+            req_id: hook_ctx.req_id.clone(),
+            model: model.clone(),
+            requested: requested.clone(),
+            provider: forwarded.provider,
+            input_tokens,
+            output_tokens,
+            cost: price.map(|_| used.cost),
+            is_fallback: forwarded.fallback_model.is_some(),
+            is_failover: forwarded.provider == "failover",
+            is_degraded: degraded.is_some(),
+        };
+        if let Some(body) = provenance::inject(&forwarded.body, &provenance) {
+            forwarded.body = body;
+        }
+    }
+    idempotency_settle(
+        &storage,
+        &user,
+        idempotency_key,
+        result.as_ref().ok(),
+    )
+    .await;
+    if let Some(error) = rejected {
+        return Err((StatusCode::BAD_GATEWAY, Json(error)).into());
+    }
+    let upstream::Forwarded {
+        code,
+        body,
+        headers,
+        fallback_model,
+        ..
+    } = result?;
+    let mut resp = Response::builder().status(code);
+    let events = as_events.and_then(|chat_req| chat_req.events_of(&body));
+    let body = if let Some(events) = events {
+        resp = resp.header(header::CONTENT_TYPE, "text/event-stream");
+        events
+    } else {
+        if is_json(&body) {
+            resp = resp.header(header::CONTENT_TYPE, "application/json");
+        }
+        body
+    };
+    if let Some(fallback_model) = fallback_model {
+        resp = resp.header("x-raskol-fallback", fallback_model);
+    }
+    match filtered {
+        Some(conf::FilterAction::Redact) => {
+            resp = resp.header("x-raskol-filtered", "redacted");
+        }
+        Some(conf::FilterAction::Annotate) => {
+            resp = resp.header("x-raskol-filtered", "annotated");
+        }
+        Some(conf::FilterAction::Reject) | None => {}
+    }
+    if price.is_some() {
+        // In dollars, as priced in conf.
+        resp = resp.header("x-raskol-cost", format!("{:.6}", used.cost));
+    }
+    if let Some(attestation) = &conf.attestation {
+        let value = attest::attest(
+            &attestation.secret,
+            &user.uid,
+            &hook_ctx.req_id,
+            unix_now_secs(),
+            body.as_bytes(),
+        );
+        resp = resp.header(attest::HEADER, value);
+    }
+    let mut resp = resp.body(body).map_err(|error| {
+        tracing::error!(?error, ?code, "Failed to build response.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    resp.headers_mut().extend(headers);
+    resp.headers_mut().extend(served_headers(
+        is_system_prompt_injected,
+        trimmed,
+        degraded.is_some(),
+        queued,
+    ));
+    let budget = token_budget(storage.as_ref(), &user).await;
+    resp.headers_mut()
+        .extend(rate_limit_headers(budget.as_ref()));
+    Ok(resp.into_response())
+}
+
+/// Of a whole response, the prompt and completion tokens, as upstream says,
+/// or else as estimated. Also where upstream's time went, if it says, as
+/// Groq does, and of audio, the seconds as upstream measured them.
+async fn usage_of(
+    storage: &dyn Storage,
+    result: &Result<upstream::Forwarded, StatusCode>,
+    payload: &upstream::Payload<'_>,
+    kind: endpoint::Kind,
+    model: &str,
+    token_count: usize,
+    tokens_estimate: Option<usize>,
+) -> ((usize, usize), Option<data::Timing>, Option<f64>) {
+    let body = match result {
+        Ok(upstream::Forwarded { body, .. }) => body,
+        Err(_) => return ((0, 0), None, None),
+    };
+    match payload {
+        upstream::Payload::Chat(_) => match chat::Usage::from_resp_body(body)
+        {
+            Some(usage) => {
+                if let Some(estimate) = tokens_estimate {
+                    record_tokens_estimate(storage, model, estimate).await;
+                }
+                let tokens = (usage.prompt_tokens, usage.completion_tokens);
+                (tokens, usage.timing(), None)
+            }
+            None => ((token_count, 0), None, None),
+        },
+        upstream::Payload::Raw { .. } if kind == endpoint::Kind::Audio => {
+            let seconds = audio::duration_from_resp_body(body);
+            let tokens = seconds.map_or(token_count, |duration| {
+                audio::tokens(&conf::global().audio, duration)
+            });
+            ((tokens, 0), None, seconds)
+        }
+        upstream::Payload::Raw { .. } => {
+            let tokens = endpoint::usage_from_resp_body(body);
+            (tokens.unwrap_or((token_count, 0)), None, None)
+        }
+    }
+}
+
+/// Of what we did to the request on the way: streamed or whole alike.
+fn served_headers(
+    is_system_prompt_injected: bool,
+    trimmed: Option<compress::Trimmed>,
+    is_degraded: bool,
+    queued: Duration,
+) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if is_system_prompt_injected {
+        headers.insert(
+            "x-raskol-system-prompt",
+            header::HeaderValue::from_static("injected"),
+        );
+    }
+    if let Some(trimmed) = trimmed {
+        if let Ok(value) = header::HeaderValue::from_str(&trimmed.to_string())
+        {
+            headers.insert("x-raskol-compressed", value);
+        }
+    }
+    if is_degraded {
+        headers.insert(
+            "x-raskol-overage",
+            header::HeaderValue::from_static("degraded"),
+        );
+    }
+    if queued.as_millis() > 0 {
+        let queued = u64::try_from(queued.as_millis()).unwrap_or(0);
+        headers.insert("x-raskol-queued-ms", queued.into());
+    }
+    headers
+}
+
+/// Of a chat request, as parsed, checked and made ready for upstream.
+struct Prepared<'a> {
+    req: chat::Req,
+
+    /// By the client.
+    tags: BTreeMap<String, String>,
+    is_system_prompt_injected: bool,
+
+    /// Name, as configured, and arm.
+    experiment: Option<(&'a String, &'a conf::Experiment, &'static str)>,
+
+    /// Of what would have overflowed the context.
+    trimmed: Option<compress::Trimmed>,
+
+    /// Before calibration.
+    tokens_estimate: usize,
+    token_count: usize,
+
+    /// Served past the budget.
+    degraded: Option<overage::Degraded>,
+}
+
+/// Of the body of a chat request: filled in with the user's preferences,
+/// checked against the role's limits, and made ready for upstream, with
+/// what's ours injected and the prompt compressed. The session of its
+/// metadata, unless already given.
+#[allow(clippy::too_many_arguments)]
+async fn chat_prepare<'a>(
+    conf: &'a Conf,
+    storage: &dyn Storage,
+    hooks: &hook::Hooks,
+    hook_ctx: &hook::Context,
+    duplicates: &duplicates::Duplicates,
+    is_dry_run: bool,
+    idempotency_key: Option<&str>,
+    session: &mut Option<String>,
+    body: &[u8],
+) -> Result<Prepared<'a>> {
+    let user: User = USER.get();
+    let mut is_system_prompt_injected = false;
+    let mut experiment = None;
+    let mut trimmed = None;
+    let mut degraded = None;
+    let mut req: serde_json::Value =
+        serde_json::from_slice(body).map_err(invalid_json)?;
+    if preferences::is_lacking(&req) {
+        if let Some(preferences) = preferences::get(storage, &user.uid).await
+        {
+            preferences.fill(&mut req);
+        }
+    }
+    let problems = lint::chat(&req, conf.strict_requests);
+    if !problems.is_empty() {
+        return Err(invalid_problems(problems).into());
+    }
+    let mut req: chat::Req =
+        serde_json::from_value(req).map_err(invalid_req)?;
+    if let Some(limits) = conf.limits.per_role.get(&user.role) {
+        req.constrain(limits).map_err(|message| {
+            tracing::warn!(message, "Rejecting. Over role limits.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "role_limit_exceeded",
+                message,
+            );
+            (StatusCode::BAD_REQUEST, Json(error))
+        })?;
+    }
+    let tags = req.take_tags().map_err(|message| {
+        tracing::warn!(message, "Rejecting. Invalid tags.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "invalid_tags",
+            message,
+        );
+        (StatusCode::BAD_REQUEST, Json(error))
+    })?;
+    // As the client sent it, before anything of ours is injected.
+    if let (false, Some(duplicates_conf)) = (is_dry_run, &conf.duplicates) {
+        duplicates_check(
+            duplicates,
+            duplicates_conf,
+            &user,
+            &req,
+            idempotency_key,
+        )?;
+    }
+    if let Some(prompt) = conf
+        .roles
+        .get(&user.role)
+        .and_then(|role| role.system_prompt.as_ref())
+    {
+        req.inject_system_prompt(prompt);
+        is_system_prompt_injected = true;
+    }
+    if session.is_none() {
+        *session = req
+            .rest
+            .get("metadata")
+            .and_then(|metadata| metadata.get("session"))
+            .map(|value| session_valid(value.as_str()))
+            .transpose()?;
+    }
+    if let Some((name, experiment_conf)) =
+        conf.experiments.iter().find(|(_, e)| e.model == req.model)
+    {
+        let arm = if experiment_conf.is_alternative() {
+            req.model.clone_from(&experiment_conf.alternative);
+            "alternative"
+        } else {
+            "control"
+        };
+        tracing::debug!(experiment = name, arm, model = req.model);
+        experiment = Some((name, experiment_conf, arm));
+    }
+    hooks.pre_forward(hook_ctx, &mut req).await?;
+    if let Some(compression) = &conf.prompt_compression {
+        let window = conf.context_windows.get(&req.model).copied();
+        trimmed = compress::compress(compression, &mut req, window, |req| {
+            tokenizer::global().calibrate(
+                &req.model,
+                req.tokens_estimate(tokenizer::for_prompts(conf)),
+            )
+        });
+        if let Some(trimmed) = trimmed {
+            tracing::info!(%trimmed, "Compressed the prompt.");
+        }
+    }
+    let estimate = req.tokens_estimate(tokenizer::for_prompts(conf));
+    let token_count = tokenizer::global().calibrate(&req.model, estimate);
+    if let (false, Some(overage_conf)) = (is_dry_run, &conf.overage) {
+        degraded = overage_check(
+            storage,
+            overage_conf,
+            &user,
+            token_count,
+            &mut req,
+        )
+        .await?;
+    }
+    Ok(Prepared {
+        req,
+        tags,
+        is_system_prompt_injected,
+        experiment,
+        trimmed,
+        tokens_estimate: estimate,
+        token_count,
+        degraded,
+    })
+}
+
+/// Multipart uploads to file APIs, streamed upstream as they arrive. Not
+/// charged against budgets, since files use no tokens until requests refer
+/// to them.
+#[allow(clippy::too_many_arguments)]
+async fn handle_upload(
+    storage: &dyn Storage,
+    http: &reqwest::Client,
+    upstream: &Upstream,
+    events: &events::Events,
+    endpoint: String,
+    content_type: &str,
+    session: Option<String>,
+    req: Request,
+) -> Result<Response<String>> {
+    let user = USER.get();
+    let max_bytes = files::max_upload_bytes(&conf::global(), &user.role);
+    let length = files::content_length(req.headers());
+    if length.is_some_and(|length| {
+        usize::try_from(length).map_or(true, |length| length > max_bytes)
+    }) {
+        tracing::warn!(?length, max_bytes, "Rejecting. Upload too large.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "upload_too_large",
+            format!("Uploads are limited to {max_bytes} bytes."),
+        );
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, Json(error)).into());
+    }
+    tracing::info!(?length, content_type, "Streaming upload.");
+    let started = Instant::now();
+    let body = files::limited(req.into_body(), max_bytes);
+    let (result, error_class) = classified(
+        upstream
+            .forward_stream(http, &endpoint, content_type, body)
+            .await,
+    );
+    let log = data::RequestLog {
+        req_id: REQ_ID.get().req_id,
+        uid: user.uid.clone(),
+        // Files are not of any.
+        model: String::new(),
+        endpoint,
+        status: match &result {
+            Ok(upstream::Forwarded { code, .. }) | Err(code) => {
+                i64::from(code.as_u16())
+            }
+        },
+        input_tokens: 0,
+        output_tokens: 0,
+        cost: 0.0,
+        duration_ms: i64::try_from(started.elapsed().as_millis())
+            .unwrap_or(i64::MAX),
+        time: unix_now_secs(),
+        error_message: result
+            .as_ref()
+            .err()
+            .and_then(|code| code.canonical_reason())
+            .map(redact::error_message),
+        session,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class,
+        client_ip: REQ_ID.get().client_ip,
+        tags: BTreeMap::new(),
+        usage: None,
+    };
+    log_request(storage, &log).await;
+    events.publish(Event::RequestFinished(log));
+    let upstream::Forwarded {
+        code,
+        body,
+        headers,
+        ..
+    } = result?;
+    let mut resp = Response::builder().status(code);
+    if is_json(&body) {
+        resp = resp.header(header::CONTENT_TYPE, "application/json");
+    }
+    let mut resp = resp.body(body).map_err(|error| {
+        tracing::error!(?error, ?code, "Failed to build response.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    resp.headers_mut().extend(headers);
+    Ok(resp)
+}
+
+/// Charged by the characters of the input.
+#[utoipa::path(
+    post,
+    path = "/v1/audio/speech",
+    tag = "api",
+    request_body = media::SpeechReq,
+    responses((status = 200, description = "Upstream's audio, as is."))
+)]
+async fn handle_speech(
+    state: State<AppState>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Response> {
+    let req: media::SpeechReq =
+        serde_json::from_value(body.clone()).map_err(invalid_req)?;
+    let units = media::Units::of_speech(&req);
+    generate(state, media::SPEECH_ENDPOINT, req.model, units, body).await
+}
+
+/// Charged by the images and their size.
+#[utoipa::path(
+    post,
+    path = "/v1/images/generations",
+    tag = "api",
+    request_body = media::ImagesReq,
+    responses((status = 200, body = serde_json::Value))
+)]
+async fn handle_images(
+    state: State<AppState>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Response> {
+    let req: media::ImagesReq =
+        serde_json::from_value(body.clone()).map_err(invalid_req)?;
+    let units = media::Units::of_images(&req);
+    let model = req
+        .model
+        .unwrap_or_else(|| media::IMAGES_MODEL_DEFAULT.to_string());
+    generate(state, media::IMAGES_ENDPOINT, model, units, body).await
+}
+
+fn body_too_large(endpoint: &str, max_bytes: usize) -> Response {
+    tracing::warn!(endpoint, max_bytes, "Rejecting. Body too large.");
+    let mut error = chat::Error::new(
+        "invalid_request_error",
+        "request_too_large",
+        format!(
+            "Request bodies to {endpoint:?} are limited to {max_bytes} \
+            bytes."
+        ),
+    );
+    error.max_bytes = Some(max_bytes);
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(error)).into_response()
+}
+
+/// Before forwarding anything to the endpoint, with our keys.
+fn upstream_allowed(role: &str, endpoint: &str) -> Result<(), Response> {
+    if auth::is_upstream_allowed(&conf::global(), role, endpoint) {
+        return Ok(());
+    }
+    tracing::warn!(endpoint, "Rejecting. Upstream endpoint not allowed.");
+    metrics::counter!("raskol_upstream_blocked_total").increment(1);
+    let error = chat::Error::new(
+        "permission_error",
+        "endpoint_not_allowed",
+        format!("Endpoint {endpoint:?} is not allowed for role {role:?}."),
+    );
+    Err((StatusCode::FORBIDDEN, Json(error)).into_response())
+}
+
+/// Before forwarding anything, once the providers the request could go to
+/// are all capped, per [`conf::SpendCaps`].
+fn spend_capped(
+    upstream: &Upstream,
+    can_fail_over: bool,
+) -> Result<(), Response> {
+    if !upstream.is_capped(can_fail_over) {
+        return Ok(());
+    }
+    tracing::warn!("Rejecting. Spend cap reached.");
+    metrics::counter!("raskol_spend_capped_total").increment(1);
+    // Of the day, per the timezone and reset time of budget periods.
+    let until = period::tomorrow()
+        .ok()
+        .and_then(|next| chrono::DateTime::from_timestamp(next, 0))
+        .map_or_else(
+            || "the day is over".to_string(),
+            |next| next.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        );
+    let error = chat::Error::new(
+        "server_error",
+        "spend_cap_reached",
+        format!(
+            "The daily spend cap of the provider is reached. Requests are \
+            refused until {until}."
+        ),
+    );
+    Err((StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response())
+}
+
+/// As `application/json`, or any `+json` type, as [`Json`] takes them.
+fn is_json_typed(headers: &header::HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+fn not_json() -> Response {
+    tracing::warn!("Rejecting. Not JSON.");
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "unsupported_media_type",
+        "Expected a request with `Content-Type: application/json`."
+            .to_string(),
+    );
+    (StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(error)).into_response()
+}
+
+fn invalid_json(error: serde_json::Error) -> Response {
+    tracing::warn!(?error, "Rejecting. Invalid JSON.");
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "invalid_json",
+        format!(
+            "Invalid JSON: {error}. Check for trailing commas, unquoted \
+            keys, and quotes or newlines in strings left unescaped."
+        ),
+    );
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+}
+
+/// Each of them, the first as the error, as OpenAI reports one.
+fn invalid_problems(problems: Vec<lint::Problem>) -> Response {
+    tracing::warn!(?problems, "Rejecting. Invalid request.");
+    let mut error = chat::Error::new(
+        "invalid_request_error",
+        "invalid_request",
+        "Invalid request.".to_string(),
+    );
+    if let Some(first) = problems.first() {
+        error.error.message =
+            format!("Invalid request: {}: {}", first.param, first.message);
+        error.error.param = Some(first.param.clone());
+    }
+    error.problems = problems;
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+}
+
+fn invalid_req(error: serde_json::Error) -> Response {
+    tracing::warn!(?error, "Rejecting. Invalid request.");
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "invalid_request",
+        format!("Invalid request: {error}"),
+    );
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+}
+
+/// Speech or images, charged by their units rather than tokens, and passed
+/// back as upstream responded, since speech is audio.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn generate(
+    State(AppState {
+        storage,
+        http,
+        upstream,
+        events,
+        ..
+    }): State<AppState>,
+    endpoint: &str,
+    model: String,
+    units: media::Units,
+    body: serde_json::Value,
+) -> Result<Response> {
+    let conf = conf::global();
+    let user: User = USER.get();
+    upstream_allowed(&user.role, endpoint)?;
+    spend_capped(&upstream, false)?;
+    if !auth::authorize(
+        &conf,
+        &user.role,
+        &format!("/{endpoint}"),
+        Some(&model),
+    ) {
+        tracing::warn!(model, "Rejecting. Model not allowed.");
+        let error = chat::Error::new(
+            "invalid_request_error",
+            "model_not_allowed",
+            format!(
+                "Model {model:?} is not allowed for role {:?}.",
+                user.role
+            ),
+        );
+        return Err((StatusCode::FORBIDDEN, Json(error)).into());
+    }
+    deprecation::note_model(&model);
+    let estimate = data::Amount {
+        tokens: units.tokens(&conf.media),
+        cost: units.cost(&conf.media, &model),
+    };
+    let reservation = storage
+        .budget_reserve(
+            &user.uid,
+            user.org.as_deref(),
+            &user.role,
+            user.budget_overrides,
+            &model,
+            estimate,
+        )
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to hit storage.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    let Ok(reservation) = reservation else {
+        tracing::warn!(?units, "Rejecting. Budget exceeded.");
+        let error = chat::Error::new(
+            "insufficient_quota",
+            "budget_exceeded",
+            budget_exceeded("exceeded.", &budget_reset_at()),
+        );
+        return Err((StatusCode::TOO_MANY_REQUESTS, Json(error)).into());
+    };
+    let started = Instant::now();
+    let unsettled = Unsettled {
+        storage: storage.clone(),
+        events: events.clone(),
+        reservation,
+        idempotency_key: None,
+        req_id: REQ_ID.get().req_id,
+        client_ip: REQ_ID.get().client_ip,
+        endpoint: endpoint.to_string(),
+        session: None,
+        experiment: None,
+        arm: None,
+        tags: BTreeMap::new(),
+        started,
+        is_forwarded: AtomicBool::new(true),
+        is_settled: false,
+    };
+    let (result, error_class) = classified(
+        upstream.forward_bytes(&http, endpoint, &model, &body).await,
+    );
+    let generated = match &result {
+        Ok(received) => Some(units.generated(&received.body)),
+        Err(_) => None,
+    };
+    let used =
+        generated
+            .as_ref()
+            .map_or_else(data::Amount::default, |units| data::Amount {
+                tokens: units.tokens(&conf.media),
+                cost: units.cost(&conf.media, &model),
+            });
+    let reservation = unsettled.settle();
+    if let Err(error) = storage.budget_settle(&reservation, used).await {
+        tracing::error!(
+            ?error,
+            ?reservation,
+            ?used,
+            "Failed to settle budget!"
+        );
+    }
+    if let (Some(spend), Ok(received)) = (upstream.spend(), &result) {
+        spend
+            .record(storage.as_ref(), &events, received.provider, used)
+            .await;
+    }
+    let log = data::RequestLog {
+        req_id: REQ_ID.get().req_id,
+        uid: user.uid.clone(),
+        model,
+        endpoint: endpoint.to_string(),
+        status: match &result {
+            Ok(upstream::Received { code, .. }) | Err(code) => {
+                i64::from(code.as_u16())
+            }
+        },
+        input_tokens: i64::try_from(used.tokens).unwrap_or(i64::MAX),
+        output_tokens: 0,
+        cost: used.cost,
+        duration_ms: i64::try_from(started.elapsed().as_millis())
+            .unwrap_or(i64::MAX),
+        time: unix_now_secs(),
+        error_message: result
+            .as_ref()
+            .err()
+            .and_then(|code| code.canonical_reason())
+            .map(redact::error_message),
+        session: None,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class,
+        client_ip: REQ_ID.get().client_ip,
+        tags: BTreeMap::new(),
+        usage: result
+            .as_ref()
+            .ok()
+            .zip(generated)
+            .map(|(received, units)| units.usage(received.provider)),
+    };
+    log_request(storage.as_ref(), &log).await;
+    events.publish(Event::RequestFinished(log));
+    let upstream::Received {
+        code,
+        content_type,
+        headers,
+        body,
+        ..
+    } = result?;
+    let mut resp = Response::builder().status(code);
+    if let Some(content_type) = content_type {
+        resp = resp.header(header::CONTENT_TYPE, content_type);
+    }
+    let mut resp = resp.body(Body::from(body)).map_err(|error| {
+        tracing::error!(?error, ?code, "Failed to build response.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    resp.headers_mut().extend(headers);
+    Ok(resp)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RealtimeQuery {
+    model: String,
+}
+
+/// Relays a realtime session to upstream, once some of its time is
+/// reserved from the budget. Settled by how long it lasted, when it ends.
+#[utoipa::path(
+    get,
+    path = "/v1/realtime",
+    tag = "api",
+    params(RealtimeQuery),
+    responses(
+        (status = 101, description = "Upgraded to a WebSocket."),
+        (status = 403, description = "Model not allowed."),
+        (status = 429, description = "Budget exceeded."),
+    )
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn handle_realtime(
+    State(AppState {
+        storage,
+        upstream,
+        events,
+        ..
+    }): State<AppState>,
+    Query(RealtimeQuery { model }): Query<RealtimeQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response> {
+    let conf = conf::global();
+    let user: User = USER.get();
+    upstream_allowed(&user.role, "v1/realtime")?;
+    spend_capped(&upstream, false)?;
+    if !auth::authorize(&conf, &user.role, "/v1/realtime", Some(&model)) {
+        tracing::warn!(model, "Rejecting. Model not allowed.");
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let reserved = Duration::try_from_secs_f64(conf.realtime.reserve_secs)
+        .unwrap_or_default();
+    let tokens = realtime::tokens(&conf.realtime, reserved);
+    let price = conf.pricing.get(&model).copied();
+    let estimate = data::Amount {
+        tokens,
+        cost: price.map_or(0.0, |price| price.cost(tokens, 0)),
+    };
+    let reservation = storage
+        .budget_reserve(
+            &user.uid,
+            user.org.as_deref(),
+            &user.role,
+            user.budget_overrides,
+            &model,
+            estimate,
+        )
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to hit storage.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    let Ok(reservation) = reservation else {
+        tracing::warn!(tokens, "Rejecting. Budget exceeded.");
+        return Err(StatusCode::TOO_MANY_REQUESTS.into());
+    };
+    let socket = match upstream.realtime(&model).await {
+        Ok(socket) => socket,
+        Err(code) => {
+            realtime_refund(storage.as_ref(), &reservation).await;
+            return Err(code.into());
+        }
+    };
+    tracing::info!(model, "Realtime session starting.");
+    let max = Duration::from_secs(conf.realtime.max_secs);
+    let req_id = REQ_ID.get();
+    // Of the budget, for the session beyond what was reserved at first.
+    let drawn = Arc::new(Mutex::new(data::Amount::default()));
+    let draw = {
+        let (storage, drawn) = (storage.clone(), drawn.clone());
+        move |period| {
+            let (storage, user, model, drawn) =
+                (storage.clone(), user.clone(), model.clone(), drawn.clone());
+            async move {
+                realtime_draw(storage.as_ref(), &user, &model, period, &drawn)
+                    .await
+            }
+        }
+    };
+    let refund = {
+        let storage = storage.clone();
+        let reservation = reservation.clone();
+        move |error| {
+            tracing::warn!(?error, "Failed to upgrade to WebSocket.");
+            tokio::spawn(async move {
+                realtime_refund(storage.as_ref(), &reservation).await;
+            });
+        }
+    };
+    // Task-locals don't survive into the socket's task.
+    let conf = conf::global();
+    let resp = upgrade.on_failed_upgrade(refund).on_upgrade(move |client| {
+        conf::scope(conf, async move {
+            let duration =
+                realtime::relay(client, socket, max, reserved, draw).await;
+            let mut reservation = reservation;
+            let drawn = *drawn.lock().unwrap_or_else(PoisonError::into_inner);
+            reservation.amount.tokens =
+                reservation.amount.tokens.saturating_add(drawn.tokens);
+            reservation.amount.cost += drawn.cost;
+            realtime_settle(
+                &storage,
+                &upstream,
+                &events,
+                &reservation,
+                req_id,
+                duration,
+            )
+            .await;
+        })
+    });
+    Ok(resp)
+}
+
+/// Of the budget, for the session to go on for the period. False once it is
+/// exceeded, or storage fails, not to go on unbilled.
+async fn realtime_draw(
+    storage: &dyn Storage,
+    user: &User,
+    model: &str,
+    period: Duration,
+    drawn: &Mutex<data::Amount>,
+) -> bool {
+    let conf = conf::global();
+    let tokens = realtime::tokens(&conf.realtime, period);
+    let price = conf.pricing.get(model).copied();
+    let amount = data::Amount {
+        tokens,
+        cost: price.map_or(0.0, |price| price.cost(tokens, 0)),
+    };
+    let reserved = storage
+        .budget_reserve(
+            &user.uid,
+            user.org.as_deref(),
+            &user.role,
+            user.budget_overrides,
+            model,
+            amount,
+        )
+        .await;
+    match reserved {
+        Ok(Ok(_)) => {
+            let mut drawn =
+                drawn.lock().unwrap_or_else(PoisonError::into_inner);
+            drawn.tokens = drawn.tokens.saturating_add(amount.tokens);
+            drawn.cost += amount.cost;
+            true
+        }
+        Ok(Err(_)) => false,
+        Err(error) => {
+            tracing::error!(?error, "Failed to draw on budget.");
+            false
+        }
+    }
+}
+
+async fn realtime_refund(
+    storage: &dyn Storage,
+    reservation: &data::Reservation,
+) {
+    let none = data::Amount::default();
+    if let Err(error) = storage.budget_settle(reservation, none).await {
+        tracing::error!(?error, ?reservation, "Failed to refund budget!");
+    }
+}
+
+/// Charges the session for how long it lasted, and logs it. Outside of the
+/// request, so with what's left of its context passed in.
+async fn realtime_settle(
+    storage: &Arc<dyn Storage>,
+    upstream: &Upstream,
+    events: &events::Events,
+    reservation: &data::Reservation,
+    req_id: ReqId,
+    duration: Duration,
+) {
+    let conf = conf::global();
+    let tokens = realtime::tokens(&conf.realtime, duration);
+    let price = conf.pricing.get(&reservation.model).copied();
+    let used = data::Amount {
+        tokens,
+        cost: price.map_or(0.0, |price| price.cost(tokens, 0)),
+    };
+    if let Err(error) = storage.budget_settle(reservation, used).await {
+        tracing::error!(
+            ?error,
+            ?reservation,
+            ?used,
+            "Failed to settle budget!"
+        );
+    }
+    if let Some(spend) = upstream.spend() {
+        spend
+            .record(storage.as_ref(), events, "primary", used)
+            .await;
+    }
+    let log = data::RequestLog {
+        req_id: req_id.req_id,
+        uid: reservation.uid.clone(),
+        model: reservation.model.clone(),
+        endpoint: "v1/realtime".to_string(),
+        status: i64::from(StatusCode::SWITCHING_PROTOCOLS.as_u16()),
+        input_tokens: i64::try_from(tokens).unwrap_or(i64::MAX),
+        output_tokens: 0,
+        cost: used.cost,
+        duration_ms: i64::try_from(duration.as_millis()).unwrap_or(i64::MAX),
+        time: unix_now_secs(),
+        error_message: None,
+        session: None,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class: None,
+        client_ip: req_id.client_ip,
+        tags: BTreeMap::new(),
+        usage: Some(data::Usage {
+            provider: Some("primary".to_string()),
+            unit: data::Unit::Seconds,
+            input: duration.as_secs_f64(),
+            output: 0.0,
+            detail: None,
+        }),
+    };
+    tracing::info!(?duration, tokens, "Realtime session ended.");
+    log_request(storage, &log).await;
+    events.publish(Event::RequestFinished(log));
+}
+
+/// Of a request rejected before it was forwarded, of no usage. Untagged,
+/// for the caller to add the session, experiment and tags.
+fn rejected_log(
+    user: &User,
+    model: &str,
+    endpoint: &str,
+    status: StatusCode,
+    message: &str,
+    class: data::ErrorClass,
+) -> data::RequestLog {
+    data::RequestLog {
+        req_id: REQ_ID.get().req_id,
+        uid: user.uid.clone(),
+        model: model.to_string(),
+        endpoint: endpoint.to_string(),
+        status: i64::from(status.as_u16()),
+        input_tokens: 0,
+        output_tokens: 0,
+        cost: 0.0,
+        duration_ms: 0,
+        time: unix_now_secs(),
+        error_message: Some(redact::error_message(message)),
+        session: None,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class: Some(class),
+        client_ip: REQ_ID.get().client_ip,
+        tags: BTreeMap::new(),
+        usage: None,
+    }
+}
+
+/// Counting those which failed by why, for alerting on without going
+/// through the logs. By kind of endpoint, rather than by path, so as not to
+/// make a series of each one a client makes up.
+async fn log_request(storage: &dyn Storage, log: &data::RequestLog) {
+    access::note(log);
+    slow::offer(log);
+    if let Some(class) = log.error_class {
+        let class = class.as_str();
+        metrics::counter!("raskol_request_errors_total", "class" => class)
+            .increment(1);
+    }
+    let kind = endpoint::classify(&conf::global(), &log.endpoint)
+        .map_or("other", endpoint::Kind::as_str);
+    metrics::counter!(
+        "raskol_requests_total",
+        "endpoint" => kind,
+        "status" => log.status.to_string()
+    )
+    .increment(1);
+    #[allow(clippy::cast_precision_loss)] // Nowhere near.
+    metrics::histogram!(
+        "raskol_request_duration_seconds",
+        "endpoint" => kind
+    )
+    .record(log.duration_ms as f64 / 1000.0);
+    if let Err(error) = storage.log_request(log).await {
+        tracing::error!(?error, ?log, "Failed to log request.");
+    }
+}
+
+/// Counting them by rule too, for alerting on as on request errors.
+fn log_violations(uid: &str, violations: &[filter::Violation]) {
+    for violation in violations {
+        let rule = violation.rule.as_str();
+        tracing::warn!(
+            uid,
+            index = violation.index,
+            rule,
+            detail = violation.detail,
+            "Response filter violated."
+        );
+        metrics::counter!(
+            "raskol_response_filter_violations_total",
+            "rule" => rule
+        )
+        .increment(1);
+    }
+}
+
+/// Forwards to upstream once it's the request's turn in the queue, if
+/// there is one, and back to it if upstream throttles, all by the
+/// deadline. Returns how long it was queued for too.
+async fn forward_queued<T, F>(
+    upstream: &Upstream,
+    queue: Option<&queue::Queue>,
+    user: &User,
+    deadline: Option<tokio::time::Instant>,
+    is_forwarded: &AtomicBool,
+    forward: impl Fn() -> F,
+) -> (Result<T, upstream::Failed>, Duration)
+where
+    F: Future<Output = Result<T, upstream::Failed>>,
+{
+    let conf = conf::global();
+    let queued_since = tokio::time::Instant::now();
+    let mut queued = Duration::ZERO;
+    // A slot of upstream's requests per minute is taken once, not again
+    // when back in the queue.
+    let mut is_paced = false;
+    let forwarding = async {
+        loop {
+            if let Some(queue) = queue {
+                let priority = conf
+                    .roles
+                    .get(&user.role)
+                    .map_or(0, |role| role.priority);
+                let headroom = || upstream.headroom();
+                let throttled_for = || upstream.throttled_for();
+                let waited = async {
+                    queue.hold(priority, queued_since, headroom).await?;
+                    if !is_paced {
+                        queue.pace(user.org.as_deref(), queued_since).await?;
+                        is_paced = true;
+                    }
+                    queue
+                        .wait(
+                            &user.uid,
+                            priority,
+                            queued_since,
+                            throttled_for,
+                        )
+                        .await
+                };
+                match waited.await {
+                    Ok(waited) => queued = waited,
+                    Err(rejection) => {
+                        tracing::warn!(
+                            ?rejection,
+                            "Rejecting. Upstream throttles."
+                        );
+                        break Err(upstream::Failed {
+                            code: StatusCode::TOO_MANY_REQUESTS,
+                            class: data::ErrorClass::Upstream429,
+                        });
+                    }
+                }
+            }
+            is_forwarded.store(true, Ordering::Relaxed);
+            let result = forward().await;
+            // Such as by the burst of those released from the queue at once.
+            if queue.is_some()
+                && result.is_err()
+                && upstream.throttled_for().is_some()
+            {
+                tracing::warn!("Throttled by upstream. Back to the queue.");
+                continue;
+            }
+            break result;
+        }
+    };
+    let result = match deadline {
+        None => forwarding.await,
+        Some(deadline) => tokio::time::timeout_at(deadline, forwarding)
+            .await
+            .unwrap_or_else(|_| {
+                tracing::warn!("Upstream timed out.");
+                Err(upstream::Failed {
+                    code: StatusCode::GATEWAY_TIMEOUT,
+                    class: data::ErrorClass::Timeout,
+                })
+            }),
+    };
+    (result, queued)
+}
+
+/// The class apart, since it's only logged.
+fn classified<T>(
+    result: Result<T, upstream::Failed>,
+) -> (Result<T, StatusCode>, Option<data::ErrorClass>) {
+    match result {
+        Ok(ok) => (Ok(ok), None),
+        Err(upstream::Failed { code, class }) => (Err(code), Some(class)),
+    }
+}
+
+/// Reservation of a request in flight, if dropped before settling it, as
+/// when the client disconnects while we wait on upstream: refunded if
+/// upstream wasn't sent the request yet, and otherwise charged as is, of
+/// the prompt and what was streamed, since upstream bills for them even so,
+/// and what else it used is unknown to us.
+struct Unsettled {
+    storage: Arc<dyn Storage>,
+    events: events::Events,
+    reservation: data::Reservation,
+    idempotency_key: Option<String>,
+
+    /// Captured, since the task-local may be gone by the time of the drop.
+    req_id: String,
+    client_ip: Option<String>,
+    endpoint: String,
+    session: Option<String>,
+    experiment: Option<String>,
+    arm: Option<String>,
+    tags: BTreeMap<String, String>,
+    started: Instant,
+
+    /// Set once upstream is sent the request.
+    is_forwarded: AtomicBool,
+
+    is_settled: bool,
+}
+
+impl Unsettled {
+    /// For the caller to settle, with the actual usage.
+    fn settle(mut self) -> data::Reservation {
+        self.is_settled = true;
+        self.reservation.clone()
+    }
+}
+
+impl Drop for Unsettled {
+    fn drop(&mut self) {
+        if self.is_settled {
+            return;
+        }
+        let is_forwarded = self.is_forwarded.load(Ordering::Relaxed);
+        tracing::warn!(
+            req_id = self.req_id,
+            reservation = ?self.reservation,
+            is_forwarded,
+            "Client disconnected."
+        );
+        metrics::counter!("raskol_requests_abandoned_total").increment(1);
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let reservation = self.reservation.clone();
+        let used = if is_forwarded {
+            reservation.amount
+        } else {
+            data::Amount::default()
+        };
+        let idempotency_key = self.idempotency_key.take();
+        let log = data::RequestLog {
+            req_id: self.req_id.clone(),
+            uid: reservation.uid.clone(),
+            model: reservation.model.clone(),
+            endpoint: self.endpoint.clone(),
+            status: CLIENT_CLOSED_REQUEST,
+            input_tokens: i64::try_from(used.tokens).unwrap_or(i64::MAX),
+            output_tokens: 0,
+            cost: used.cost,
+            duration_ms: i64::try_from(self.started.elapsed().as_millis())
+                .unwrap_or(i64::MAX),
+            time: unix_now_secs(),
+            error_message: Some("Client disconnected.".to_string()),
+            session: self.session.take(),
+            experiment: self.experiment.take(),
+            arm: self.arm.take(),
+            timing: None,
+            // Not an error, but the client going away.
+            error_class: None,
+            client_ip: self.client_ip.take(),
+            tags: std::mem::take(&mut self.tags),
+            usage: None,
+        };
+        tokio::spawn(conf::carry(async move {
+            if let Err(error) =
+                storage.budget_settle(&reservation, used).await
+            {
+                tracing::error!(
+                    ?error,
+                    ?reservation,
+                    ?used,
+                    "Failed to settle budget!"
+                );
+            }
+            log_request(storage.as_ref(), &log).await;
+            if let Some(key) = idempotency_key {
+                if let Err(error) =
+                    storage.idempotency_release(&reservation.uid, &key).await
+                {
+                    tracing::error!(?error, key, "Failed to release key.");
+                }
+            }
+            events.publish(Event::RequestFinished(log));
+        }));
+    }
+}
+
+/// Of upstream, to pass on.
+enum Forwarding {
+    Whole(upstream::Forwarded),
+    Streaming(upstream::Received<upstream::Events>),
+}
+
+/// Of a chat completion streamed to the client, per [`streaming`], drawing
+/// on the budget as it goes and settled once it ends.
+struct Streaming {
+    storage: Arc<dyn Storage>,
+    events: events::Events,
+    upstream: Arc<Upstream>,
+    user: User,
+    model: String,
+    price: Option<conf::Price>,
+
+    /// Of the prompt, as reserved.
+    token_count: usize,
+
+    /// Before calibration.
+    tokens_estimate: Option<usize>,
+
+    /// Of the budget drawn on, with the margin when past it, per
+    /// [`overage`].
+    overrides: data::BudgetOverrides,
+
+    /// Whether the client asked for the usage, which upstream always is.
+    is_usage_passed: bool,
+
+    /// Told of the stream once it is done, too late to alter it.
+    hooks: Arc<hook::Hooks>,
+    hook_ctx: hook::Context,
+
+    /// Of the events passed on, in a comment ending the stream.
+    attestation: Option<conf::Attestation>,
+
+    capture: Option<(conf::Capture, chat::Req)>,
+    unsettled: Unsettled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamEnd {
+    Done,
+
+    /// By upstream, midway, or without finishing it.
+    Broken,
+
+    /// By upstream, with an error event of its own.
+    Failed,
+
+    /// For the budget being used up.
+    CutOff,
+
+    /// For the budget not being drawn on, as storage failed.
+    Unaccounted,
+
+    ClientGone,
+}
+
+impl Streaming {
+    /// Passed on by a task of its own, which settles once the stream ends,
+    /// however it ends.
+    fn respond(
+        self,
+        received: upstream::Received<upstream::Events>,
+    ) -> Response {
+        let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+        let req_id = ReqId {
+            req_id: self.unsettled.req_id.clone(),
+            client_ip: self.unsettled.client_ip.clone(),
+        };
+        let streaming =
+            self.run(received.body, received.code, received.provider, tx);
+        tokio::spawn(conf::carry(access::carry(slow::carry(
+            REQ_ID.scope(req_id, streaming),
+        ))));
+        let body = futures_util::stream::unfold(rx, |mut rx| async move {
+            let events = rx.recv().await?;
+            Some((Ok::<_, std::convert::Infallible>(events), rx))
+        });
+        let mut resp = Response::new(Body::from_stream(body));
+        *resp.status_mut() = received.code;
+        let headers = resp.headers_mut();
+        headers.extend(received.headers);
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/event-stream"),
+        );
+        headers.insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("no-cache"),
+        );
+        resp
+    }
+
+    async fn run(
+        mut self,
+        mut body: upstream::Events,
+        code: StatusCode,
+        provider: &'static str,
+        tx: tokio::sync::mpsc::Sender<axum::body::Bytes>,
+    ) {
+        let mut tally = streaming::Tally {
+            is_usage_stripped: !self.is_usage_passed,
+            ..streaming::Tally::default()
+        };
+        // Only when captured, attested or hooked on to.
+        let mut passed = (self.capture.is_some()
+            || self.attestation.is_some()
+            || !self.hooks.is_empty())
+        .then(String::new);
+        let mut drawn = 0;
+        let mut end = loop {
+            let chunk = match body.next().await {
+                None => break StreamEnd::Done,
+                Some(Ok(chunk)) => chunk,
+                Some(Err(error)) => {
+                    tracing::error!(?error, "Stream broke off upstream.");
+                    break StreamEnd::Broken;
+                }
+            };
+            let events = tally.add(&chunk);
+            match self.draw(&mut drawn, tally.completion_tokens).await {
+                Ok(true) => {}
+                Ok(false) => break StreamEnd::CutOff,
+                Err(error) => {
+                    tracing::error!(?error, "Failed to draw on budget.");
+                    break StreamEnd::Unaccounted;
+                }
+            }
+            if !pass(&tx, &mut passed, events).await {
+                break StreamEnd::ClientGone;
+            }
+        };
+        // Upstream's, for it to stop generating what no one will get.
+        drop(body);
+        match end {
+            StreamEnd::Done | StreamEnd::Broken | StreamEnd::Failed => {
+                let rest = tally.rest();
+                if let Some(error) = &tally.error {
+                    tracing::error!(error, "Stream failed upstream.");
+                    end = StreamEnd::Failed;
+                } else if end == StreamEnd::Done && !tally.is_done {
+                    tracing::error!("Stream ended upstream unfinished.");
+                    end = StreamEnd::Broken;
+                }
+                if end == StreamEnd::Broken {
+                    // Instead of what is left, of an event cut short.
+                    let error = chat::Error::new(
+                        "server_error",
+                        "stream_broken",
+                        format!(
+                            "The stream broke off upstream, after {} tokens \
+                            of the completion.",
+                            tally.completion_tokens
+                        ),
+                    );
+                    pass(&tx, &mut passed, streaming::cut_off(&error)).await;
+                } else {
+                    pass(&tx, &mut passed, rest).await;
+                }
+            }
+            StreamEnd::CutOff => {
+                tracing::warn!(
+                    completion_tokens = tally.completion_tokens,
+                    "Cutting stream off. Budget exceeded."
+                );
+                let error = self.cut_off_error(&tally).await;
+                pass(&tx, &mut passed, streaming::cut_off(&error)).await;
+            }
+            StreamEnd::Unaccounted => {
+                let error = chat::Error::new(
+                    "server_error",
+                    "budget_unavailable",
+                    format!(
+                        "The budget could not be drawn on, after {} tokens \
+                        of the completion.",
+                        tally.completion_tokens
+                    ),
+                );
+                pass(&tx, &mut passed, streaming::cut_off(&error)).await;
+            }
+            StreamEnd::ClientGone => {
+                tracing::warn!("Client disconnected mid-stream.");
+            }
+        }
+        if let (Some(attestation), Some(passed), false) =
+            (&self.attestation, &passed, end == StreamEnd::ClientGone)
+        {
+            let value = attest::attest(
+                &attestation.secret,
+                &self.user.uid,
+                &self.hook_ctx.req_id,
+                unix_now_secs(),
+                passed.as_bytes(),
+            );
+            // Ignored by clients, as a comment, but for those who verify.
+            let comment = format!(": {}: {value}\n\n", attest::HEADER);
+            let _ = tx.send(axum::body::Bytes::from(comment)).await;
+        }
+        drop(tx);
+        self.settle(&tally, code, provider, end, passed.as_deref())
+            .await;
+    }
+
+    /// Whether the budget covers the completion so far, drawing on it if
+    /// need be.
+    async fn draw(
+        &mut self,
+        drawn: &mut usize,
+        completion_tokens: usize,
+    ) -> anyhow::Result<bool> {
+        while *drawn < completion_tokens {
+            let amount = data::Amount {
+                tokens: streaming::DRAW,
+                cost: self
+                    .price
+                    .map_or(0.0, |price| price.cost(0, streaming::DRAW)),
+            };
+            let reserved = self
+                .storage
+                .budget_reserve(
+                    &self.user.uid,
+                    self.user.org.as_deref(),
+                    &self.user.role,
+                    self.overrides,
+                    &self.model,
+                    amount,
+                )
+                .await;
+            match reserved {
+                Ok(Ok(_)) => {
+                    let reserved = &mut self.unsettled.reservation.amount;
+                    reserved.tokens =
+                        reserved.tokens.saturating_add(amount.tokens);
+                    reserved.cost += amount.cost;
+                    *drawn += streaming::DRAW;
+                }
+                // Not to stream on unbilled, however long storage is out.
+                Ok(Err(_)) => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(true)
+    }
+
+    async fn cut_off_error(&self, tally: &streaming::Tally) -> chat::Error {
+        let reset_at = budget_reset_at();
+        let mut error = chat::Error::new(
+            "insufficient_quota",
+            "budget_exceeded",
+            budget_exceeded(
+                &format!(
+                    "exceeded, after {} tokens of the completion.",
+                    tally.completion_tokens
+                ),
+                &reset_at,
+            ),
+        );
+        let budget = token_budget(self.storage.as_ref(), &self.user).await;
+        error.budget = budget.map(|budget| chat::Budget {
+            tokens_used: budget.used,
+            tokens_limit: budget.limit,
+            tokens_remaining: budget.remaining(),
+            reset_at,
+        });
+        error
+    }
+
+    async fn settle(
+        self,
+        tally: &streaming::Tally,
+        code: StatusCode,
+        provider: &'static str,
+        end: StreamEnd,
+        passed: Option<&str>,
+    ) {
+        let Self {
+            storage,
+            events,
+            upstream,
+            user,
+            model,
+            price,
+            token_count,
+            tokens_estimate,
+            hooks,
+            hook_ctx,
+            capture,
+            unsettled,
+            ..
+        } = self;
+        let (input_tokens, output_tokens) = match &tally.usage {
+            Some(usage) => {
+                if let Some(estimate) = tokens_estimate {
+                    record_tokens_estimate(
+                        storage.as_ref(),
+                        &model,
+                        estimate,
+                    )
+                    .await;
+                }
+                (usage.prompt_tokens, usage.completion_tokens)
+            }
+            // Not sent by upstream, which is always asked for it.
+            None => (token_count, tally.completion_tokens),
+        };
+        let used = data::Amount {
+            tokens: input_tokens.saturating_add(output_tokens),
+            cost: price
+                .map_or(0.0, |price| price.cost(input_tokens, output_tokens)),
+        };
+        let req_id = unsettled.req_id.clone();
+        let client_ip = unsettled.client_ip.clone();
+        let endpoint = unsettled.endpoint.clone();
+        let session = unsettled.session.clone();
+        let experiment = unsettled.experiment.clone();
+        let arm = unsettled.arm.clone();
+        let tags = unsettled.tags.clone();
+        let started = unsettled.started;
+        let idempotency_key = unsettled.idempotency_key.clone();
+        let reservation = unsettled.settle();
+        if let Err(error) = storage.budget_settle(&reservation, used).await {
+            tracing::error!(
+                ?error,
+                ?reservation,
+                ?used,
+                "Failed to settle budget!"
+            );
+        }
+        if let Some(spend) = upstream.spend() {
+            spend
+                .record(storage.as_ref(), &events, provider, used)
+                .await;
+        }
+        let status = i64::from(code.as_u16());
+        let (status, error_message, error_class) = match end {
+            StreamEnd::Done => (status, None, None),
+            StreamEnd::Broken => (
+                status,
+                Some("Stream broke off upstream.".to_string()),
+                Some(data::ErrorClass::Network),
+            ),
+            StreamEnd::Failed => (
+                status,
+                tally.error.as_deref().map(redact::error_message),
+                Some(data::ErrorClass::Upstream5xx),
+            ),
+            StreamEnd::CutOff => (
+                status,
+                Some("Cut off. Budget exceeded.".to_string()),
+                Some(data::ErrorClass::Budget),
+            ),
+            StreamEnd::Unaccounted => (
+                status,
+                Some("Cut off. Failed to draw on budget.".to_string()),
+                Some(data::ErrorClass::Unaccounted),
+            ),
+            StreamEnd::ClientGone => (
+                CLIENT_CLOSED_REQUEST,
+                Some("Client disconnected.".to_string()),
+                None,
+            ),
+        };
+        let log = data::RequestLog {
+            req_id,
+            uid: user.uid.clone(),
+            model,
+            endpoint,
+            status,
+            input_tokens: i64::try_from(input_tokens).unwrap_or(i64::MAX),
+            output_tokens: i64::try_from(output_tokens).unwrap_or(i64::MAX),
+            cost: used.cost,
+            duration_ms: i64::try_from(started.elapsed().as_millis())
+                .unwrap_or(i64::MAX),
+            time: unix_now_secs(),
+            error_message,
+            session,
+            experiment,
+            arm,
+            timing: tally.usage.as_ref().and_then(chat::Usage::timing),
+            error_class,
+            client_ip,
+            tags,
+            usage: Some(data::Usage::tokens(
+                Some(provider),
+                input_tokens,
+                output_tokens,
+            )),
+        };
+        slow::note_response_bytes(tally.bytes);
+        log_request(storage.as_ref(), &log).await;
+        if let Some((capture, chat_req)) = &capture {
+            capture_bodies(storage.as_ref(), capture, &log, chat_req, passed)
+                .await;
+        }
+        // Streams aren't replayed, so a repeat is made anew.
+        idempotency_settle(&storage, &user, idempotency_key, None).await;
+        if end == StreamEnd::Done {
+            let mut forwarded = upstream::Forwarded {
+                code,
+                body: passed.unwrap_or_default().to_string(),
+                headers: header::HeaderMap::new(),
+                fallback_model: None,
+                provider,
+            };
+            hooks.post_response(&hook_ctx, &mut forwarded).await;
+        }
+        if end == StreamEnd::CutOff {
+            metrics::counter!("raskol_streams_cut_off_total").increment(1);
+            events.publish(Event::BudgetRejected(log.clone()));
+        }
+        if let StreamEnd::Broken | StreamEnd::Failed = end {
+            metrics::counter!("raskol_streams_broken_total").increment(1);
+        }
+        events.publish(Event::RequestFinished(log));
+    }
+}
+
+/// To the client, unless it is gone.
+async fn pass(
+    tx: &tokio::sync::mpsc::Sender<axum::body::Bytes>,
+    passed: &mut Option<String>,
+    events: axum::body::Bytes,
+) -> bool {
+    if events.is_empty() {
+        return true;
+    }
+    if let Some(passed) = passed {
+        passed.push_str(&String::from_utf8_lossy(&events));
+    }
+    tx.send(events).await.is_ok()
+}
+
+/// Of chat, degrading it if it is to be served past the user's budget, or
+/// rejecting it if that is too soon.
+async fn overage_check(
+    storage: &dyn Storage,
+    overage_conf: &conf::Overage,
+    user: &User,
+    token_count: usize,
+    req: &mut chat::Req,
+) -> Result<Option<overage::Degraded>> {
+    // Left to the budget to reject, as usual.
+    let Some(budget) = token_budget(storage, user).await else {
+        return Ok(None);
+    };
+    let checked = overage::check(
+        storage,
+        overage_conf,
+        &user.uid,
+        budget,
+        token_count,
+        req,
+    )
+    .await
+    .map_err(|error| {
+        tracing::error!(?error, "Failed to check overage.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    match checked {
+        overage::Overage::No => Ok(None),
+        overage::Overage::Degraded(degraded) => {
+            tracing::info!(
+                requested = degraded.requested,
+                model = req.model,
+                max_tokens = req.max_tokens,
+                used = budget.used,
+                limit = budget.limit,
+                "Past the budget. Degrading."
+            );
+            metrics::counter!("raskol_overage_total", "outcome" => "degraded")
+                .increment(1);
+            Ok(Some(degraded))
+        }
+        overage::Overage::Throttled(rejection) => {
+            let retry_after = rejection.retry_after().as_secs_f64().ceil();
+            tracing::warn!(
+                ?rejection,
+                retry_after,
+                "Rejecting. Past the budget and rate limited."
+            );
+            metrics::counter!(
+                "raskol_overage_total",
+                "outcome" => "throttled"
+            )
+            .increment(1);
+            let error = chat::Error::new(
+                "rate_limit_exceeded",
+                "overage_rate_limited",
+                format!(
+                    "{} budget exceeded, so served at a reduced rate. Used \
+                    {} of {} tokens. Retry after {retry_after} seconds.",
+                    period::adjective(),
+                    budget.used,
+                    budget.limit,
+                ),
+            );
+            Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(error),
+            )
+                .into())
+        }
+    }
+}
+
+fn duplicates_check(
+    duplicates: &duplicates::Duplicates,
+    duplicates_conf: &conf::Duplicates,
+    user: &User,
+    req: &chat::Req,
+    idempotency_key: Option<&str>,
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let Err(rejection) = duplicates.check(
+        duplicates_conf,
+        &user.uid,
+        req,
+        idempotency_key,
+        now,
+    ) else {
+        return Ok(());
+    };
+    let retry_after = rejection.retry_after.as_secs_f64().ceil();
+    tracing::warn!(
+        requests = rejection.requests,
+        retry_after,
+        "Rejecting. Same prompt repeated."
+    );
+    metrics::counter!("raskol_duplicates_rejected_total").increment(1);
+    let error = chat::Error::new(
+        "rate_limit_exceeded",
+        "duplicate_prompt",
+        format!(
+            "The same prompt was already sent {} times within {} seconds, \
+            as by a client stuck in a loop. Retry after {retry_after} \
+            seconds, or change the prompt.",
+            rejection.requests, duplicates_conf.window,
+        ),
+    );
+    Err((
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(error),
+    )
+        .into())
+}
+
+/// `None` when storage fails, since it is only used to inform clients.
+async fn token_budget(
+    storage: &dyn Storage,
+    user: &User,
+) -> Option<data::TokenBudget> {
+    storage
+        .token_budget(&user.uid, &user.role, user.budget_overrides)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to get token budget.");
+        })
+        .ok()
+}
+
+/// Where the user stands with their token budget, so that clients can
+/// back off before running out. Reset is in seconds.
+fn rate_limit_headers(
+    budget: Option<&data::TokenBudget>,
+) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if let Some(budget) = budget {
+        let reset = (budget_reset() - unix_now_secs()).max(0);
+        for (name, value) in [
+            ("x-ratelimit-limit-tokens", budget.limit),
+            ("x-ratelimit-remaining-tokens", budget.remaining()),
+            ("x-ratelimit-reset", reset.unsigned_abs()),
+        ] {
+            headers.insert(name, header::HeaderValue::from(value));
+        }
+    }
+    headers
+}
+
+/// Start of the next budget period, as RFC 3339.
+fn budget_reset_at() -> String {
+    chrono::DateTime::from_timestamp(budget_reset(), 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Start of the next budget period, in seconds since UNIX epoch. Else, as
+/// it only informs clients, the next UTC midnight.
+fn budget_reset() -> i64 {
+    period::next().unwrap_or_else(|error| {
+        tracing::error!(?error, "Failed to get the next budget period.");
+        let now = unix_now_secs();
+        now - now % DAY + DAY
+    })
+}
+
+/// Of the request which `budget_reserve` found to exceed a budget, what the
+/// client is told, of the budget and where the user stands.
+async fn budget_rejected(
+    storage: &dyn Storage,
+    user: &User,
+    exceeded: data::Exceeded,
+    model: &str,
+    estimate: data::Amount,
+) -> (chat::Error, header::HeaderMap) {
+    let budget = token_budget(storage, user).await;
+    let reset_at = budget_reset_at();
+    let what = exceeded_what(
+        storage,
+        user,
+        exceeded,
+        budget.as_ref(),
+        model,
+        estimate,
+    )
+    .await;
+    let mut error = chat::Error::new(
+        "insufficient_quota",
+        "budget_exceeded",
+        budget_exceeded(&what, &reset_at),
+    );
+    error.budget = budget.as_ref().map(|budget| chat::Budget {
+        tokens_used: budget.used,
+        tokens_limit: budget.limit,
+        tokens_remaining: budget.remaining(),
+        reset_at,
+    });
+    (error, rate_limit_headers(budget.as_ref()))
+}
+
+/// Of the budget which `budget_reserve` found exceeded, for
+/// [`budget_exceeded`].
+async fn exceeded_what(
+    storage: &dyn Storage,
+    user: &User,
+    exceeded: data::Exceeded,
+    budget: Option<&data::TokenBudget>,
+    model: &str,
+    estimate: data::Amount,
+) -> String {
+    let tokens = estimate.tokens;
+    match (exceeded, budget) {
+        (data::Exceeded::Model, _) => format!(
+            "for model {model} exceeded. The request needs about {tokens} \
+            tokens, more than remain of it."
+        ),
+        (data::Exceeded::Tokens, Some(budget)) => format!(
+            "exceeded. Used {} of {} tokens, {} remaining, but the request \
+            needs about {tokens}.",
+            budget.used,
+            budget.limit,
+            budget.remaining(),
+        ),
+        (data::Exceeded::Cost, _) => {
+            let conf = conf::global();
+            let multiplier = auth::budget_multiplier(&conf, &user.role);
+            let max = user.budget_overrides.max_cost_per_day.or_else(|| {
+                conf.max_cost_per_day.map(|max| max * multiplier)
+            });
+            match max {
+                Some(max) => format!(
+                    "of ${max:.2} exceeded. The request costs about ${:.4}, \
+                    more than remains of it.",
+                    estimate.cost,
+                ),
+                None => "exceeded.".to_string(),
+            }
+        }
+        (data::Exceeded::Org, _) => {
+            let org = match &user.org {
+                Some(org) => storage.org_get(org).await.ok(),
+                None => None,
+            };
+            match org {
+                Some(org) => format!(
+                    "of org {} exceeded. Its members used {} tokens of it, \
+                    but the request needs about {tokens}.",
+                    org.org, org.tokens_today,
+                ),
+                None => "of the org exceeded.".to_string(),
+            }
+        }
+        (data::Exceeded::Tokens, None) => "exceeded.".to_string(),
+    }
+}
+
+/// Of the budget of the period, what the client is told of it being
+/// exceeded, as in "exceeded.", and when it resets.
+fn budget_exceeded(what: &str, reset_at: &str) -> String {
+    format!(
+        "{} budget {what} Resets at {reset_at}.",
+        period::adjective()
+    )
+}
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    /// Of the server, per [`conf_layer`].
+    pub(crate) conf: Arc<Conf>,
+    metrics: PrometheusHandle,
+    pub(crate) storage: Arc<dyn Storage>,
+    revocations: Arc<auth::Revocations>,
+    pub(crate) concurrency: Arc<ratelimit::Concurrency>,
+    per_ip: Arc<ratelimit::PerIp>,
+    duplicates: Arc<duplicates::Duplicates>,
+    pub(crate) events: events::Events,
+    /// Shared, so that connections are pooled across requests.
+    pub(crate) http: reqwest::Client,
+    pub(crate) upstream: Arc<Upstream>,
+
+    /// Not when off in conf.
+    pub(crate) queue: Option<Arc<queue::Queue>>,
+
+    activity: Arc<Activity>,
+    hooks: Arc<hook::Hooks>,
+
+    /// Not when off in conf.
+    pub(crate) access: Option<Arc<access::Log>>,
+}
+
+/// Of API requests, so that database maintenance can wait for a lull.
+#[derive(Default)]
+struct Activity {
+    in_flight: Mutex<(usize, Option<Instant>)>,
+}
+
+impl Activity {
+    fn start(self: &Arc<Self>) -> ActivityGuard {
+        self.state().0 += 1;
+        ActivityGuard(self.clone())
+    }
+
+    /// No requests in flight and none ended within the duration.
+    fn is_idle_for(&self, duration: Duration) -> bool {
+        let (in_flight, last_ended) = *self.state();
+        in_flight == 0
+            && last_ended.is_none_or(|ended| ended.elapsed() >= duration)
+    }
+
+    fn state(&self) -> MutexGuard<'_, (usize, Option<Instant>)> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+struct ActivityGuard(Arc<Activity>);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.0 = state.0.saturating_sub(1);
+        state.1 = Some(Instant::now());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub uid: String,
+    pub role: String,
+
+    /// Only from JWTs. API keys belong to users alone.
+    pub org: Option<String>,
+
+    /// Only from JWTs.
+    pub budget_overrides: data::BudgetOverrides,
+
+    /// Only from JWTs. Besides the role's, per [`conf::Role::features`].
+    pub features: Vec<String>,
+}
+
+/// Of the features needed, the first the user has not.
+pub(crate) fn missing_feature(
+    conf: &Conf,
+    user: &User,
+    needed: &[conf::Feature],
+) -> Option<conf::Feature> {
+    let role = conf.roles.get(&user.role);
+    needed.iter().copied().find(|feature| {
+        !role.is_some_and(|role| role.has_feature(*feature, &user.features))
+    })
+}
+
+pub(crate) fn feature_not_allowed(feature: conf::Feature) -> Response {
+    tracing::warn!(
+        feature = feature.as_str(),
+        "Rejecting. Feature not allowed."
+    );
+    let error = chat::Error::new(
+        "invalid_request_error",
+        "feature_not_allowed",
+        feature_not_allowed_message(feature),
+    );
+    (StatusCode::FORBIDDEN, Json(error)).into_response()
+}
+
+fn feature_not_allowed_message(feature: conf::Feature) -> String {
+    format!("Feature {:?} is not enabled for you.", feature.as_str())
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ReqId {
+    pub req_id: String,
+
+    /// Past trusted proxies. None for requests of our own, as of batches.
+    pub client_ip: Option<String>,
+}
+
+impl ReqId {
+    /// Max length of client-provided IDs.
+    const MAX_LEN: usize = 128;
+
+    fn new() -> Self {
+        let req_id = cuid2::create_id();
+        Self {
+            req_id,
+            client_ip: None,
+        }
+    }
+
+    /// Client-provided, as long as it is sane to log and pass on.
+    fn from_client(req_id: &str) -> Option<Self> {
+        let is_sane = !req_id.is_empty()
+            && req_id.len() <= Self::MAX_LEN
+            && req_id.chars().all(|c| {
+                c.is_ascii_alphanumeric()
+                    || matches!(c, '-' | '_' | '.' | ':')
+            });
+        is_sane.then(|| Self {
+            req_id: req_id.to_string(),
+            client_ip: None,
+        })
+    }
+}
+
+/// Of the server, for [`conf::global`] to see, when more than one are
+/// embedded in a process.
+async fn conf_layer(
+    State(AppState { conf, .. }): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    conf::scope(conf, next.run(req)).await
+}
+
+async fn req_id_layer(
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut req_id = req
+        .headers()
+        .get(REQ_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ReqId::from_client)
+        .unwrap_or_else(ReqId::new);
+    req_id.client_ip = Some(from.ip().to_canonical().to_string());
+    let value = header::HeaderValue::from_str(&req_id.req_id);
+    let mut resp = REQ_ID.scope(req_id, next.run(req)).await;
+    if let Ok(value) = value {
+        resp.headers_mut().insert(REQ_ID_HEADER, value);
+    }
+    resp
+}
+
+async fn pre_auth_layer(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Err(resp) = state.hooks.pre_auth(&mut req).await {
+        return resp;
+    }
+    next.run(req).await
+}
+
+/// Of a request a trusted proxy passed on, the proxy, the client being in
+/// [`ConnectInfo`] instead, per [`client_ip_layer`].
+#[derive(Debug, Clone, Copy)]
+struct Proxy(SocketAddr);
+
+/// Takes the client to be the one the trusted proxies name, for all after
+/// to see, as if it had connected itself.
+async fn client_ip_layer(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let client = headers::client_ip(
+        &conf::global().ip_filter,
+        req.headers(),
+        peer.ip(),
+    );
+    if client != peer.ip().to_canonical() {
+        tracing::debug!(?peer, %client, "Proxied.");
+        let extensions = req.extensions_mut();
+        extensions.insert(ConnectInfo(SocketAddr::new(client, 0)));
+        extensions.insert(Proxy(peer));
+    }
+    next.run(req).await
+}
+
+/// Picks the client headers which upstream requests are to carry.
+async fn forwarded_headers_layer(
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    proxy: Option<Extension<Proxy>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // Its chain, which names the client already, goes on from the proxy.
+    let from = proxy.map_or(from, |Extension(Proxy(peer))| peer);
+    let forwarded =
+        headers::outgoing(&conf::global().headers, req.headers(), from.ip());
+    FORWARDED_HEADERS.scope(forwarded, next.run(req)).await
+}
+
+tokio::task_local! {
+    pub(crate) static USER: User;
+    pub(crate) static REQ_ID: ReqId;
+
+    /// Of the client's request, those to pass on upstream.
+    pub(crate) static FORWARDED_HEADERS: header::HeaderMap;
+}
+
+async fn auth_layer(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let conf: Arc<Conf> = conf::global();
+    let storage = &state.storage;
+    let (user_opt, req) = match &conf.signing {
+        Some(signing)
+            if req.headers().contains_key(signing::SERVICE_HEADER) =>
+        {
+            authorize_signed(signing, storage.as_ref(), req).await?
+        }
+        _ => {
+            let auth_token = bearer_token(req.headers())
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let user_opt = authorize_token(&state, auth_token).await?;
+            (user_opt, req)
+        }
+    };
+    if let Some(user) = user_opt {
+        let account =
+            storage.account_get(&user.uid).await.map_err(|error| {
+                tracing::error!(?error, "Failed to get account.");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+        access::note_uid(&user.uid);
+        if account.is_suspended {
+            tracing::warn!(?user, "Rejecting. Suspended.");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if let Some(error) = access_closed(&user.role) {
+            return Ok((StatusCode::FORBIDDEN, Json(error)).into_response());
+        }
         Ok(USER.scope(user, next.run(req)).await)
     } else {
-        tracing::debug!(?req, "Invalid or missing authorization.");
+        tracing::debug!(
+            method = %req.method(),
+            uri = %redact::redact(&req.uri().to_string()),
+            "Invalid or missing authorization."
+        );
         Err(StatusCode::UNAUTHORIZED)
     }
 }
 
-fn authorize(auth_token: &str, jwt_conf: &conf::Jwt) -> Option<User> {
-    auth::Claims::from_str(auth_token, jwt_conf)
-        .inspect_err(|error| tracing::debug!(?error, "Auth failed."))
+/// Of the Authorization header, the token, with or without "Bearer ".
+pub(crate) fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    let auth_header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())?;
+    Some(
+        auth_header
+            .strip_prefix("Bearer ")
+            .unwrap_or(auth_header)
+            .trim(),
+    )
+}
+
+/// Of an API key or JWT, as [`auth_layer`] has it, for connections to check
+/// it again, so as not to outlive its revocation or expiry.
+pub(crate) async fn authorize_token(
+    state: &AppState,
+    auth_token: &str,
+) -> Result<Option<User>, StatusCode> {
+    let storage = state.storage.as_ref();
+    if auth_token.starts_with(auth::API_KEY_PREFIX) {
+        authorize_api_key(auth_token, storage).await
+    } else {
+        let jwt = &conf::global().jwt;
+        authorize(auth_token, jwt, &state.revocations, storage).await
+    }
+}
+
+/// Outside of the role's access windows, per [`conf::Access`], the error to
+/// refuse its requests with. Closed when they are invalid, though those are
+/// refused at start.
+pub(crate) fn access_closed(role: &str) -> Option<chat::Error> {
+    let schedule = match schedule::of(role)? {
+        Ok(schedule) => schedule,
+        Err(error) => {
+            tracing::error!(?error, role, "Rejecting. Invalid schedule.");
+            return Some(chat::Error::new(
+                "permission_error",
+                "outside_access_window",
+                format!("Role {role:?} has an invalid access schedule."),
+            ));
+        }
+    };
+    let now = chrono::Utc::now();
+    if schedule.is_open(now) {
+        return None;
+    }
+    let next = schedule.next(now);
+    tracing::warn!(role, ?next, "Rejecting. Outside access windows.");
+    let role_label = role.to_string();
+    metrics::counter!("raskol_access_closed_total", "role" => role_label)
+        .increment(1);
+    let message = match &next {
+        Some(next) => format!(
+            "Role {role:?} may not be used at this time. Next allowed from \
+            {} until {}.",
+            next.from, next.until
+        ),
+        None => format!("Role {role:?} may not be used at any time."),
+    };
+    let mut error = chat::Error::new(
+        "permission_error",
+        "outside_access_window",
+        message,
+    );
+    error.access_window = next;
+    Some(error)
+}
+
+/// Must run after auth.
+#[tracing::instrument(
+    name = "role",
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn role_layer(
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let user: User = USER.get();
+    let route = version::route(req.extensions(), uri.path());
+    if !auth::authorize(&conf::global(), &user.role, route, None) {
+        tracing::warn!(path = uri.path(), "Rejecting. Route not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(req).await)
+}
+
+#[tracing::instrument(
+    name = "rate_limit",
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+        role = USER.get().role
+    )
+)]
+async fn rate_limit_layer(
+    State(AppState { storage, .. }): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    rate_limit_check(storage.as_ref(), &USER.get()).await?;
+    Ok(next.run(req).await)
+}
+
+/// Of [`rate_limit_layer`], for requests not routed through it, such as
+/// replays.
+pub(crate) async fn rate_limit_check(
+    storage: &dyn Storage,
+    user: &User,
+) -> Result<(), Response> {
+    let result = ratelimit::check(storage, &user.uid, &user.role)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to check rate limit.");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        })?;
+    if let Err(rejection) = result {
+        // Round up, so that retrying after that many seconds succeeds.
+        let retry_after = rejection.retry_after().as_secs_f64().ceil();
+        tracing::warn!(?rejection, retry_after, "Rejecting. Rate limited.");
+        let error = chat::Error::new(
+            "requests",
+            "rate_limit_exceeded",
+            format!(
+                "{} Retry after {retry_after} seconds.",
+                rejection.message()
+            ),
+        );
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            Json(error),
+        )
+            .into_response());
+    }
+    Ok(())
+}
+
+/// Of [`concurrency_layer`], for requests not routed through it, such as
+/// replays. None when unlimited, else the permit to hold until the
+/// response is sent.
+pub(crate) fn concurrency_acquire(
+    concurrency: &ratelimit::Concurrency,
+    user: &User,
+) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, Response> {
+    let Some(max) = conf::global().max_concurrent_requests_per_user else {
+        return Ok(None);
+    };
+    let Some(permit) = concurrency.try_acquire(&user.uid, max) else {
+        tracing::warn!(max, "Rejecting. Too many concurrent requests.");
+        let error = chat::Error::new(
+            "requests",
+            "rate_limit_exceeded",
+            format!(
+                "Too many concurrent requests. At most {max} may be in \
+                flight at once, per max_concurrent_requests_per_user."
+            ),
+        );
+        return Err(
+            (StatusCode::TOO_MANY_REQUESTS, Json(error)).into_response()
+        );
+    };
+    Ok(Some(permit))
+}
+
+/// Gives OpenAI-style bodies to errors which were only given a status.
+async fn error_body_layer(req: Request, next: Next) -> Response {
+    use axum::body::HttpBody;
+
+    let resp = next.run(req).await;
+    let status = resp.status();
+    let is_bare = resp.body().size_hint().exact() == Some(0);
+    if !(status.is_client_error() || status.is_server_error()) || !is_bare {
+        return resp;
+    }
+    let (mut parts, _) = resp.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    (parts, Json(chat::Error::from_status(status))).into_response()
+}
+
+async fn ip_filter_layer(
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if !conf::global().ip_filter.is_allowed(from.ip()) {
+        tracing::warn!(?from, "Rejecting. Address not allowed.");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(req).await)
+}
+
+#[tracing::instrument(
+    name = "per_ip_rate_limit",
+    skip_all,
+    fields(req_id = REQ_ID.get().req_id)
+)]
+async fn per_ip_rate_limit_layer(
+    State(AppState { per_ip, .. }): State<AppState>,
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    let windows =
+        ratelimit::windows(&conf::global().ip_filter.public_rate_limit);
+    if windows.is_empty() {
+        return Ok(next.run(req).await);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    if let Some(window) =
+        per_ip.acquire(from.ip().to_canonical(), &windows, now)
+    {
+        let period = window.period.as_secs();
+        let retry_after = period - now.as_secs() % period;
+        tracing::warn!(?from, ?window, "Rejecting. Rate limited.");
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response());
+    }
+    Ok(next.run(req).await)
+}
+
+#[tracing::instrument(
+    name = "concurrency",
+    skip_all,
+    fields(
+        req_id = REQ_ID.get().req_id,
+        uid = USER.get().uid,
+    )
+)]
+async fn activity_layer(
+    State(AppState { activity, .. }): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let guard = activity.start();
+    let resp = next.run(req).await;
+    // Until the body is fully sent, since it may be streamed.
+    resp.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &guard;
+            chunk
+        }))
+    })
+}
+
+async fn concurrency_layer(
+    State(AppState { concurrency, .. }): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    let user: User = USER.get();
+    let Some(permit) = concurrency_acquire(&concurrency, &user)? else {
+        return Ok(next.run(req).await);
+    };
+    let resp = next.run(req).await;
+    // Hold the permit until the body is fully sent, not just until the
+    // handler returns, since the body may be streamed.
+    let resp = resp.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &permit;
+            chunk
+        }))
+    });
+    Ok(resp)
+}
+
+async fn authorize(
+    auth_token: &str,
+    jwt_conf: &conf::Jwt,
+    revocations: &auth::Revocations,
+    storage: &dyn Storage,
+) -> Result<Option<User>, StatusCode> {
+    let (claims, verifier) =
+        match auth::Claims::verify(auth_token, jwt_conf).await {
+            Ok(verified) => verified,
+            Err(error) => {
+                tracing::debug!(?error, "Auth failed.");
+                return Ok(None);
+            }
+        };
+    let is_revoked =
+        revocations
+            .is_revoked(&claims, storage)
+            .await
+            .map_err(|error| {
+                tracing::error!(?error, "Failed to check JWT revocation.");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+    if is_revoked {
+        tracing::warn!(jti = ?claims.jti, uid = ?claims.sub, "JWT revoked.");
+        return Ok(None);
+    }
+    // Only we may lift budgets, not whoever else we take tokens of.
+    let budget_overrides = match verifier {
+        jwt::Verifier::Ours => data::BudgetOverrides {
+            max_tokens_per_day: claims.max_tokens_per_day,
+            max_cost_per_day: claims.max_cost_per_day,
+            overage_tokens: 0,
+        },
+        jwt::Verifier::Jwks | jwt::Verifier::Keyed => {
+            if claims.max_tokens_per_day.is_some()
+                || claims.max_cost_per_day.is_some()
+            {
+                tracing::warn!(
+                    ?verifier,
+                    "Ignoring budget overrides of a token not ours."
+                );
+            }
+            data::BudgetOverrides::default()
+        }
+    };
+    Ok(Some(User {
+        uid: claims.sub,
+        role: claims.role,
+        org: claims.org,
+        budget_overrides,
+        features: claims.features,
+    }))
+}
+
+async fn authorize_api_key(
+    api_key: &str,
+    storage: &dyn Storage,
+) -> Result<Option<User>, StatusCode> {
+    let key_opt = storage.api_key_lookup(api_key).await.map_err(|error| {
+        tracing::error!(?error, "Failed to lookup API key.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    let user_opt = key_opt.map(|key| {
+        tracing::debug!(key_id = ?key.id, "Authorized by API key.");
+        User {
+            uid: key.uid,
+            role: key.role,
+            org: None,
+            budget_overrides: data::BudgetOverrides::default(),
+            features: Vec::new(),
+        }
+    });
+    Ok(user_opt)
+}
+
+/// Per [`signing`], with the body read whole, for its digest, then put back
+/// for the handlers. Only once the headers, timestamp and service check out,
+/// not to read big bodies of requests refused anyway.
+async fn authorize_signed(
+    conf: &conf::Signing,
+    storage: &dyn Storage,
+    req: Request,
+) -> Result<(Option<User>, Request), StatusCode> {
+    let (parts, body) = req.into_parts();
+    let now = unix_now_secs();
+    let Some(key) = signing_key(conf, storage, &parts.headers, now).await?
+    else {
+        return Ok((None, Request::from_parts(parts, body)));
+    };
+    let body = axum::body::to_bytes(body, conf.max_body_bytes)
+        .await
+        .map_err(|error| {
+            tracing::warn!(?error, "Rejecting. Signed body unread.");
+            body_unread(&error)
+        })?;
+    let user_opt =
+        authorize_signature(conf, storage, &parts, &body, key, now).await?;
+    Ok((user_opt, Request::from_parts(parts, Body::from(body))))
+}
+
+/// Of the service the request is signed as, unless its headers are invalid,
+/// or its timestamp too far off.
+async fn signing_key(
+    conf: &conf::Signing,
+    storage: &dyn Storage,
+    headers: &header::HeaderMap,
+    now: i64,
+) -> Result<Option<data::ServiceKey>, StatusCode> {
+    let signed = match signing::Signed::from_headers(headers) {
+        Some(Ok(signed)) => signed,
+        invalid => {
+            tracing::debug!(?invalid, "Auth failed.");
+            return Ok(None);
+        }
+    };
+    let service = signed.service;
+    if let Err(invalid) = signed.check_time(conf, now) {
+        tracing::warn!(service, ?invalid, "Signature invalid.");
+        return Ok(None);
+    }
+    let key = storage.service_key_get(service).await.map_err(|error| {
+        tracing::error!(?error, "Failed to get service key.");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    if key.is_none() {
+        tracing::debug!(service, "Auth failed. No such service.");
+    }
+    Ok(key)
+}
+
+/// Of a body read up to its limit: past it, or else broken off.
+pub(crate) fn body_unread(error: &axum::Error) -> StatusCode {
+    let is_too_large = std::error::Error::source(error)
+        .is_some_and(|error| error.is::<http_body_util::LengthLimitError>());
+    if is_too_large {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+async fn authorize_signature(
+    conf: &conf::Signing,
+    storage: &dyn Storage,
+    parts: &axum::http::request::Parts,
+    body: &[u8],
+    key: data::ServiceKey,
+    now: i64,
+) -> Result<Option<User>, StatusCode> {
+    let Some(Ok(signed)) = signing::Signed::from_headers(&parts.headers)
+    else {
+        return Ok(None);
+    };
+    let service = signed.service;
+    // As the service sent it, ahead of any nesting.
+    let uri = parts
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(&parts.uri, |OriginalUri(uri)| uri);
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    if let Err(invalid) = signed.verify(
+        conf,
+        &key.secret,
+        parts.method.as_str(),
+        path,
+        body,
+        now,
+    ) {
+        tracing::warn!(service, ?invalid, "Signature invalid.");
+        return Ok(None);
+    }
+    // Past which the timestamp is refused anyway.
+    let max_skew = i64::try_from(conf.max_skew).unwrap_or(i64::MAX);
+    let expires = signed.timestamp.saturating_add(max_skew).saturating_add(1);
+    let is_claimed = storage
+        .signing_nonce_claim(service, signed.nonce, now, expires)
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to claim signing nonce.");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    if !is_claimed {
+        tracing::warn!(service, "Rejecting. Nonce reused.");
+        return Ok(None);
+    }
+    tracing::debug!(service, "Authorized by signature.");
+    Ok(Some(User {
+        uid: key.uid,
+        role: key.role,
+        org: None,
+        budget_overrides: data::BudgetOverrides::default(),
+        features: Vec::new(),
+    }))
+}
+
+pub(crate) fn unix_now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
         .ok()
-        .map(|claims| User { uid: claims.sub })
+        .and_then(|d| i64::try_from(d.as_secs()).ok())
+        .unwrap_or_default()
 }
 
 fn is_json(s: &str) -> bool {
@@ -0,0 +1,407 @@
+//! Counters shared by the instances behind a load balancer, in Redis.
+//! Each check-and-count is one Lua script, so it's atomic across them.
+
+use std::{sync::LazyLock, time::Duration};
+
+use anyhow::Context;
+
+use crate::{
+    conf,
+    data::{Amount, Exceeded, Reservation},
+    ratelimit,
+};
+
+/// Of daily usage keys. Past the day, so that settlements of requests
+/// which started before midnight still find them.
+const USAGE_TTL: Duration = Duration::from_secs(2 * 24 * 3600);
+
+/// Returns the 1-based index of the first window which is full, or 0 after
+/// counting the request in all of them.
+///
+/// KEYS: current and previous window counts, per window.
+/// ARGV: max requests, remaining fraction of the previous window and TTL,
+/// per window.
+static RATE_ACQUIRE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local n = #KEYS / 2
+        for i = 1, n do
+            local curr = tonumber(redis.call('GET', KEYS[2 * i - 1]) or '0')
+            local prev = tonumber(redis.call('GET', KEYS[2 * i]) or '0')
+            local max = tonumber(ARGV[3 * i - 2])
+            local remaining = tonumber(ARGV[3 * i - 1])
+            if curr + 1 > max - math.floor(prev * remaining) then
+                return i
+            end
+        end
+        for i = 1, n do
+            redis.call('INCR', KEYS[2 * i - 1])
+            redis.call('EXPIRE', KEYS[2 * i - 1], ARGV[3 * i])
+        end
+        return 0
+        ",
+    )
+});
+
+/// Returns 0 after taking a token, or else the milliseconds until there is
+/// one. Full when unknown.
+///
+/// KEYS: tokens and the time they were left at, in a hash.
+/// ARGV: tokens per millisecond, capacity, now in milliseconds and TTL.
+static RATE_TAKE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local rate = tonumber(ARGV[1])
+        local capacity = tonumber(ARGV[2])
+        local now = tonumber(ARGV[3])
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'time')
+        local tokens = capacity
+        if bucket[1] then
+            local elapsed = math.max(0, now - tonumber(bucket[2]))
+            tokens = math.min(capacity, tonumber(bucket[1]) + elapsed * rate)
+        end
+        if tokens < 1 then
+            return math.ceil((1 - tokens) / rate)
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens - 1),
+            'time', now)
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        return 0
+        ",
+    )
+});
+
+/// Returns 0 after counting the amount, or the 1-based index of the first
+/// max it exceeds: of tokens for the model, tokens and cost.
+///
+/// KEYS: tokens, tokens for the model and cost.
+/// ARGV: tokens, cost, their 3 maxes and TTL.
+static RESERVE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local tokens = tonumber(ARGV[1])
+        local cost = tonumber(ARGV[2])
+        local used = tonumber(redis.call('GET', KEYS[1]) or '0')
+        local used_for_model = tonumber(redis.call('GET', KEYS[2]) or '0')
+        local used_cost = tonumber(redis.call('GET', KEYS[3]) or '0')
+        if used_for_model + tokens > tonumber(ARGV[4]) then
+            return 1
+        end
+        if used + tokens > tonumber(ARGV[3]) then
+            return 2
+        end
+        if used_cost + cost > tonumber(ARGV[5]) then
+            return 3
+        end
+        redis.call('INCRBY', KEYS[1], tokens)
+        redis.call('INCRBY', KEYS[2], tokens)
+        redis.call('INCRBYFLOAT', KEYS[3], ARGV[2])
+        for i = 1, 3 do
+            redis.call('EXPIRE', KEYS[i], ARGV[6])
+        end
+        return 0
+        ",
+    )
+});
+
+/// Adds the differences, clamped at 0 the way the database does. Keys
+/// which are gone are left to be loaded from the database again.
+///
+/// KEYS: tokens, tokens for the model and cost.
+/// ARGV: tokens and cost differences.
+static SETTLE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        for i = 1, 3 do
+            if redis.call('EXISTS', KEYS[i]) == 1 then
+                local total
+                if i < 3 then
+                    total = redis.call('INCRBY', KEYS[i], ARGV[1])
+                else
+                    total = tonumber(
+                        redis.call('INCRBYFLOAT', KEYS[i], ARGV[2]))
+                end
+                if total < 0 then
+                    redis.call('SET', KEYS[i], 0, 'KEEPTTL')
+                end
+            end
+        end
+        return 0
+        ",
+    )
+});
+
+/// Daily usage of a user, or the limits on it.
+#[derive(Debug, Clone, Copy)]
+pub struct Totals {
+    pub tokens: i64,
+    pub tokens_for_model: i64,
+    pub cost: f64,
+}
+
+pub struct Redis {
+    conn: redis::aio::ConnectionManager,
+    prefix: String,
+}
+
+impl Redis {
+    pub async fn connect(conf: &conf::Redis) -> anyhow::Result<Self> {
+        let client = redis::Client::open(conf.url.as_str())
+            .context("Invalid Redis URL")?;
+        let conn = redis::aio::ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+        Ok(Self {
+            conn,
+            prefix: conf.prefix.clone(),
+        })
+    }
+
+    /// Same as the SQL version: counts the request, unless it exceeds any
+    /// of the windows, in which case the exceeded window is returned.
+    pub async fn rate_acquire(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Option<ratelimit::Window>> {
+        let mut invocation = RATE_ACQUIRE.prepare_invoke();
+        for window in windows {
+            let period = window.period.as_secs().max(1);
+            let into_window = now.as_secs() % period;
+            let start = now.as_secs() - into_window;
+            let prev_start = start.saturating_sub(period);
+            let elapsed = Duration::from_secs(into_window)
+                + Duration::from_nanos(u64::from(now.subsec_nanos()));
+            let remaining = (1.0
+                - elapsed.as_secs_f64() / window.period.as_secs_f64())
+            .clamp(0.0, 1.0);
+            let key = |start: u64| {
+                self.key(&[
+                    "rate",
+                    uid,
+                    &period.to_string(),
+                    &start.to_string(),
+                ])
+            };
+            invocation
+                .key(key(start))
+                .key(key(prev_start))
+                .arg(window.max_requests)
+                .arg(remaining)
+                .arg(2 * period);
+        }
+        let full: usize = invocation.invoke_async(&mut self.conn()).await?;
+        Ok(full.checked_sub(1).map(|i| windows[i]))
+    }
+
+    /// Per window, counts of its current and previous fixed windows,
+    /// without counting a request.
+    pub async fn rate_counts(
+        &self,
+        uid: &str,
+        windows: &[ratelimit::Window],
+        now: Duration,
+    ) -> anyhow::Result<Vec<(u64, u64)>> {
+        if windows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut keys = Vec::with_capacity(2 * windows.len());
+        for window in windows {
+            let period = window.period.as_secs().max(1);
+            let start = now.as_secs() - now.as_secs() % period;
+            for start in [start, start.saturating_sub(period)] {
+                keys.push(self.key(&[
+                    "rate",
+                    uid,
+                    &period.to_string(),
+                    &start.to_string(),
+                ]));
+            }
+        }
+        let counts: Vec<Option<u64>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut self.conn())
+            .await?;
+        Ok(counts
+            .chunks(2)
+            .map(|counts| (counts[0].unwrap_or(0), counts[1].unwrap_or(0)))
+            .collect())
+    }
+
+    /// Same as the SQL version: takes a token, unless there is none, in
+    /// which case how long until there is one is returned.
+    pub async fn rate_take(
+        &self,
+        uid: &str,
+        role: &str,
+        bucket: ratelimit::Bucket,
+        now: Duration,
+    ) -> anyhow::Result<Option<Duration>> {
+        let wait_ms: u64 = RATE_TAKE
+            .key(self.key(&["bucket", role, uid]))
+            .arg(bucket.rate / 1000.0)
+            .arg(bucket.capacity)
+            .arg(u64::try_from(now.as_millis())?)
+            .arg(bucket.ttl())
+            .invoke_async(&mut self.conn())
+            .await?;
+        Ok((wait_ms > 0).then(|| Duration::from_millis(wait_ms)))
+    }
+
+    /// `None` when not in Redis, e.g. on the first request of the day, in
+    /// which case it is to be loaded with [`Self::seed`].
+    pub async fn usage(
+        &self,
+        uid: &str,
+        model: &str,
+        date: &str,
+    ) -> anyhow::Result<Option<Totals>> {
+        let (tokens, tokens_for_model, cost): (
+            Option<i64>,
+            Option<i64>,
+            Option<f64>,
+        ) = redis::cmd("MGET")
+            .arg(self.usage_keys(uid, model, date))
+            .query_async(&mut self.conn())
+            .await?;
+        Ok(tokens.zip(tokens_for_model).zip(cost).map(
+            |((tokens, tokens_for_model), cost)| Totals {
+                tokens,
+                tokens_for_model,
+                cost,
+            },
+        ))
+    }
+
+    /// Tokens used today, `None` when not in Redis.
+    pub async fn tokens(
+        &self,
+        uid: &str,
+        date: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let tokens: Option<i64> = redis::cmd("GET")
+            .arg(self.key(&["tokens", date, uid]))
+            .query_async(&mut self.conn())
+            .await?;
+        Ok(tokens)
+    }
+
+    /// Sets those which aren't set yet, leaving alone those which another
+    /// instance has set meanwhile.
+    pub async fn seed(
+        &self,
+        uid: &str,
+        model: &str,
+        date: &str,
+        usage: Totals,
+    ) -> anyhow::Result<()> {
+        let [tokens, tokens_for_model, cost] =
+            self.usage_keys(uid, model, date);
+        let ttl = USAGE_TTL.as_secs();
+        redis::pipe()
+            .cmd("SET")
+            .arg(tokens)
+            .arg(usage.tokens)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .ignore()
+            .cmd("SET")
+            .arg(tokens_for_model)
+            .arg(usage.tokens_for_model)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .ignore()
+            .cmd("SET")
+            .arg(cost)
+            .arg(usage.cost)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .ignore()
+            .query_async::<()>(&mut self.conn())
+            .await?;
+        Ok(())
+    }
+
+    /// Counts the reservation against the user's daily usage, unless it
+    /// exceeds any of the maxes. Returns the one it exceeds, if any.
+    pub async fn reserve(
+        &self,
+        reservation: &Reservation,
+        max: Totals,
+    ) -> anyhow::Result<Option<Exceeded>> {
+        let Reservation {
+            uid,
+            model,
+            date,
+            amount,
+            ..
+        } = reservation;
+        let mut invocation = RESERVE.prepare_invoke();
+        for key in self.usage_keys(uid, model, date) {
+            invocation.key(key);
+        }
+        let exceeded: u8 = invocation
+            .arg(amount.tokens)
+            .arg(amount.cost)
+            .arg(max.tokens)
+            .arg(max.tokens_for_model)
+            .arg(max.cost)
+            .arg(USAGE_TTL.as_secs())
+            .invoke_async(&mut self.conn())
+            .await?;
+        Ok(match exceeded {
+            0 => None,
+            1 => Some(Exceeded::Model),
+            2 => Some(Exceeded::Tokens),
+            _ => Some(Exceeded::Cost),
+        })
+    }
+
+    pub async fn settle(
+        &self,
+        reservation: &Reservation,
+        used: Amount,
+    ) -> anyhow::Result<()> {
+        let Reservation {
+            uid,
+            model,
+            date,
+            amount,
+            ..
+        } = reservation;
+        let tokens_delta =
+            i64::try_from(used.tokens)? - i64::try_from(amount.tokens)?;
+        let cost_delta = used.cost - amount.cost;
+        let mut invocation = SETTLE.prepare_invoke();
+        for key in self.usage_keys(uid, model, date) {
+            invocation.key(key);
+        }
+        invocation
+            .arg(tokens_delta)
+            .arg(cost_delta)
+            .invoke_async::<()>(&mut self.conn())
+            .await?;
+        Ok(())
+    }
+
+    fn usage_keys(&self, uid: &str, model: &str, date: &str) -> [String; 3] {
+        [
+            self.key(&["tokens", date, uid]),
+            self.key(&["tokens_by_model", date, uid, model]),
+            self.key(&["costs", date, uid]),
+        ]
+    }
+
+    fn key(&self, parts: &[&str]) -> String {
+        format!("{}{}", self.prefix, parts.join(":"))
+    }
+
+    /// Cheap, since it's a handle to a shared, reconnecting connection.
+    fn conn(&self) -> redis::aio::ConnectionManager {
+        self.conn.clone()
+    }
+}
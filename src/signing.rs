@@ -0,0 +1,216 @@
+//! Of requests by backend services, signed with HMAC-SHA256, with a key
+//! shared with each, kept in storage, per [`conf::Signing`]. For those which
+//! can't easily keep renewing JWTs. The signature covers the method, path,
+//! timestamp, a nonce and the digest of the body, which is checked too.
+//! Timestamps too far off are refused, and so are nonces seen before, so a
+//! signed request can't be replayed.
+
+use rand::Rng;
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+use crate::conf;
+
+/// Of the service, whose key the request is signed with.
+pub const SERVICE_HEADER: &str = "x-raskol-service";
+
+/// Seconds since UNIX epoch, of when it was signed.
+pub const TIMESTAMP_HEADER: &str = "x-raskol-timestamp";
+
+/// Unique to the request, of the service's.
+pub const NONCE_HEADER: &str = "x-raskol-nonce";
+
+/// Hex SHA-256 of the body.
+pub const DIGEST_HEADER: &str = "x-raskol-content-sha256";
+
+/// Hex HMAC-SHA256 of the [`string_to_sign`].
+pub const SIGNATURE_HEADER: &str = "x-raskol-signature";
+
+const SECRET_LEN: usize = 32;
+
+/// Of a request, as signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signed<'a> {
+    pub service: &'a str,
+    pub timestamp: i64,
+    pub nonce: &'a str,
+    pub digest: &'a str,
+    pub signature: &'a str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Invalid {
+    /// Of the headers, the one missing or malformed.
+    Header(&'static str),
+
+    /// Further from now than allowed.
+    Skewed,
+
+    /// Not of the body.
+    Digest,
+    Signature,
+}
+
+impl<'a> Signed<'a> {
+    /// None when not signed at all, as without [`SERVICE_HEADER`].
+    #[must_use]
+    pub fn from_headers(
+        headers: &'a axum::http::HeaderMap,
+    ) -> Option<Result<Self, Invalid>> {
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .ok_or(Invalid::Header(name))
+        };
+        let service = header(SERVICE_HEADER).ok()?;
+        let signed = || -> Result<Self, Invalid> {
+            Ok(Self {
+                service,
+                timestamp: header(TIMESTAMP_HEADER)?
+                    .parse()
+                    .map_err(|_| Invalid::Header(TIMESTAMP_HEADER))?,
+                nonce: header(NONCE_HEADER)?,
+                digest: header(DIGEST_HEADER)?,
+                signature: header(SIGNATURE_HEADER)?,
+            })
+        };
+        Some(signed())
+    }
+
+    /// Of the timestamp, as of now, which can be checked before the body is
+    /// even read.
+    pub fn check_time(
+        &self,
+        conf: &conf::Signing,
+        now: i64,
+    ) -> Result<(), Invalid> {
+        if self.timestamp.abs_diff(now) > conf.max_skew {
+            return Err(Invalid::Skewed);
+        }
+        Ok(())
+    }
+
+    /// Of the request, with the body, as of now, in seconds since UNIX
+    /// epoch. The nonce is left for the caller to check, once the rest is.
+    pub fn verify(
+        &self,
+        conf: &conf::Signing,
+        secret: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: i64,
+    ) -> Result<(), Invalid> {
+        self.check_time(conf, now)?;
+        if !self.digest.eq_ignore_ascii_case(&digest(body)) {
+            return Err(Invalid::Digest);
+        }
+        let signature =
+            hex::decode(self.signature).map_err(|_| Invalid::Signature)?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let string = string_to_sign(
+            method,
+            path,
+            self.timestamp,
+            self.nonce,
+            self.digest,
+        );
+        hmac::verify(&key, string.as_bytes(), &signature)
+            .map_err(|_| Invalid::Signature)
+    }
+}
+
+/// What is signed: the method, path and query, timestamp, nonce and the
+/// digest of the body, a line each.
+#[must_use]
+pub fn string_to_sign(
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    nonce: &str,
+    digest: &str,
+) -> String {
+    format!(
+        "{}\n{path}\n{timestamp}\n{nonce}\n{}",
+        method.to_ascii_uppercase(),
+        digest.to_ascii_lowercase()
+    )
+}
+
+/// As a service would, hex.
+#[must_use]
+pub fn sign(secret: &str, string_to_sign: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hex::encode(hmac::sign(&key, string_to_sign.as_bytes()))
+}
+
+/// Hex SHA-256.
+#[must_use]
+pub fn digest(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// For a service's key, hex.
+#[must_use]
+pub fn secret_generate() -> String {
+    let secret: [u8; SECRET_LEN] = rand::thread_rng().gen();
+    hex::encode(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use crate::conf;
+
+    use super::{Invalid, Signed};
+
+    #[test]
+    fn verify() {
+        let conf = conf::Signing::default();
+        let secret = super::secret_generate();
+        let body = br#"{"model": "gpt-4o"}"#;
+        let digest = super::digest(body);
+        let path = "/v1/chat/completions";
+        let string =
+            super::string_to_sign("POST", path, 1000, "abc", &digest);
+        let signature = super::sign(&secret, &string);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (super::SERVICE_HEADER, "billing"),
+            (super::TIMESTAMP_HEADER, "1000"),
+            (super::NONCE_HEADER, "abc"),
+            (super::DIGEST_HEADER, digest.as_str()),
+            (super::SIGNATURE_HEADER, signature.as_str()),
+        ] {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        let signed = Signed::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(signed.service, "billing");
+        let verify = |method: &str, path: &str, body: &[u8], now: i64| {
+            signed.verify(&conf, &secret, method, path, body, now)
+        };
+        assert_eq!(verify("POST", path, body, 1100), Ok(()));
+        assert_eq!(verify("POST", path, body, 1400), Err(Invalid::Skewed));
+        assert_eq!(verify("POST", path, b"{}", 1000), Err(Invalid::Digest));
+        assert_eq!(verify("PUT", path, body, 1000), Err(Invalid::Signature));
+        assert_eq!(
+            verify("POST", "/v1/embeddings", body, 1000),
+            Err(Invalid::Signature)
+        );
+        assert_eq!(
+            signed.verify(&conf, "other", "POST", path, body, 1000),
+            Err(Invalid::Signature)
+        );
+
+        headers.remove(super::NONCE_HEADER);
+        assert_eq!(
+            Signed::from_headers(&headers),
+            Some(Err(Invalid::Header(super::NONCE_HEADER)))
+        );
+        headers.remove(super::SERVICE_HEADER);
+        assert_eq!(Signed::from_headers(&headers), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Users signing themselves up with an email and an invite code, per
+//! [`conf::Signup`], rather than waiting on an admin to mint their token.
+//! Tokens issued carry a starter budget, which admins may upgrade.
+
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{auth, chat, conf, data::SignupOutcome, server::AppState};
+
+const INVITE_LEN: usize = 10;
+
+/// As per RFC 5321, for the whole address.
+const EMAIL_MAX_LEN: usize = 254;
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_signup))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/signup", post(handle_signup))
+}
+
+#[must_use]
+pub fn invite_generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct SignupReq {
+    email: String,
+    invite: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Signed {
+    token: String,
+    max_tokens_per_day: u64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/signup",
+    security(()),
+    request_body = SignupReq,
+    responses(
+        (status = 200, body = Signed),
+        (status = 400, body = chat::Error),
+        (status = 403, body = chat::Error),
+        (status = 409, body = chat::Error),
+        (status = 404, description = "Signups are off."),
+    )
+)]
+async fn handle_signup(
+    State(AppState { storage, .. }): State<AppState>,
+    Json(SignupReq { email, invite }): Json<SignupReq>,
+) -> Result<Json<Signed>, Response> {
+    let conf = conf::global();
+    let Some(signup) = &conf.signup else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+    let Some(email) = email_normalized(&email) else {
+        tracing::warn!(email, "Rejecting signup. Invalid email.");
+        return Err(rejection(
+            StatusCode::BAD_REQUEST,
+            "invalid_email",
+            "Not an email address.",
+        ));
+    };
+    let outcome = storage
+        .signup_create(&email, invite.trim())
+        .await
+        .map_err(|error| {
+            tracing::error!(?error, "Failed to hit storage.");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        })?;
+    match outcome {
+        SignupOutcome::Created => {}
+        SignupOutcome::InvalidInvite => {
+            tracing::warn!(email, "Rejecting signup. Invalid invite.");
+            return Err(rejection(
+                StatusCode::FORBIDDEN,
+                "invalid_invite",
+                "Invite code is unknown, expired or used up.",
+            ));
+        }
+        SignupOutcome::Exists => {
+            tracing::warn!(email, "Rejecting signup. Already signed up.");
+            return Err(rejection(
+                StatusCode::CONFLICT,
+                "already_signed_up",
+                "Already signed up. Ask an organizer for a new token.",
+            ));
+        }
+    }
+    let token = issue(&conf, signup, &email).map_err(|error| {
+        tracing::error!(?error, "Failed to issue token.");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    tracing::info!(email, "Signed up.");
+    Ok(Json(Signed {
+        token,
+        max_tokens_per_day: signup.max_tokens_per_day,
+    }))
+}
+
+fn issue(
+    conf: &conf::Conf,
+    signup: &conf::Signup,
+    email: &str,
+) -> anyhow::Result<String> {
+    let mut claims = auth::Claims::new(
+        email,
+        Duration::from_secs_f64(signup.ttl),
+        &conf.jwt,
+    )?;
+    claims.role.clone_from(&signup.role);
+    claims.max_tokens_per_day = Some(signup.max_tokens_per_day);
+    Ok(claims.to_str(&conf.jwt)?)
+}
+
+/// Lowercased, since that's how people expect them to compare. Only
+/// roughly checked, since the invite is what is trusted.
+fn email_normalized(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let (local, domain) = email.split_once('@')?;
+    let is_valid = !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && email.len() <= EMAIL_MAX_LEN
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control());
+    is_valid.then_some(email)
+}
+
+fn rejection(status: StatusCode, code: &str, message: &str) -> Response {
+    let error =
+        chat::Error::new("invalid_request_error", code, message.to_string());
+    (status, Json(error)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::email_normalized;
+
+    #[test]
+    fn email() {
+        assert_eq!(
+            email_normalized(" Ada@Example.org ").as_deref(),
+            Some("ada@example.org")
+        );
+        for invalid in
+            ["ada", "@example.org", "ada@org", "a@b@c.org", "a b@c.d"]
+        {
+            assert_eq!(email_normalized(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Exemplars of the slowest API requests, per [`conf::SlowRequests`], kept
+//! in memory for each window of time, at `/admin/slow`, so that why it was
+//! slow at 3pm can be told without debug logs having been on at the time.
+//! With what the logs lack: how long the request was queued, the sizes of
+//! its payloads and which of the provider's keys it went out with.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use axum::{
+    extract::Request, http::header, middleware::Next, response::Response,
+};
+
+use crate::{conf, data};
+
+tokio::task_local! {
+    static NOTED: Arc<Mutex<Noted>>;
+}
+
+static GLOBAL: conf::Derived<Option<Sampler>> =
+    conf::Derived::new(|conf| conf.slow_requests.clone().map(Sampler::new));
+
+/// Of the global conf, unless off.
+#[must_use]
+pub fn global() -> Option<&'static Sampler> {
+    GLOBAL.get().as_ref()
+}
+
+pub struct Sampler {
+    conf: conf::SlowRequests,
+
+    /// Oldest first.
+    windows: Mutex<VecDeque<Window>>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone)]
+pub struct Window {
+    /// Seconds since UNIX epoch.
+    pub start: i64,
+    pub end: i64,
+
+    /// Slowest first.
+    pub requests: Vec<Exemplar>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub req_id: String,
+    pub uid: String,
+    pub endpoint: String,
+    pub model: String,
+    pub status: i64,
+
+    /// Seconds since UNIX epoch, of when it was done.
+    pub time: i64,
+
+    /// Of the whole exchange with upstream, the queue included.
+    pub duration_ms: i64,
+
+    /// Of the duration, waiting in the queue, if it did.
+    pub queued_ms: Option<i64>,
+
+    /// As reported by upstream, if it does.
+    pub upstream_timing: Option<data::Timing>,
+
+    /// As the client declared it.
+    pub request_bytes: Option<u64>,
+
+    /// As received from upstream.
+    pub response_bytes: Option<u64>,
+
+    /// As "provider#index", of the key in conf, never the key itself.
+    pub key: Option<String>,
+
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub error_class: Option<data::ErrorClass>,
+}
+
+/// Of a request, by the handlers, as it goes on.
+#[derive(Debug, Default)]
+struct Noted {
+    request_bytes: Option<u64>,
+    response_bytes: Option<u64>,
+    queued_ms: Option<i64>,
+    key: Option<String>,
+}
+
+impl Sampler {
+    #[must_use]
+    pub fn new(conf: conf::SlowRequests) -> Self {
+        Self {
+            conf,
+            windows: Mutex::default(),
+        }
+    }
+
+    /// Kept if among the slowest of its window so far. Windows past those
+    /// kept are dropped.
+    pub fn offer(&self, exemplar: Exemplar) {
+        let length =
+            i64::try_from(self.conf.window.max(1)).unwrap_or(i64::MAX);
+        let start = exemplar.time - exemplar.time.rem_euclid(length);
+        let mut windows = self.lock();
+        if windows.back().is_none_or(|last| last.start < start) {
+            windows.push_back(Window {
+                start,
+                end: start.saturating_add(length),
+                requests: Vec::new(),
+            });
+            while windows.len() > self.conf.windows.max(1) {
+                windows.pop_front();
+            }
+        }
+        // Not of one dropped already, as of a stream which went on for long.
+        let Some(window) =
+            windows.iter_mut().rev().find(|w| w.start == start)
+        else {
+            return;
+        };
+        let requests = &mut window.requests;
+        let position = requests
+            .iter()
+            .position(|other| other.duration_ms < exemplar.duration_ms)
+            .unwrap_or(requests.len());
+        if position < self.conf.per_window {
+            requests.insert(position, exemplar);
+            requests.truncate(self.conf.per_window);
+        }
+    }
+
+    /// Newest first.
+    #[must_use]
+    pub fn windows(&self) -> Vec<Window> {
+        self.lock().iter().rev().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Window>> {
+        self.windows.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Offers the request logged to the global sampler, if on, along with
+/// what was noted of it.
+pub fn offer(log: &data::RequestLog) {
+    let Some(sampler) = global() else {
+        return;
+    };
+    let noted = NOTED
+        .try_with(|noted| std::mem::take(&mut *lock(noted)))
+        .unwrap_or_default();
+    sampler.offer(Exemplar {
+        req_id: log.req_id.clone(),
+        uid: log.uid.clone(),
+        endpoint: log.endpoint.clone(),
+        model: log.model.clone(),
+        status: log.status,
+        time: log.time,
+        duration_ms: log.duration_ms,
+        queued_ms: noted.queued_ms,
+        upstream_timing: log.timing,
+        request_bytes: noted.request_bytes,
+        response_bytes: noted.response_bytes,
+        key: noted.key,
+        input_tokens: log.input_tokens,
+        output_tokens: log.output_tokens,
+        error_class: log.error_class,
+    });
+}
+
+/// Of the key a request went out with, by its index in conf.
+pub fn note_key(provider: &str, index: usize) {
+    let _ = NOTED.try_with(|noted| {
+        lock(noted).key = Some(format!("{provider}#{index}"));
+    });
+}
+
+pub fn note_queued_ms(queued_ms: i64) {
+    let _ = NOTED.try_with(|noted| {
+        lock(noted).queued_ms = Some(queued_ms);
+    });
+}
+
+pub fn note_response_bytes(bytes: usize) {
+    let _ = NOTED.try_with(|noted| {
+        lock(noted).response_bytes = u64::try_from(bytes).ok();
+    });
+}
+
+/// The future, noting for the request it was made in, for tasks of its own
+/// which outlive the handler, such as those of streams.
+pub fn carry<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let noted = NOTED.try_with(Arc::clone).ok();
+    async move {
+        match noted {
+            Some(noted) => NOTED.scope(noted, future).await,
+            None => future.await,
+        }
+    }
+}
+
+pub(crate) async fn layer(req: Request, next: Next) -> Response {
+    if global().is_none() {
+        return next.run(req).await;
+    }
+    let request_bytes = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let noted = Arc::new(Mutex::new(Noted {
+        request_bytes,
+        ..Noted::default()
+    }));
+    NOTED.scope(noted, next.run(req)).await
+}
+
+fn lock(noted: &Mutex<Noted>) -> MutexGuard<'_, Noted> {
+    noted.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exemplar, Sampler};
+    use crate::conf;
+
+    fn exemplar(req_id: &str, time: i64, duration_ms: i64) -> Exemplar {
+        Exemplar {
+            req_id: req_id.to_string(),
+            uid: "alice".to_string(),
+            endpoint: "chat/completions".to_string(),
+            model: "gpt-4o".to_string(),
+            status: 200,
+            time,
+            duration_ms,
+            queued_ms: None,
+            upstream_timing: None,
+            request_bytes: Some(100),
+            response_bytes: Some(1000),
+            key: Some("primary#0".to_string()),
+            input_tokens: 10,
+            output_tokens: 20,
+            error_class: None,
+        }
+    }
+
+    #[test]
+    fn offer() {
+        let sampler = Sampler::new(conf::SlowRequests {
+            per_window: 2,
+            window: 60,
+            windows: 2,
+        });
+        sampler.offer(exemplar("a", 0, 100));
+        sampler.offer(exemplar("b", 10, 300));
+        sampler.offer(exemplar("c", 20, 200));
+        sampler.offer(exemplar("d", 30, 50));
+        let slowest = |window: &super::Window| -> Vec<String> {
+            window.requests.iter().map(|r| r.req_id.clone()).collect()
+        };
+        let windows = sampler.windows();
+        assert_eq!(windows.len(), 1);
+        assert_eq!((windows[0].start, windows[0].end), (0, 60));
+        assert_eq!(slowest(&windows[0]), ["b", "c"]);
+        sampler.offer(exemplar("e", 70, 10));
+        sampler.offer(exemplar("f", 130, 10));
+        // Late, of a window dropped already.
+        sampler.offer(exemplar("g", 50, 1000));
+        let windows = sampler.windows();
+        let starts: Vec<i64> = windows.iter().map(|w| w.start).collect();
+        assert_eq!(starts, [120, 60]);
+        assert_eq!(slowest(&windows[1]), ["e"]);
+    }
+}
@@ -0,0 +1,264 @@
+//! Kill switch of spending at the providers, per [`conf::SpendCaps`]. Once
+//! what all users together spent today, per [`period::today`], at a provider
+//! reaches its cap, or at all of them the total one, nothing more is
+//! forwarded to it until the day is over, rather than the whole API budget
+//! being blown overnight.
+//!
+//! Spend is kept in storage, to survive restarts, and in memory, for the
+//! checks to cost nothing. Instances sharing the storage see each other's
+//! spend at a provider as of their own last request to it.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    conf,
+    data::{Amount, Storage},
+    events::{Event, Events},
+    period,
+};
+
+/// Name of the cap of all providers together.
+pub const TOTAL: &str = "total";
+
+pub struct Spend {
+    caps: conf::SpendCaps,
+    today: Mutex<Today>,
+}
+
+#[derive(Debug, Default)]
+struct Today {
+    /// YYYY-MM-DD, per [`period::today`].
+    date: String,
+
+    by_provider: BTreeMap<String, Amount>,
+}
+
+/// Of a provider, today.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug, Clone, Copy)]
+pub struct Spent {
+    pub tokens: usize,
+    pub cost: f64,
+
+    /// Whether its cap, or the total one, is reached, so that nothing is
+    /// forwarded to it.
+    pub is_capped: bool,
+}
+
+impl Spend {
+    #[must_use]
+    pub fn new(caps: conf::SpendCaps) -> Self {
+        Self {
+            caps,
+            today: Mutex::new(Today::default()),
+        }
+    }
+
+    /// What was spent today before we started.
+    pub async fn load(&self, storage: &dyn Storage) -> anyhow::Result<()> {
+        let date = period::today();
+        let by_provider = storage.provider_spend(&date).await?;
+        let mut today = self.today();
+        *today = Today {
+            date,
+            by_provider: by_provider.into_iter().collect(),
+        };
+        self.gauge(&today);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_capped(&self, provider: &str) -> bool {
+        let today = self.today();
+        today.date == period::today() && self.cap_reached(&today, provider)
+    }
+
+    #[must_use]
+    pub fn spent(&self, provider: &str) -> Spent {
+        let today = self.today();
+        if today.date != period::today() {
+            return Spent {
+                tokens: 0,
+                cost: 0.0,
+                is_capped: false,
+            };
+        }
+        let amount =
+            today.by_provider.get(provider).copied().unwrap_or_default();
+        Spent {
+            tokens: amount.tokens,
+            cost: amount.cost,
+            is_capped: self.cap_reached(&today, provider),
+        }
+    }
+
+    /// Of a request to the provider, telling admins when it reaches a cap.
+    pub async fn record(
+        &self,
+        storage: &dyn Storage,
+        events: &Events,
+        provider: &str,
+        used: Amount,
+    ) {
+        if used.tokens == 0 && used.cost <= 0.0 {
+            return;
+        }
+        let date = period::today();
+        let stored = storage.provider_spend_add(provider, &date, used).await;
+        let mut today = self.today();
+        if today.date != date {
+            *today = Today {
+                date: date.clone(),
+                by_provider: BTreeMap::new(),
+            };
+        }
+        let was_capped = self.cap_reached(&today, provider);
+        let spent =
+            today.by_provider.entry(provider.to_string()).or_default();
+        match stored {
+            Ok(total) => *spent = total,
+            Err(error) => {
+                // Counted here all the same, so that the caps hold.
+                tracing::error!(?error, provider, "Failed to record spend.");
+                spent.tokens = spent.tokens.saturating_add(used.tokens);
+                spent.cost += used.cost;
+            }
+        }
+        self.gauge(&today);
+        if was_capped {
+            return;
+        }
+        let Some(cap) = self.cap_reached_by(&today, provider) else {
+            return;
+        };
+        let amount = if cap == TOTAL {
+            total(&today)
+        } else {
+            today.by_provider.get(provider).copied().unwrap_or_default()
+        };
+        drop(today);
+        tracing::error!(
+            cap,
+            provider,
+            tokens = amount.tokens,
+            cost = amount.cost,
+            "Spend cap reached. Not forwarding until the day is over."
+        );
+        events.publish(Event::SpendCapped {
+            cap: cap.to_string(),
+            date,
+            tokens: amount.tokens,
+            cost: amount.cost,
+        });
+    }
+
+    fn cap_reached(&self, today: &Today, provider: &str) -> bool {
+        self.cap_reached_by(today, provider).is_some()
+    }
+
+    /// Name of the cap reached: the provider's or the total one.
+    fn cap_reached_by<'a>(
+        &self,
+        today: &Today,
+        provider: &'a str,
+    ) -> Option<&'a str> {
+        let spent = today.by_provider.get(provider).copied();
+        if let (Some(cap), Some(spent)) =
+            (self.caps.providers.get(provider), spent)
+        {
+            if is_reached(cap, spent) {
+                return Some(provider);
+            }
+        }
+        is_reached(&self.caps.total, total(today)).then_some(TOTAL)
+    }
+
+    fn gauge(&self, today: &Today) {
+        for (provider, spent) in &today.by_provider {
+            let provider = provider.clone();
+            #[allow(clippy::cast_precision_loss)]
+            let tokens = spent.tokens as f64;
+            metrics::gauge!(
+                "raskol_provider_spend_tokens",
+                "provider" => provider.clone()
+            )
+            .set(tokens);
+            metrics::gauge!(
+                "raskol_provider_spend_cost",
+                "provider" => provider.clone()
+            )
+            .set(spent.cost);
+            let is_capped = self.cap_reached(today, &provider);
+            metrics::gauge!(
+                "raskol_provider_spend_capped",
+                "provider" => provider
+            )
+            .set(if is_capped { 1.0 } else { 0.0 });
+        }
+    }
+
+    fn today(&self) -> MutexGuard<'_, Today> {
+        self.today.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn total(today: &Today) -> Amount {
+    today
+        .by_provider
+        .values()
+        .fold(Amount::default(), |total, spent| Amount {
+            tokens: total.tokens.saturating_add(spent.tokens),
+            cost: total.cost + spent.cost,
+        })
+}
+
+fn is_reached(cap: &conf::SpendCap, spent: Amount) -> bool {
+    let tokens = u64::try_from(spent.tokens).unwrap_or(u64::MAX);
+    cap.max_tokens_per_day.is_some_and(|max| tokens >= max)
+        || cap.max_cost_per_day.is_some_and(|max| spent.cost >= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Spend, Today, TOTAL};
+    use crate::{conf, data::Amount};
+
+    #[test]
+    fn caps() {
+        let cap = |tokens, cost| conf::SpendCap {
+            max_tokens_per_day: tokens,
+            max_cost_per_day: cost,
+        };
+        let spend = Spend::new(conf::SpendCaps {
+            total: cap(None, Some(10.0)),
+            providers: BTreeMap::from([(
+                "primary".to_string(),
+                cap(Some(1000), None),
+            )]),
+        });
+        let amount = |tokens, cost| Amount { tokens, cost };
+        let mut today = Today {
+            date: "2025-06-01".to_string(),
+            by_provider: BTreeMap::from([
+                ("primary".to_string(), amount(999, 1.0)),
+                ("failover".to_string(), amount(5000, 1.0)),
+            ]),
+        };
+        assert_eq!(spend.cap_reached_by(&today, "primary"), None);
+        assert_eq!(spend.cap_reached_by(&today, "failover"), None);
+        today
+            .by_provider
+            .insert("primary".to_string(), amount(1000, 1.0));
+        assert_eq!(spend.cap_reached_by(&today, "primary"), Some("primary"));
+        assert_eq!(spend.cap_reached_by(&today, "failover"), None);
+        today
+            .by_provider
+            .insert("failover".to_string(), amount(0, 9.0));
+        assert_eq!(spend.cap_reached_by(&today, "failover"), Some(TOTAL));
+        assert_eq!(spend.cap_reached_by(&today, "shadow"), Some(TOTAL));
+    }
+}
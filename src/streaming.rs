@@ -0,0 +1,214 @@
+//! Chat completions streamed as upstream sends them, in server-sent events,
+//! rather than passed on once whole. Tallied as they pass through, a token
+//! per delta with content, since that is how providers send them, for the
+//! budget to be drawn on as they arrive, [`DRAW`] tokens at a time. Once a
+//! draw is refused, the stream is cut off with a final error event, as
+//! OpenAI ends streams which fail midway. So are streams which upstream
+//! broke off, rather than leave clients with a silent truncation.
+//!
+//! Upstream is always asked for the usage at the end, for the stream to be
+//! billed by what it says rather than by the tally, which is only a
+//! fallback. The event with it is passed on only to clients which asked for
+//! it too.
+
+use axum::body::Bytes;
+
+use crate::chat;
+
+/// Tokens drawn from the budget at a time, as a stream goes on. How far
+/// past the budget a stream may get, at most.
+pub const DRAW: usize = 64;
+
+/// Of a stream so far.
+#[derive(Debug, Default)]
+pub struct Tally {
+    /// Of a line not yet complete, since chunks may end anywhere.
+    pending: Vec<u8>,
+
+    pub completion_tokens: usize,
+
+    /// Received.
+    pub bytes: usize,
+
+    /// As upstream reported it, by the end, if asked to.
+    pub usage: Option<chat::Usage>,
+
+    /// Whether upstream ended it, with "[DONE]", rather than just stopped.
+    pub is_done: bool,
+
+    /// Of an error event, which upstream may send instead of finishing.
+    pub error: Option<String>,
+
+    /// Whether the event with just the usage is kept from the client,
+    /// which didn't ask for it.
+    pub is_usage_stripped: bool,
+}
+
+impl Tally {
+    /// Of what was received, the complete lines, to pass on, so that the
+    /// stream can be cut off between events.
+    pub fn add(&mut self, chunk: &[u8]) -> Bytes {
+        self.bytes = self.bytes.saturating_add(chunk.len());
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Bytes::new();
+        };
+        let complete: Vec<u8> = self.pending.drain(..=end).collect();
+        let mut passed = Vec::with_capacity(complete.len());
+        for line in complete.split_inclusive(|b| *b == b'\n') {
+            if self.line(&String::from_utf8_lossy(line)) {
+                passed.extend_from_slice(line);
+            }
+        }
+        Bytes::from(passed)
+    }
+
+    /// What is left of a stream which ended without a final newline.
+    pub fn rest(&mut self) -> Bytes {
+        let rest = std::mem::take(&mut self.pending);
+        if self.line(&String::from_utf8_lossy(&rest)) {
+            Bytes::from(rest)
+        } else {
+            Bytes::new()
+        }
+    }
+
+    /// Returns whether to pass it on.
+    fn line(&mut self, line: &str) -> bool {
+        let Some(data) = line.strip_prefix("data:") else {
+            return true;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            self.is_done = true;
+            return true;
+        }
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(data)
+        else {
+            return true;
+        };
+        self.completion_tokens =
+            self.completion_tokens.saturating_add(deltas(&event));
+        let usage = chat::Usage::from_resp(&event);
+        let is_usage_only = usage.is_some()
+            && event["choices"].as_array().is_some_and(Vec::is_empty);
+        if let Some(usage) = usage {
+            self.usage = Some(usage);
+        }
+        let error = &event["error"];
+        if !error.is_null() {
+            self.error = Some(
+                error["message"]
+                    .as_str()
+                    .map_or_else(|| error.to_string(), str::to_string),
+            );
+        }
+        !(is_usage_only && self.is_usage_stripped)
+    }
+}
+
+/// Ending a stream cut off short, with the error. After a blank line, in
+/// case the last event passed on was left unterminated.
+#[must_use]
+pub fn cut_off(error: &chat::Error) -> Bytes {
+    let data = serde_json::to_string(error).unwrap_or_default();
+    Bytes::from(format!("\ndata: {data}\n\ndata: [DONE]\n\n"))
+}
+
+/// Of an event, those with content, of text or of tool calls.
+fn deltas(event: &serde_json::Value) -> usize {
+    let Some(choices) = event["choices"].as_array() else {
+        return 0;
+    };
+    let is_filled = |value: &serde_json::Value| {
+        value.as_str().is_some_and(|text| !text.is_empty())
+    };
+    choices
+        .iter()
+        .map(|choice| {
+            let delta = &choice["delta"];
+            let tool_calls =
+                delta["tool_calls"].as_array().map_or(0, |calls| {
+                    calls
+                        .iter()
+                        .filter(|call| {
+                            is_filled(&call["function"]["arguments"])
+                        })
+                        .count()
+                });
+            usize::from(is_filled(&delta["content"])) + tool_calls
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tally;
+
+    const STREAM: &str = concat!(
+        r#"data: {"choices":[{"delta":{"role":"assistant","content":""}}]}"#,
+        "\n\n",
+        r#"data: {"choices":[{"delta":{"content":"Hi"}}]}"#,
+        "\n\n",
+        r#"data: {"choices":[{"delta":{"content":" there"}}]}"#,
+        "\n\n",
+        r#"data: {"choices":[],"usage":{"prompt_tokens":5,"#,
+        r#""completion_tokens":2,"total_tokens":7}}"#,
+        "\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    #[test]
+    fn tally() {
+        let mut tally = Tally::default();
+        let mut passed = Vec::new();
+        // Split mid-event, as upstream may.
+        for chunk in STREAM.as_bytes().chunks(7) {
+            passed.extend_from_slice(&tally.add(chunk));
+        }
+        passed.extend_from_slice(&tally.rest());
+        assert_eq!(passed, STREAM.as_bytes());
+        assert_eq!(tally.completion_tokens, 2);
+        let usage = tally.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 5);
+        assert_eq!(usage.completion_tokens, 2);
+        assert!(tally.is_done);
+        assert_eq!(tally.error, None);
+    }
+
+    #[test]
+    fn usage_stripped() {
+        let mut tally = Tally {
+            is_usage_stripped: true,
+            ..Tally::default()
+        };
+        let mut passed = tally.add(STREAM.as_bytes()).to_vec();
+        passed.extend_from_slice(&tally.rest());
+        let passed = String::from_utf8(passed).unwrap();
+        assert!(!passed.contains("usage"));
+        assert!(passed.contains(" there"));
+        assert!(passed.ends_with("data: [DONE]\n\n"));
+        assert_eq!(tally.usage.unwrap().completion_tokens, 2);
+    }
+
+    #[test]
+    fn error() {
+        let mut tally = Tally::default();
+        tally.add(br#"data: {"choices":[{"delta":{"content":"Hi"}}]}"#);
+        tally.add(b"\n\n");
+        assert!(!tally.is_done);
+        tally.add(br#"data: {"error":{"message":"Overloaded."}}"#);
+        tally.add(b"\n\n");
+        assert_eq!(tally.error.as_deref(), Some("Overloaded."));
+        assert_eq!(tally.completion_tokens, 1);
+    }
+
+    #[test]
+    fn pending() {
+        let mut tally = Tally::default();
+        assert!(tally.add(br#"data: {"choices":[{"delta":"#).is_empty());
+        assert!(!tally.add(b"{\"content\":\"x\"}}]}\n").is_empty());
+        assert_eq!(tally.completion_tokens, 1);
+        assert!(tally.rest().is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Reporting of usage to Stripe, per [`conf::Stripe`], as meter events of
+//! the customers mapped to users and orgs, for running a paid gateway.
+//!
+//! Only closed days are reported, once per customer each: the event's
+//! identifier is of the account and day, which Stripe deduplicates, and
+//! the day is recorded as reported once Stripe accepts it, so that retries
+//! after failures, or from several instances, don't bill twice.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{Days, NaiveDate, Utc};
+
+use crate::{
+    billing::{self, Invoice},
+    conf,
+    data::{Storage, StripeCustomer},
+    jobs, period,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A meter event, sent or, in a dry run, not.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Reported {
+    pub kind: billing::Account,
+    pub id: String,
+    pub customer: String,
+
+    /// YYYY-MM-DD, UTC.
+    pub date: String,
+
+    pub value: u64,
+    pub identifier: String,
+}
+
+/// As the "report_stripe" job, per [`jobs`].
+pub fn spawn(
+    storage: Arc<dyn Storage>,
+    http: reqwest::Client,
+    conf: conf::Stripe,
+) {
+    let interval = Duration::from_secs(conf.interval);
+    jobs::spawn("report_stripe", interval, move || {
+        let (storage, http, conf) =
+            (storage.clone(), http.clone(), conf.clone());
+        async move {
+            let reported =
+                report(storage.as_ref(), &http, &conf, conf.dry_run)
+                    .await
+                    .context("Failed to report usage to Stripe.")?;
+            if !reported.is_empty() {
+                tracing::info!(
+                    events = reported.len(),
+                    dry_run = conf.dry_run,
+                    "Reported usage to Stripe."
+                );
+            }
+            Ok(())
+        }
+    });
+}
+
+/// The closed days of the lookback which are not reported yet. Stops at
+/// the first failure, leaving the rest for the next run.
+pub async fn report(
+    storage: &dyn Storage,
+    http: &reqwest::Client,
+    conf: &conf::Stripe,
+    dry_run: bool,
+) -> anyhow::Result<Vec<Reported>> {
+    let customers = storage.stripe_customers().await?;
+    if customers.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Of the days, so that what credits covered isn't billed.
+    let max_rollover = conf::global()
+        .rollover
+        .as_ref()
+        .map(|rollover| rollover.max_credits);
+    storage
+        .credits_close(&period::current(), max_rollover)
+        .await?;
+    let today = Utc::now().date_naive();
+    let mut reported = Vec::new();
+    for days in (1..=u64::from(conf.lookback_days)).rev() {
+        let date = today
+            .checked_sub_days(Days::new(days))
+            .context("Date out of range.")?;
+        let date = date.format("%Y-%m-%d").to_string();
+        let invoices = billing::daily(storage, &date).await?;
+        for customer in &customers {
+            let Some(event) = event(conf, customer, &date, &invoices) else {
+                continue;
+            };
+            if storage
+                .stripe_is_reported(event.kind, &event.id, &date)
+                .await?
+            {
+                continue;
+            }
+            if dry_run {
+                tracing::info!(?event, "Would report usage to Stripe.");
+            } else {
+                send(http, conf, &event).await?;
+                storage
+                    .stripe_reported(
+                        event.kind,
+                        &event.id,
+                        &date,
+                        event.value,
+                    )
+                    .await?;
+                tracing::info!(?event, "Reported usage to Stripe.");
+            }
+            reported.push(event);
+        }
+    }
+    Ok(reported)
+}
+
+/// None when there was no usage to bill.
+fn event(
+    conf: &conf::Stripe,
+    customer: &StripeCustomer,
+    date: &str,
+    invoices: &[Invoice],
+) -> Option<Reported> {
+    let invoice = invoices.iter().find(|invoice| {
+        invoice.account == customer.kind && invoice.id == customer.id
+    })?;
+    let value = match conf.measure {
+        conf::StripeMeasure::Tokens => invoice
+            .total
+            .input_tokens
+            .saturating_add(invoice.total.output_tokens)
+            .saturating_sub(invoice.total.credited_tokens),
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        conf::StripeMeasure::Cents => {
+            (invoice.total.cost * 100.0).round().max(0.0) as u64
+        }
+    };
+    (value > 0).then(|| Reported {
+        kind: customer.kind,
+        id: customer.id.clone(),
+        customer: customer.customer.clone(),
+        date: date.to_string(),
+        value,
+        identifier: format!(
+            "raskol-{}-{}-{date}",
+            customer.kind.as_str(),
+            customer.id
+        ),
+    })
+}
+
+async fn send(
+    http: &reqwest::Client,
+    conf: &conf::Stripe,
+    event: &Reported,
+) -> anyhow::Result<()> {
+    // Last second of the day, which the usage is of.
+    let timestamp = NaiveDate::parse_from_str(&event.date, "%Y-%m-%d")?
+        .and_hms_opt(23, 59, 59)
+        .context("Invalid end of day.")?
+        .and_utc()
+        .timestamp();
+    let url = format!(
+        "{}/v1/billing/meter_events",
+        conf.api_base.trim_end_matches('/')
+    );
+    http.post(url)
+        .timeout(TIMEOUT)
+        .bearer_auth(&conf.api_key)
+        .header("idempotency-key", &event.identifier)
+        .form(&[
+            ("event_name", conf.event_name.clone()),
+            ("identifier", event.identifier.clone()),
+            ("timestamp", timestamp.to_string()),
+            ("payload[stripe_customer_id]", event.customer.clone()),
+            ("payload[value]", event.value.to_string()),
+        ])
+        .send()
+        .await
+        .context("Failed to reach Stripe.")?
+        .error_for_status()
+        .context("Stripe rejected meter event.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::event;
+    use crate::{
+        billing::{Account, Invoice, Line},
+        conf,
+        data::StripeCustomer,
+    };
+
+    #[test]
+    fn events() {
+        let mut conf = conf::Stripe {
+            api_key: "sk_test".to_string(),
+            event_name: "tokens".to_string(),
+            measure: conf::StripeMeasure::Tokens,
+            interval: 3600,
+            lookback_days: 7,
+            dry_run: false,
+            api_base: "http://localhost".to_string(),
+        };
+        let invoices = [Invoice {
+            account: Account::Org,
+            id: "team".to_string(),
+            month: "2025-06-01".to_string(),
+            total: Line {
+                input_tokens: 100,
+                output_tokens: 20,
+                cost: 0.125,
+                ..Line::default()
+            },
+            models: Vec::new(),
+        }];
+        let customer = |kind, id: &str| StripeCustomer {
+            kind,
+            id: id.to_string(),
+            customer: "cus_1".to_string(),
+        };
+        let team = customer(Account::Org, "team");
+        let reported = event(&conf, &team, "2025-06-01", &invoices).unwrap();
+        assert_eq!(reported.value, 120);
+        assert_eq!(reported.identifier, "raskol-org-team-2025-06-01");
+        conf.measure = conf::StripeMeasure::Cents;
+        let reported = event(&conf, &team, "2025-06-01", &invoices).unwrap();
+        assert_eq!(reported.value, 13);
+        let user = customer(Account::User, "team");
+        assert!(event(&conf, &user, "2025-06-01", &invoices).is_none());
+    }
+}
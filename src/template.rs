@@ -0,0 +1,385 @@
+//! Prompt templates kept server-side, per user or org, so that clients
+//! send just the variables instead of the whole prompt every time.
+//! Placeholders are `{{name}}`, in the text of the messages.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+
+use crate::{
+    chat,
+    data::{self, Owner},
+    server::{self, AppState, USER},
+};
+
+const NAME_MAX_LEN: usize = 64;
+
+/// As stored and as given to the API.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Clone)]
+#[schema(as = TemplateBody)]
+pub struct Body {
+    /// Unless the completion asks for another.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    pub messages: Vec<chat::Msg>,
+}
+
+impl Body {
+    /// Names of the placeholders.
+    #[must_use]
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for msg in &self.messages {
+            visit(&msg.content, &mut |text| {
+                names.extend(placeholders(text).map(|(_, name)| name));
+            });
+        }
+        names
+    }
+
+    /// The messages with the placeholders filled in, or the names of those
+    /// which have no value.
+    pub fn render(
+        &self,
+        values: &BTreeMap<String, String>,
+    ) -> Result<Vec<chat::Msg>, BTreeSet<String>> {
+        let missing: BTreeSet<String> = self
+            .variables()
+            .into_iter()
+            .filter(|name| !values.contains_key(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+        let mut messages = self.messages.clone();
+        for msg in &mut messages {
+            visit_mut(&mut msg.content, &mut |text| {
+                *text = fill(text, values);
+            });
+        }
+        Ok(messages)
+    }
+}
+
+/// Text content, or the text of its parts.
+fn visit(content: &serde_json::Value, f: &mut impl FnMut(&str)) {
+    match content {
+        serde_json::Value::String(text) => f(text),
+        serde_json::Value::Array(parts) => {
+            for part in parts {
+                if let Some(text) = part.get("text").and_then(|t| t.as_str())
+                {
+                    f(text);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn visit_mut(
+    content: &mut serde_json::Value,
+    f: &mut impl FnMut(&mut String),
+) {
+    match content {
+        serde_json::Value::String(text) => f(text),
+        serde_json::Value::Array(parts) => {
+            for part in parts {
+                if let Some(serde_json::Value::String(text)) =
+                    part.get_mut("text")
+                {
+                    f(text);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Byte ranges and names of the placeholders in the text. Braces around
+/// anything but a name are left alone.
+fn placeholders(
+    text: &str,
+) -> impl Iterator<Item = (std::ops::Range<usize>, String)> + '_ {
+    let mut from = 0;
+    std::iter::from_fn(move || loop {
+        let start = from + text[from..].find("{{")?;
+        let end = start + 2 + text[start + 2..].find("}}")? + 2;
+        let name = text[start + 2..end - 2].trim();
+        if is_name_valid(name) {
+            from = end;
+            return Some((start..end, name.to_string()));
+        }
+        from = start + 2;
+    })
+}
+
+fn fill(text: &str, values: &BTreeMap<String, String>) -> String {
+    let mut filled = String::with_capacity(text.len());
+    let mut from = 0;
+    for (range, name) in placeholders(text) {
+        filled.push_str(&text[from..range.start]);
+        filled.push_str(values.get(&name).map_or("", String::as_str));
+        from = range.end;
+    }
+    filled.push_str(&text[from..]);
+    filled
+}
+
+/// Of templates and variables alike.
+fn is_name_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= NAME_MAX_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_list, handle_get, handle_put, handle_delete))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/templates", get(handle_list)).route(
+        "/templates/:name",
+        get(handle_get).put(handle_put).delete(handle_delete),
+    )
+}
+
+/// What clients see of a stored template.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+#[schema(as = Template)]
+struct View {
+    name: String,
+    version: i64,
+    #[serde(flatten)]
+    body: Body,
+    variables: BTreeSet<String>,
+    time: i64,
+}
+
+impl TryFrom<data::Template> for View {
+    type Error = anyhow::Error;
+
+    fn try_from(template: data::Template) -> anyhow::Result<Self> {
+        let body: Body = serde_json::from_str(&template.body)?;
+        Ok(Self {
+            name: template.name,
+            version: template.version,
+            variables: body.variables(),
+            body,
+            time: template.time,
+        })
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OwnerQuery {
+    /// The org's templates, instead of the user's own.
+    org: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GetQuery {
+    org: Option<String>,
+
+    /// The latest when not given.
+    version: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates",
+    tag = "templates",
+    params(OwnerQuery),
+    responses(
+        (status = 200, body = Vec<View>),
+        (status = 403, description = "Not of the org."),
+    )
+)]
+async fn handle_list(
+    State(AppState { storage, .. }): State<AppState>,
+    Query(OwnerQuery { org }): Query<OwnerQuery>,
+) -> Result<Json<Vec<View>>, StatusCode> {
+    let uid = USER.get().uid;
+    let owner = owner(&uid, org.as_deref())?;
+    let templates = storage.template_list(owner).await.map_err(internal)?;
+    let views = templates
+        .into_iter()
+        .map(View::try_from)
+        .collect::<anyhow::Result<_>>()
+        .map_err(internal)?;
+    Ok(Json(views))
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates/{name}",
+    tag = "templates",
+    params(("name" = String, Path), GetQuery),
+    responses(
+        (status = 200, body = View),
+        (status = 404, description = "No such template or version."),
+    )
+)]
+async fn handle_get(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(name): Path<String>,
+    Query(GetQuery { org, version }): Query<GetQuery>,
+) -> Result<Json<View>, StatusCode> {
+    let uid = USER.get().uid;
+    let owner = owner(&uid, org.as_deref())?;
+    let template = storage
+        .template_get(owner, &name, version)
+        .await
+        .map_err(internal)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(View::try_from(template).map_err(internal)?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/templates/{name}",
+    tag = "templates",
+    params(("name" = String, Path), OwnerQuery),
+    request_body = Body,
+    responses(
+        (status = 200, description = "A new version.", body = View),
+        (status = 400, description = "Invalid name or no messages."),
+    )
+)]
+async fn handle_put(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(name): Path<String>,
+    Query(OwnerQuery { org }): Query<OwnerQuery>,
+    Json(body): Json<Body>,
+) -> Result<Json<View>, StatusCode> {
+    let uid = USER.get().uid;
+    let owner = owner(&uid, org.as_deref())?;
+    if !is_name_valid(&name) || body.messages.is_empty() {
+        tracing::warn!(name, "Rejecting. Invalid template.");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let json = serde_json::to_string(&body)
+        .map_err(|error| internal(error.into()))?;
+    let version = storage
+        .template_put(owner, &name, &json)
+        .await
+        .map_err(internal)?;
+    tracing::info!(name, version, ?owner, "Template saved.");
+    let template = storage
+        .template_get(owner, &name, Some(version))
+        .await
+        .map_err(internal)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(View::try_from(template).map_err(internal)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/templates/{name}",
+    tag = "templates",
+    params(("name" = String, Path), OwnerQuery),
+    responses(
+        (status = 204, description = "All versions deleted."),
+        (status = 404, description = "No such template."),
+    )
+)]
+async fn handle_delete(
+    State(AppState { storage, .. }): State<AppState>,
+    Path(name): Path<String>,
+    Query(OwnerQuery { org }): Query<OwnerQuery>,
+) -> Result<StatusCode, StatusCode> {
+    let uid = USER.get().uid;
+    let owner = owner(&uid, org.as_deref())?;
+    if storage
+        .template_delete(owner, &name)
+        .await
+        .map_err(internal)?
+    {
+        tracing::info!(name, ?owner, "Template deleted.");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The user's own, or the org's, if they're allowed to see it.
+fn owner<'a>(
+    uid: &'a str,
+    org: Option<&'a str>,
+) -> Result<Owner<'a>, StatusCode> {
+    match org {
+        None => Ok(Owner::User(uid)),
+        Some(org) if server::is_org_allowed(&USER.get(), org) => {
+            Ok(Owner::Org(org))
+        }
+        Some(org) => {
+            tracing::warn!(
+                org,
+                "Rejecting. Other orgs' templates not allowed."
+            );
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+fn internal(error: anyhow::Error) -> StatusCode {
+    tracing::error!(?error, "Failed to hit storage.");
+    StatusCode::SERVICE_UNAVAILABLE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::Body;
+
+    fn body(json: serde_json::Value) -> Body {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn render() {
+        let template = body(serde_json::json!({
+            "messages": [
+                {"role": "system", "content": "You speak {{ lang }}."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Translate: {{text}}"},
+                    {"type": "image_url", "image_url": {"url": "{{text}}"}}
+                ]}
+            ]
+        }));
+        assert_eq!(
+            template.variables().into_iter().collect::<Vec<_>>(),
+            ["lang", "text"]
+        );
+        let values = BTreeMap::from([
+            ("lang".to_string(), "French".to_string()),
+            ("text".to_string(), "{{lang}}".to_string()),
+        ]);
+        let messages = template.render(&values).unwrap();
+        assert_eq!(messages[0].text(), "You speak French.");
+        // Values are not rendered again, nor are non-text parts.
+        assert_eq!(messages[1].text(), "Translate: {{lang}}");
+        assert_eq!(messages[1].content[1]["image_url"]["url"], "{{text}}");
+    }
+
+    #[test]
+    fn missing() {
+        let template = body(serde_json::json!({
+            "messages": [{"role": "user", "content": "{{a}} {{b}} {{ }}"}]
+        }));
+        let values = BTreeMap::from([("a".to_string(), "1".to_string())]);
+        let missing = template.render(&values).unwrap_err();
+        assert_eq!(missing.into_iter().collect::<Vec<_>>(), ["b"]);
+    }
+}
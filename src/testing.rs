@@ -0,0 +1,151 @@
+//! For integration tests, of raskol or of apps embedding it: a server run
+//! in-process, on a random port, with its data in a temp dir and a stub of
+//! upstream, per [`crate::mock`], so that each test needn't set them up.
+//! Each has its own conf, but what the test runs outside of any, such as
+//! [`crate::data::connect`], sees the first one's, unless one is set, per
+//! [`conf::set`]. Behind the `testing` feature.
+
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use tempfile::TempDir;
+
+use crate::{
+    auth,
+    conf::{self, Conf},
+    jwt, mock, Routes, Server,
+};
+
+/// Of the JWTs minted by [`Harness::jwt`].
+const JWT_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub struct Harness {
+    /// Of the server, as "http://127.0.0.1:1234", or "https://" when the
+    /// conf has TLS.
+    pub url: String,
+
+    /// Of the stub of upstream.
+    pub upstream: SocketAddr,
+    pub conf: Arc<Conf>,
+
+    /// Removed once the harness is dropped.
+    pub dir: TempDir,
+}
+
+impl Harness {
+    /// With the default conf, but for the harness's parts, as of
+    /// [`Self::start_with`].
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::start_with(Conf::default(), mock::Mock::default()).await
+    }
+
+    /// With the conf given, but for its storage, upstream and JWT secret,
+    /// which are the harness's, and its minimum interval between a user's
+    /// requests, which is none, for tests to make them back to back. Per
+    /// role, it still applies. Served with TLS of the conf's cert files, if
+    /// any.
+    pub async fn start_with(
+        conf: Conf,
+        mock: mock::Mock,
+    ) -> anyhow::Result<Self> {
+        Self::start_with_routes(conf, mock, &Routes::ALL).await
+    }
+
+    /// As [`Self::start_with`], but of only the routes given.
+    pub async fn start_with_routes(
+        mut conf: Conf,
+        mock: mock::Mock,
+        routes: &[Routes],
+    ) -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let upstream = serve(mock::routes(mock)).await?;
+        conf.storage = conf::Storage::Sqlite {
+            file: dir.path().join("data.db"),
+        };
+        conf.target_address = upstream.to_string();
+        conf.target_scheme = conf::Scheme::Http;
+        conf.jwt.secret = "test-secret".to_string();
+        conf.min_hit_interval = 0.0;
+        let tls = conf.tls.clone();
+        let kept = Arc::new(conf.clone());
+        let router = Server::new(conf)
+            .routes(routes)
+            .background(false)
+            .router()
+            .await
+            .context("Failed to build server.")?;
+        let url = match tls {
+            None => format!("http://{}", serve(router).await?),
+            Some(conf::Tls::Files {
+                cert_file,
+                key_file,
+            }) => {
+                let addr = serve_tls(router, &cert_file, &key_file).await?;
+                format!("https://{addr}")
+            }
+            Some(conf::Tls::Acme { .. }) => {
+                return Err(anyhow!("Tests can't provision certs via ACME."));
+            }
+        };
+        Ok(Self {
+            url,
+            upstream,
+            conf: kept,
+            dir,
+        })
+    }
+
+    /// Of the path, as "/v1/chat/completions".
+    #[must_use]
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.url)
+    }
+
+    /// For the user, of the role, valid for an hour.
+    pub fn jwt(&self, uid: &str, role: &str) -> anyhow::Result<String> {
+        let mut claims = auth::Claims::new(uid, JWT_TTL, &self.conf.jwt)?;
+        role.clone_into(&mut claims.role);
+        Ok(jwt::encode(&claims, &self.conf.jwt)?)
+    }
+}
+
+/// On a random port of localhost, until the runtime ends.
+async fn serve(router: axum::Router) -> anyhow::Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let routes = router.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, routes).await {
+            tracing::error!(?error, %addr, "Test server failed.");
+        }
+    });
+    Ok(addr)
+}
+
+/// As [`serve`], with TLS.
+async fn serve_tls(
+    router: axum::Router,
+    cert_file: &Path,
+    key_file: &Path,
+) -> anyhow::Result<SocketAddr> {
+    // Whichever is installed first will do, if the test installed one.
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(
+        cert_file, key_file,
+    )
+    .await
+    .context("Failed to read the cert files.")?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let routes = router.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        if let Err(error) = axum_server::from_tcp_rustls(listener, config)
+            .serve(routes)
+            .await
+        {
+            tracing::error!(?error, %addr, "Test server failed.");
+        }
+    });
+    Ok(addr)
+}
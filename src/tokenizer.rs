@@ -0,0 +1,167 @@
+//! Exact token counts for models with a configured tokenizer, so that
+//! budgets are not charged by a rough estimate when we can do better, and
+//! calibration of the estimates by how they compared with actual counts.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{LazyLock, PoisonError, RwLock},
+};
+
+use tokenizers::Tokenizer;
+
+use crate::conf;
+
+static GLOBAL: conf::Derived<Registry> =
+    conf::Derived::new(|conf| Registry::new(&conf.tokenizers));
+
+/// Of none, so that counts fall back to the rough estimate.
+static UNTOKENIZED: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// The tokenizers of the global conf.
+#[must_use]
+pub fn global() -> &'static Registry {
+    GLOBAL.get()
+}
+
+/// To count prompts with ahead: none for local providers, over plain HTTP,
+/// which report their usage anyway, and whose tokenizers may be other than
+/// those configured for the model names they serve.
+#[must_use]
+pub fn for_prompts(conf: &conf::Conf) -> &'static Registry {
+    match conf.target_scheme {
+        conf::Scheme::Https => global(),
+        conf::Scheme::Http => &UNTOKENIZED,
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    /// Most specific (longest) pattern first.
+    tokenizers: Vec<(String, Tokenizer)>,
+
+    /// Model -> calibration factor. Replaced as they are recomputed.
+    factors: RwLock<BTreeMap<String, f64>>,
+}
+
+impl Registry {
+    /// Tokenizers which fail to load are skipped, with an error logged,
+    /// rather than preventing startup.
+    #[must_use]
+    pub fn new(conf: &BTreeMap<String, PathBuf>) -> Self {
+        let tokenizers = conf
+            .iter()
+            .filter_map(|(pattern, path)| {
+                Tokenizer::from_file(path)
+                    .map_err(|error| {
+                        tracing::error!(
+                            ?error,
+                            pattern,
+                            ?path,
+                            "Failed to load tokenizer. Skipping."
+                        );
+                    })
+                    .ok()
+                    .map(|tokenizer| (pattern.clone(), tokenizer))
+            })
+            .collect();
+        Self::from_tokenizers(tokenizers)
+    }
+
+    fn from_tokenizers(mut tokenizers: Vec<(String, Tokenizer)>) -> Self {
+        tokenizers
+            .sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+        Self {
+            tokenizers,
+            factors: RwLock::default(),
+        }
+    }
+
+    pub fn set_factors(&self, factors: BTreeMap<String, f64>) {
+        *self.factors.write().unwrap_or_else(PoisonError::into_inner) =
+            factors;
+    }
+
+    /// The estimate corrected by the model's calibration factor, if any.
+    #[must_use]
+    pub fn calibrate(&self, model: &str, estimate: usize) -> usize {
+        let factors =
+            self.factors.read().unwrap_or_else(PoisonError::into_inner);
+        match factors.get(model) {
+            #[allow(
+                clippy::cast_possible_truncation,
+                clippy::cast_precision_loss,
+                clippy::cast_sign_loss
+            )]
+            Some(factor) => (estimate as f64 * factor).ceil() as usize,
+            None => estimate,
+        }
+    }
+
+    /// None if the model has no tokenizer, or it fails on the text.
+    #[must_use]
+    pub fn count(&self, model: &str, text: &str) -> Option<usize> {
+        let (_, tokenizer) = self
+            .tokenizers
+            .iter()
+            .find(|(pattern, _)| conf::matches(pattern, model))?;
+        tokenizer
+            .encode(text, false)
+            .map_err(|error| {
+                tracing::warn!(?error, model, "Failed to tokenize.");
+            })
+            .ok()
+            .map(|encoding| encoding.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokenizers::Tokenizer;
+
+    use super::Registry;
+
+    /// Of only unknown words, split by the given pre-tokenizer.
+    fn word_level(pre_tokenizer: &str) -> Tokenizer {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": pre_tokenizer},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0},
+                "unk_token": "[UNK]",
+            },
+        });
+        Tokenizer::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn count() {
+        let registry = Registry::from_tokenizers(vec![
+            ("llama*".to_string(), word_level("Whitespace")),
+            ("llama-3*".to_string(), word_level("WhitespaceSplit")),
+        ]);
+        // Punctuation is split off only by the less specific one.
+        assert_eq!(registry.count("llama-2", "hello, world"), Some(3));
+        assert_eq!(registry.count("llama-3.1", "hello, world"), Some(2));
+        assert_eq!(registry.count("gpt-4o", "hello"), None);
+    }
+
+    #[test]
+    fn calibrate() {
+        let registry = Registry::default();
+        assert_eq!(registry.calibrate("llama-3.1", 100), 100);
+        registry.set_factors([("llama-3.1".to_string(), 1.25)].into());
+        assert_eq!(registry.calibrate("llama-3.1", 100), 125);
+        assert_eq!(registry.calibrate("llama-3.1", 1), 2);
+        assert_eq!(registry.calibrate("gpt-4o", 100), 100);
+    }
+}
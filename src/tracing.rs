@@ -1,20 +1,140 @@
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+use anyhow::{anyhow, Context};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, registry::LookupSpan, reload, EnvFilter, Layer,
+};
+
 use crate::conf;
 
-pub fn init() -> anyhow::Result<()> {
-    use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer};
+/// Of the layers' filters, for changing what is logged while running.
+type Reload = Box<dyn Fn(EnvFilter) -> anyhow::Result<()> + Send + Sync>;
 
-    let layer_stderr = fmt::Layer::new()
-        .with_writer(std::io::stderr)
-        .with_ansi(true)
-        .with_file(false)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_filter(
-            EnvFilter::from_default_env()
-                .add_directive(conf::global().log_level.into()),
-        );
+static RELOADS: OnceLock<Vec<Reload>> = OnceLock::new();
+
+static CURRENT: Mutex<Option<LogLevel>> = Mutex::new(None);
+
+/// What is logged, on top of `RUST_LOG`.
+#[derive(
+    serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone,
+)]
+pub struct LogLevel {
+    /// Of all targets, unless directed otherwise, e.g. "debug".
+    #[serde(
+        serialize_with = "conf::serialize_log_level",
+        deserialize_with = "conf::deserialize_log_level"
+    )]
+    #[schema(value_type = String)]
+    pub level: tracing::Level,
+
+    /// Per target, such as "raskol::upstream=trace".
+    #[serde(default)]
+    pub directives: Vec<String>,
+}
+
+impl LogLevel {
+    fn from_conf(conf: &conf::Conf) -> Self {
+        Self {
+            level: conf.log_level,
+            directives: conf.log.directives.clone(),
+        }
+    }
+
+    fn env_filter(&self) -> anyhow::Result<EnvFilter> {
+        let mut filter =
+            EnvFilter::from_default_env().add_directive(self.level.into());
+        for directive in &self.directives {
+            filter =
+                filter.add_directive(directive.parse().context(format!(
+                    "Invalid log directive: {directive:?}"
+                ))?);
+        }
+        Ok(filter)
+    }
+}
+
+pub fn init() -> anyhow::Result<()> {
+    let conf = conf::global();
+    let current = LogLevel::from_conf(&conf);
+    let mut reloads: Vec<Reload> = Vec::new();
+    let (filter, handle) = reload::Layer::new(current.env_filter()?);
+    reloads.push(Box::new(move |filter| Ok(handle.reload(filter)?)));
+    let layer_stderr =
+        layer(conf.log.format, std::io::stderr, true).with_filter(filter);
+    let layer_file = conf
+        .log
+        .file
+        .as_ref()
+        .map(|file| -> anyhow::Result<_> {
+            // Otherwise old files fail to be pruned until the first write.
+            std::fs::create_dir_all(&file.dir).context(format!(
+                "Failed to create log dir: {:?}",
+                file.dir
+            ))?;
+            let mut builder = tracing_appender::rolling::Builder::new()
+                .rotation(tracing_appender::rolling::Rotation::DAILY)
+                .filename_prefix(&file.prefix);
+            if let Some(max_files) = file.max_files {
+                builder = builder.max_log_files(max_files);
+            }
+            let appender = builder.build(&file.dir).context(format!(
+                "Failed to create log file appender. dir={:?}",
+                file.dir
+            ))?;
+            let (filter, handle) = reload::Layer::new(current.env_filter()?);
+            reloads.push(Box::new(move |filter| Ok(handle.reload(filter)?)));
+            Ok(layer(conf.log.format, appender, false).with_filter(filter))
+        })
+        .transpose()?;
     tracing::subscriber::set_global_default(
-        tracing_subscriber::registry().with(layer_stderr),
+        tracing_subscriber::registry()
+            .with(layer_stderr)
+            .with(layer_file),
     )?;
+    let _ = RELOADS.set(reloads);
+    *current_lock() = Some(current);
+    Ok(())
+}
+
+/// None when logging was not set up by [`init`], such as when embedded.
+#[must_use]
+pub fn log_level() -> Option<LogLevel> {
+    current_lock().clone()
+}
+
+/// Until the next change or restart, after which the conf's is back.
+pub fn log_level_set(filter: LogLevel) -> anyhow::Result<()> {
+    let reloads = RELOADS
+        .get()
+        .ok_or_else(|| anyhow!("Logging was not set up by us."))?;
+    let mut current = current_lock();
+    for reload in reloads {
+        reload(filter.env_filter()?)?;
+    }
+    *current = Some(filter);
     Ok(())
 }
+
+fn current_lock() -> MutexGuard<'static, Option<LogLevel>> {
+    CURRENT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn layer<S, W>(
+    format: conf::LogFormat,
+    writer: W,
+    is_ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::Layer::new()
+        .with_writer(writer)
+        .with_file(false)
+        .with_line_number(true)
+        .with_thread_ids(true);
+    match format {
+        conf::LogFormat::Pretty => layer.with_ansi(is_ansi).boxed(),
+        conf::LogFormat::Json => layer.json().boxed(),
+    }
+}
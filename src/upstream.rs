@@ -0,0 +1,1370 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, StatusCode};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite;
+
+use crate::{
+    breaker::Breaker,
+    chat,
+    conf::{self, Conf},
+    data::{self, ErrorClass},
+    dialect, headers,
+    keypool::{self, KeyPool},
+    redact::redact,
+    server, slow,
+    spend::{self, Spend},
+};
+
+/// Where requests are forwarded to: the primary provider and, optionally,
+/// a secondary one to fail over to once the primary keeps failing.
+pub struct Upstream {
+    primary: Provider,
+    failover: Option<Provider>,
+
+    /// Mirrored to, for comparison.
+    shadow: Option<Provider>,
+
+    /// Not when off in conf.
+    spend: Option<Spend>,
+
+    retry: conf::Retry,
+    fallback_models: BTreeMap<String, String>,
+
+    /// Until when upstream asked us to back off, last it throttled us.
+    throttled_until: Mutex<Option<Instant>>,
+}
+
+/// Of a realtime session with upstream.
+pub type RealtimeSocket = tokio_tungstenite::WebSocketStream<
+    tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+>;
+
+/// How long to back off when throttled without being told for how long.
+const THROTTLE_DEFAULT: Duration = Duration::from_secs(1);
+
+/// A successful response from upstream.
+pub struct Forwarded {
+    pub code: StatusCode,
+    pub body: String,
+
+    /// To pass back to the client, per [`conf::Headers::pass_back`].
+    pub headers: HeaderMap,
+
+    /// Model used instead of the requested one, which upstream refused.
+    pub fallback_model: Option<String>,
+
+    /// Which one responded: "primary", "failover" or "shadow".
+    pub provider: &'static str,
+}
+
+/// Of a chat completion streamed from upstream, the server-sent events, as
+/// OpenAI sends them, whichever dialect upstream speaks.
+pub type Events = BoxStream<'static, reqwest::Result<axum::body::Bytes>>;
+
+/// A successful response from upstream, as is, unless its body is
+/// converted to text.
+pub struct Received<B = axum::body::Bytes> {
+    pub code: StatusCode,
+    pub content_type: Option<String>,
+
+    /// To pass back to the client, per [`conf::Headers::pass_back`].
+    pub headers: HeaderMap,
+
+    pub body: B,
+
+    /// Which one responded: "primary", "failover" or "shadow".
+    pub provider: &'static str,
+}
+
+impl Received<String> {
+    fn forwarded(self, fallback_model: Option<String>) -> Forwarded {
+        Forwarded {
+            code: self.code,
+            body: self.body,
+            headers: self.headers,
+            fallback_model,
+            provider: self.provider,
+        }
+    }
+}
+
+struct Provider {
+    name: &'static str,
+    scheme: conf::Scheme,
+    address: String,
+    keys: KeyPool,
+    breaker: Breaker,
+    dialect: conf::Dialect,
+    azure: conf::Azure,
+    models_endpoint: String,
+
+    /// Model to use instead of the requested one.
+    model: Option<String>,
+
+    /// Of which those to pass back are picked from responses.
+    header_policy: conf::Headers,
+}
+
+/// What to forward.
+pub enum Payload<'a> {
+    Chat(&'a chat::Req),
+
+    /// Passed through as is, such as multipart audio uploads. The failover
+    /// model override does not apply, since we do not rewrite these.
+    Raw {
+        model: &'a str,
+        content_type: &'a str,
+        body: axum::body::Bytes,
+    },
+}
+
+/// Of a request upstream, given up on.
+#[derive(Debug, Clone, Copy)]
+pub struct Failed {
+    /// What to respond to our client with.
+    pub code: StatusCode,
+
+    pub class: ErrorClass,
+}
+
+impl From<Failure> for Failed {
+    fn from(failure: Failure) -> Self {
+        Self {
+            code: failure.code,
+            class: failure.class,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failure {
+    /// What to respond to our client with, if we give up.
+    code: StatusCode,
+
+    /// Why, for the request logs.
+    class: ErrorClass,
+
+    /// Whether trying again (later or elsewhere) has a chance to succeed.
+    is_transient: bool,
+
+    /// Whether it indicates that upstream is down, rather than, say,
+    /// throttling us.
+    is_outage: bool,
+
+    /// Whether upstream is throttling us, or all our keys are.
+    is_throttled: bool,
+
+    /// Whether we stopped forwarding to it, per [`conf::SpendCaps`].
+    is_capped: bool,
+
+    /// As requested by upstream.
+    retry_after: Option<Duration>,
+
+    /// Upstream's own, from an OpenAI-style error body.
+    error_code: Option<String>,
+}
+
+impl Failure {
+    fn permanent(code: StatusCode, class: ErrorClass) -> Self {
+        Self {
+            code,
+            class,
+            is_transient: false,
+            is_outage: false,
+            is_throttled: false,
+            is_capped: false,
+            retry_after: None,
+            error_code: None,
+        }
+    }
+
+    fn transient(retry_after: Option<Duration>) -> Self {
+        Self {
+            code: StatusCode::SERVICE_UNAVAILABLE,
+            class: ErrorClass::Upstream5xx,
+            is_transient: true,
+            is_outage: false,
+            is_throttled: false,
+            is_capped: false,
+            retry_after,
+            error_code: None,
+        }
+    }
+
+    fn outage(retry_after: Option<Duration>) -> Self {
+        Self {
+            is_outage: true,
+            ..Self::transient(retry_after)
+        }
+    }
+
+    fn throttled(retry_after: Option<Duration>) -> Self {
+        Self {
+            class: ErrorClass::Upstream429,
+            is_throttled: true,
+            ..Self::transient(retry_after)
+        }
+    }
+
+    /// Of our spend with the provider, as upstream would refuse us past a
+    /// quota, rather than of the client's budget.
+    fn capped() -> Self {
+        Self {
+            is_capped: true,
+            ..Self::permanent(
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorClass::Upstream429,
+            )
+        }
+    }
+
+    /// The model cannot serve the request, but another one might.
+    fn is_fallback_worthy(&self) -> bool {
+        matches!(
+            self.error_code.as_deref(),
+            Some("model_decommissioned" | "context_length_exceeded")
+        )
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct ProviderHealth {
+    #[schema(value_type = String)]
+    pub name: &'static str,
+    pub address: String,
+    #[schema(value_type = String)]
+    pub circuit: &'static str,
+
+    /// Whether it responded at all, with whatever status. Unknown when not
+    /// probed.
+    pub is_reachable: Option<bool>,
+
+    /// Of the probe's round trip, when it responded.
+    pub latency_ms: Option<u128>,
+
+    pub error: Option<String>,
+
+    /// Today, when spend caps are on.
+    pub spent: Option<spend::Spent>,
+}
+
+impl Upstream {
+    #[must_use]
+    pub fn new(conf: &Conf) -> Self {
+        Self {
+            primary: Provider {
+                name: "primary",
+                scheme: conf.target_scheme,
+                address: conf.target_address.clone(),
+                keys: KeyPool::new("primary", &conf.target_auth_token),
+                breaker: Breaker::new("primary", &conf.circuit_breaker),
+                dialect: conf.dialect,
+                azure: conf.azure.clone(),
+                models_endpoint: conf.models_endpoint.clone(),
+                model: None,
+                header_policy: conf.headers.clone(),
+            },
+            failover: conf.failover.as_ref().map(|failover| {
+                Provider::secondary("failover", conf, failover)
+            }),
+            shadow: conf.shadow.as_ref().map(|shadow| {
+                let mut provider =
+                    Provider::secondary("shadow", conf, &shadow.provider);
+                if shadow.model.is_some() {
+                    provider.model.clone_from(&shadow.model);
+                }
+                provider
+            }),
+            spend: conf.spend_caps.clone().map(Spend::new),
+            retry: conf.retry.clone(),
+            fallback_models: conf.fallback_models.clone(),
+            throttled_until: Mutex::new(None),
+        }
+    }
+
+    /// Not when spend caps are off in conf.
+    #[must_use]
+    pub fn spend(&self) -> Option<&Spend> {
+        self.spend.as_ref()
+    }
+
+    /// Whether the providers a request could go to are all capped, per
+    /// [`conf::SpendCaps`]. Only the primary, for those which don't fail
+    /// over.
+    #[must_use]
+    pub fn is_capped(&self, can_fail_over: bool) -> bool {
+        self.is_provider_capped(&self.primary)
+            && match (&self.failover, can_fail_over) {
+                (Some(failover), true) => self.is_provider_capped(failover),
+                _ => true,
+            }
+    }
+
+    fn is_provider_capped(&self, provider: &Provider) -> bool {
+        self.spend
+            .as_ref()
+            .is_some_and(|spend| spend.is_capped(provider.name))
+    }
+
+    /// Whether requests can't be served for now: the providers all capped,
+    /// or their circuits all open, or upstream throttling us, or our keys'
+    /// limits used up.
+    #[must_use]
+    pub fn is_exhausted(&self) -> bool {
+        self.is_capped(true)
+            || self.throttled_for().is_some()
+            || self
+                .headroom()
+                .is_some_and(|headroom| headroom.fraction <= 0.0)
+            || std::iter::once(&self.primary)
+                .chain(&self.failover)
+                .all(|provider| provider.breaker.state_name() == "open")
+    }
+
+    /// How much longer upstream asked us to back off, if it is throttling
+    /// us.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        let throttled_until = *self
+            .throttled_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        throttled_until?
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Of the primary's rate limits, if upstream reports them.
+    #[must_use]
+    pub fn headroom(&self) -> Option<keypool::Headroom> {
+        self.primary.keys.headroom()
+    }
+
+    /// Circuit states of the providers and, if `probe_timeout` is given,
+    /// whether they can be reached.
+    pub async fn health(
+        &self,
+        http: &reqwest::Client,
+        probe_timeout: Option<Duration>,
+    ) -> Vec<ProviderHealth> {
+        let mut providers = vec![("primary", &self.primary)];
+        if let Some(failover) = &self.failover {
+            providers.push(("failover", failover));
+        }
+        let mut healths = Vec::new();
+        for (name, provider) in providers {
+            let mut health = ProviderHealth {
+                name,
+                address: provider.address.clone(),
+                circuit: provider.breaker.state_name(),
+                is_reachable: None,
+                latency_ms: None,
+                error: None,
+                spent: self.spend.as_ref().map(|spend| spend.spent(name)),
+            };
+            if let Some(timeout) = probe_timeout {
+                let url = format!("{}/", provider.base_url());
+                let started = Instant::now();
+                match http.get(url).timeout(timeout).send().await {
+                    Ok(_) => {
+                        health.is_reachable = Some(true);
+                        health.latency_ms =
+                            Some(started.elapsed().as_millis());
+                    }
+                    Err(error) => {
+                        health.is_reachable = Some(false);
+                        health.error = Some(error_chain(&error));
+                    }
+                }
+            }
+            healths.push(health);
+        }
+        healths
+    }
+
+    /// Of each provider, by listing its models, as cheaply as it can be
+    /// asked anything, for [`crate::prober`].
+    pub async fn probe(
+        &self,
+        http: &reqwest::Client,
+        timeout: Duration,
+    ) -> Vec<data::Probe> {
+        let mut providers = vec![&self.primary];
+        providers.extend(&self.failover);
+        let mut probes = Vec::new();
+        for provider in providers {
+            let time = server::unix_now_secs();
+            let started = Instant::now();
+            let listed =
+                tokio::time::timeout(timeout, provider.models(http)).await;
+            let error = match listed {
+                Ok(Ok(received)) if received.code.is_success() => None,
+                Ok(Ok(received)) => {
+                    Some(format!("Responded with {}.", received.code))
+                }
+                Ok(Err(failure)) => Some(format!(
+                    "Failed with {}: {}.",
+                    failure.code,
+                    failure.class.as_str()
+                )),
+                Err(_) => Some("Timed out.".to_string()),
+            };
+            probes.push(data::Probe {
+                provider: provider.name.to_string(),
+                time,
+                is_up: error.is_none(),
+                latency_ms: i64::try_from(started.elapsed().as_millis())
+                    .unwrap_or(i64::MAX),
+                error,
+            });
+        }
+        probes
+    }
+
+    /// Models listed by the named provider ("primary" or "failover") or, if
+    /// none is named, by the primary, failing over like [`Self::forward`].
+    pub async fn models(
+        &self,
+        http: &reqwest::Client,
+        provider: Option<&str>,
+    ) -> Result<chat::Models, StatusCode> {
+        let Received { body, .. } = match (provider, &self.failover) {
+            (None, failover) => {
+                match (self.primary.models(http).await, failover) {
+                    (Err(failure), Some(failover))
+                        if failure.is_transient =>
+                    {
+                        tracing::warn!(
+                            ?failure,
+                            failover = ?failover.address,
+                            "Primary upstream failed. Failing over."
+                        );
+                        failover.models(http).await
+                    }
+                    (result, _) => result,
+                }
+            }
+            (Some("primary"), _) => self.primary.models(http).await,
+            (Some("failover"), Some(failover)) => failover.models(http).await,
+            (Some(_), _) => return Err(StatusCode::NOT_FOUND),
+        }
+        .map_err(|failure| failure.code)?;
+        serde_json::from_str(&body).map_err(|error| {
+            let body = redact(&body);
+            tracing::error!(?error, ?body, "Failed to parse model listing.");
+            StatusCode::BAD_GATEWAY
+        })
+    }
+
+    /// Forwards to the primary, failing over to the secondary on transient
+    /// failures, and retrying once with the fallback model if the requested
+    /// one is decommissioned or its context is exceeded.
+    pub async fn forward(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        payload: &Payload<'_>,
+    ) -> Result<Forwarded, Failed> {
+        let failure = match self.forward_(http, endpoint, payload).await {
+            Ok(received) => return Ok(received.forwarded(None)),
+            Err(failure) => failure,
+        };
+        let fallback = match payload {
+            Payload::Chat(chat_req) if failure.is_fallback_worthy() => self
+                .fallback_models
+                .get(&chat_req.model)
+                .map(|fallback| (chat_req, fallback)),
+            Payload::Chat(_) | Payload::Raw { .. } => None,
+        };
+        let Some((chat_req, fallback)) = fallback else {
+            return Err(failure.into());
+        };
+        tracing::warn!(
+            ?failure,
+            model = chat_req.model,
+            fallback,
+            "Model refused. Falling back."
+        );
+        let chat_req = chat::Req {
+            model: fallback.clone(),
+            ..(*chat_req).clone()
+        };
+        let received = self
+            .forward_(http, endpoint, &Payload::Chat(&chat_req))
+            .await?;
+        Ok(received.forwarded(Some(fallback.clone())))
+    }
+
+    /// Model a chat request for the given one is mirrored as, if mirroring
+    /// is on.
+    #[must_use]
+    pub fn shadow_model<'a>(&'a self, requested: &'a str) -> Option<&'a str> {
+        let shadow = self.shadow.as_ref()?;
+        Some(shadow.model.as_deref().unwrap_or(requested))
+    }
+
+    /// Mirrors the chat request, once. Without retries, failover or
+    /// fallback models, which would muddle the comparison.
+    pub async fn forward_shadow(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        chat_req: &chat::Req,
+    ) -> Result<Forwarded, StatusCode> {
+        let Some(shadow) = &self.shadow else {
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        };
+        if self.is_provider_capped(shadow) {
+            tracing::debug!("Shadow capped. Not mirroring.");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let received = shadow
+            .send_payload(http, endpoint, &Payload::Chat(chat_req))
+            .await
+            .map_err(|failure| failure.code)?;
+        Ok(received.forwarded(None))
+    }
+
+    /// Streams the body to the primary, once. Without retries or failover,
+    /// since the body is consumed as it's sent.
+    pub async fn forward_stream(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        content_type: &str,
+        body: reqwest::Body,
+    ) -> Result<Forwarded, Failed> {
+        if self.is_provider_capped(&self.primary) {
+            return Err(Failure::capped().into());
+        }
+        let received = self
+            .primary
+            .send_stream(http, endpoint, content_type, body)
+            .await?;
+        Ok(received.forwarded(None))
+    }
+
+    /// Forwards to the primary, once, with the response as is, since it
+    /// need not be text. Without retries or failover, as for uploads.
+    pub async fn forward_bytes(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        model: &str,
+        body: &serde_json::Value,
+    ) -> Result<Received, Failed> {
+        if self.is_provider_capped(&self.primary) {
+            return Err(Failure::capped().into());
+        }
+        self.primary
+            .send_json(http, endpoint, model, body)
+            .await
+            .map_err(Failed::from)
+    }
+
+    /// Forwards chat to the primary, failing over to the secondary on
+    /// transient failures, with the response to be passed on as it arrives.
+    /// Without retries or fallback models, so as not to hold up the stream.
+    pub async fn forward_streaming(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        chat_req: &chat::Req,
+    ) -> Result<Received<Events>, Failed> {
+        let result = if self.is_provider_capped(&self.primary) {
+            Err(Failure::capped())
+        } else {
+            self.primary.send_streaming(http, endpoint, chat_req).await
+        };
+        let result = match (result, &self.failover) {
+            (Err(failure), Some(failover))
+                if (failure.is_transient || failure.is_capped)
+                    && !self.is_provider_capped(failover) =>
+            {
+                tracing::warn!(
+                    ?failure,
+                    failover = ?failover.address,
+                    "Primary upstream failed. Failing over."
+                );
+                failover.send_streaming(http, endpoint, chat_req).await
+            }
+            (result, _) => result,
+        };
+        self.note_throttling(&result);
+        result.map_err(Failed::from)
+    }
+
+    /// Connects to the primary's realtime WebSocket API for the model.
+    /// Without retries or failover, since the session is stateful.
+    pub async fn realtime(
+        &self,
+        model: &str,
+    ) -> Result<RealtimeSocket, StatusCode> {
+        if self.is_provider_capped(&self.primary) {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let req = self.primary.realtime_req(model)?;
+        let (socket, resp) = tokio_tungstenite::connect_async(req)
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    error = error_chain(&error),
+                    "Failed to connect to upstream realtime API."
+                );
+                StatusCode::BAD_GATEWAY
+            })?;
+        tracing::debug!(status = ?resp.status(), "Realtime connected.");
+        Ok(socket)
+    }
+
+    async fn forward_(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        payload: &Payload<'_>,
+    ) -> Result<Received<String>, Failure> {
+        let result = if self.is_provider_capped(&self.primary) {
+            Err(Failure::capped())
+        } else {
+            self.primary
+                .forward(http, &self.retry, endpoint, payload)
+                .await
+        };
+        let result = match (result, &self.failover) {
+            (Err(failure), Some(failover))
+                if (failure.is_transient || failure.is_capped)
+                    && !self.is_provider_capped(failover) =>
+            {
+                tracing::warn!(
+                    ?failure,
+                    failover = ?failover.address,
+                    "Primary upstream failed. Failing over."
+                );
+                failover.forward(http, &self.retry, endpoint, payload).await
+            }
+            (result, _) => result,
+        };
+        self.note_throttling(&result);
+        result
+    }
+
+    /// Until when upstream throttles us, as of the result.
+    fn note_throttling<T>(&self, result: &Result<T, Failure>) {
+        let throttled_until = match result {
+            Ok(_) => None,
+            Err(failure) if failure.is_throttled => Some(
+                Instant::now()
+                    + failure.retry_after.unwrap_or(THROTTLE_DEFAULT),
+            ),
+            Err(_) => return,
+        };
+        *self
+            .throttled_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = throttled_until;
+    }
+}
+
+impl Provider {
+    fn secondary(
+        name: &'static str,
+        conf: &Conf,
+        target: &conf::Failover,
+    ) -> Self {
+        Self {
+            name,
+            scheme: target.target_scheme,
+            address: target.target_address.clone(),
+            keys: KeyPool::new(name, &target.target_auth_token),
+            breaker: Breaker::new(name, &conf.circuit_breaker),
+            dialect: target.dialect,
+            azure: target.azure.clone(),
+            models_endpoint: target
+                .models_endpoint
+                .clone()
+                .unwrap_or_else(|| conf.models_endpoint.clone()),
+            model: target.model.clone(),
+            header_policy: conf.headers.clone(),
+        }
+    }
+
+    async fn forward(
+        &self,
+        http: &reqwest::Client,
+        retry: &conf::Retry,
+        endpoint: &str,
+        payload: &Payload<'_>,
+    ) -> Result<Received<String>, Failure> {
+        let max_attempts = retry.max_attempts.max(1);
+        let max_backoff = Duration::from_secs_f32(retry.max_backoff);
+        let mut backoff = Duration::from_secs_f32(retry.initial_backoff);
+        let mut attempt = 1;
+        loop {
+            let failure =
+                match self.send_payload(http, endpoint, payload).await {
+                    Ok(ok) => return Ok(ok),
+                    Err(failure) => failure,
+                };
+            if !failure.is_transient || attempt >= max_attempts {
+                return Err(failure);
+            }
+            let delay = match failure.retry_after {
+                // Not worth holding the client for that long.
+                Some(retry_after) if retry_after > max_backoff => {
+                    return Err(failure);
+                }
+                Some(retry_after) => retry_after.max(backoff),
+                None => backoff,
+            };
+            tracing::warn!(
+                attempt,
+                max_attempts,
+                ?delay,
+                ?failure,
+                "Upstream request failed. Retrying."
+            );
+            tokio::time::sleep(delay).await;
+            backoff = backoff.saturating_mul(2).min(max_backoff);
+            attempt += 1;
+        }
+    }
+
+    async fn models(
+        &self,
+        http: &reqwest::Client,
+    ) -> Result<Received<String>, Failure> {
+        let mut url = format!("{}/{}", self.base_url(), self.models_endpoint);
+        if let conf::Dialect::Azure = self.dialect {
+            url.push_str("?api-version=");
+            url.push_str(&self.azure.api_version);
+        }
+        self.send(http.get(url)).await
+    }
+
+    async fn send_payload(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        payload: &Payload<'_>,
+    ) -> Result<Received<String>, Failure> {
+        let base_url = self.base_url();
+        match (self.dialect, payload) {
+            (
+                conf::Dialect::OpenAi | conf::Dialect::Azure,
+                Payload::Chat(chat_req),
+            ) => {
+                let chat_req = self.with_model(chat_req);
+                let url = self.url(endpoint, &chat_req.model);
+                self.send(http.post(url).json(&chat_req)).await
+            }
+            (
+                conf::Dialect::OpenAi | conf::Dialect::Azure,
+                Payload::Raw {
+                    model,
+                    content_type,
+                    body,
+                },
+            ) => {
+                let url = self.url(endpoint, model);
+                let builder = http
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, *content_type)
+                    .body(body.clone());
+                self.send(builder).await
+            }
+            (conf::Dialect::Anthropic, Payload::Chat(chat_req)) => {
+                let endpoint = dialect::ANTHROPIC_ENDPOINT;
+                let url = format!("{base_url}/{endpoint}");
+                let chat_req = self.with_model(chat_req);
+                let builder = http
+                    .post(url)
+                    .json(&dialect::AnthropicReq::from(&chat_req));
+                let received = self.send(builder).await?;
+                let body = dialect::from_anthropic_resp_body(&received.body)
+                    .map_err(|error| {
+                        tracing::error!(
+                            ?error,
+                            body = ?redact(&received.body),
+                            "Failed to translate Anthropic response."
+                        );
+                        Failure::permanent(
+                            StatusCode::BAD_GATEWAY,
+                            ErrorClass::Upstream5xx,
+                        )
+                    })?;
+                Ok(Received { body, ..received })
+            }
+            (conf::Dialect::Anthropic, Payload::Raw { .. }) => {
+                tracing::warn!(endpoint, "Not supported by Anthropic.");
+                Err(Failure::permanent(
+                    StatusCode::NOT_IMPLEMENTED,
+                    ErrorClass::Validation,
+                ))
+            }
+        }
+    }
+
+    async fn send_json(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        model: &str,
+        body: &serde_json::Value,
+    ) -> Result<Received, Failure> {
+        if let conf::Dialect::Anthropic = self.dialect {
+            tracing::warn!(endpoint, "Not supported by Anthropic.");
+            return Err(Failure::permanent(
+                StatusCode::NOT_IMPLEMENTED,
+                ErrorClass::Validation,
+            ));
+        }
+        let url = self.url(endpoint, model);
+        self.send_bytes(http.post(url).json(body)).await
+    }
+
+    async fn send_stream(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        content_type: &str,
+        body: reqwest::Body,
+    ) -> Result<Received<String>, Failure> {
+        let base_url = self.base_url();
+        let url = match self.dialect {
+            conf::Dialect::OpenAi => format!("{base_url}/{endpoint}"),
+            conf::Dialect::Azure => {
+                // Not deployment-scoped, e.g. "v1/files" is at
+                // "openai/files".
+                let operation = endpoint
+                    .split_once("v1/")
+                    .map_or(endpoint, |(_, operation)| operation);
+                let api_version = &self.azure.api_version;
+                format!(
+                    "{base_url}/openai/{operation}?api-version={api_version}"
+                )
+            }
+            conf::Dialect::Anthropic => {
+                tracing::warn!(endpoint, "Not supported by Anthropic.");
+                return Err(Failure::permanent(
+                    StatusCode::NOT_IMPLEMENTED,
+                    ErrorClass::Validation,
+                ));
+            }
+        };
+        let builder = http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body);
+        self.send(builder).await
+    }
+
+    async fn send_streaming(
+        &self,
+        http: &reqwest::Client,
+        endpoint: &str,
+        chat_req: &chat::Req,
+    ) -> Result<Received<Events>, Failure> {
+        let chat_req = self.with_model(chat_req);
+        let builder = match self.dialect {
+            conf::Dialect::OpenAi | conf::Dialect::Azure => {
+                let url = self.url(endpoint, &chat_req.model);
+                http.post(url).json(&chat_req)
+            }
+            conf::Dialect::Anthropic => {
+                let endpoint = dialect::ANTHROPIC_ENDPOINT;
+                let url = format!("{}/{endpoint}", self.base_url());
+                let anthropic_req =
+                    dialect::AnthropicReq::from(&chat_req).streamed();
+                http.post(url).json(&anthropic_req)
+            }
+        };
+        let received = self.guarded(self.send_(builder)).await?;
+        let events = received.body.bytes_stream();
+        let body = match self.dialect {
+            conf::Dialect::OpenAi | conf::Dialect::Azure => events.boxed(),
+            conf::Dialect::Anthropic => {
+                let mut translated = dialect::AnthropicEvents::default();
+                events.map_ok(move |chunk| translated.add(&chunk)).boxed()
+            }
+        };
+        Ok(Received {
+            code: received.code,
+            content_type: received.content_type,
+            headers: received.headers,
+            body,
+            provider: received.provider,
+        })
+    }
+
+    /// To open a realtime session with, our key included.
+    fn realtime_req(
+        &self,
+        model: &str,
+    ) -> Result<tungstenite::handshake::client::Request, StatusCode> {
+        use tungstenite::client::IntoClientRequest;
+
+        let scheme = match self.scheme {
+            conf::Scheme::Https => "wss",
+            conf::Scheme::Http => "ws",
+        };
+        let address = &self.address;
+        let encoded = |value: &str| {
+            percent_encoding::utf8_percent_encode(
+                value,
+                percent_encoding::NON_ALPHANUMERIC,
+            )
+            .to_string()
+        };
+        let url = match self.dialect {
+            conf::Dialect::OpenAi => {
+                let model = encoded(model);
+                format!("{scheme}://{address}/v1/realtime?model={model}")
+            }
+            conf::Dialect::Azure => {
+                let deployment = encoded(
+                    self.azure
+                        .deployments
+                        .get(model)
+                        .map_or(model, String::as_str),
+                );
+                let api_version = &self.azure.api_version;
+                format!(
+                    "{scheme}://{address}/openai/realtime?\
+                    api-version={api_version}&deployment={deployment}"
+                )
+            }
+            conf::Dialect::Anthropic => {
+                tracing::warn!("Realtime not supported by Anthropic.");
+                return Err(StatusCode::NOT_IMPLEMENTED);
+            }
+        };
+        let invalid = |error| {
+            tracing::error!(?error, "Failed to build realtime request.");
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        let mut req = url
+            .into_client_request()
+            .map_err(|error| invalid(error.to_string()))?;
+        let headers = req.headers_mut();
+        if let Ok(forwarded) =
+            server::FORWARDED_HEADERS.try_with(Clone::clone)
+        {
+            headers.extend(forwarded);
+        }
+        if let Ok(req_id) = server::REQ_ID.try_with(|id| id.req_id.clone()) {
+            let value = req_id.parse().map_err(|_| invalid(req_id))?;
+            headers.insert(server::REQ_ID_HEADER, value);
+        }
+        let (name, value) = match (self.keys.pick(), self.dialect) {
+            (keypool::Pick::Key(lease), conf::Dialect::Azure) => {
+                ("api-key", lease.token)
+            }
+            (keypool::Pick::Key(lease), _) => {
+                ("authorization", format!("Bearer {}", lease.token))
+            }
+            (keypool::Pick::Anonymous, _) => return Ok(req),
+            (keypool::Pick::Throttled { retry_after }, _) => {
+                tracing::warn!(
+                    ?retry_after,
+                    "All upstream keys are throttled."
+                );
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+        let mut value: reqwest::header::HeaderValue = value
+            .parse()
+            .map_err(|_| invalid("Invalid key.".to_string()))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+        if let conf::Dialect::OpenAi = self.dialect {
+            headers.insert(
+                "openai-beta",
+                reqwest::header::HeaderValue::from_static("realtime=v1"),
+            );
+        }
+        Ok(req)
+    }
+
+    fn base_url(&self) -> String {
+        format!("{}://{}", self.scheme.as_str(), self.address)
+    }
+
+    /// Of an OpenAI-compatible endpoint.
+    fn url(&self, endpoint: &str, model: &str) -> String {
+        let base_url = self.base_url();
+        match self.dialect {
+            conf::Dialect::Azure => {
+                // Deployment-scoped, e.g. "openai/v1/chat/completions" is
+                // at "openai/deployments/{deployment}/chat/completions".
+                let operation = endpoint
+                    .split_once("v1/")
+                    .map_or(endpoint, |(_, operation)| operation);
+                let deployment = self
+                    .azure
+                    .deployments
+                    .get(model)
+                    .map_or(model, String::as_str);
+                let api_version = &self.azure.api_version;
+                format!(
+                    "{base_url}/openai/deployments/{deployment}/\
+                    {operation}?api-version={api_version}"
+                )
+            }
+            conf::Dialect::OpenAi | conf::Dialect::Anthropic => {
+                format!("{base_url}/{endpoint}")
+            }
+        }
+    }
+
+    fn with_model(&self, chat_req: &chat::Req) -> chat::Req {
+        match &self.model {
+            None => chat_req.clone(),
+            Some(model) => chat::Req {
+                model: model.clone(),
+                ..chat_req.clone()
+            },
+        }
+    }
+
+    async fn send(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<Received<String>, Failure> {
+        let received = self.send_bytes(builder).await?;
+        Ok(Received {
+            body: String::from_utf8_lossy(&received.body).into_owned(),
+            code: received.code,
+            content_type: received.content_type,
+            headers: received.headers,
+            provider: received.provider,
+        })
+    }
+
+    /// For responses which need not be text, such as audio.
+    async fn send_bytes(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<Received, Failure> {
+        self.guarded(async {
+            let received = self.send_(builder).await?;
+            let body = received
+                .body
+                .bytes()
+                .await
+                .map_err(|error| unreceived(&error, received.code))?;
+            Ok(Received {
+                code: received.code,
+                content_type: received.content_type,
+                headers: received.headers,
+                body,
+                provider: received.provider,
+            })
+        })
+        .await
+    }
+
+    /// Unless the circuit is open, keeping it informed.
+    async fn guarded<T>(
+        &self,
+        sending: impl Future<Output = Result<T, Failure>>,
+    ) -> Result<T, Failure> {
+        if let Err(retry_after) = self.breaker.allow() {
+            tracing::warn!(?retry_after, "Circuit open. Not sending.");
+            return Err(Failure::transient(Some(retry_after)));
+        }
+        let result = sending.await;
+        match &result {
+            Ok(_) => self.breaker.record_success(),
+            Err(failure) if failure.is_outage => {
+                self.breaker.record_failure()
+            }
+            // Upstream is up, it just didn't like this particular request.
+            Err(_) => self.breaker.record_success(),
+        }
+        result
+    }
+
+    /// With the body of successful responses yet to be received.
+    async fn send_(
+        &self,
+        mut builder: reqwest::RequestBuilder,
+    ) -> Result<Received<reqwest::Response>, Failure> {
+        // So that provider-side logs can be matched with ours.
+        if let Ok(req_id) = server::REQ_ID.try_with(|id| id.req_id.clone()) {
+            builder = builder.header(server::REQ_ID_HEADER, req_id);
+        }
+        if let Ok(headers) = server::FORWARDED_HEADERS.try_with(Clone::clone)
+        {
+            builder = builder.headers(headers);
+        }
+        let lease = match self.keys.pick() {
+            keypool::Pick::Key(lease) => {
+                builder = match self.dialect {
+                    conf::Dialect::OpenAi => {
+                        builder.bearer_auth(&lease.token)
+                    }
+                    conf::Dialect::Azure => {
+                        builder.header("api-key", &lease.token)
+                    }
+                    conf::Dialect::Anthropic => {
+                        builder.header("x-api-key", &lease.token).header(
+                            "anthropic-version",
+                            dialect::ANTHROPIC_VERSION,
+                        )
+                    }
+                };
+                Some(lease)
+            }
+            keypool::Pick::Anonymous => None,
+            keypool::Pick::Throttled { retry_after } => {
+                tracing::warn!(
+                    ?retry_after,
+                    "All upstream keys are throttled."
+                );
+                return Err(Failure::throttled(Some(retry_after)));
+            }
+        };
+        let (client, out_req) = builder.build_split();
+        let mut out_req = out_req.map_err(|error| {
+            tracing::error!(?error, "Failed to build outgoing request.");
+            Failure::permanent(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorClass::Network,
+            )
+        })?;
+        // As bearer_auth marks authorization, so that they aren't logged.
+        for name in ["api-key", "x-api-key"] {
+            if let Some(value) = out_req.headers_mut().get_mut(name) {
+                value.set_sensitive(true);
+            }
+        }
+        // Of the body, when strict, only its length.
+        let body = out_req.body().and_then(|b| b.as_bytes());
+        let out_body = body
+            .filter(|_| !crate::redact::is_strict())
+            .map(|b| redact(&String::from_utf8_lossy(b)).into_owned());
+        tracing::debug!(
+            out_headers = ?out_req.headers(),
+            out_body = ?out_body,
+            out_body_len = body.map(<[u8]>::len),
+            "Outgoing reqwest."
+        );
+        let resp = client.execute(out_req).await.map_err(|error| {
+            tracing::error!(?error, "Failed to make the external request.");
+            Failure {
+                class: unreached_class(&error),
+                ..Failure::outage(None)
+            }
+        })?;
+
+        let status = resp.status();
+        let headers = resp.headers().to_owned();
+        if let Some(lease) = &lease {
+            self.keys.update(lease, status, &headers);
+            slow::note_key(self.name, lease.index());
+        }
+        let code = status.as_u16();
+        let code = StatusCode::from_u16(code).map_err(|error| {
+            tracing::error!(?error, ?code, "Failed to convert status code.");
+            Failure::permanent(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorClass::Upstream5xx,
+            )
+        })?;
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+        if !status.is_success() {
+            let body = resp
+                .bytes()
+                .await
+                .map_err(|error| unreceived(&error, code))?;
+            let body = String::from_utf8_lossy(&body);
+            tracing::error!(
+                ?status,
+                ?headers,
+                body = ?redact(&body),
+                "External request rejected."
+            );
+            return Err(rejected(code, &headers, &body));
+        }
+        Ok(Received {
+            code,
+            content_type,
+            headers: headers::pass_back(&self.header_policy, &headers),
+            body: resp,
+            provider: self.name,
+        })
+    }
+}
+
+fn unreceived(error: &reqwest::Error, code: StatusCode) -> Failure {
+    tracing::error!(
+        ?error,
+        ?code,
+        "Failed to receive body from target host."
+    );
+    Failure {
+        class: unreached_class(error),
+        ..Failure::outage(None)
+    }
+}
+
+/// Of a request upstream didn't answer (in whole), whether we gave up
+/// waiting on it, or couldn't reach it.
+fn unreached_class(error: &reqwest::Error) -> ErrorClass {
+    if error.is_timeout() {
+        ErrorClass::Timeout
+    } else {
+        ErrorClass::Network
+    }
+}
+
+/// Of a response other than a success, why, and what to make of it.
+fn rejected(code: StatusCode, headers: &HeaderMap, body: &str) -> Failure {
+    let retry_after = keypool::retry_after(headers);
+    if code == StatusCode::TOO_MANY_REQUESTS {
+        return Failure::throttled(retry_after);
+    }
+    let class = match code {
+        // Of our key, or the account behind it, rather than the client's.
+        StatusCode::UNAUTHORIZED
+        | StatusCode::PAYMENT_REQUIRED
+        | StatusCode::FORBIDDEN => ErrorClass::Auth,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => {
+            ErrorClass::Timeout
+        }
+        _ if code.is_server_error() => ErrorClass::Upstream5xx,
+        _ => ErrorClass::Validation,
+    };
+    if code.is_server_error() {
+        return Failure {
+            class,
+            ..Failure::outage(retry_after)
+        };
+    }
+    Failure {
+        error_code: chat::Error::code_from_resp_body(body),
+        ..Failure::permanent(StatusCode::SERVICE_UNAVAILABLE, class)
+    }
+}
+
+/// reqwest errors' Display omits the underlying cause, which is usually the
+/// interesting part.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        chain.push_str(": ");
+        chain.push_str(&error.to_string());
+        source = error.source();
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::http::{HeaderMap, StatusCode};
+
+    use super::{rejected, unreached_class, Failure, Provider};
+    use crate::{breaker::Breaker, conf, data::ErrorClass, keypool::KeyPool};
+
+    #[test]
+    fn azure_url() {
+        let provider = Provider {
+            name: "test",
+            scheme: conf::Scheme::Https,
+            address: "x.openai.azure.com".to_string(),
+            keys: KeyPool::new("test", &[]),
+            breaker: Breaker::new("test", &conf::CircuitBreaker::default()),
+            dialect: conf::Dialect::Azure,
+            azure: conf::Azure {
+                api_version: "2024-10-21".to_string(),
+                deployments: BTreeMap::from([(
+                    "gpt-4o".to_string(),
+                    "prod-4o".to_string(),
+                )]),
+            },
+            models_endpoint: "openai/models".to_string(),
+            model: None,
+            header_policy: conf::Headers::default(),
+        };
+        assert_eq!(
+            provider.url("openai/v1/chat/completions", "gpt-4o"),
+            "https://x.openai.azure.com/openai/deployments/prod-4o/\
+            chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(
+            provider.url("v1/audio/transcriptions", "whisper"),
+            "https://x.openai.azure.com/openai/deployments/whisper/\
+            audio/transcriptions?api-version=2024-10-21"
+        );
+    }
+
+    #[test]
+    fn classes() {
+        let class = |code: u16| {
+            let code = StatusCode::from_u16(code).unwrap();
+            rejected(code, &HeaderMap::new(), "{}").class
+        };
+        assert_eq!(class(401), ErrorClass::Auth);
+        assert_eq!(class(402), ErrorClass::Auth);
+        assert_eq!(class(403), ErrorClass::Auth);
+        assert_eq!(class(400), ErrorClass::Validation);
+        assert_eq!(class(404), ErrorClass::Validation);
+        assert_eq!(class(422), ErrorClass::Validation);
+        assert_eq!(class(408), ErrorClass::Timeout);
+        assert_eq!(class(504), ErrorClass::Timeout);
+        assert_eq!(class(429), ErrorClass::Upstream429);
+        assert_eq!(class(500), ErrorClass::Upstream5xx);
+        assert_eq!(class(503), ErrorClass::Upstream5xx);
+
+        // Ours, not the client's budget.
+        assert_eq!(Failure::capped().class, ErrorClass::Upstream429);
+
+        let error = rejected(
+            StatusCode::BAD_REQUEST,
+            &HeaderMap::new(),
+            r#"{"error": {"code": "context_length_exceeded"}}"#,
+        );
+        assert!(error.is_fallback_worthy());
+        assert!(!error.is_transient);
+        assert!(
+            rejected(StatusCode::BAD_GATEWAY, &HeaderMap::new(), "")
+                .is_outage
+        );
+    }
+
+    #[tokio::test]
+    async fn unreached_classes() {
+        // Nothing listening, once the listener is gone.
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let error = reqwest::get(format!("http://{addr}")).await.unwrap_err();
+        assert_eq!(unreached_class(&error), ErrorClass::Network);
+
+        // Listening, but never answering.
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let error = reqwest::Client::new()
+            .get(format!("http://{addr}"))
+            .timeout(std::time::Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(unreached_class(&error), ErrorClass::Timeout);
+        drop(listener);
+    }
+}
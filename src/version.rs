@@ -0,0 +1,123 @@
+//! Versions of our own routes, which are served under [`PREFIX`], e.g.
+//! `/v1/quota` and `/v1/admin/users/:uid`, so that clients built against a
+//! version keep working when routes change in the next. Upstream's, such
+//! as `/v1/chat/completions`, are forwarded as they are, and ops routes,
+//! such as `/ping` and `/metrics`, are not versioned.
+//!
+//! Our routes are also served without the prefix, as they used to be, but
+//! deprecated, unless turned off with [`crate::conf::Conf::legacy_routes`].
+//! Clients may ask for a version with the [`HEADER`], and are told in it
+//! which one they got.
+
+use axum::{
+    extract::{MatchedPath, OriginalUri, Request},
+    http::{header, Extensions, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::chat;
+
+pub const CURRENT: u32 = 1;
+
+pub const PREFIX: &str = "/v1";
+
+pub const HEADER: &str = "x-raskol-api-version";
+
+/// Of requests to routes under the prefix, which roles' routes are written
+/// without.
+#[derive(Debug, Clone, Copy)]
+pub struct Versioned;
+
+/// Of the request, as roles' routes are written: without the prefix.
+#[must_use]
+pub fn route<'a>(extensions: &Extensions, path: &'a str) -> &'a str {
+    if extensions.get::<Versioned>().is_none() {
+        return path;
+    }
+    path.strip_prefix(PREFIX)
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or(path)
+}
+
+/// As asked for in the header, e.g. "1" or "v1".
+fn parse(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('v')
+        .or_else(|| value.strip_prefix('V'))
+        .unwrap_or(value);
+    value.parse().ok()
+}
+
+/// Rejects requests for versions we don't serve, and tells the rest which
+/// one they got.
+pub async fn negotiate_layer(req: Request, next: Next) -> Response {
+    if let Some(value) = req.headers().get(HEADER) {
+        let requested = value.to_str().ok().and_then(parse);
+        if requested != Some(CURRENT) {
+            tracing::warn!(?value, "Rejecting. Unsupported API version.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "unsupported_api_version",
+                format!("Only version {CURRENT} of the API is served."),
+            );
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    }
+    let mut resp = next.run(req).await;
+    resp.headers_mut()
+        .insert(HEADER, HeaderValue::from(CURRENT));
+    resp
+}
+
+/// Points requests to routes without the prefix at their successors, per
+/// RFC 8594, and counts them, to know when they can be turned off.
+pub async fn legacy_layer(
+    matched: Option<MatchedPath>,
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Response {
+    let route = matched.map_or_else(
+        || uri.path().to_string(),
+        |matched| matched.as_str().to_string(),
+    );
+    metrics::counter!("raskol_legacy_requests_total", "route" => route)
+        .increment(1);
+    tracing::debug!(path = uri.path(), "Legacy route.");
+    let mut resp = next.run(req).await;
+    let headers = resp.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    let link = format!("<{PREFIX}{}>; rel=\"successor-version\"", uri.path());
+    if let Ok(link) = HeaderValue::from_str(&link) {
+        headers.insert(header::LINK, link);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Extensions;
+
+    use super::{parse, route, Versioned};
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse("1"), Some(1));
+        assert_eq!(parse(" v1"), Some(1));
+        assert_eq!(parse("V2"), Some(2));
+        assert_eq!(parse("latest"), None);
+    }
+
+    #[test]
+    fn routes() {
+        let mut extensions = Extensions::new();
+        assert_eq!(route(&extensions, "/v1/admin/logs"), "/v1/admin/logs");
+        extensions.insert(Versioned);
+        assert_eq!(route(&extensions, "/v1/admin/logs"), "/admin/logs");
+        assert_eq!(route(&extensions, "/v1"), "/v1");
+        assert_eq!(route(&extensions, "/v10/quota"), "/v10/quota");
+    }
+}
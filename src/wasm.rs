@@ -0,0 +1,241 @@
+//! Request policies as WebAssembly plugins, per [`conf::WasmPlugin`], so
+//! that operators can add them without rebuilding us. Each runs sandboxed:
+//! given no imports, and stopped once out of fuel or memory.
+//!
+//! A plugin is a core module exporting:
+//! - `memory`;
+//! - `alloc(len: i32) -> i32`, for us to write the chat request's JSON to;
+//! - `transform(ptr: i32, len: i32) -> i64`, returning where its verdict's
+//!   JSON is, as `ptr << 32 | len`, or 0 to leave the request as is.
+//!
+//! A verdict is either `{"request": {..}}`, to forward that request
+//! instead, or `{"reject": "reason"}`, to not forward it at all.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context as _};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    chat, conf,
+    hook::{Context, Hook},
+};
+
+pub struct Plugin {
+    name: String,
+    sandbox: Arc<Sandbox>,
+}
+
+/// Of a plugin, what runs it, shared with the blocking threads it runs on.
+struct Sandbox {
+    engine: wasmtime::Engine,
+    pre: wasmtime::InstancePre<wasmtime::StoreLimits>,
+    fuel: u64,
+    max_memory_bytes: usize,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Request(chat::Req),
+    Reject(String),
+}
+
+impl Plugin {
+    pub fn load(conf: &conf::WasmPlugin) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(&conf.path)
+            .context(format!("Failed to read plugin: {:?}", conf.path))?;
+        let name = conf.path.file_stem().map_or_else(
+            || "wasm".to_string(),
+            |stem| stem.to_string_lossy().into_owned(),
+        );
+        Self::new(name, &bytes, conf)
+            .context(format!("Invalid plugin: {:?}", conf.path))
+    }
+
+    /// Of a module's binary or text.
+    pub fn new(
+        name: String,
+        module: &[u8],
+        conf: &conf::WasmPlugin,
+    ) -> anyhow::Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let module = wasmtime::Module::new(&engine, module)?;
+        // Nothing linked, so nothing of ours is reachable from within.
+        let linker = wasmtime::Linker::new(&engine);
+        let pre = linker.instantiate_pre(&module)?;
+        Ok(Self {
+            name,
+            sandbox: Arc::new(Sandbox {
+                engine,
+                pre,
+                fuel: conf.fuel,
+                max_memory_bytes: conf.max_memory_bytes,
+            }),
+        })
+    }
+}
+
+impl Sandbox {
+    /// Its verdict's JSON, if any. In a fresh instance each time, so that
+    /// requests can't leak into one another.
+    fn run(&self, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let limits = wasmtime::StoreLimitsBuilder::new()
+            .memory_size(self.max_memory_bytes)
+            .build();
+        let mut store = wasmtime::Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("No memory exported.")?;
+        let alloc =
+            instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "transform")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, usize::try_from(ptr)?, input)?;
+        let packed = transform.call(&mut store, (ptr, len))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let ptr = usize::try_from((packed >> 32) & 0xffff_ffff)?;
+        let len = usize::try_from(packed & 0xffff_ffff)?;
+        if ptr.saturating_add(len) > memory.data_size(&store) {
+            bail!("Verdict out of memory bounds: {ptr}+{len}");
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output)?;
+        Ok(Some(output))
+    }
+}
+
+#[async_trait::async_trait]
+impl Hook for Plugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run on a blocking thread, since, even bounded by fuel, it may take
+    /// long enough to hold up the other requests of a runtime thread.
+    async fn pre_forward(
+        &self,
+        _ctx: &Context,
+        chat_req: &mut chat::Req,
+    ) -> Result<(), Response> {
+        let verdict: anyhow::Result<Option<Verdict>> = async {
+            let input = serde_json::to_vec(chat_req)?;
+            let sandbox = self.sandbox.clone();
+            let output =
+                tokio::task::spawn_blocking(move || sandbox.run(&input))
+                    .await??;
+            let verdict = output
+                .map(|output| serde_json::from_slice(&output))
+                .transpose()?;
+            Ok(verdict)
+        }
+        .await;
+        match verdict {
+            Ok(None) => Ok(()),
+            Ok(Some(Verdict::Request(rewritten))) => {
+                *chat_req = rewritten;
+                Ok(())
+            }
+            Ok(Some(Verdict::Reject(reason))) => {
+                let error = chat::Error::new(
+                    "invalid_request_error",
+                    "rejected_by_policy",
+                    reason,
+                );
+                Err((StatusCode::FORBIDDEN, Json(error)).into_response())
+            }
+            Err(error) => {
+                // Fail closed, since the policy could not be checked.
+                tracing::error!(?error, plugin = self.name, "Plugin failed.");
+                Err(StatusCode::SERVICE_UNAVAILABLE.into_response())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::Plugin;
+    use crate::{chat, conf, hook};
+
+    /// Returns the verdict at 1024, of the length given, if any.
+    fn plugin(verdict: &str, body: &str) -> Plugin {
+        let module = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "{verdict}")
+                (func (export "alloc") (param i32) (result i32)
+                    (i32.const 2048))
+                (func (export "transform") (param i32 i32) (result i64)
+                    {body}))"#,
+            verdict = verdict.replace('"', "\\\""),
+        );
+        let conf = conf::WasmPlugin {
+            path: "test.wasm".into(),
+            fuel: 10_000,
+            max_memory_bytes: 1024 * 1024,
+        };
+        Plugin::new("test".to_string(), module.as_bytes(), &conf).unwrap()
+    }
+
+    fn packed(len: usize) -> String {
+        format!("(i64.const {})", (1024_i64 << 32) | len as i64)
+    }
+
+    #[tokio::test]
+    async fn verdicts() {
+        let ctx = hook::Context {
+            uid: "u".to_string(),
+            role: "user".to_string(),
+            req_id: "r".to_string(),
+            endpoint: "v1/chat/completions".to_string(),
+        };
+        let mut chat_req: chat::Req =
+            serde_json::from_value(serde_json::json!({
+                "model": "m",
+                "messages": [{"role": "user", "content": "Hi."}],
+            }))
+            .unwrap();
+        let as_is = plugin("", "(i64.const 0)");
+        assert!(hook::Hook::pre_forward(&as_is, &ctx, &mut chat_req)
+            .await
+            .is_ok());
+        assert_eq!(chat_req.model, "m");
+
+        let verdict = r#"{"request":{"model":"n","messages":[]}}"#;
+        let rewrite = plugin(verdict, &packed(verdict.len()));
+        assert!(hook::Hook::pre_forward(&rewrite, &ctx, &mut chat_req)
+            .await
+            .is_ok());
+        assert_eq!(chat_req.model, "n");
+
+        let verdict = r#"{"reject":"No."}"#;
+        let reject = plugin(verdict, &packed(verdict.len()));
+        let resp = hook::Hook::pre_forward(&reject, &ctx, &mut chat_req)
+            .await
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let spin = plugin("", "(loop (br 0)) (i64.const 0)");
+        let resp = hook::Hook::pre_forward(&spin, &ctx, &mut chat_req)
+            .await
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,375 @@
+//! Chat over WebSocket, for clients behind proxies which mangle SSE. Each
+//! text message is a chat request, handled as if POSTed to
+//! `/v1/chat/completions`, and answered with frames shaped like streamed
+//! chunks: the content of each choice, then the finish reasons and usage.
+//!
+//! Upstream responses are not streamed to us, so a choice's delta is its
+//! whole content, sent once the completion is done.
+//!
+//! Per [`conf::Ws`], sessions are closed once idle, or once the credentials
+//! they were opened with are revoked or expire.
+
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::{
+    chat, conf, data, deprecation, ratelimit,
+    server::{self, AppState, ReqId, User, REQ_ID, USER},
+};
+
+const ENDPOINT: &str = "v1/chat/completions";
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(handle_upgrade))]
+pub(crate) struct Docs;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/ws/chat", get(handle_upgrade))
+}
+
+/// Upgrades to a WebSocket, of which each text message is a chat request.
+#[utoipa::path(
+    get,
+    path = "/ws/chat",
+    tag = "api",
+    responses(
+        (status = 101, description = "Switching protocols."),
+        (status = 403, body = chat::Error),
+    )
+)]
+async fn handle_upgrade(
+    State(state): State<AppState>,
+    ConnectInfo(from): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Task-locals don't survive into the socket's task.
+    let user = USER.get();
+    let needed = [conf::Feature::Ws];
+    if let Some(feature) =
+        server::missing_feature(&conf::global(), &user, &needed)
+    {
+        return server::feature_not_allowed(feature);
+    }
+    let req_id = REQ_ID.get().req_id;
+    // None of signed requests, whose signatures are of the upgrade alone.
+    let token = server::bearer_token(&headers).map(str::to_string);
+    let conf = state.conf.clone();
+    upgrade.on_upgrade(move |socket| {
+        conf::scope(conf, relay(state, from, user, token, req_id, socket))
+    })
+}
+
+/// Requests of a connection are handled one at a time, in order. One in
+/// flight when the client goes away is aborted, upstream too, and its
+/// reservation settled, per [`server::handle_api`]'s.
+async fn relay(
+    state: AppState,
+    from: SocketAddr,
+    user: User,
+    token: Option<String>,
+    req_id: String,
+    mut socket: WebSocket,
+) {
+    tracing::info!(?from, uid = user.uid, req_id, "WebSocket opened.");
+    let idle = Duration::from_secs(conf::global().ws.idle_secs);
+    let mut total = chat::Usage::default();
+    let mut count: usize = 0;
+    // Received while one was in flight.
+    let mut pending = VecDeque::new();
+    'socket: loop {
+        let msg = match pending.pop_front() {
+            Some(msg) => msg,
+            None => match tokio::time::timeout(idle, socket.recv()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(None | Some(Err(_))) => break,
+                Err(_) => {
+                    tracing::info!(?idle, "Closing idle WebSocket.");
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+        };
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // Pings are answered by axum.
+            Message::Binary(_) | Message::Ping(_) | Message::Pong(_) => {
+                continue;
+            }
+        };
+        count += 1;
+        if let Some(status) = reauthorize(&state, token.as_deref()).await {
+            let error =
+                error_frame(status, &chat::Error::from_status(status));
+            let _ = socket.send(Message::Text(error)).await;
+            if status == StatusCode::UNAUTHORIZED {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            continue;
+        }
+        let req_id = ReqId {
+            req_id: format!("{req_id}-{count}"),
+            client_ip: Some(from.ip().to_canonical().to_string()),
+        };
+        let handling = deprecation::count_model(
+            state.storage.clone(),
+            handle(&state, from, &user, &text, count == 1),
+        );
+        let handling =
+            USER.scope(user.clone(), REQ_ID.scope(req_id, handling));
+        tokio::pin!(handling);
+        let frames = loop {
+            tokio::select! {
+                frames = &mut handling => break frames,
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => {
+                        tracing::warn!("Client gone mid-request. Aborting.");
+                        break 'socket;
+                    }
+                    Some(Ok(msg)) => pending.push_back(msg),
+                },
+            }
+        };
+        for frame in frames {
+            if let Some(usage) = chat::Usage::from_resp_body(&frame) {
+                total.prompt_tokens += usage.prompt_tokens;
+                total.completion_tokens += usage.completion_tokens;
+                total.total_tokens += usage.total_tokens;
+            }
+            if socket.send(Message::Text(frame)).await.is_err() {
+                tracing::warn!("Failed to send frame. Client gone?");
+                break 'socket;
+            }
+        }
+    }
+    tracing::info!(
+        uid = user.uid,
+        req_id,
+        requests = count,
+        prompt_tokens = total.prompt_tokens,
+        completion_tokens = total.completion_tokens,
+        "WebSocket closed."
+    );
+}
+
+/// Status to reject the request with, if any, as the credentials the
+/// session was opened with may have since been revoked or expired.
+async fn reauthorize(
+    state: &AppState,
+    token: Option<&str>,
+) -> Option<StatusCode> {
+    match server::authorize_token(state, token?).await {
+        Ok(Some(_)) => None,
+        Ok(None) => {
+            tracing::warn!(
+                "Closing WebSocket. Credentials revoked or expired."
+            );
+            Some(StatusCode::UNAUTHORIZED)
+        }
+        Err(status) => Some(status),
+    }
+}
+
+/// Frames to respond to a message with.
+async fn handle(
+    state: &AppState,
+    from: SocketAddr,
+    user: &User,
+    text: &str,
+    is_first: bool,
+) -> Vec<String> {
+    let mut chat_req: chat::Req = match serde_json::from_str(text) {
+        Ok(chat_req) => chat_req,
+        Err(error) => {
+            tracing::warn!(?error, "Rejecting. Invalid chat request.");
+            let error = chat::Error::new(
+                "invalid_request_error",
+                "invalid_request",
+                format!("Invalid chat request: {error}"),
+            );
+            return vec![error_frame(StatusCode::BAD_REQUEST, &error)];
+        }
+    };
+    // We frame the response ourselves.
+    chat_req.rest.remove("stream");
+    chat_req.rest.remove("stream_options");
+
+    // Each, as the session may outlast the window it was opened in.
+    if let Some(error) = server::access_closed(&user.role) {
+        return vec![error_frame(StatusCode::FORBIDDEN, &error)];
+    }
+    let status = admit(state.storage.as_ref(), user, is_first)
+        .await
+        .unwrap_or_else(|error| {
+            tracing::error!(?error, "Failed to hit storage.");
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        });
+    if let Some(status) = status {
+        return vec![error_frame(status, &chat::Error::from_status(status))];
+    }
+
+    let (status, body) = match forward(state, from, &chat_req).await {
+        Ok(resp) => resp,
+        Err(error) => {
+            tracing::error!(?error, "Failed to handle request.");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            return vec![error_frame(
+                status,
+                &chat::Error::from_status(status),
+            )];
+        }
+    };
+    let body: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+    match body {
+        Some(completion) if status.is_success() => chunks(&completion),
+        // As over HTTP, unless it was just the status.
+        Some(error) if error.get("error").is_some() => {
+            vec![error_frame(status, &error)]
+        }
+        _ => vec![error_frame(status, &chat::Error::from_status(status))],
+    }
+}
+
+/// Status to reject the request with, if any. Checked by the layers for
+/// the upgrade, which counts as the first request, but due for each.
+async fn admit(
+    storage: &dyn data::Storage,
+    user: &User,
+    is_first: bool,
+) -> anyhow::Result<Option<StatusCode>> {
+    if storage.account_get(&user.uid).await?.is_suspended {
+        tracing::warn!("Rejecting. Account suspended.");
+        return Ok(Some(StatusCode::FORBIDDEN));
+    }
+    if is_first {
+        return Ok(None);
+    }
+    if let Err(rejection) =
+        ratelimit::check(storage, &user.uid, &user.role).await?
+    {
+        tracing::warn!(?rejection, "Rejecting. Rate limited.");
+        return Ok(Some(StatusCode::TOO_MANY_REQUESTS));
+    }
+    Ok(None)
+}
+
+/// Status and body of the response, as to a POST of the request.
+async fn forward(
+    state: &AppState,
+    from: SocketAddr,
+    chat_req: &chat::Req,
+) -> anyhow::Result<(StatusCode, String)> {
+    let req = axum::http::Request::post(format!("/{ENDPOINT}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(chat_req)?))?;
+    let resp = match server::handle_api(
+        State(state.clone()),
+        ConnectInfo(from),
+        Path(ENDPOINT.to_string()),
+        req,
+    )
+    .await
+    {
+        Ok(resp) => resp.into_response(),
+        Err(error) => error.into_response(),
+    };
+    let status = resp.status();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Of a completion: the content of each choice, then one with the finish
+/// reasons and usage.
+fn chunks(completion: &serde_json::Value) -> Vec<String> {
+    let choices = completion["choices"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let chunk = |choices: Vec<serde_json::Value>, usage| {
+        serde_json::json!({
+            "id": completion["id"],
+            "object": "chat.completion.chunk",
+            "created": completion["created"],
+            "model": completion["model"],
+            "choices": choices,
+            "usage": usage,
+        })
+        .to_string()
+    };
+    let mut frames: Vec<String> = choices
+        .iter()
+        .map(|choice| {
+            let delta = serde_json::json!({
+                "index": choice["index"],
+                "delta": choice["message"],
+                "finish_reason": null,
+            });
+            chunk(vec![delta], serde_json::Value::Null)
+        })
+        .collect();
+    let finishes = choices
+        .iter()
+        .map(|choice| {
+            serde_json::json!({
+                "index": choice["index"],
+                "delta": {},
+                "finish_reason": choice["finish_reason"],
+            })
+        })
+        .collect();
+    frames.push(chunk(finishes, completion["usage"].clone()));
+    frames
+}
+
+/// An error body, as over HTTP, with the status, which frames lack.
+fn error_frame(status: StatusCode, error: &impl serde::Serialize) -> String {
+    let mut frame = serde_json::json!(error);
+    frame["status"] = status.as_u16().into();
+    frame.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::chunks;
+
+    #[test]
+    fn framing() {
+        let completion = serde_json::json!({
+            "id": "chatcmpl-1",
+            "created": 1,
+            "model": "gpt",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi."},
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 5,
+                "completion_tokens": 2,
+                "total_tokens": 7
+            }
+        });
+        let frames: Vec<serde_json::Value> = chunks(&completion)
+            .iter()
+            .map(|frame| serde_json::from_str(frame).unwrap())
+            .collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["choices"][0]["delta"]["content"], "Hi.");
+        assert!(frames[0]["usage"].is_null());
+        assert_eq!(frames[1]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(frames[1]["usage"]["total_tokens"], 7);
+    }
+}
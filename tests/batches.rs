@@ -0,0 +1,48 @@
+use raskol::{
+    data::{self, Batch, BatchStatus},
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn claims() {
+    let _harness = Harness::start().await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let batch = Batch {
+        id: "batch_a".to_string(),
+        uid: "alice".to_string(),
+        role: "hacker".to_string(),
+        org: None,
+        budget_overrides: data::BudgetOverrides::default(),
+        features: Vec::new(),
+        endpoint: "v1/chat/completions".to_string(),
+        status: BatchStatus::Queued,
+        total: 2,
+        completed: 0,
+        failed: 0,
+        time_created: 0,
+        time_finished: None,
+    };
+    let requests = ["{}".to_string(), "{}".to_string()];
+    storage.batch_create(&batch, &requests).await.unwrap();
+    let claim = |now: i64| storage.batch_item_claim(&batch.id, now, now + 60);
+
+    // Never the same item twice, however close together.
+    let (a, b) = tokio::join!(claim(100), claim(100));
+    let mut claimed = [a.unwrap().unwrap().index, b.unwrap().unwrap().index];
+    claimed.sort_unstable();
+    assert_eq!(claimed, [0, 1]);
+    assert!(claim(100).await.unwrap().is_none());
+
+    // Until released, or the claim runs out.
+    storage.batch_item_release(&batch.id, 1).await.unwrap();
+    assert_eq!(claim(110).await.unwrap().unwrap().index, 1);
+    assert_eq!(claim(160).await.unwrap().unwrap().index, 0);
+
+    // Nor once it has a response.
+    assert!(storage
+        .batch_item_complete(&batch.id, 0, 200, "{}")
+        .await
+        .unwrap());
+    assert_eq!(claim(1000).await.unwrap().unwrap().index, 1);
+    assert!(claim(1000).await.unwrap().is_none());
+}
@@ -0,0 +1,57 @@
+use raskol::{
+    auth,
+    conf::Conf,
+    data::{self, Amount, BudgetOverrides},
+    mock::Mock,
+    period,
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn reserve_settle() {
+    let conf = Conf {
+        budget_cache: true,
+        max_tokens_per_day: 1000,
+        ..Conf::default()
+    };
+    let _harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let date = period::current();
+    let amount = |tokens| Amount { tokens, cost: 0.0 };
+    let reserve = |tokens| {
+        storage.budget_reserve(
+            "alice",
+            None,
+            auth::ROLE_HACKER,
+            BudgetOverrides::default(),
+            "mock",
+            amount(tokens),
+        )
+    };
+    let used = || async { storage.usage("alice", &date).await.unwrap() };
+
+    // Each settled for less right after, as when upstream answers at once.
+    let requests = (0..10).map(|_| async {
+        let reservation = reserve(90).await.unwrap().unwrap();
+        storage
+            .budget_settle(&reservation, amount(10))
+            .await
+            .unwrap();
+    });
+    futures_util::future::join_all(requests).await;
+    assert_eq!(used().await.tokens, 100);
+
+    // The cache agrees with the database on what is left.
+    let reservation = reserve(900).await.unwrap().unwrap();
+    assert_eq!(
+        reserve(1).await.unwrap().unwrap_err(),
+        data::Exceeded::Tokens
+    );
+    assert_eq!(used().await.tokens, 1000);
+    storage
+        .budget_settle(&reservation, amount(0))
+        .await
+        .unwrap();
+    assert_eq!(used().await.tokens, 100);
+    assert!(reserve(900).await.unwrap().is_ok());
+}
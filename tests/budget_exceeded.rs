@@ -0,0 +1,55 @@
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    mock::Mock,
+    testing::Harness,
+};
+
+// One test, as storage is of the first server started.
+#[tokio::test]
+async fn which() {
+    let mut conf = Conf {
+        max_cost_per_day: Some(1.0),
+        ..Conf::default()
+    };
+    conf.limits.per_model.insert(
+        "mock".to_string(),
+        conf::ModelLimits {
+            max_tokens_per_day: 1,
+        },
+    );
+    // A dollar a token.
+    conf.pricing.insert(
+        "mock-priced".to_string(),
+        conf::Price {
+            input_per_million: 1_000_000.0,
+            output_per_million: 1_000_000.0,
+        },
+    );
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+    let message = |model: &str| {
+        let req = client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .json(&serde_json::json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "Hi there"}],
+            }))
+            .send();
+        async {
+            let resp = req.await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+            let error: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(error["error"]["code"], "budget_exceeded");
+            error["error"]["message"].as_str().unwrap().to_string()
+        }
+    };
+
+    // Of the model, though the global one has plenty left.
+    let model = message("mock").await;
+    assert!(model.contains("for model mock exceeded"), "{model}");
+    let cost = message("mock-priced").await;
+    assert!(cost.contains("of $1.00 exceeded"), "{cost}");
+}
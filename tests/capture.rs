@@ -0,0 +1,96 @@
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    data,
+    mock::Mock,
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn redacted() {
+    let conf = Conf {
+        capture: Some(conf::Capture::default()),
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let resp = reqwest::Client::new()
+        .post(harness.url("/openai/v1/chat/completions"))
+        .bearer_auth(&jwt)
+        .header("x-request-id", "captured")
+        .json(&serde_json::json!({
+            "model": "mock",
+            "messages": [
+                {"role": "user", "content": "Mail bob@example.com back"},
+            ],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    // Echoed back, so in both.
+    assert!(resp.text().await.unwrap().contains("bob@example.com"));
+
+    let capture = storage.capture_get("captured").await.unwrap().unwrap();
+    assert!(capture.request.contains("Mail [REDACTED] back"));
+    assert!(!capture.request.contains("bob@example.com"));
+    let response = capture.response.unwrap();
+    assert!(response.contains("[REDACTED]"));
+    assert!(!response.contains("bob@example.com"));
+}
+
+#[tokio::test]
+async fn replayed() {
+    let conf = Conf {
+        capture: Some(conf::Capture::default()),
+        rate_limit: conf::RateLimit {
+            requests_per_minute: Some(2),
+            ..conf::RateLimit::default()
+        },
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let admin = harness.jwt("ada", auth::ROLE_ADMIN).unwrap();
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(harness.url("/openai/v1/chat/completions"))
+        .bearer_auth(&jwt)
+        .header("x-request-id", "original")
+        .json(&serde_json::json!({
+            "model": "mock",
+            "messages": [{"role": "user", "content": "Hi there"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    let replay = |req_id: &str| {
+        client
+            .post(harness.url(&format!("/admin/replay/{req_id}")))
+            .bearer_auth(&admin)
+            .send()
+    };
+
+    for _ in 0..2 {
+        let resp = replay("original").await.unwrap();
+        assert!(resp.status().is_success());
+        let replayed: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(replayed["status"], 200);
+        assert_eq!(replayed["original_status"], 200);
+        let req_id = replayed["req_id"].as_str().unwrap();
+        assert!(req_id.starts_with("replay-original-"));
+        assert!(replayed["response"].as_str().unwrap().contains("Hi there"));
+    }
+
+    // Limited as the requests of whoever replays them are.
+    let replayed: serde_json::Value =
+        replay("original").await.unwrap().json().await.unwrap();
+    assert_eq!(replayed["status"], 429);
+    let error = replayed["response"].as_str().unwrap();
+    assert!(error.contains("rate_limit.requests_per_minute"), "{error}");
+
+    let resp = replay("uncaptured").await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
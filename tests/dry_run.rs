@@ -0,0 +1,62 @@
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    data,
+    mock::Mock,
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn unlimited() {
+    let conf = Conf {
+        duplicates: Some(conf::Duplicates {
+            window: 60,
+            max_requests: 1,
+        }),
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let jwt = harness.jwt("ada", auth::ROLE_ADMIN).unwrap();
+    let client = reqwest::Client::new();
+    let chat = |is_dry_run: bool| {
+        client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .header("x-raskol-dry-run", is_dry_run.to_string())
+            .json(&serde_json::json!({
+                "model": "mock",
+                "messages": [{"role": "user", "content": "Hi there"}],
+            }))
+            .send()
+    };
+
+    for _ in 0..3 {
+        let resp = chat(true).await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers()["x-raskol-dry-run"], "true");
+    }
+    let filter = data::LogFilter::default();
+    let logs = storage.request_logs(&filter, 10, 0).await.unwrap();
+    assert!(logs.is_empty());
+
+    // Not counted as repeats of the prompt, unlike real ones.
+    let resp = chat(false).await.unwrap();
+    assert!(resp.status().is_success());
+    let resp = chat(false).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    let error: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(error["error"]["code"], "duplicate_prompt");
+
+    // Only of roles allowed to.
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let resp = client
+        .post(harness.url("/openai/v1/chat/completions"))
+        .bearer_auth(&jwt)
+        .header("x-raskol-dry-run", "true")
+        .json(&serde_json::json!({"model": "mock", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+}
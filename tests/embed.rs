@@ -0,0 +1,32 @@
+use raskol::{conf::Conf, mock::Mock, testing::Harness, Routes};
+
+#[tokio::test]
+async fn router() {
+    let harness = Harness::start_with_routes(
+        Conf::default(),
+        Mock::default(),
+        &[Routes::Public],
+    )
+    .await
+    .unwrap();
+
+    let resp = reqwest::get(harness.url("/ping")).await.unwrap();
+    assert!(resp.status().is_success());
+    assert!(resp.headers().contains_key("x-request-id"));
+
+    // Not mounted.
+    let resp = reqwest::get(harness.url("/stats")).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    // Another, in the same process, of its own conf.
+    let mut conf = Conf::default();
+    conf.ip_filter.deny = vec!["127.0.0.0/8".parse().unwrap()];
+    let denying =
+        Harness::start_with_routes(conf, Mock::default(), &[Routes::Public])
+            .await
+            .unwrap();
+    let resp = reqwest::get(denying.url("/ping")).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    let resp = reqwest::get(harness.url("/ping")).await.unwrap();
+    assert!(resp.status().is_success());
+}
@@ -0,0 +1,60 @@
+use raskol::{auth, data, testing::Harness};
+
+#[tokio::test]
+async fn users() {
+    let harness = Harness::start().await.unwrap();
+    let storage = data::connect().await.unwrap();
+    storage
+        .account_set_max_tokens_per_day("ada", Some(1000))
+        .await
+        .unwrap();
+    storage.account_grant_tokens("ada", 100).await.unwrap();
+    storage.credits_grant("ada", 50, None).await.unwrap();
+    storage.account_set_suspended("bob", true).await.unwrap();
+    storage.account_set_suspended("eve", false).await.unwrap();
+    let jwt = harness.jwt("admin", auth::ROLE_ADMIN).unwrap();
+    let client = reqwest::Client::new();
+    let query = |query: &str| {
+        client
+            .post(harness.url("/v1/admin/graphql"))
+            .bearer_auth(&jwt)
+            .json(&serde_json::json!({"query": query}))
+            .send()
+    };
+    let data = |resp: serde_json::Value| {
+        assert!(resp.get("errors").is_none(), "{resp}");
+        resp["data"]["users"].as_array().unwrap().clone()
+    };
+
+    // Paged by uid, with budgets listed along.
+    let resp = query(
+        "{ users(limit: 2) { uid isSuspended credits
+            budget { limit used remaining } } }",
+    )
+    .await
+    .unwrap();
+    let users = data(resp.json().await.unwrap());
+    assert_eq!(users.len(), 2);
+    assert_eq!(users[0]["uid"], "ada");
+    assert_eq!(users[0]["credits"], 50);
+    assert_eq!(users[0]["budget"]["limit"], 1150);
+    assert_eq!(users[0]["budget"]["used"], 0);
+    assert_eq!(users[1]["uid"], "bob");
+    let resp = query("{ users(offset: 2) { uid } }").await.unwrap();
+    let users = data(resp.json().await.unwrap());
+    assert_eq!(users, [serde_json::json!({"uid": "eve"})]);
+    let resp = query("{ users(isSuspended: true) { uid } }").await.unwrap();
+    let users = data(resp.json().await.unwrap());
+    assert_eq!(users, [serde_json::json!({"uid": "bob"})]);
+
+    // A full page of budgets is too much at once.
+    let resp = query(
+        "{ users(limit: 1000) { uid credits
+            budget { limit used remaining } } }",
+    )
+    .await
+    .unwrap();
+    let resp: serde_json::Value = resp.json().await.unwrap();
+    let errors = resp["errors"].as_array().unwrap();
+    assert!(errors[0]["message"].as_str().unwrap().contains("complex"));
+}
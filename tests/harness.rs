@@ -0,0 +1,81 @@
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    mock::Mock,
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn chat() {
+    let harness = Harness::start().await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+
+    let resp = client
+        .post(harness.url("/openai/v1/chat/completions"))
+        .bearer_auth(&jwt)
+        .json(&serde_json::json!({
+            "model": "mock",
+            "messages": [{"role": "user", "content": "Hi there"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    let completion: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(completion["choices"][0]["message"]["content"], "Hi there");
+
+    let resp = client
+        .get(harness.url("/stats"))
+        .bearer_auth(&jwt)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let resp = client
+        .get(harness.url("/admin/users/alice"))
+        .bearer_auth(&jwt)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn client_config() {
+    let client = reqwest::Client::new();
+    let base_url = |harness: Harness| {
+        let client = client.clone();
+        async move {
+            let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+            let config: serde_json::Value = client
+                .get(harness.url("/client-config"))
+                .bearer_auth(&jwt)
+                .header("x-forwarded-proto", "https")
+                .header("x-forwarded-host", "raskol.example")
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            config["base_url"].as_str().unwrap().to_string()
+        }
+    };
+
+    // Only of trusted proxies.
+    let harness = Harness::start().await.unwrap();
+    assert!(base_url(harness).await.starts_with("http://127.0.0.1:"));
+    let conf = Conf {
+        ip_filter: conf::IpFilter {
+            trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()],
+            ..conf::IpFilter::default()
+        },
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    assert!(base_url(harness)
+        .await
+        .starts_with("https://raskol.example/"));
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use raskol::{
+    auth,
+    conf::Conf,
+    data::{self, Idempotency},
+    mock::Mock,
+    testing::Harness,
+};
+
+#[tokio::test]
+async fn replayed() {
+    let mock = Mock {
+        latency: Duration::from_millis(500),
+        ..Mock::default()
+    };
+    let harness = Harness::start_with(Conf::default(), mock).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+    let chat = |key: &str, content: &str| {
+        client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .header("idempotency-key", key)
+            .json(&serde_json::json!({
+                "model": "mock",
+                "messages": [{"role": "user", "content": content}],
+            }))
+            .send()
+    };
+
+    // While the first is in flight.
+    let (first, repeat) = tokio::join!(chat("a", "Hi there"), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        chat("a", "Hi there").await
+    });
+    let repeat = repeat.unwrap();
+    assert_eq!(repeat.status(), reqwest::StatusCode::CONFLICT);
+    let error: serde_json::Value = repeat.json().await.unwrap();
+    assert_eq!(error["error"]["code"], "idempotency_key_in_use");
+    let first = first.unwrap();
+    assert!(first.status().is_success());
+    let completion = first.text().await.unwrap();
+
+    // Once done, as it was, without going upstream again.
+    let resp = chat("a", "Hi there").await.unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["x-raskol-idempotent-replay"], "true");
+    assert_eq!(resp.text().await.unwrap(), completion);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let filter = data::LogFilter::default();
+    let logs = storage.request_logs(&filter, 10, 0).await.unwrap();
+    assert_eq!(logs.iter().filter(|log| log.status == 200).count(), 1);
+
+    // Nor for another request.
+    let resp = chat("a", "Bye").await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    let resp = chat(&"a".repeat(256), "Hi there").await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // Of storage, until released, or expired.
+    let claim =
+        |now: i64| storage.idempotency_claim("bob", "b", "x", now, 60);
+    assert!(matches!(claim(0).await.unwrap(), Idempotency::Claimed));
+    assert!(matches!(claim(10).await.unwrap(), Idempotency::InProgress));
+    storage
+        .idempotency_complete("bob", "b", 200, "{}")
+        .await
+        .unwrap();
+    let done = claim(20).await.unwrap();
+    assert!(matches!(done, Idempotency::Done { status: 200, .. }));
+    let other = storage.idempotency_claim("bob", "b", "y", 30, 60);
+    assert!(matches!(other.await.unwrap(), Idempotency::Mismatch));
+
+    // Released, or expired, for the key to be claimed again.
+    storage.idempotency_release("bob", "b").await.unwrap();
+    assert!(matches!(claim(40).await.unwrap(), Idempotency::Claimed));
+    assert!(matches!(claim(50).await.unwrap(), Idempotency::InProgress));
+    assert!(matches!(claim(60).await.unwrap(), Idempotency::Claimed));
+}
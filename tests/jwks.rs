@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use axum::{routing::get, Json};
+use raskol::{
+    auth,
+    conf::{Conf, JwtKeyAlgorithm},
+    jwt,
+    mock::Mock,
+    testing::Harness,
+};
+
+/// Of a third-party issuer, serving the key's JWK.
+async fn issuer(jwk: serde_json::Value) -> String {
+    let jwks = serde_json::json!({"keys": [jwk]});
+    let routes = axum::Router::new().route(
+        "/jwks",
+        get(move || {
+            let jwks = jwks.clone();
+            async move { Json(jwks) }
+        }),
+    );
+    let listener =
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, routes).await });
+    format!("http://{addr}/jwks")
+}
+
+#[tokio::test]
+async fn overrides_only_ours() {
+    let keypair = jwt::keygen(JwtKeyAlgorithm::Eddsa, "k1").unwrap();
+    let mut conf = Conf {
+        max_tokens_per_day: 600,
+        ..Conf::default()
+    };
+    conf.jwt.jwks_url = Some(issuer(keypair.jwk).await);
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let mut claims = auth::Claims::new(
+        "alice",
+        Duration::from_secs(60),
+        &harness.conf.jwt,
+    )
+    .unwrap();
+    claims.max_tokens_per_day = Some(1_000_000);
+    let theirs = {
+        let header = jsonwebtoken::Header {
+            kid: Some("k1".to_string()),
+            ..jsonwebtoken::Header::new(jsonwebtoken::Algorithm::EdDSA)
+        };
+        let key = jsonwebtoken::EncodingKey::from_ed_pem(
+            keypair.private_pem.as_bytes(),
+        )
+        .unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    };
+    let ours = claims.to_str(&harness.conf.jwt).unwrap();
+    let client = reqwest::Client::new();
+    let limit = |token: String| {
+        let client = client.clone();
+        let url = harness.url("/quota");
+        async move {
+            let resp = client.get(url).bearer_auth(token).send().await;
+            let quota: serde_json::Value =
+                resp.unwrap().json().await.unwrap();
+            quota["tokens"]["limit"].clone()
+        }
+    };
+
+    assert_eq!(limit(ours).await, 1_000_000);
+    // Accepted, but held to conf's.
+    assert_eq!(limit(theirs).await, 600);
+}
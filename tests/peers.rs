@@ -0,0 +1,85 @@
+use axum::{http::HeaderMap, routing::get, Json};
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    mock::Mock,
+    peers,
+    testing::Harness,
+};
+
+/// Of the peer, ready, and echoing what it was sent.
+async fn peer() -> String {
+    let routes = axum::Router::new()
+        .route(
+            "/health/ready",
+            get(|| async { Json(serde_json::json!({"is_ready": true})) }),
+        )
+        .fallback(|headers: HeaderMap, body: String| async move {
+            let authorization = headers
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            Json(serde_json::json!({
+                "authorization": authorization,
+                "body": body,
+            }))
+        });
+    let listener =
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, routes).await });
+    format!("http://{addr}")
+}
+
+#[tokio::test]
+async fn proxied() {
+    let peer = peer().await;
+    let conf = Conf {
+        peers: Some(conf::Peers {
+            urls: vec![peer],
+            ..conf::Peers::default()
+        }),
+        retry: conf::Retry {
+            max_attempts: 1,
+            ..conf::Retry::default()
+        },
+        ..Conf::default()
+    };
+    // Throttling, so that upstream is exhausted once it has been tried.
+    let mock = Mock {
+        error_rate: 1.0,
+        error_status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+        ..Mock::default()
+    };
+    let harness = Harness::start_with(conf, mock).await.unwrap();
+    peers::global()
+        .unwrap()
+        .heartbeat(&reqwest::Client::new())
+        .await
+        .unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+    let body = serde_json::json!({
+        "model": "mock",
+        "messages": [{"role": "user", "content": "Hi there"}],
+    });
+    let chat = || {
+        client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .json(&body)
+            .send()
+    };
+
+    let resp = chat().await.unwrap();
+    assert!(!resp.status().is_success());
+
+    // Authorization and all, which a redirect to another origin would drop.
+    let resp = chat().await.unwrap();
+    assert!(resp.status().is_success());
+    let echoed: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(echoed["authorization"], format!("Bearer {jwt}"));
+    let echoed: serde_json::Value =
+        serde_json::from_str(echoed["body"].as_str().unwrap()).unwrap();
+    assert_eq!(echoed, body);
+}
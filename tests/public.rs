@@ -0,0 +1,106 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use raskol::{
+    conf::{self, Conf},
+    data,
+    mock::Mock,
+    period,
+    testing::Harness,
+};
+
+fn log(
+    req_id: &str,
+    uid: &str,
+    duration_ms: i64,
+    time: i64,
+) -> data::RequestLog {
+    data::RequestLog {
+        req_id: req_id.to_string(),
+        uid: uid.to_string(),
+        model: "mock".to_string(),
+        endpoint: "v1/chat/completions".to_string(),
+        status: 200,
+        input_tokens: 10,
+        output_tokens: 20,
+        cost: 0.0,
+        duration_ms,
+        time,
+        error_message: None,
+        session: None,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class: None,
+        client_ip: None,
+        tags: BTreeMap::new(),
+        usage: None,
+    }
+}
+
+// One test, as storage is of the first server started.
+#[tokio::test]
+async fn stats() {
+    let conf = Conf {
+        public_stats: Some(conf::PublicStats::default()),
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let storage = data::connect().await.unwrap();
+
+    // Of storage, the nearest-rank p95, of those which succeeded.
+    let since = 1_700_000_000;
+    for i in 1..=20 {
+        let uid = if i % 2 == 0 { "alice" } else { "bob" };
+        let entry = log(&format!("a{i}"), uid, i, since + i);
+        storage.log_request(&entry).await.unwrap();
+    }
+    let failed = data::RequestLog {
+        status: 500,
+        ..log("b", "carol", 1000, since)
+    };
+    storage.log_request(&failed).await.unwrap();
+    let stats = storage.public_stats(since).await.unwrap();
+    assert_eq!(
+        stats,
+        data::PublicStats {
+            requests: 21,
+            tokens: 21 * 30,
+            active_teams: 3,
+            duration_ms_p95: Some(19),
+        }
+    );
+    let stats = storage.public_stats(since + 20).await.unwrap();
+    assert_eq!(stats.duration_ms_p95, Some(20));
+    let stats = storage.public_stats(since + 1000).await.unwrap();
+    assert_eq!(stats, data::PublicStats::default());
+
+    // Of the handler, of today only, and cached.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let now = i64::try_from(now).unwrap();
+    let get = || async {
+        let resp = reqwest::get(harness.url("/public/stats")).await.unwrap();
+        assert!(resp.status().is_success());
+        resp.json::<serde_json::Value>().await.unwrap()
+    };
+    storage
+        .log_request(&log("c", "dave", 5, now))
+        .await
+        .unwrap();
+    let stats = get().await;
+    assert_eq!(stats["day"], period::today());
+    assert_eq!(stats["requests"], 1);
+    assert_eq!(stats["tokens"], 30);
+    assert_eq!(stats["active_teams"], 1);
+    assert_eq!(stats["duration_ms_p95"], 5);
+    storage
+        .log_request(&log("d", "erin", 5, now))
+        .await
+        .unwrap();
+    assert_eq!(get().await, stats);
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+use raskol::{data, testing::Harness};
+
+const HOUR: i64 = 60 * 60;
+
+fn log(req_id: &str, time: i64) -> data::RequestLog {
+    data::RequestLog {
+        req_id: req_id.to_string(),
+        uid: "alice".to_string(),
+        model: "mock".to_string(),
+        endpoint: "v1/chat/completions".to_string(),
+        status: 200,
+        input_tokens: 10,
+        output_tokens: 20,
+        cost: 0.0,
+        duration_ms: 100,
+        time,
+        error_message: None,
+        session: None,
+        experiment: None,
+        arm: None,
+        timing: None,
+        error_class: None,
+        client_ip: None,
+        tags: BTreeMap::new(),
+        usage: None,
+    }
+}
+
+#[tokio::test]
+async fn late() {
+    let _harness = Harness::start().await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let hour = 472_223 * HOUR;
+    let requests = || async {
+        let page = data::StatsPage::default();
+        let stats = storage.stats(Some("alice"), None, hour, &page);
+        stats.await.unwrap()[0].requests
+    };
+
+    storage.log_request(&log("a", hour + 10)).await.unwrap();
+    let until = storage.rollup(hour + 2 * HOUR).await.unwrap();
+    assert_eq!(until, hour + 2 * HOUR);
+    assert_eq!(requests().await, 1);
+
+    // Of an hour rolled up already, as of a long stream.
+    storage.log_request(&log("b", hour + 20)).await.unwrap();
+    storage.rollup(hour + 2 * HOUR).await.unwrap();
+    assert_eq!(requests().await, 2);
+    storage.rollup(hour + 3 * HOUR).await.unwrap();
+    assert_eq!(requests().await, 2);
+}
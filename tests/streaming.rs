@@ -0,0 +1,48 @@
+use raskol::{auth, conf::Conf, data, mock::Mock, testing::Harness};
+
+#[tokio::test]
+async fn cut_off() {
+    let conf = Conf {
+        max_tokens_per_day: 600,
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+    let stream = |content: String| {
+        client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .json(&serde_json::json!({
+                "model": "mock",
+                "messages": [{"role": "user", "content": content}],
+                "stream": true,
+            }))
+            .send()
+    };
+
+    // Upstream is asked for the usage, which the client wasn't.
+    let resp = stream("Hi there".to_string()).await.unwrap();
+    assert!(resp.status().is_success());
+    let events = resp.text().await.unwrap();
+    assert!(events.contains("there"));
+    assert!(!events.contains("\"usage\""));
+    assert!(events.trim_end().ends_with("data: [DONE]"));
+
+    // Echoed back, past the budget, a draw at a time.
+    let resp = stream("a ".repeat(400)).await.unwrap();
+    assert!(resp.status().is_success());
+    let events = resp.text().await.unwrap();
+    let (streamed, end) = events.rsplit_once("data: {\"error\"").unwrap();
+    assert!(streamed.contains("\"a"));
+    assert!(end.contains("budget_exceeded"));
+    assert!(end.trim_end().ends_with("data: [DONE]"));
+    // Once settled, after the stream ends.
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let storage = data::connect().await.unwrap();
+    let filter = data::LogFilter::default();
+    let logs = storage.request_logs(&filter, 10, 0).await.unwrap();
+    let classes: Vec<_> = logs.iter().map(|log| log.error_class).collect();
+    assert!(classes.contains(&Some(data::ErrorClass::Budget)));
+    assert!(classes.contains(&None));
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use raskol::{auth, conf::Conf, data, mock::Mock, testing::Harness};
+
+#[tokio::test]
+async fn timeouts() {
+    let mock = Mock {
+        latency: Duration::from_secs(2),
+        ..Mock::default()
+    };
+    let conf = Conf {
+        validate_json_output: true,
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, mock).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let jwt = harness.jwt("alice", auth::ROLE_HACKER).unwrap();
+    let client = reqwest::Client::new();
+    let chat = |timeout: &str| {
+        client
+            .post(harness.url("/openai/v1/chat/completions"))
+            .bearer_auth(&jwt)
+            .header("x-raskol-timeout", timeout)
+            .json(&serde_json::json!({
+                "model": "mock",
+                "messages": [{"role": "user", "content": "Hi there"}],
+            }))
+    };
+
+    let resp = chat("0.5").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+    let resp = chat("soon").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // Retried, for the echo not being JSON, but by the same deadline.
+    let resp = chat("3")
+        .json(&serde_json::json!({
+            "model": "mock",
+            "messages": [{"role": "user", "content": "Hi there"}],
+            "response_format": {"type": "json_object"},
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+
+    // Given up on by the client, once upstream was sent it, so charged.
+    let resp = chat("10").timeout(Duration::from_millis(500)).send().await;
+    assert!(resp.is_err());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let filter = data::LogFilter::default();
+    let logs = storage.request_logs(&filter, 10, 0).await.unwrap();
+    let abandoned = logs.iter().find(|log| log.status == 499).unwrap();
+    assert!(abandoned.input_tokens > 0);
+    let timed_out = logs.iter().find(|log| log.status == 504).unwrap();
+    assert_eq!(timed_out.error_class, Some(data::ErrorClass::Timeout));
+}
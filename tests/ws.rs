@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use raskol::{
+    auth,
+    conf::{self, Conf},
+    data,
+    mock::Mock,
+    testing::Harness,
+};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+const CHAT: &str = r#"{"model": "mock", "messages": [
+    {"role": "user", "content": "Hi there"}
+]}"#;
+
+#[tokio::test]
+async fn sessions() {
+    let conf = Conf {
+        ws: conf::Ws { idle_secs: 1 },
+        ..Conf::default()
+    };
+    let harness = Harness::start_with(conf, Mock::default()).await.unwrap();
+    let storage = data::connect().await.unwrap();
+    let (key, token) = storage
+        .api_key_create("alice", auth::ROLE_HACKER)
+        .await
+        .unwrap();
+    let url = harness.url("/v1/ws/chat").replacen("http", "ws", 1);
+    let bearer = format!("Bearer {token}");
+    let connect = || {
+        let mut req = url.as_str().into_client_request().unwrap();
+        req.headers_mut()
+            .insert("authorization", bearer.parse().unwrap());
+        async move { tokio_tungstenite::connect_async(req).await.unwrap().0 }
+    };
+
+    // Closed once idle.
+    let mut socket = connect().await;
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok());
+
+    // And once the key is revoked.
+    let mut socket = connect().await;
+    socket.send(Message::Text(CHAT.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(frame))) = socket.next().await else {
+        panic!("No frame.");
+    };
+    assert!(frame.contains("there"));
+    // Until the last, of the finish reasons.
+    while let Some(Ok(Message::Text(frame))) = socket.next().await {
+        if frame.contains("\"stop\"") {
+            break;
+        }
+    }
+    storage
+        .api_key_revoke("alice", Some(&key.id))
+        .await
+        .unwrap();
+    socket.send(Message::Text(CHAT.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(frame))) = socket.next().await else {
+        panic!("No frame.");
+    };
+    let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+    assert_eq!(frame["status"], 401);
+    assert!(matches!(
+        socket.next().await,
+        Some(Ok(Message::Close(_))) | None
+    ));
+}